@@ -31,8 +31,8 @@ int main(int argc, char *const argv[])
     };
     char *const rlimits[] =
     {
-        // RLIMIT_NPROC = 6
-        "6=4096:8192",
+        "RLIMIT_NPROC=4096:8192",
+        "RLIMIT_NOFILE=65536",
         0
     };
     char *mapped_volumes[2];
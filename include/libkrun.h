@@ -1,4 +1,6 @@
 #include <inttypes.h>
+#include <stdbool.h>
+#include <stddef.h>
 
 /*
  * Sets the log level for the library.
@@ -17,6 +19,144 @@
  */
 int32_t krun_set_log_level(uint32_t level);
 
+/*
+ * Serves the metrics of the VMM in the OpenMetrics format, over HTTP, to every connection accepted
+ * on a listening socket. The metrics are process-wide, and independent of any context.
+ *
+ * Arguments:
+ *  "listener_fd" - a listening stream socket (TCP or Unix). Its ownership is transferred to the
+ *                  library.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_serve_metrics(int listener_fd);
+
+/*
+ * Periodically writes the metrics of the VMM in the OpenMetrics format to a file. The file is
+ * replaced atomically, so readers always observe a complete set of metrics. The metrics are
+ * process-wide, and independent of any context.
+ *
+ * Arguments:
+ *  "path"        - the path to the file to be written.
+ *  "interval_ms" - the time between writes, in milliseconds.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_write_metrics(const char *path, uint32_t interval_ms);
+
+/*
+ * Records security-relevant events (shares and devices attached, network listeners and flows
+ * opened, shutdown) to an audit log, as one JSON object per line. Every record has, at least, a
+ * "timestamp" field, in milliseconds since the UNIX epoch, and an "event" field. The audit log is
+ * process-wide, and independent of any context.
+ *
+ * Arguments:
+ *  "fd" - a file descriptor open for writing. Its ownership is transferred to the library.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_audit_fd(int fd);
+
+/*
+ * Records security-relevant events to an audit log file, as described in "krun_set_audit_fd".
+ * The file is created if it doesn't exist, and records are appended to it.
+ *
+ * Arguments:
+ *  "path" - the path to the audit log file.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_audit_file(const char *path);
+
+/* Flags of "struct krun_host_caps". */
+#define KRUN_HOST_HYPERVISOR (1 << 0) /* KVM or HVF can be used by this process */
+#define KRUN_HOST_NESTED     (1 << 1) /* guests can run hypervisors of their own */
+#define KRUN_HOST_SEV        (1 << 2) /* AMD SEV is enabled in KVM */
+#define KRUN_HOST_TDX        (1 << 3) /* Intel TDX is enabled in KVM */
+
+/* Virtualization capabilities of the host, as reported by "krun_check_host". */
+struct krun_host_caps {
+    /* A combination of KRUN_HOST_* flags. */
+    uint32_t flags;
+    /* The maximum number of vCPUs of a microVM. */
+    uint32_t max_vcpus;
+    /* The maximum number of memory slots, or zero if the hypervisor doesn't limit them. */
+    uint32_t max_memslots;
+    /* The maximum amount of RAM of a microVM, in MiB, bounded by the memory of the host. */
+    uint64_t max_mem_mib;
+};
+
+/*
+ * Probes the virtualization capabilities of the host, to diagnose setup issues before trying to
+ * boot a microVM. Nothing is left behind, so this can be called at any time, without a context.
+ *
+ * Arguments:
+ *  "caps" - if not NULL, filled in with the capabilities of the host, even if the hypervisor is
+ *           unusable.
+ *
+ * Returns:
+ *  Zero if the hypervisor is usable, or a negative error number telling why it isn't (like
+ *  -ENOENT if /dev/kvm doesn't exist, or -EACCES if this process isn't allowed to use it).
+ */
+int32_t krun_check_host(struct krun_host_caps *caps);
+
+/*
+ * Sets where libkrunfw is searched for. libkrunfw isn't linked to libkrun, but located and loaded
+ * as the first configuration context is created, so this must be called before. The entries of the
+ * search path are tried first, then the ones in the KRUN_KRUNFW_PATH environment variable,
+ * colon-separated, and last the search path of the dynamic loader. An entry is either a directory,
+ * holding the library under one of its sonames, or the library itself.
+ *
+ * Arguments:
+ *  "search_path" - a NULL-terminated array of directories or libraries, or NULL to only search
+ *                  KRUN_KRUNFW_PATH and the search path of the dynamic loader.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EBUSY if libkrunfw was already loaded.
+ */
+int32_t krun_set_krunfw_search_path(const char *const search_path[]);
+
+/*
+ * Sets the range of versions of libkrunfw accepted. The libraries found outside of it are skipped.
+ * Like the search path, it must be set before the first configuration context is created.
+ *
+ * Arguments:
+ *  "min_version" - the oldest version accepted. The versions older than the oldest one supported
+ *                  by libkrun are never accepted.
+ *  "max_version" - the newest version accepted, or zero for no maximum.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EBUSY if libkrunfw was already loaded.
+ */
+int32_t krun_set_krunfw_version(uint32_t min_version, uint32_t max_version);
+
+/*
+ * Returns the version of libkrunfw, locating and loading it if it wasn't yet. A failure isn't
+ * remembered, so the search path can be fixed before trying again.
+ *
+ * Returns:
+ *  The version of libkrunfw on success or a negative error number on failure: -ENOENT if it can't
+ *  be found, or, when only unusable libraries were found, -ENOTSUP if the first of them is of a
+ *  version outside the accepted range, or -ENOEXEC if it isn't a valid libkrunfw.
+ */
+int32_t krun_get_krunfw_version();
+
+/*
+ * States of a configuration context. A context starts in KRUN_STATE_CONFIGURING, moves to
+ * KRUN_STATE_RUNNING once "krun_start_enter" is called on it, and to KRUN_STATE_STOPPED if the
+ * microVM fails to start or the VMM stops servicing it.
+ *
+ * All the functions in this library may be called from multiple threads. Functions modifying the
+ * configuration of a context fail with -EBUSY once the context has left KRUN_STATE_CONFIGURING.
+ */
+#define KRUN_STATE_CONFIGURING 0
+#define KRUN_STATE_RUNNING     1
+#define KRUN_STATE_STOPPED     2
+
 /*
  * Creates a configuration context.
  *
@@ -26,7 +166,7 @@ int32_t krun_set_log_level(uint32_t level);
 int32_t krun_create_ctx();
 
 /*
- * Frees an existing configuration context.
+ * Frees an existing configuration context. Contexts in KRUN_STATE_RUNNING can't be freed.
  *
  * Arguments:
  *  "ctx_id" - the configuration context ID.
@@ -36,6 +176,45 @@ int32_t krun_create_ctx();
  */
 int32_t krun_free_ctx(uint32_t ctx_id);
 
+/*
+ * Returns the state of a configuration context.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  One of the KRUN_STATE_* values on success or a negative error number on failure.
+ */
+int32_t krun_get_ctx_state(uint32_t ctx_id);
+
+/*
+ * States of a microVM. It's KRUN_VM_STATE_CONFIGURED until "krun_start_enter" builds it, moving to
+ * KRUN_VM_STATE_BOOTING, then to KRUN_VM_STATE_RUNNING once its vCPUs are started. It moves between
+ * KRUN_VM_STATE_RUNNING and KRUN_VM_STATE_PAUSED when paused and resumed through the monitor, to
+ * KRUN_VM_STATE_SHUTTING_DOWN once the guest is asked to shut down or the microVM to stop, and to
+ * KRUN_VM_STATE_STOPPED once it stops, or if it fails to boot. A microVM frozen by its idle policy
+ * is still KRUN_VM_STATE_RUNNING, as it's thawed on the first sign of activity.
+ */
+#define KRUN_VM_STATE_CONFIGURED    0
+#define KRUN_VM_STATE_BOOTING       1
+#define KRUN_VM_STATE_RUNNING       2
+#define KRUN_VM_STATE_PAUSED        3
+#define KRUN_VM_STATE_SHUTTING_DOWN 4
+#define KRUN_VM_STATE_STOPPED       5
+
+/*
+ * Returns the state of the microVM of a configuration context, which can be queried from any thread
+ * while the microVM runs. The reason a microVM stopped is available from "krun_get_exit_status",
+ * if it has an exit handle.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  One of the KRUN_VM_STATE_* values on success or a negative error number on failure.
+ */
+int32_t krun_get_vm_state(uint32_t ctx_id);
+
 /*
  * Sets the basic configuration parameters for the microVM.
  *
@@ -49,6 +228,145 @@ int32_t krun_free_ctx(uint32_t ctx_id);
  */
 int32_t krun_set_vm_config(uint32_t ctx_id, uint8_t num_vcpus, uint32_t ram_mib);
 
+/*
+ * Lays the vCPUs out in sockets, cores and threads, as exposed to the guest through the CPUID
+ * leaves on x86_64 and the "cpu-map" node of the device tree on aarch64. By default, all the
+ * vCPUs are in a single socket.
+ *
+ * This sets the number of vCPUs to "sockets * cores_per_socket * threads_per_core", which
+ * "krun_set_vm_config" must then be called with, if at all.
+ *
+ * Arguments:
+ *  "ctx_id"           - the configuration context ID.
+ *  "sockets"          - the number of sockets.
+ *  "cores_per_socket" - the number of cores in each socket; with several sockets, it must be a
+ *                       power of 2.
+ *  "threads_per_core" - the number of threads in each core, 1 or 2.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_cpu_topology(uint32_t ctx_id, uint8_t sockets, uint8_t cores_per_socket,
+                              uint8_t threads_per_core);
+
+/*
+ * Replaces the kernel bundle provided by libkrunfw with a kernel read from a file. The kernel is
+ * read, and its digest checked, as the microVM is built, so the file can't change behind the
+ * check.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "fd"         - a file descriptor of the file holding the kernel. Its ownership is transferred
+ *                 to libkrun.
+ *  "offset"     - the offset of the kernel in the file.
+ *  "size"       - the size of the kernel, a non-zero multiple of the page size.
+ *  "guest_addr" - the page-aligned guest address the kernel is placed at.
+ *  "sha256"     - the 32-byte SHA-256 digest the kernel must match, or NULL not to check it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_kernel_bundle_fd(uint32_t ctx_id, int fd, uint64_t offset, size_t size,
+                                  uint64_t guest_addr, const uint8_t *sha256);
+
+/*
+ * Replaces the kernel bundle provided by libkrunfw with a copy of a kernel held in memory. The
+ * buffer only has to be valid for the duration of the call.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "buf"        - the kernel.
+ *  "size"       - the size of the kernel, a non-zero multiple of the page size.
+ *  "guest_addr" - the page-aligned guest address the kernel is placed at.
+ *  "sha256"     - the 32-byte SHA-256 digest the kernel must match, or NULL not to check it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_kernel_bundle(uint32_t ctx_id, const uint8_t *buf, size_t size,
+                               uint64_t guest_addr, const uint8_t *sha256);
+
+/*
+ * Registers a kernel read from a file as a flavor, such as a general-purpose kernel or a minimal
+ * one, which each microVM can then pick with krun_set_kernel_flavor(). The flavors aren't tied to
+ * a configuration context, and registering a flavor again replaces it for the contexts picking it
+ * from then on. The kernel is read, and its digest checked, as each microVM picking it is built.
+ *
+ * Arguments:
+ *  "name"       - the name of the flavor.
+ *  "fd"         - a file descriptor of the file holding the kernel. Its ownership is transferred
+ *                 to libkrun.
+ *  "offset"     - the offset of the kernel in the file.
+ *  "size"       - the size of the kernel, a non-zero multiple of the page size.
+ *  "guest_addr" - the page-aligned guest address the kernel is placed at.
+ *  "sha256"     - the 32-byte SHA-256 digest the kernel must match, or NULL not to check it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_register_kernel_flavor_fd(const char *name, int fd, uint64_t offset, size_t size,
+                                       uint64_t guest_addr, const uint8_t *sha256);
+
+/*
+ * Registers a copy of a kernel held in memory as a flavor, which each microVM can then pick with
+ * krun_set_kernel_flavor(). The buffer only has to be valid for the duration of the call.
+ *
+ * Arguments:
+ *  "name"       - the name of the flavor.
+ *  "buf"        - the kernel.
+ *  "size"       - the size of the kernel, a non-zero multiple of the page size.
+ *  "guest_addr" - the page-aligned guest address the kernel is placed at.
+ *  "sha256"     - the 32-byte SHA-256 digest the kernel must match, or NULL not to check it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_register_kernel_flavor(const char *name, const uint8_t *buf, size_t size,
+                                    uint64_t guest_addr, const uint8_t *sha256);
+
+/*
+ * Replaces the kernel bundle provided by libkrunfw with the one registered as a flavor, placed at
+ * the guest address it was registered with.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "name"   - the name of the flavor.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if no kernel is registered as
+ *  the flavor.
+ */
+int32_t krun_set_kernel_flavor(uint32_t ctx_id, const char *name);
+
+/*
+ * Trusts an Ed25519 public key to sign the kernel bundle. Once a key is trusted, the microVM only
+ * boots a kernel whose signature, set with krun_set_kernel_signature(), matches one of the trusted
+ * keys. The signature covers the whole kernel bundle, as loaded, and is checked before the kernel
+ * is placed in the guest memory. Can be called several times to trust several keys.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "key"    - the 32-byte public key, as encoded in RFC 8032.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EINVAL if the key isn't valid.
+ */
+int32_t krun_add_trusted_kernel_key(uint32_t ctx_id, const uint8_t *key);
+
+/*
+ * Sets the detached Ed25519 signature of the kernel bundle, checked against the keys trusted with
+ * krun_add_trusted_kernel_key(). Starting the microVM fails if the signature is set but no key is
+ * trusted, or a key is trusted but no signature is set.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "signature" - the 64-byte signature, as encoded in RFC 8032.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_kernel_signature(uint32_t ctx_id, const uint8_t *signature);
+
 /*
  * Sets the path to be use as root for the microVM.
  *
@@ -75,77 +393,1974 @@ int32_t krun_set_root(uint32_t ctx_id, const char *root_path);
  */
 int32_t krun_set_mapped_volumes(uint32_t ctx_id, char *const mapped_volumes[]);
 
+/* Flags for "krun_add_share". */
+#define KRUN_SHARE_READ_ONLY (1 << 0)
+
+/* Mount propagation types for "krun_add_share", as described in mount_namespaces(7). */
+#define KRUN_PROPAGATION_PRIVATE    0
+#define KRUN_PROPAGATION_SHARED     1
+#define KRUN_PROPAGATION_SLAVE      2
+#define KRUN_PROPAGATION_UNBINDABLE 3
+
 /*
- * Configures a map of host to guest TCP ports for the microVM.
+ * Shares a host directory with the guest through a virtio-fs device of its own, mounted by the
+ * init process of the guest at an arbitrary path before the workload is executed. Unlike the
+ * mapped volumes, shares can be nested: the shares are mounted by ascending "order", and among
+ * those with the same order, the shallower guest paths are mounted first, so a share is mounted
+ * before the ones nested in it.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "tag"         - the tag of the virtio-fs device, made of up to 36 ASCII alphanumerics, '-' and
+ *                  '_'. It must be unique.
+ *  "host_path"   - the path of the directory to be shared.
+ *  "guest_path"  - the absolute path the share is mounted at, created if missing. It must not be
+ *                  "/", nor contain "..", ',', ':' or '"'.
+ *  "flags"       - KRUN_SHARE_READ_ONLY to mount the share read-only, or zero.
+ *  "propagation" - one of KRUN_PROPAGATION_*.
+ *  "order"       - where the share stands in the mount order.
+ *
+ * Returns:
+ *  Zero on success, -EEXIST if another share has the same tag or guest path, or another negative
+ *  error number on failure.
+ */
+int32_t krun_add_share(uint32_t ctx_id,
+                       const char *tag,
+                       const char *host_path,
+                       const char *guest_path,
+                       uint32_t flags,
+                       uint32_t propagation,
+                       uint32_t order);
+
+/* Flags for "krun_set_share_quota". */
+#define KRUN_SHARE_QUOTA_ENFORCE (1 << 0)
+
+/*
+ * Sets the quota of a virtio-fs device, so "statfs" in the guest reports its capacity rather than
+ * the totals of the host file system. The free space reported never exceeds the one left on the
+ * host. The device may be added after its quota is set.
+ *
+ * With KRUN_SHARE_QUOTA_ENFORCE, the writes that would take the share beyond its capacity fail
+ * with ENOSPC. The cap is approximate: the usage of the share is measured again every couple of
+ * seconds, with the data written in between added to it, and the writes through DAX mappings
+ * aren't accounted for.
  *
  * Arguments:
  *  "ctx_id"         - the configuration context ID.
- *  "mapped_volumes" - an array of string pointers with format "host_port:guest_port"
+ *  "tag"            - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *  "capacity_bytes" - the size of the share reported to the guest. It must not be zero.
+ *  "flags"          - KRUN_SHARE_QUOTA_ENFORCE to cap the writes to the share, or zero.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_port_map(uint32_t ctx_id, char *const port_map[]);
+int32_t krun_set_share_quota(uint32_t ctx_id,
+                             const char *tag,
+                             uint64_t capacity_bytes,
+                             uint32_t flags);
+
+/* Flags for "krun_set_share_io". */
+#define KRUN_SHARE_IO_DIRECT      (1 << 0)
+#define KRUN_SHARE_IO_PREALLOCATE (1 << 1)
 
 /*
- * Configures a map of rlimits to be set in the guest before starting the isolated binary.
+ * Sets how a virtio-fs device does the I/O of the files it shares, for workloads such as databases
+ * which cache their data themselves. The device may be added after its I/O is configured.
+ *
+ * With KRUN_SHARE_IO_DIRECT, the data of the regular files bypasses the page cache of the host, so
+ * it's only cached by the guest. On Linux, the files are opened again with O_DIRECT, which serves
+ * the requests aligned to 4 KiB; the others, and the files of host file systems without O_DIRECT,
+ * still go through the page cache. On macOS, F_NOCACHE is set on the files instead.
+ *
+ * With KRUN_SHARE_IO_PREALLOCATE, the space the files are extended by when the guest truncates
+ * them is allocated right away, rather than left sparse, where the host file system supports it.
  *
  * Arguments:
- *  "ctx_id"  - the configuration context ID.
- *  "rlimits" - an array of string pointers with format "RESOURCE=RLIM_CUR:RLIM_MAX".
+ *  "ctx_id" - the configuration context ID.
+ *  "tag"    - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *  "flags"  - a combination of KRUN_SHARE_IO_*, or zero to restore the defaults.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_rlimits(uint32_t ctx_id, char *const rlimits[]);
+int32_t krun_set_share_io(uint32_t ctx_id, const char *tag, uint32_t flags);
+
+/* Policies for "krun_set_share_escape_policy". */
+#define KRUN_SHARE_ESCAPE_OFF    0
+#define KRUN_SHARE_ESCAPE_AUDIT  1
+#define KRUN_SHARE_ESCAPE_STRICT 2
 
 /*
- * Sets the working directory for the executable to be run inside the microVM.
+ * Sets how a virtio-fs device handles the attempts of the guest to reach outside of its share: a
+ * name with a slash, which goes through the directories and symlinks of the host rather than the
+ * ones of the guest, ".." in the root of the share, or ".." leading outside of it once the host
+ * moved a directory elsewhere. The device may be added after its policy is set.
+ *
+ * With KRUN_SHARE_ESCAPE_AUDIT, the attempts are recorded as "share_escape_attempt" events in the
+ * audit log, and allowed. With KRUN_SHARE_ESCAPE_STRICT, they're recorded and denied with EACCES,
+ * and on Linux, the lookups are done with openat2 and RESOLVE_NO_SYMLINKS, so the host kernel
+ * refuses to follow any symlink, where it supports it. KRUN_SHARE_ESCAPE_OFF, the default,
+ * resolves the names as the guest passes them.
  *
  * Arguments:
- *  "ctx_id"        - the configuration context ID.
- *  "workdir_path"  - the path to the working directory, relative to the root configured with
- *                    "krun_set_root".
+ *  "ctx_id" - the configuration context ID.
+ *  "tag"    - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *  "policy" - one of KRUN_SHARE_ESCAPE_*.
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_workdir(uint32_t ctx_id,
-                         const char *workdir_path);
+int32_t krun_set_share_escape_policy(uint32_t ctx_id, const char *tag, uint32_t policy);
 
 /*
- * Sets the path to the executable to be run inside the microVM, the arguments to be passed to the
- * executable, and the environment variables to be configured in the context of the executable.
+ * Makes the host cache the metadata and the contents of a virtio-fs share, for shares backed by a
+ * remote file system such as NFS or SMB, where every access costs a round trip to the server. The
+ * device may be added after its cache is configured.
+ *
+ * The lookups and the attributes are cached for "metadata_ttl_ms", and the contents read for
+ * "content_ttl_ms", in blocks of 64 KiB, each bounded in size by dropping the least recently used
+ * entries. The changes made through the share drop what they affect right away, but the ones made
+ * on the host, or on the server by other clients, only show up once the entries expire, or once
+ * "krun_share_cache_invalidate" is called. The files opened for direct I/O, and the mappings of
+ * DAX, bypass the cache of the contents.
+ *
+ * Arguments:
+ *  "ctx_id"            - the configuration context ID.
+ *  "tag"               - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *  "metadata_ttl_ms"   - how long the metadata is cached, or zero not to cache it.
+ *  "max_entries"       - the most lookups, and the most attributes, cached.
+ *  "content_ttl_ms"    - how long the contents are cached, or zero not to cache them.
+ *  "max_content_bytes" - the most bytes of contents cached.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_share_cache(uint32_t ctx_id,
+                             const char *tag,
+                             uint32_t metadata_ttl_ms,
+                             uint32_t max_entries,
+                             uint32_t content_ttl_ms,
+                             uint64_t max_content_bytes);
+
+/*
+ * Drops everything the host cached of a virtio-fs share, for the changes made outside of the
+ * guest to show up right away. It may be called while the microVM runs.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "tag"    - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if the share has no cache, or another negative error number on failure.
+ */
+int32_t krun_share_cache_invalidate(uint32_t ctx_id, const char *tag);
+
+/*
+ * Experimental. Takes the read-only DAX mappings of a virtio-fs share from a content-addressed
+ * store of the host, shared by the microVMs, cutting the memory they use when each has its own
+ * copy of the same base image. The device may be added after its store is set.
+ *
+ * Each chunk the guest maps read-only is copied, once, to a file of "store_dir" named after the
+ * SHA-256 of its contents, and that file is mapped instead, so the identical chunks of the images
+ * of all the microVMs using the store take their pages only once in the page cache of the host.
+ * A file changed on the host after its chunks were mapped keeps showing its previous contents to
+ * the guests mapping them, so the store is only meant for shares which don't change. The chunks
+ * may be removed from the store at any time, even while they're mapped.
  *
  * Arguments:
  *  "ctx_id"    - the configuration context ID.
- *  "exec_path" - the path to the executable, relative to the root configured with "krun_set_root".
- *  "argv"      - an array of string pointers to be passed as arguments.
- *  "envp"      - an array of string pointers to be injected as environment variables into the
- *                context of the executable. If NULL, it will auto-generate an array collecting the
- *                the variables currently present in the environment.
+ *  "tag"       - the tag of the virtio-fs device, or "/dev/root" for the root.
+ *  "store_dir" - the directory of the store, created if it doesn't exist. It must be owned by the
+ *                user of the process, and not writable by others.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -ENOTSUP if the host doesn't support
+ *  DAX, or -EACCES if the directory can be written by others.
+ */
+int32_t krun_set_share_dedup(uint32_t ctx_id, const char *tag, const char *store_dir);
+
+/*
+ * Configures a map of host to guest TCP ports for the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "mapped_volumes" - an array of string pointers with format "host_port:guest_port"
  *
  * Returns:
  *  Zero on success or a negative error number on failure.
  */
-int32_t krun_set_exec(uint32_t ctx_id,
-                      const char *exec_path,
-                      char *const argv[],
-                      char *const envp[]);
+int32_t krun_set_port_map(uint32_t ctx_id, char *const port_map[]);
 
 /*
- * Starts and enters the microVM with the configured parameters. The VMM will attempt to take over
- * stdin/stdout to manage them on behalf of the process running inside the isolated environment,
- * simulating that the latter has direct control of the terminal.
+ * Boots the microVM on demand: "krun_start_enter" first listens on the host ports of the port map,
+ * and only boots the microVM once a connection arrives on one of them, or on one of the sockets
+ * handed over with "krun_add_listen_fd" or "krun_add_sd_listen_fds", so no microVM runs until it's
+ * needed. The connections wait in the backlog of their socket until the workload listens on its
+ * port, so the first one is only accepted once the workload is ready to serve it.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "listen_addr" - the IPv4 or IPv6 address the host ports are bound to, such as "0.0.0.0".
  *
- * This function consumes the configuration pointed by the context ID.
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_boot_on_demand(uint32_t ctx_id, const char *listen_addr);
+
+/* Flags of "krun_set_vsock_guest_cid". */
+#define KRUN_VSOCK_CID_CLAIM (1 << 0)
+
+/* The wildcard CID, asking for the lowest CID not claimed by another microVM. */
+#define KRUN_VSOCK_CID_ANY 0xffffffff
+
+/*
+ * Sets the CID of the guest on the vsock device, 3 by default. The vsock device isn't backed by
+ * the vsock of the host kernel, so microVMs with the same CID don't interfere with each other, but
+ * an embedder addressing its microVMs by CID can claim them to detect collisions.
+ *
+ * A claimed CID is locked, in the temporary directory of the host, until the context is freed or
+ * the process exits, so microVMs claiming the same CID from that directory fail. CID 1
+ * (VMADDR_CID_LOCAL) is accepted, but never claimed, as it only ever designates a guest to itself.
  *
  * Arguments:
  *  "ctx_id" - the configuration context ID.
+ *  "cid"    - the CID of the guest, other than 0, 2 (the host) or KRUN_VSOCK_CID_ANY to claim the
+ *             lowest free CID from 3.
+ *  "flags"  - KRUN_VSOCK_CID_CLAIM to claim the CID.
  *
  * Returns:
- *  This function only returns if an error happens before starting the microVM. Otherwise, the
- *  VMM assumes it has full control of the process, and will call to exit() once the microVM shuts
- *  down.
+ *  Zero on success, -EADDRINUSE if another microVM claimed the CID, -EADDRNOTAVAIL if no CID up to
+ *  65535 is free, or another negative error number on failure.
  */
-int32_t krun_start_enter(uint32_t ctx_id);
+int32_t krun_set_vsock_guest_cid(uint32_t ctx_id, uint32_t cid, uint32_t flags);
+
+/*
+ * Gets the CID of the guest on the vsock device, including the one assigned in place of
+ * KRUN_VSOCK_CID_ANY. Can also be called once the microVM is running.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "cid"    - filled in with the CID of the guest.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_get_vsock_guest_cid(uint32_t ctx_id, uint32_t *cid);
+
+/* Flags of "krun_add_listen_fd". */
+#define KRUN_LISTEN_FD_CONSOLE (1 << 0)
+
+/*
+ * Hands over a listening socket, for socket-activated microVMs. A TCP or Unix socket is used by the
+ * vsock device, rather than binding a new one, when the guest listens on the same port (after the
+ * port map) or path. With KRUN_LISTEN_FD_CONSOLE, a Unix socket is instead attached to the console:
+ * starting the microVM waits for a client to connect to it, which the console then exchanges data
+ * with.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a listening socket, owned by the library from then on, even on failure.
+ *  "flags"  - KRUN_LISTEN_FD_CONSOLE to attach the socket to the console.
+ *
+ * Returns:
+ *  Zero on success, -ENOTSOCK if "fd" isn't a listening TCP or Unix stream socket, -EADDRINUSE if
+ *  another socket was handed over for the same port or path, or another negative error number on
+ *  failure.
+ */
+int32_t krun_add_listen_fd(uint32_t ctx_id, int fd, uint32_t flags);
+
+/*
+ * Hands over the listening sockets passed by the service manager, as described by LISTEN_PID,
+ * LISTEN_FDS and LISTEN_FDNAMES (see sd_listen_fds(3)), as with "krun_add_listen_fd". The socket
+ * named "console", if any, is attached to the console. The variables are removed from the
+ * environment, and the sockets are set close-on-exec.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  The number of sockets handed over, zero if none was passed to this process, or a negative error
+ *  number on failure.
+ */
+int32_t krun_add_sd_listen_fds(uint32_t ctx_id);
+
+/*
+ * Configures a map of rlimits to be set in the guest before starting the isolated binary.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "rlimits" - an array of string pointers with format "RESOURCE=RLIM_CUR:RLIM_MAX". "RESOURCE"
+ *              is either the number of the resource on Linux or its name, like "RLIMIT_NOFILE"
+ *              or "NOFILE". The limits are either numbers or "unlimited", and a single limit,
+ *              like in "NOFILE=65536", sets both. The last pointer must be NULL.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_rlimits(uint32_t ctx_id, char *const rlimits[]);
+
+/*
+ * Configures the limits of a cgroup v2 the init process of the guest moves the isolated binary
+ * to, before starting it. The controllers of the limits are enabled as needed, and a limit the
+ * guest kernel doesn't support is reported on the console, and ignored.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "limits" - an array of string pointers with format "controller.file=value", like
+ *             "memory.max=512M", "pids.max=1024" or "cpu.max=50000 100000". The supported
+ *             controllers are "cpu", "cpuset", "hugetlb", "io", "memory" and "pids". The last
+ *             pointer must be NULL.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_workload_cgroup(uint32_t ctx_id, char *const limits[]);
+
+/*
+ * Sets the working directory for the executable to be run inside the microVM.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "workdir_path"  - the path to the working directory, relative to the root configured with
+ *                    "krun_set_root".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_workdir(uint32_t ctx_id,
+                         const char *workdir_path);
+
+/*
+ * Sets the path to the executable to be run inside the microVM, the arguments to be passed to the
+ * executable, and the environment variables to be configured in the context of the executable.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "exec_path" - the path to the executable, relative to the root configured with "krun_set_root".
+ *  "argv"      - an array of string pointers to be passed as arguments.
+ *  "envp"      - an array of string pointers to be injected as environment variables into the
+ *                context of the executable. If NULL, it will auto-generate an array collecting the
+ *                the variables currently present in the environment.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec(uint32_t ctx_id,
+                      const char *exec_path,
+                      char *const argv[],
+                      char *const envp[]);
+
+/*
+ * Flags for "krun_set_exec_env".
+ */
+#define KRUN_EXEC_LOGIN_SHELL (1 << 0)
+
+/*
+ * Sets up the environment the init process of the guest executes the workload in, so embedders
+ * don't have to wrap their commands in a shell to get it.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "flags"  - KRUN_EXEC_LOGIN_SHELL to execute the workload through a login shell, "/bin/sh -l",
+ *             so the profiles of the guest, such as /etc/profile, are applied to its environment.
+ *             The executable and its arguments are passed to the shell untouched.
+ *  "path"   - the search path of the workload, overriding the one set with "krun_set_exec" and
+ *             by the profiles, or NULL to leave it alone. It can't contain double quotes or
+ *             control characters.
+ *  "locale" - the locale of the workload, such as "C.UTF-8", set as LANG with LC_ALL cleared, or
+ *             NULL to leave it alone.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec_env(uint32_t ctx_id, uint32_t flags, const char *path, const char *locale);
+
+/*
+ * Sets the user the init process of the guest executes the workload as, rather than root. The
+ * home directory of the user, from /etc/passwd of the guest, and its runtime directory,
+ * /run/user/<uid>, are created if missing, and set as HOME and XDG_RUNTIME_DIR, along with USER
+ * and LOGNAME when the user has an entry. The services of "krun_add_service" keep running as root.
+ * If the guest can't switch to the user, the microVM stops rather than running the workload as
+ * root.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "uid"     - the user ID.
+ *  "gid"     - the primary group ID.
+ *  "groups"  - an array of the supplementary group IDs, replacing those of root.
+ *  "ngroups" - the number of supplementary groups, at most 32. "groups" may be NULL if it's zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec_user(uint32_t ctx_id,
+                           uint32_t uid,
+                           uint32_t gid,
+                           const uint32_t *groups,
+                           size_t ngroups);
+
+/*
+ * Security profiles of the workload, for "krun_set_exec_security_profile".
+ */
+#define KRUN_SECURITY_PRIVILEGED 0
+#define KRUN_SECURITY_DEFAULT    1
+#define KRUN_SECURITY_RESTRICTED 2
+
+/*
+ * Sets the privileges the init process of the guest drops before executing the workload to those
+ * of a named profile, replacing any set with "krun_set_exec_capabilities" and
+ * "krun_set_exec_seccomp". By default, the workload keeps all the privileges of root.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "profile" - KRUN_SECURITY_PRIVILEGED to keep all the privileges, KRUN_SECURITY_DEFAULT to keep
+ *              the default capabilities of OCI containers and deny the system calls of the
+ *              KRUN_SECCOMP_DEFAULT profile, or KRUN_SECURITY_RESTRICTED to keep no capability, set
+ *              no_new_privs, and deny the system calls of the KRUN_SECCOMP_STRICT profile.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec_security_profile(uint32_t ctx_id, uint32_t profile);
+
+/*
+ * Sets the capabilities the workload keeps, dropping all the others from its sets and from the
+ * bounding set, so they can't be regained through setuid executables.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "caps"   - an array of string pointers, the names of the capabilities, such as "CAP_NET_RAW",
+ *             with or without the "CAP_" prefix. The last pointer must be NULL. An empty array
+ *             drops all the capabilities, and NULL keeps them all.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec_capabilities(uint32_t ctx_id, const char *const caps[]);
+
+/*
+ * Seccomp profiles and flags, for "krun_set_exec_seccomp".
+ */
+#define KRUN_SECCOMP_UNCONFINED 0
+#define KRUN_SECCOMP_DEFAULT    1
+#define KRUN_SECCOMP_STRICT     2
+#define KRUN_EXEC_NO_NEW_PRIVS  (1 << 0)
+
+/*
+ * Sets the system calls denied to the workload, failing with EPERM, and whether it can gain
+ * privileges when executing programs. The filter is inherited by all the processes of the
+ * workload, but not by the services of "krun_add_service".
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "profile" - KRUN_SECCOMP_UNCONFINED to deny nothing, KRUN_SECCOMP_DEFAULT to deny the system
+ *              calls affecting the whole guest, such as loading modules, mounting, rebooting,
+ *              setting the clock or using BPF, or KRUN_SECCOMP_STRICT to also deny tracing other
+ *              processes and entering or creating namespaces.
+ *  "flags"   - KRUN_EXEC_NO_NEW_PRIVS to set no_new_privs, so setuid executables and file
+ *              capabilities grant nothing.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_exec_seccomp(uint32_t ctx_id, uint32_t profile, uint32_t flags);
+
+/*
+ * Connects the console of the microVM to the embedder through callbacks instead of the stdin and
+ * stdout of the process. Input for the guest must be provided with "krun_console_write".
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "output_cb" - a function to be called, from the VMM thread, with every chunk of data written by
+ *                the guest to its console.
+ *  "opaque"    - a pointer to be passed unmodified as the first argument of "output_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_callbacks(uint32_t ctx_id,
+                                   void (*output_cb)(void *opaque, const uint8_t *buf, size_t len),
+                                   void *opaque);
+
+/*
+ * Queues data to be read by the guest from its console. Only valid for contexts configured with
+ * "krun_set_console_callbacks", and may be called from any thread, before or after the microVM
+ * has been started.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "buf"    - the data to be queued.
+ *  "len"    - the length of the data in bytes.
+ *
+ * Returns:
+ *  The number of bytes queued, which may be less than "len" if the internal buffer is full, or a
+ *  negative error number on failure.
+ */
+int32_t krun_console_write(uint32_t ctx_id, const uint8_t *buf, size_t len);
+
+/*
+ * Flags for "krun_set_console_output".
+ */
+#define KRUN_CONSOLE_LINE_BUFFERED (1 << 0)
+#define KRUN_CONSOLE_TIMESTAMPS    (1 << 1)
+#define KRUN_CONSOLE_STRIP_ANSI    (1 << 2)
+
+/*
+ * Processes the output written by the guest to its console before it reaches stdout, or the
+ * callback set with "krun_set_console_callbacks", so logs forwarded to aggregation systems stay
+ * clean. By default, the output is delivered untouched.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "flags"           - a combination of KRUN_CONSOLE_LINE_BUFFERED, to deliver whole lines only,
+ *                      holding partial lines back until they're completed, KRUN_CONSOLE_TIMESTAMPS,
+ *                      to prefix each line with the time it was written at, and
+ *                      KRUN_CONSOLE_STRIP_ANSI, to strip the ANSI escape sequences.
+ *  "max_line_length" - the length, in bytes, beyond which lines are split, or zero for no limit.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_output(uint32_t ctx_id, uint32_t flags, uint32_t max_line_length);
+
+/*
+ * Flags for "krun_set_console_terminal".
+ */
+#define KRUN_TERMINAL_ALTERNATE_SCREEN (1 << 0)
+
+/*
+ * Sets how the terminal of the process is handed over to the console, when it uses stdin and
+ * stdout. Its settings are saved before it's set to raw mode and restored once the microVM exits,
+ * after resetting the attributes, the cursor and the modes the guest may have left changed, so
+ * the shell the process was started from gets it back in a usable state. Nothing is written to
+ * stdout if it isn't a terminal.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "flags"  - KRUN_TERMINAL_ALTERNATE_SCREEN to run the console on the alternate screen of the
+ *             terminal, so the screen and the scrollback of the shell are left untouched, or zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_terminal(uint32_t ctx_id, uint32_t flags);
+
+/*
+ * Sets a file descriptor telling the console the terminal of the process has been resized, in
+ * place of SIGWINCH, for embedders handling the signal themselves. By default, SIGWINCH is
+ * received through a signalfd on Linux, or a kqueue on macOS, without installing a signal handler.
+ * On Linux, the signal is then blocked in the thread starting the microVM, and so in the threads
+ * it starts, while a SIGWINCH sent to the process may still be delivered to another thread
+ * instead.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor, such as an eventfd or the read end of a pipe, made readable on each
+ *             resize. The library drains it, and stops watching it once it reaches end-of-file.
+ *             Its ownership is transferred to the library.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_resize_fd(uint32_t ctx_id, int fd);
+
+/*
+ * Flags for "krun_set_console_recording".
+ */
+#define KRUN_RECORDING_PAUSED (1 << 0)
+
+/*
+ * Records the session of the console to a file in the asciicast v2 format, as played by
+ * asciinema: the output of the guest, as it writes it, its input, and the changes of the size of
+ * the console, all timestamped. The recording can be paused and resumed at any time with
+ * "krun_set_console_recording_enabled".
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor open for writing. Its ownership is transferred to the library.
+ *  "flags"  - KRUN_RECORDING_PAUSED to start with the recording paused, or zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_recording(uint32_t ctx_id, int fd, uint32_t flags);
+
+/*
+ * Pauses or resumes the recording of the session of the console, before or while the microVM
+ * runs. Time keeps running while paused, so the recording stays in sync with the session.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "enabled" - whether to record.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if the console isn't recorded.
+ */
+int32_t krun_set_console_recording_enabled(uint32_t ctx_id, bool enabled);
+
+/*
+ * Frame types of the protocol spoken over the socket of "krun_set_console_attach".
+ */
+#define KRUN_ATTACH_FRAME_DATA   0
+#define KRUN_ATTACH_FRAME_RESIZE 1
+#define KRUN_ATTACH_FRAME_DETACH 2
+
+/*
+ * Detaches the console from stdio, and lets clients attach to it, and detach from it, over a Unix
+ * socket while the microVM runs, in the way of screen or tmux. The socket is removed once the
+ * microVM stops.
+ *
+ * Both ways, the data is sent in frames: a byte holding the type of the frame, the length of its
+ * payload as a 16-bit little-endian integer, and the payload.
+ *  - KRUN_ATTACH_FRAME_DATA holds the output of the guest, or input for it.
+ *  - KRUN_ATTACH_FRAME_RESIZE, sent by clients, holds the size of their terminal as two 16-bit
+ *    little-endian integers, the columns followed by the rows.
+ *  - KRUN_ATTACH_FRAME_DETACH, sent by clients, detaches them. Closing the connection does too.
+ *
+ * A single client is attached at a time: a new one detaches the previous one. While no client is
+ * attached, the output of the guest is kept, up to the size of the scrollback, and replayed to the
+ * next one as its first frame, which is empty if there's nothing to replay.
+ *
+ * Arguments:
+ *  "ctx_id"          - the configuration context ID.
+ *  "path"            - the path of the socket, created by the library.
+ *  "scrollback_size" - the number of bytes of output kept while no client is attached, or zero for
+ *                      the default of 64 KiB.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_console_attach(uint32_t ctx_id, const char *path, size_t scrollback_size);
+
+/*
+ * Detaches the client attached to the console set with "krun_set_console_attach", if any.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if the console isn't attachable.
+ */
+int32_t krun_console_detach(uint32_t ctx_id);
+
+/*
+ * Tells whether a client is attached to the console set with "krun_set_console_attach".
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  1 if a client is attached, 0 if not, or a negative error number on failure, -ENOENT if the
+ *  console isn't attachable.
+ */
+int32_t krun_console_is_attached(uint32_t ctx_id);
+
+/*
+ * Forwards the records the guest sends to /dev/log, like those of syslog(3), through a console of
+ * their own, so they don't interleave with the output of the interactive console. Each record is
+ * delivered whole, as it was sent, to a function called from the VMM thread. Records larger than
+ * 64 KiB are truncated.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "log_record_cb" - a function to be called with every record.
+ *  "opaque"        - a pointer to be passed unmodified as the first argument of "log_record_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_log_callback(uint32_t ctx_id,
+                              void (*log_record_cb)(void *opaque, const uint8_t *record, size_t len),
+                              void *opaque);
+
+/*
+ * Like "krun_set_log_callback", but writes the records to a file descriptor instead, each one
+ * preceded by its length as a 32-bit little-endian integer.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor open for writing, like the writing end of a pipe or a stream
+ *             socket. Its ownership is transferred to the library.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_log_fd(uint32_t ctx_id, int fd);
+
+/*
+ * Flags of "krun_set_clipboard", the directions the contents of the clipboard may flow in.
+ */
+#define KRUN_CLIPBOARD_HOST_TO_GUEST (1 << 0)
+#define KRUN_CLIPBOARD_GUEST_TO_HOST (1 << 1)
+
+/*
+ * Shares a clipboard between the embedder and an agent running in the guest, through a console of
+ * its own. The guest finds the device in the KRUN_CLIPBOARD environment variable. Each copy, in
+ * either direction, is a frame of the contents preceded by their length as a 32-bit little-endian
+ * integer; the agent writes the contents copied in the guest to the device, and reads those copied
+ * on the host from it.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "flags"        - the directions allowed, KRUN_CLIPBOARD_HOST_TO_GUEST and/or
+ *                   KRUN_CLIPBOARD_GUEST_TO_HOST.
+ *  "max_size"     - the largest contents copied, in bytes, up to 65532. Larger contents copied
+ *                   in the guest are dropped.
+ *  "clipboard_cb" - a function called from the VMM thread with the contents copied in the guest.
+ *                   Required with KRUN_CLIPBOARD_GUEST_TO_HOST, and may be NULL otherwise.
+ *  "opaque"       - a pointer to be passed unmodified as the first argument of "clipboard_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_clipboard(uint32_t ctx_id,
+                           uint32_t flags,
+                           size_t max_size,
+                           void (*clipboard_cb)(void *opaque, const uint8_t *buf, size_t len),
+                           void *opaque);
+
+/*
+ * Copies contents to the clipboard of the guest, set with "krun_set_clipboard". It can be called
+ * at any time, including while the microVM is running.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "buf"    - the contents copied.
+ *  "len"    - the length of the contents, at most the "max_size" of the clipboard.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -EPERM if the clipboard isn't shared
+ *  from the host to the guest, -EFBIG if the contents are too large, -EAGAIN if the guest hasn't
+ *  read enough of the contents copied before to make room for them, or -ENOENT if the context
+ *  has no clipboard.
+ */
+int32_t krun_clipboard_copy(uint32_t ctx_id, const uint8_t *buf, size_t len);
+
+/*
+ * Binds a character device of the host, such as a serial adapter, to a console of the guest, for
+ * the bytes to go through as they are. The guest finds the devices of the consoles in the
+ * KRUN_SERIALS environment variable, a comma-separated list of "name=device" pairs.
+ *
+ * A terminal is claimed exclusively, set to raw mode and to ignore the carrier detect line, and
+ * given back as it was once the microVM stops. As a console port carries no modem control
+ * signals, DTR and RTS are raised while the microVM runs, and dropped once it stops. The microVM
+ * keeps running if the device is unplugged, its output being dropped from then on.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "name"      - the name of the console, made of ASCII letters, digits, '_' and '-'.
+ *  "path"      - the path to the character device, like "/dev/ttyUSB0".
+ *  "baud_rate" - the baud rate the terminal is set to, or zero to leave it as it is.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -EEXIST if a device is already passed
+ *  through under the name. Failing to open the device fails the start of the microVM.
+ */
+int32_t krun_add_serial_passthrough(uint32_t ctx_id,
+                                    const char *name,
+                                    const char *path,
+                                    uint32_t baud_rate);
+
+/*
+ * Lets the host freeze the filesystems of the guest, so the disk images can be snapshotted by
+ * external backup tooling in a consistent state, through a channel to the init of the guest.
+ *
+ * Freezing syncs every filesystem, so the writes the guest cached also reach the virtio-fs
+ * shares, but only the filesystems backed by a block device are frozen: the workload can keep
+ * writing to the shares. The guest thaws its filesystems on its own once "thaw_timeout_secs" have
+ * elapsed since it froze them, so an embedder failing to thaw them doesn't stall it forever.
+ *
+ * Arguments:
+ *  "ctx_id"            - the configuration context ID.
+ *  "thaw_timeout_secs" - the time after which the guest thaws its filesystems on its own.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -EINVAL if the timeout is zero.
+ */
+int32_t krun_set_fsfreeze(uint32_t ctx_id, uint32_t thaw_timeout_secs);
+
+/*
+ * Freezes the filesystems of the guest, set up with "krun_set_fsfreeze", while the microVM is
+ * running. It blocks until the guest has frozen them, and must be followed by "krun_fs_thaw"
+ * once the snapshot is taken.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "timeout_ms" - the time to wait for the guest to reply.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -ETIMEDOUT if the guest didn't reply in
+ *  time, -ENOENT if the context can't freeze the filesystems, or the error the guest failed with,
+ *  the filesystems it froze being thawed again.
+ */
+int32_t krun_fs_freeze(uint32_t ctx_id, uint32_t timeout_ms);
+
+/*
+ * Thaws the filesystems of the guest, frozen with "krun_fs_freeze".
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "timeout_ms" - the time to wait for the guest to reply.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -ESTALE if the guest had already
+ *  thawed them on its own, so the snapshot may not be consistent, -ETIMEDOUT if the guest didn't
+ *  reply in time, or -ENOENT if the context can't freeze the filesystems.
+ */
+int32_t krun_fs_thaw(uint32_t ctx_id, uint32_t timeout_ms);
+
+/*
+ * Lets the host query the network configuration of the guest, so the embedder can surface it,
+ * through a channel to the init of the guest. It's mostly useful with a virtio-net backend, as
+ * with TSI the guest shares the network of the host.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_guest_network(uint32_t ctx_id);
+
+/*
+ * Queries the network configuration of the guest, set up with "krun_set_guest_network", while the
+ * microVM is running. It blocks until the guest has replied, with the configuration it has at the
+ * time, as a JSON object such as:
+ *
+ * {"addresses":[{"interface":"eth0","address":"192.168.127.2","prefix_len":24},
+ *               {"interface":"eth0","address":"fe80::5054:ff:fe12:3456","prefix_len":64}],
+ *  "routes":[{"destination":"0.0.0.0","prefix_len":0,"gateway":"192.168.127.1",
+ *             "interface":"eth0"},
+ *            {"destination":"192.168.127.0","prefix_len":24,"gateway":null,"interface":"eth0"}],
+ *  "nameservers":["192.168.127.1"]}
+ *
+ * "addresses" are the IPv4 and IPv6 addresses of the interfaces of the guest, but the loopback
+ * one. "routes" are its IPv4 and IPv6 routes, with a null "gateway" for those directly reachable,
+ * and "nameservers" those listed in its "/etc/resolv.conf".
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "timeout_ms" - the time to wait for the guest to reply.
+ *  "buf"        - the buffer to copy the NUL-terminated configuration into. If NULL, nothing is
+ *                 copied.
+ *  "len"        - the size of "buf" in bytes.
+ *
+ * Returns:
+ *  The length of the configuration, without the terminator. Nothing is copied if "buf" is too
+ *  small to hold it along with the terminator. -ETIMEDOUT if the guest didn't reply in time,
+ *  -ENOENT if the context can't query the network configuration, or another negative error number
+ *  on failure.
+ */
+int32_t krun_get_guest_network(uint32_t ctx_id, uint32_t timeout_ms, char *buf, size_t len);
+
+/*
+ * Enables the capture of the output written by the guest kernel to the early console, before the
+ * regular console is available, into a ring buffer. Useful for triaging boot failures.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "size"   - the size of the ring buffer in bytes. Once full, the oldest output is discarded.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_earlycon_buffer(uint32_t ctx_id, size_t size);
+
+/*
+ * Retrieves the early console output captured so far. May be called from any thread, before or
+ * after the microVM has been started.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "buf"    - the buffer to copy the output into. If NULL, nothing is copied.
+ *  "len"    - the size of "buf" in bytes.
+ *
+ * Returns:
+ *  The number of bytes copied or, if "buf" is NULL, the number of bytes available, or a negative
+ *  error number on failure.
+ */
+int32_t krun_get_earlycon_log(uint32_t ctx_id, uint8_t *buf, size_t len);
+
+/*
+ * Sets a wall-clock limit on the runtime of the microVM. Once "max_runtime_secs" have elapsed since
+ * the microVM was started, the guest is asked to shut down (on x86_64, by injecting CTRL+ALT+DEL),
+ * and it's forcibly stopped if it's still running after "grace_period_secs". On architectures
+ * where the guest can't be asked to shut down, it's stopped right away. Either way, the process
+ * exits with code 124.
+ *
+ * Arguments:
+ *  "ctx_id"            - the configuration context ID.
+ *  "max_runtime_secs"  - the maximum runtime in seconds. Must be non-zero.
+ *  "grace_period_secs" - the time given to the guest to shut down, in seconds.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_max_runtime(uint32_t ctx_id, uint32_t max_runtime_secs,
+                             uint32_t grace_period_secs);
+
+/*
+ * Freezes the microVM while it's idle. Once its vCPUs have been halted, and its devices inactive,
+ * for "idle_interval_ms", the vCPUs are paused. They're resumed as soon as there's activity on the
+ * console, vsock or fs devices. Only supported on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"           - the configuration context ID.
+ *  "idle_interval_ms" - the time the microVM must be idle before it's frozen, in milliseconds.
+ *                       Must be non-zero.
+ *  "reclaim_memory"   - whether to ask the host to reclaim the memory of the frozen guest. Its
+ *                       contents are preserved, as pages are written back to swap before being
+ *                       dropped.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_idle_policy(uint32_t ctx_id, uint32_t idle_interval_ms, bool reclaim_memory);
+
+/* Flags of "krun_set_profiling". */
+#define KRUN_PROFILING_PMU (1 << 0)
+
+/*
+ * Profiles the vCPUs from the host, so the workload can be profiled without any tooling in the
+ * guest. Every interval, what the vCPUs did since the previous report is sampled into a new one,
+ * retrieved with "krun_get_profile". Only supported on Linux.
+ *
+ * Each report has the CPU time consumed by the thread of each vCPU, and the exits of the vCPUs to
+ * the VMM by reason. With KRUN_PROFILING_PMU, it also has the cycles and the instructions spent in
+ * the guest by each vCPU, as counted by the PMU of the host. Those need hardware counters and a
+ * perf_event_paranoid allowing the process to count the events of its own threads; they're null in
+ * the reports otherwise.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "interval_ms" - the period of the reports, in milliseconds, at least 100.
+ *  "flags"       - zero or KRUN_PROFILING_PMU.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_profiling(uint32_t ctx_id, uint32_t interval_ms, uint32_t flags);
+
+/*
+ * Retrieves the last report of the profiling of the vCPUs, as a single-line JSON object such as:
+ *  {"sequence":3,"period_us":1000000,
+ *   "vcpus":[{"cpu_time_us":250,"guest_cycles":1200,"guest_instructions":900}],
+ *   "exits":{"io_in":0,"io_out":1,"mmio_read":2,"mmio_write":3}}
+ * May be called from any thread once the microVM has been started.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "buf"    - the buffer to copy the NUL-terminated report into. If NULL, nothing is copied.
+ *  "len"    - the size of "buf" in bytes.
+ *
+ * Returns:
+ *  The length of the report, without the terminator. Nothing is copied if "buf" is too small to
+ *  hold it along with the terminator. -ENOENT if profiling isn't enabled, -EAGAIN if there's no
+ *  report yet, or another negative error number on failure.
+ */
+int32_t krun_get_profile(uint32_t ctx_id, char *buf, size_t len);
+
+/*
+ * Retrieves the timeline of the boot of the microVM, as a single-line JSON object such as:
+ *  {"stages":[{"name":"guest_memory","duration_us":950},{"name":"vm","duration_us":2100},
+ *             {"name":"devices","duration_us":1800},{"name":"start","duration_us":400}],
+ *   "devices":[{"name":"console","duration_us":310},{"name":"fs","duration_us":1200}],
+ *   "skipped":["balloon"],
+ *   "milestones":[{"name":"boot_complete","elapsed_us":98000},
+ *                 {"name":"workload_started","elapsed_us":104000}]}
+ * "stages" are the stages of the build of the microVM, and "devices" the devices attached while
+ * building it, with the time each took. "skipped" are the devices left out of the microVM, as with
+ * the "devices.balloon" option. "milestones" are the points of the boot reported by the guest,
+ * with the time elapsed since the build started: "boot_complete" once the kernel has booted, only
+ * reported on Linux, and "workload_started" once the workload has been started. May be called
+ * from any thread once the microVM has been started.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "buf"    - the buffer to copy the NUL-terminated timeline into. If NULL, nothing is copied.
+ *  "len"    - the size of "buf" in bytes.
+ *
+ * Returns:
+ *  The length of the timeline, without the terminator. Nothing is copied if "buf" is too small to
+ *  hold it along with the terminator. -EAGAIN if the microVM hasn't started being built yet, or
+ *  another negative error number on failure.
+ */
+int32_t krun_get_boot_timeline(uint32_t ctx_id, char *buf, size_t len);
+
+/* Flags of "krun_set_kvm_tuning", each applying one of its tunings. */
+#define KRUN_KVM_DIRTY_RING (1 << 0)
+#define KRUN_KVM_HALT_POLL  (1 << 1)
+#define KRUN_KVM_MLOCK      (1 << 2)
+#define KRUN_KVM_SLOT_PER_REGION (1 << 3)
+#define KRUN_KVM_NO_PIT          (1 << 4)
+
+/*
+ * Tunes KVM to lower the tail latency of the guest, for real-time-ish workloads, at the expense of
+ * the host. The tunings not set in "flags" keep the defaults of the host, and building the microVM
+ * fails if the host doesn't support those set. Only supported on Linux.
+ *
+ * Arguments:
+ *  "ctx_id"             - the configuration context ID.
+ *  "flags"              - a combination of the KRUN_KVM_* flags.
+ *  "dirty_ring_entries" - with KRUN_KVM_DIRTY_RING, the entries of the dirty ring of each vCPU,
+ *                         used in place of the dirty bitmap. Must be a power of two.
+ *  "halt_poll_ns"       - with KRUN_KVM_HALT_POLL, how long a halted vCPU polls for a wakeup
+ *                         before yielding its thread, in nanoseconds, or zero not to poll.
+ *
+ * With KRUN_KVM_MLOCK, the memory of the guest is faulted in and locked in the RAM of the host
+ * before the guest starts, within the RLIMIT_MEMLOCK of the process.
+ *
+ * The regions of the guest memory contiguous in both the guest and the host share a memory slot of
+ * KVM, of which the host has a limited number, reported by "krun_check_host". With
+ * KRUN_KVM_SLOT_PER_REGION, every region gets a slot of its own.
+ *
+ * On x86_64, the guest is given an in-kernel model of the PIT, on top of the timer of the local
+ * APIC and kvmclock. With KRUN_KVM_NO_PIT it's left out, which spares kernels using the timer of
+ * the local APIC from the start from calibrating the PIT at boot. The flag is ignored on other
+ * architectures. There's no HPET either way, as the guest only finds one through ACPI.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_kvm_tuning(uint32_t ctx_id, uint32_t flags, uint32_t dirty_ring_entries,
+                            uint32_t halt_poll_ns);
+
+/*
+ * Records every descriptor chain processed by the virtio devices, along with the responses of the
+ * devices, to a file. The recording can be replayed against a device instance, without a guest, to
+ * reproduce device bugs deterministically. Note the recording holds all the data exchanged with
+ * the guest, so it may contain sensitive information.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - the path to the recording file. It's truncated if it already exists.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_virtio_recording(uint32_t ctx_id, const char *path);
+
+/*
+ * Provisions the guest through the cloud-init NoCloud datasource. The documents are written to a
+ * private host directory, shared with the guest and mounted at "/var/lib/cloud/seed/nocloud", where
+ * cloud-init looks for a local seed. The guest needs to run cloud-init after the init process of
+ * libkrun, like when the executed binary is "/sbin/init".
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "user_data"      - the contents of the "user-data" document.
+ *  "meta_data"      - the contents of the "meta-data" document, or NULL to use a fixed instance ID.
+ *  "network_config" - the contents of the "network-config" document, or NULL to omit it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_cloud_init(uint32_t ctx_id, const char *user_data, const char *meta_data,
+                            const char *network_config);
+
+/*
+ * Adds a kernel module to be loaded by the init process of the guest before executing the
+ * workload, for modules missing from the kernel bundle. The module is copied to a private host
+ * directory shared with the guest, so it can be changed once this returns. Modules are loaded in
+ * the order they were added, so dependencies must be added first. The kernel of the bundle must
+ * support loadable modules, and the modules must be built for it.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - the path to an uncompressed ".ko" file.
+ *  "params" - the space-separated parameters of the module, such as "debug=1", or NULL for none.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_kernel_module(uint32_t ctx_id, const char *path, const char *params);
+
+/*
+ * Makes the guest trust a bundle of CA certificates, so TLS works out of the box in environments
+ * using custom CAs. The bundle is copied to a private host directory shared read-only with the
+ * guest, and the init process of the guest bind-mounts it over the bundles of the common
+ * distributions found in the root, like "/etc/ssl/certs/ca-certificates.crt" or
+ * "/etc/pki/tls/certs/ca-bundle.crt". If there's none, "SSL_CERT_FILE" is set to point at it
+ * instead. The root isn't modified.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "bundle_path" - the path to a PEM bundle of CA certificates, or NULL to export the trust store
+ *                  of the host, including the CAs added by its administrator.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_ca_certs(uint32_t ctx_id, const char *bundle_path);
+
+/*
+ * Sets the hostname of the guest, applied by its init process before executing the workload. If
+ * the root has an "/etc/hostname" file, it's overridden as described in "krun_set_etc_file".
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "hostname" - dot-separated labels of letters, digits and hyphens, up to 64 characters.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_hostname(uint32_t ctx_id, const char *hostname);
+
+/*
+ * Sets the machine ID of the guest, described in machine-id(5), by overriding "/etc/machine-id"
+ * as described in "krun_set_etc_file". Clones of a root, or microVMs restored from the same
+ * state, should each be given their own, so they can be told apart by the workload and by
+ * network services.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "machine_id" - 32 lowercase hexadecimal characters, not all zeros.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_machine_id(uint32_t ctx_id, const char *machine_id);
+
+/*
+ * Overrides a file in "/etc" of the guest, without modifying the root. The contents are written
+ * to a private host directory, shared with the guest, and the init process of the guest
+ * bind-mounts the file over its counterpart, read-only, before executing the workload. Only files
+ * already existing in the root are overridden.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "name"     - the name of a file directly under "/etc", like "hosts" or "resolv.conf".
+ *  "contents" - the contents of the file.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_etc_file(uint32_t ctx_id, const char *name, const char *contents);
+
+/*
+ * Sets the kernel tunables applied by the init process of the guest before executing the
+ * workload, replacing any previously set. The sysctls are validated when set: names must be under
+ * one of the "/proc/sys" directories, and values can't contain commas, double quotes or control
+ * characters. A sysctl the guest kernel doesn't support is reported on the console, and ignored.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "sysctls" - an array of string pointers, in the "name=value" form used by sysctl(8), such as
+ *              "vm.overcommit_memory=1" or "net.core.somaxconn=4096". The last pointer must be
+ *              NULL.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_sysctls(uint32_t ctx_id, char *const sysctls[]);
+
+/*
+ * Attaches a TPM 2.0 to the microVM, so the guest can use measured boot and keep keys in it. The
+ * TPM is emulated by swtpm, which must be listening on a Unix socket when the microVM starts, as
+ * with "swtpm socket --tpm2 --server type=unixio,path=PATH --flags not-need-init,startup-clear".
+ * Its state lives in swtpm, so it persists across runs if swtpm is given a state directory.
+ *
+ * The guest kernel needs the "tpm_tis" driver. The device is described in the device tree on
+ * aarch64, and probed at its standard address on x86_64.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "socket_path" - the path to the server socket of swtpm.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tpm(uint32_t ctx_id, const char *socket_path);
+
+/*
+ * Hands a secret, such as a token or a password, to the guest, without putting it on the kernel
+ * command line or in a share the guest could read at any time. Early during boot, init reads the
+ * secrets once into "/run/secrets/NAME", a file only readable by root on a tmpfs, and the secrets
+ * are then wiped from the memory of the VMM. Secrets the guest doesn't read in time are wiped too,
+ * see krun_set_secrets_ttl.
+ *
+ * Up to 64 secrets can be added, each of at most 64 KiB.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "name"      - the name of the secret, which must be usable as a file name.
+ *  "value"     - the value of the secret, which is copied, so it can be wiped once this returns.
+ *  "value_len" - the length of the value.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_add_secret(uint32_t ctx_id, const char *name, const void *value, size_t value_len);
+
+/*
+ * Sets how long the guest has to read the secrets, from the start of the microVM. Past this time,
+ * the secrets are wiped, whether the guest read them or not. Defaults to 60 seconds.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "ttl_secs" - the time to live of the secrets, in seconds, which can't be zero.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_secrets_ttl(uint32_t ctx_id, uint32_t ttl_secs);
+
+/*
+ * Levels reported by the callback set with "krun_set_queue_watermarks".
+ */
+#define KRUN_WATERMARK_LOW  0
+#define KRUN_WATERMARK_HIGH 1
+
+/*
+ * Reports the depth of the virtqueues of the console and vsock devices, the number of buffers the
+ * guest made available and the device didn't process yet, crossing watermarks. The callback is
+ * called once with KRUN_WATERMARK_HIGH when the depth of a queue rises to "high", and once with
+ * KRUN_WATERMARK_LOW when it falls back to "low", so the embedder can throttle whatever feeds the
+ * device instead of letting data pile up in buffers.
+ *
+ * On a queue carrying data from the guest, such as a transmit queue, a high depth means the device
+ * isn't keeping up with the guest. On a queue carrying data to the guest, such as a receive queue,
+ * a low depth means the guest isn't handing buffers back fast enough.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "low"          - the low watermark, which must be lower than "high".
+ *  "high"         - the high watermark, at most 256, the size of the queues.
+ *  "watermark_cb" - a function to be called, from the thread of the device, with the virtio type of
+ *                   the device, the index of the queue in the device, its depth and the level
+ *                   crossed. It may be called from several threads concurrently.
+ *  "opaque"       - a pointer to be passed unmodified as the first argument of "watermark_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_queue_watermarks(uint32_t ctx_id,
+                                  uint32_t low,
+                                  uint32_t high,
+                                  void (*watermark_cb)(void *opaque,
+                                                       uint32_t device_type,
+                                                       uint32_t queue,
+                                                       uint32_t depth,
+                                                       uint32_t level),
+                                  void *opaque);
+
+/*
+ * Moves the MMIO devices of the microVM, and the IRQs they use, so they don't collide with those of
+ * other virtualization code in the same process. By default, the devices start at the beginning of
+ * the MMIO area of the architecture and may use all the IRQs the interrupt controller provides.
+ *
+ * The window can be moved within the first 256 MiB of the MMIO area, and the IRQs picked among those
+ * available by default. Each device takes one IRQ, so the range limits the number of devices.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "mmio_start" - the guest physical address of the first device, which must be page aligned.
+ *  "irq_base"   - the first IRQ handed to the devices.
+ *  "irq_max"    - the last IRQ handed to the devices, which can't be lower than "irq_base".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_device_window(uint32_t ctx_id,
+                               uint64_t mmio_start,
+                               uint32_t irq_base,
+                               uint32_t irq_max);
+
+/* Address spaces of "krun_add_user_device". */
+#define KRUN_USER_DEVICE_MMIO 0
+#define KRUN_USER_DEVICE_PIO  1
+
+/*
+ * Hands the accesses of the guest to a region of its physical address space, or of the port I/O
+ * space, to a function of the embedder, to prototype a device without adding it to libkrun. Each
+ * access is dispatched from the vCPU making it, which waits for the function to return, and the
+ * accesses to the same region are handed one at a time.
+ *
+ * The MMIO regions must be within the first 256 MiB of the MMIO area of the architecture, starting
+ * at 0xd0000000 on x86_64 and at 0x40000000 on aarch64, but clear of the 1 MiB window of the MMIO
+ * devices set with "krun_set_device_window". The port I/O regions are only supported on x86_64,
+ * and mustn't overlap the ports of the legacy devices.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "kind"      - KRUN_USER_DEVICE_MMIO or KRUN_USER_DEVICE_PIO.
+ *  "base"      - the guest physical address, or the port, of the start of the region.
+ *  "len"       - the length of the region.
+ *  "access_cb" - a function to be called with the vCPU making the access, whether it's a write,
+ *                its offset in the region, and its "len" bytes at "data": those written, or those
+ *                to be filled for a read, zeroed beforehand. It should return promptly.
+ *  "opaque"    - a pointer to be passed unmodified as the first argument of "access_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure: -ENOTSUP for a port I/O region on
+ *  another architecture than x86_64, or -EEXIST if the region overlaps another one.
+ */
+int32_t krun_add_user_device(uint32_t ctx_id,
+                             uint32_t kind,
+                             uint64_t base,
+                             uint64_t len,
+                             void (*access_cb)(void *opaque,
+                                               uint32_t vcpu,
+                                               uint32_t is_write,
+                                               uint64_t offset,
+                                               uint8_t *data,
+                                               uint32_t len),
+                             void *opaque);
+
+/*
+ * Sets the threads running the requests of the virtio-fs devices, which issue blocking syscalls,
+ * so a share backed by a slow filesystem doesn't hold up the other devices. The threads are shared
+ * by all the devices, each having up to "device_limit" requests in flight, so a single device can't
+ * take all of them. By default, 4 threads run up to 16 requests of each device.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "threads"      - the number of threads, up to 64, or zero to run the requests on the thread of
+ *                   the event loop.
+ *  "device_limit" - the number of requests each device can have in flight, from 1 to 1024.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_worker_pool(uint32_t ctx_id, uint32_t threads, uint32_t device_limit);
+
+/*
+ * Reasons reported by the callback set with "krun_set_fd_budget".
+ */
+#define KRUN_FD_BUDGET_EXHAUSTED 0
+#define KRUN_FD_HOST_EXHAUSTED   1
+
+/*
+ * Limits the host file descriptors the devices of the microVM can hold, and reports the devices
+ * failing to get one. A device failing to get a file descriptor returns an error to the guest, and
+ * the microVM keeps running.
+ *
+ * Arguments:
+ *  "ctx_id"      - the configuration context ID.
+ *  "max_fds"     - the number of file descriptors the devices can hold together, or zero to only
+ *                  be limited by the host.
+ *  "fd_event_cb" - an optional function to be called, from the thread of the device, with the name
+ *                  of the device, the file descriptors it holds, and KRUN_FD_BUDGET_EXHAUSTED if
+ *                  "max_fds" was reached or KRUN_FD_HOST_EXHAUSTED if the host ran out of them. It
+ *                  may be called from several threads concurrently.
+ *  "opaque"      - a pointer to be passed unmodified as the first argument of "fd_event_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_fd_budget(uint32_t ctx_id,
+                           uint32_t max_fds,
+                           void (*fd_event_cb)(void *opaque,
+                                               const char *device,
+                                               uint32_t in_use,
+                                               uint32_t kind),
+                           void *opaque);
+
+/*
+ * Returns the host file descriptors held by a device of the microVM, and can be called while it
+ * runs. The virtio-fs devices are named after their tag, and the vsock device is "vsock".
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "device" - the name of the device, or NULL for all the devices together.
+ *
+ * Returns:
+ *  The number of file descriptors on success or a negative error number on failure, -ENOENT if the
+ *  device doesn't exist or isn't started yet.
+ */
+int32_t krun_get_fd_usage(uint32_t ctx_id, const char *device);
+
+/*
+ * Policies for "krun_set_device_panic_policy".
+ */
+#define KRUN_PANIC_STOP   0
+#define KRUN_PANIC_DETACH 1
+
+/*
+ * Sets what happens when a device of the microVM panics, rather than letting the panic abort the
+ * whole process. By default, the microVM is stopped, terminating the process.
+ *
+ * Arguments:
+ *  "ctx_id"            - the configuration context ID.
+ *  "policy"            - KRUN_PANIC_STOP to stop the microVM, or KRUN_PANIC_DETACH to detach the
+ *                        device and keep the microVM running. The guest then finds the device
+ *                        needing a reset.
+ *  "device_failure_cb" - an optional function to be called, from the thread of the event loop, with
+ *                        the name of the device and the message it panicked with. Under
+ *                        KRUN_PANIC_STOP, it's called before the microVM is stopped.
+ *  "opaque"            - a pointer to be passed unmodified as the first argument of
+ *                        "device_failure_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_device_panic_policy(uint32_t ctx_id,
+                                     uint32_t policy,
+                                     void (*device_failure_cb)(void *opaque,
+                                                               const char *device,
+                                                               const char *message),
+                                     void *opaque);
+
+/*
+ * Flags for "krun_set_guest_panic_callback".
+ */
+#define KRUN_GUEST_PANIC_SCAN_CONSOLE (1 << 0)
+
+/*
+ * Kinds of failures passed to the callback of "krun_set_guest_panic_callback".
+ */
+#define KRUN_GUEST_PANIC 0
+#define KRUN_GUEST_OOPS  1
+
+/*
+ * Reports the guest kernel panicking or oopsing. With KRUN_GUEST_PANIC_SCAN_CONSOLE, they're
+ * detected from the output of the console, where the kernel prints them, so unmodified guests can
+ * be monitored. This is a heuristic: a workload printing the same messages is taken for the
+ * kernel. The microVM isn't stopped by the callback; a panicking guest exits on its own.
+ *
+ * Arguments:
+ *  "ctx_id"         - the configuration context ID.
+ *  "flags"          - KRUN_GUEST_PANIC_SCAN_CONSOLE to detect the failures from the console output.
+ *  "guest_panic_cb" - an optional function to be called, from the thread of the event loop, with
+ *                     KRUN_GUEST_PANIC or KRUN_GUEST_OOPS and the line of the console the failure
+ *                     was detected from. It's called once per panic, and once per oops report.
+ *  "opaque"         - a pointer to be passed unmodified as the first argument of "guest_panic_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_guest_panic_callback(uint32_t ctx_id,
+                                      uint32_t flags,
+                                      void (*guest_panic_cb)(void *opaque,
+                                                             uint32_t kind,
+                                                             const char *message),
+                                      void *opaque);
+
+/*
+ * Sizes the tmpfs mounted by the init process of the guest, for the workloads needing a large
+ * /dev/shm or /tmp, like browsers and ML runtimes, without modifying the root.
+ *
+ * Arguments:
+ *  "ctx_id"       - the configuration context ID.
+ *  "tmp_size_mib" - the size of a tmpfs mounted at /tmp, in MiB, or zero to leave /tmp to the root.
+ *  "shm_size_mib" - the size of /dev/shm, in MiB, or zero for the default of the kernel, half of
+ *                   the RAM.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_tmpfs_sizes(uint32_t ctx_id, uint32_t tmp_size_mib, uint32_t shm_size_mib);
+
+/*
+ * Gives the guest a swap, which its init process formats and enables before executing the
+ * workload, so memory-spiky workloads can survive without a huge RAM. The swap is backed by a
+ * sparse file of the host, only taking the space of the pages swapped out, and removed once the
+ * microVM stops.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "size_mib" - the size of the swap, in MiB.
+ *  "dir"      - the directory the file backing the swap is created in, or NULL for the temporary
+ *               directory of the host. It shouldn't be on a tmpfs, which would keep the pages
+ *               swapped out in the RAM of the host.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_swap(uint32_t ctx_id, uint32_t size_mib, const char *dir);
+
+/*
+ * Restricts the virtio devices to a pool of guest memory, reserved through the device tree, which
+ * the guest bounces their buffers through, so the rest of its memory is never handed to them. The
+ * devices negotiate VIRTIO_F_ACCESS_PLATFORM, and their queues reject the buffers out of the pool.
+ * The balloon device is left out, as it hands over the pages themselves.
+ *
+ * Only supported on Linux aarch64; elsewhere, starting the microVM fails.
+ *
+ * Arguments:
+ *  "ctx_id"        - the configuration context ID.
+ *  "pool_size_mib" - the size of the pool, in MiB, a multiple of 2 up to 1024. It's taken from the
+ *                    top of the RAM of the guest, and can't exceed half of it.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_restricted_dma(uint32_t ctx_id, uint32_t pool_size_mib);
+
+/*
+ * Grows the disk of a block device while the microVM runs, extending the file backing it, and
+ * notifies the guest of its new capacity. The disk can't shrink. The only block device is the swap,
+ * "krun-swap", whose new capacity the guest only uses once it enables the swap again.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "block_id" - the id of the block device, which is also the serial the guest reads from it.
+ *  "size"     - the new size of the disk, in bytes, rounded down to a multiple of 512.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if the device doesn't exist or
+ *  isn't started yet, -EINVAL if the disk is larger than "size".
+ */
+int32_t krun_resize_disk(uint32_t ctx_id, const char *block_id, uint64_t size);
+
+/*
+ * Serves a monitor controlling the running microVM on a Unix socket, speaking a subset of QMP, the
+ * QEMU Machine Protocol. Each client is greeted with {"QMP": {"version": ..., "capabilities": []}},
+ * the version being the one of libkrun, then sends one JSON command per line, such as
+ * {"execute": "query-status"}, each answered on a line of its own with {"return": ...} or
+ * {"error": {"class": ..., "desc": ...}}, along with the "id" of the command if it has one.
+ *
+ * The commands are:
+ *  "qmp_capabilities" - accepted, though there are no capabilities to negotiate. The other
+ *                       commands don't have to wait for it.
+ *  "query-status"     - the state of the microVM, as krun_get_vm_state, as "status": "running",
+ *                       "paused", "shutting-down" or "stopped", with the "reason" it stopped.
+ *  "query-devices"    - the id and the type of each device.
+ *  "stop", "cont"     - pauses and resumes the vCPUs, on Linux only.
+ *  "query-balloon"    - the memory left to the guest by the balloon, in bytes, as "actual".
+ *  "balloon"          - asks the guest to leave itself "value" bytes of memory, giving up the rest
+ *                       to the balloon.
+ *  "block_resize"     - grows the disk of the block device "device" to "size" bytes, as
+ *                       krun_resize_disk.
+ *  "system_powerdown" - asks the guest to shut down, on x86_64 only.
+ *  "quit"             - stops the microVM once the reply is sent.
+ *
+ * "device_add" and "device_del" are refused, as the devices are fixed once the microVM is built.
+ * The clients aren't authenticated, so the socket must only be reachable by those trusted with the
+ * microVM. Up to 8 clients are served at once, and a client sending a line longer than 64 KiB is
+ * disconnected.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - the path of the socket, which is bound right away and must not exist.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_monitor(uint32_t ctx_id, const char *path);
+
+/*
+ * Signals a file descriptor once the init process of the guest reports it's starting the workload,
+ * which tells when the workload is actually running, rather than when the vCPUs were started. An
+ * 8-byte value of 1 is written to it, so it can be an eventfd or the writing end of a pipe. Only
+ * the first report is delivered.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "fd"     - a file descriptor open for writing. Its ownership is transferred to the library.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_workload_started_fd(uint32_t ctx_id, int fd);
+
+/*
+ * Kinds of probes, and their states, for the "krun_set_probe_*" functions.
+ */
+#define KRUN_PROBE_READINESS 0
+#define KRUN_PROBE_LIVENESS  1
+
+#define KRUN_PROBE_UNKNOWN 0
+#define KRUN_PROBE_SUCCESS 1
+#define KRUN_PROBE_FAILURE 2
+
+/*
+ * Sets the probe of a kind to a command the init process of the guest runs, succeeding if it
+ * exits with 0, replacing the previous probe of that kind. A probe starts unknown, and succeeds or
+ * fails once it reaches a threshold of consecutive results, as in "krun_set_probe_timing". If the
+ * guest goes a period and a timeout without reporting a result, once it reported one, the run is
+ * counted as a failure, as the guest may be stuck.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "kind"   - KRUN_PROBE_READINESS or KRUN_PROBE_LIVENESS.
+ *  "argv"   - a NULL-terminated array of the command and its arguments, looked up in the PATH of
+ *             the guest. They can't contain commas, double quotes or control characters.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_probe_exec(uint32_t ctx_id, uint32_t kind, const char *const argv[]);
+
+/*
+ * Sets the probe of a kind to a TCP connection to a port of the host, forwarded to the guest as in
+ * "krun_set_port_map", replacing the previous probe of that kind.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "kind"   - KRUN_PROBE_READINESS or KRUN_PROBE_LIVENESS.
+ *  "port"   - the port of the host the workload can be reached on, connected to on 127.0.0.1.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_probe_tcp(uint32_t ctx_id, uint32_t kind, uint16_t port);
+
+/*
+ * Sets the probe of a kind to the existence of a path of the host, such as a file the workload
+ * creates in a share, replacing the previous probe of that kind.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "kind"   - KRUN_PROBE_READINESS or KRUN_PROBE_LIVENESS.
+ *  "path"   - the path of the host.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_probe_file(uint32_t ctx_id, uint32_t kind, const char *path);
+
+/*
+ * Sets when the probe of a kind runs, and how many consecutive results change its state. By
+ * default, a probe runs every 10 seconds from the start of the microVM, times out after a second,
+ * succeeds after a success and fails after 3 failures.
+ *
+ * Arguments:
+ *  "ctx_id"            - the configuration context ID.
+ *  "kind"              - KRUN_PROBE_READINESS or KRUN_PROBE_LIVENESS.
+ *  "initial_delay_ms"  - the time before the first run, in milliseconds.
+ *  "period_ms"         - the time between the runs, in milliseconds.
+ *  "timeout_ms"        - the time after which a run fails, in milliseconds. It can't exceed the
+ *                        period.
+ *  "success_threshold" - the consecutive successes after which the probe succeeds.
+ *  "failure_threshold" - the consecutive failures after which the probe fails.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -ENOENT if no probe of that kind is set.
+ */
+int32_t krun_set_probe_timing(uint32_t ctx_id,
+                              uint32_t kind,
+                              uint32_t initial_delay_ms,
+                              uint32_t period_ms,
+                              uint32_t timeout_ms,
+                              uint32_t success_threshold,
+                              uint32_t failure_threshold);
+
+/*
+ * Sets the function called when the state of a probe changes. Without it, the changes are only
+ * logged.
+ *
+ * Arguments:
+ *  "ctx_id"   - the configuration context ID.
+ *  "probe_cb" - a function to be called, from the thread running the probe, with its kind and its
+ *               new state, KRUN_PROBE_SUCCESS or KRUN_PROBE_FAILURE.
+ *  "opaque"   - a pointer to be passed unmodified as the first argument of "probe_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_probe_callback(uint32_t ctx_id,
+                                void (*probe_cb)(void *opaque, uint32_t kind, uint32_t state),
+                                void *opaque);
+
+/*
+ * Restart policies of the services, for "krun_add_service".
+ */
+#define KRUN_RESTART_ONESHOT 0
+#define KRUN_RESTART_ALWAYS  1
+
+/*
+ * States of the services, for "krun_set_service_callback".
+ */
+#define KRUN_SERVICE_RUNNING 0
+#define KRUN_SERVICE_EXITED  1
+#define KRUN_SERVICE_FAILED  2
+
+/*
+ * Adds a service the init process of the guest runs alongside the workload, such as a sidecar,
+ * without wrapping the workload in a shell script. The services are started in the order they
+ * were added, before the workload. Up to 32 services can be added.
+ *
+ * Arguments:
+ *  "ctx_id"  - the configuration context ID.
+ *  "name"    - the name the service is reported under, made of ASCII alphanumerics, '-', '_', '.'
+ *              and '@'.
+ *  "argv"    - an array of string pointers, the path of the executable in the guest followed by
+ *              its arguments, which can't contain commas, double quotes or control characters.
+ *              The last pointer must be NULL.
+ *  "restart" - KRUN_RESTART_ONESHOT to run the service to completion before starting the next
+ *              one, or KRUN_RESTART_ALWAYS to run it in the background, restarting it a second
+ *              after it exits.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure, -EEXIST if a service with the same name
+ *  has already been added, or -ENOSPC if there are too many services.
+ */
+int32_t krun_add_service(uint32_t ctx_id,
+                         const char *name,
+                         const char *const argv[],
+                         uint32_t restart);
+
+/*
+ * Sets the function called when the state of a service changes, as reported by the guest.
+ * Without it, the changes are only logged.
+ *
+ * Arguments:
+ *  "ctx_id"     - the configuration context ID.
+ *  "service_cb" - a function to be called, from a vCPU thread, with the name of the service and
+ *                 its new state: KRUN_SERVICE_RUNNING each time it's started, KRUN_SERVICE_EXITED
+ *                 when it exits with 0, and KRUN_SERVICE_FAILED when it exits with another status,
+ *                 is killed, or can't be executed. It should return promptly.
+ *  "opaque"     - a pointer to be passed unmodified as the first argument of "service_cb".
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_set_service_callback(uint32_t ctx_id,
+                                  void (*service_cb)(void *opaque, const char *name, uint32_t state),
+                                  void *opaque);
+
+/*
+ * Version of the option registry. It's bumped whenever options are added, and every option records
+ * the version it was introduced in.
+ */
+#define KRUN_OPTIONS_VERSION 9
+
+/* Option value types, as reported by "krun_get_option_info". */
+#define KRUN_OPTION_TYPE_BOOL 0 /* "true" or "false" */
+#define KRUN_OPTION_TYPE_U32  1 /* a decimal unsigned 32-bit integer */
+
+/*
+ * Sets a context option by key. Supported options, as of version 9:
+ *  "machine.vcpus"       - the number of vCPUs.
+ *  "machine.mem_mib"     - the amount of RAM, in MiB.
+ *  "machine.ht"          - whether to enable hyperthreading.
+ *  "runtime.max_secs"    - the limit on the runtime, as in "krun_set_max_runtime", or 0 for none.
+ *  "runtime.grace_secs"  - the grace period once the runtime limit is exceeded. Requires
+ *                          "runtime.max_secs" to be set first.
+ *  "idle.interval_ms"    - the idle interval, as in "krun_set_idle_policy", or 0 to disable it.
+ *  "idle.reclaim_memory" - whether to reclaim the memory of the frozen microVM. Requires
+ *                          "idle.interval_ms" to be set first.
+ *  "devices.crypto"      - whether to add a virtio-crypto device, offloading the AES ciphers of
+ *                          the guest to the host kernel on Linux, or to CommonCrypto on macOS.
+ *                          Since version 2.
+ *  "vsock.shm"           - whether to offer the guest driver a shared-memory data path for vsock
+ *                          connections, moving their data through rings in the shared-memory
+ *                          region of the device rather than the virtqueues. Guests without
+ *                          support for it keep using the virtqueues. Only supported on Linux.
+ *                          Since version 3.
+ *  "vsock.buf_size"      - the buffer size of each vsock connection, in bytes, a power of 2
+ *                          between 4 KiB and 16 MiB. Larger buffers let the guest send more data
+ *                          before waiting for the host. Also sets "vsock.credit_threshold" to
+ *                          three quarters of the buffer. Defaults to 64 KiB. Since version 4.
+ *  "vsock.credit_threshold" - the number of bytes forwarded to the host after which the guest
+ *                          is told it can send more. Must not exceed "vsock.buf_size".
+ *                          Since version 4.
+ *  "guest.tmp_mib"       - the size of /tmp, in MiB, as in "krun_set_tmpfs_sizes". Since version 5.
+ *  "guest.shm_mib"       - the size of /dev/shm, in MiB, as in "krun_set_tmpfs_sizes".
+ *                          Since version 5.
+ *  "devices.i8042"       - whether to emulate the i8042 PS/2 controller, on x86_64. Without it,
+ *                          the guest resets the machine through the reset control of the
+ *                          chipset, at port 0xcf9, and "reboot=pci" is added to the kernel
+ *                          command line. The workload can't be asked to shut down gracefully
+ *                          once the runtime limit is exceeded, and is stopped instead.
+ *                          Defaults to true. Ignored on other architectures. Since version 6.
+ *  "devices.balloon"     - whether to attach the balloon device, through which the guest reports
+ *                          its free memory to the host. Workloads that don't need their memory
+ *                          reclaimed can leave it out, sparing its setup from the boot, as
+ *                          reported by "krun_get_boot_timeline". Defaults to true.
+ *                          Since version 7.
+ *  "fs.negative_timeout_ms" - how long the guest caches the names it didn't find on the
+ *                          virtio-fs devices, in milliseconds, sparing it from looking them up
+ *                          again, as when a build probes many paths that don't exist. Files the
+ *                          guest creates show up right away, but those created on the host under
+ *                          a cached name only show up once it expires. Defaults to 0, not caching
+ *                          them. Since version 8.
+ *  "fs.batch_lookups"    - whether the guest always lists the directories of the virtio-fs
+ *                          devices with READDIRPLUS, looking up all their entries in the same
+ *                          requests, rather than only when it expects the lookups to follow.
+ *                          Defaults to false. Since version 8.
+ *  "machine.low_latency" - whether to trade host CPU time for the wakeup latency of the guest.
+ *                          Idle vCPUs keep polling for a wakeup instead of yielding their
+ *                          threads: on x86_64, "idle=poll" is added to the kernel command line,
+ *                          unless it selects an idle loop already, and halted vCPUs poll in KVM
+ *                          for 200 us, unless "krun_set_kvm_tuning" sets KRUN_KVM_HALT_POLL or
+ *                          the host doesn't support it. An idle guest then consumes up to a
+ *                          host CPU per vCPU, as reported by the "krun_vcpu_cpu_time_us" metric,
+ *                          and on x86_64 it's never frozen by the idle policy. Defaults to false.
+ *                          Only has an effect on Linux. Since version 9.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "key"    - the name of the option.
+ *  "value"  - the value of the option, as a string.
+ *
+ * Returns:
+ *  Zero on success, -ENOENT if the option is unknown, -EINVAL if the value is invalid, or another
+ *  negative error number on failure.
+ */
+int32_t krun_set_option(uint32_t ctx_id, const char *key, const char *value);
+
+/*
+ * Enumerates the options supported by this library.
+ *
+ * Arguments:
+ *  "index" - the index of the option, starting at zero.
+ *
+ * Returns:
+ *  The key of the option, or NULL if "index" is past the last option. The string is owned by the
+ *  library.
+ */
+const char *krun_get_option_key(uint32_t index);
+
+/*
+ * Describes an option supported by this library.
+ *
+ * Arguments:
+ *  "key"           - the name of the option.
+ *  "type"          - if not NULL, set to the type of the option value (KRUN_OPTION_TYPE_*).
+ *  "since_version" - if not NULL, set to the version of the registry the option was introduced in.
+ *
+ * Returns:
+ *  Zero on success or -ENOENT if the option is unknown.
+ */
+int32_t krun_get_option_info(const char *key, uint32_t *type, uint32_t *since_version);
+
+/*
+ * Configures the microVM from a definition file, in JSON or TOML format as told by its ".json" or
+ * ".toml" extension. Every field of the definition is optional:
+ *  "vcpus", "memory_mib"  - the number of vCPUs and the amount of RAM, in MiB.
+ *  "root"                 - the host path to be used as root, as in "krun_set_root".
+ *  "volumes"              - a list of {"host", "guest"} paths, as in "krun_set_mapped_volumes".
+ *  "ports"                - a list of {"host", "guest"} TCP ports, as in "krun_set_port_map".
+ *  "rlimits"              - a list of rlimits, as in "krun_set_rlimits".
+ *  "workdir"              - the working directory, as in "krun_set_workdir".
+ *  "exec"                 - the {"path", "args", "env"} of the executable, as in "krun_set_exec".
+ *  "options"              - a table of options, as in "krun_set_option".
+ * Errors, including their position in the file, are logged.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *  "path"   - the path to the definition file.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_load_config(uint32_t ctx_id, const char *path);
+
+/*
+ * Starts and enters the microVM with the configured parameters. The VMM will attempt to take over
+ * stdin/stdout to manage them on behalf of the process running inside the isolated environment,
+ * simulating that the latter has direct control of the terminal.
+ *
+ * This function consumes the configuration pointed by the context ID, moving the context to
+ * KRUN_STATE_RUNNING. It fails with -EBUSY if the context has already been started.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  This function only returns if an error happens before starting the microVM. Otherwise, the
+ *  VMM assumes it has full control of the process, and will call to exit() once the microVM shuts
+ *  down, unless "krun_get_exit_fd" was called on the context, in which case it returns zero once
+ *  the microVM has exited, moving the context to KRUN_STATE_STOPPED.
+ */
+int32_t krun_start_enter(uint32_t ctx_id);
+
+/*
+ * Returns a file descriptor which becomes readable once the microVM has exited, so the embedder
+ * can wait for it in its own poll loop, and keeps the VMM from exiting the process when the
+ * microVM shuts down. By then the vCPUs are stopped and what the microVM left behind is released,
+ * and "krun_start_enter" returns shortly after. How the microVM exited is then available from
+ * "krun_get_exit_status".
+ *
+ * The file descriptor belongs to libkrun, and stays valid until the context is freed. It's the
+ * same one on every call. Only supported on Linux; the VMM always exits the process elsewhere.
+ *
+ * Arguments:
+ *  "ctx_id" - the configuration context ID.
+ *
+ * Returns:
+ *  The file descriptor on success or a negative error number on failure: -ENOTSUP if the host
+ *  doesn't support it.
+ */
+int32_t krun_get_exit_fd(uint32_t ctx_id);
+
+/*
+ * Reasons of the exit of a microVM.
+ */
+#define KRUN_EXIT_GUEST_SHUTDOWN 0
+#define KRUN_EXIT_MAX_RUNTIME    1
+#define KRUN_EXIT_DEVICE_FAILURE 2
+#define KRUN_EXIT_VCPU_ERROR     3
+
+/*
+ * Returns how the microVM exited, once the file descriptor returned by "krun_get_exit_fd" is
+ * readable.
+ *
+ * Arguments:
+ *  "ctx_id"    - the configuration context ID.
+ *  "exit_code" - where the exit code the VMM would have exited the process with is stored, if not
+ *                NULL.
+ *
+ * Returns:
+ *  One of the KRUN_EXIT_* values on success or a negative error number on failure: -EAGAIN if the
+ *  microVM hasn't exited yet, or -ENOENT if "krun_get_exit_fd" wasn't called on the context.
+ */
+int32_t krun_get_exit_status(uint32_t ctx_id, int32_t *exit_code);
+
+/*
+ * Fault injection, for validating the resilience of guest workloads. These functions are only
+ * available when libkrun is built with the "fault-injection" feature. The faults are process-wide,
+ * and can be armed at any time, including while the microVM is running.
+ *
+ * Devices are identified by their virtio device type.
+ */
+#define KRUN_DEVICE_CONSOLE 3
+#define KRUN_DEVICE_VSOCK 19
+#define KRUN_DEVICE_FS 26
+
+/*
+ * Fails the processing of the next buffers the guest places in the queues of a device. The buffers
+ * are returned to the guest unprocessed.
+ *
+ * Arguments:
+ *  "device_type" - the type of the device, one of the KRUN_DEVICE_* values.
+ *  "count"       - the number of buffers to fail.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_inject_queue_error(uint32_t device_type, uint32_t count);
+
+/*
+ * Makes the fs device fail with EIO to open any file under a host path.
+ *
+ * Arguments:
+ *  "path" - the host path prefix.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_inject_fs_eio(const char *path);
+
+/*
+ * Resets the next vsock connections the guest sends data through.
+ *
+ * Arguments:
+ *  "count" - the number of connections to reset.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_inject_vsock_reset(uint32_t count);
+
+/*
+ * Delays every interrupt raised by a device. The device isn't serviced during the delay.
+ *
+ * Arguments:
+ *  "device_type" - the type of the device, one of the KRUN_DEVICE_* values.
+ *  "delay_ms"    - the delay in milliseconds. Zero disarms the fault.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_inject_irq_delay(uint32_t device_type, uint32_t delay_ms);
+
+/*
+ * Disarms all the faults.
+ *
+ * Returns:
+ *  Zero on success or a negative error number on failure.
+ */
+int32_t krun_clear_faults();
@@ -1,51 +1,1848 @@
+#include <errno.h>
+#include <fcntl.h>
+#include <grp.h>
+#include <ifaddrs.h>
+#include <dirent.h>
 #include <limits.h>
+#include <mntent.h>
+#include <poll.h>
+#include <stddef.h>
 #include <unistd.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <signal.h>
 #include <string.h>
+#include <termios.h>
+#include <time.h>
+#include <linux/audit.h>
+#include <linux/capability.h>
+#include <linux/filter.h>
+#include <linux/seccomp.h>
+#include <arpa/inet.h>
 #include <net/if.h>
 #include <sys/ioctl.h>
 #include <sys/mount.h>
+#include <sys/prctl.h>
 #include <sys/resource.h>
 #include <sys/socket.h>
 #include <sys/stat.h>
+#include <sys/swap.h>
+#include <sys/syscall.h>
 #include <sys/time.h>
 #include <sys/types.h>
+#include <sys/un.h>
+#include <sys/wait.h>
+
+#if defined(__x86_64__)
+#include <sys/io.h>
+#define KRUN_SIGNAL_PORT 0x3f0
+#define KRUN_SECRETS_PORT 0x3f4
+#elif defined(__aarch64__)
+#include <stdint.h>
+#include <sys/mman.h>
+#define KRUN_SIGNAL_ADDR 0x40000000
+#define KRUN_SECRETS_ADDR 0x40000800
+#endif
+#define KRUN_SIGNAL_WORKLOAD_STARTED 124
+#define KRUN_SIGNAL_READINESS_SUCCESS 125
+#define KRUN_SIGNAL_READINESS_FAILURE 126
+#define KRUN_SIGNAL_LIVENESS_SUCCESS 127
+#define KRUN_SIGNAL_LIVENESS_FAILURE 128
+/* The events of the services are signaled as KRUN_SIGNAL_SERVICE_BASE + index * 3 + state. */
+#define KRUN_SIGNAL_SERVICE_BASE 129
+#define KRUN_SERVICE_RUNNING 0
+#define KRUN_SERVICE_EXITED 1
+#define KRUN_SERVICE_FAILED 2
+#define KRUN_SERVICE_STATES 3
+
+#define PROBE_POLL_MS 10
+
+#define SECRETS_REG_STATUS 0
+#define SECRETS_REG_DATA 1
+#define SECRETS_STATUS_AVAILABLE 1
+#define SECRETS_CONTROL_DONE 1
+#define SECRETS_MAX_SIZE (64 * 1024)
+#define SECRETS_DIR "/run/secrets"
+
+#define CLOUD_INIT_FS_TAG "cidata"
+#define CLOUD_INIT_SEED_DIR "/var/lib/cloud/seed/nocloud"
+
+#define ETC_FS_TAG "krun-etc"
+#define ETC_OVERRIDES_DIR "/dev/.krun-etc"
+
+#define CA_CERTS_FS_TAG "krun-certs"
+#define CA_CERTS_DIR "/dev/.krun-certs"
+#define CA_CERTS_BUNDLE CA_CERTS_DIR "/ca-certificates.crt"
+
+#define LOG_SOCKET "/dev/log"
+#define LOG_RECORD_MAX_SIZE (64 * 1024)
+#define LOG_HEADER_SIZE 4
+
+#define SWAP_HEADER_OFFSET 1024
+#define SWAP_SIGNATURE "SWAPSPACE2"
+#define SWAP_MIN_PAGES 10
+
+#define WORKLOAD_CGROUP "/sys/fs/cgroup/krun-workload"
+
+#define KERNEL_MODULES_FS_TAG "krun-modules"
+#define KERNEL_MODULES_DIR "/dev/.krun-modules"
+#define KERNEL_MODULES_ORDER KERNEL_MODULES_DIR "/modules.order"
+
+#define LOGIN_SHELL "/bin/sh"
+
+#define MAX_SUPPLEMENTARY_GROUPS 32
+#define USER_RUNTIME_DIR "/run/user"
+
+#if defined(__x86_64__)
+#define SECCOMP_AUDIT_ARCH AUDIT_ARCH_X86_64
+/* The x32 system calls share the architecture of x86_64, with this bit set in their numbers. */
+#define X32_SYSCALL_BIT 0x40000000
+#elif defined(__aarch64__)
+#define SECCOMP_AUDIT_ARCH AUDIT_ARCH_AARCH64
+#endif
+
+/* From <linux/fs.h>, which clashes with <sys/mount.h>. */
+#ifndef FIFREEZE
+#define FIFREEZE _IOWR('X', 119, int)
+#define FITHAW _IOWR('X', 120, int)
+#endif
+#define FSFREEZE_FREEZE 'F'
+#define FSFREEZE_THAW 'T'
+#define MAX_FROZEN 64
+
+/* From <linux/route.h> and <linux/ipv6_route.h>, which clash with <net/route.h>. */
+#define ROUTE_UP 0x0001
+#define ROUTE_GATEWAY 0x0002
+#define ROUTE_LOCAL 0x80000000
+#define GUEST_NETWORK_QUERY 'Q'
+#define GUEST_NETWORK_REPORT_MAX 0xffff
+
+#define MAX_SERVICES 32
+#define SERVICE_RESTART_DELAY_MS 1000
 
 char DEFAULT_KRUN_INIT[] = "/bin/sh";
 
-void set_rlimits(const char *rlimits)
+/* Creates a directory and its missing parents. */
+int mkdir_p(const char *path, mode_t mode)
+{
+    char buf[PATH_MAX];
+    char *p;
+
+    if (strlen(path) >= sizeof buf) {
+        return -1;
+    }
+    strcpy(buf, path);
+
+    for (p = buf + 1; *p; p++) {
+        if (*p == '/') {
+            *p = '\0';
+            mkdir(buf, mode);
+            *p = '/';
+        }
+    }
+
+    if (mkdir(buf, mode) != 0 && access(buf, F_OK) != 0) {
+        return -1;
+    }
+    return 0;
+}
+
+/*
+ * Mounts the shares from a comma-separated list of mounts in the "tag:path:access:propagation"
+ * form, in the order given, creating the mount points as needed.
+ */
+void mount_shares(char *shares)
+{
+    char *item, *tag, *path, *access, *propagation;
+    unsigned long flags;
+
+    for (item = strtok(shares, ","); item; item = strtok(NULL, ",")) {
+        tag = strsep(&item, ":");
+        path = strsep(&item, ":");
+        access = strsep(&item, ":");
+        propagation = item;
+        if (!path || !access || !propagation) {
+            printf("Invalid share: %s\n", tag);
+            continue;
+        }
+
+        if (mkdir_p(path, 0755) != 0) {
+            perror(path);
+            continue;
+        }
+
+        flags = strcmp(access, "ro") == 0 ? MS_RDONLY : 0;
+        if (mount(tag, path, "virtiofs", flags, NULL) < 0) {
+            perror(path);
+            continue;
+        }
+
+        if (strcmp(propagation, "shared") == 0) {
+            flags = MS_SHARED;
+        } else if (strcmp(propagation, "slave") == 0) {
+            flags = MS_SLAVE;
+        } else if (strcmp(propagation, "unbindable") == 0) {
+            flags = MS_UNBINDABLE;
+        } else {
+            flags = MS_PRIVATE;
+        }
+        if (mount(NULL, path, NULL, flags, NULL) < 0) {
+            perror(path);
+        }
+    }
+}
+
+/*
+ * Formats the block device whose serial is "serial" as a swap, as mkswap(8) does, and enables it.
+ * The device is backed by a sparse file of the host, so only the header is written.
+ */
+void enable_swap(const char *serial)
+{
+    char path[PATH_MAX];
+    char buf[64];
+    struct dirent *entry;
+    uint32_t *header;
+    long page_size;
+    off_t size;
+    ssize_t len;
+    char *page;
+    DIR *dir;
+    int fd;
+
+    dir = opendir("/sys/block");
+    if (!dir) {
+        perror("opendir(/sys/block)");
+        return;
+    }
+
+    while ((entry = readdir(dir))) {
+        if (strncmp(entry->d_name, "vd", 2) != 0) {
+            continue;
+        }
+        snprintf(path, sizeof path, "/sys/block/%s/serial", entry->d_name);
+        fd = open(path, O_RDONLY);
+        if (fd < 0) {
+            continue;
+        }
+        len = read(fd, buf, sizeof buf - 1);
+        close(fd);
+        if (len <= 0) {
+            continue;
+        }
+        buf[len] = '\0';
+        buf[strcspn(buf, "\n")] = '\0';
+        if (strcmp(buf, serial) == 0) {
+            break;
+        }
+    }
+    if (!entry) {
+        printf("Unable to find the swap device\n");
+        closedir(dir);
+        return;
+    }
+    snprintf(path, sizeof path, "/dev/%s", entry->d_name);
+    closedir(dir);
+
+    fd = open(path, O_RDWR | O_CLOEXEC);
+    if (fd < 0) {
+        perror(path);
+        return;
+    }
+
+    page_size = sysconf(_SC_PAGESIZE);
+    size = lseek(fd, 0, SEEK_END);
+    if (size < page_size * SWAP_MIN_PAGES) {
+        printf("The swap device is too small\n");
+        close(fd);
+        return;
+    }
+
+    page = calloc(1, page_size);
+    if (!page) {
+        close(fd);
+        return;
+    }
+    /* The version, the last page and the number of bad pages, following the boot block. */
+    header = (uint32_t *)(page + SWAP_HEADER_OFFSET);
+    header[0] = 1;
+    header[1] = size / page_size - 1;
+    header[2] = 0;
+    memcpy(page + page_size - strlen(SWAP_SIGNATURE), SWAP_SIGNATURE, strlen(SWAP_SIGNATURE));
+
+    if (pwrite(fd, page, page_size, 0) != page_size || fsync(fd) < 0) {
+        perror("write(swap header)");
+        free(page);
+        close(fd);
+        return;
+    }
+    free(page);
+    close(fd);
+
+    if (swapon(path, 0) < 0) {
+        perror("swapon");
+    }
+}
+
+/* Mounts the NoCloud seed shared by the VMM where cloud-init looks for it. */
+void mount_cloud_init_seed()
+{
+    if (mkdir_p(CLOUD_INIT_SEED_DIR, 0755) != 0) {
+        perror("mkdir(" CLOUD_INIT_SEED_DIR ")");
+        return;
+    }
+
+    if (mount(CLOUD_INIT_FS_TAG, CLOUD_INIT_SEED_DIR, "virtiofs",
+              MS_NODEV | MS_NOEXEC | MS_NOSUID | MS_RDONLY, NULL) < 0) {
+        perror("mount(" CLOUD_INIT_SEED_DIR ")");
+    }
+}
+
+/*
+ * Loads the kernel modules staged by the VMM, in the order listed in "modules.order", each line
+ * holding the name of a module followed by its parameters. A module failing to load is reported
+ * but doesn't stop the others, since the workload may not need it.
+ */
+void load_kernel_modules()
+{
+    char line[PATH_MAX + 4096];
+    char path[PATH_MAX];
+    char *params;
+    FILE *order;
+    int fd;
+
+    if (mkdir(KERNEL_MODULES_DIR, 0700) != 0 && errno != EEXIST) {
+        perror("mkdir(" KERNEL_MODULES_DIR ")");
+        return;
+    }
+
+    if (mount(KERNEL_MODULES_FS_TAG, KERNEL_MODULES_DIR, "virtiofs",
+              MS_NODEV | MS_NOEXEC | MS_NOSUID | MS_RDONLY, NULL) < 0) {
+        perror("mount(" KERNEL_MODULES_DIR ")");
+        rmdir(KERNEL_MODULES_DIR);
+        return;
+    }
+
+    order = fopen(KERNEL_MODULES_ORDER, "r");
+    if (order == NULL) {
+        perror("fopen(" KERNEL_MODULES_ORDER ")");
+    } else {
+        while (fgets(line, sizeof line, order)) {
+            line[strcspn(line, "\n")] = '\0';
+            params = strchr(line, ' ');
+            if (params) {
+                *params++ = '\0';
+            } else {
+                params = "";
+            }
+            if (*line == '\0') {
+                continue;
+            }
+
+            snprintf(path, sizeof path, KERNEL_MODULES_DIR "/%s", line);
+            fd = open(path, O_RDONLY | O_CLOEXEC);
+            if (fd < 0) {
+                perror(path);
+                continue;
+            }
+            if (syscall(SYS_finit_module, fd, params, 0) != 0 && errno != EEXIST) {
+                fprintf(stderr, "Couldn't load kernel module %s: %s\n", line,
+                        strerror(errno));
+            }
+            close(fd);
+        }
+        fclose(order);
+    }
+
+    umount(KERNEL_MODULES_DIR);
+    rmdir(KERNEL_MODULES_DIR);
+}
+
+/*
+ * Bind-mounts the files shared by the VMM over their counterparts in "/etc". The share stays
+ * mounted, since the bind mounts refer to it.
+ */
+void override_etc_files()
+{
+    char source[PATH_MAX];
+    char target[PATH_MAX];
+    struct dirent *entry;
+    DIR *dir;
+
+    if (mkdir(ETC_OVERRIDES_DIR, 0755) != 0 && errno != EEXIST) {
+        perror("mkdir(" ETC_OVERRIDES_DIR ")");
+        return;
+    }
+
+    if (mount(ETC_FS_TAG, ETC_OVERRIDES_DIR, "virtiofs",
+              MS_NODEV | MS_NOEXEC | MS_NOSUID | MS_RDONLY, NULL) < 0) {
+        perror("mount(" ETC_OVERRIDES_DIR ")");
+        return;
+    }
+
+    dir = opendir(ETC_OVERRIDES_DIR);
+    if (dir == NULL) {
+        perror("opendir(" ETC_OVERRIDES_DIR ")");
+        return;
+    }
+
+    while ((entry = readdir(dir)) != NULL) {
+        if (entry->d_name[0] == '.' &&
+            (entry->d_name[1] == '\0' || strcmp(entry->d_name, "..") == 0)) {
+            continue;
+        }
+
+        snprintf(source, sizeof source, ETC_OVERRIDES_DIR "/%s", entry->d_name);
+        snprintf(target, sizeof target, "/etc/%s", entry->d_name);
+        /* Bind mounts need an existing target, and creating it would modify the root. */
+        if (access(target, F_OK) != 0) {
+            printf("Not overriding missing %s\n", target);
+            continue;
+        }
+        if (mount(source, target, NULL, MS_BIND, NULL) < 0 ||
+            mount(NULL, target, NULL, MS_BIND | MS_REMOUNT | MS_RDONLY, NULL) < 0) {
+            perror(target);
+        }
+    }
+    closedir(dir);
+}
+
+/* The locations of the CA bundle of the common distributions. */
+const char *CA_BUNDLES[] = {
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/tls/cacert.pem",
+    "/etc/ssl/cert.pem",
+    NULL,
+};
+
+/*
+ * Bind-mounts the CA bundle shared by the VMM over the bundles found in the root, or points
+ * SSL_CERT_FILE at it if there's none. The share stays mounted, since the bind mounts refer to it.
+ */
+void install_ca_certs()
+{
+    char resolved[PATH_MAX];
+    const char **bundle;
+    int installed = 0;
+
+    if (mkdir(CA_CERTS_DIR, 0755) != 0 && errno != EEXIST) {
+        perror("mkdir(" CA_CERTS_DIR ")");
+        return;
+    }
+
+    if (mount(CA_CERTS_FS_TAG, CA_CERTS_DIR, "virtiofs",
+              MS_NODEV | MS_NOEXEC | MS_NOSUID | MS_RDONLY, NULL) < 0) {
+        perror("mount(" CA_CERTS_DIR ")");
+        return;
+    }
+
+    for (bundle = CA_BUNDLES; *bundle; bundle++) {
+        /* Bundles are often symlinks, which can't be mounted over, so their target is. */
+        if (realpath(*bundle, resolved) == NULL) {
+            continue;
+        }
+        if (mount(CA_CERTS_BUNDLE, resolved, NULL, MS_BIND, NULL) < 0) {
+            perror(resolved);
+            continue;
+        }
+        installed = 1;
+    }
+
+    if (!installed) {
+        setenv("SSL_CERT_FILE", CA_CERTS_BUNDLE, 0);
+    }
+}
+
+/* Writes `value` to the port, or the address, the VMM listens to. Best effort, errors are ignored. */
+void signal_vmm(unsigned char value)
+{
+#if defined(__x86_64__)
+    if (ioperm(KRUN_SIGNAL_PORT, 1, 1) == 0) {
+        outb(value, KRUN_SIGNAL_PORT);
+        ioperm(KRUN_SIGNAL_PORT, 1, 0);
+    }
+#elif defined(__aarch64__)
+    volatile uint8_t *addr;
+    int fd;
+
+    fd = open("/dev/mem", O_RDWR | O_SYNC);
+    if (fd < 0) {
+        return;
+    }
+
+    addr = mmap(NULL, getpagesize(), PROT_WRITE, MAP_SHARED, fd, KRUN_SIGNAL_ADDR);
+    if (addr != MAP_FAILED) {
+        *addr = value;
+        munmap((void *) addr, getpagesize());
+    }
+    close(fd);
+#endif
+}
+
+/* Tells the VMM the workload is about to be started. */
+void signal_workload_started()
+{
+    signal_vmm(KRUN_SIGNAL_WORKLOAD_STARTED);
+}
+
+#if defined(__aarch64__)
+static volatile uint8_t *secrets_regs;
+static void *secrets_map;
+#endif
+
+/* Gives access to the registers of the mailbox the VMM hands the secrets through. */
+int secrets_open()
+{
+#if defined(__x86_64__)
+    return ioperm(KRUN_SECRETS_PORT, 2, 1);
+#elif defined(__aarch64__)
+    off_t base = KRUN_SECRETS_ADDR & ~((off_t) getpagesize() - 1);
+    int fd;
+
+    fd = open("/dev/mem", O_RDWR | O_SYNC);
+    if (fd < 0) {
+        return -1;
+    }
+    secrets_map = mmap(NULL, getpagesize(), PROT_READ | PROT_WRITE, MAP_SHARED, fd, base);
+    close(fd);
+    if (secrets_map == MAP_FAILED) {
+        return -1;
+    }
+    secrets_regs = (volatile uint8_t *) secrets_map + (KRUN_SECRETS_ADDR - base);
+    return 0;
+#else
+    return -1;
+#endif
+}
+
+void secrets_close()
+{
+#if defined(__x86_64__)
+    ioperm(KRUN_SECRETS_PORT, 2, 0);
+#elif defined(__aarch64__)
+    munmap(secrets_map, getpagesize());
+#endif
+}
+
+unsigned char secrets_read(int reg)
+{
+#if defined(__x86_64__)
+    return inb(KRUN_SECRETS_PORT + reg);
+#elif defined(__aarch64__)
+    return secrets_regs[reg];
+#else
+    return 0;
+#endif
+}
+
+void secrets_write(int reg, unsigned char value)
+{
+#if defined(__x86_64__)
+    outb(value, KRUN_SECRETS_PORT + reg);
+#elif defined(__aarch64__)
+    secrets_regs[reg] = value;
+#endif
+}
+
+/* Creates a file only readable by root, holding the value of a secret. */
+int write_secret(const char *name, const unsigned char *value, size_t len)
+{
+    char path[PATH_MAX];
+    ssize_t written;
+    int fd;
+
+    snprintf(path, sizeof path, "%s/%s", SECRETS_DIR, name);
+    fd = open(path, O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW | O_CLOEXEC, 0400);
+    if (fd < 0) {
+        return -1;
+    }
+    while (len > 0) {
+        written = write(fd, value, len);
+        if (written < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            close(fd);
+            return -1;
+        }
+        value += written;
+        len -= written;
+    }
+    return close(fd);
+}
+
+/*
+ * Reads the secrets from the mailbox, which hands them out only once, into files on a tmpfs
+ * mounted at SECRETS_DIR. Each record of the stream is the NUL-terminated name of a secret, the
+ * length of its value, as a little-endian 32-bit integer, and the value itself. An empty name
+ * ends the stream.
+ */
+void read_secrets()
+{
+    unsigned char *value;
+    char name[NAME_MAX + 1];
+    uint32_t len, i;
+    size_t n;
+
+    if (secrets_open() < 0) {
+        perror("secrets mailbox");
+        return;
+    }
+
+    if (secrets_read(SECRETS_REG_STATUS) != SECRETS_STATUS_AVAILABLE) {
+        fprintf(stderr, "The secrets have expired\n");
+        goto out;
+    }
+
+    /* Never let the secrets reach a persistent filesystem. */
+    value = malloc(SECRETS_MAX_SIZE);
+    if (value == NULL
+        || mkdir_p(SECRETS_DIR, 0755) < 0
+        || mount("tmpfs", SECRETS_DIR, "tmpfs",
+                 MS_NODEV | MS_NOEXEC | MS_NOSUID, "mode=0700") < 0) {
+        perror(SECRETS_DIR);
+        free(value);
+        goto out;
+    }
+
+    while (1) {
+        for (n = 0; n < sizeof name; n++) {
+            name[n] = secrets_read(SECRETS_REG_DATA);
+            if (name[n] == '\0') {
+                break;
+            }
+        }
+        if (n == 0 || n == sizeof name) {
+            break;
+        }
+
+        len = 0;
+        for (i = 0; i < 4; i++) {
+            len |= (uint32_t) secrets_read(SECRETS_REG_DATA) << (8 * i);
+        }
+        if (len > SECRETS_MAX_SIZE) {
+            break;
+        }
+        for (i = 0; i < len; i++) {
+            value[i] = secrets_read(SECRETS_REG_DATA);
+        }
+
+        if (write_secret(name, value, len) < 0) {
+            perror(name);
+        }
+        explicit_bzero(value, len);
+    }
+    free(value);
+
+out:
+    /* Lets the VMM wipe the secrets, even if they couldn't all be read. */
+    secrets_write(SECRETS_REG_STATUS, SECRETS_CONTROL_DONE);
+    secrets_close();
+}
+
+void set_rlimits(const char *rlimits)
+{
+    unsigned long long int lim_id, lim_cur, lim_max;
+    struct rlimit rlim;
+    char *item = (char *) rlimits;
+
+    while (1) {
+        lim_id = lim_cur = lim_max = ULLONG_MAX;
+
+        lim_id = strtoull(item, &item, 10);
+        if (lim_id == ULLONG_MAX) {
+            printf("Invalid rlimit ID\n");
+            break;
+        }
+
+        item++;
+        lim_cur = strtoull(item, &item, 10);
+        item++;
+        lim_max = strtoull(item, &item, 10);
+
+        rlim.rlim_cur = lim_cur;
+        rlim.rlim_max = lim_max;
+        if (setrlimit(lim_id, &rlim) != 0) {
+            printf("Error setting rlimit for ID=%lld\n", lim_id);
+        }
+
+        if (*item != '\0') {
+            item++;
+        } else {
+            break;
+        }
+    }
+}
+
+/* Writes a value to a file, such as a sysctl or a cgroup interface file. */
+int write_file(const char *path, const char *value)
+{
+    FILE *f;
+    int failed;
+
+    f = fopen(path, "w");
+    if (f == NULL) {
+        return -1;
+    }
+    /* Errors from the kernel are only reported when the write is flushed. */
+    failed = fputs(value, f) == EOF;
+    if (fclose(f) == EOF || failed) {
+        return -1;
+    }
+    return 0;
+}
+
+/*
+ * Applies a comma-separated list of sysctls in the "name=value" form. As with sysctl(8), the
+ * components of the name are separated by '.', and a '/' stands for a '.' within a component.
+ */
+void apply_sysctls(char *sysctls)
+{
+    char path[PATH_MAX];
+    char *item, *value;
+    char *c;
+
+    for (item = strtok(sysctls, ","); item; item = strtok(NULL, ",")) {
+        value = strchr(item, '=');
+        if (value == NULL) {
+            continue;
+        }
+        *value++ = '\0';
+
+        if (snprintf(path, sizeof path, "/proc/sys/%s", item) >= sizeof path) {
+            continue;
+        }
+        for (c = path + strlen("/proc/sys/"); *c; c++) {
+            if (*c == '.') {
+                *c = '/';
+            } else if (*c == '/') {
+                *c = '.';
+            }
+        }
+
+        if (write_file(path, value) != 0) {
+            printf("Error setting sysctl %s: %s\n", item, strerror(errno));
+        }
+    }
+}
+
+/*
+ * Moves this process, and so the workload, to a new cgroup with a comma-separated list of limits
+ * in the "controller.file=value" form, enabling their controllers first.
+ */
+void setup_workload_cgroup(char *limits)
+{
+    char controller[64];
+    char path[PATH_MAX];
+    char pid[32];
+    char *item, *value;
+    size_t len;
+
+    if (mkdir(WORKLOAD_CGROUP, 0755) != 0 && errno != EEXIST) {
+        perror("mkdir(" WORKLOAD_CGROUP ")");
+        return;
+    }
+
+    for (item = strtok(limits, ","); item; item = strtok(NULL, ",")) {
+        value = strchr(item, '=');
+        if (value == NULL) {
+            continue;
+        }
+        *value++ = '\0';
+
+        len = strcspn(item, ".");
+        if (len + 2 > sizeof controller) {
+            continue;
+        }
+        controller[0] = '+';
+        memcpy(controller + 1, item, len);
+        controller[len + 1] = '\0';
+        /* Fails harmlessly if the controller is already enabled. */
+        write_file("/sys/fs/cgroup/cgroup.subtree_control", controller);
+
+        snprintf(path, sizeof path, WORKLOAD_CGROUP "/%s", item);
+        if (write_file(path, value) != 0) {
+            printf("Error setting cgroup limit %s: %s\n", item, strerror(errno));
+        }
+    }
+
+    snprintf(pid, sizeof pid, "%d", getpid());
+    if (write_file(WORKLOAD_CGROUP "/cgroup.procs", pid) != 0) {
+        perror("Error moving the workload to " WORKLOAD_CGROUP);
+    }
+}
+
+/* Writes the whole buffer, retrying on short writes. */
+int write_all(int fd, const unsigned char *buf, size_t len)
+{
+    ssize_t written;
+
+    while (len > 0) {
+        written = write(fd, buf, len);
+        if (written < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            return -1;
+        }
+        buf += written;
+        len -= written;
+    }
+    return 0;
+}
+
+/*
+ * Forwards the records sent to /dev/log to the log channel of the VMM, each one preceded by its
+ * length as a 32-bit little-endian integer. The socket is bound before the workload starts, and
+ * the records are forwarded by a child process, left running once init is replaced.
+ */
+void forward_logs(const char *device)
+{
+    struct sockaddr_un addr;
+    struct termios tty;
+    unsigned char *frame;
+    ssize_t len;
+    int sockfd;
+    int fd;
+
+    fd = open(device, O_WRONLY | O_NOCTTY | O_CLOEXEC);
+    if (fd < 0) {
+        perror(device);
+        return;
+    }
+
+    /* The records are binary, so the terminal mustn't translate them. */
+    if (tcgetattr(fd, &tty) == 0) {
+        cfmakeraw(&tty);
+        tcsetattr(fd, TCSANOW, &tty);
+    }
+
+    sockfd = socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+    if (sockfd < 0) {
+        perror("socket(" LOG_SOCKET ")");
+        close(fd);
+        return;
+    }
+
+    memset(&addr, 0, sizeof addr);
+    addr.sun_family = AF_UNIX;
+    strncpy(addr.sun_path, LOG_SOCKET, sizeof addr.sun_path - 1);
+    unlink(LOG_SOCKET);
+    if (bind(sockfd, (struct sockaddr *) &addr, sizeof addr) < 0 ||
+        chmod(LOG_SOCKET, 0666) < 0) {
+        perror("bind(" LOG_SOCKET ")");
+        close(sockfd);
+        close(fd);
+        return;
+    }
+
+    switch (fork()) {
+    case 0:
+        break;
+    case -1:
+        perror("fork(" LOG_SOCKET ")");
+        /* fallthrough */
+    default:
+        close(sockfd);
+        close(fd);
+        return;
+    }
+
+    frame = malloc(LOG_HEADER_SIZE + LOG_RECORD_MAX_SIZE);
+    if (!frame) {
+        exit(1);
+    }
+
+    for (;;) {
+        /* Larger records are truncated. */
+        len = recv(sockfd, frame + LOG_HEADER_SIZE, LOG_RECORD_MAX_SIZE, 0);
+        if (len < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            perror("recv(" LOG_SOCKET ")");
+            exit(1);
+        }
+
+        frame[0] = len & 0xff;
+        frame[1] = (len >> 8) & 0xff;
+        frame[2] = (len >> 16) & 0xff;
+        frame[3] = (len >> 24) & 0xff;
+        if (write_all(fd, frame, LOG_HEADER_SIZE + len) < 0) {
+            perror(device);
+            exit(1);
+        }
+    }
+}
+
+void sleep_ms(long ms)
+{
+    struct timespec ts;
+
+    ts.tv_sec = ms / 1000;
+    ts.tv_nsec = (ms % 1000) * 1000000;
+    while (nanosleep(&ts, &ts) < 0 && errno == EINTR)
+        ;
+}
+
+long monotonic_ms()
+{
+    struct timespec ts;
+
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return ts.tv_sec * 1000 + ts.tv_nsec / 1000000;
+}
+
+/* Runs the command of a probe, killing it after `timeout_ms`. Returns whether it exited with 0. */
+int run_probe_command(char **argv, long timeout_ms)
+{
+    long waited_ms;
+    pid_t pid;
+    pid_t ret;
+    int status;
+    int fd;
+
+    pid = fork();
+    if (pid < 0) {
+        return 0;
+    }
+    if (pid == 0) {
+        /* The output of the probe would mix with the one of the workload. */
+        fd = open("/dev/null", O_RDWR);
+        if (fd >= 0) {
+            dup2(fd, STDIN_FILENO);
+            dup2(fd, STDOUT_FILENO);
+            dup2(fd, STDERR_FILENO);
+        }
+        execvp(argv[0], argv);
+        _exit(127);
+    }
+
+    for (waited_ms = 0;; waited_ms += PROBE_POLL_MS) {
+        ret = waitpid(pid, &status, WNOHANG);
+        if (ret == pid) {
+            return WIFEXITED(status) && WEXITSTATUS(status) == 0;
+        }
+        if (ret < 0 && errno != EINTR) {
+            return 0;
+        }
+        if (waited_ms >= timeout_ms) {
+            kill(pid, SIGKILL);
+            waitpid(pid, &status, 0);
+            return 0;
+        }
+        sleep_ms(PROBE_POLL_MS);
+    }
+}
+
+/*
+ * Runs the command of a probe on its schedule, reporting each result to the VMM. `spec` holds the
+ * initial delay, the period and the timeout, in milliseconds, followed by the arguments of the
+ * command, separated by commas. The command is run by a child process, left running once init
+ * is replaced.
+ */
+void start_probe(char *spec, unsigned char success, unsigned char failure)
+{
+    long initial_delay_ms, period_ms, timeout_ms, started_ms, elapsed_ms;
+    char **argv;
+    char *item;
+    size_t argc;
+    size_t i;
+
+    initial_delay_ms = strtol(strsep(&spec, ","), NULL, 10);
+    period_ms = spec ? strtol(strsep(&spec, ","), NULL, 10) : 0;
+    timeout_ms = spec ? strtol(strsep(&spec, ","), NULL, 10) : 0;
+    if (!spec || period_ms <= 0 || timeout_ms <= 0) {
+        printf("Invalid probe\n");
+        return;
+    }
+
+    for (argc = 1, item = spec; *item; item++) {
+        if (*item == ',') {
+            argc++;
+        }
+    }
+    argv = calloc(argc + 1, sizeof(char *));
+    if (!argv) {
+        return;
+    }
+    for (i = 0; i < argc; i++) {
+        argv[i] = strsep(&spec, ",");
+    }
+
+    switch (fork()) {
+    case 0:
+        break;
+    case -1:
+        perror("fork(probe)");
+        /* fallthrough */
+    default:
+        free(argv);
+        return;
+    }
+
+    sleep_ms(initial_delay_ms);
+    for (;;) {
+        started_ms = monotonic_ms();
+        signal_vmm(run_probe_command(argv, timeout_ms) ? success : failure);
+        elapsed_ms = monotonic_ms() - started_ms;
+        if (elapsed_ms < period_ms) {
+            sleep_ms(period_ms - elapsed_ms);
+        }
+    }
+}
+
+static int frozen_fds[MAX_FROZEN];
+static int frozen_count;
+
+/* Thaws the filesystems frozen, in the reverse order. Returns 0 or the first errno. */
+int thaw_filesystems()
+{
+    int ret = 0;
+
+    while (frozen_count > 0) {
+        frozen_count--;
+        if (ioctl(frozen_fds[frozen_count], FITHAW, 0) < 0 && !ret) {
+            ret = errno;
+        }
+        close(frozen_fds[frozen_count]);
+    }
+    return ret;
+}
+
+/*
+ * Syncs every filesystem, then freezes those backed by a block device, the others having nothing
+ * the host could snapshot. Returns 0, or an errno once the ones frozen so far are thawed again.
+ */
+int freeze_filesystems()
+{
+    struct mntent *mnt;
+    FILE *mounts;
+    int ret = 0;
+    int fd;
+
+    sync();
+
+    mounts = setmntent("/proc/self/mounts", "r");
+    if (!mounts) {
+        return errno;
+    }
+    while (!ret && (mnt = getmntent(mounts))) {
+        if (strncmp(mnt->mnt_fsname, "/dev/", 5) != 0) {
+            continue;
+        }
+        fd = open(mnt->mnt_dir, O_RDONLY | O_DIRECTORY | O_CLOEXEC);
+        if (fd < 0) {
+            ret = errno;
+            break;
+        }
+        /* EBUSY if the filesystem is mounted more than once, and frozen already. */
+        if (ioctl(fd, FIFREEZE, 0) < 0) {
+            if (errno != EBUSY && errno != EOPNOTSUPP) {
+                ret = errno;
+            }
+            close(fd);
+            continue;
+        }
+        if (frozen_count == MAX_FROZEN) {
+            ioctl(fd, FITHAW, 0);
+            close(fd);
+            ret = ENOSPC;
+            break;
+        }
+        frozen_fds[frozen_count++] = fd;
+    }
+    endmntent(mounts);
+
+    if (ret) {
+        thaw_filesystems();
+    }
+    return ret;
+}
+
+/*
+ * Freezes and thaws the filesystems on the requests of the host, read from the console in `spec`,
+ * followed by the time after which they're thawed anyway, in seconds. Each request is a command
+ * and a sequence number, replied to with the sequence number and 0 or an errno. A thaw replies
+ * ESTALE if the filesystems had already been thawed on their own. The requests are served by a
+ * child process, left running once init is replaced.
+ */
+void start_fsfreeze_agent(char *spec)
+{
+    unsigned char request[2];
+    unsigned char reply[2];
+    struct termios tty;
+    struct pollfd pollfd;
+    long thaw_timeout_ms;
+    long frozen_ms = 0;
+    long remaining_ms;
+    int expired = 0;
+    char *device;
+    ssize_t len;
+    size_t got;
+    int fd;
+
+    device = strsep(&spec, ",");
+    thaw_timeout_ms = spec ? strtol(spec, NULL, 10) * 1000 : 0;
+    if (thaw_timeout_ms <= 0) {
+        printf("Invalid filesystem freeze channel\n");
+        return;
+    }
+
+    fd = open(device, O_RDWR | O_NOCTTY | O_CLOEXEC);
+    if (fd < 0) {
+        perror(device);
+        return;
+    }
+
+    /* The requests are binary, so the terminal mustn't translate them. */
+    if (tcgetattr(fd, &tty) == 0) {
+        cfmakeraw(&tty);
+        tcsetattr(fd, TCSANOW, &tty);
+    }
+
+    switch (fork()) {
+    case 0:
+        break;
+    case -1:
+        perror("fork(fsfreeze)");
+        /* fallthrough */
+    default:
+        close(fd);
+        return;
+    }
+
+    pollfd.fd = fd;
+    pollfd.events = POLLIN;
+    for (got = 0;;) {
+        remaining_ms = -1;
+        if (frozen_count > 0) {
+            remaining_ms = frozen_ms + thaw_timeout_ms - monotonic_ms();
+            if (remaining_ms <= 0) {
+                printf("Thawing the filesystems the host didn't thaw\n");
+                thaw_filesystems();
+                expired = 1;
+                continue;
+            }
+        }
+        if (poll(&pollfd, 1, remaining_ms) <= 0) {
+            continue;
+        }
+
+        len = read(fd, request + got, sizeof request - got);
+        if (len <= 0) {
+            if (len < 0 && errno == EINTR) {
+                continue;
+            }
+            perror(device);
+            thaw_filesystems();
+            exit(1);
+        }
+        got += len;
+        if (got < sizeof request) {
+            continue;
+        }
+        got = 0;
+
+        reply[0] = request[1];
+        switch (request[0]) {
+        case FSFREEZE_FREEZE:
+            if (frozen_count > 0) {
+                reply[1] = EBUSY;
+                break;
+            }
+            reply[1] = freeze_filesystems();
+            frozen_ms = monotonic_ms();
+            expired = 0;
+            break;
+        case FSFREEZE_THAW:
+            reply[1] = expired ? ESTALE : thaw_filesystems();
+            expired = 0;
+            break;
+        default:
+            reply[1] = EINVAL;
+        }
+        if (write_all(fd, reply, sizeof reply) < 0) {
+            perror(device);
+        }
+    }
+}
+
+/* Returns the length of the prefix of `netmask`, assumed to be contiguous. */
+int netmask_prefix_len(const struct sockaddr *netmask)
 {
-    unsigned long long int lim_id, lim_cur, lim_max;
-    struct rlimit rlim;
-    char *item = (char *) rlimits;
+    const unsigned char *bytes;
+    int prefix_len = 0;
+    size_t len;
+    size_t i;
 
-    while (1) {
-        lim_id = lim_cur = lim_max = ULLONG_MAX;
+    if (!netmask) {
+        return 0;
+    }
+    if (netmask->sa_family == AF_INET) {
+        bytes = (const unsigned char *)&((const struct sockaddr_in *)netmask)->sin_addr;
+        len = 4;
+    } else {
+        bytes = (const unsigned char *)&((const struct sockaddr_in6 *)netmask)->sin6_addr;
+        len = 16;
+    }
+    for (i = 0; i < len; i++) {
+        prefix_len += __builtin_popcount(bytes[i]);
+    }
+    return prefix_len;
+}
 
-        lim_id = strtoull(item, &item, 10);
-        if (lim_id == ULLONG_MAX) {
-            printf("Invalid rlimit ID\n");
+/* Reports the IPv4 and IPv6 addresses of the interfaces, but the loopback one. */
+void report_addresses(FILE *out)
+{
+    char address[INET6_ADDRSTRLEN];
+    struct ifaddrs *ifaddrs;
+    struct ifaddrs *ifa;
+    const void *addr;
+    int family;
+
+    if (getifaddrs(&ifaddrs) < 0) {
+        return;
+    }
+    for (ifa = ifaddrs; ifa; ifa = ifa->ifa_next) {
+        if (!ifa->ifa_addr || (ifa->ifa_flags & IFF_LOOPBACK)) {
+            continue;
+        }
+        family = ifa->ifa_addr->sa_family;
+        if (family == AF_INET) {
+            addr = &((struct sockaddr_in *)ifa->ifa_addr)->sin_addr;
+        } else if (family == AF_INET6) {
+            addr = &((struct sockaddr_in6 *)ifa->ifa_addr)->sin6_addr;
+        } else {
+            continue;
+        }
+        if (inet_ntop(family, addr, address, sizeof address)) {
+            fprintf(out, "addr %s %s/%d\n", ifa->ifa_name, address,
+                    netmask_prefix_len(ifa->ifa_netmask));
+        }
+    }
+    freeifaddrs(ifaddrs);
+}
+
+/*
+ * Reports the IPv4 routes, from /proc/net/route, whose addresses are the hexadecimal values of
+ * the addresses in network byte order.
+ */
+void report_ipv4_routes(FILE *out)
+{
+    char destination[INET_ADDRSTRLEN];
+    char gateway[INET_ADDRSTRLEN];
+    char iface[IFNAMSIZ];
+    unsigned int dest_addr, gateway_addr, flags, mask;
+    struct in_addr addr;
+    char line[256];
+    FILE *routes;
+
+    routes = fopen("/proc/net/route", "r");
+    if (!routes) {
+        return;
+    }
+    /* Skips the header. */
+    if (!fgets(line, sizeof line, routes)) {
+        fclose(routes);
+        return;
+    }
+    while (fgets(line, sizeof line, routes)) {
+        if (sscanf(line, "%15s %x %x %x %*d %*d %*d %x", iface, &dest_addr, &gateway_addr, &flags,
+                   &mask) != 5 || !(flags & ROUTE_UP)) {
+            continue;
+        }
+        addr.s_addr = dest_addr;
+        inet_ntop(AF_INET, &addr, destination, sizeof destination);
+        strcpy(gateway, "-");
+        if (flags & ROUTE_GATEWAY) {
+            addr.s_addr = gateway_addr;
+            inet_ntop(AF_INET, &addr, gateway, sizeof gateway);
+        }
+        fprintf(out, "route %s/%d %s %s\n", destination, __builtin_popcount(mask), gateway, iface);
+    }
+    fclose(routes);
+}
+
+/* Parses an IPv6 address written as 32 hexadecimal digits. */
+int parse_ipv6_hex(const char *hex, struct in6_addr *addr)
+{
+    unsigned int byte;
+    int i;
+
+    for (i = 0; i < 16; i++) {
+        if (sscanf(hex + 2 * i, "%2x", &byte) != 1) {
+            return -1;
+        }
+        addr->s6_addr[i] = byte;
+    }
+    return 0;
+}
+
+/*
+ * Reports the IPv6 routes, from /proc/net/ipv6_route, but those of the local addresses, the
+ * multicast ones and those through the loopback interface.
+ */
+void report_ipv6_routes(FILE *out)
+{
+    char destination[INET6_ADDRSTRLEN];
+    char gateway[INET6_ADDRSTRLEN];
+    char dest_hex[33], nexthop_hex[33];
+    struct in6_addr dest_addr, nexthop_addr;
+    unsigned int prefix_len, flags;
+    char iface[IFNAMSIZ];
+    char line[256];
+    FILE *routes;
+
+    routes = fopen("/proc/net/ipv6_route", "r");
+    if (!routes) {
+        return;
+    }
+    while (fgets(line, sizeof line, routes)) {
+        if (sscanf(line, "%32s %x %*s %*s %32s %*s %*s %*s %x %15s", dest_hex, &prefix_len,
+                   nexthop_hex, &flags, iface) != 5 ||
+            !(flags & ROUTE_UP) || (flags & ROUTE_LOCAL) || strcmp(iface, "lo") == 0) {
+            continue;
+        }
+        if (parse_ipv6_hex(dest_hex, &dest_addr) < 0 ||
+            parse_ipv6_hex(nexthop_hex, &nexthop_addr) < 0 || IN6_IS_ADDR_MULTICAST(&dest_addr)) {
+            continue;
+        }
+        inet_ntop(AF_INET6, &dest_addr, destination, sizeof destination);
+        strcpy(gateway, "-");
+        if (!IN6_IS_ADDR_UNSPECIFIED(&nexthop_addr)) {
+            inet_ntop(AF_INET6, &nexthop_addr, gateway, sizeof gateway);
+        }
+        fprintf(out, "route %s/%u %s %s\n", destination, prefix_len, gateway, iface);
+    }
+    fclose(routes);
+}
+
+/* Reports the nameservers of /etc/resolv.conf, skipping those with a scope. */
+void report_nameservers(FILE *out)
+{
+    char nameserver[INET6_ADDRSTRLEN];
+    unsigned char addr[sizeof(struct in6_addr)];
+    char line[256];
+    FILE *resolv;
+
+    resolv = fopen("/etc/resolv.conf", "r");
+    if (!resolv) {
+        return;
+    }
+    while (fgets(line, sizeof line, resolv)) {
+        if (sscanf(line, " nameserver %45s", nameserver) != 1) {
+            continue;
+        }
+        if (inet_pton(AF_INET, nameserver, addr) == 1 ||
+            inet_pton(AF_INET6, nameserver, addr) == 1) {
+            fprintf(out, "dns %s\n", nameserver);
+        }
+    }
+    fclose(resolv);
+}
+
+/*
+ * Reports the network configuration on the requests of the host, read from the console
+ * `device`. Each request is a command and a sequence number, replied to with the sequence number,
+ * the length of the report on two bytes in big-endian order, and the report, one line per
+ * address, route and nameserver. The configuration is read anew on each request, so it reflects
+ * the changes made by the workload. The requests are served by a child process, left running
+ * once init is replaced.
+ */
+void start_guest_network_agent(const char *device)
+{
+    unsigned char request[2];
+    unsigned char header[3];
+    struct termios tty;
+    size_t report_len;
+    char *report;
+    FILE *out;
+    ssize_t len;
+    size_t got;
+    int fd;
+
+    fd = open(device, O_RDWR | O_NOCTTY | O_CLOEXEC);
+    if (fd < 0) {
+        perror(device);
+        return;
+    }
+
+    /* The requests are binary, so the terminal mustn't translate them. */
+    if (tcgetattr(fd, &tty) == 0) {
+        cfmakeraw(&tty);
+        tcsetattr(fd, TCSANOW, &tty);
+    }
+
+    switch (fork()) {
+    case 0:
+        break;
+    case -1:
+        perror("fork(guest_network)");
+        /* fallthrough */
+    default:
+        close(fd);
+        return;
+    }
+
+    for (got = 0;;) {
+        len = read(fd, request + got, sizeof request - got);
+        if (len <= 0) {
+            if (len < 0 && errno == EINTR) {
+                continue;
+            }
+            perror(device);
+            exit(1);
+        }
+        got += len;
+        if (got < sizeof request) {
+            continue;
+        }
+        got = 0;
+
+        /* Requests of unknown commands are replied to with an empty report. */
+        report = NULL;
+        report_len = 0;
+        out = open_memstream(&report, &report_len);
+        if (out) {
+            if (request[0] == GUEST_NETWORK_QUERY) {
+                report_addresses(out);
+                report_ipv4_routes(out);
+                report_ipv6_routes(out);
+                report_nameservers(out);
+            }
+            fclose(out);
+        }
+        /* Only whole lines are reported. */
+        if (report_len > GUEST_NETWORK_REPORT_MAX) {
+            report_len = GUEST_NETWORK_REPORT_MAX;
+            while (report_len > 0 && report[report_len - 1] != '\n') {
+                report_len--;
+            }
+        }
+
+        header[0] = request[1];
+        header[1] = report_len >> 8;
+        header[2] = report_len & 0xff;
+        if (write_all(fd, header, sizeof header) < 0 ||
+            write_all(fd, (unsigned char *)report, report_len) < 0) {
+            perror(device);
+        }
+        free(report);
+    }
+}
+
+/* Tells the VMM the service `index` is now in `state`. */
+void signal_service(int index, int state)
+{
+    signal_vmm(KRUN_SIGNAL_SERVICE_BASE + index * KRUN_SERVICE_STATES + state);
+}
+
+/* Runs the command of the service `index` once, reporting it to the VMM, until it exits. */
+void run_service_command(int index, char **argv)
+{
+    int status;
+    pid_t pid;
+
+    pid = fork();
+    if (pid == 0) {
+        execv(argv[0], argv);
+        perror(argv[0]);
+        _exit(127);
+    }
+    if (pid < 0) {
+        perror("fork(service)");
+        signal_service(index, KRUN_SERVICE_FAILED);
+        return;
+    }
+
+    signal_service(index, KRUN_SERVICE_RUNNING);
+    if (waitpid(pid, &status, 0) < 0) {
+        perror("waitpid(service)");
+        signal_service(index, KRUN_SERVICE_FAILED);
+        return;
+    }
+    if (WIFEXITED(status) && WEXITSTATUS(status) == 0) {
+        signal_service(index, KRUN_SERVICE_EXITED);
+    } else {
+        signal_service(index, KRUN_SERVICE_FAILED);
+    }
+}
+
+/*
+ * Starts the service `index`. `spec` holds its restart policy, "oneshot" or "always", followed by
+ * the arguments of its command, separated by commas. A one-shot service runs to completion before
+ * this returns. Any other service is supervised by a child process, left running once init is
+ * replaced, restarting it whenever it exits.
+ */
+void start_service(int index, char *spec)
+{
+    char *restart;
+    char **argv;
+    char *item;
+    size_t argc;
+    size_t i;
+
+    restart = strsep(&spec, ",");
+    if (!spec || !*spec) {
+        printf("Invalid service\n");
+        return;
+    }
+
+    for (argc = 1, item = spec; *item; item++) {
+        if (*item == ',') {
+            argc++;
+        }
+    }
+    argv = calloc(argc + 1, sizeof(char *));
+    if (!argv) {
+        return;
+    }
+    for (i = 0; i < argc; i++) {
+        argv[i] = strsep(&spec, ",");
+    }
+
+    if (strcmp(restart, "oneshot") == 0) {
+        run_service_command(index, argv);
+        free(argv);
+        return;
+    }
+
+    switch (fork()) {
+    case 0:
+        break;
+    case -1:
+        perror("fork(service supervisor)");
+        signal_service(index, KRUN_SERVICE_FAILED);
+        /* fallthrough */
+    default:
+        free(argv);
+        return;
+    }
+
+    for (;;) {
+        run_service_command(index, argv);
+        sleep_ms(SERVICE_RESTART_DELAY_MS);
+    }
+}
+
+/* Starts the services, in order, as configured by the VMM. */
+void start_services(void)
+{
+    char name[32];
+    char *service;
+    int i;
+
+    for (i = 0; i < MAX_SERVICES; i++) {
+        snprintf(name, sizeof(name), "KRUN_SERVICE_%d", i);
+        service = getenv(name);
+        if (!service) {
             break;
         }
+        start_service(i, service);
+    }
+}
+
+/* Overrides the search path and the locale of the workload, as configured by the VMM. */
+void apply_exec_env(void)
+{
+    char *path;
+    char *lang;
+
+    path = getenv("KRUN_PATH");
+    if (path && setenv("PATH", path, 1) < 0) {
+        perror("setenv(PATH)");
+    }
+
+    lang = getenv("KRUN_LANG");
+    if (lang) {
+        if (setenv("LANG", lang, 1) < 0) {
+            perror("setenv(LANG)");
+        }
+        unsetenv("LC_ALL");
+    }
+}
 
-        item++;
-        lim_cur = strtoull(item, &item, 10);
-        item++;
-        lim_max = strtoull(item, &item, 10);
+/*
+ * Looks `uid` up in "/etc/passwd", copying its name and its home directory. Returns 0 if it has
+ * an entry, parsed by hand as the NSS of a static init can't be relied on.
+ */
+int lookup_passwd(uid_t uid, char *name, size_t name_size, char *home, size_t home_size)
+{
+    char line[1024];
+    char *fields[7];
+    char *cursor;
+    FILE *passwd;
+    int found = -1;
+    int i;
 
-        rlim.rlim_cur = lim_cur;
-        rlim.rlim_max = lim_max;
-        if (setrlimit(lim_id, &rlim) != 0) {
-            printf("Error setting rlimit for ID=%lld\n", lim_id);
+    passwd = fopen("/etc/passwd", "r");
+    if (!passwd) {
+        return -1;
+    }
+
+    while (found != 0 && fgets(line, sizeof(line), passwd)) {
+        line[strcspn(line, "\n")] = '\0';
+        cursor = line;
+        for (i = 0; i < 7; i++) {
+            fields[i] = strsep(&cursor, ":");
+            if (!fields[i]) {
+                break;
+            }
+        }
+        if (i < 7 || strtoul(fields[2], NULL, 10) != uid) {
+            continue;
         }
+        if (strlen(fields[0]) < name_size && strlen(fields[5]) < home_size) {
+            strcpy(name, fields[0]);
+            strcpy(home, fields[5]);
+            found = 0;
+        }
+    }
 
-        if (*item != '\0') {
-            item++;
-        } else {
+    fclose(passwd);
+    return found;
+}
+
+/* Creates the directory `path`, owned by `uid` and `gid`, if missing. */
+void create_user_dir(const char *path, mode_t mode, uid_t uid, gid_t gid)
+{
+    if (access(path, F_OK) == 0) {
+        return;
+    }
+    if (mkdir_p(path, mode) < 0 || chown(path, uid, gid) < 0) {
+        perror(path);
+    }
+}
+
+/*
+ * Switches to the user the workload is executed as. `spec` holds the user ID, the group ID and the
+ * comma-separated supplementary groups, separated by colons. The home directory of the user and
+ * its runtime directory are set up first, while still root. Exits on failure, stopping the
+ * microVM, rather than executing the workload as root.
+ */
+void switch_user(char *spec)
+{
+    gid_t groups[MAX_SUPPLEMENTARY_GROUPS];
+    char runtime_dir[PATH_MAX];
+    char home[PATH_MAX];
+    char name[256];
+    char *groups_spec;
+    char *group;
+    size_t ngroups = 0;
+    uid_t uid;
+    gid_t gid;
+
+    uid = strtoul(strsep(&spec, ":"), NULL, 10);
+    gid = spec ? strtoul(strsep(&spec, ":"), NULL, 10) : 0;
+    groups_spec = spec;
+    if (!groups_spec) {
+        printf("Invalid user\n");
+        exit(-1);
+    }
+    while ((group = strsep(&groups_spec, ",")) && *group) {
+        if (ngroups == MAX_SUPPLEMENTARY_GROUPS) {
             break;
         }
+        groups[ngroups++] = strtoul(group, NULL, 10);
+    }
+
+    if (lookup_passwd(uid, name, sizeof(name), home, sizeof(home)) == 0) {
+        create_user_dir(home, 0755, uid, gid);
+        setenv("HOME", home, 1);
+        setenv("USER", name, 1);
+        setenv("LOGNAME", name, 1);
+    }
+
+    snprintf(runtime_dir, sizeof(runtime_dir), USER_RUNTIME_DIR "/%u", uid);
+    mkdir_p(USER_RUNTIME_DIR, 0755);
+    create_user_dir(runtime_dir, 0700, uid, gid);
+    if (access(runtime_dir, F_OK) == 0) {
+        setenv("XDG_RUNTIME_DIR", runtime_dir, 1);
+    }
+
+    if (setgroups(ngroups, groups) < 0 || setgid(gid) < 0 || setuid(uid) < 0) {
+        perror("switching to the user of the workload");
+        exit(-1);
+    }
+}
+
+/* The system calls affecting the whole guest, denied by the default seccomp profile. */
+static const int DEFAULT_DENIED_SYSCALLS[] = {
+    SYS_acct, SYS_add_key, SYS_bpf, SYS_clock_adjtime, SYS_clock_settime, SYS_delete_module,
+    SYS_finit_module, SYS_init_module, SYS_kcmp, SYS_kexec_load, SYS_keyctl, SYS_mount,
+    SYS_move_pages, SYS_name_to_handle_at, SYS_open_by_handle_at, SYS_perf_event_open,
+    SYS_pivot_root, SYS_quotactl, SYS_reboot, SYS_request_key, SYS_settimeofday, SYS_swapoff,
+    SYS_swapon, SYS_umount2, SYS_userfaultfd,
+#ifdef SYS_kexec_file_load
+    SYS_kexec_file_load,
+#endif
+#ifdef SYS_lookup_dcookie
+    SYS_lookup_dcookie,
+#endif
+#ifdef SYS_ioperm
+    SYS_ioperm,
+#endif
+#ifdef SYS_iopl
+    SYS_iopl,
+#endif
+#ifdef SYS_uselib
+    SYS_uselib,
+#endif
+#ifdef SYS_ustat
+    SYS_ustat,
+#endif
+};
+
+/* The system calls also denied by the strict seccomp profile. */
+static const int STRICT_DENIED_SYSCALLS[] = {
+    SYS_chroot, SYS_personality, SYS_process_vm_readv, SYS_process_vm_writev, SYS_ptrace,
+    SYS_setns, SYS_syslog, SYS_unshare, SYS_vhangup,
+};
+
+#define ARRAY_SIZE(array) (sizeof(array) / sizeof((array)[0]))
+
+/*
+ * Denies the system calls of the seccomp profile `profile`, "default" or "strict", to this process
+ * and the ones it executes, failing them with EPERM. The system calls of other ABIs kill the
+ * process, as their numbers differ, and those of x32 are denied. Exits on failure, stopping the
+ * microVM, rather than executing the workload unconfined.
+ */
+void apply_seccomp(const char *profile)
+{
+    struct sock_fprog prog;
+    struct sock_filter *filter;
+    size_t ndenied, len, i;
+    int strict;
+    int nr;
+
+    strict = strcmp(profile, "strict") == 0;
+    if (!strict && strcmp(profile, "default") != 0) {
+        printf("Invalid seccomp profile: %s\n", profile);
+        exit(-1);
+    }
+
+    ndenied = ARRAY_SIZE(DEFAULT_DENIED_SYSCALLS);
+    if (strict) {
+        ndenied += ARRAY_SIZE(STRICT_DENIED_SYSCALLS);
+    }
+    filter = calloc(ndenied + 7, sizeof(struct sock_filter));
+    if (!filter) {
+        exit(-1);
+    }
+
+    len = 0;
+    filter[len++] = (struct sock_filter) BPF_STMT(BPF_LD | BPF_W | BPF_ABS,
+                                                  offsetof(struct seccomp_data, arch));
+    filter[len++] = (struct sock_filter) BPF_JUMP(BPF_JMP | BPF_JEQ | BPF_K,
+                                                  SECCOMP_AUDIT_ARCH, 1, 0);
+    filter[len++] = (struct sock_filter) BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_KILL);
+    filter[len++] = (struct sock_filter) BPF_STMT(BPF_LD | BPF_W | BPF_ABS,
+                                                  offsetof(struct seccomp_data, nr));
+#ifdef X32_SYSCALL_BIT
+    filter[len++] = (struct sock_filter) BPF_JUMP(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT,
+                                                  ndenied + 1, 0);
+#endif
+    /* Each match jumps over the remaining ones and the allowing return, to the denying one. */
+    for (i = 0; i < ndenied; i++) {
+        nr = i < ARRAY_SIZE(DEFAULT_DENIED_SYSCALLS)
+                 ? DEFAULT_DENIED_SYSCALLS[i]
+                 : STRICT_DENIED_SYSCALLS[i - ARRAY_SIZE(DEFAULT_DENIED_SYSCALLS)];
+        filter[len++] = (struct sock_filter) BPF_JUMP(BPF_JMP | BPF_JEQ | BPF_K, nr,
+                                                      ndenied - i, 0);
+    }
+    filter[len++] = (struct sock_filter) BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_ALLOW);
+    filter[len++] = (struct sock_filter) BPF_STMT(BPF_RET | BPF_K, SECCOMP_RET_ERRNO | EPERM);
+
+    prog.len = len;
+    prog.filter = filter;
+    if (prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog) < 0) {
+        perror("prctl(PR_SET_SECCOMP)");
+        exit(-1);
+    }
+    free(filter);
+}
+
+/*
+ * Drops the capabilities missing from the mask `keep` from the bounding set, so neither the
+ * workload nor the programs it executes, even setuid ones, can gain them.
+ */
+void drop_capability_bounding_set(uint64_t keep)
+{
+    int cap;
+
+    for (cap = 0; cap < 64; cap++) {
+        if (keep & (1ULL << cap)) {
+            continue;
+        }
+        if (prctl(PR_CAPBSET_DROP, cap, 0, 0, 0) < 0) {
+            if (errno == EINVAL) {
+                /* Past the last capability of the kernel. */
+                break;
+            }
+            perror("prctl(PR_CAPBSET_DROP)");
+            exit(-1);
+        }
+    }
+}
+
+/* Removes the capabilities missing from the mask `keep` from those of this process. */
+void restrict_capabilities(uint64_t keep)
+{
+    struct __user_cap_header_struct header = {
+        .version = _LINUX_CAPABILITY_VERSION_3,
+        .pid = 0,
+    };
+    struct __user_cap_data_struct data[_LINUX_CAPABILITY_U32S_3];
+    int i;
+
+    if (syscall(SYS_capget, &header, data) < 0) {
+        perror("capget");
+        exit(-1);
+    }
+    for (i = 0; i < _LINUX_CAPABILITY_U32S_3; i++) {
+        data[i].effective &= keep >> (32 * i);
+        data[i].permitted &= keep >> (32 * i);
+        data[i].inheritable &= keep >> (32 * i);
+    }
+    if (syscall(SYS_capset, &header, data) < 0) {
+        perror("capset");
+        exit(-1);
+    }
+}
+
+/*
+ * Executes the workload through a login shell, so the profiles of the guest are applied to its
+ * environment. The overrides of the search path and the locale are applied again once the
+ * profiles have run, and the arguments are passed to the workload untouched. Only returns on
+ * failure.
+ */
+void exec_login_shell(int argc, char **argv)
+{
+    static char script[] = "[ -n \"$KRUN_PATH\" ] && export PATH=\"$KRUN_PATH\"; "
+                           "[ -n \"$KRUN_LANG\" ] && export LANG=\"$KRUN_LANG\" && unset LC_ALL; "
+                           "exec \"$0\" \"$@\"";
+    char **shell_argv;
+    int i;
+
+    shell_argv = calloc(argc + 5, sizeof(char *));
+    if (!shell_argv) {
+        perror("calloc(login shell)");
+        return;
     }
+    shell_argv[0] = LOGIN_SHELL;
+    shell_argv[1] = "-l";
+    shell_argv[2] = "-c";
+    shell_argv[3] = script;
+    for (i = 0; i < argc; i++) {
+        shell_argv[i + 4] = argv[i];
+    }
+
+    execv(shell_argv[0], shell_argv);
+    perror("execv(login shell)");
+    free(shell_argv);
 }
 
 int main(int argc, char **argv)
@@ -54,8 +1851,21 @@ int main(int argc, char **argv)
     int sockfd;
     char *hostname;
     char *krun_init;
+    char *sysctls;
+    char *cgroup;
     char *workdir;
     char *rlimits;
+    char *log_channel;
+    char *fsfreeze;
+    char *guest_network;
+    char *probe;
+    char *user;
+    char *caps;
+    char *seccomp;
+    char *shares;
+    char *swap;
+    char *size_mib;
+    char tmpfs_opts[64];
 
     if (mount("proc", "/proc", "proc",
               MS_NODEV | MS_NOEXEC | MS_NOSUID | MS_RELATIME, NULL) < 0) {
@@ -88,16 +1898,65 @@ int main(int argc, char **argv)
 
     /* Ignore error */
     mkdir("/dev/shm", 0755);
+    size_mib = getenv("KRUN_SHM_SIZE_MIB");
+    if (size_mib) {
+        snprintf(tmpfs_opts, sizeof tmpfs_opts, "size=%sm", size_mib);
+    }
     if (mount("tmpfs", "/dev/shm", "tmpfs",
-              MS_NOEXEC | MS_NOSUID | MS_RELATIME, NULL) < 0) {
+              MS_NOEXEC | MS_NOSUID | MS_RELATIME, size_mib ? tmpfs_opts : NULL) < 0) {
         perror("mount(/dev/shm)");
         exit(-1);
     }
 
+    size_mib = getenv("KRUN_TMP_SIZE_MIB");
+    if (size_mib) {
+        /* Ignore error */
+        mkdir("/tmp", 01777);
+        snprintf(tmpfs_opts, sizeof tmpfs_opts, "mode=1777,size=%sm", size_mib);
+        if (mount("tmpfs", "/tmp", "tmpfs",
+                  MS_NODEV | MS_NOSUID | MS_RELATIME, tmpfs_opts) < 0) {
+            perror("mount(/tmp)");
+        }
+    }
+
     /* May fail if already exists and that's fine. */
     symlink("/proc/self/fd", "/dev/fd");
 
-    hostname = getenv("HOSTNAME");
+    shares = getenv("KRUN_SHARES");
+    if (shares) {
+        mount_shares(shares);
+    }
+
+    if (getenv("KRUN_CLOUD_INIT")) {
+        mount_cloud_init_seed();
+    }
+
+    if (getenv("KRUN_KERNEL_MODULES")) {
+        load_kernel_modules();
+    }
+
+    if (getenv("KRUN_ETC")) {
+        override_etc_files();
+    }
+
+    if (getenv("KRUN_CA_CERTS")) {
+        install_ca_certs();
+    }
+
+    if (getenv("KRUN_SECRETS")) {
+        read_secrets();
+    }
+
+    swap = getenv("KRUN_SWAP");
+    if (swap) {
+        enable_swap(swap);
+    }
+
+    /* The hostname configured for the microVM takes precedence over the environment. */
+    hostname = getenv("KRUN_HOSTNAME");
+    if (!hostname) {
+        hostname = getenv("HOSTNAME");
+    }
     if (hostname) {
         sethostname(hostname, strlen(hostname));
     }
@@ -114,22 +1973,89 @@ int main(int argc, char **argv)
         close(sockfd);
     }
 
+    log_channel = getenv("KRUN_LOG_CHANNEL");
+    if (log_channel) {
+        forward_logs(log_channel);
+    }
+
+    fsfreeze = getenv("KRUN_FSFREEZE");
+    if (fsfreeze) {
+        start_fsfreeze_agent(fsfreeze);
+    }
+
+    guest_network = getenv("KRUN_GUEST_NETWORK");
+    if (guest_network) {
+        start_guest_network_agent(guest_network);
+    }
+
     rlimits = getenv("KRUN_RLIMITS");
     if (rlimits) {
         set_rlimits(rlimits);
     }
 
+    sysctls = getenv("KRUN_SYSCTLS");
+    if (sysctls) {
+        apply_sysctls(sysctls);
+    }
+
+    cgroup = getenv("KRUN_CGROUP");
+    if (cgroup) {
+        setup_workload_cgroup(cgroup);
+    }
+
     workdir = getenv("KRUN_WORKDIR");
     if (workdir) {
         chdir(workdir);
     }
 
+    probe = getenv("KRUN_READINESS_PROBE");
+    if (probe) {
+        start_probe(probe, KRUN_SIGNAL_READINESS_SUCCESS, KRUN_SIGNAL_READINESS_FAILURE);
+    }
+
+    probe = getenv("KRUN_LIVENESS_PROBE");
+    if (probe) {
+        start_probe(probe, KRUN_SIGNAL_LIVENESS_SUCCESS, KRUN_SIGNAL_LIVENESS_FAILURE);
+    }
+
     krun_init = getenv("KRUN_INIT");
     if (!krun_init) {
         krun_init = &DEFAULT_KRUN_INIT[0];
     }
     argv[0] = krun_init;
 
+    apply_exec_env();
+    start_services();
+
+    /*
+     * The seccomp filter is installed first, as it needs CAP_SYS_ADMIN without no_new_privs, and
+     * none of the system calls left to init are denied.
+     */
+    if (getenv("KRUN_NO_NEW_PRIVS") && prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0) {
+        perror("prctl(PR_SET_NO_NEW_PRIVS)");
+        exit(-1);
+    }
+    seccomp = getenv("KRUN_SECCOMP");
+    if (seccomp) {
+        apply_seccomp(seccomp);
+    }
+    caps = getenv("KRUN_CAPS");
+    if (caps) {
+        drop_capability_bounding_set(strtoull(caps, NULL, 16));
+    }
+
+    user = getenv("KRUN_USER");
+    if (user) {
+        switch_user(user);
+    }
+    if (caps) {
+        restrict_capabilities(strtoull(caps, NULL, 16));
+    }
+
+    signal_workload_started();
+    if (getenv("KRUN_LOGIN_SHELL")) {
+        exec_login_shell(argc, argv);
+    }
     execv(argv[0], argv);
 
     return 0;
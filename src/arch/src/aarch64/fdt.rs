@@ -20,11 +20,16 @@ use super::layout::{FDT_MAX_SIZE, GTIMER_HYP, GTIMER_PHYS, GTIMER_SEC, GTIMER_VI
 use aarch64::fdt::Error::CstringFDTTransform;
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 use ArchMemoryInfo;
+use CpuTopology;
 
 // This is a value for uniquely identifying the FDT node declaring the interrupt controller.
 const GIC_PHANDLE: u32 = 1;
 // This is a value for uniquely identifying the FDT node containing the clock definition.
 const CLOCK_PHANDLE: u32 = 2;
+// This is a value for uniquely identifying the FDT node reserving the restricted DMA pool.
+const DMA_POOL_PHANDLE: u32 = 3;
+// The FDT nodes of the vCPUs are identified from this value onward, in the order of their index.
+const FIRST_CPU_PHANDLE: u32 = 0x100;
 // Read the documentation specified when appending the root node to the FDT.
 const ADDRESS_CELLS: u32 = 0x2;
 const SIZE_CELLS: u32 = 0x2;
@@ -87,10 +92,13 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
     vcpu_mpidr: Vec<u64>,
+    topology: &CpuTopology,
     cmdline: &CStr,
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<InitrdConfig>,
+    rng_seed: Option<&[u8]>,
+    dma_pool: Option<(u64, u64)>,
 ) -> Result<Vec<u8>> {
     // Alocate stuff necessary for the holding the blob.
     let mut fdt = vec![0; FDT_MAX_SIZE];
@@ -111,14 +119,17 @@ pub fn create_fdt<T: DeviceInfoForFDT + Clone + Debug>(
     // This is not mandatory but we use it to point the root node to the node
     // containing description of the interrupt controller for this VM.
     append_property_u32(&mut fdt, "interrupt-parent", GIC_PHANDLE)?;
-    create_cpu_nodes(&mut fdt, &vcpu_mpidr)?;
+    create_cpu_nodes(&mut fdt, &vcpu_mpidr, topology)?;
     create_memory_node(&mut fdt, guest_mem, arch_memory_info)?;
-    create_chosen_node(&mut fdt, cmdline, initrd)?;
+    if let Some(dma_pool) = dma_pool {
+        create_dma_pool_node(&mut fdt, dma_pool)?;
+    }
+    create_chosen_node(&mut fdt, cmdline, initrd, rng_seed)?;
     create_gic_node(&mut fdt, gic_device)?;
     create_timer_node(&mut fdt)?;
     create_clock_node(&mut fdt)?;
     create_psci_node(&mut fdt)?;
-    create_devices_node(&mut fdt, device_info)?;
+    create_devices_node(&mut fdt, device_info, dma_pool.is_some())?;
 
     // End Header node.
     append_end_node(&mut fdt)?;
@@ -301,7 +312,11 @@ fn generate_prop64(cells: &[u64]) -> Vec<u8> {
 }
 
 // Following are the auxiliary function for creating the different nodes that we append to our FDT.
-fn create_cpu_nodes(fdt: &mut Vec<u8>, vcpu_mpidr: &Vec<u64>) -> Result<()> {
+fn create_cpu_nodes(
+    fdt: &mut Vec<u8>,
+    vcpu_mpidr: &Vec<u64>,
+    topology: &CpuTopology,
+) -> Result<()> {
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/cpus.yaml.
     append_begin_node(fdt, "cpus")?;
     // As per documentation, on ARM v8 64-bit systems value should be set to 2.
@@ -321,6 +336,45 @@ fn create_cpu_nodes(fdt: &mut Vec<u8>, vcpu_mpidr: &Vec<u64>) -> Result<()> {
         // Set the field to first 24 bits of the MPIDR - Multiprocessor Affinity Register.
         // See http://infocenter.arm.com/help/index.jsp?topic=/com.arm.doc.ddi0488c/BABHBJCI.html.
         append_property_u64(fdt, "reg", vcpu_mpidr[cpu_index] & 0x7FFFFF)?;
+        if num_cpus > 1 {
+            append_property_u32(fdt, "phandle", FIRST_CPU_PHANDLE + cpu_index as u32)?;
+        }
+        append_end_node(fdt)?;
+    }
+    if num_cpus > 1 {
+        create_cpu_map_node(fdt, num_cpus, topology)?;
+    }
+    append_end_node(fdt)?;
+    Ok(())
+}
+
+// Lays the vCPUs out in sockets, cores and threads, each socket holding a single cluster.
+// See https://www.kernel.org/doc/Documentation/devicetree/bindings/cpu/cpu-topology.txt.
+fn create_cpu_map_node(fdt: &mut Vec<u8>, num_cpus: usize, topology: &CpuTopology) -> Result<()> {
+    append_begin_node(fdt, "cpu-map")?;
+    let mut cpu_index = 0;
+    for socket in 0..topology.sockets {
+        append_begin_node(fdt, &format!("socket{}", socket))?;
+        append_begin_node(fdt, "cluster0")?;
+        for core in 0..topology.cores_per_socket {
+            append_begin_node(fdt, &format!("core{}", core))?;
+            for thread in 0..topology.threads_per_core {
+                if cpu_index >= num_cpus {
+                    break;
+                }
+                let phandle = FIRST_CPU_PHANDLE + cpu_index as u32;
+                cpu_index += 1;
+                if topology.threads_per_core > 1 {
+                    append_begin_node(fdt, &format!("thread{}", thread))?;
+                    append_property_u32(fdt, "cpu", phandle)?;
+                    append_end_node(fdt)?;
+                } else {
+                    append_property_u32(fdt, "cpu", phandle)?;
+                }
+            }
+            append_end_node(fdt)?;
+        }
+        append_end_node(fdt)?;
         append_end_node(fdt)?;
     }
     append_end_node(fdt)?;
@@ -344,10 +398,29 @@ fn create_memory_node(
     Ok(())
 }
 
+// Reserves the pool the virtio devices are restricted to, through which the guest bounces their
+// buffers. See https://www.kernel.org/doc/Documentation/devicetree/bindings/reserved-memory/shared-dma-pool.yaml
+fn create_dma_pool_node(fdt: &mut Vec<u8>, (start, size): (u64, u64)) -> Result<()> {
+    append_begin_node(fdt, "reserved-memory")?;
+    append_property_u32(fdt, "#address-cells", ADDRESS_CELLS)?;
+    append_property_u32(fdt, "#size-cells", SIZE_CELLS)?;
+    append_property_null(fdt, "ranges")?;
+
+    append_begin_node(fdt, &format!("restricted_dma@{:x}", start))?;
+    append_property_string(fdt, "compatible", "restricted-dma-pool")?;
+    append_property(fdt, "reg", &generate_prop64(&[start, size]))?;
+    append_property_u32(fdt, "phandle", DMA_POOL_PHANDLE)?;
+    append_end_node(fdt)?;
+
+    append_end_node(fdt)?;
+    Ok(())
+}
+
 fn create_chosen_node(
     fdt: &mut Vec<u8>,
     cmdline: &CStr,
     initrd: &Option<InitrdConfig>,
+    rng_seed: Option<&[u8]>,
 ) -> Result<()> {
     append_begin_node(fdt, "chosen")?;
     append_property_cstring(fdt, "bootargs", cmdline)?;
@@ -365,6 +438,11 @@ fn create_chosen_node(
         )?;
     }
 
+    // The guest kernel credits the seed to its random number generator, and wipes it.
+    if let Some(seed) = rng_seed {
+        append_property(fdt, "rng-seed", seed)?;
+    }
+
     append_end_node(fdt)?;
 
     Ok(())
@@ -454,6 +532,7 @@ fn create_psci_node(fdt: &mut Vec<u8>) -> Result<()> {
 fn create_virtio_node<T: DeviceInfoForFDT + Clone + Debug>(
     fdt: &mut Vec<u8>,
     dev_info: &T,
+    restricted_dma: bool,
 ) -> Result<()> {
     let device_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
     #[cfg(target_os = "linux")]
@@ -470,6 +549,9 @@ fn create_virtio_node<T: DeviceInfoForFDT + Clone + Debug>(
     append_property(fdt, "reg", &device_reg_prop)?;
     append_property(fdt, "interrupts", &irq)?;
     append_property_u32(fdt, "interrupt-parent", GIC_PHANDLE)?;
+    if restricted_dma {
+        append_property_u32(fdt, "memory-region", DMA_POOL_PHANDLE)?;
+    }
     append_end_node(fdt)?;
 
     Ok(())
@@ -514,9 +596,24 @@ fn create_rtc_node<T: DeviceInfoForFDT + Clone + Debug>(
     Ok(())
 }
 
+fn create_tpm_node<T: DeviceInfoForFDT + Clone + Debug>(
+    fdt: &mut Vec<u8>,
+    dev_info: &T,
+) -> Result<()> {
+    // The device doesn't have an interrupt, so the driver polls it.
+    let tpm_reg_prop = generate_prop64(&[dev_info.addr(), dev_info.length()]);
+    append_begin_node(fdt, &format!("tpm@{:x}", dev_info.addr()))?;
+    append_property_string(fdt, "compatible", "tcg,tpm-tis-mmio")?;
+    append_property(fdt, "reg", &tpm_reg_prop)?;
+    append_end_node(fdt)?;
+
+    Ok(())
+}
+
 fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
     fdt: &mut Vec<u8>,
     dev_info: &HashMap<(DeviceType, String), T>,
+    restricted_dma: bool,
 ) -> Result<()> {
     // Create one temp Vec to store all virtio devices
     let mut ordered_virtio_device: Vec<&T> = Vec::new();
@@ -525,6 +622,7 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
         match device_type {
             DeviceType::RTC => create_rtc_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
+            DeviceType::Tpm => create_tpm_node(fdt, info)?,
             DeviceType::Virtio(_) => {
                 ordered_virtio_device.push(info);
             }
@@ -534,7 +632,7 @@ fn create_devices_node<T: DeviceInfoForFDT + Clone + Debug>(
     // Sort out virtio devices by address from low to high and insert them into fdt table.
     ordered_virtio_device.sort_by(|a, b| a.addr().cmp(&b.addr()));
     for ordered_device_info in ordered_virtio_device.drain(..) {
-        create_virtio_node(fdt, ordered_device_info)?;
+        create_virtio_node(fdt, ordered_device_info, restricted_dma)?;
     }
 
     Ok(())
@@ -613,6 +711,7 @@ mod tests {
             &dev_info,
             &gic,
             &None,
+            None,
         )
         .is_ok())
     }
@@ -631,6 +730,7 @@ mod tests {
             &HashMap::<(DeviceType, std::string::String), MMIODeviceInfo>::new(),
             &gic,
             &None,
+            None,
         )
         .unwrap();
 
@@ -681,6 +781,7 @@ mod tests {
             &HashMap::<(DeviceType, std::string::String), MMIODeviceInfo>::new(),
             &gic,
             &Some(initrd),
+            None,
         )
         .unwrap();
 
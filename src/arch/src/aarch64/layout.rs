@@ -83,3 +83,7 @@ pub const GTIMER_PHYS: u32 = 12;
 
 /// Below this address will reside the GIC, above this address will reside the MMIO devices.
 pub const MAPPED_IO_START: u64 = 1 << 30; // 1 GB
+
+/// Address of the mailbox handing the secrets to the guest, in the page the guest signals the
+/// VMM through, which precedes the MMIO devices.
+pub const SECRETS_MAILBOX_START: u64 = MAPPED_IO_START + 0x800;
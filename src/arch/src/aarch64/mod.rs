@@ -71,17 +71,21 @@ pub fn arch_memory_regions(
 pub fn arch_memory_regions(
     size: usize,
     _kernel_load_addr: u64,
-    _kernel_size: usize,
+    kernel_size: usize,
 ) -> (ArchMemoryInfo, Vec<(GuestAddress, usize)>) {
     let dram_size = min(size as u64, layout::DRAM_MEM_MAX_SIZE) as usize;
     let info = ArchMemoryInfo {
-        ram_last_addr: layout::DRAM_MEM_START + dram_size as u64,
+        ram_last_addr: layout::DRAM_MEM_START + (kernel_size as u64) + (dram_size as u64),
         shm_start_addr: 0,
         shm_size: 0,
     };
+    // As on Linux, the kernel bundle is mapped right before the DRAM, in its own region.
     (
         info,
-        vec![(GuestAddress(layout::DRAM_MEM_START), dram_size)],
+        vec![(
+            GuestAddress(layout::DRAM_MEM_START + kernel_size as u64),
+            dram_size,
+        )],
     )
 }
 
@@ -93,31 +97,55 @@ pub fn arch_memory_regions(
 /// * `guest_mem` - The memory to be used by the guest.
 /// * `cmdline_cstring` - The kernel commandline.
 /// * `vcpu_mpidr` - Array of MPIDR register values per vcpu.
+/// * `topology` - How the vcpus are laid out in sockets, cores and threads.
 /// * `device_info` - A hashmap containing the attached devices for building FDT device nodes.
 /// * `gic_device` - The GIC device.
 /// * `initrd` - Information about an optional initrd.
+/// * `rng_seed` - Entropy to seed the random number generator of the guest kernel with.
+/// * `dma_pool` - The start and the size of the pool the virtio devices are restricted to, if any.
 pub fn configure_system<T: DeviceInfoForFDT + Clone + Debug>(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
     cmdline_cstring: &CStr,
     vcpu_mpidr: Vec<u64>,
+    topology: &super::CpuTopology,
     device_info: &HashMap<(DeviceType, String), T>,
     gic_device: &Box<dyn GICDevice>,
     initrd: &Option<super::InitrdConfig>,
+    rng_seed: Option<&[u8]>,
+    dma_pool: Option<(u64, u64)>,
 ) -> super::Result<()> {
     fdt::create_fdt(
         guest_mem,
         arch_memory_info,
         vcpu_mpidr,
+        topology,
         cmdline_cstring,
         device_info,
         gic_device,
         initrd,
+        rng_seed,
+        dma_pool,
     )
     .map_err(Error::SetupFDT)?;
     Ok(())
 }
 
+/// Returns where a restricted DMA pool of `size` bytes starts: at the top of the DRAM, aligned to
+/// 2 MiB, as the FDT and the initrd are placed at the end of the shared memory region that
+/// follows it. Returns `None` if the DRAM isn't large enough for the pool to leave room for the
+/// guest.
+#[cfg(target_os = "linux")]
+pub fn restricted_dma_pool(arch_memory_info: &ArchMemoryInfo, size: u64) -> Option<u64> {
+    const DMA_POOL_ALIGN: u64 = 2 << 20;
+    let start = arch_memory_info.ram_last_addr.checked_sub(size)? & !(DMA_POOL_ALIGN - 1);
+    let dram_size = arch_memory_info.ram_last_addr - layout::DRAM_MEM_START;
+    if start < layout::DRAM_MEM_START + dram_size / 2 {
+        return None;
+    }
+    Some(start)
+}
+
 /// Returns the memory address where the kernel could be loaded.
 pub fn get_kernel_start() -> u64 {
     layout::DRAM_MEM_START
@@ -188,4 +216,19 @@ mod tests {
         let mem = GuestMemoryMmap::from_ranges(&regions).expect("Cannot initialize memory");
         assert_eq!(get_fdt_addr(&mem), 0x1000 + layout::DRAM_MEM_START);
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_restricted_dma_pool() {
+        let info = ArchMemoryInfo {
+            ram_last_addr: layout::DRAM_MEM_START + (256 << 20) + 0x1000,
+            shm_start_addr: 0,
+            shm_size: 0,
+        };
+        assert_eq!(
+            restricted_dma_pool(&info, 64 << 20),
+            Some(layout::DRAM_MEM_START + (192 << 20))
+        );
+        assert_eq!(restricted_dma_pool(&info, 192 << 20), None);
+    }
 }
@@ -59,6 +59,9 @@ pub enum DeviceType {
     /// Device Type: RTC.
     #[cfg(target_arch = "aarch64")]
     RTC,
+    /// Device Type: TPM.
+    #[cfg(target_arch = "aarch64")]
+    Tpm,
 }
 
 /// Type for passing information about the initrd in the guest memory.
@@ -69,11 +72,117 @@ pub struct InitrdConfig {
     pub size: usize,
 }
 
+/// How the vCPUs are laid out in sockets, cores and threads, as exposed to the guest. The vCPUs
+/// are numbered thread first, then core, then socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuTopology {
+    /// The number of sockets.
+    pub sockets: u8,
+    /// The number of cores in each socket.
+    pub cores_per_socket: u8,
+    /// The number of threads in each core.
+    pub threads_per_core: u8,
+}
+
+impl CpuTopology {
+    /// Returns the layout of `vcpu_count` vCPUs in a single socket, paired in the cores if
+    /// `ht_enabled`.
+    pub fn single_socket(vcpu_count: u8, ht_enabled: bool) -> Self {
+        let threads_per_core = if ht_enabled && vcpu_count > 1 { 2 } else { 1 };
+        CpuTopology {
+            sockets: 1,
+            cores_per_socket: vcpu_count / threads_per_core,
+            threads_per_core,
+        }
+    }
+
+    /// Returns the number of vCPUs, or `None` if it doesn't fit in an `u8`.
+    pub fn vcpu_count(&self) -> Option<u8> {
+        self.sockets
+            .checked_mul(self.cores_per_socket)?
+            .checked_mul(self.threads_per_core)
+    }
+
+    /// Returns whether the guest can be shown this layout. A core has at most 2 threads, and the
+    /// IDs of the vCPUs being their index, the bits identifying the socket in them are only apart
+    /// from the others if there is a power of 2 of cores in each socket.
+    pub fn is_valid(&self) -> bool {
+        self.vcpu_count().map_or(false, |count| count > 0)
+            && self.threads_per_core <= 2
+            && (self.sockets == 1 || self.cores_per_socket.is_power_of_two())
+    }
+
+    /// Returns the socket of the vCPU `cpu_index`, the core within the socket and the thread
+    /// within the core.
+    pub fn locate(&self, cpu_index: u8) -> (u8, u8, u8) {
+        let thread = cpu_index % self.threads_per_core;
+        let core = cpu_index / self.threads_per_core;
+        (
+            core / self.cores_per_socket,
+            core % self.cores_per_socket,
+            thread,
+        )
+    }
+}
+
 /// Default (smallest) memory page size for the supported architectures.
 pub const PAGE_SIZE: usize = 4096;
 
+/// Size of the entropy seed handed to the guest kernel at boot, enough for its generator to be
+/// fully initialized without waiting for other sources of entropy.
+pub const RNG_SEED_SIZE: usize = 64;
+
 impl fmt::Display for DeviceType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_topology() {
+        let topology = CpuTopology::single_socket(4, true);
+        assert_eq!(
+            topology,
+            CpuTopology {
+                sockets: 1,
+                cores_per_socket: 2,
+                threads_per_core: 2,
+            }
+        );
+        assert_eq!(CpuTopology::single_socket(1, true).threads_per_core, 1);
+
+        // 2 sockets of 4 cores of 2 threads.
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 4,
+            threads_per_core: 2,
+        };
+        assert!(topology.is_valid());
+        assert_eq!(topology.vcpu_count(), Some(16));
+        assert_eq!(topology.locate(0), (0, 0, 0));
+        assert_eq!(topology.locate(3), (0, 1, 1));
+        assert_eq!(topology.locate(13), (1, 2, 1));
+
+        for &(sockets, cores_per_socket, threads_per_core) in
+            [(0, 1, 1), (1, 1, 0), (1, 1, 3), (2, 3, 1), (16, 16, 2)].iter()
+        {
+            assert!(!CpuTopology {
+                sockets,
+                cores_per_socket,
+                threads_per_core,
+            }
+            .is_valid());
+        }
+        // A single socket can have any number of cores.
+        assert!(CpuTopology {
+            sockets: 1,
+            cores_per_socket: 3,
+            threads_per_core: 1,
+        }
+        .is_valid());
+    }
+}
@@ -29,3 +29,14 @@ pub const KVM_TSS_ADDRESS: u64 = 0xfffb_d000;
 
 /// The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: u64 = 0x7000;
+
+/// The setup_data entries linked from the zero page, such as the RNG seed.
+pub const SETUP_DATA_START: u64 = 0x6000;
+
+/// Address of the TPM, which the guest driver probes when forced to, as there's no ACPI table
+/// describing it.
+pub const TPM_TIS_START: u64 = 0xfed4_0000;
+
+/// I/O port of the mailbox handing the secrets to the guest, next to the one the guest signals
+/// the VMM through.
+pub const SECRETS_MAILBOX_PORT: u64 = 0x3f4;
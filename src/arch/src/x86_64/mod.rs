@@ -41,12 +41,17 @@ pub enum Error {
     E820Configuration,
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
+    /// Error writing the RNG seed to guest memory.
+    RngSeedSetup,
     /// Error writing the zero page of guest memory.
     ZeroPageSetup,
     /// Failed to compute initrd address.
     InitrdAddress,
 }
 
+// The setup_data type of the RNG seed, from arch/x86/include/uapi/asm/bootparam.h.
+const SETUP_RNG_SEED: u32 = 9;
+
 // Where BIOS/VGA magic would live on a real PC.
 const EBDA_START: u64 = 0x9fc00;
 const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
@@ -143,6 +148,7 @@ pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> supe
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `initrd` - Information about where the ramdisk image was loaded in the `guest_mem`.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `rng_seed` - Entropy to seed the random number generator of the guest kernel with.
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     arch_memory_info: &ArchMemoryInfo,
@@ -150,6 +156,7 @@ pub fn configure_system(
     cmdline_size: usize,
     initrd: &Option<InitrdConfig>,
     num_cpus: u8,
+    rng_seed: Option<&[u8]>,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -176,6 +183,10 @@ pub fn configure_system(
         params.0.hdr.ramdisk_size = initrd_config.size as u32;
     }
 
+    if let Some(seed) = rng_seed {
+        params.0.hdr.setup_data = setup_rng_seed(guest_mem, seed)?;
+    }
+
     add_e820_entry(&mut params.0, 0, EBDA_START, E820_RAM)?;
 
     let last_addr = GuestAddress(arch_memory_info.ram_last_addr);
@@ -218,6 +229,27 @@ pub fn configure_system(
     Ok(())
 }
 
+/// Writes `seed` as a setup_data entry, which the guest kernel credits to its random number
+/// generator and wipes, returning its address.
+fn setup_rng_seed(guest_mem: &GuestMemoryMmap, seed: &[u8]) -> super::Result<u64> {
+    // struct setup_data: the address of the next entry, the type and length of the data, and
+    // the data itself.
+    let mut entry = Vec::with_capacity(16 + seed.len());
+    entry.extend_from_slice(&0u64.to_le_bytes());
+    entry.extend_from_slice(&SETUP_RNG_SEED.to_le_bytes());
+    entry.extend_from_slice(&(seed.len() as u32).to_le_bytes());
+    entry.extend_from_slice(seed);
+
+    if entry.len() as u64 > layout::ZERO_PAGE_START - layout::SETUP_DATA_START {
+        return Err(Error::RngSeedSetup);
+    }
+    guest_mem
+        .write_slice(&entry, GuestAddress(layout::SETUP_DATA_START))
+        .map_err(|_| Error::RngSeedSetup)?;
+
+    Ok(layout::SETUP_DATA_START)
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(
@@ -278,7 +310,7 @@ mod tests {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
         let info = ArchMemoryInfo::default();
-        let config_err = configure_system(&gm, &info, GuestAddress(0), 0, &None, 1);
+        let config_err = configure_system(&gm, &info, GuestAddress(0), 0, &None, 1, None);
         assert!(config_err.is_err());
         assert_eq!(
             config_err.unwrap_err(),
@@ -290,21 +322,91 @@ mod tests {
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            None,
+        )
+        .unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            None,
+        )
+        .unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let (arch_mem_info, arch_mem_regions) =
             arch_memory_regions(mem_size, KERNEL_LOAD_ADDR, KERNEL_SIZE);
         let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
-        configure_system(&gm, &arch_mem_info, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            no_vcpus,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rng_seed() {
+        let (arch_mem_info, arch_mem_regions) =
+            arch_memory_regions(128 << 20, KERNEL_LOAD_ADDR, KERNEL_SIZE);
+        let gm = GuestMemoryMmap::from_ranges(&arch_mem_regions).unwrap();
+        let seed = [0xa5u8; ::RNG_SEED_SIZE];
+        configure_system(
+            &gm,
+            &arch_mem_info,
+            GuestAddress(0),
+            0,
+            &None,
+            1,
+            Some(&seed),
+        )
+        .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(GuestAddress(layout::ZERO_PAGE_START)).unwrap();
+        assert_eq!(params.0.hdr.setup_data, layout::SETUP_DATA_START);
+
+        let mut entry = [0u8; 16 + ::RNG_SEED_SIZE];
+        gm.read_slice(&mut entry, GuestAddress(layout::SETUP_DATA_START))
+            .unwrap();
+        assert_eq!(entry[..8], 0u64.to_le_bytes());
+        assert_eq!(entry[8..12], SETUP_RNG_SEED.to_le_bytes());
+        assert_eq!(entry[12..16], (::RNG_SEED_SIZE as u32).to_le_bytes());
+        assert_eq!(entry[16..], seed[..]);
+
+        let oversized = [0u8; 0x1000];
+        assert_eq!(
+            configure_system(
+                &gm,
+                &arch_mem_info,
+                GuestAddress(0),
+                0,
+                &None,
+                1,
+                Some(&oversized)
+            ),
+            Err(Error::RngSeedSetup)
+        );
     }
 
     #[test]
@@ -67,12 +67,21 @@ pub fn update_amd_features_entry(
 ) -> Result<(), Error> {
     use cpu_leaf::leaf_0x80000008::*;
 
-    // We don't support more then 64 threads right now.
-    // It's safe to put them all on the same processor.
+    // We don't support more then 64 threads right now, so with a single socket it's safe to put
+    // them all on the same processor. Otherwise, the bits of the APIC ID above those identifying
+    // the thread within its processor identify the socket.
+    let thread_id_size = if vm_spec.sockets() > 1 {
+        vm_spec.package_bits()
+    } else {
+        THREAD_ID_MAX_SIZE
+    };
     entry
         .ecx
-        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, THREAD_ID_MAX_SIZE)
-        .write_bits_in_range(&ecx::NUM_THREADS_BITRANGE, u32::from(vm_spec.cpu_count - 1));
+        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, thread_id_size)
+        .write_bits_in_range(
+            &ecx::NUM_THREADS_BITRANGE,
+            u32::from(vm_spec.cpus_per_socket() - 1),
+        );
 
     Ok(())
 }
@@ -103,6 +112,8 @@ pub fn update_extended_apic_id_entry(
     if vm_spec.ht_enabled {
         core_id /= 2;
     }
+    // The core ids restart from 0 in each socket.
+    core_id %= u32::from(vm_spec.cores_per_socket);
 
     entry
         .eax
@@ -120,8 +131,8 @@ pub fn update_extended_apic_id_entry(
     entry
         .ecx
         .write_bits_in_range(&ecx::NODES_PER_PROCESSOR_BITRANGE, NODES_PER_PROCESSOR)
-        // Put all the cpus in the same node.
-        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, 0);
+        // Put all the cpus of a socket in the same node.
+        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, u32::from(vm_spec.socket_id()));
 
     Ok(())
 }
@@ -352,4 +363,52 @@ mod tests {
         check_update_extended_apic_id_entry(0, 2, true, 0, 1);
         check_update_extended_apic_id_entry(1, 2, true, 0, 1);
     }
+
+    #[test]
+    fn test_2sockets() {
+        // 2 sockets of 2 cores of 2 hyperthreads; the 6th logical CPU is in the 2nd socket.
+        let vm_spec = VmSpec::with_topology(5, 2, 2, 2).expect("Error creating vm_spec");
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x80000008::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_amd_features_entry(&mut entry, &vm_spec).is_ok());
+        {
+            use cpu_leaf::leaf_0x80000008::*;
+            assert_eq!(entry.ecx.read_bits_in_range(&ecx::NUM_THREADS_BITRANGE), 3);
+            assert_eq!(
+                entry.ecx.read_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE),
+                2
+            );
+        }
+
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x8000001e::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_extended_apic_id_entry(&mut entry, &vm_spec).is_ok());
+        {
+            use cpu_leaf::leaf_0x8000001e::*;
+            assert_eq!(entry.ebx.read_bits_in_range(&ebx::CORE_ID_BITRANGE), 0);
+            assert_eq!(
+                entry
+                    .ebx
+                    .read_bits_in_range(&ebx::THREADS_PER_CORE_BITRANGE),
+                1
+            );
+            assert_eq!(entry.ecx.read_bits_in_range(&ecx::NODE_ID_BITRANGE), 1);
+        }
+    }
 }
@@ -33,7 +33,8 @@ pub fn update_feature_info_entry(
 ) -> Result<(), Error> {
     use cpu_leaf::leaf_0x1::*;
 
-    let max_cpus_per_package = u32::from(common::get_max_cpus_per_package(vm_spec.cpu_count)?);
+    let max_cpus_per_package =
+        u32::from(common::get_max_cpus_per_package(vm_spec.cpus_per_socket())?);
 
     // X86 hypervisor feature
     entry.ecx.write_bit(ecx::HYPERVISOR_BITINDEX, true);
@@ -49,7 +50,7 @@ pub fn update_feature_info_entry(
     // is valid for the package
     entry
         .edx
-        .write_bit(edx::HTT_BITINDEX, vm_spec.cpu_count > 1);
+        .write_bit(edx::HTT_BITINDEX, vm_spec.cpus_per_socket() > 1);
 
     Ok(())
 }
@@ -84,10 +85,10 @@ pub fn update_cache_parameters_entry(
         }
         // L3 Cache
         3 => {
-            // The L3 cache is shared among all the logical threads
+            // The L3 cache is shared among all the logical threads of a socket
             entry.eax.write_bits_in_range(
                 &eax::MAX_CPUS_PER_CORE_BITRANGE,
-                u32::from(vm_spec.cpu_count - 1),
+                u32::from(vm_spec.cpus_per_socket() - 1),
             );
         }
         _ => (),
@@ -30,10 +30,9 @@ fn update_deterministic_cache_entry(
 
     common::update_cache_parameters_entry(entry, vm_spec)?;
 
-    // Put all the cores in the same socket
     entry.eax.write_bits_in_range(
         &eax::MAX_CORES_PER_PACKAGE_BITRANGE,
-        u32::from(vm_spec.cpu_count - 1),
+        u32::from(vm_spec.cpus_per_socket() - 1),
     );
 
     Ok(())
@@ -103,9 +102,16 @@ fn update_extended_cache_topology_entry(
         }
         // Core Level Processor Topology; index = 1
         1 => {
+            // With several sockets, shifting out the bits identifying the logical processor
+            // within its socket leaves the socket.
+            let apicid_shift = if vm_spec.sockets() > 1 {
+                vm_spec.package_bits()
+            } else {
+                LEAFBH_INDEX1_APICID
+            };
             entry
                 .eax
-                .write_bits_in_range(&eax::APICID_BITRANGE, LEAFBH_INDEX1_APICID);
+                .write_bits_in_range(&eax::APICID_BITRANGE, apicid_shift);
             entry
                 .ecx
                 .write_bits_in_range(&ecx::LEVEL_NUMBER_BITRANGE, entry.index as u32);
@@ -117,7 +123,7 @@ fn update_extended_cache_topology_entry(
             } else {
                 entry.ebx.write_bits_in_range(
                     &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
-                    u32::from(vm_spec.cpu_count),
+                    u32::from(vm_spec.cpus_per_socket()),
                 );
                 entry
                     .ecx
@@ -364,4 +370,60 @@ mod tests {
             LEVEL_TYPE_CORE,
         );
     }
+
+    #[test]
+    fn test_2sockets() {
+        use cpu_leaf::leaf_0x4::*;
+
+        // 2 sockets of 2 cores of 2 hyperthreads; the 6th logical CPU is in the 2nd socket.
+        let vm_spec = VmSpec::with_topology(5, 2, 2, 2).expect("Error creating vm_spec");
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x4::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: *(0 as u32).write_bits_in_range(&eax::CACHE_LEVEL_BITRANGE, 3),
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_deterministic_cache_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .eax
+                .read_bits_in_range(&eax::MAX_CORES_PER_PACKAGE_BITRANGE),
+            3
+        );
+
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0xb::LEAF_NUM,
+            index: 1,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_extended_cache_topology_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .eax
+                .read_bits_in_range(&leaf_0xb::eax::APICID_BITRANGE),
+            2
+        );
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&leaf_0xb::ebx::NUM_LOGICAL_PROCESSORS_BITRANGE),
+            4
+        );
+        assert_eq!(
+            entry
+                .ecx
+                .read_bits_in_range(&leaf_0xb::ecx::LEVEL_TYPE_BITRANGE),
+            LEVEL_TYPE_CORE
+        );
+        assert_eq!(entry.edx, 5);
+    }
 }
@@ -21,6 +21,8 @@ pub struct VmSpec {
     cpu_count: u8,
     /// Specifies whether hyper-threading is enabled.
     ht_enabled: bool,
+    /// The number of cores in each socket.
+    cores_per_socket: u8,
     /// The desired brand string for the guest.
     brand_string: BrandString,
 }
@@ -28,22 +30,85 @@ pub struct VmSpec {
 impl VmSpec {
     /// Creates a new instance of VmSpec with the specified parameters
     /// The brand string is deduced from the vendor_id
+    /// All the logical cpus are put in the same socket.
     pub fn new(cpu_id: u8, cpu_count: u8, ht_enabled: bool) -> Result<VmSpec, Error> {
         let cpu_vendor_id = get_vendor_id().map_err(Error::InternalError)?;
+        let threads_per_core = if ht_enabled && cpu_count > 1 { 2 } else { 1 };
 
         Ok(VmSpec {
             cpu_vendor_id,
             cpu_id,
             cpu_count,
             ht_enabled,
+            cores_per_socket: cpu_count / threads_per_core + cpu_count % threads_per_core,
             brand_string: BrandString::from_vendor_id(&cpu_vendor_id),
         })
     }
 
+    /// Creates a new instance of VmSpec for the logical cpus laid out in `sockets`, with
+    /// `cores_per_socket` cores of `threads_per_core` threads each.
+    ///
+    /// The APIC ID of a logical cpu is its id, numbered thread first, then core, then socket, so
+    /// there can only be several sockets if the number of cores in each is a power of 2.
+    pub fn with_topology(
+        cpu_id: u8,
+        sockets: u8,
+        cores_per_socket: u8,
+        threads_per_core: u8,
+    ) -> Result<VmSpec, Error> {
+        if sockets == 0
+            || cores_per_socket == 0
+            || threads_per_core == 0
+            || threads_per_core > 2
+            || (sockets > 1 && !cores_per_socket.is_power_of_two())
+        {
+            return Err(Error::InvalidTopology);
+        }
+        let cpu_count = sockets
+            .checked_mul(cores_per_socket)
+            .and_then(|cores| cores.checked_mul(threads_per_core))
+            .ok_or(Error::VcpuCountOverflow)?;
+
+        let mut vm_spec = VmSpec::new(cpu_id, cpu_count, threads_per_core > 1)?;
+        vm_spec.cores_per_socket = cores_per_socket;
+        Ok(vm_spec)
+    }
+
     /// Returns an immutable reference to cpu_vendor_id
     pub fn cpu_vendor_id(&self) -> &[u8; 12] {
         &self.cpu_vendor_id
     }
+
+    /// Returns the number of logical cpus in each core.
+    fn threads_per_core(&self) -> u8 {
+        if self.ht_enabled && self.cpu_count > 1 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Returns the number of logical cpus in each socket.
+    fn cpus_per_socket(&self) -> u8 {
+        self.cores_per_socket * self.threads_per_core()
+    }
+
+    /// Returns the number of sockets.
+    fn sockets(&self) -> u8 {
+        self.cpu_count / self.cpus_per_socket()
+    }
+
+    /// Returns the socket of the current logical cpu.
+    fn socket_id(&self) -> u8 {
+        self.cpu_id / self.cpus_per_socket()
+    }
+
+    /// Returns the number of bits of the APIC ID identifying a logical cpu within its socket.
+    fn package_bits(&self) -> u32 {
+        u32::from(self.cpus_per_socket())
+            .next_power_of_two()
+            .trailing_zeros()
+    }
 }
 
 /// Errors associated with processing the CPUID leaves.
@@ -55,6 +120,8 @@ pub enum Error {
     InternalError(super::common::Error),
     /// The maximum number of addressable logical CPUs cannot be stored in an `u8`.
     VcpuCountOverflow,
+    /// The logical CPUs can't be laid out in the requested sockets, cores and threads.
+    InvalidTopology,
 }
 
 pub type EntryTransformerFn =
@@ -135,4 +202,40 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_with_topology() {
+        let vm_spec = VmSpec::new(3, 4, true).unwrap();
+        assert_eq!(vm_spec.threads_per_core(), 2);
+        assert_eq!(vm_spec.cpus_per_socket(), 4);
+        assert_eq!(vm_spec.sockets(), 1);
+        assert_eq!(vm_spec.socket_id(), 0);
+
+        // 2 sockets of 4 cores of 2 threads.
+        let vm_spec = VmSpec::with_topology(13, 2, 4, 2).unwrap();
+        assert_eq!(vm_spec.cpu_count, 16);
+        assert!(vm_spec.ht_enabled);
+        assert_eq!(vm_spec.cpus_per_socket(), 8);
+        assert_eq!(vm_spec.sockets(), 2);
+        assert_eq!(vm_spec.socket_id(), 1);
+        assert_eq!(vm_spec.package_bits(), 3);
+
+        // A single socket can have any number of cores.
+        let vm_spec = VmSpec::with_topology(0, 1, 3, 1).unwrap();
+        assert_eq!(vm_spec.cpu_count, 3);
+        assert_eq!(vm_spec.sockets(), 1);
+
+        for &(sockets, cores, threads) in
+            [(0, 1, 1), (1, 0, 1), (1, 1, 0), (1, 1, 3), (2, 3, 1)].iter()
+        {
+            match VmSpec::with_topology(0, sockets, cores, threads) {
+                Err(Error::InvalidTopology) => {}
+                _ => panic!("{}/{}/{} accepted", sockets, cores, threads),
+            }
+        }
+        match VmSpec::with_topology(0, 16, 16, 2) {
+            Err(Error::VcpuCountOverflow) => {}
+            _ => panic!("Wrong behavior"),
+        }
+    }
 }
@@ -48,6 +48,8 @@ impl fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug, Copy, Clone)]
@@ -0,0 +1,150 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Test-mode injection of failures into running devices, so embedders can validate the
+//! resilience of their guest workloads. Only built with the `fault-injection` feature.
+//!
+//! Devices are identified by their virtio device type. The faults are process-wide, and can be
+//! armed at any time, including while the microVM is running.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// The set of faults armed in the devices.
+#[derive(Default)]
+pub struct FaultInjector {
+    // Number of queue buffers to fail, per device type.
+    queue_errors: Mutex<HashMap<u32, usize>>,
+    // Host path prefixes for which the fs device fails to open files.
+    fs_eio_paths: Mutex<Vec<PathBuf>>,
+    // Number of vsock connections to reset.
+    vsock_resets: AtomicUsize,
+    // Delay before raising an interrupt, per device type.
+    irq_delays: Mutex<HashMap<u32, Duration>>,
+}
+
+impl FaultInjector {
+    /// Fails the processing of the next `count` buffers the guest places in the queues of
+    /// devices of `device_type`. The buffers are returned to the guest unprocessed.
+    pub fn fail_queue(&self, device_type: u32, count: usize) {
+        *self
+            .queue_errors
+            .lock()
+            .unwrap()
+            .entry(device_type)
+            .or_insert(0) += count;
+    }
+
+    /// Consumes a queue failure for `device_type`, returning whether the current buffer must
+    /// fail.
+    pub fn take_queue_error(&self, device_type: u32) -> bool {
+        let mut queue_errors = self.queue_errors.lock().unwrap();
+        match queue_errors.get_mut(&device_type) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Makes the fs device fail with EIO to open any file under `prefix` on the host.
+    pub fn fail_fs_path(&self, prefix: PathBuf) {
+        self.fs_eio_paths.lock().unwrap().push(prefix);
+    }
+
+    /// Returns whether opening the file at `path`, on the host, must fail with EIO.
+    pub fn fs_path_fails(&self, path: &Path) -> bool {
+        self.fs_eio_paths
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+    }
+
+    /// Resets the next `count` vsock connections the guest sends data through.
+    pub fn reset_vsock_connections(&self, count: usize) {
+        self.vsock_resets.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Consumes a vsock reset, returning whether the current connection must be reset.
+    pub fn take_vsock_reset(&self) -> bool {
+        self.vsock_resets
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Delays every interrupt raised by devices of `device_type` by `delay`. A zero `delay`
+    /// disarms the fault.
+    pub fn delay_interrupts(&self, device_type: u32, delay: Duration) {
+        let mut irq_delays = self.irq_delays.lock().unwrap();
+        if delay == Duration::from_secs(0) {
+            irq_delays.remove(&device_type);
+        } else {
+            irq_delays.insert(device_type, delay);
+        }
+    }
+
+    /// Waits for the delay armed for interrupts of `device_type`, if any. This stalls the thread
+    /// servicing the device, as a slow host would.
+    pub fn delay_interrupt(&self, device_type: u32) {
+        let delay = self.irq_delays.lock().unwrap().get(&device_type).copied();
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Disarms all the faults.
+    pub fn clear(&self) {
+        self.queue_errors.lock().unwrap().clear();
+        self.fs_eio_paths.lock().unwrap().clear();
+        self.vsock_resets.store(0, Ordering::SeqCst);
+        self.irq_delays.lock().unwrap().clear();
+    }
+}
+
+lazy_static! {
+    /// The faults armed in the devices of this process.
+    pub static ref FAULTS: FaultInjector = FaultInjector::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_errors() {
+        let faults = FaultInjector::default();
+        faults.fail_queue(3, 2);
+        assert!(!faults.take_queue_error(26));
+        assert!(faults.take_queue_error(3));
+        assert!(faults.take_queue_error(3));
+        assert!(!faults.take_queue_error(3));
+    }
+
+    #[test]
+    fn test_fs_eio_paths() {
+        let faults = FaultInjector::default();
+        faults.fail_fs_path(PathBuf::from("/srv/root/data"));
+        assert!(faults.fs_path_fails(Path::new("/srv/root/data/file")));
+        assert!(!faults.fs_path_fails(Path::new("/srv/root/database")));
+
+        faults.clear();
+        assert!(!faults.fs_path_fails(Path::new("/srv/root/data/file")));
+    }
+
+    #[test]
+    fn test_vsock_resets() {
+        let faults = FaultInjector::default();
+        assert!(!faults.take_vsock_reset());
+        faults.reset_vsock_connections(1);
+        assert!(faults.take_vsock_reset());
+        assert!(!faults.take_vsock_reset());
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounts for the host file descriptors held by the devices, on behalf of the guest, so running
+//! out of them is reported to the embedder rather than left to fail somewhere unrelated.
+//!
+//! Each device gets an `FdAccount` from the budget of the microVM, and acquires an `FdToken` for
+//! every fd it keeps open, releasing it along with the fd. Acquiring fails with `EMFILE` once the
+//! budget is spent, and both that and the host itself running out of fds are reported to the
+//! embedder as an `FdEvent`.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Why a device couldn't get an fd.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdEventKind {
+    /// The budget of the microVM is spent.
+    BudgetExhausted,
+    /// The host refused the fd, with `EMFILE` or `ENFILE`.
+    HostExhausted,
+}
+
+/// A device failing to get an fd.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdEvent {
+    /// The device the fd was for.
+    pub device: String,
+    /// The fds held by the device at the time.
+    pub in_use: usize,
+    pub kind: FdEventKind,
+}
+
+/// Callback invoked with every `FdEvent`, from the thread of the device.
+pub type FdEventCallback = Arc<dyn Fn(&FdEvent) + Send + Sync>;
+
+/// The fds held by a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdUsage {
+    pub device: String,
+    pub in_use: usize,
+}
+
+struct Account {
+    device: String,
+    in_use: AtomicUsize,
+}
+
+/// The limit of a budget only bounded by the host.
+const NO_LIMIT: usize = usize::MAX;
+
+struct Budget {
+    limit: AtomicUsize,
+    in_use: AtomicUsize,
+    accounts: Mutex<Vec<Arc<Account>>>,
+    callback: Mutex<Option<FdEventCallback>>,
+}
+
+/// The fds the devices of a microVM can hold, and the accounts of the devices holding them.
+/// Clones share the same budget.
+#[derive(Clone)]
+pub struct FdBudget {
+    inner: Arc<Budget>,
+}
+
+impl FdBudget {
+    /// Creates a budget only bounded by the host.
+    pub fn new() -> Self {
+        FdBudget {
+            inner: Arc::new(Budget {
+                limit: AtomicUsize::new(NO_LIMIT),
+                in_use: AtomicUsize::new(0),
+                accounts: Mutex::new(Vec::new()),
+                callback: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Limits the fds held by all the devices to `limit`, or only to what the host allows with
+    /// none.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.inner
+            .limit
+            .store(limit.unwrap_or(NO_LIMIT), Ordering::Release);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self.inner.limit.load(Ordering::Acquire) {
+            NO_LIMIT => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Reports the devices failing to get an fd to `callback`.
+    pub fn set_callback(&self, callback: FdEventCallback) {
+        *self.inner.callback.lock().unwrap() = Some(callback);
+    }
+
+    /// Returns the fds held by all the devices.
+    pub fn in_use(&self) -> usize {
+        self.inner.in_use.load(Ordering::Acquire)
+    }
+
+    /// Opens an account for `device`. Devices sharing a name share their usage.
+    pub fn account(&self, device: &str) -> FdAccount {
+        let mut accounts = self.inner.accounts.lock().unwrap();
+        let account = match accounts.iter().find(|a| a.device == device) {
+            Some(account) => account.clone(),
+            None => {
+                let account = Arc::new(Account {
+                    device: device.to_string(),
+                    in_use: AtomicUsize::new(0),
+                });
+                accounts.push(account.clone());
+                account
+            }
+        };
+
+        FdAccount {
+            budget: self.inner.clone(),
+            account,
+        }
+    }
+
+    /// Returns the fds held by each device.
+    pub fn usage(&self) -> Vec<FdUsage> {
+        self.inner
+            .accounts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|a| FdUsage {
+                device: a.device.clone(),
+                in_use: a.in_use.load(Ordering::Acquire),
+            })
+            .collect()
+    }
+}
+
+impl Default for FdBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for FdBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdBudget")
+            .field("limit", &self.limit())
+            .field("in_use", &self.in_use())
+            .finish()
+    }
+}
+
+/// The share of the budget used by a device.
+#[derive(Clone)]
+pub struct FdAccount {
+    budget: Arc<Budget>,
+    account: Arc<Account>,
+}
+
+impl FdAccount {
+    /// Accounts for an fd about to be opened, failing with `EMFILE` if the budget is spent. The
+    /// fd is accounted for until the token is dropped.
+    pub fn acquire(&self) -> io::Result<FdToken> {
+        let in_use = self.budget.in_use.fetch_add(1, Ordering::AcqRel);
+        if in_use >= self.budget.limit.load(Ordering::Acquire) {
+            self.budget.in_use.fetch_sub(1, Ordering::AcqRel);
+            self.notify(FdEventKind::BudgetExhausted);
+            return Err(io::Error::from_raw_os_error(libc::EMFILE));
+        }
+        self.account.in_use.fetch_add(1, Ordering::AcqRel);
+
+        Ok(FdToken {
+            budget: self.budget.clone(),
+            account: self.account.clone(),
+        })
+    }
+
+    /// Reports `err`, returned by the host opening an fd, if it means the host ran out of them.
+    pub fn check_error(&self, err: &io::Error) {
+        if matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE)) {
+            self.notify(FdEventKind::HostExhausted);
+        }
+    }
+
+    /// Returns the fds held by the device.
+    pub fn in_use(&self) -> usize {
+        self.account.in_use.load(Ordering::Acquire)
+    }
+
+    fn notify(&self, kind: FdEventKind) {
+        let event = FdEvent {
+            device: self.account.device.clone(),
+            in_use: self.in_use(),
+            kind,
+        };
+        warn!(
+            "{}: out of file descriptors ({:?}, {} in use)",
+            event.device, kind, event.in_use
+        );
+        // The callback is cloned so it doesn't run with the lock held.
+        let callback = self.budget.callback.lock().unwrap().clone();
+        if let Some(callback) = callback {
+            callback(&event);
+        }
+    }
+}
+
+impl fmt::Debug for FdAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FdAccount")
+            .field("device", &self.account.device)
+            .field("in_use", &self.in_use())
+            .finish()
+    }
+}
+
+/// An fd accounted for, released on drop.
+pub struct FdToken {
+    budget: Arc<Budget>,
+    account: Arc<Account>,
+}
+
+impl Drop for FdToken {
+    fn drop(&mut self) {
+        self.account.in_use.fetch_sub(1, Ordering::AcqRel);
+        self.budget.in_use.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let callback_events = events.clone();
+        let budget = FdBudget::new();
+        budget.set_limit(Some(2));
+        budget.set_callback(Arc::new(move |event: &FdEvent| {
+            callback_events.lock().unwrap().push(event.clone())
+        }));
+        let fs = budget.account("fs");
+        let vsock = budget.account("vsock");
+
+        let token = fs.acquire().unwrap();
+        let _vsock_token = vsock.acquire().unwrap();
+        assert_eq!(budget.in_use(), 2);
+        assert_eq!(fs.acquire().unwrap_err().raw_os_error(), Some(libc::EMFILE));
+
+        // Dropping a token makes room for another fd.
+        drop(token);
+        assert_eq!(fs.in_use(), 0);
+        let _token = budget.account("fs").acquire().unwrap();
+        assert_eq!(
+            budget.usage(),
+            vec![
+                FdUsage {
+                    device: "fs".to_string(),
+                    in_use: 1
+                },
+                FdUsage {
+                    device: "vsock".to_string(),
+                    in_use: 1
+                },
+            ]
+        );
+
+        vsock.check_error(&io::Error::from_raw_os_error(libc::ENFILE));
+        vsock.check_error(&io::Error::from_raw_os_error(libc::ENOENT));
+        let kinds: Vec<(String, FdEventKind)> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.device.clone(), e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("fs".to_string(), FdEventKind::BudgetExhausted),
+                ("vsock".to_string(), FdEventKind::HostExhausted),
+            ]
+        );
+    }
+}
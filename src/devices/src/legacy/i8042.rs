@@ -22,24 +22,25 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::CloneCpuResetEvt(io_err) => write!(
-                f,
-                "Could not clone CPU reset eventfd: {}.",
-                io_err.to_string()
-            ),
+            Error::CloneCpuResetEvt(_) => write!(f, "Could not clone CPU reset eventfd."),
             Error::KbdInterruptDisabled => {
                 write!(f, "Keyboard interrupt disabled by guest driver.",)
             }
-            Error::KbdInterruptFailure(io_err) => write!(
-                f,
-                "Could not trigger keyboard interrupt: {}.",
-                io_err.to_string()
-            ),
+            Error::KbdInterruptFailure(_) => write!(f, "Could not trigger keyboard interrupt."),
             Error::InternalBufferFull => write!(f, "i8042 internal buffer full."),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CloneCpuResetEvt(e) | Error::KbdInterruptFailure(e) => Some(e),
+            Error::KbdInterruptDisabled | Error::InternalBufferFull => None,
+        }
+    }
+}
+
 type Result<T> = result::Result<T, Error>;
 
 /// Offset of the status port (port 0x64)
@@ -9,17 +9,30 @@
 #[allow(non_camel_case_types)]
 mod gic;
 mod i8042;
+#[cfg(target_arch = "x86_64")]
+mod reset;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
+mod secrets;
 mod serial;
+mod tpm_tis;
+mod user;
 
 #[cfg(target_os = "macos")]
 pub use self::gic::Gic;
 pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+#[cfg(target_arch = "x86_64")]
+pub use self::reset::{ResetControl, RESET_CONTROL_PORT};
 #[cfg(target_arch = "aarch64")]
 pub use self::rtc_pl031::RTC;
+pub use self::secrets::{
+    Error as SecretsError, SecretsMailbox, DEFAULT_SECRETS_TTL, MAX_SECRETS, MAX_SECRET_SIZE,
+    SECRETS_MAILBOX_SIZE,
+};
 pub use self::serial::{ReadableFd, Serial};
+pub use self::tpm_tis::{SwtpmBackend, TpmBackend, TpmTis, TPM_TIS_SIZE};
+pub use self::user::{UserAccess, UserDevice, UserDeviceCallback};
 
 #[cfg(target_os = "linux")]
 pub struct Gic {}
@@ -0,0 +1,74 @@
+use utils::eventfd::EventFd;
+
+use crate::bus::BusDevice;
+
+/// The port of the reset control register of the PCI chipset, as used by `reboot=pci`.
+pub const RESET_CONTROL_PORT: u64 = 0xcf9;
+
+/// Reset control register bits
+const RCR_SYS_RST: u8 = 0x02; // Full reset, rather than an INIT of the CPUs
+const RCR_RST_CPU: u8 = 0x04; // Issue the reset
+
+/// The reset control register of the PCI chipset, emulating just enough to let the guest reset
+/// the machine without going through a keyboard controller.
+pub struct ResetControl {
+    /// Signaled when the guest issues a reset.
+    reset_evt: EventFd,
+    value: u8,
+}
+
+impl ResetControl {
+    pub fn new(reset_evt: EventFd) -> Self {
+        ResetControl {
+            reset_evt,
+            value: 0,
+        }
+    }
+}
+
+impl BusDevice for ResetControl {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if offset == 0 && data.len() == 1 {
+            data[0] = self.value;
+        }
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        // The register is byte-wide.
+        if offset != 0 || data.len() != 1 {
+            return;
+        }
+
+        self.value = data[0] & RCR_SYS_RST;
+        if data[0] & RCR_RST_CPU != 0 {
+            if let Err(e) = self.reset_evt.write(1) {
+                error!("Failed to trigger the guest reset event: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_control() {
+        let reset_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap();
+        let mut reset = ResetControl::new(reset_evt.try_clone().unwrap());
+
+        // Selecting the kind of reset doesn't issue it.
+        reset.write(0, 0, &[RCR_SYS_RST]);
+        assert!(reset.reset_evt.read().is_err());
+        let mut data = [0];
+        reset.read(0, 0, &mut data);
+        assert_eq!(data[0], RCR_SYS_RST);
+
+        // Nor do wider accesses.
+        reset.write(0, 0, &[RCR_RST_CPU, 0]);
+        assert!(reset.reset_evt.read().is_err());
+
+        reset.write(0, 0, &[RCR_SYS_RST | RCR_RST_CPU]);
+        assert_eq!(reset_evt.read().unwrap(), 1);
+    }
+}
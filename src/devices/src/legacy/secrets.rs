@@ -0,0 +1,292 @@
+//! Read-once mailbox handing small secrets to the guest, so they never show up on the kernel
+//! command line or in a share the guest can read at any time.
+//!
+//! The guest reads the secrets as a stream of records, a byte at a time, through the data
+//! register. Each record is the NUL-terminated name of the secret, followed by the length of its
+//! value, as a little-endian u32, and the value itself. An empty name ends the stream. Once the
+//! stream has been read in full, the guest acknowledges it, or the deadline passes, the secrets
+//! are wiped from the memory of the VMM, and the mailbox stays empty for the rest of the life of
+//! the microVM.
+
+use std::fmt;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use crate::BusDevice;
+
+/// The size of the register space of the mailbox.
+pub const SECRETS_MAILBOX_SIZE: u64 = 2;
+
+/// Reads 1 while secrets are waiting for the guest, and 0 otherwise. Writing `CONTROL_DONE` to
+/// it wipes the secrets.
+const REG_STATUS: u64 = 0;
+/// Reads the next byte of the stream, and 0 past its end.
+const REG_DATA: u64 = 1;
+
+const STATUS_EMPTY: u8 = 0;
+const STATUS_AVAILABLE: u8 = 1;
+const CONTROL_DONE: u8 = 1;
+
+/// How long the guest has to read the secrets, from the start of the microVM, by default.
+pub const DEFAULT_SECRETS_TTL: Duration = Duration::from_secs(60);
+
+/// The largest value of a secret.
+pub const MAX_SECRET_SIZE: usize = 64 * 1024;
+/// The largest number of secrets handed to the guest.
+pub const MAX_SECRETS: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A secret with this name was already added.
+    DuplicateName(String),
+    /// The name isn't usable as a file name in the guest.
+    InvalidName(String),
+    /// The value of the secret exceeds `MAX_SECRET_SIZE`.
+    TooLarge(String),
+    /// More than `MAX_SECRETS` secrets were added.
+    TooMany,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            DuplicateName(name) => write!(f, "Secret \"{}\" already added", name),
+            InvalidName(name) => write!(f, "Invalid secret name \"{}\"", name),
+            TooLarge(name) => write!(f, "Secret \"{}\" exceeds {} bytes", name, MAX_SECRET_SIZE),
+            TooMany => write!(f, "More than {} secrets", MAX_SECRETS),
+        }
+    }
+}
+
+/// Overwrites `buf` with zeros, in a way the compiler can't elide.
+fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // Safe because the byte is a valid reference.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// The secrets waiting for the guest, and the state of their transfer.
+pub struct SecretsMailbox {
+    names: Vec<String>,
+    /// The serialized records, without the terminating empty name.
+    stream: Vec<u8>,
+    pos: usize,
+    ttl: Duration,
+    deadline: Option<Instant>,
+}
+
+impl SecretsMailbox {
+    pub fn new() -> Self {
+        SecretsMailbox {
+            names: Vec::new(),
+            stream: Vec::new(),
+            pos: 0,
+            ttl: DEFAULT_SECRETS_TTL,
+            deadline: None,
+        }
+    }
+
+    /// Queues a secret for the guest, which creates a file named `name` holding `value`.
+    pub fn add(&mut self, name: &str, value: &[u8]) -> Result<(), Error> {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.len() > 255
+            || name.contains(|c: char| c == '/' || c == '\0')
+        {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+        if self.names.iter().any(|n| n == name) {
+            return Err(Error::DuplicateName(name.to_string()));
+        }
+        if value.len() > MAX_SECRET_SIZE {
+            return Err(Error::TooLarge(name.to_string()));
+        }
+        if self.names.len() == MAX_SECRETS {
+            return Err(Error::TooMany);
+        }
+
+        // Growing the stream in place could leave copies of the secrets in freed memory, so the
+        // records are copied to a buffer of the final size, and the previous one is wiped.
+        let len = self.stream.len() + name.len() + 1 + 4 + value.len();
+        let mut stream = Vec::with_capacity(len);
+        stream.extend_from_slice(&self.stream);
+        stream.extend_from_slice(name.as_bytes());
+        stream.push(0);
+        stream.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        stream.extend_from_slice(value);
+        wipe(&mut self.stream);
+        self.stream = stream;
+        self.names.push(name.to_string());
+
+        Ok(())
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Opens the mailbox to the guest, until the time to live elapses.
+    pub fn arm(&mut self) {
+        self.deadline = Some(Instant::now() + self.ttl);
+    }
+
+    /// Wipes the secrets if their deadline has passed.
+    pub fn expire(&mut self) {
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                if !self.stream.is_empty() {
+                    warn!("secrets: expired before the guest read them");
+                }
+                self.wipe();
+            }
+        }
+    }
+
+    fn wipe(&mut self) {
+        wipe(&mut self.stream);
+        self.stream = Vec::new();
+        self.pos = 0;
+    }
+
+    fn available(&mut self) -> bool {
+        self.expire();
+        self.deadline.is_some() && !self.stream.is_empty()
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if !self.available() {
+            return 0;
+        }
+        match self.stream.get(self.pos) {
+            Some(byte) => {
+                self.pos += 1;
+                *byte
+            }
+            None => {
+                // The guest read the terminating empty name.
+                debug!("secrets: handed to the guest");
+                self.wipe();
+                0
+            }
+        }
+    }
+}
+
+impl Default for SecretsMailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SecretsMailbox {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl fmt::Debug for SecretsMailbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecretsMailbox")
+            .field("names", &self.names)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl BusDevice for SecretsMailbox {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            return;
+        }
+        data[0] = match offset {
+            REG_STATUS if self.available() => STATUS_AVAILABLE,
+            REG_STATUS => STATUS_EMPTY,
+            REG_DATA => self.next_byte(),
+            _ => 0,
+        };
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if offset == REG_STATUS && data == [CONTROL_DONE] {
+            self.wipe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_reg(mailbox: &mut SecretsMailbox, offset: u64) -> u8 {
+        let mut data = [0xffu8];
+        mailbox.read(0, offset, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_add() {
+        let mut mailbox = SecretsMailbox::new();
+        mailbox.add("token", b"abc").unwrap();
+
+        for name in &["", ".", "..", "a/b", "a\0b"] {
+            match mailbox.add(name, b"") {
+                Err(Error::InvalidName(_)) => (),
+                _ => panic!("\"{}\" accepted", name),
+            }
+        }
+        match mailbox.add("token", b"") {
+            Err(Error::DuplicateName(_)) => (),
+            _ => panic!("duplicate accepted"),
+        }
+        match mailbox.add("big", &vec![0u8; MAX_SECRET_SIZE + 1]) {
+            Err(Error::TooLarge(_)) => (),
+            _ => panic!("oversized secret accepted"),
+        }
+    }
+
+    #[test]
+    fn test_read_once() {
+        let mut mailbox = SecretsMailbox::new();
+        mailbox.add("a", b"xy").unwrap();
+
+        // Nothing is handed out before the microVM starts.
+        assert_eq!(read_reg(&mut mailbox, REG_STATUS), STATUS_EMPTY);
+        assert_eq!(read_reg(&mut mailbox, REG_DATA), 0);
+
+        mailbox.arm();
+        assert_eq!(read_reg(&mut mailbox, REG_STATUS), STATUS_AVAILABLE);
+        let stream: Vec<u8> = (0..9).map(|_| read_reg(&mut mailbox, REG_DATA)).collect();
+        assert_eq!(stream, vec![b'a', 0, 2, 0, 0, 0, b'x', b'y', 0]);
+
+        // The stream can't be read again.
+        assert_eq!(read_reg(&mut mailbox, REG_STATUS), STATUS_EMPTY);
+        assert_eq!(read_reg(&mut mailbox, REG_DATA), 0);
+    }
+
+    #[test]
+    fn test_done_and_expire() {
+        let mut mailbox = SecretsMailbox::new();
+        mailbox.add("a", b"xy").unwrap();
+        mailbox.arm();
+        mailbox.write(0, REG_STATUS, &[CONTROL_DONE]);
+        assert_eq!(read_reg(&mut mailbox, REG_STATUS), STATUS_EMPTY);
+
+        let mut mailbox = SecretsMailbox::new();
+        mailbox.set_ttl(Duration::from_secs(0));
+        mailbox.add("a", b"xy").unwrap();
+        mailbox.arm();
+        assert_eq!(read_reg(&mut mailbox, REG_STATUS), STATUS_EMPTY);
+        assert_eq!(read_reg(&mut mailbox, REG_DATA), 0);
+    }
+}
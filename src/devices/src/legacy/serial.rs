@@ -305,6 +305,10 @@ impl Subscriber for Serial {
             None => vec![],
         }
     }
+
+    fn name(&self) -> String {
+        "serial".to_string()
+    }
 }
 
 #[cfg(test)]
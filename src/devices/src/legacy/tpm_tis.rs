@@ -0,0 +1,402 @@
+//! TPM 2.0 device, exposing the FIFO interface of the TCG PC Client Platform TPM Profile
+//! specification over MMIO, and forwarding the commands of the guest to a TPM emulator running on
+//! the host, such as swtpm.
+//!
+//! Only locality 0 is implemented, and the device doesn't raise interrupts, so the guest driver
+//! must poll it. Commands are executed synchronously, in the context of the vCPU starting them.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::BusDevice;
+
+/// The size of the register space of a locality.
+const LOCALITY_SIZE: u64 = 0x1000;
+/// The size of the register space of the device, covering the five localities.
+pub const TPM_TIS_SIZE: u64 = 5 * LOCALITY_SIZE;
+
+// Registers of a locality.
+const TPM_ACCESS: u64 = 0x00;
+const TPM_INT_ENABLE: u64 = 0x08;
+const TPM_INT_VECTOR: u64 = 0x0c;
+const TPM_INT_STATUS: u64 = 0x10;
+const TPM_INTF_CAPABILITY: u64 = 0x14;
+const TPM_STS: u64 = 0x18;
+const TPM_DATA_FIFO: u64 = 0x24;
+const TPM_INTERFACE_ID: u64 = 0x30;
+const TPM_XDATA_FIFO: u64 = 0x80;
+const TPM_XDATA_FIFO_END: u64 = 0xc0;
+const TPM_DID_VID: u64 = 0xf00;
+const TPM_RID: u64 = 0xf04;
+
+// Bits of TPM_ACCESS.
+const ACCESS_VALID: u8 = 0x80;
+const ACCESS_ACTIVE_LOCALITY: u8 = 0x20;
+const ACCESS_REQUEST_USE: u8 = 0x02;
+
+// Bits of TPM_STS.
+const STS_FAMILY_TPM2: u32 = 1 << 26;
+const STS_BURST_COUNT_SHIFT: u32 = 8;
+const STS_VALID: u32 = 0x80;
+const STS_COMMAND_READY: u32 = 0x40;
+const STS_GO: u32 = 0x20;
+const STS_DATA_AVAIL: u32 = 0x10;
+const STS_EXPECT: u32 = 0x08;
+const STS_RESPONSE_RETRY: u32 = 0x02;
+
+/// Interface version 1.3 for TPM 2.0, 64-byte transfers and a static burst count, without any
+/// interrupt.
+const INTF_CAPABILITY: u32 = (3 << 28) | (3 << 9) | (1 << 8);
+/// The FIFO interface, with five localities.
+const INTERFACE_ID: u32 = (1 << 13) | (1 << 8);
+/// The IDs reported by the TPM emulator of QEMU, known by the guest drivers.
+const DID_VID: u32 = 0x0001_1014;
+const RID: u8 = 0x01;
+
+/// The bytes the guest can transfer in a row.
+const MAX_BURST: usize = 64;
+
+/// The size of the header of the commands and responses, holding their size.
+const TPM_HEADER_SIZE: usize = 10;
+/// The largest command or response.
+const TPM_BUFFER_MAX: usize = 4096;
+
+/// The response of the device when the TPM can't be reached, a TPM_RC_FAILURE.
+const FAILURE_RESPONSE: [u8; TPM_HEADER_SIZE] =
+    [0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0x01];
+
+/// Returns the size of the command or response starting with `header`, if it's long enough to
+/// tell.
+fn buffer_size(header: &[u8]) -> Option<usize> {
+    if header.len() < 6 {
+        return None;
+    }
+    let mut size = [0u8; 4];
+    size.copy_from_slice(&header[2..6]);
+    Some(u32::from_be_bytes(size) as usize)
+}
+
+/// A TPM executing the commands of the guest.
+pub trait TpmBackend: Send {
+    /// Executes `command`, returning the response of the TPM.
+    fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// A TPM emulated by swtpm, reached through the server socket it was started with, as in
+/// "swtpm socket --tpm2 --server type=unixio,path=PATH --flags not-need-init,startup-clear".
+pub struct SwtpmBackend {
+    stream: UnixStream,
+}
+
+impl SwtpmBackend {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(SwtpmBackend {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+}
+
+impl TpmBackend for SwtpmBackend {
+    fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream.write_all(command)?;
+
+        let mut response = vec![0u8; TPM_HEADER_SIZE];
+        self.stream.read_exact(&mut response)?;
+        let size = buffer_size(&response).unwrap();
+        if size < TPM_HEADER_SIZE || size > TPM_BUFFER_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid TPM response size {}", size),
+            ));
+        }
+        response.resize(size, 0);
+        self.stream.read_exact(&mut response[TPM_HEADER_SIZE..])?;
+        Ok(response)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for a command.
+    Ready,
+    /// Receiving a command.
+    Reception,
+    /// Holding the response to a command.
+    Completion,
+}
+
+/// A TPM device following the FIFO interface of the TIS.
+pub struct TpmTis {
+    backend: Box<dyn TpmBackend>,
+    state: State,
+    active: bool,
+    int_enable: u32,
+    /// The command being received, or the response being read.
+    buffer: Vec<u8>,
+    read_pos: usize,
+}
+
+impl TpmTis {
+    pub fn new(backend: Box<dyn TpmBackend>) -> Self {
+        TpmTis {
+            backend,
+            state: State::Idle,
+            active: false,
+            int_enable: 0,
+            buffer: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    fn command_complete(&self) -> bool {
+        buffer_size(&self.buffer).map_or(false, |size| self.buffer.len() >= size)
+    }
+
+    fn sts(&self) -> u32 {
+        let (flags, burst) = match self.state {
+            State::Idle => (0, 0),
+            State::Ready => (STS_VALID | STS_COMMAND_READY, MAX_BURST),
+            State::Reception => {
+                let expect = if self.command_complete() {
+                    0
+                } else {
+                    STS_EXPECT
+                };
+                (STS_VALID | expect, MAX_BURST)
+            }
+            State::Completion => {
+                let remaining = self.buffer.len() - self.read_pos;
+                let avail = if remaining > 0 { STS_DATA_AVAIL } else { 0 };
+                (STS_VALID | avail, remaining.min(MAX_BURST))
+            }
+        };
+        STS_FAMILY_TPM2 | ((burst as u32) << STS_BURST_COUNT_SHIFT) | flags
+    }
+
+    fn execute(&mut self) {
+        // The guest may write past the size of the command, which is trimmed to it.
+        if let Some(size) = buffer_size(&self.buffer) {
+            self.buffer.truncate(size);
+        }
+        self.buffer = match self.backend.execute(&self.buffer) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("tpm: unable to execute command: {}", e);
+                FAILURE_RESPONSE.to_vec()
+            }
+        };
+        self.read_pos = 0;
+        self.state = State::Completion;
+    }
+
+    fn write_sts(&mut self, value: u32) {
+        if value & STS_COMMAND_READY != 0 {
+            // Aborts the command being received, or discards the response.
+            self.buffer.clear();
+            self.read_pos = 0;
+            self.state = State::Ready;
+        } else if value & STS_GO != 0 {
+            if self.state == State::Reception && self.command_complete() {
+                self.execute();
+            } else {
+                warn!("tpm: command started before being fully written");
+            }
+        } else if value & STS_RESPONSE_RETRY != 0 && self.state == State::Completion {
+            self.read_pos = 0;
+        }
+    }
+
+    fn write_fifo(&mut self, data: &[u8]) {
+        match self.state {
+            State::Ready | State::Reception => {
+                if self.buffer.len() + data.len() > TPM_BUFFER_MAX {
+                    warn!("tpm: command larger than {} bytes", TPM_BUFFER_MAX);
+                    return;
+                }
+                self.buffer.extend_from_slice(data);
+                self.state = State::Reception;
+            }
+            _ => warn!("tpm: command written while not ready"),
+        }
+    }
+
+    fn read_fifo(&mut self) -> u8 {
+        if self.state == State::Completion && self.read_pos < self.buffer.len() {
+            self.read_pos += 1;
+            self.buffer[self.read_pos - 1]
+        } else {
+            0xff
+        }
+    }
+
+    /// Returns the value of the register holding the byte at `offset`, and the position of that
+    /// byte in it.
+    fn register(&self, offset: u64) -> (u32, u64) {
+        let reg = offset & !3;
+        let value = match reg {
+            TPM_ACCESS => {
+                let active = if self.active {
+                    ACCESS_ACTIVE_LOCALITY
+                } else {
+                    0
+                };
+                u32::from(ACCESS_VALID | active)
+            }
+            TPM_INT_ENABLE => self.int_enable,
+            TPM_INT_VECTOR | TPM_INT_STATUS => 0,
+            TPM_INTF_CAPABILITY => INTF_CAPABILITY,
+            TPM_STS => self.sts(),
+            TPM_INTERFACE_ID => INTERFACE_ID,
+            TPM_DID_VID => DID_VID,
+            TPM_RID => u32::from(RID),
+            _ => 0xffff_ffff,
+        };
+        (value, offset - reg)
+    }
+
+    fn is_fifo(offset: u64) -> bool {
+        (TPM_DATA_FIFO..TPM_DATA_FIFO + 4).contains(&offset)
+            || (TPM_XDATA_FIFO..TPM_XDATA_FIFO_END).contains(&offset)
+    }
+}
+
+impl BusDevice for TpmTis {
+    fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        let locality = offset / LOCALITY_SIZE;
+        let offset = offset % LOCALITY_SIZE;
+        if locality != 0 {
+            // The other localities can't be requested, but the guest driver probes them.
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = if offset + i as u64 == TPM_ACCESS {
+                    ACCESS_VALID
+                } else {
+                    0xff
+                };
+            }
+            return;
+        }
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            *byte = if Self::is_fifo(offset) {
+                self.read_fifo()
+            } else {
+                let (value, shift) = self.register(offset);
+                (value >> (shift * 8)) as u8
+            };
+        }
+    }
+
+    fn write(&mut self, _vcpuid: u64, offset: u64, data: &[u8]) {
+        if offset / LOCALITY_SIZE != 0 || data.is_empty() {
+            return;
+        }
+
+        match offset {
+            TPM_ACCESS => {
+                if data[0] & ACCESS_REQUEST_USE != 0 {
+                    self.active = true;
+                } else if data[0] & ACCESS_ACTIVE_LOCALITY != 0 {
+                    self.active = false;
+                }
+            }
+            TPM_INT_ENABLE => {
+                let mut value = [0u8; 4];
+                let len = data.len().min(4);
+                value[..len].copy_from_slice(&data[..len]);
+                self.int_enable = u32::from_le_bytes(value);
+            }
+            // The commands of the status register are all in its first byte.
+            TPM_STS if self.active => self.write_sts(u32::from(data[0])),
+            _ if Self::is_fifo(offset) && self.active => self.write_fifo(data),
+            _ => debug!("tpm: ignored write at 0x{:x}", offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMAND: [u8; 12] = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x44, 0, 0];
+    const RESPONSE: [u8; 10] = [0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0];
+
+    struct FakeTpm;
+
+    impl TpmBackend for FakeTpm {
+        fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+            assert_eq!(command, &COMMAND[..]);
+            Ok(RESPONSE.to_vec())
+        }
+    }
+
+    fn read_sts(tpm: &mut TpmTis) -> u32 {
+        let mut data = [0u8; 4];
+        tpm.read(0, TPM_STS, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_tpm_tis_command() {
+        let mut tpm = TpmTis::new(Box::new(FakeTpm));
+        let mut access = [0u8];
+
+        tpm.write(0, TPM_ACCESS, &[ACCESS_REQUEST_USE]);
+        tpm.read(0, TPM_ACCESS, &mut access);
+        assert_eq!(access[0], ACCESS_VALID | ACCESS_ACTIVE_LOCALITY);
+
+        tpm.write(0, TPM_STS, &[STS_COMMAND_READY as u8]);
+        assert_ne!(read_sts(&mut tpm) & STS_COMMAND_READY, 0);
+
+        // The burst count can also be read on its own.
+        let mut burst = [0u8; 2];
+        tpm.read(0, TPM_STS + 1, &mut burst);
+        assert_eq!(u16::from_le_bytes(burst) as usize, MAX_BURST);
+
+        tpm.write(0, TPM_DATA_FIFO, &COMMAND[..8]);
+        assert_ne!(read_sts(&mut tpm) & STS_EXPECT, 0);
+        tpm.write(0, TPM_DATA_FIFO, &COMMAND[8..]);
+        assert_eq!(read_sts(&mut tpm) & STS_EXPECT, 0);
+
+        tpm.write(0, TPM_STS, &[STS_GO as u8]);
+        assert_ne!(read_sts(&mut tpm) & STS_DATA_AVAIL, 0);
+
+        let mut response = [0u8; 10];
+        for byte in response.iter_mut() {
+            let mut data = [0u8];
+            tpm.read(0, TPM_DATA_FIFO, &mut data);
+            *byte = data[0];
+        }
+        assert_eq!(response, RESPONSE);
+        assert_eq!(read_sts(&mut tpm) & STS_DATA_AVAIL, 0);
+
+        // The response can be read again.
+        tpm.write(0, TPM_STS, &[STS_RESPONSE_RETRY as u8]);
+        let mut header = [0u8; 4];
+        tpm.read(0, TPM_DATA_FIFO, &mut header);
+        assert_eq!(header, RESPONSE[..4]);
+
+        tpm.write(0, TPM_ACCESS, &[ACCESS_ACTIVE_LOCALITY]);
+        tpm.read(0, TPM_ACCESS, &mut access);
+        assert_eq!(access[0], ACCESS_VALID);
+    }
+
+    #[test]
+    fn test_tpm_tis_inactive_locality() {
+        let mut tpm = TpmTis::new(Box::new(FakeTpm));
+
+        // Nothing is accepted until the locality is requested.
+        tpm.write(0, TPM_STS, &[STS_COMMAND_READY as u8]);
+        assert_eq!(read_sts(&mut tpm) & STS_COMMAND_READY, 0);
+
+        let mut access = [0u8];
+        tpm.read(0, LOCALITY_SIZE + TPM_ACCESS, &mut access);
+        assert_eq!(access[0], ACCESS_VALID);
+
+        let mut did_vid = [0u8; 4];
+        tpm.read(0, TPM_DID_VID, &mut did_vid);
+        assert_eq!(u32::from_le_bytes(did_vid), DID_VID);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use crate::bus::BusDevice;
+
+/// An access of the guest to a user device.
+pub enum UserAccess<'a> {
+    /// The guest reads the bytes the callback fills, zeroed beforehand.
+    Read(&'a mut [u8]),
+    /// The guest writes the bytes.
+    Write(&'a [u8]),
+}
+
+/// Handles the accesses of the guest to a user device, given the vCPU making each one, and its
+/// offset from the base of the region.
+pub type UserDeviceCallback = Arc<dyn Fn(u64, u64, UserAccess) + Send + Sync>;
+
+/// A region of the guest physical address space, or of the port I/O space, whose accesses are
+/// handed to the embedder, to prototype devices without adding them to the VMM. The accesses
+/// are dispatched from the vCPU making them, which waits for the callback to return, one at a
+/// time for each region.
+pub struct UserDevice {
+    callback: UserDeviceCallback,
+}
+
+impl UserDevice {
+    pub fn new(callback: UserDeviceCallback) -> Self {
+        UserDevice { callback }
+    }
+}
+
+impl BusDevice for UserDevice {
+    fn read(&mut self, vcpuid: u64, offset: u64, data: &mut [u8]) {
+        // The guest doesn't see what was there before, if the callback leaves it.
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        (self.callback)(vcpuid, offset, UserAccess::Read(data));
+    }
+
+    fn write(&mut self, vcpuid: u64, offset: u64, data: &[u8]) {
+        (self.callback)(vcpuid, offset, UserAccess::Write(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_user_device() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let callback_writes = writes.clone();
+        let mut device = UserDevice::new(Arc::new(move |vcpuid, offset, access| match access {
+            UserAccess::Read(data) => {
+                if offset == 4 {
+                    data[0] = vcpuid as u8;
+                }
+            }
+            UserAccess::Write(data) => callback_writes
+                .lock()
+                .unwrap()
+                .push((offset, data.to_vec())),
+        }));
+
+        let mut data = [0xff; 2];
+        device.read(3, 4, &mut data);
+        assert_eq!(data, [3, 0]);
+        device.read(3, 8, &mut data);
+        assert_eq!(data, [0, 0]);
+
+        device.write(1, 2, &[5, 6]);
+        assert_eq!(*writes.lock().unwrap(), vec![(2, vec![5, 6])]);
+    }
+}
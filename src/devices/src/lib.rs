@@ -7,6 +7,9 @@
 
 //! Emulates virtual and hardware devices.
 
+#[cfg(feature = "fault-injection")]
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 extern crate lru;
 
@@ -15,9 +18,13 @@ extern crate logger;
 extern crate polly;
 extern crate vm_memory;
 
+use std::fmt;
 use std::io;
 
 mod bus;
+#[cfg(feature = "fault-injection")]
+pub mod fault_injection;
+pub mod fd_budget;
 pub mod legacy;
 pub mod virtio;
 
@@ -30,9 +37,41 @@ pub enum Error {
         underlying: io::Error,
     },
     FailedReadTap,
+    FailedSignalingConfigChange(io::Error),
     FailedSignalingUsedQueue(io::Error),
     PayloadExpected,
     IoError(io::Error),
     NoAvailBuffers,
     SpuriousEvent,
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            FailedReadingQueue { event_type, .. } => {
+                write!(f, "Failed to read the {} queue event", event_type)
+            }
+            FailedReadTap => write!(f, "Failed to read from the tap device"),
+            FailedSignalingConfigChange(_) => {
+                write!(f, "Failed to signal the change of the configuration")
+            }
+            FailedSignalingUsedQueue(_) => write!(f, "Failed to signal the used queue"),
+            PayloadExpected => write!(f, "A payload was expected"),
+            IoError(_) => write!(f, "I/O error"),
+            NoAvailBuffers => write!(f, "No buffers available"),
+            SpuriousEvent => write!(f, "Spurious event"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+        match self {
+            FailedReadingQueue { underlying, .. } => Some(underlying),
+            FailedSignalingConfigChange(e) | FailedSignalingUsedQueue(e) | IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
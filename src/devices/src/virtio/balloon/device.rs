@@ -1,16 +1,17 @@
 use std::cmp;
-use std::convert::TryInto;
 use std::io::Write;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, GuestMemory, GuestMemoryMmap};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
+use super::super::guest_memory::host_address;
 use super::super::{
-    ActivateError, ActivateResult, BalloonError, DeviceState, Queue as VirtQueue, VirtioDevice,
-    VIRTIO_MMIO_INT_VRING,
+    signal_config_change, ActivateError, ActivateResult, BalloonError, ConfigGeneration,
+    DeviceState, Queue as VirtQueue, VirtioDevice, VIRTIO_MMIO_INT_VRING, VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
 };
 use super::{defs, defs::uapi};
 use crate::legacy::Gic;
@@ -27,11 +28,20 @@ pub(crate) const PHQ_INDEX: usize = 3;
 // Free page reporting queue.
 pub(crate) const FRQ_INDEX: usize = 4;
 
+// The pages of the inflate and deflate queues are given by their frame number, in 4 KiB pages
+// whatever the page size of the guest.
+const PFN_SHIFT: u64 = 12;
+const BALLOON_PAGE_SIZE: u64 = 1 << PFN_SHIFT;
+// The offset of the number of pages the driver has given up in the configuration space.
+const ACTUAL_OFFSET: u64 = 4;
+
 // Supported features.
 pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
     | 1 << uapi::VIRTIO_BALLOON_F_STATS_VQ as u64
     | 1 << uapi::VIRTIO_BALLOON_F_FREE_PAGE_HINT as u64
-    | 1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64;
+    | 1 << uapi::VIRTIO_BALLOON_F_REPORTING as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
@@ -59,6 +69,7 @@ pub struct Balloon {
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
     config: VirtioBalloonConfig,
+    config_generation: ConfigGeneration,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
 }
@@ -85,6 +96,7 @@ impl Balloon {
                 .map_err(BalloonError::EventFd)?,
             device_state: DeviceState::Inactive,
             config,
+            config_generation: ConfigGeneration::default(),
             intc: None,
             irq_line: None,
         })
@@ -106,6 +118,29 @@ impl Balloon {
         self.intc = Some(intc);
     }
 
+    /// Asks the guest to give up `num_pages` pages of 4 KiB to the balloon, which the host
+    /// releases, or to take them back if it has given up more.
+    pub fn set_target(&mut self, num_pages: u32) -> result::Result<(), DeviceError> {
+        self.config.num_pages = num_pages;
+        signal_config_change(
+            &self.config_generation,
+            &self.interrupt_status,
+            &self.interrupt_evt,
+            self.intc.as_ref(),
+            self.irq_line,
+        )
+    }
+
+    /// Returns the number of pages the guest is asked to give up.
+    pub fn target(&self) -> u32 {
+        self.config.num_pages
+    }
+
+    /// Returns the number of pages the guest has given up, as reported by its driver.
+    pub fn actual(&self) -> u32 {
+        self.config.actual
+    }
+
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         debug!("balloon: raising IRQ");
         self.interrupt_status
@@ -121,6 +156,65 @@ impl Balloon {
         }
     }
 
+    /// Releases the pages the guest gives up to the balloon.
+    pub fn process_ifq(&mut self) -> bool {
+        debug!("balloon: process_ifq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[IFQ_INDEX].pop(mem) {
+            let index = head.index;
+            for desc in head.into_iter() {
+                // The descriptors hold arrays of 32-bit frame numbers.
+                for pfn_index in 0..u64::from(desc.len / 4) {
+                    let addr = desc.addr.checked_add(pfn_index * 4);
+                    let pfn: u32 = match addr.map(|addr| mem.read_obj(addr)) {
+                        Some(Ok(pfn)) => pfn,
+                        _ => {
+                            warn!("balloon: unable to read a page frame number");
+                            break;
+                        }
+                    };
+                    release(
+                        mem,
+                        GuestAddress(u64::from(pfn) << PFN_SHIFT),
+                        BALLOON_PAGE_SIZE as usize,
+                    );
+                }
+            }
+
+            have_used = true;
+            self.queues[IFQ_INDEX].add_used(mem, index, 0);
+        }
+
+        have_used
+    }
+
+    /// Returns the pages the guest takes back from the balloon, which fault back in as the guest
+    /// touches them, so there's nothing else to do with them.
+    pub fn process_dfq(&mut self) -> bool {
+        debug!("balloon: process_dfq()");
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[DFQ_INDEX].pop(mem) {
+            have_used = true;
+            self.queues[DFQ_INDEX].add_used(mem, head.index, 0);
+        }
+
+        have_used
+    }
+
     pub fn process_frq(&mut self) -> bool {
         debug!("balloon: process_frq()");
         let mem = match self.device_state {
@@ -134,18 +228,7 @@ impl Balloon {
         while let Some(head) = self.queues[FRQ_INDEX].pop(mem) {
             let index = head.index;
             for desc in head.into_iter() {
-                let host_addr = mem.get_host_address(desc.addr).unwrap();
-                debug!(
-                    "balloon: should release guest_addr={:?} host_addr={:p} len={}",
-                    desc.addr, host_addr, desc.len
-                );
-                unsafe {
-                    libc::madvise(
-                        host_addr as *mut libc::c_void,
-                        desc.len.try_into().unwrap(),
-                        libc::MADV_DONTNEED,
-                    )
-                };
+                release(mem, desc.addr, desc.len as usize);
             }
 
             have_used = true;
@@ -156,6 +239,24 @@ impl Balloon {
     }
 }
 
+/// Releases the host pages backing the `len` bytes of guest memory at `addr`.
+fn release(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
+    // The whole range must be guest memory, or the host would release its own.
+    let host_addr = match host_address(mem, addr, len) {
+        Ok(host_addr) => host_addr,
+        Err(e) => {
+            warn!("balloon: not releasing the page: {}", e);
+            return;
+        }
+    };
+    debug!(
+        "balloon: should release guest_addr={:?} host_addr={:p} len={}",
+        addr, host_addr, len
+    );
+    // Safe because the range lies within a region of the guest memory.
+    unsafe { libc::madvise(host_addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+}
+
 impl VirtioDevice for Balloon {
     fn avail_features(&self) -> u64 {
         self.avail_features
@@ -212,6 +313,11 @@ impl VirtioDevice for Balloon {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The driver only reports the number of pages it has given up.
+        if offset == ACTUAL_OFFSET && data.len() == 4 {
+            self.config.actual = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            return;
+        }
         warn!(
             "balloon: guest driver attempted to write device config (offset={:x}, len={:x})",
             offset,
@@ -245,4 +351,32 @@ impl VirtioDevice for Balloon {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn config_generation(&self) -> Option<ConfigGeneration> {
+        Some(self.config_generation.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        let mut balloon = Balloon::new().unwrap();
+        balloon.set_target(256).unwrap();
+        assert_eq!(balloon.target(), 256);
+        assert_eq!(balloon.config_generation.get(), 1);
+        assert_eq!(balloon.interrupt_evt.read().unwrap(), 1);
+
+        let mut num_pages = [0u8; 4];
+        balloon.read_config(0, &mut num_pages);
+        assert_eq!(u32::from_le_bytes(num_pages), 256);
+
+        // The driver reports the pages it has given up.
+        balloon.write_config(ACTUAL_OFFSET, &128u32.to_le_bytes());
+        assert_eq!(balloon.actual(), 128);
+        balloon.write_config(0, &0u32.to_le_bytes());
+        assert_eq!(balloon.target(), 256);
+    }
 }
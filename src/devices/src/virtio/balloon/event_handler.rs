@@ -8,7 +8,7 @@ use crate::virtio::device::VirtioDevice;
 
 impl Balloon {
     pub(crate) fn handle_ifq_event(&mut self, event: &EpollEvent) {
-        error!("balloon: unsupported inflate queue event");
+        debug!("balloon: inflate queue event");
 
         let event_set = event.event_set();
         if event_set != EventSet::IN {
@@ -18,11 +18,13 @@ impl Balloon {
 
         if let Err(e) = self.queue_events[IFQ_INDEX].read() {
             error!("Failed to read balloon inflate queue event: {:?}", e);
+        } else if self.process_ifq() && self.device_state.needs_notification(&mut self.queues) {
+            self.signal_used_queue().unwrap();
         }
     }
 
     pub(crate) fn handle_dfq_event(&mut self, event: &EpollEvent) {
-        error!("balloon: unsupported deflate queue event");
+        debug!("balloon: deflate queue event");
 
         let event_set = event.event_set();
         if event_set != EventSet::IN {
@@ -31,7 +33,9 @@ impl Balloon {
         }
 
         if let Err(e) = self.queue_events[DFQ_INDEX].read() {
-            error!("Failed to read balloon inflate queue event: {:?}", e);
+            error!("Failed to read balloon deflate queue event: {:?}", e);
+        } else if self.process_dfq() && self.device_state.needs_notification(&mut self.queues) {
+            self.signal_used_queue().unwrap();
         }
     }
 
@@ -80,7 +84,7 @@ impl Balloon {
                 "Failed to read balloon free-page reporting queue event: {:?}",
                 e
             );
-        } else if self.process_frq() {
+        } else if self.process_frq() && self.device_state.needs_notification(&mut self.queues) {
             self.signal_used_queue().unwrap();
         }
     }
@@ -206,4 +210,8 @@ impl Subscriber for Balloon {
             self.activate_evt.as_raw_fd() as u64,
         )]
     }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
 }
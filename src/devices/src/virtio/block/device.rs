@@ -0,0 +1,444 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Write};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::EventFd;
+use vm_memory::{ByteValued, GuestMemoryMmap};
+
+use super::super::{
+    signal_config_change, ActivateError, ActivateResult, BlockError, ConfigGeneration, DeviceState,
+    Queue as VirtQueue, VirtioDevice, VIRTIO_MMIO_INT_VRING, VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
+};
+use super::{defs, defs::uapi};
+use crate::legacy::Gic;
+use crate::virtio::fs::descriptor_utils::{Reader, Writer};
+use crate::Error as DeviceError;
+
+// Request queue.
+pub(crate) const REQ_INDEX: usize = 0;
+
+// Supported features.
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << uapi::VIRTIO_BLK_F_FLUSH as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioBlockConfig {
+    /* The size of the disk, in 512-byte sectors. */
+    capacity: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBlockConfig {}
+
+/// The header of the requests, followed by their data and, last, by their status.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for RequestHeader {}
+
+pub struct Block {
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) interrupt_status: Arc<AtomicUsize>,
+    pub(crate) interrupt_evt: EventFd,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+    id: String,
+    disk: File,
+    disk_size: u64,
+    config: VirtioBlockConfig,
+    config_generation: ConfigGeneration,
+    intc: Option<Arc<Mutex<Gic>>>,
+    irq_line: Option<u32>,
+}
+
+impl Block {
+    pub(crate) fn with_queues(
+        queues: Vec<VirtQueue>,
+        id: &str,
+        disk: File,
+    ) -> super::Result<Block> {
+        let mut queue_events = Vec::new();
+        for _ in 0..queues.len() {
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(BlockError::EventFd)?);
+        }
+
+        // The sectors the disk ends in the middle of aren't exposed to the guest.
+        let disk_size = disk.metadata().map_err(BlockError::DiskSize)?.len();
+        let config = VirtioBlockConfig {
+            capacity: disk_size / defs::SECTOR_SIZE,
+        };
+
+        Ok(Block {
+            queues,
+            queue_events,
+            avail_features: AVAIL_FEATURES,
+            acked_features: 0,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(BlockError::EventFd)?,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(BlockError::EventFd)?,
+            device_state: DeviceState::Inactive,
+            id: id.to_string(),
+            disk,
+            disk_size: config.capacity * defs::SECTOR_SIZE,
+            config,
+            config_generation: ConfigGeneration::default(),
+            intc: None,
+            irq_line: None,
+        })
+    }
+
+    /// Creates a device exposing `disk` to the guest. `id` names the device, and is the serial
+    /// the guest reads from it.
+    pub fn new(id: &str, disk: File) -> super::Result<Block> {
+        let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
+            .iter()
+            .map(|&max_size| VirtQueue::new(max_size))
+            .collect();
+        Self::with_queues(queues, id, disk)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the size of the disk exposed to the guest, in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    /// Grows the disk to `size` bytes, extending its backing file, and notifies the guest of its
+    /// new capacity. The disk can't shrink, as the guest may be using its end. Returns the size
+    /// exposed to the guest, which leaves out the partial sector at the end.
+    pub fn resize(&mut self, size: u64) -> io::Result<u64> {
+        let size = size - size % defs::SECTOR_SIZE;
+        if size < self.disk_size {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if size == self.disk_size {
+            return Ok(size);
+        }
+
+        self.disk.set_len(size)?;
+        self.disk_size = size;
+        self.config.capacity = size / defs::SECTOR_SIZE;
+        signal_config_change(
+            &self.config_generation,
+            &self.interrupt_status,
+            &self.interrupt_evt,
+            self.intc.as_ref(),
+            self.irq_line,
+        )
+        .map_err(|e| {
+            error!("Failed to signal config change: {:?}", e);
+            io::Error::from_raw_os_error(libc::EIO)
+        })?;
+        Ok(size)
+    }
+
+    pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
+        self.intc = Some(intc);
+    }
+
+    pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        debug!("block: raising IRQ");
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.lock().unwrap().set_irq(self.irq_line.unwrap());
+            Ok(())
+        } else {
+            self.interrupt_evt.write(1).map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+        }
+    }
+
+    /// Checks the `len` bytes at `sector` are within the disk, returning their offset.
+    fn disk_offset(&self, sector: u64, len: usize) -> io::Result<u64> {
+        sector
+            .checked_mul(defs::SECTOR_SIZE)
+            .filter(|offset| {
+                offset
+                    .checked_add(len as u64)
+                    .map_or(false, |end| end <= self.disk_size)
+            })
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+
+    fn read_disk(&mut self, sector: u64, writer: &mut Writer) -> io::Result<()> {
+        let len = writer.available_bytes();
+        let offset = self.disk_offset(sector, len)?;
+        let mut done = 0;
+        while done < len {
+            match writer.write_from_at(&mut self.disk, len - done, offset + done as u64) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(count) => done += count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_disk(&mut self, sector: u64, reader: &mut Reader) -> io::Result<()> {
+        let len = reader.available_bytes();
+        let offset = self.disk_offset(sector, len)?;
+        let mut done = 0;
+        while done < len {
+            match reader.read_to_at(&mut self.disk, len - done, offset + done as u64) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => done += count,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, mut reader: Reader, mut writer: Writer) -> usize {
+        // The status is the last byte of the buffers of the guest, following the data.
+        let mut status_writer = match writer
+            .available_bytes()
+            .checked_sub(1)
+            .map(|offset| writer.split_at(offset))
+        {
+            Some(Ok(status_writer)) => status_writer,
+            _ => {
+                error!("block: request without room for its status");
+                return 0;
+            }
+        };
+
+        let header: RequestHeader = match reader.read_obj() {
+            Ok(header) => header,
+            Err(e) => {
+                error!("block: unable to read request header: {}", e);
+                return 0;
+            }
+        };
+
+        let result = match header.request_type {
+            uapi::VIRTIO_BLK_T_IN => self.read_disk(header.sector, &mut writer),
+            uapi::VIRTIO_BLK_T_OUT => self.write_disk(header.sector, &mut reader),
+            uapi::VIRTIO_BLK_T_FLUSH => self.disk.sync_data(),
+            uapi::VIRTIO_BLK_T_GET_ID => {
+                let mut serial = [0u8; defs::ID_LEN];
+                let len = cmp::min(self.id.len(), defs::ID_LEN);
+                serial[..len].copy_from_slice(&self.id.as_bytes()[..len]);
+                let len = cmp::min(writer.available_bytes(), defs::ID_LEN);
+                writer.write_all(&serial[..len])
+            }
+            request_type => {
+                debug!("block: unsupported request type {}", request_type);
+                Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+            }
+        };
+        let status = match result {
+            Ok(()) => uapi::VIRTIO_BLK_S_OK,
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => uapi::VIRTIO_BLK_S_UNSUPP,
+            Err(e) => {
+                error!(
+                    "block: request {} on sector {} failed: {}",
+                    header.request_type, header.sector, e
+                );
+                uapi::VIRTIO_BLK_S_IOERR
+            }
+        };
+
+        if let Err(e) = status_writer.write_obj(status) {
+            error!("block: unable to write request status: {}", e);
+        }
+        writer.bytes_written() + status_writer.bytes_written()
+    }
+
+    /// Processes the requests available in the queue, returning whether any was handed back to
+    /// the guest.
+    pub(crate) fn process_queue(&mut self) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[REQ_INDEX].pop(&mem) {
+            let index = head.index;
+            let len = match (Reader::new(&mem, head.clone()), Writer::new(&mem, head)) {
+                (Ok(reader), Ok(writer)) => self.handle_request(reader, writer),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("block: invalid descriptor chain: {}", e);
+                    0
+                }
+            };
+
+            have_used = true;
+            self.queues[REQ_INDEX].add_used(&mem, index, len as u32);
+        }
+
+        have_used
+    }
+}
+
+impl VirtioDevice for Block {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features
+    }
+
+    fn device_type(&self) -> u32 {
+        uapi::VIRTIO_ID_BLOCK
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn set_irq_line(&mut self, irq: u32) {
+        self.irq_line = Some(irq);
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        warn!(
+            "block: guest driver attempted to write device config (offset={:x}, len={:x})",
+            offset,
+            data.len()
+        );
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.queues.len() != defs::NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                defs::NUM_QUEUES,
+                self.queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        match self.device_state {
+            DeviceState::Inactive => false,
+            DeviceState::Activated(_) => true,
+        }
+    }
+
+    fn config_generation(&self) -> Option<ConfigGeneration> {
+        Some(self.config_generation.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_disk_offset() {
+        let disk = TempFile::new().unwrap().into_file();
+        // The partial sector at the end isn't exposed.
+        disk.set_len(4 * defs::SECTOR_SIZE + 100).unwrap();
+        let block = Block::new("test", disk).unwrap();
+        let capacity = block.config.capacity;
+        assert_eq!(capacity, 4);
+        assert_eq!(block.disk_size(), 4 * defs::SECTOR_SIZE);
+
+        assert_eq!(block.disk_offset(1, 512).unwrap(), defs::SECTOR_SIZE);
+        assert_eq!(block.disk_offset(0, 4 * 512).unwrap(), 0);
+        assert!(block.disk_offset(3, 513).is_err());
+        assert!(block.disk_offset(4, 1).is_err());
+        assert!(block.disk_offset(u64::MAX, 0).is_err());
+    }
+
+    #[test]
+    fn test_resize() {
+        let disk = TempFile::new().unwrap().into_file();
+        disk.set_len(4 * defs::SECTOR_SIZE).unwrap();
+        let mut block = Block::new("test", disk.try_clone().unwrap()).unwrap();
+
+        assert_eq!(
+            block
+                .resize(3 * defs::SECTOR_SIZE)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(block.resize(4 * defs::SECTOR_SIZE + 100).unwrap(), 2048);
+        assert_eq!(block.config_generation.get(), 0);
+
+        assert_eq!(block.resize(8 * defs::SECTOR_SIZE + 100).unwrap(), 4096);
+        assert_eq!(disk.metadata().unwrap().len(), 4096);
+        let capacity = block.config.capacity;
+        assert_eq!(capacity, 8);
+        assert_eq!(block.config_generation.get(), 1);
+        assert_eq!(block.interrupt_evt.read().unwrap(), 1);
+        assert!(block.disk_offset(7, 512).is_ok());
+    }
+}
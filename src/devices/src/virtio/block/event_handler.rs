@@ -0,0 +1,94 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::{Block, REQ_INDEX};
+use crate::virtio::device::VirtioDevice;
+
+impl Block {
+    pub(crate) fn handle_queue_event(&mut self, event: &EpollEvent) {
+        debug!("block: request queue event");
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!("block: request queue unexpected event {:?}", event_set);
+            return;
+        }
+
+        if let Err(e) = self.queue_events[REQ_INDEX].read() {
+            error!("Failed to read block request queue event: {:?}", e);
+        } else if self.process_queue() && self.device_state.needs_notification(&mut self.queues) {
+            self.signal_used_queue().unwrap();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("block: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume block activate event: {:?}", e);
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        event_manager
+            .register(
+                self.queue_events[REQ_INDEX].as_raw_fd(),
+                EpollEvent::new(
+                    EventSet::IN,
+                    self.queue_events[REQ_INDEX].as_raw_fd() as u64,
+                ),
+                self_subscriber,
+            )
+            .unwrap_or_else(|e| {
+                error!(
+                    "Failed to register block request queue with event manager: {:?}",
+                    e
+                );
+            });
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister block activate evt: {:?}", e);
+            })
+    }
+}
+
+impl Subscriber for Block {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let reqq = self.queue_events[REQ_INDEX].as_raw_fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            match source {
+                _ if source == reqq => self.handle_queue_event(event),
+                _ if source == activate_evt => {
+                    self.handle_activate_event(event_manager);
+                }
+                _ => warn!("Unexpected block event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "block: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
+}
@@ -0,0 +1,38 @@
+mod device;
+mod event_handler;
+
+pub use self::device::Block;
+
+mod defs {
+    pub const NUM_QUEUES: usize = 1;
+    pub const QUEUE_SIZES: &[u16] = &[256; NUM_QUEUES];
+    /// The size of the sectors requests are addressed in, regardless of the disk.
+    pub const SECTOR_SIZE: u64 = 512;
+    /// The length of the serial returned to VIRTIO_BLK_T_GET_ID.
+    pub const ID_LEN: usize = 20;
+
+    pub mod uapi {
+        pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_ID_BLOCK: u32 = 2;
+        pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
+
+        pub const VIRTIO_BLK_T_IN: u32 = 0;
+        pub const VIRTIO_BLK_T_OUT: u32 = 1;
+        pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+        pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+        pub const VIRTIO_BLK_S_OK: u8 = 0;
+        pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+        pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+    /// Failed to get the size of the disk.
+    DiskSize(std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, BlockError>;
@@ -0,0 +1,385 @@
+//! Lets clients attach to, and detach from, the console while the microVM runs, over a Unix
+//! socket, in the way of screen or tmux.
+//!
+//! Both ways, the data is sent in frames of a 3-byte header, holding the type of the frame and the
+//! length of its payload as a 16-bit little-endian integer, followed by the payload:
+//!
+//! ```text
+//! FRAME_DATA   output of the guest, or input for it
+//! FRAME_RESIZE the size of the terminal of the client, as two 16-bit little-endian integers,
+//!              the columns followed by the rows
+//! FRAME_DETACH the client detaches, with an empty payload
+//! ```
+//!
+//! A single client is attached at a time: a new one detaches the previous one. While no client is
+//! attached, the output of the guest is kept, up to the size of the scrollback, and replayed to
+//! the next one as its first frame, which is empty if there's nothing to replay.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use utils::eventfd::EventFd;
+
+use super::callback::CallbackInput;
+
+pub const FRAME_DATA: u8 = 0;
+pub const FRAME_RESIZE: u8 = 1;
+pub const FRAME_DETACH: u8 = 2;
+
+const FRAME_HEADER_SIZE: usize = 3;
+const FRAME_MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// The default size of the output kept while no client is attached.
+pub const DEFAULT_SCROLLBACK_SIZE: usize = 64 * 1024;
+
+// The size of the console until a client tells the size of its terminal.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+// The time a client has to take the output of the guest before it's detached, so a stuck client
+// can't stall the console.
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+// The time to wait for room in the input buffer of the console.
+const INPUT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Writes a frame of type `kind` holding `payload`, split in several frames if needed.
+pub fn write_frame<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut chunks = payload.chunks(FRAME_MAX_PAYLOAD).peekable();
+    if chunks.peek().is_none() {
+        return writer.write_all(&[kind, 0, 0]);
+    }
+    for chunk in chunks {
+        let len = (chunk.len() as u16).to_le_bytes();
+        writer.write_all(&[kind, len[0], len[1]])?;
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+/// Reads a frame, returning its type and its payload, or `None` at the end of the stream.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut payload = vec![0u8; u16::from_le_bytes([header[1], header[2]]) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((header[0], payload)))
+}
+
+struct AttachState {
+    // The attached client, along with the number it was given on attach.
+    client: Option<(u64, UnixStream)>,
+    attaches: u64,
+    scrollback: VecDeque<u8>,
+    scrollback_size: usize,
+    size: (u16, u16),
+    resize_evt: Option<EventFd>,
+}
+
+impl AttachState {
+    fn detach(&mut self) {
+        if let Some((_, client)) = self.client.take() {
+            let _ = client.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn keep(&mut self, data: &[u8]) {
+        let data = &data[data.len().saturating_sub(self.scrollback_size)..];
+        let excess = (self.scrollback.len() + data.len()).saturating_sub(self.scrollback_size);
+        self.scrollback.drain(..excess);
+        self.scrollback.extend(data);
+    }
+}
+
+/// The console backend clients attach to over a Unix socket. It's the output of the console
+/// device, and `input` is its input.
+#[derive(Clone)]
+pub struct ConsoleAttach {
+    state: Arc<Mutex<AttachState>>,
+    input: CallbackInput,
+    path: Option<PathBuf>,
+}
+
+impl ConsoleAttach {
+    /// Starts accepting clients on `listener`, keeping up to `scrollback_size` bytes of output
+    /// while none is attached.
+    pub fn new(listener: UnixListener, scrollback_size: usize) -> io::Result<Self> {
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|path| path.to_path_buf());
+        listener.set_nonblocking(false)?;
+
+        let attach = ConsoleAttach {
+            state: Arc::new(Mutex::new(AttachState {
+                client: None,
+                attaches: 0,
+                scrollback: VecDeque::new(),
+                scrollback_size,
+                size: DEFAULT_SIZE,
+                resize_evt: None,
+            })),
+            input: CallbackInput::new()?,
+            path,
+        };
+
+        let acceptor = attach.clone();
+        thread::Builder::new()
+            .name("console attach".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => acceptor.attach(stream),
+                        Err(e) => warn!("console: unable to accept a client: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(attach)
+    }
+
+    /// The input of the console device, fed by the attached client.
+    pub fn input(&self) -> CallbackInput {
+        self.input.clone()
+    }
+
+    /// The path of the socket clients attach through, if it has one.
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    /// Returns the size of the terminal of the last client, as columns and rows.
+    pub fn size(&self) -> (u16, u16) {
+        self.state.lock().unwrap().size
+    }
+
+    /// Signals `evt` whenever the client resizes its terminal.
+    pub fn set_resize_evt(&self, evt: EventFd) {
+        self.state.lock().unwrap().resize_evt = Some(evt);
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.state.lock().unwrap().client.is_some()
+    }
+
+    /// Detaches the attached client, if any.
+    pub fn detach(&self) {
+        self.state.lock().unwrap().detach();
+    }
+
+    fn attach(&self, stream: UnixStream) {
+        let reader = match stream
+            .set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))
+            .and_then(|_| stream.try_clone())
+        {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("console: unable to attach a client: {}", e);
+                return;
+            }
+        };
+
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.detach();
+            state.attaches += 1;
+            let id = state.attaches;
+
+            let scrollback: Vec<u8> = state.scrollback.drain(..).collect();
+            let mut stream = stream;
+            if let Err(e) = write_frame(&mut stream, FRAME_DATA, &scrollback) {
+                warn!("console: unable to attach a client: {}", e);
+                return;
+            }
+            state.client = Some((id, stream));
+            id
+        };
+        info!("console: client attached");
+
+        let attach = self.clone();
+        let spawned = thread::Builder::new()
+            .name("console client".to_string())
+            .spawn(move || attach.serve(id, reader));
+        if let Err(e) = spawned {
+            warn!("console: unable to serve the client: {}", e);
+            self.detach();
+        }
+    }
+
+    // Handles the frames of the client `id`, until it detaches or is replaced.
+    fn serve(&self, id: u64, mut reader: UnixStream) {
+        loop {
+            let frame = match read_frame(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("console: client error: {}", e);
+                    break;
+                }
+            };
+            if !self.is_current(id) {
+                return;
+            }
+
+            match frame {
+                (FRAME_DATA, data) => self.push_input(id, &data),
+                (FRAME_RESIZE, size) if size.len() == 4 => {
+                    let cols = u16::from_le_bytes([size[0], size[1]]);
+                    let rows = u16::from_le_bytes([size[2], size[3]]);
+                    let mut state = self.state.lock().unwrap();
+                    state.size = (cols, rows);
+                    if let Some(evt) = &state.resize_evt {
+                        let _ = evt.write(1);
+                    }
+                }
+                (FRAME_DETACH, _) => break,
+                (kind, _) => debug!("console: ignoring a frame of type {}", kind),
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.client, Some((current, _)) if current == id) {
+            state.detach();
+            info!("console: client detached");
+        }
+    }
+
+    #[cfg(test)]
+    fn scrollback(&self) -> Vec<u8> {
+        self.state
+            .lock()
+            .unwrap()
+            .scrollback
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn is_current(&self, id: u64) -> bool {
+        matches!(self.state.lock().unwrap().client, Some((current, _)) if current == id)
+    }
+
+    fn push_input(&self, id: u64, mut data: &[u8]) {
+        while !data.is_empty() && self.is_current(id) {
+            match self.input.push(data) {
+                Ok(0) => thread::sleep(INPUT_RETRY_INTERVAL),
+                Ok(count) => data = &data[count..],
+                Err(e) => {
+                    warn!("console: unable to queue the input of the client: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Write for ConsoleAttach {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let written = match state.client.as_mut() {
+            Some((_, client)) => write_frame(client, FRAME_DATA, buf),
+            None => {
+                state.keep(buf);
+                return Ok(buf.len());
+            }
+        };
+        if let Err(e) = written {
+            // The output is kept for the next client, rather than failing the console.
+            warn!("console: detaching the client: {}", e);
+            state.detach();
+            state.keep(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempdir::TempDir;
+
+    fn connect(attach: &ConsoleAttach) -> UnixStream {
+        let client = UnixStream::connect(attach.path().unwrap()).unwrap();
+        while !attach.is_attached() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        client
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, FRAME_DETACH, &[]).unwrap();
+        write_frame(&mut stream, FRAME_DATA, &vec![b'x'; FRAME_MAX_PAYLOAD + 1]).unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            Some((FRAME_DETACH, Vec::new()))
+        );
+        assert_eq!(
+            read_frame(&mut reader).unwrap().unwrap().1.len(),
+            FRAME_MAX_PAYLOAD
+        );
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap().1, b"x");
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let dir = TempDir::new().unwrap();
+        let listener = UnixListener::bind(dir.as_path().join("console.sock")).unwrap();
+        let mut attach = ConsoleAttach::new(listener, 8).unwrap();
+
+        // Only the end of the output is kept while detached.
+        attach.write_all(b"0123456789").unwrap();
+        assert_eq!(attach.scrollback(), b"23456789");
+
+        let mut client = connect(&attach);
+        assert_eq!(
+            read_frame(&mut client).unwrap(),
+            Some((FRAME_DATA, b"23456789".to_vec()))
+        );
+        attach.write_all(b"live").unwrap();
+        assert_eq!(
+            read_frame(&mut client).unwrap(),
+            Some((FRAME_DATA, b"live".to_vec()))
+        );
+
+        write_frame(&mut client, FRAME_RESIZE, &[120, 0, 40, 0]).unwrap();
+        write_frame(&mut client, FRAME_DATA, b"ls\n").unwrap();
+        let mut input = attach.input();
+        let mut buf = [0u8; 3];
+        while input.read(&mut buf[..]).unwrap_or(0) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(&buf, b"ls\n");
+        assert_eq!(attach.size(), (120, 40));
+
+        // A new client detaches the previous one.
+        let mut second = connect(&attach);
+        assert_eq!(read_frame(&mut client).unwrap(), None);
+        assert_eq!(
+            read_frame(&mut second).unwrap(),
+            Some((FRAME_DATA, Vec::new()))
+        );
+
+        write_frame(&mut second, FRAME_DETACH, &[]).unwrap();
+        while attach.is_attached() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        attach.write_all(b"later").unwrap();
+        assert_eq!(attach.scrollback(), b"later");
+    }
+}
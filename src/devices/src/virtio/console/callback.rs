@@ -0,0 +1,179 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::EventFd;
+
+use crate::legacy::ReadableFd;
+
+/// Maximum amount of bytes buffered on behalf of the guest before `push` starts rejecting input.
+pub const CALLBACK_INPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Console input fed by the embedder through function calls instead of a file descriptor.
+///
+/// Bytes pushed with `push` are stored in an internal ring buffer, and the wakeup eventfd
+/// exposed through `AsRawFd` is signaled so the console device picks them up from the
+/// event loop.
+#[derive(Clone)]
+pub struct CallbackInput {
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+    wakeup_evt: Arc<EventFd>,
+}
+
+impl CallbackInput {
+    pub fn new() -> io::Result<Self> {
+        Ok(CallbackInput {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(
+                CALLBACK_INPUT_BUFFER_SIZE,
+            ))),
+            wakeup_evt: Arc::new(EventFd::new(utils::eventfd::EFD_NONBLOCK)?),
+        })
+    }
+
+    /// Queues `data` to be delivered to the guest. Returns the number of bytes accepted, which
+    /// may be less than `data.len()` if the internal buffer is full.
+    pub fn push(&self, data: &[u8]) -> io::Result<usize> {
+        let count = {
+            let mut buffer = self.buffer.lock().unwrap();
+            let count = cmp::min(data.len(), CALLBACK_INPUT_BUFFER_SIZE - buffer.len());
+            buffer.extend(&data[..count]);
+            count
+        };
+
+        if count > 0 {
+            self.wakeup_evt.write(1)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Queues `data` to be delivered to the guest as a whole. Returns false, queuing nothing, if
+    /// the internal buffer can't hold all of it.
+    pub fn push_all(&self, data: &[u8]) -> io::Result<bool> {
+        {
+            let mut buffer = self.buffer.lock().unwrap();
+            if data.len() > CALLBACK_INPUT_BUFFER_SIZE - buffer.len() {
+                return Ok(false);
+            }
+            buffer.extend(data);
+        }
+
+        if !data.is_empty() {
+            self.wakeup_evt.write(1)?;
+        }
+
+        Ok(true)
+    }
+}
+
+impl io::Read for CallbackInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Consume the wakeup before draining the buffer, so a concurrent `push` always leaves
+        // the eventfd signaled for the next iteration of the event loop.
+        if let Err(e) = self.wakeup_evt.read() {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+
+        let mut buffer = self.buffer.lock().unwrap();
+        let count = cmp::min(buf.len(), buffer.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.drain(..count)) {
+            *dst = src;
+        }
+
+        if !buffer.is_empty() {
+            self.wakeup_evt.write(1)?;
+        }
+
+        Ok(count)
+    }
+}
+
+impl AsRawFd for CallbackInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup_evt.as_raw_fd()
+    }
+}
+
+impl ReadableFd for CallbackInput {}
+
+/// Console output delivered to the embedder through a callback instead of a file descriptor.
+#[derive(Clone)]
+pub struct CallbackOutput {
+    callback: Arc<Mutex<Box<dyn FnMut(&[u8]) + Send>>>,
+}
+
+impl CallbackOutput {
+    pub fn new(callback: Box<dyn FnMut(&[u8]) + Send>) -> Self {
+        CallbackOutput {
+            callback: Arc::new(Mutex::new(callback)),
+        }
+    }
+}
+
+impl io::Write for CallbackOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.callback.lock().unwrap())(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_callback_input() {
+        let input = CallbackInput::new().unwrap();
+        let mut reader = input.clone();
+
+        assert_eq!(input.push(b"hello").unwrap(), 5);
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let big = vec![0u8; CALLBACK_INPUT_BUFFER_SIZE + 1];
+        assert_eq!(input.push(&big).unwrap(), CALLBACK_INPUT_BUFFER_SIZE);
+        assert_eq!(input.push(b"x").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_callback_input_push_all() {
+        let input = CallbackInput::new().unwrap();
+        let mut reader = input.clone();
+
+        let big = vec![0u8; CALLBACK_INPUT_BUFFER_SIZE - 2];
+        assert!(input.push_all(&big).unwrap());
+        // What doesn't fit whole isn't queued at all.
+        assert!(!input.push_all(b"abc").unwrap());
+        assert!(input.push_all(b"ab").unwrap());
+
+        let mut buf = vec![0u8; CALLBACK_INPUT_BUFFER_SIZE];
+        assert_eq!(reader.read(&mut buf).unwrap(), CALLBACK_INPUT_BUFFER_SIZE);
+        assert_eq!(&buf[CALLBACK_INPUT_BUFFER_SIZE - 2..], b"ab");
+    }
+
+    #[test]
+    fn test_callback_output() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let sink = captured.clone();
+        let mut output = CallbackOutput::new(Box::new(move |buf: &[u8]| {
+            sink.lock().unwrap().extend_from_slice(buf)
+        }));
+
+        assert_eq!(output.write(b"guest").unwrap(), 5);
+        output.flush().unwrap();
+        assert_eq!(&captured.lock().unwrap()[..], b"guest");
+    }
+}
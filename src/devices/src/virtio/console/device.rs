@@ -2,6 +2,7 @@ use std::cmp;
 use std::collections::VecDeque;
 use std::io;
 use std::io::Write;
+use std::mem;
 use std::ops::DerefMut;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
@@ -9,22 +10,31 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use libc::TIOCGWINSZ;
+use logger::METRICS;
 use utils::eventfd::EventFd;
-use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 use super::super::super::legacy::ReadableFd;
 use super::super::{
-    ActivateError, ActivateResult, ConsoleError, DeviceState, Queue as VirtQueue, VirtioDevice,
-    VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING,
+    signal_config_change, ActivateError, ActivateResult, ConfigGeneration, ConsoleError,
+    DeviceState, Queue as VirtQueue, VirtioDevice, VIRTIO_MMIO_INT_VRING, VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
 };
+use super::filter::{ConsoleOutputConfig, OutputFilter};
+use super::panic_scan::{GuestPanic, PanicScanner};
+use super::recorder::{RecordingOutput, SessionRecorder};
 use super::{defs, defs::uapi};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
 use crate::legacy::Gic;
 use crate::Error as DeviceError;
 
 pub(crate) const RXQ_INDEX: usize = 0;
 pub(crate) const TXQ_INDEX: usize = 1;
-pub(crate) const AVAIL_FEATURES: u64 =
-    1 << uapi::VIRTIO_CONSOLE_F_SIZE as u64 | 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_CONSOLE_F_SIZE as u64
+    | 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
 
 pub(crate) fn get_win_size() -> (u16, u16) {
     #[repr(C)]
@@ -73,6 +83,7 @@ impl VirtioConsoleConfig {
 }
 
 pub struct Console {
+    id: String,
     pub(crate) queues: Vec<VirtQueue>,
     pub(crate) queue_events: Vec<EventFd>,
     pub(crate) avail_features: u64,
@@ -84,10 +95,14 @@ pub struct Console {
     pub(crate) device_state: DeviceState,
     pub(crate) in_buffer: VecDeque<u8>,
     config: VirtioConsoleConfig,
+    config_generation: ConfigGeneration,
     pub(crate) input: Box<dyn ReadableFd + Send>,
     output: Box<dyn io::Write + Send>,
+    pub(crate) recorder: Option<SessionRecorder>,
+    pub(crate) size_provider: Option<Box<dyn Fn() -> (u16, u16) + Send>>,
     configured: bool,
     pub(crate) interactive: bool,
+    pub(crate) exit_on_hangup: bool,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
 }
@@ -108,6 +123,7 @@ impl Console {
         let config = VirtioConsoleConfig::new(cols, rows);
 
         Ok(Console {
+            id: defs::CONSOLE_DEV_ID.to_string(),
             queues,
             queue_events,
             avail_features: AVAIL_FEATURES,
@@ -122,10 +138,14 @@ impl Console {
             device_state: DeviceState::Inactive,
             in_buffer: VecDeque::new(),
             config,
+            config_generation: ConfigGeneration::default(),
             input,
             output,
+            recorder: None,
+            size_provider: None,
             configured: false,
             interactive: true,
+            exit_on_hangup: true,
             intc: None,
             irq_line: None,
         })
@@ -143,7 +163,12 @@ impl Console {
     }
 
     pub fn id(&self) -> &str {
-        defs::CONSOLE_DEV_ID
+        &self.id
+    }
+
+    /// Names the device, to tell it apart from the other consoles.
+    pub fn set_id(&mut self, id: &str) {
+        self.id = id.to_string();
     }
 
     pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
@@ -154,13 +179,59 @@ impl Console {
         self.sigwinch_evt.as_raw_fd()
     }
 
+    /// Returns a handle on the event telling the device the size of the console changed.
+    pub fn sigwinch_evt(&self) -> io::Result<EventFd> {
+        self.sigwinch_evt.try_clone()
+    }
+
+    /// Takes the size of the console, as columns and rows, from `provider` rather than from the
+    /// terminal of the VMM, as when the backend is a terminal of its own.
+    pub fn set_size_provider(&mut self, provider: Box<dyn Fn() -> (u16, u16) + Send>) {
+        let (cols, rows) = provider();
+        self.config.update_console_size(cols, rows);
+        self.size_provider = Some(provider);
+    }
+
     pub fn set_interactive(&mut self, interactive: bool) {
         self.interactive = interactive;
     }
 
+    /// Whether the VMM exits once the input hangs up, as when the terminal it runs in is closed.
+    /// Otherwise the input is only unregistered, as when it's a device that may be unplugged.
+    pub fn set_exit_on_hangup(&mut self, exit_on_hangup: bool) {
+        self.exit_on_hangup = exit_on_hangup;
+    }
+
+    /// Processes the output of the guest according to `config` before it reaches the backend.
+    pub fn set_output_config(&mut self, config: ConsoleOutputConfig) {
+        if config.is_passthrough() {
+            return;
+        }
+        let output = mem::replace(&mut self.output, Box::new(io::sink()));
+        self.output = Box::new(OutputFilter::new(output, config));
+    }
+
+    /// Records the session of the console with `recorder`. The output is recorded as the guest
+    /// writes it, so this is set after `set_output_config` to leave the recording unprocessed.
+    pub fn set_recorder(&mut self, recorder: SessionRecorder) {
+        recorder.start(self.config.cols, self.config.rows);
+        let output = mem::replace(&mut self.output, Box::new(io::sink()));
+        self.output = Box::new(RecordingOutput::new(output, recorder.clone()));
+        self.recorder = Some(recorder);
+    }
+
+    /// Scans the output for the guest kernel panicking or oopsing, invoking `callback` for each.
+    /// The output is scanned as the guest writes it, so this is set after `set_output_config`.
+    pub fn set_panic_scanner(&mut self, callback: Box<dyn Fn(&GuestPanic) + Send>) {
+        let output = mem::replace(&mut self.output, Box::new(io::sink()));
+        self.output = Box::new(PanicScanner::new(output, callback));
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        #[cfg(feature = "fault-injection")]
+        FAULTS.delay_interrupt(uapi::VIRTIO_ID_CONSOLE);
         debug!("console: raising IRQ");
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -177,17 +248,25 @@ impl Console {
 
     pub fn signal_config_update(&self) -> result::Result<(), DeviceError> {
         debug!("console: raising IRQ for config update");
-        self.interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
-        self.interrupt_evt.write(1).map_err(|e| {
-            error!("Failed to signal used queue: {:?}", e);
-            DeviceError::FailedSignalingUsedQueue(e)
+        signal_config_change(
+            &self.config_generation,
+            &self.interrupt_status,
+            &self.interrupt_evt,
+            self.intc.as_ref(),
+            self.irq_line,
+        )
+        .map_err(|e| {
+            error!("Failed to signal config change: {:?}", e);
+            e
         })
     }
 
     pub fn update_console_size(&mut self, cols: u16, rows: u16) {
         debug!("update_console_size: {} {}", cols, rows);
         self.config.update_console_size(cols, rows);
+        if let Some(recorder) = &self.recorder {
+            recorder.record_resize(cols, rows);
+        }
         self.signal_config_update().unwrap();
     }
 
@@ -207,14 +286,25 @@ impl Console {
         let mut used_any = false;
         while let Some(head) = queue.pop(mem) {
             let len = cmp::min(head.len as u32, self.in_buffer.len() as u32);
-            let source_slice = self.in_buffer.drain(..len as usize).collect::<Vec<u8>>();
-            if let Err(e) = mem.write_slice(&source_slice[..], head.addr) {
+            // The buffer is written straight from the two halves of the ring, and only dropped
+            // once in guest memory, rather than collected in a temporary buffer.
+            let (front, back) = self.in_buffer.as_slices();
+            let front = &front[..cmp::min(front.len(), len as usize)];
+            let back = &back[..len as usize - front.len()];
+            let mut res = mem.write_slice(front, head.addr);
+            if res.is_ok() && !back.is_empty() {
+                // The chain was validated to fit in guest memory, so this can't overflow.
+                res = mem.write_slice(back, GuestAddress(head.addr.0 + front.len() as u64));
+            }
+            if let Err(e) = res {
                 error!("Failed to write slice: {:?}", e);
                 queue.go_to_previous_position();
                 break;
             }
+            self.in_buffer.drain(..len as usize);
 
             queue.add_used(mem, head.index, len);
+            METRICS.console.rx_bytes.add(len as usize);
             used_any = true;
 
             if self.in_buffer.is_empty() {
@@ -242,12 +332,22 @@ impl Console {
         let queue = &mut self.queues[TXQ_INDEX];
         let mut used_any = false;
         while let Some(head) = queue.pop(mem) {
+            #[cfg(feature = "fault-injection")]
+            {
+                if FAULTS.take_queue_error(uapi::VIRTIO_ID_CONSOLE) {
+                    queue.add_used(mem, head.index, 0);
+                    used_any = true;
+                    continue;
+                }
+            }
+
             //let mut out = self.output.lock().unwrap();
             mem.write_to(head.addr, &mut self.output.deref_mut(), head.len as usize)
                 .unwrap();
             self.output.flush().unwrap();
 
             queue.add_used(mem, head.index, head.len);
+            METRICS.console.tx_bytes.add(head.len as usize);
             used_any = true;
         }
 
@@ -344,4 +444,8 @@ impl VirtioDevice for Console {
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn config_generation(&self) -> Option<ConfigGeneration> {
+        Some(self.config_generation.clone())
+    }
 }
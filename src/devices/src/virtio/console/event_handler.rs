@@ -6,6 +6,7 @@ use polly::event_manager::{EventManager, Subscriber};
 use utils::epoll::{EpollEvent, EventSet};
 
 use super::device::{get_win_size, Console, RXQ_INDEX, TXQ_INDEX};
+use super::recorder::SessionEvent;
 use crate::virtio::device::VirtioDevice;
 
 impl Console {
@@ -45,10 +46,20 @@ impl Console {
         raise_irq
     }
 
-    pub(crate) fn handle_input(&mut self, event: &EpollEvent) {
+    pub(crate) fn handle_input(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
         debug!("console: input event");
 
         let event_set = event.event_set();
+        if event_set.contains(EventSet::HANG_UP) && !self.exit_on_hangup {
+            warn!(
+                "console {}: the input hung up, no longer reading it",
+                self.id()
+            );
+            if let Err(e) = event_manager.unregister(self.input.as_raw_fd()) {
+                error!("Failed to unregister the console input: {:?}", e);
+            }
+            return;
+        }
         match event_set {
             EventSet::HANG_UP => process::exit(0),
             EventSet::IN => {}
@@ -61,8 +72,11 @@ impl Console {
         let mut out = [0u8; 64];
         let count = self.input.read(&mut out).unwrap();
         self.in_buffer.extend(&out[..count]);
+        if let Some(recorder) = &self.recorder {
+            recorder.record(SessionEvent::Input, &out[..count]);
+        }
 
-        if self.process_rx() {
+        if self.process_rx() && self.device_state.needs_notification(&mut self.queues) {
             self.signal_used_queue().unwrap();
         }
     }
@@ -124,7 +138,10 @@ impl Console {
             error!("Failed to read the sigwinch event: {:?}", e);
         }
 
-        let (cols, rows) = get_win_size();
+        let (cols, rows) = match &self.size_provider {
+            Some(provider) => provider(),
+            None => get_win_size(),
+        };
         self.update_console_size(cols, rows);
     }
 }
@@ -143,7 +160,7 @@ impl Subscriber for Console {
             match source {
                 _ if source == rxq => raise_irq = self.handle_rxq_event(event),
                 _ if source == txq => raise_irq = self.handle_txq_event(event),
-                _ if source == input => self.handle_input(event),
+                _ if source == input => self.handle_input(event, event_manager),
                 _ if source == activate_evt => {
                     self.handle_activate_event(event_manager);
                 }
@@ -152,7 +169,7 @@ impl Subscriber for Console {
                 }
                 _ => warn!("Unexpected console event received: {:?}", source),
             }
-            if raise_irq {
+            if raise_irq && self.device_state.needs_notification(&mut self.queues) {
                 self.signal_used_queue().unwrap_or_default();
             }
         } else {
@@ -177,4 +194,8 @@ impl Subscriber for Console {
             ]
         }
     }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
 }
@@ -0,0 +1,237 @@
+use std::io;
+
+use utils::time::LocalTime;
+
+/// The largest partial line held back by line buffering, so a guest never writing a newline
+/// can't grow the buffer unbounded.
+pub const LINE_BUFFER_LIMIT: usize = 4096;
+
+/// How the output of the console is processed before reaching its backend, for logs forwarded to
+/// aggregation systems.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConsoleOutputConfig {
+    /// Delivers the output a whole line at a time. Partial lines, like prompts, are held back
+    /// until completed, or until `LINE_BUFFER_LIMIT` is reached.
+    pub line_buffered: bool,
+    /// Prefixes each line with the time the guest started writing it.
+    pub timestamps: bool,
+    /// Strips the ANSI escape sequences, like colors and cursor movements.
+    pub strip_ansi: bool,
+    /// Splits the lines longer than this number of bytes. Zero is the same as no limit.
+    pub max_line_length: Option<usize>,
+}
+
+impl ConsoleOutputConfig {
+    /// Returns whether the output is left untouched.
+    pub fn is_passthrough(&self) -> bool {
+        !self.line_buffered
+            && !self.timestamps
+            && !self.strip_ansi
+            && self.max_line_length.unwrap_or(0) == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AnsiState {
+    Text,
+    // After ESC.
+    Escape,
+    // Within a Control Sequence Introducer, "ESC [".
+    Csi,
+    // Within an Operating System Command, "ESC ]", ended by BEL or "ESC \".
+    Osc,
+    // After ESC within an Operating System Command.
+    OscEscape,
+}
+
+/// Applies a `ConsoleOutputConfig` to the output written to `inner`.
+pub struct OutputFilter<W: io::Write> {
+    inner: W,
+    config: ConsoleOutputConfig,
+    ansi: AnsiState,
+    pending: Vec<u8>,
+    line_len: usize,
+    line_start: bool,
+}
+
+impl<W: io::Write> OutputFilter<W> {
+    pub fn new(inner: W, config: ConsoleOutputConfig) -> Self {
+        OutputFilter {
+            inner,
+            config,
+            ansi: AnsiState::Text,
+            pending: Vec::new(),
+            line_len: 0,
+            line_start: true,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> io::Result<()> {
+        if !self.config.strip_ansi {
+            return self.push_text(byte);
+        }
+
+        self.ansi = match (self.ansi, byte) {
+            (AnsiState::Text, 0x1b) => AnsiState::Escape,
+            (AnsiState::Text, _) => return self.push_text(byte),
+            (AnsiState::Escape, b'[') => AnsiState::Csi,
+            (AnsiState::Escape, b']') => AnsiState::Osc,
+            // Intermediate bytes, as in the charset selections.
+            (AnsiState::Escape, 0x20..=0x2f) => AnsiState::Escape,
+            (AnsiState::Escape, _) => AnsiState::Text,
+            (AnsiState::Csi, 0x40..=0x7e) => AnsiState::Text,
+            (AnsiState::Csi, _) => AnsiState::Csi,
+            (AnsiState::Osc, 0x07) => AnsiState::Text,
+            (AnsiState::Osc, 0x1b) => AnsiState::OscEscape,
+            (AnsiState::Osc, _) => AnsiState::Osc,
+            (AnsiState::OscEscape, b'\\') => AnsiState::Text,
+            (AnsiState::OscEscape, _) => AnsiState::Osc,
+        };
+        Ok(())
+    }
+
+    fn push_text(&mut self, byte: u8) -> io::Result<()> {
+        // Lines aren't split within a UTF-8 sequence, which may take them slightly over the limit.
+        let max_line_length = self.config.max_line_length.unwrap_or(0);
+        if byte != b'\n'
+            && max_line_length > 0
+            && self.line_len >= max_line_length
+            && (byte & 0xc0) != 0x80
+        {
+            self.push_text(b'\n')?;
+        }
+
+        if self.line_start && self.config.timestamps {
+            self.pending
+                .extend_from_slice(format!("[{}] ", LocalTime::now()).as_bytes());
+        }
+        self.line_start = false;
+        self.pending.push(byte);
+        self.line_len += 1;
+
+        if byte == b'\n' {
+            self.line_len = 0;
+            self.line_start = true;
+            if self.config.line_buffered {
+                self.write_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.inner.write_all(&self.pending)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> io::Write for OutputFilter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.push(*byte)?;
+        }
+        if !self.config.line_buffered || self.pending.len() >= LINE_BUFFER_LIMIT {
+            self.write_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial lines are held back until completed.
+        self.inner.flush()
+    }
+}
+
+impl<W: io::Write> Drop for OutputFilter<W> {
+    fn drop(&mut self) {
+        let _ = self.write_pending();
+        let _ = self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn filter(config: ConsoleOutputConfig, writes: &[&[u8]]) -> (Vec<u8>, Vec<usize>) {
+        let mut filter = OutputFilter::new(Vec::new(), config);
+        let mut delivered = Vec::new();
+        for buf in writes {
+            filter.write_all(buf).unwrap();
+            filter.flush().unwrap();
+            delivered.push(filter.inner.len());
+        }
+        (filter.inner.clone(), delivered)
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let config = ConsoleOutputConfig {
+            strip_ansi: true,
+            ..Default::default()
+        };
+        let (out, _) = filter(
+            config,
+            &[
+                b"\x1b[1;31mred\x1b[0m ",
+                b"\x1b]0;title\x07plain \x1b(Bcharset \x1b]8;;url\x1b\\link",
+            ],
+        );
+        assert_eq!(out, b"red plain charset link");
+    }
+
+    #[test]
+    fn test_line_buffering() {
+        let config = ConsoleOutputConfig {
+            line_buffered: true,
+            ..Default::default()
+        };
+        // Partial lines are held back until completed.
+        let (out, delivered) = filter(config, &[b"hel", b"lo\nwor", b"ld\n", b"prompt"]);
+        assert_eq!(out, b"hello\nworld\n");
+        assert_eq!(delivered, vec![0, 6, 12, 12]);
+    }
+
+    #[test]
+    fn test_max_line_length() {
+        let config = ConsoleOutputConfig {
+            max_line_length: Some(4),
+            ..Default::default()
+        };
+        let (out, _) = filter(config, &[b"abcdefghij\nabcd\n", "ééé".as_bytes()]);
+        assert_eq!(out, "abcd\nefgh\nij\nabcd\néé\né".as_bytes());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let config = ConsoleOutputConfig {
+            timestamps: true,
+            ..Default::default()
+        };
+        let (out, _) = filter(config, &[b"one\ntw", b"o\n"]);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("] one"));
+        assert!(lines[1].ends_with("] two"));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        assert!(ConsoleOutputConfig::default().is_passthrough());
+        assert!(ConsoleOutputConfig {
+            max_line_length: Some(0),
+            ..Default::default()
+        }
+        .is_passthrough());
+        assert!(!ConsoleOutputConfig {
+            strip_ansi: true,
+            ..Default::default()
+        }
+        .is_passthrough());
+    }
+}
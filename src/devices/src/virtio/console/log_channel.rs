@@ -0,0 +1,145 @@
+use std::io;
+
+/// The largest record forwarded by the guest. Larger ones are dropped whole.
+pub const LOG_RECORD_MAX_SIZE: usize = 64 * 1024;
+
+const HEADER_SIZE: usize = 4;
+
+/// Writes `record` to `writer` as a frame of the log channel: its length, as a 32-bit
+/// little-endian integer, followed by the record itself.
+pub fn write_log_record<W: io::Write>(writer: &mut W, record: &[u8]) -> io::Result<()> {
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(record)
+}
+
+/// Splits the output of the log channel back into the records forwarded by the guest, handing
+/// each complete record to `deliver`.
+pub struct LogRecordDecoder<F: FnMut(&[u8])> {
+    deliver: F,
+    max_size: usize,
+    header: [u8; HEADER_SIZE],
+    header_len: usize,
+    // The bytes of the current record not received yet.
+    remaining: usize,
+    // Whether the current record is too large, and is being skipped.
+    skipping: bool,
+    record: Vec<u8>,
+}
+
+impl<F: FnMut(&[u8])> LogRecordDecoder<F> {
+    pub fn new(deliver: F) -> Self {
+        Self::with_max_size(LOG_RECORD_MAX_SIZE, deliver)
+    }
+
+    /// Like `new`, but drops the records larger than `max_size` instead.
+    pub fn with_max_size(max_size: usize, deliver: F) -> Self {
+        LogRecordDecoder {
+            deliver,
+            max_size,
+            header: [0; HEADER_SIZE],
+            header_len: 0,
+            remaining: 0,
+            skipping: false,
+            record: Vec::new(),
+        }
+    }
+
+    fn start_record(&mut self) {
+        let len = u32::from_le_bytes(self.header) as usize;
+        if len > self.max_size {
+            warn!("dropping a record of {} bytes from the guest", len);
+            self.skipping = true;
+        }
+        self.remaining = len;
+        if len == 0 {
+            self.header_len = 0;
+        }
+    }
+
+    fn end_record(&mut self) {
+        if !self.skipping {
+            (self.deliver)(&self.record);
+        }
+        self.record.clear();
+        self.skipping = false;
+        self.header_len = 0;
+    }
+}
+
+impl<F: FnMut(&[u8])> io::Write for LogRecordDecoder<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = buf;
+        while !data.is_empty() {
+            if self.header_len < HEADER_SIZE {
+                let count = std::cmp::min(HEADER_SIZE - self.header_len, data.len());
+                self.header[self.header_len..self.header_len + count]
+                    .copy_from_slice(&data[..count]);
+                self.header_len += count;
+                data = &data[count..];
+                if self.header_len == HEADER_SIZE {
+                    self.start_record();
+                }
+                continue;
+            }
+
+            // Oversized records are skipped rather than discarded along with what follows, so
+            // the stream stays in sync.
+            let count = std::cmp::min(self.remaining, data.len());
+            if !self.skipping {
+                self.record.extend_from_slice(&data[..count]);
+            }
+            self.remaining -= count;
+            data = &data[count..];
+            if self.remaining == 0 {
+                self.end_record();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_decode_records() {
+        let mut stream = Vec::new();
+        write_log_record(&mut stream, b"<14>first").unwrap();
+        write_log_record(&mut stream, b"").unwrap();
+        write_log_record(&mut stream, &vec![b'x'; LOG_RECORD_MAX_SIZE + 1]).unwrap();
+        write_log_record(&mut stream, b"<11>second\nline").unwrap();
+
+        let mut records = Vec::new();
+        {
+            let mut decoder = LogRecordDecoder::new(|record: &[u8]| records.push(record.to_vec()));
+            // Frames split across writes, even within the headers, are put back together.
+            for chunk in stream.chunks(3) {
+                decoder.write_all(chunk).unwrap();
+            }
+        }
+        // Empty records are ignored, and oversized ones dropped without losing the next one.
+        assert_eq!(
+            records,
+            vec![b"<14>first".to_vec(), b"<11>second\nline".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_decode_max_size() {
+        let mut stream = Vec::new();
+        write_log_record(&mut stream, b"12345").unwrap();
+        write_log_record(&mut stream, b"1234").unwrap();
+
+        let mut records = Vec::new();
+        LogRecordDecoder::with_max_size(4, |record: &[u8]| records.push(record.to_vec()))
+            .write_all(&stream)
+            .unwrap();
+        assert_eq!(records, vec![b"1234".to_vec()]);
+    }
+}
@@ -1,8 +1,23 @@
+mod attach;
+mod callback;
 mod device;
 mod event_handler;
+mod filter;
+mod log_channel;
+mod panic_scan;
+mod recorder;
 
+pub use self::attach::{
+    read_frame, write_frame, ConsoleAttach, DEFAULT_SCROLLBACK_SIZE, FRAME_DATA, FRAME_DETACH,
+    FRAME_RESIZE,
+};
+pub use self::callback::{CallbackInput, CallbackOutput, CALLBACK_INPUT_BUFFER_SIZE};
 pub use self::defs::uapi::VIRTIO_ID_CONSOLE as TYPE_CONSOLE;
 pub use self::device::Console;
+pub use self::filter::{ConsoleOutputConfig, OutputFilter, LINE_BUFFER_LIMIT};
+pub use self::log_channel::{write_log_record, LogRecordDecoder, LOG_RECORD_MAX_SIZE};
+pub use self::panic_scan::{GuestPanic, GuestPanicKind, PanicScanner};
+pub use self::recorder::{SessionEvent, SessionRecorder};
 
 mod defs {
     pub const CONSOLE_DEV_ID: &str = "virtio_console";
@@ -0,0 +1,176 @@
+//! Detection of the guest kernel panicking or oopsing from the output of its console, for the
+//! guests with no other way of reporting it to the host.
+//!
+//! The kernel prints its panics and oopses to its console, which is the virtio console under the
+//! default command line, so their signatures are matched against each line of its output. This
+//! is a heuristic: a workload printing the same signatures is taken for the kernel.
+
+use std::io;
+
+use super::filter::LINE_BUFFER_LIMIT;
+
+/// The signatures of the lines printed by the kernel as it panics.
+const PANIC_SIGNATURES: &[&str] = &["Kernel panic - not syncing"];
+
+/// The signatures of the lines starting the report of an oops, on x86_64 and aarch64.
+const OOPS_SIGNATURES: &[&str] = &[
+    "Oops:",
+    "BUG: unable to handle",
+    "BUG: kernel NULL pointer dereference",
+    "general protection fault",
+    "Unable to handle kernel",
+    "Internal error:",
+];
+
+/// The line ending the report of an oops, after which another oops is reported again.
+const END_OF_TRACE: &str = "---[ end trace";
+
+/// How the guest kernel failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuestPanicKind {
+    /// The kernel panicked, and the guest won't make any progress.
+    Panic,
+    /// The kernel oopsed, killing the task at fault, and may keep running.
+    Oops,
+}
+
+/// The guest kernel panicking or oopsing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestPanic {
+    pub kind: GuestPanicKind,
+    /// The line the failure was detected from, as printed by the kernel.
+    pub message: String,
+}
+
+/// Scans the lines of the output written to `inner` for the kernel panicking or oopsing, invoking
+/// `callback` once per panic, and once per oops report.
+pub struct PanicScanner<W: io::Write> {
+    inner: W,
+    callback: Box<dyn Fn(&GuestPanic) + Send>,
+    line: Vec<u8>,
+    panicked: bool,
+    in_oops: bool,
+}
+
+impl<W: io::Write> PanicScanner<W> {
+    pub fn new(inner: W, callback: Box<dyn Fn(&GuestPanic) + Send>) -> Self {
+        PanicScanner {
+            inner,
+            callback,
+            line: Vec::new(),
+            panicked: false,
+            in_oops: false,
+        }
+    }
+
+    fn scan_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+
+        let kind = if PANIC_SIGNATURES.iter().any(|s| line.contains(s)) {
+            // The kernel repeats the panic message as it ends its report.
+            if self.panicked {
+                None
+            } else {
+                self.panicked = true;
+                Some(GuestPanicKind::Panic)
+            }
+        } else if line.contains(END_OF_TRACE) {
+            self.in_oops = false;
+            None
+        } else if !self.in_oops && OOPS_SIGNATURES.iter().any(|s| line.contains(s)) {
+            // The report of a single oops may match several signatures.
+            self.in_oops = true;
+            Some(GuestPanicKind::Oops)
+        } else {
+            None
+        };
+
+        if let Some(kind) = kind {
+            (self.callback)(&GuestPanic {
+                kind,
+                message: line.trim_start().to_string(),
+            });
+        }
+        self.line.clear();
+    }
+
+    fn scan(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.scan_line();
+            } else if self.line.len() < LINE_BUFFER_LIMIT {
+                self.line.push(byte);
+            }
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for PanicScanner<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.scan(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_panic_scanner() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let mut output = Vec::new();
+        let mut scanner = PanicScanner::new(
+            &mut output,
+            Box::new(move |panic: &GuestPanic| sink.lock().unwrap().push(panic.clone())),
+        );
+
+        scanner.write_all(b"$ echo Oops\r\nOops\r\n").unwrap();
+        scanner
+            .write_all(b"[    2.1] BUG: kernel NULL pointer dereference, address: 0\r\n")
+            .unwrap();
+        // A line split across writes is scanned whole.
+        scanner.write_all(b"[    2.1] Oo").unwrap();
+        scanner.write_all(b"ps: 0002 [#1] SMP\r\n").unwrap();
+        scanner
+            .write_all(b"[    2.2] ---[ end trace 0 ]---\r\n")
+            .unwrap();
+        scanner
+            .write_all(b"[    3.0] Kernel panic - not syncing: Attempted to kill init!\r\n")
+            .unwrap();
+        scanner
+            .write_all(
+                b"[    3.0] ---[ end Kernel panic - not syncing: Attempted to kill init! ]---\r\n",
+            )
+            .unwrap();
+        drop(scanner);
+
+        // The output is passed through untouched.
+        assert!(output.starts_with(b"$ echo Oops\r\n"));
+        assert!(output.ends_with(b"init! ]---\r\n"));
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![
+                GuestPanic {
+                    kind: GuestPanicKind::Oops,
+                    message: "[    2.1] BUG: kernel NULL pointer dereference, address: 0"
+                        .to_string(),
+                },
+                GuestPanic {
+                    kind: GuestPanicKind::Panic,
+                    message: "[    3.0] Kernel panic - not syncing: Attempted to kill init!"
+                        .to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt::Write as _;
+use std::io;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// The size recorded when the console has none, as when its backend isn't a terminal.
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+
+/// The kinds of events of an asciicast.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionEvent {
+    /// Data written by the guest.
+    Output,
+    /// Data read by the guest.
+    Input,
+}
+
+impl SessionEvent {
+    fn code(self) -> &'static str {
+        match self {
+            SessionEvent::Output => "o",
+            SessionEvent::Input => "i",
+        }
+    }
+}
+
+struct RecorderState {
+    writer: Box<dyn io::Write + Send>,
+    started: Option<Instant>,
+    // The trailing bytes of a UTF-8 sequence split across writes, for each kind of event.
+    pending: [Vec<u8>; 2],
+    failed: bool,
+}
+
+impl RecorderState {
+    fn write_line(&mut self, line: &str) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self
+            .writer
+            .write_all(line.as_bytes())
+            .and_then(|_| self.writer.flush())
+        {
+            // Recording is best effort, and mustn't get in the way of the console.
+            warn!("console: stopping the session recording: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn write_event(&mut self, code: &str, data: &str) {
+        let elapsed = self
+            .started
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or(0.0);
+        let mut line = format!("[{:.6}, \"{}\", \"", elapsed, code);
+        escape_json(&mut line, data);
+        line.push_str("\"]\n");
+        self.write_line(&line);
+    }
+}
+
+/// Records the session of a console to an asciicast v2 file, as played by asciinema: the data
+/// written and read by the guest, timestamped, along with the changes of the size of the console.
+///
+/// Recording can be paused and resumed while the microVM runs. The time keeps running while
+/// paused, so the recording stays in sync with the session.
+#[derive(Clone)]
+pub struct SessionRecorder {
+    state: Arc<Mutex<RecorderState>>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl SessionRecorder {
+    /// Creates a recorder writing to `writer`, recording right away if `enabled`.
+    pub fn new(writer: Box<dyn io::Write + Send>, enabled: bool) -> Self {
+        SessionRecorder {
+            state: Arc::new(Mutex::new(RecorderState {
+                writer,
+                started: None,
+                pending: [Vec::new(), Vec::new()],
+                failed: false,
+            })),
+            enabled: Arc::new(AtomicBool::new(enabled)),
+        }
+    }
+
+    /// Writes the header of the recording, starting its clock. Only the first call has an effect.
+    pub fn start(&self, cols: u16, rows: u16) {
+        let mut state = self.state.lock().unwrap();
+        if state.started.is_some() {
+            return;
+        }
+        state.started = Some(Instant::now());
+
+        let (cols, rows) = match (cols, rows) {
+            (0, _) | (_, 0) => (DEFAULT_COLS, DEFAULT_ROWS),
+            size => size,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+        state.write_line(&format!(
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}\n",
+            cols, rows, timestamp
+        ));
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Records `data`, written or read by the guest.
+    pub fn record(&self, event: SessionEvent, data: &[u8]) {
+        if data.is_empty() || !self.is_enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let mut bytes = std::mem::take(&mut state.pending[event as usize]);
+        bytes.extend_from_slice(data);
+        let (text, pending) = decode_utf8(&bytes);
+        state.pending[event as usize] = pending.to_vec();
+        if !text.is_empty() {
+            state.write_event(event.code(), &text);
+        }
+    }
+
+    /// Records the console being resized to `cols` by `rows`.
+    pub fn record_resize(&self, cols: u16, rows: u16) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .write_event("r", &format!("{}x{}", cols, rows));
+    }
+}
+
+/// Decodes the UTF-8 text in `bytes`, replacing the invalid sequences, and returns it along with
+/// the trailing bytes of a sequence that may be completed by the next ones.
+fn decode_utf8(mut bytes: &[u8]) -> (String, &[u8]) {
+    let mut text = String::new();
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return (text, &[]);
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                // Safe because the bytes up to `valid_up_to` were checked.
+                text.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        bytes = &rest[len..];
+                    }
+                    None => return (text, rest),
+                }
+            }
+        }
+    }
+}
+
+fn escape_json(out: &mut String, data: &str) {
+    for c in data.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Passes the output of the guest through to `inner`, recording it as it goes.
+pub(crate) struct RecordingOutput {
+    inner: Box<dyn io::Write + Send>,
+    recorder: SessionRecorder,
+}
+
+impl RecordingOutput {
+    pub(crate) fn new(inner: Box<dyn io::Write + Send>, recorder: SessionRecorder) -> Self {
+        RecordingOutput { inner, recorder }
+    }
+}
+
+impl io::Write for RecordingOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.recorder.record(SessionEvent::Output, &buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_session_recorder() {
+        let buffer = SharedBuffer::default();
+        let recorder = SessionRecorder::new(Box::new(buffer.clone()), true);
+        recorder.start(0, 0);
+        recorder.start(120, 40);
+
+        let mut output = RecordingOutput::new(Box::new(io::sink()), recorder.clone());
+        output.write_all(b"$ \"ls\"\r\n\x1b[0m").unwrap();
+        // A sequence split across writes is recorded whole, and an invalid one replaced.
+        output.write_all(b"\xc3").unwrap();
+        output.write_all(b"\xa9\xff").unwrap();
+        recorder.record(SessionEvent::Input, b"q");
+        recorder.record_resize(100, 30);
+
+        recorder.set_enabled(false);
+        recorder.record(SessionEvent::Input, b"secret");
+        recorder.set_enabled(true);
+
+        let recording = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = recording.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("{\"version\": 2, \"width\": 80, \"height\": 24, "));
+        let events: Vec<&str> = lines[1..]
+            .iter()
+            .map(|line| &line[line.find(',').unwrap() + 2..])
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "\"o\", \"$ \\\"ls\\\"\\r\\n\\u001b[0m\"]",
+                "\"o\", \"\u{e9}\u{fffd}\"]",
+                "\"i\", \"q\"]",
+                "\"r\", \"100x30\"]",
+            ]
+        );
+    }
+}
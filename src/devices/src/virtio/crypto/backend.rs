@@ -0,0 +1,508 @@
+//! Symmetric ciphers of the host backing the crypto device: the kernel crypto API, through
+//! AF_ALG sockets, on Linux, and CommonCrypto on macOS.
+
+use std::io;
+
+use super::defs::uapi;
+
+pub const AES_BLOCK_SIZE: usize = 16;
+
+/// The ciphers the crypto device can offer to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherAlgo {
+    AesEcb,
+    AesCbc,
+    AesCtr,
+}
+
+impl CipherAlgo {
+    pub const ALL: [CipherAlgo; 3] = [CipherAlgo::AesEcb, CipherAlgo::AesCbc, CipherAlgo::AesCtr];
+
+    /// Returns the cipher designated by a VIRTIO_CRYPTO_CIPHER_* value.
+    pub fn from_virtio(algo: u32) -> Option<Self> {
+        match algo {
+            uapi::VIRTIO_CRYPTO_CIPHER_AES_ECB => Some(CipherAlgo::AesEcb),
+            uapi::VIRTIO_CRYPTO_CIPHER_AES_CBC => Some(CipherAlgo::AesCbc),
+            uapi::VIRTIO_CRYPTO_CIPHER_AES_CTR => Some(CipherAlgo::AesCtr),
+            _ => None,
+        }
+    }
+
+    /// Returns the VIRTIO_CRYPTO_CIPHER_* value of the cipher.
+    pub fn virtio_id(self) -> u32 {
+        match self {
+            CipherAlgo::AesEcb => uapi::VIRTIO_CRYPTO_CIPHER_AES_ECB,
+            CipherAlgo::AesCbc => uapi::VIRTIO_CRYPTO_CIPHER_AES_CBC,
+            CipherAlgo::AesCtr => uapi::VIRTIO_CRYPTO_CIPHER_AES_CTR,
+        }
+    }
+
+    pub fn iv_len(self) -> usize {
+        match self {
+            CipherAlgo::AesEcb => 0,
+            CipherAlgo::AesCbc | CipherAlgo::AesCtr => AES_BLOCK_SIZE,
+        }
+    }
+
+    /// Whether the data must be a multiple of the block size, as CTR turns AES into a stream
+    /// cipher.
+    fn block_aligned(self) -> bool {
+        self != CipherAlgo::AesCtr
+    }
+
+    /// Returns the IV continuing the processing of the data following `src`, whose result is
+    /// `dst`, so the data can be handed to the backend in several chunks.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn next_iv(self, iv: &[u8], encrypt: bool, src: &[u8], dst: &[u8]) -> Vec<u8> {
+        match self {
+            CipherAlgo::AesEcb => Vec::new(),
+            // The IV of a block is the previous ciphertext block.
+            CipherAlgo::AesCbc => {
+                let ciphertext = if encrypt { dst } else { src };
+                ciphertext[ciphertext.len() - AES_BLOCK_SIZE..].to_vec()
+            }
+            // The IV is a big-endian counter, incremented for every block.
+            CipherAlgo::AesCtr => {
+                let mut counter = [0u8; AES_BLOCK_SIZE];
+                counter.copy_from_slice(iv);
+                let counter =
+                    u128::from_be_bytes(counter).wrapping_add((src.len() / AES_BLOCK_SIZE) as u128);
+                counter.to_be_bytes().to_vec()
+            }
+        }
+    }
+}
+
+/// A cipher keyed for a single direction, as sessions are created by the guest.
+pub struct CipherSession {
+    algo: CipherAlgo,
+    encrypt: bool,
+    cipher: platform::Cipher,
+}
+
+impl CipherSession {
+    pub fn new(algo: CipherAlgo, key: &[u8], encrypt: bool) -> io::Result<Self> {
+        if ![16, 24, 32].contains(&key.len()) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(CipherSession {
+            algo,
+            encrypt,
+            cipher: platform::Cipher::new(algo, key)?,
+        })
+    }
+
+    pub fn encrypt(&self) -> bool {
+        self.encrypt
+    }
+
+    /// Encrypts or decrypts `src` into `dst`, which must be of the same length. Fails with
+    /// EINVAL if the request doesn't suit the cipher.
+    pub fn process(&mut self, iv: &[u8], src: &[u8], dst: &mut [u8]) -> io::Result<()> {
+        if iv.len() != self.algo.iv_len()
+            || src.len() != dst.len()
+            || (self.algo.block_aligned() && src.len() % AES_BLOCK_SIZE != 0)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if src.is_empty() {
+            return Ok(());
+        }
+        self.cipher.process(self.algo, self.encrypt, iv, src, dst)
+    }
+}
+
+/// Tells if the host is able to provide `algo`.
+pub fn is_supported(algo: CipherAlgo) -> bool {
+    platform::is_supported(algo)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::ptr;
+
+    use super::CipherAlgo;
+
+    /// The data is handed to the kernel in chunks, as AF_ALG limits the size of a request.
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    fn kernel_name(algo: CipherAlgo) -> &'static str {
+        match algo {
+            CipherAlgo::AesEcb => "ecb(aes)",
+            CipherAlgo::AesCbc => "cbc(aes)",
+            CipherAlgo::AesCtr => "ctr(aes)",
+        }
+    }
+
+    pub struct Cipher {
+        // The transform socket, which must outlive the operation socket.
+        _tfm: File,
+        op: File,
+    }
+
+    impl Cipher {
+        pub fn new(algo: CipherAlgo, key: &[u8]) -> io::Result<Self> {
+            let fd =
+                unsafe { libc::socket(libc::AF_ALG, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safe because we just created the socket, and nothing else owns it.
+            let tfm = unsafe { File::from_raw_fd(fd) };
+
+            // Safe because sockaddr_alg is plain data, for which zero is a valid value.
+            let mut addr: libc::sockaddr_alg = unsafe { mem::zeroed() };
+            addr.salg_family = libc::AF_ALG as u16;
+            for (dst, src) in addr.salg_type.iter_mut().zip(b"skcipher") {
+                *dst = *src;
+            }
+            for (dst, src) in addr.salg_name.iter_mut().zip(kernel_name(algo).as_bytes()) {
+                *dst = *src;
+            }
+            // Safe because the address is a valid sockaddr_alg, and its size is passed along.
+            let ret = unsafe {
+                libc::bind(
+                    tfm.as_raw_fd(),
+                    &addr as *const libc::sockaddr_alg as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_alg>() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Safe because the key is valid for its length.
+            let ret = unsafe {
+                libc::setsockopt(
+                    tfm.as_raw_fd(),
+                    libc::SOL_ALG,
+                    libc::ALG_SET_KEY,
+                    key.as_ptr() as *const libc::c_void,
+                    key.len() as libc::socklen_t,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let fd = unsafe {
+                libc::accept4(
+                    tfm.as_raw_fd(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    libc::SOCK_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Safe because we just accepted the socket, and nothing else owns it.
+            let op = unsafe { File::from_raw_fd(fd) };
+
+            Ok(Cipher { _tfm: tfm, op })
+        }
+
+        fn process_chunk(
+            &mut self,
+            encrypt: bool,
+            iv: &[u8],
+            src: &[u8],
+            dst: &mut [u8],
+        ) -> io::Result<()> {
+            let op = if encrypt {
+                libc::ALG_OP_ENCRYPT
+            } else {
+                libc::ALG_OP_DECRYPT
+            } as u32;
+            let op_len = mem::size_of::<u32>();
+            // The IV is passed as a struct af_alg_iv, its length followed by its bytes.
+            let iv_len = mem::size_of::<u32>() + iv.len();
+
+            // Safe because CMSG_SPACE only computes a size.
+            let mut control_len = unsafe { libc::CMSG_SPACE(op_len as u32) } as usize;
+            if !iv.is_empty() {
+                control_len += unsafe { libc::CMSG_SPACE(iv_len as u32) } as usize;
+            }
+            // Backed by u64s, so the control messages are properly aligned.
+            let mut control = vec![0u64; (control_len + 7) / 8];
+
+            let mut iov = libc::iovec {
+                iov_base: src.as_ptr() as *mut libc::c_void,
+                iov_len: src.len(),
+            };
+            // Safe because msghdr is plain data, for which zero is a valid value.
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control_len as _;
+
+            // Safe because the control buffer has room for both messages, as computed with
+            // CMSG_SPACE, and the CMSG_* macros don't go past msg_controllen.
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_ALG;
+                (*cmsg).cmsg_type = libc::ALG_SET_OP;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(op_len as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u32, op);
+
+                if !iv.is_empty() {
+                    let cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                    (*cmsg).cmsg_level = libc::SOL_ALG;
+                    (*cmsg).cmsg_type = libc::ALG_SET_IV;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(iv_len as u32) as _;
+                    let data = libc::CMSG_DATA(cmsg);
+                    ptr::write_unaligned(data as *mut u32, iv.len() as u32);
+                    ptr::copy_nonoverlapping(
+                        iv.as_ptr(),
+                        data.add(mem::size_of::<u32>()),
+                        iv.len(),
+                    );
+                }
+            }
+
+            // Safe because msg only points to buffers that outlive the call.
+            let sent = unsafe { libc::sendmsg(self.op.as_raw_fd(), &msg, 0) };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if sent as usize != src.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "partial AF_ALG request",
+                ));
+            }
+            self.op.read_exact(dst)
+        }
+
+        pub fn process(
+            &mut self,
+            algo: CipherAlgo,
+            encrypt: bool,
+            iv: &[u8],
+            src: &[u8],
+            dst: &mut [u8],
+        ) -> io::Result<()> {
+            let mut iv = iv.to_vec();
+            for (src, dst) in src.chunks(CHUNK_SIZE).zip(dst.chunks_mut(CHUNK_SIZE)) {
+                self.process_chunk(encrypt, &iv, src, dst)?;
+                iv = algo.next_iv(&iv, encrypt, src, dst);
+            }
+            Ok(())
+        }
+    }
+
+    pub fn is_supported(algo: CipherAlgo) -> bool {
+        Cipher::new(algo, &[0u8; 16]).is_ok()
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use std::io;
+    use std::ptr;
+
+    use super::CipherAlgo;
+
+    type CCCryptorRef = *mut libc::c_void;
+
+    const K_CC_ENCRYPT: u32 = 0;
+    const K_CC_DECRYPT: u32 = 1;
+    const K_CC_ALGORITHM_AES: u32 = 0;
+    const K_CC_MODE_ECB: u32 = 1;
+    const K_CC_MODE_CBC: u32 = 2;
+    const K_CC_MODE_CTR: u32 = 4;
+    const CC_NO_PADDING: u32 = 0;
+    const K_CC_MODE_OPTION_CTR_BE: u32 = 2;
+
+    #[link(name = "System", kind = "dylib")]
+    extern "C" {
+        fn CCCryptorCreateWithMode(
+            op: u32,
+            mode: u32,
+            alg: u32,
+            padding: u32,
+            iv: *const libc::c_void,
+            key: *const libc::c_void,
+            key_length: usize,
+            tweak: *const libc::c_void,
+            tweak_length: usize,
+            num_rounds: i32,
+            options: u32,
+            cryptor_ref: *mut CCCryptorRef,
+        ) -> i32;
+        fn CCCryptorUpdate(
+            cryptor_ref: CCCryptorRef,
+            data_in: *const libc::c_void,
+            data_in_length: usize,
+            data_out: *mut libc::c_void,
+            data_out_available: usize,
+            data_out_moved: *mut usize,
+        ) -> i32;
+        fn CCCryptorRelease(cryptor_ref: CCCryptorRef) -> i32;
+    }
+
+    fn cc_error(status: i32) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("CommonCrypto error {}", status),
+        )
+    }
+
+    /// CommonCrypto cryptors can't be rewound to a new IV in every mode, so a cryptor is created
+    /// for every request from the key.
+    pub struct Cipher {
+        key: Vec<u8>,
+    }
+
+    impl Cipher {
+        pub fn new(_algo: CipherAlgo, key: &[u8]) -> io::Result<Self> {
+            Ok(Cipher { key: key.to_vec() })
+        }
+
+        pub fn process(
+            &mut self,
+            algo: CipherAlgo,
+            encrypt: bool,
+            iv: &[u8],
+            src: &[u8],
+            dst: &mut [u8],
+        ) -> io::Result<()> {
+            let (mode, options) = match algo {
+                CipherAlgo::AesEcb => (K_CC_MODE_ECB, 0),
+                CipherAlgo::AesCbc => (K_CC_MODE_CBC, 0),
+                CipherAlgo::AesCtr => (K_CC_MODE_CTR, K_CC_MODE_OPTION_CTR_BE),
+            };
+            let op = if encrypt { K_CC_ENCRYPT } else { K_CC_DECRYPT };
+            let iv_ptr = if iv.is_empty() {
+                ptr::null()
+            } else {
+                iv.as_ptr() as *const libc::c_void
+            };
+
+            let mut cryptor: CCCryptorRef = ptr::null_mut();
+            // Safe because the key and the IV are valid for their lengths.
+            let status = unsafe {
+                CCCryptorCreateWithMode(
+                    op,
+                    mode,
+                    K_CC_ALGORITHM_AES,
+                    CC_NO_PADDING,
+                    iv_ptr,
+                    self.key.as_ptr() as *const libc::c_void,
+                    self.key.len(),
+                    ptr::null(),
+                    0,
+                    0,
+                    options,
+                    &mut cryptor,
+                )
+            };
+            if status != 0 {
+                return Err(cc_error(status));
+            }
+
+            let mut moved = 0;
+            // Safe because the cryptor is valid, and the buffers are valid for their lengths.
+            let status = unsafe {
+                let status = CCCryptorUpdate(
+                    cryptor,
+                    src.as_ptr() as *const libc::c_void,
+                    src.len(),
+                    dst.as_mut_ptr() as *mut libc::c_void,
+                    dst.len(),
+                    &mut moved,
+                );
+                CCCryptorRelease(cryptor);
+                status
+            };
+            if status != 0 {
+                return Err(cc_error(status));
+            }
+            if moved != src.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "partial CommonCrypto request",
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Cipher {
+        fn drop(&mut self) {
+            for byte in self.key.iter_mut() {
+                // Safe because the byte is a valid reference. The volatile write keeps the
+                // compiler from eliding the wipe of the key.
+                unsafe { ptr::write_volatile(byte, 0) };
+            }
+        }
+    }
+
+    pub fn is_supported(_algo: CipherAlgo) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_iv() {
+        let iv = [0xffu8; AES_BLOCK_SIZE];
+        let src = [1u8; 2 * AES_BLOCK_SIZE];
+        let dst = [2u8; 2 * AES_BLOCK_SIZE];
+
+        assert!(CipherAlgo::AesEcb.next_iv(&[], true, &src, &dst).is_empty());
+        assert_eq!(
+            CipherAlgo::AesCbc.next_iv(&iv, true, &src, &dst),
+            vec![2u8; AES_BLOCK_SIZE]
+        );
+        assert_eq!(
+            CipherAlgo::AesCbc.next_iv(&iv, false, &src, &dst),
+            vec![1u8; AES_BLOCK_SIZE]
+        );
+        // The counter wraps around.
+        let mut expected = vec![0u8; AES_BLOCK_SIZE];
+        expected[AES_BLOCK_SIZE - 1] = 1;
+        assert_eq!(CipherAlgo::AesCtr.next_iv(&iv, true, &src, &dst), expected);
+    }
+
+    #[test]
+    fn test_cipher_session() {
+        if !is_supported(CipherAlgo::AesEcb) {
+            return;
+        }
+
+        // The example vector of FIPS-197, appendix C.1.
+        let key: Vec<u8> = (0..16).collect();
+        let plaintext = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        let ciphertext = [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+            0xc5, 0x5a,
+        ];
+
+        let mut out = [0u8; 16];
+        let mut session = CipherSession::new(CipherAlgo::AesEcb, &key, true).unwrap();
+        session.process(&[], &plaintext, &mut out).unwrap();
+        assert_eq!(out, ciphertext);
+
+        let mut session = CipherSession::new(CipherAlgo::AesEcb, &key, false).unwrap();
+        session.process(&[], &ciphertext, &mut out).unwrap();
+        assert_eq!(out, plaintext);
+
+        // Unaligned data, and keys of the wrong size, are rejected.
+        assert!(session
+            .process(&[], &plaintext[..15], &mut out[..15])
+            .is_err());
+        assert!(CipherSession::new(CipherAlgo::AesEcb, &key[..15], true).is_err());
+    }
+}
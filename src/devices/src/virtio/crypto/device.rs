@@ -0,0 +1,566 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use logger::METRICS;
+use utils::eventfd::EventFd;
+use vm_memory::{ByteValued, GuestMemoryMmap};
+
+use super::super::{
+    ActivateError, ActivateResult, CryptoError, DeviceState, Queue as VirtQueue, VirtioDevice,
+    VIRTIO_MMIO_INT_VRING, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
+use super::backend::{self, CipherAlgo, CipherSession, AES_BLOCK_SIZE};
+use super::{defs, defs::uapi};
+use crate::legacy::Gic;
+use crate::virtio::fs::descriptor_utils::{Reader, Writer};
+use crate::Error as DeviceError;
+
+// Data queue.
+pub(crate) const DATAQ_INDEX: usize = 0;
+// Control queue.
+pub(crate) const CTRLQ_INDEX: usize = 1;
+
+// Supported features.
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioCryptoConfig {
+    status: u32,
+    max_dataqueues: u32,
+    /* Bitmask of the supported services. */
+    crypto_services: u32,
+    /* Bitmasks of the supported algorithms of each service. */
+    cipher_algo_l: u32,
+    cipher_algo_h: u32,
+    hash_algo: u32,
+    mac_algo_l: u32,
+    mac_algo_h: u32,
+    aead_algo: u32,
+    max_cipher_key_len: u32,
+    max_auth_key_len: u32,
+    akcipher_algo: u32,
+    /* Largest buffer of a data request. */
+    max_size: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioCryptoConfig {}
+
+/// The header shared by the requests of the control queue.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct CtrlHeader {
+    opcode: u32,
+    algo: u32,
+    flag: u32,
+    queue_id: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for CtrlHeader {}
+
+/// A request of the control queue. The layout of the body depends on the opcode.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct CtrlRequest {
+    header: CtrlHeader,
+    body: [u8; 56],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for CtrlRequest {}
+
+/// The reply to the creation of a session.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct SessionInput {
+    session_id: u64,
+    status: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for SessionInput {}
+
+/// The header shared by the requests of the data queue.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct DataHeader {
+    opcode: u32,
+    algo: u32,
+    session_id: u64,
+    flag: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for DataHeader {}
+
+/// A request of the data queue. The layout of the body depends on the opcode.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct DataRequest {
+    header: DataHeader,
+    body: [u8; 48],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for DataRequest {}
+
+/// Reads the little-endian u32 at `offset` in the body of a request.
+fn body_u32(body: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(body[offset..offset + 4].try_into().unwrap())
+}
+
+fn status_of(e: &io::Error) -> u8 {
+    match e.raw_os_error() {
+        Some(libc::EINVAL) => uapi::VIRTIO_CRYPTO_BADMSG,
+        _ => uapi::VIRTIO_CRYPTO_ERR,
+    }
+}
+
+pub struct Crypto {
+    pub(crate) queues: Vec<VirtQueue>,
+    pub(crate) queue_events: Vec<EventFd>,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) interrupt_status: Arc<AtomicUsize>,
+    pub(crate) interrupt_evt: EventFd,
+    pub(crate) activate_evt: EventFd,
+    pub(crate) device_state: DeviceState,
+    config: VirtioCryptoConfig,
+    algos: Vec<CipherAlgo>,
+    sessions: HashMap<u64, CipherSession>,
+    next_session_id: u64,
+    intc: Option<Arc<Mutex<Gic>>>,
+    irq_line: Option<u32>,
+}
+
+impl Crypto {
+    pub(crate) fn with_queues(queues: Vec<VirtQueue>) -> super::Result<Crypto> {
+        let mut queue_events = Vec::new();
+        for _ in 0..queues.len() {
+            queue_events
+                .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(CryptoError::EventFd)?);
+        }
+
+        // Only the ciphers the host is able to provide are advertised to the guest.
+        let algos: Vec<CipherAlgo> = CipherAlgo::ALL
+            .iter()
+            .copied()
+            .filter(|algo| backend::is_supported(*algo))
+            .collect();
+        let cipher_algo_l = algos
+            .iter()
+            .fold(0, |mask, algo| mask | 1 << algo.virtio_id());
+
+        let config = VirtioCryptoConfig {
+            status: uapi::VIRTIO_CRYPTO_S_HW_READY,
+            max_dataqueues: 1,
+            crypto_services: 1 << uapi::VIRTIO_CRYPTO_SERVICE_CIPHER,
+            cipher_algo_l,
+            max_cipher_key_len: 32,
+            max_size: defs::MAX_DATA_SIZE,
+            ..Default::default()
+        };
+
+        Ok(Crypto {
+            queues,
+            queue_events,
+            avail_features: AVAIL_FEATURES,
+            acked_features: 0,
+            interrupt_status: Arc::new(AtomicUsize::new(0)),
+            interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(CryptoError::EventFd)?,
+            activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(CryptoError::EventFd)?,
+            device_state: DeviceState::Inactive,
+            config,
+            algos,
+            sessions: HashMap::new(),
+            next_session_id: 0,
+            intc: None,
+            irq_line: None,
+        })
+    }
+
+    pub fn new() -> super::Result<Crypto> {
+        let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
+            .iter()
+            .map(|&max_size| VirtQueue::new(max_size))
+            .collect();
+        Self::with_queues(queues)
+    }
+
+    pub fn id(&self) -> &str {
+        defs::CRYPTO_DEV_ID
+    }
+
+    /// Returns the ciphers offered to the guest.
+    pub fn algos(&self) -> &[CipherAlgo] {
+        &self.algos
+    }
+
+    pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
+        self.intc = Some(intc);
+    }
+
+    pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        debug!("crypto: raising IRQ");
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
+        if let Some(intc) = &self.intc {
+            intc.lock().unwrap().set_irq(self.irq_line.unwrap());
+            Ok(())
+        } else {
+            self.interrupt_evt.write(1).map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+        }
+    }
+
+    fn create_session(&mut self, req: &CtrlRequest, reader: &mut Reader) -> (u64, u8) {
+        // The body starts with the parameters of the cipher, and ends with the type of the
+        // symmetric operation.
+        let algo = body_u32(&req.body, 0);
+        let key_len = body_u32(&req.body, 4);
+        let op = body_u32(&req.body, 8);
+        let op_type = body_u32(&req.body, 48);
+
+        let algo = match CipherAlgo::from_virtio(algo) {
+            Some(algo) if op_type == uapi::VIRTIO_CRYPTO_SYM_OP_CIPHER => algo,
+            _ => return (0, uapi::VIRTIO_CRYPTO_NOTSUPP),
+        };
+        if !self.algos.contains(&algo) {
+            return (0, uapi::VIRTIO_CRYPTO_NOTSUPP);
+        }
+        let encrypt = match op {
+            uapi::VIRTIO_CRYPTO_OP_ENCRYPT => true,
+            uapi::VIRTIO_CRYPTO_OP_DECRYPT => false,
+            _ => return (0, uapi::VIRTIO_CRYPTO_BADMSG),
+        };
+        if key_len > defs::MAX_KEY_LEN {
+            return (0, uapi::VIRTIO_CRYPTO_BADMSG);
+        }
+        if self.sessions.len() >= defs::MAX_SESSIONS {
+            return (0, uapi::VIRTIO_CRYPTO_ERR);
+        }
+
+        let mut key = vec![0u8; key_len as usize];
+        if reader.read_exact(&mut key).is_err() {
+            return (0, uapi::VIRTIO_CRYPTO_BADMSG);
+        }
+        let session = CipherSession::new(algo, &key, encrypt);
+        for byte in key.iter_mut() {
+            *byte = 0;
+        }
+
+        match session {
+            Ok(session) => {
+                let session_id = self.next_session_id;
+                self.next_session_id += 1;
+                self.sessions.insert(session_id, session);
+                debug!("crypto: created {:?} session {}", algo, session_id);
+                (session_id, uapi::VIRTIO_CRYPTO_OK)
+            }
+            Err(e) => {
+                error!("crypto: unable to create {:?} session: {}", algo, e);
+                (0, status_of(&e))
+            }
+        }
+    }
+
+    /// Handles a request of the control queue, returning the number of bytes written back.
+    fn handle_ctrl_request(&mut self, mut reader: Reader, mut writer: Writer) -> usize {
+        let req: CtrlRequest = match reader.read_obj() {
+            Ok(req) => req,
+            Err(e) => {
+                error!("crypto: invalid control request: {}", e);
+                METRICS.crypto.failures.inc();
+                return 0;
+            }
+        };
+
+        // Creating a session is answered with its ID, destroying it with a single status byte.
+        let ret = match req.header.opcode {
+            uapi::VIRTIO_CRYPTO_CIPHER_CREATE_SESSION => {
+                let (session_id, status) = self.create_session(&req, &mut reader);
+                writer
+                    .write_obj(SessionInput {
+                        session_id,
+                        status: status as u32,
+                        padding: 0,
+                    })
+                    .map(|_| status)
+            }
+            uapi::VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION => {
+                let session_id = u64::from_le_bytes(req.body[..8].try_into().unwrap());
+                let status = match self.sessions.remove(&session_id) {
+                    Some(_) => uapi::VIRTIO_CRYPTO_OK,
+                    None => uapi::VIRTIO_CRYPTO_INVSESS,
+                };
+                writer.write_obj(status).map(|_| status)
+            }
+            // Sessions of the other services, which aren't offered.
+            opcode if opcode & 0xff == 0x02 => writer
+                .write_obj(SessionInput {
+                    session_id: 0,
+                    status: uapi::VIRTIO_CRYPTO_NOTSUPP as u32,
+                    padding: 0,
+                })
+                .map(|_| uapi::VIRTIO_CRYPTO_NOTSUPP),
+            _ => writer
+                .write_obj(uapi::VIRTIO_CRYPTO_NOTSUPP)
+                .map(|_| uapi::VIRTIO_CRYPTO_NOTSUPP),
+        };
+
+        match ret {
+            Ok(uapi::VIRTIO_CRYPTO_OK) => (),
+            Ok(_) => METRICS.crypto.failures.inc(),
+            Err(e) => {
+                error!("crypto: unable to reply to control request: {}", e);
+                METRICS.crypto.failures.inc();
+            }
+        }
+        writer.bytes_written()
+    }
+
+    fn cipher_request(&mut self, reader: &mut Reader, writer: &mut Writer) -> u8 {
+        let req: DataRequest = match reader.read_obj() {
+            Ok(req) => req,
+            Err(_) => return uapi::VIRTIO_CRYPTO_BADMSG,
+        };
+        let encrypt = match req.header.opcode {
+            uapi::VIRTIO_CRYPTO_CIPHER_ENCRYPT => true,
+            uapi::VIRTIO_CRYPTO_CIPHER_DECRYPT => false,
+            _ => return uapi::VIRTIO_CRYPTO_NOTSUPP,
+        };
+        // The body starts with the parameters of the cipher, and ends with the type of the
+        // symmetric operation.
+        let iv_len = body_u32(&req.body, 0) as usize;
+        let src_len = body_u32(&req.body, 4) as usize;
+        let dst_len = body_u32(&req.body, 8) as usize;
+        let op_type = body_u32(&req.body, 40);
+        if op_type != uapi::VIRTIO_CRYPTO_SYM_OP_CIPHER {
+            return uapi::VIRTIO_CRYPTO_NOTSUPP;
+        }
+        if iv_len > AES_BLOCK_SIZE || src_len as u64 > defs::MAX_DATA_SIZE || dst_len != src_len {
+            return uapi::VIRTIO_CRYPTO_BADMSG;
+        }
+
+        let session = match self.sessions.get_mut(&req.header.session_id) {
+            Some(session) => session,
+            None => return uapi::VIRTIO_CRYPTO_INVSESS,
+        };
+        if session.encrypt() != encrypt {
+            return uapi::VIRTIO_CRYPTO_BADMSG;
+        }
+
+        let mut iv = vec![0u8; iv_len];
+        let mut src = vec![0u8; src_len];
+        if reader.read_exact(&mut iv).is_err() || reader.read_exact(&mut src).is_err() {
+            return uapi::VIRTIO_CRYPTO_BADMSG;
+        }
+        let mut dst = vec![0u8; dst_len];
+        if let Err(e) = session.process(&iv, &src, &mut dst) {
+            error!("crypto: unable to process request: {}", e);
+            return status_of(&e);
+        }
+        if writer.write_all(&dst).is_err() {
+            return uapi::VIRTIO_CRYPTO_ERR;
+        }
+
+        METRICS.crypto.requests.inc();
+        METRICS.crypto.bytes.add(src_len);
+        uapi::VIRTIO_CRYPTO_OK
+    }
+
+    /// Handles a request of the data queue, returning the number of bytes written back.
+    fn handle_data_request(&mut self, mut reader: Reader, mut writer: Writer) -> usize {
+        // The status is the last byte of the buffers of the guest, following the output.
+        let mut status_writer = match writer
+            .available_bytes()
+            .checked_sub(1)
+            .map(|offset| writer.split_at(offset))
+        {
+            Some(Ok(status_writer)) => status_writer,
+            _ => {
+                error!("crypto: data request without room for its status");
+                METRICS.crypto.failures.inc();
+                return 0;
+            }
+        };
+
+        let status = self.cipher_request(&mut reader, &mut writer);
+        if status != uapi::VIRTIO_CRYPTO_OK {
+            METRICS.crypto.failures.inc();
+        }
+        if let Err(e) = status_writer.write_obj(status) {
+            error!("crypto: unable to write request status: {}", e);
+        }
+        writer.bytes_written() + status_writer.bytes_written()
+    }
+
+    /// Processes the requests available in the queue at `queue_index`, returning whether any was
+    /// handed back to the guest.
+    pub(crate) fn process_queue(&mut self, queue_index: usize) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem.clone(),
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
+
+        let mut have_used = false;
+
+        while let Some(head) = self.queues[queue_index].pop(&mem) {
+            let index = head.index;
+            let len = match (Reader::new(&mem, head.clone()), Writer::new(&mem, head)) {
+                (Ok(reader), Ok(writer)) => {
+                    if queue_index == CTRLQ_INDEX {
+                        self.handle_ctrl_request(reader, writer)
+                    } else {
+                        self.handle_data_request(reader, writer)
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("crypto: invalid descriptor chain: {}", e);
+                    METRICS.crypto.failures.inc();
+                    0
+                }
+            };
+
+            have_used = true;
+            self.queues[queue_index].add_used(&mem, index, len as u32);
+        }
+
+        have_used
+    }
+}
+
+impl VirtioDevice for Crypto {
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features
+    }
+
+    fn device_type(&self) -> u32 {
+        uapi::VIRTIO_ID_CRYPTO
+    }
+
+    fn queues(&self) -> &[VirtQueue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [VirtQueue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.interrupt_status.clone()
+    }
+
+    fn set_irq_line(&mut self, irq: u32) {
+        self.irq_line = Some(irq);
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        warn!(
+            "crypto: guest driver attempted to write device config (offset={:x}, len={:x})",
+            offset,
+            data.len()
+        );
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.queues.len() != defs::NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                defs::NUM_QUEUES,
+                self.queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Cannot write to activate_evt",);
+            return Err(ActivateError::BadActivate);
+        }
+
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        match self.device_state {
+            DeviceState::Inactive => false,
+            DeviceState::Activated(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_layout() {
+        // The sizes of the requests in the virtio specification, without the MUX mode.
+        assert_eq!(std::mem::size_of::<VirtioCryptoConfig>(), 56);
+        assert_eq!(std::mem::size_of::<CtrlRequest>(), 72);
+        assert_eq!(std::mem::size_of::<SessionInput>(), 16);
+        assert_eq!(std::mem::size_of::<DataRequest>(), 72);
+    }
+
+    #[test]
+    fn test_advertised_algos() {
+        let crypto = Crypto::new().unwrap();
+        let config = crypto.config;
+        let cipher_algo_l = config.cipher_algo_l;
+        for algo in CipherAlgo::ALL.iter() {
+            assert_eq!(
+                cipher_algo_l & (1 << algo.virtio_id()) != 0,
+                crypto.algos().contains(algo)
+            );
+        }
+        assert_eq!(crypto.device_type(), uapi::VIRTIO_ID_CRYPTO);
+    }
+}
@@ -0,0 +1,100 @@
+use std::os::unix::io::AsRawFd;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+
+use super::device::{Crypto, CTRLQ_INDEX, DATAQ_INDEX};
+use crate::virtio::device::VirtioDevice;
+
+impl Crypto {
+    pub(crate) fn handle_queue_event(&mut self, queue_index: usize, event: &EpollEvent) {
+        debug!("crypto: queue {} event", queue_index);
+
+        let event_set = event.event_set();
+        if event_set != EventSet::IN {
+            warn!(
+                "crypto: queue {} unexpected event {:?}",
+                queue_index, event_set
+            );
+            return;
+        }
+
+        if let Err(e) = self.queue_events[queue_index].read() {
+            error!("Failed to read crypto queue {} event: {:?}", queue_index, e);
+        } else if self.process_queue(queue_index)
+            && self.device_state.needs_notification(&mut self.queues)
+        {
+            self.signal_used_queue().unwrap();
+        }
+    }
+
+    fn handle_activate_event(&self, event_manager: &mut EventManager) {
+        debug!("crypto: activate event");
+        if let Err(e) = self.activate_evt.read() {
+            error!("Failed to consume crypto activate event: {:?}", e);
+        }
+
+        // The subscriber must exist as we previously registered activate_evt via
+        // `interest_list()`.
+        let self_subscriber = event_manager
+            .subscriber(self.activate_evt.as_raw_fd())
+            .unwrap();
+
+        for queue_event in self.queue_events.iter() {
+            event_manager
+                .register(
+                    queue_event.as_raw_fd(),
+                    EpollEvent::new(EventSet::IN, queue_event.as_raw_fd() as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to register crypto queue with event manager: {:?}",
+                        e
+                    );
+                });
+        }
+
+        event_manager
+            .unregister(self.activate_evt.as_raw_fd())
+            .unwrap_or_else(|e| {
+                error!("Failed to unregister crypto activate evt: {:?}", e);
+            })
+    }
+}
+
+impl Subscriber for Crypto {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        let source = event.fd();
+        let dataq = self.queue_events[DATAQ_INDEX].as_raw_fd();
+        let ctrlq = self.queue_events[CTRLQ_INDEX].as_raw_fd();
+        let activate_evt = self.activate_evt.as_raw_fd();
+
+        if self.is_activated() {
+            match source {
+                _ if source == dataq => self.handle_queue_event(DATAQ_INDEX, event),
+                _ if source == ctrlq => self.handle_queue_event(CTRLQ_INDEX, event),
+                _ if source == activate_evt => {
+                    self.handle_activate_event(event_manager);
+                }
+                _ => warn!("Unexpected crypto event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "crypto: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(
+            EventSet::IN,
+            self.activate_evt.as_raw_fd() as u64,
+        )]
+    }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
+}
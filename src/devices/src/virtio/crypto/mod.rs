@@ -0,0 +1,60 @@
+mod backend;
+mod device;
+mod event_handler;
+
+pub use self::backend::CipherAlgo;
+pub use self::defs::uapi::VIRTIO_ID_CRYPTO as TYPE_CRYPTO;
+pub use self::device::Crypto;
+
+mod defs {
+    pub const CRYPTO_DEV_ID: &str = "virtio_crypto";
+    /// A single data queue, followed by the control queue.
+    pub const NUM_QUEUES: usize = 2;
+    pub const QUEUE_SIZES: &[u16] = &[256; NUM_QUEUES];
+    /// The largest buffer accepted by a data request.
+    pub const MAX_DATA_SIZE: u64 = 1 << 20;
+    /// The largest key accepted when creating a session.
+    pub const MAX_KEY_LEN: u32 = 64;
+    /// The largest number of sessions a guest can keep open at once.
+    pub const MAX_SESSIONS: usize = 1024;
+
+    pub mod uapi {
+        pub const VIRTIO_F_VERSION_1: u32 = 32;
+        pub const VIRTIO_ID_CRYPTO: u32 = 20;
+
+        pub const VIRTIO_CRYPTO_S_HW_READY: u32 = 1;
+        pub const VIRTIO_CRYPTO_SERVICE_CIPHER: u32 = 0;
+
+        pub const VIRTIO_CRYPTO_CIPHER_AES_ECB: u32 = 2;
+        pub const VIRTIO_CRYPTO_CIPHER_AES_CBC: u32 = 3;
+        pub const VIRTIO_CRYPTO_CIPHER_AES_CTR: u32 = 4;
+
+        const fn opcode(service: u32, op: u32) -> u32 {
+            (service << 8) | op
+        }
+        pub const VIRTIO_CRYPTO_CIPHER_CREATE_SESSION: u32 =
+            opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x02);
+        pub const VIRTIO_CRYPTO_CIPHER_DESTROY_SESSION: u32 =
+            opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x03);
+        pub const VIRTIO_CRYPTO_CIPHER_ENCRYPT: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x00);
+        pub const VIRTIO_CRYPTO_CIPHER_DECRYPT: u32 = opcode(VIRTIO_CRYPTO_SERVICE_CIPHER, 0x01);
+
+        pub const VIRTIO_CRYPTO_SYM_OP_CIPHER: u32 = 1;
+        pub const VIRTIO_CRYPTO_OP_ENCRYPT: u32 = 1;
+        pub const VIRTIO_CRYPTO_OP_DECRYPT: u32 = 2;
+
+        pub const VIRTIO_CRYPTO_OK: u8 = 0;
+        pub const VIRTIO_CRYPTO_ERR: u8 = 1;
+        pub const VIRTIO_CRYPTO_BADMSG: u8 = 2;
+        pub const VIRTIO_CRYPTO_NOTSUPP: u8 = 3;
+        pub const VIRTIO_CRYPTO_INVSESS: u8 = 4;
+    }
+}
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// Failed to create event fd.
+    EventFd(std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, CryptoError>;
@@ -5,10 +5,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
-use super::{ActivateResult, Queue};
+use super::{ActivateResult, Queue, VIRTIO_MMIO_INT_CONFIG};
+use crate::legacy::Gic;
 use crate::virtio::AsAny;
+use crate::Error as DeviceError;
+use logger::METRICS;
 use utils::eventfd::EventFd;
 use vm_memory::GuestMemoryMmap;
 
@@ -19,6 +23,27 @@ pub enum DeviceState {
     Activated(GuestMemoryMmap),
 }
 
+impl DeviceState {
+    /// Returns whether the driver wants to be notified of the buffers used on `queues`, counting
+    /// the notifications it suppressed.
+    pub fn needs_notification(&self, queues: &mut [Queue]) -> bool {
+        let mem = match self {
+            DeviceState::Activated(mem) => mem,
+            DeviceState::Inactive => return false,
+        };
+        // Every queue is asked, as a single interrupt notifies the driver of all of them.
+        let notify = queues.iter_mut().fold(false, |notify, queue| {
+            queue.needs_notification(mem) | notify
+        });
+        if notify {
+            METRICS.queue.notifications.inc();
+        } else {
+            METRICS.queue.suppressed_notifications.inc();
+        }
+        notify
+    }
+}
+
 #[derive(Clone)]
 pub struct VirtioShmRegion {
     pub host_addr: u64,
@@ -26,6 +51,44 @@ pub struct VirtioShmRegion {
     pub size: usize,
 }
 
+/// The changes of the configuration space of a device at runtime, shared with its transport,
+/// which counts them in the configuration generation it exposes. The driver reading the same
+/// generation before and after the configuration space knows it didn't change in between.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigGeneration(Arc<AtomicU32>);
+
+impl ConfigGeneration {
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Notifies the driver of a change of the configuration space of a device, once applied: counts
+/// it in `generation`, and raises the configuration interrupt, through `intc` where the interrupt
+/// controller is emulated by the VMM, as on macOS, or through `interrupt_evt` otherwise.
+///
+/// The device is expected to apply the change and call this with its lock held, as the transport
+/// reads the configuration space with it.
+pub fn signal_config_change(
+    generation: &ConfigGeneration,
+    interrupt_status: &AtomicUsize,
+    interrupt_evt: &EventFd,
+    intc: Option<&Arc<Mutex<Gic>>>,
+    irq_line: Option<u32>,
+) -> Result<(), DeviceError> {
+    generation.0.fetch_add(1, Ordering::SeqCst);
+    interrupt_status.fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+    match (intc, irq_line) {
+        (Some(intc), Some(irq_line)) => {
+            intc.lock().unwrap().set_irq(irq_line);
+            Ok(())
+        }
+        _ => interrupt_evt
+            .write(1)
+            .map_err(DeviceError::FailedSignalingConfigChange),
+    }
+}
+
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
 /// The lifecycle of a virtio device is to be moved to a virtio transport, which will then query the
@@ -123,6 +186,12 @@ pub trait VirtioDevice: AsAny + Send {
     fn shm_region(&self) -> Option<&VirtioShmRegion> {
         None
     }
+
+    /// Returns the changes of the configuration space of the device at runtime, for the devices
+    /// notifying the driver of them with `signal_config_change`.
+    fn config_generation(&self) -> Option<ConfigGeneration> {
+        None
+    }
 }
 
 impl std::fmt::Debug for dyn VirtioDevice {
@@ -0,0 +1,73 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restricts the guest memory the devices access through their queues to designated windows.
+//!
+//! The guest is told to bounce the buffers of the devices through a pool within the windows, so
+//! the rest of its memory is never handed to them. The queues reject the descriptors out of the
+//! windows, whether the guest doesn't follow the restriction or a device would be tricked into
+//! accessing memory it shouldn't.
+
+use std::sync::Arc;
+
+use vm_memory::GuestAddress;
+
+/// A range of guest memory the devices may access.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmaWindow {
+    pub start: GuestAddress,
+    pub size: u64,
+}
+
+impl DmaWindow {
+    /// Returns whether the `len` bytes at `addr` lie within the window.
+    pub fn contains(&self, addr: GuestAddress, len: u64) -> bool {
+        addr.0 >= self.start.0
+            && addr
+                .0
+                .checked_add(len)
+                .map_or(false, |end| end <= self.start.0 + self.size)
+    }
+}
+
+/// The windows of guest memory the devices may access, shared by all their queues.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DmaWindows(Arc<[DmaWindow]>);
+
+impl DmaWindows {
+    pub fn new(windows: Vec<DmaWindow>) -> Self {
+        DmaWindows(Arc::from(windows))
+    }
+
+    /// Returns whether the `len` bytes at `addr` lie within one of the windows.
+    pub fn contains(&self, addr: GuestAddress, len: u64) -> bool {
+        self.0.iter().any(|window| window.contains(addr, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dma_windows() {
+        let windows = DmaWindows::new(vec![
+            DmaWindow {
+                start: GuestAddress(0x1000),
+                size: 0x1000,
+            },
+            DmaWindow {
+                start: GuestAddress(0x8000),
+                size: 0x100,
+            },
+        ]);
+
+        assert!(windows.contains(GuestAddress(0x1000), 0x1000));
+        assert!(windows.contains(GuestAddress(0x8080), 0x80));
+        assert!(windows.contains(GuestAddress(0x1800), 0));
+        // Straddling the end of a window, or bridging two, is out.
+        assert!(!windows.contains(GuestAddress(0x1800), 0x1000));
+        assert!(!windows.contains(GuestAddress(0x800), 0x1000));
+        assert!(!windows.contains(GuestAddress(u64::MAX), 2));
+    }
+}
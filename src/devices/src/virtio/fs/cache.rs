@@ -0,0 +1,464 @@
+//! Cache of the metadata and the contents of a share, kept by the host for shares backed by a
+//! remote file system, such as NFS or SMB, where every system call of the passthrough costs a
+//! round trip to the server.
+//!
+//! The lookups and the attributes are cached for `metadata_ttl`, and the contents read for
+//! `content_ttl`, each bounded in size by evicting the least recently used entries. The changes
+//! made through the share invalidate what they affect right away, but the ones made on the host, or
+//! on the server by other clients, only show up once the entries expire, or once the whole cache
+//! is invalidated. The writes through DAX mappings aren't seen by the cache.
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::bindings;
+
+/// The size of the blocks the contents are read and cached in.
+pub const CONTENT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// How the host caches the metadata and the contents of a share.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ShareCacheConfig {
+    /// How long the lookups and the attributes are cached, or zero not to cache them.
+    pub metadata_ttl: Duration,
+    /// The most lookups, and the most attributes, cached.
+    pub max_entries: usize,
+    /// How long the contents read are cached, or zero not to cache them.
+    pub content_ttl: Duration,
+    /// The most bytes of contents cached, in blocks of `CONTENT_BLOCK_SIZE`.
+    pub max_content_size: usize,
+}
+
+impl ShareCacheConfig {
+    fn caches_metadata(&self) -> bool {
+        !self.metadata_ttl.is_zero() && self.max_entries > 0
+    }
+
+    fn caches_content(&self) -> bool {
+        !self.content_ttl.is_zero() && self.max_content_size >= CONTENT_BLOCK_SIZE
+    }
+}
+
+struct LruEntry<V> {
+    value: V,
+    expires: Instant,
+    weight: usize,
+    used: u64,
+}
+
+/// A map bounded by the total weight of its entries, evicting the least recently used ones, and
+/// dropping them once they expire.
+struct Lru<K, V> {
+    entries: BTreeMap<K, LruEntry<V>>,
+    // The keys, by the last time they were used.
+    order: BTreeMap<u64, K>,
+    next_use: u64,
+    weight: usize,
+    capacity: usize,
+}
+
+impl<K: Clone + Ord, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+            weight: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &K, now: Instant) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires <= now {
+            self.remove(key);
+            return None;
+        }
+        self.order.remove(&entry.used);
+        entry.used = self.next_use;
+        self.order.insert(self.next_use, key.clone());
+        self.next_use += 1;
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V, weight: usize, expires: Instant) {
+        self.remove(&key);
+        if weight > self.capacity {
+            return;
+        }
+        while self.weight + weight > self.capacity {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+        self.entries.insert(
+            key.clone(),
+            LruEntry {
+                value,
+                expires,
+                weight,
+                used: self.next_use,
+            },
+        );
+        self.order.insert(self.next_use, key);
+        self.next_use += 1;
+        self.weight += weight;
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.used);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    fn remove_range<R: RangeBounds<K>>(&mut self, range: R) {
+        let keys: Vec<K> = self
+            .entries
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.weight = 0;
+    }
+}
+
+struct CacheState {
+    config: ShareCacheConfig,
+    // Bumped by every invalidation, so what was read from the host before isn't cached after.
+    generation: AtomicU64,
+    names: Mutex<Lru<(u64, CString), u64>>,
+    attrs: Mutex<Lru<u64, bindings::stat64>>,
+    blocks: Mutex<Lru<(u64, u64), Arc<Vec<u8>>>>,
+}
+
+/// The cache of a share. Clones share the cache, so the embedder can keep one to invalidate it
+/// while the microVM runs.
+#[derive(Clone)]
+pub struct ShareCache(Arc<CacheState>);
+
+impl ShareCache {
+    pub fn new(config: ShareCacheConfig) -> Self {
+        ShareCache(Arc::new(CacheState {
+            config,
+            generation: AtomicU64::new(0),
+            names: Mutex::new(Lru::new(config.max_entries)),
+            attrs: Mutex::new(Lru::new(config.max_entries)),
+            blocks: Mutex::new(Lru::new(config.max_content_size)),
+        }))
+    }
+
+    pub fn config(&self) -> ShareCacheConfig {
+        self.0.config
+    }
+
+    /// Drops everything cached, for the changes made outside of the share to show up right away.
+    pub fn invalidate(&self) {
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        self.0.names.lock().unwrap().clear();
+        self.0.attrs.lock().unwrap().clear();
+        self.0.blocks.lock().unwrap().clear();
+    }
+
+    /// Returns the generation of the cache, to be taken before reading from the host what is
+    /// then inserted.
+    pub(crate) fn generation(&self) -> u64 {
+        self.0.generation.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn caches_content(&self) -> bool {
+        self.0.config.caches_content()
+    }
+
+    /// Returns the inode `name` was found as in the directory `parent`, if cached.
+    pub(crate) fn name(&self, parent: u64, name: &CStr) -> Option<u64> {
+        if !self.0.config.caches_metadata() {
+            return None;
+        }
+        self.0
+            .names
+            .lock()
+            .unwrap()
+            .get(&(parent, name.to_owned()), Instant::now())
+    }
+
+    /// Returns the attributes of `inode`, if cached.
+    pub(crate) fn attr(&self, inode: u64) -> Option<bindings::stat64> {
+        if !self.0.config.caches_metadata() {
+            return None;
+        }
+        self.0.attrs.lock().unwrap().get(&inode, Instant::now())
+    }
+
+    /// Caches the lookup of `name` in the directory `parent` as `inode`, with its attributes, if
+    /// nothing was invalidated since `generation`.
+    pub(crate) fn insert_entry(
+        &self,
+        generation: u64,
+        parent: u64,
+        name: &CStr,
+        inode: u64,
+        attr: bindings::stat64,
+    ) {
+        // "." and ".." are resolved by the host each time, in case a directory was moved.
+        let bytes = name.to_bytes();
+        if !self.0.config.caches_metadata() || bytes == b"." || bytes == b".." {
+            return;
+        }
+        let expires = Instant::now() + self.0.config.metadata_ttl;
+        let mut names = self.0.names.lock().unwrap();
+        let mut attrs = self.0.attrs.lock().unwrap();
+        if self.generation() == generation {
+            names.insert((parent, name.to_owned()), inode, 1, expires);
+            attrs.insert(inode, attr, 1, expires);
+        }
+    }
+
+    /// Caches the attributes of `inode`, if nothing was invalidated since `generation`.
+    pub(crate) fn insert_attr(&self, generation: u64, inode: u64, attr: bindings::stat64) {
+        if !self.0.config.caches_metadata() {
+            return;
+        }
+        let expires = Instant::now() + self.0.config.metadata_ttl;
+        let mut attrs = self.0.attrs.lock().unwrap();
+        if self.generation() == generation {
+            attrs.insert(inode, attr, 1, expires);
+        }
+    }
+
+    /// Drops the lookup of `name` in the directory `parent`, just created, removed or renamed,
+    /// along with the attributes of the directory and of the inode it was found as.
+    pub(crate) fn invalidate_name(&self, parent: u64, name: &CStr) {
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        let inode = self
+            .0
+            .names
+            .lock()
+            .unwrap()
+            .remove(&(parent, name.to_owned()));
+        let mut attrs = self.0.attrs.lock().unwrap();
+        attrs.remove(&parent);
+        if let Some(inode) = inode {
+            attrs.remove(&inode);
+        }
+    }
+
+    /// Drops the attributes and the contents of `inode`, just changed.
+    pub(crate) fn invalidate_inode(&self, inode: u64) {
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        self.0.attrs.lock().unwrap().remove(&inode);
+        self.0
+            .blocks
+            .lock()
+            .unwrap()
+            .remove_range((inode, 0)..=(inode, u64::MAX));
+    }
+
+    /// Reads `size` bytes of the contents of `inode` at `offset`, from the blocks cached, or with
+    /// `read_at` for the ones that aren't, which are cached then. Fewer bytes are returned past the
+    /// end of the file.
+    pub(crate) fn read<F>(
+        &self,
+        inode: u64,
+        offset: u64,
+        size: usize,
+        mut read_at: F,
+    ) -> io::Result<Vec<u8>>
+    where
+        F: FnMut(&mut [u8], u64) -> io::Result<usize>,
+    {
+        let block_size = CONTENT_BLOCK_SIZE as u64;
+        let end = offset.saturating_add(size as u64);
+        let mut contents = Vec::with_capacity(size);
+        let mut index = offset / block_size;
+        while index * block_size < end {
+            let generation = self.generation();
+            let cached = self
+                .0
+                .blocks
+                .lock()
+                .unwrap()
+                .get(&(inode, index), Instant::now());
+            let block = match cached {
+                Some(block) => block,
+                None => {
+                    let block = Arc::new(read_block(&mut read_at, index * block_size)?);
+                    let expires = Instant::now() + self.0.config.content_ttl;
+                    let mut blocks = self.0.blocks.lock().unwrap();
+                    if self.generation() == generation {
+                        blocks.insert((inode, index), block.clone(), block.len(), expires);
+                    }
+                    block
+                }
+            };
+
+            let start = offset.saturating_sub(index * block_size) as usize;
+            let stop = std::cmp::min((end - index * block_size) as usize, block.len());
+            if start < stop {
+                contents.extend_from_slice(&block[start..stop]);
+            }
+            // A block short of its size ends the file.
+            if block.len() < CONTENT_BLOCK_SIZE {
+                break;
+            }
+            index += 1;
+        }
+        Ok(contents)
+    }
+}
+
+/// Reads a whole block at `offset`, short of its size only at the end of the file.
+fn read_block<F>(read_at: &mut F, offset: u64) -> io::Result<Vec<u8>>
+where
+    F: FnMut(&mut [u8], u64) -> io::Result<usize>,
+{
+    let mut block = vec![0u8; CONTENT_BLOCK_SIZE];
+    let mut len = 0;
+    while len < block.len() {
+        match read_at(&mut block[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(count) => len += count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    block.truncate(len);
+    Ok(block)
+}
+
+impl fmt::Debug for ShareCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShareCache")
+            .field("config", &self.0.config)
+            .finish()
+    }
+}
+
+/// Caches are equal if they're the same cache.
+impl PartialEq for ShareCache {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use std::thread;
+
+    fn config() -> ShareCacheConfig {
+        ShareCacheConfig {
+            metadata_ttl: Duration::from_secs(60),
+            max_entries: 2,
+            content_ttl: Duration::from_secs(60),
+            max_content_size: 2 * CONTENT_BLOCK_SIZE,
+        }
+    }
+
+    fn attr(size: i64) -> bindings::stat64 {
+        // Safe because the attributes are plain data.
+        let mut attr: bindings::stat64 = unsafe { mem::zeroed() };
+        attr.st_size = size;
+        attr
+    }
+
+    fn name(name: &[u8]) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    #[test]
+    fn test_metadata() {
+        let cache = ShareCache::new(config());
+        let generation = cache.generation();
+        cache.insert_entry(generation, 1, &name(b"a"), 10, attr(1));
+        cache.insert_entry(generation, 1, &name(b".."), 11, attr(1));
+        assert_eq!(cache.name(1, &name(b"a")), Some(10));
+        assert_eq!(cache.attr(10).unwrap().st_size, 1);
+        assert_eq!(cache.name(1, &name(b"..")), None);
+
+        // The least recently used entries are evicted.
+        cache.insert_entry(generation, 1, &name(b"b"), 12, attr(2));
+        cache.name(1, &name(b"a"));
+        cache.insert_entry(generation, 1, &name(b"c"), 13, attr(3));
+        assert_eq!(cache.name(1, &name(b"a")), Some(10));
+        assert_eq!(cache.name(1, &name(b"b")), None);
+
+        // Changing a name drops its inode and its directory.
+        cache.insert_attr(cache.generation(), 1, attr(0));
+        cache.invalidate_name(1, &name(b"a"));
+        assert_eq!(cache.name(1, &name(b"a")), None);
+        assert!(cache.attr(10).is_none());
+        assert!(cache.attr(1).is_none());
+
+        // What was read before an invalidation isn't cached after it.
+        cache.insert_attr(generation, 14, attr(4));
+        assert!(cache.attr(14).is_none());
+        assert_eq!(cache.name(1, &name(b"c")), Some(13));
+        cache.invalidate();
+        assert_eq!(cache.name(1, &name(b"c")), None);
+    }
+
+    #[test]
+    fn test_metadata_expiry() {
+        let cache = ShareCache::new(ShareCacheConfig {
+            metadata_ttl: Duration::from_millis(10),
+            ..config()
+        });
+        cache.insert_attr(cache.generation(), 10, attr(1));
+        assert!(cache.attr(10).is_some());
+        thread::sleep(Duration::from_millis(20));
+        assert!(cache.attr(10).is_none());
+    }
+
+    #[test]
+    fn test_content() {
+        let cache = ShareCache::new(config());
+        let file: Vec<u8> = (0..CONTENT_BLOCK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut reads = 0;
+        let mut read_at = |buf: &mut [u8], offset: u64| {
+            reads += 1;
+            let offset = std::cmp::min(offset as usize, file.len());
+            let count = std::cmp::min(buf.len(), file.len() - offset);
+            buf[..count].copy_from_slice(&file[offset..offset + count]);
+            Ok(count)
+        };
+
+        let offset = CONTENT_BLOCK_SIZE as u64 - 5;
+        let contents = cache.read(10, offset, 10, &mut read_at).unwrap();
+        assert_eq!(contents, &file[offset as usize..offset as usize + 10]);
+        assert_eq!(cache.read(10, offset, 10, &mut read_at).unwrap(), contents);
+
+        // Past the end of the file.
+        let offset = 2 * CONTENT_BLOCK_SIZE as u64;
+        let contents = cache.read(10, offset, 100, &mut read_at).unwrap();
+        assert_eq!(contents, &file[offset as usize..]);
+        assert!(cache
+            .read(10, offset + 100, 100, &mut read_at)
+            .unwrap()
+            .is_empty());
+
+        cache.invalidate_inode(10);
+        cache.read(10, 0, 1, &mut read_at).unwrap();
+        drop(read_at);
+        // Two blocks, the last one until the end of the file, and the first one again.
+        assert_eq!(reads, 2 + 2 + 1);
+    }
+}
@@ -0,0 +1,276 @@
+//! Content-addressed store of the chunks of the files the guest maps read-only through DAX.
+//!
+//! Each chunk mapped is copied, once, to a file of the store named after the SHA-256 of its
+//! contents, and that file is mapped instead of the one shared. The microVMs sharing the same
+//! store, each with its own copy of a base image, then map the same files of the host, so the
+//! identical chunks only take their pages once in its page cache.
+//!
+//! The chunks are only taken from files which don't change while they're copied, but a file
+//! changed on the host after its chunks were mapped keeps showing its previous contents to the
+//! guests mapping them, so the store is only meant for shares which don't change, such as base
+//! images. The writable mappings always map the files shared.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use utils::sha256::{self, DIGEST_SIZE};
+
+/// The largest chunk taken from the store. The larger mappings map the files shared.
+pub const MAX_CHUNK_SIZE: u64 = 64 << 20;
+
+// The most chunks whose digest is remembered, so they aren't read again each time they're mapped.
+const MAX_INDEX_ENTRIES: usize = 16384;
+
+/// Identifies a chunk of a file as long as the file doesn't change.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct ChunkKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    offset: u64,
+    len: u64,
+}
+
+impl ChunkKey {
+    fn new(metadata: &fs::Metadata, offset: u64, len: u64) -> Self {
+        ChunkKey {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            offset,
+            len,
+        }
+    }
+}
+
+struct DedupState {
+    dir: PathBuf,
+    // The digests of the chunks already stored.
+    index: Mutex<LruCache<ChunkKey, [u8; DIGEST_SIZE]>>,
+    // Names the temporary files of the chunks being stored.
+    next_tmp: AtomicU64,
+}
+
+/// A content-addressed store of chunks, in a directory of the host shared by the microVMs. Clones
+/// share the store, so it can be set on several shares.
+#[derive(Clone)]
+pub struct DedupStore(Arc<DedupState>);
+
+impl DedupStore {
+    /// Opens the store in `dir`, creating it if it doesn't exist. The directory must be owned by
+    /// the user of the process, and not writable by others, as the guests map what it holds.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        match fs::DirBuilder::new().mode(0o700).create(dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => (),
+        }
+
+        let metadata = fs::metadata(dir)?;
+        // Safe because geteuid() can't fail.
+        let uid = unsafe { libc::geteuid() };
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the dedup store must be a directory of the user, writable only by it",
+            ));
+        }
+
+        Ok(DedupStore(Arc::new(DedupState {
+            dir: dir.to_path_buf(),
+            index: Mutex::new(LruCache::new(MAX_INDEX_ENTRIES)),
+            next_tmp: AtomicU64::new(0),
+        })))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.0.dir
+    }
+
+    /// Returns the file of the store holding the `len` bytes of `file` at `offset`, or fewer at
+    /// the end of the file, to be mapped from its start instead. Returns `None` for the chunks
+    /// that can't be taken from the store, past the end of the file or too large, and for the
+    /// files changing while they're read.
+    pub(crate) fn chunk(&self, file: &File, offset: u64, len: u64) -> io::Result<Option<File>> {
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || len == 0 || len > MAX_CHUNK_SIZE || offset >= metadata.size() {
+            return Ok(None);
+        }
+        let key = ChunkKey::new(&metadata, offset, len);
+
+        let digest = self.0.index.lock().unwrap().get(&key).copied();
+        if let Some(digest) = digest {
+            match File::open(self.path(&digest)) {
+                Ok(chunk) => return Ok(Some(chunk)),
+                // Removed from the store since, so stored again.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let contents = read_chunk(file, offset, len)?;
+        if ChunkKey::new(&file.metadata()?, offset, len) != key {
+            return Ok(None);
+        }
+        let digest = sha256::digest(&contents);
+        let chunk = self.store(&digest, &contents)?;
+        self.0.index.lock().unwrap().put(key, digest);
+        Ok(Some(chunk))
+    }
+
+    fn path(&self, digest: &[u8; DIGEST_SIZE]) -> PathBuf {
+        let name: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        self.0.dir.join(name)
+    }
+
+    /// Returns the file of the store holding `contents`, writing it unless another microVM did.
+    fn store(&self, digest: &[u8; DIGEST_SIZE], contents: &[u8]) -> io::Result<File> {
+        let path = self.path(digest);
+        match File::open(&path) {
+            // Checked, rather than trusting the name, in case it was cut short.
+            Ok(chunk) if same_contents(&chunk, contents)? => return Ok(chunk),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        // Written aside and then renamed, so the others never map a partial chunk.
+        let tmp = self.0.dir.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            self.0.next_tmp.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut chunk = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o444)
+            .open(&tmp)?;
+        let res = chunk
+            .write_all(contents)
+            .and_then(|()| chunk.set_permissions(fs::Permissions::from_mode(0o444)))
+            .and_then(|()| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        Ok(chunk)
+    }
+}
+
+impl std::fmt::Debug for DedupStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("DedupStore").field(&self.0.dir).finish()
+    }
+}
+
+impl PartialEq for DedupStore {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Reads the `len` bytes of `file` at `offset`, or fewer at its end.
+fn read_chunk(file: &File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut contents = vec![0u8; len as usize];
+    let mut read = 0;
+    while read < contents.len() {
+        match file.read_at(&mut contents[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    contents.truncate(read);
+    Ok(contents)
+}
+
+fn same_contents(chunk: &File, contents: &[u8]) -> io::Result<bool> {
+    if chunk.metadata()?.len() != contents.len() as u64 {
+        return Ok(false);
+    }
+    Ok(read_chunk(chunk, 0, contents.len() as u64)? == contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempdir::TempDir;
+
+    fn chunk_contents(chunk: Option<File>) -> Vec<u8> {
+        let chunk = chunk.unwrap();
+        read_chunk(&chunk, 0, chunk.metadata().unwrap().len()).unwrap()
+    }
+
+    #[test]
+    fn test_dedup_store() {
+        let tmp = TempDir::new().unwrap();
+        let store = DedupStore::new(tmp.as_path().join("store")).unwrap();
+
+        // Two copies of the same image share their chunks.
+        let data: Vec<u8> = (0..12288u32).map(|i| (i % 251) as u8).collect();
+        let images: Vec<File> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let path = tmp.as_path().join(name);
+                fs::write(&path, &data).unwrap();
+                File::open(path).unwrap()
+            })
+            .collect();
+        let chunk = store.chunk(&images[0], 4096, 4096).unwrap();
+        assert_eq!(chunk_contents(chunk), &data[4096..8192]);
+        let chunk = store.chunk(&images[1], 4096, 4096).unwrap();
+        assert_eq!(chunk_contents(chunk), &data[4096..8192]);
+        // The last chunk is cut short at the end of the file.
+        let chunk = store.chunk(&images[1], 8192, 8192).unwrap();
+        assert_eq!(chunk_contents(chunk), &data[8192..]);
+        assert_eq!(fs::read_dir(store.dir()).unwrap().count(), 2);
+
+        assert!(store.chunk(&images[0], 12288, 4096).unwrap().is_none());
+        assert!(store
+            .chunk(&images[0], 0, MAX_CHUNK_SIZE + 4096)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_dedup_store_damaged() {
+        let tmp = TempDir::new().unwrap();
+        let store = DedupStore::new(tmp.as_path()).unwrap();
+        let path = tmp.as_path().join("image");
+        fs::write(&path, b"base image").unwrap();
+        let image = File::open(&path).unwrap();
+
+        // A chunk whose contents don't match its name is replaced.
+        let name = store.path(&sha256::digest(b"base image"));
+        fs::write(&name, b"base").unwrap();
+        let chunk = store.chunk(&image, 0, 4096).unwrap();
+        assert_eq!(chunk_contents(chunk), b"base image");
+        assert_eq!(fs::read(&name).unwrap(), b"base image");
+
+        // As is a chunk removed from the store.
+        fs::remove_file(&name).unwrap();
+        let chunk = store.chunk(&image, 0, 4096).unwrap();
+        assert_eq!(chunk_contents(chunk), b"base image");
+    }
+
+    #[test]
+    fn test_dedup_store_permissions() {
+        let tmp = TempDir::new().unwrap();
+        fs::set_permissions(tmp.as_path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(
+            DedupStore::new(tmp.as_path()).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+}
@@ -7,14 +7,14 @@ use std::collections::VecDeque;
 use std::fmt::{self, Display};
 use std::io::{self, Read, Write};
 use std::mem::{size_of, MaybeUninit};
-use std::ops::Deref;
 use std::ptr::copy_nonoverlapping;
 use std::result;
 
+use crate::virtio::guest_memory::checked_slice;
 use crate::virtio::queue::DescriptorChain;
 use vm_memory::{
-    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
-    GuestMemoryRegion, Le16, Le32, Le64, VolatileMemory, VolatileMemoryError, VolatileSlice,
+    Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, Le16, Le32, Le64,
+    VolatileMemory, VolatileMemoryError, VolatileSlice,
 };
 
 use super::file_traits::{FileReadWriteAtVolatile, FileReadWriteVolatile};
@@ -202,15 +202,8 @@ impl<'a> Reader<'a> {
                     .checked_add(desc.len as usize)
                     .ok_or(Error::DescriptorChainOverflow)?;
 
-                let region = mem.find_region(desc.addr).ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .deref()
-                    .get_slice(offset.raw_value() as usize, desc.len as usize)
-                    .map_err(Error::VolatileMemoryError)
+                checked_slice(mem, desc.addr, desc.len as usize)
+                    .map_err(|_| Error::FindMemoryRegion)
             })
             .collect::<Result<VecDeque<VolatileSlice<'a>>>>()?;
         Ok(Reader {
@@ -354,15 +347,8 @@ impl<'a> Writer<'a> {
                     .checked_add(desc.len as usize)
                     .ok_or(Error::DescriptorChainOverflow)?;
 
-                let region = mem.find_region(desc.addr).ok_or(Error::FindMemoryRegion)?;
-                let offset = desc
-                    .addr
-                    .checked_sub(region.start_addr().raw_value())
-                    .unwrap();
-                region
-                    .deref()
-                    .get_slice(offset.raw_value() as usize, desc.len as usize)
-                    .map_err(Error::VolatileMemoryError)
+                checked_slice(mem, desc.addr, desc.len as usize)
+                    .map_err(|_| Error::FindMemoryRegion)
             })
             .collect::<Result<VecDeque<VolatileSlice<'a>>>>()?;
 
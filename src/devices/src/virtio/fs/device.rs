@@ -1,21 +1,33 @@
 use std::cmp;
 use std::io::Write;
+use std::panic;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+use logger::METRICS;
 use utils::eventfd::EventFd;
 use vm_memory::{ByteValued, GuestMemoryMmap};
 
+use super::super::worker_pool::WorkerGroup;
 use super::super::{
-    ActivateError, ActivateResult, DeviceState, FsError, Queue as VirtQueue, VirtioDevice,
-    VirtioShmRegion, VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DescriptorChain, DeviceState, FsError, Queue as VirtQueue,
+    VirtioDevice, VirtioShmRegion, VIRTIO_F_RING_PACKED, VIRTIO_MMIO_INT_VRING,
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
 };
 use super::descriptor_utils::{Reader, Writer};
 use super::passthrough::{self, PassthroughFs};
+use super::quota::QuotaTracker;
 use super::server::Server;
-use super::{defs, defs::uapi};
+#[cfg(target_os = "linux")]
+use super::DedupStore;
+use super::{
+    defs, defs::uapi, EscapePolicy, LookupCacheConfig, ShareCache, ShareIoConfig, ShareQuota,
+};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
+use crate::fd_budget::FdAccount;
 use crate::legacy::Gic;
 use crate::Error as DeviceError;
 
@@ -24,7 +36,10 @@ pub(crate) const HPQ_INDEX: usize = 0;
 // Request queue.
 pub(crate) const REQ_INDEX: usize = 1;
 
-pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64;
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << VIRTIO_F_RING_PACKED as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
 
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
@@ -54,10 +69,18 @@ pub struct Fs {
     pub(crate) activate_evt: EventFd,
     pub(crate) device_state: DeviceState,
     config: VirtioFsConfig,
+    tag: String,
+    shared_dir: String,
     shm_region: Option<VirtioShmRegion>,
-    server: Server<PassthroughFs>,
+    server: Arc<Server<PassthroughFs>>,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
+    /// The share of the worker pool the requests run on, if any. Without one, they're run from
+    /// the event loop.
+    pub(crate) worker_group: Option<WorkerGroup>,
+    /// The descriptor chains handled by the worker pool, as queue and head indexes, waiting to be
+    /// handed back to the guest.
+    completions: Arc<Mutex<Vec<(usize, u16)>>>,
 }
 
 impl Fs {
@@ -65,6 +88,7 @@ impl Fs {
         fs_id: String,
         shared_dir: String,
         mapped_volumes: Option<Vec<(PathBuf, PathBuf)>>,
+        fd_account: Option<FdAccount>,
         queues: Vec<VirtQueue>,
     ) -> super::Result<Fs> {
         let mut queue_events = Vec::new();
@@ -73,14 +97,15 @@ impl Fs {
                 .push(EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(FsError::EventFd)?);
         }
 
-        let tag = fs_id.into_bytes();
         let mut config = VirtioFsConfig::default();
-        config.tag[..tag.len()].copy_from_slice(tag.as_slice());
+        config.tag[..fs_id.len()].copy_from_slice(fs_id.as_bytes());
         config.num_request_queues = 1;
 
         let fs_cfg = passthrough::Config {
-            root_dir: shared_dir,
+            root_dir: shared_dir.clone(),
             mapped_volumes,
+            fd_account,
+            tag: fs_id.clone(),
             ..Default::default()
         };
 
@@ -94,10 +119,16 @@ impl Fs {
             activate_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(FsError::EventFd)?,
             device_state: DeviceState::Inactive,
             config,
+            tag: fs_id,
+            shared_dir,
             shm_region: None,
-            server: Server::new(PassthroughFs::new(fs_cfg).unwrap()),
+            server: Arc::new(Server::new(
+                PassthroughFs::new(fs_cfg).map_err(FsError::CreatePassthrough)?,
+            )),
             intc: None,
             irq_line: None,
+            worker_group: None,
+            completions: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
@@ -105,18 +136,82 @@ impl Fs {
         fs_id: String,
         shared_dir: String,
         mapped_volumes: Option<Vec<(PathBuf, PathBuf)>>,
+        fd_account: Option<FdAccount>,
     ) -> super::Result<Fs> {
         let queues: Vec<VirtQueue> = defs::QUEUE_SIZES
             .iter()
             .map(|&max_size| VirtQueue::new(max_size))
             .collect();
-        Self::with_queues(fs_id, shared_dir, mapped_volumes, queues)
+        Self::with_queues(fs_id, shared_dir, mapped_volumes, fd_account, queues)
     }
 
     pub fn id(&self) -> &str {
         defs::FS_DEV_ID
     }
 
+    /// Sets how the guest caches the lookups of the device, which must not be activated yet.
+    pub fn set_lookup_cache(&mut self, lookup_cache: LookupCacheConfig) {
+        // The server is only shared with the workers once the device is activated.
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.fs_mut().set_lookup_cache(lookup_cache),
+            None => warn!("fs: the lookup cache can't be changed once the device is activated"),
+        }
+    }
+
+    /// Sets how the I/O of the shared files is done, which must not be activated yet.
+    pub fn set_io_config(&mut self, io: ShareIoConfig) {
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.fs_mut().set_io_config(io),
+            None => warn!("fs: the I/O of the files can't be changed once the device is activated"),
+        }
+    }
+
+    /// Sets how the attempts of the guest to reach outside of the shared directory are handled,
+    /// which must not be activated yet.
+    pub fn set_escape_policy(&mut self, escape_policy: EscapePolicy) {
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.fs_mut().set_escape_policy(escape_policy),
+            None => warn!("fs: the escape policy can't be changed once the device is activated"),
+        }
+    }
+
+    /// Sets the cache the host keeps of the shared directory, which must not be activated yet.
+    pub fn set_cache(&mut self, cache: ShareCache) {
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.fs_mut().set_cache(cache),
+            None => warn!("fs: the cache can't be changed once the device is activated"),
+        }
+    }
+
+    /// Sets the content-addressed store the read-only DAX mappings of the shared directory are
+    /// taken from, which must not be activated yet.
+    #[cfg(target_os = "linux")]
+    pub fn set_dedup(&mut self, dedup: DedupStore) {
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.fs_mut().set_dedup(dedup),
+            None => warn!("fs: the dedup store can't be changed once the device is activated"),
+        }
+    }
+
+    /// Sets the quota of the shared directory, which must not be activated yet.
+    pub fn set_quota(&mut self, quota: ShareQuota) {
+        let tracker = QuotaTracker::new(PathBuf::from(&self.shared_dir), quota);
+        match Arc::get_mut(&mut self.server) {
+            Some(server) => server.set_quota(tracker),
+            None => warn!("fs: the quota can't be changed once the device is activated"),
+        }
+    }
+
+    /// Returns the tag used by the guest to mount this filesystem.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the host directory shared through this device.
+    pub fn shared_dir(&self) -> &str {
+        &self.shared_dir
+    }
+
     pub fn set_intc(&mut self, intc: Arc<Mutex<Gic>>) {
         self.intc = Some(intc);
     }
@@ -125,9 +220,16 @@ impl Fs {
         self.shm_region = Some(shm_region);
     }
 
+    /// Runs the requests on `worker_group`, rather than on the thread of the event loop.
+    pub fn set_worker_group(&mut self, worker_group: WorkerGroup) {
+        self.worker_group = Some(worker_group);
+    }
+
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        #[cfg(feature = "fault-injection")]
+        FAULTS.delay_interrupt(uapi::VIRTIO_ID_FS);
         debug!("fs: raising IRQ");
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -146,7 +248,7 @@ impl Fs {
         debug!("Fs: HPQ queue event");
         if let Err(e) = self.queue_events[0].read() {
             error!("Failed to get queue event: {:?}", e);
-        } else if self.process_queue(0) {
+        } else if self.process_queue(0) && self.device_state.needs_notification(&mut self.queues) {
             let _ = self.signal_used_queue();
         }
     }
@@ -155,34 +257,122 @@ impl Fs {
         debug!("Fs: REQ queue event");
         if let Err(e) = self.queue_events[1].read() {
             error!("Failed to get queue event: {:?}", e);
-        } else if self.process_queue(1) {
+        } else if self.process_queue(1) && self.device_state.needs_notification(&mut self.queues) {
             let _ = self.signal_used_queue();
         }
     }
 
-    pub(crate) fn process_queue(&mut self, queue_index: usize) -> bool {
+    pub(crate) fn handle_completion_event(&mut self) {
+        debug!("Fs: worker completion event");
+        if let Some(group) = &self.worker_group {
+            if let Err(e) = group.completion_evt().read() {
+                error!("Failed to get worker completion event: {:?}", e);
+            }
+            // A request panicking on a worker fails the device, as if it ran on this thread.
+            if let Some(payload) = group.take_panic() {
+                panic::resume_unwind(payload);
+            }
+        }
+
         let mem = match self.device_state {
             DeviceState::Activated(ref mem) => mem,
             // This should never happen, it's been already validated in the event handler.
             DeviceState::Inactive => unreachable!(),
         };
 
-        let queue = &mut self.queues[queue_index];
         let mut used_any = false;
-        while let Some(head) = queue.pop(mem) {
-            let reader = Reader::new(&mem, head.clone())
-                .map_err(FsError::QueueReader)
-                .unwrap();
-            let writer = Writer::new(&mem, head.clone())
-                .map_err(FsError::QueueWriter)
-                .unwrap();
+        for (queue_index, index) in self.completions.lock().unwrap().drain(..) {
+            METRICS.fs.requests.inc();
+            self.queues[queue_index].add_used(mem, index, 0);
+            used_any = true;
+        }
 
-            self.server
-                .handle_message(reader, writer, self.shm_region.as_ref())
-                //.map_err(FsError::ProcessQueue)
-                .unwrap();
+        // Resume the requests held back while the group had no room for them.
+        used_any |= self.process_queue(HPQ_INDEX);
+        used_any |= self.process_queue(REQ_INDEX);
+        if used_any && self.device_state.needs_notification(&mut self.queues) {
+            let _ = self.signal_used_queue();
+        }
+    }
+
+    fn handle_chain(
+        server: &Server<PassthroughFs>,
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+        shm_region: Option<&VirtioShmRegion>,
+    ) {
+        let reader = Reader::new(mem, head.clone())
+            .map_err(FsError::QueueReader)
+            .unwrap();
+        let writer = Writer::new(mem, head)
+            .map_err(FsError::QueueWriter)
+            .unwrap();
+
+        server
+            .handle_message(reader, writer, shm_region)
+            //.map_err(FsError::ProcessQueue)
+            .unwrap();
+    }
+
+    pub(crate) fn process_queue(&mut self, queue_index: usize) -> bool {
+        let mem = match self.device_state {
+            DeviceState::Activated(ref mem) => mem,
+            // This should never happen, it's been already validated in the event handler.
+            DeviceState::Inactive => unreachable!(),
+        };
 
-            queue.add_used(mem, head.index, 0);
+        let queue = &mut self.queues[queue_index];
+        let mut used_any = false;
+        loop {
+            if let Some(group) = &self.worker_group {
+                if !group.has_capacity() {
+                    break;
+                }
+            }
+            let head = match queue.pop(mem) {
+                Some(head) => head,
+                None => break,
+            };
+
+            #[cfg(feature = "fault-injection")]
+            {
+                if FAULTS.take_queue_error(uapi::VIRTIO_ID_FS) {
+                    queue.add_used(mem, head.index, 0);
+                    used_any = true;
+                    continue;
+                }
+            }
+
+            if let Some(group) = &self.worker_group {
+                // The chain borrows the guest memory, so the job attaches it again.
+                let server = self.server.clone();
+                let job_mem = mem.clone();
+                let chain = head.detach();
+                let index = chain.index();
+                let shm_region = self.shm_region.clone();
+                let completions = self.completions.clone();
+                let submitted = group.try_submit(move || {
+                    match chain.attach(&job_mem) {
+                        Some(head) => {
+                            Self::handle_chain(&server, &job_mem, head, shm_region.as_ref())
+                        }
+                        None => error!("fs: invalid descriptor chain {}", index),
+                    }
+                    completions.lock().unwrap().push((queue_index, index));
+                });
+                if !submitted {
+                    // The pool is full, the request is picked up again once a job completes.
+                    queue.go_to_previous_position();
+                    break;
+                }
+                continue;
+            }
+
+            let index = head.index;
+            Self::handle_chain(&self.server, mem, head, self.shm_region.as_ref());
+
+            METRICS.fs.requests.inc();
+            queue.add_used(mem, index, 0);
             used_any = true;
         }
 
@@ -45,6 +45,22 @@ impl Fs {
                 error!("Failed to register fs req with event manager: {:?}", e);
             });
 
+        if let Some(group) = &self.worker_group {
+            let completion_fd = group.completion_evt().as_raw_fd();
+            event_manager
+                .register(
+                    completion_fd,
+                    EpollEvent::new(EventSet::IN, completion_fd as u64),
+                    self_subscriber.clone(),
+                )
+                .unwrap_or_else(|e| {
+                    error!(
+                        "Failed to register fs worker completions with event manager: {:?}",
+                        e
+                    );
+                });
+        }
+
         event_manager
             .unregister(self.activate_evt.as_raw_fd())
             .unwrap_or_else(|e| {
@@ -59,11 +75,16 @@ impl Subscriber for Fs {
         let hpq = self.queue_events[HPQ_INDEX].as_raw_fd();
         let req = self.queue_events[REQ_INDEX].as_raw_fd();
         let activate_evt = self.activate_evt.as_raw_fd();
+        let completion = self
+            .worker_group
+            .as_ref()
+            .map(|group| group.completion_evt().as_raw_fd());
 
         if self.is_activated() {
             match source {
                 _ if source == hpq => self.handle_hpq_event(),
                 _ if source == req => self.handle_req_event(),
+                _ if Some(source) == completion => self.handle_completion_event(),
                 _ if source == activate_evt => {
                     self.handle_activate_event(event_manager);
                 }
@@ -83,4 +104,8 @@ impl Subscriber for Fs {
             self.activate_evt.as_raw_fd() as u64,
         )]
     }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
 }
@@ -9,6 +9,7 @@ use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, size_of, MaybeUninit};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -24,6 +25,10 @@ use super::super::filesystem::{
 };
 use super::super::fuse;
 use super::super::multikey::MultikeyBTreeMap;
+use super::super::{DedupStore, EscapePolicy, LookupCacheConfig, ShareCache, ShareIoConfig};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
+use crate::fd_budget::{FdAccount, FdToken};
 
 const CURRENT_DIR_CSTR: &[u8] = b".\0";
 const PARENT_DIR_CSTR: &[u8] = b"..\0";
@@ -31,6 +36,24 @@ const EMPTY_CSTR: &[u8] = b"\0";
 const PROC_CSTR: &[u8] = b"/proc/self/fd\0";
 const INIT_CSTR: &[u8] = b"init.krun\0";
 
+// Not exposed by the libc crate version we use.
+const SYS_OPENAT2: libc::c_long = 437;
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// The argument of `openat2`.
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// The alignment of the offsets and sizes of the requests served through `O_DIRECT`, covering the
+/// logical block size of any host file system.
+const DIRECT_IO_ALIGNMENT: u64 = 4096;
+
 static INIT_BINARY: &[u8] = include_bytes!("../../../../../../init/init");
 
 type Inode = u64;
@@ -47,11 +70,25 @@ struct InodeData {
     // Most of these aren't actually files but ¯\_(ツ)_/¯.
     file: File,
     refcount: AtomicU64,
+    _fd_token: Option<FdToken>,
 }
 
 struct HandleData {
     inode: Inode,
     file: RwLock<File>,
+    /// The file opened again with `O_DIRECT`, for the requests it can serve.
+    direct: Option<DirectFile>,
+    _fd_token: Option<FdToken>,
+}
+
+struct DirectFile {
+    file: File,
+    _fd_token: Option<FdToken>,
+}
+
+/// Returns whether a request at `offset` of `size` bytes may be served through `O_DIRECT`.
+fn direct_io_aligned(offset: u64, size: u32) -> bool {
+    offset % DIRECT_IO_ALIGNMENT == 0 && u64::from(size) % DIRECT_IO_ALIGNMENT == 0
 }
 
 #[repr(C, packed)]
@@ -254,6 +291,48 @@ pub struct Config {
     ///
     /// The default in `None`.
     pub mapped_volumes: Option<Vec<(PathBuf, PathBuf)>>,
+
+    /// Optional account the file descriptors held for the guest are charged to, failing with
+    /// `EMFILE` once the budget it belongs to is spent.
+    ///
+    /// The default is `None`.
+    pub fd_account: Option<FdAccount>,
+
+    /// How the FUSE client caches its lookups. See the documentation of `LookupCacheConfig` for
+    /// more details.
+    ///
+    /// The default is not to cache the names that don't exist, and to batch the lookups only
+    /// when the FUSE client expects them.
+    pub lookup_cache: LookupCacheConfig,
+
+    /// How the I/O of the files is done. See the documentation of `ShareIoConfig` for more
+    /// details.
+    ///
+    /// The default is to go through the page cache of the host, and to leave the files sparse.
+    pub io: ShareIoConfig,
+
+    /// The tag of the share, identifying it in the audit log.
+    ///
+    /// The default is empty.
+    pub tag: String,
+
+    /// How the attempts of the guest to reach outside of the share are handled. See the
+    /// documentation of `EscapePolicy` for more details.
+    ///
+    /// The default is to resolve the names as the guest passes them.
+    pub escape_policy: EscapePolicy,
+
+    /// The cache the host keeps of the metadata and the contents of the share. See the
+    /// documentation of `ShareCache` for more details.
+    ///
+    /// The default is not to cache anything on the host.
+    pub cache: Option<ShareCache>,
+
+    /// The content-addressed store the read-only DAX mappings are taken from. See the
+    /// documentation of `DedupStore` for more details.
+    ///
+    /// The default is to map the files shared.
+    pub dedup: Option<DedupStore>,
 }
 
 impl Default for Config {
@@ -267,6 +346,13 @@ impl Default for Config {
             xattr: true,
             proc_sfd_rawfd: None,
             mapped_volumes: None,
+            fd_account: None,
+            lookup_cache: Default::default(),
+            io: Default::default(),
+            tag: String::new(),
+            escape_policy: Default::default(),
+            cache: None,
+            dedup: None,
         }
     }
 }
@@ -346,6 +432,225 @@ impl PassthroughFs {
         })
     }
 
+    /// Sets how the FUSE client caches its lookups, taking effect once it's initialized.
+    pub fn set_lookup_cache(&mut self, lookup_cache: LookupCacheConfig) {
+        self.cfg.lookup_cache = lookup_cache;
+    }
+
+    /// Sets how the I/O of the files is done, taking effect for the files opened afterwards.
+    pub fn set_io_config(&mut self, io: ShareIoConfig) {
+        self.cfg.io = io;
+    }
+
+    /// Sets how the attempts of the guest to reach outside of the share are handled.
+    pub fn set_escape_policy(&mut self, escape_policy: EscapePolicy) {
+        self.cfg.escape_policy = escape_policy;
+    }
+
+    /// Sets the cache the host keeps of the metadata and the contents of the share.
+    pub fn set_cache(&mut self, cache: ShareCache) {
+        self.cfg.cache = Some(cache);
+    }
+
+    /// Sets the content-addressed store the read-only DAX mappings are taken from.
+    pub fn set_dedup(&mut self, dedup: DedupStore) {
+        self.cfg.dedup = Some(dedup);
+    }
+
+    fn cache_generation(&self) -> u64 {
+        self.cfg.cache.as_ref().map_or(0, ShareCache::generation)
+    }
+
+    fn invalidate_name(&self, parent: Inode, name: &CStr) {
+        if let Some(cache) = &self.cfg.cache {
+            cache.invalidate_name(parent, name);
+        }
+    }
+
+    fn invalidate_inode(&self, inode: Inode) {
+        if let Some(cache) = &self.cfg.cache {
+            cache.invalidate_inode(inode);
+        }
+    }
+
+    /// Resolves `name` in the directory `parent` from the cache of the share, taking a reference
+    /// on the inode it was found as, if that's still known.
+    fn cached_lookup(&self, parent: Inode, name: &CStr) -> Option<Entry> {
+        let cache = self.cfg.cache.as_ref()?;
+        let inode = cache.name(parent, name)?;
+        let attr = cache.attr(inode)?;
+
+        // Holding the read lock keeps `forget` from dropping the inode in the meantime.
+        let inodes = self.inodes.read().unwrap();
+        let data = inodes.get(&inode)?;
+        // Matches with the release store in `forget`.
+        data.refcount.fetch_add(1, Ordering::Acquire);
+
+        Some(Entry {
+            inode,
+            generation: 0,
+            attr,
+            attr_timeout: self.cfg.attr_timeout,
+            entry_timeout: self.cfg.entry_timeout,
+        })
+    }
+
+    /// Checks that resolving `name` in the directory `parent` stays within the share, for the
+    /// operation `op` of the guest.
+    fn check_name(&self, parent: Inode, name: &CStr, op: &str) -> io::Result<()> {
+        self.cfg
+            .escape_policy
+            .check_name(&self.cfg.tag, parent, name, op)
+    }
+
+    /// Opens `name` in the directory `parent` as an `O_PATH` file. In strict mode, the kernel
+    /// fails the lookup rather than following any symlink, or going above `parent` for any name
+    /// but "..", where it supports `openat2`.
+    fn open_path(&self, parent: &File, name: &CStr) -> libc::c_int {
+        let flags = libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        if self.cfg.escape_policy == EscapePolicy::Strict {
+            let mut resolve = RESOLVE_NO_SYMLINKS | RESOLVE_NO_MAGICLINKS;
+            if name.to_bytes() != b".." {
+                resolve |= RESOLVE_BENEATH;
+            }
+            let how = OpenHow {
+                flags: flags as u64,
+                mode: 0,
+                resolve,
+            };
+            // Safe because this doesn't modify any memory and the caller checks the return value.
+            let fd = unsafe {
+                libc::syscall(
+                    SYS_OPENAT2,
+                    parent.as_raw_fd(),
+                    name.as_ptr(),
+                    &how as *const OpenHow,
+                    mem::size_of::<OpenHow>(),
+                )
+            };
+            if fd >= 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS) {
+                return fd as libc::c_int;
+            }
+        }
+        // Safe because this doesn't modify any memory and the caller checks the return value.
+        unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), flags) }
+    }
+
+    /// Returns whether `file`, the parent of a directory of the share, is still within it, as
+    /// the host may have moved the directory elsewhere.
+    fn within_root(&self, file: &File) -> bool {
+        let root = match self.inodes.read().unwrap().get(&fuse::ROOT_ID) {
+            Some(root) => root.file.as_raw_fd(),
+            None => return false,
+        };
+        match (
+            std::fs::read_link(format!("/proc/self/fd/{}", root)),
+            std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())),
+        ) {
+            (Ok(root), Ok(path)) => path.starts_with(root),
+            _ => false,
+        }
+    }
+
+    /// Opens `file` again with `O_DIRECT` if the I/O bypasses the page cache of the host, it's a
+    /// regular file, and the host file system supports it.
+    fn open_direct(&self, file: &File) -> Option<DirectFile> {
+        if !self.cfg.io.direct_io {
+            return None;
+        }
+        match stat(file) {
+            Ok(st) if st.st_mode & libc::S_IFMT == libc::S_IFREG => {}
+            _ => return None,
+        }
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 {
+            return None;
+        }
+        let fd_token = self.acquire_fd().ok()?;
+        let pathname = CString::new(format!("{}", file.as_raw_fd())).ok()?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let fd = unsafe {
+            libc::openat(
+                self.proc_self_fd.as_raw_fd(),
+                pathname.as_ptr(),
+                (flags & (libc::O_ACCMODE | libc::O_APPEND)) | libc::O_DIRECT | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            debug!(
+                "Unable to open a file with O_DIRECT: {}",
+                io::Error::last_os_error()
+            );
+            return None;
+        }
+
+        Some(DirectFile {
+            // Safe because we just opened this fd.
+            file: unsafe { File::from_raw_fd(fd) },
+            _fd_token: fd_token,
+        })
+    }
+
+    /// Truncates the file `fd` to `size`, allocating the space it's extended by if the files are
+    /// preallocated. Returns the result of the system call, as `ftruncate`.
+    fn truncate(&self, fd: RawFd, size: libc::off64_t) -> libc::c_int {
+        if self.cfg.io.preallocate {
+            let mut st = MaybeUninit::<libc::stat64>::zeroed();
+            // Safe because the kernel will only write data in `st` and we check the return value.
+            if unsafe { libc::fstat64(fd, st.as_mut_ptr()) } == 0 {
+                // Safe because the kernel guarantees that the struct is now fully initialized.
+                let current = unsafe { st.assume_init() }.st_size;
+                // Safe because this doesn't modify any memory. The host file system may not
+                // support it, in which case the file is left sparse.
+                if size > current
+                    && unsafe { libc::fallocate64(fd, 0, current, size - current) } == 0
+                {
+                    return 0;
+                }
+            }
+        }
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::ftruncate(fd, size) }
+    }
+
+    /// Turns the failure `e` of a lookup into a negative entry, for the FUSE client to cache, if
+    /// the name doesn't exist and negative entries are enabled.
+    fn negative_entry(&self, e: io::Error) -> io::Result<Entry> {
+        let timeout = self.cfg.lookup_cache.negative_timeout;
+        if e.raw_os_error() != Some(libc::ENOENT) || timeout.is_zero() {
+            return Err(e);
+        }
+        // An inode of 0 tells the FUSE client the name doesn't exist.
+        Ok(Entry {
+            inode: 0,
+            generation: 0,
+            // Safe because the attributes are plain data, and ignored for negative entries.
+            attr: unsafe { mem::zeroed() },
+            attr_timeout: Duration::from_secs(0),
+            entry_timeout: timeout,
+        })
+    }
+
+    /// Accounts for a file descriptor about to be kept open for the guest.
+    fn acquire_fd(&self) -> io::Result<Option<FdToken>> {
+        match &self.cfg.fd_account {
+            Some(account) => account.acquire().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the error of a failed open, reporting the host running out of file descriptors.
+    fn open_error(&self) -> io::Error {
+        let err = io::Error::last_os_error();
+        if let Some(account) = &self.cfg.fd_account {
+            account.check_error(&err);
+        }
+        err
+    }
+
     fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
         let data = self
             .inodes
@@ -389,14 +694,30 @@ impl PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(self.open_error());
         }
 
         // Safe because we just opened this fd.
-        Ok(unsafe { File::from_raw_fd(fd) })
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        #[cfg(feature = "fault-injection")]
+        {
+            if let Ok(path) = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) {
+                if FAULTS.fs_path_fails(&path) {
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+            }
+        }
+
+        Ok(file)
     }
 
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        if let Some(entry) = self.cached_lookup(parent, name) {
+            return Ok(entry);
+        }
+        let generation = self.cache_generation();
+
         let p = self
             .inodes
             .read()
@@ -405,21 +726,33 @@ impl PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let fd = unsafe {
-            libc::openat(
-                p.file.as_raw_fd(),
-                name.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            )
-        };
+        let fd_token = self.acquire_fd()?;
+        let fd = self.open_path(&p.file, name);
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            let err = self.open_error();
+            // In strict mode, the kernel refuses to go through a symlink or above the directory.
+            if self.cfg.escape_policy == EscapePolicy::Strict
+                && matches!(err.raw_os_error(), Some(libc::ELOOP) | Some(libc::EXDEV))
+            {
+                self.cfg
+                    .escape_policy
+                    .escape_attempt(&self.cfg.tag, name, "lookup")?;
+            }
+            return Err(err);
         }
 
         // Safe because we just opened this fd.
         let f = unsafe { File::from_raw_fd(fd) };
 
+        if self.cfg.escape_policy != EscapePolicy::Off
+            && name.to_bytes() == b".."
+            && !self.within_root(&f)
+        {
+            self.cfg
+                .escape_policy
+                .escape_attempt(&self.cfg.tag, name, "lookup")?;
+        }
+
         let st = stat(&f)?;
 
         let altkey = InodeAltKey {
@@ -447,6 +780,7 @@ impl PassthroughFs {
                     inode,
                     file: f,
                     refcount: AtomicU64::new(1),
+                    _fd_token: fd_token,
                 }),
             );
 
@@ -455,6 +789,10 @@ impl PassthroughFs {
 
         debug!("do_lookup: {}, inode: {:?}", name.to_str().unwrap(), inode);
 
+        if let Some(cache) = &self.cfg.cache {
+            cache.insert_entry(generation, parent, name, inode, st);
+        }
+
         Ok(Entry {
             inode,
             generation: 0,
@@ -570,10 +908,24 @@ impl PassthroughFs {
 
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
         debug!("do_open: {:?}", inode);
-        let file = RwLock::new(self.open_inode(inode, flags as i32)?);
+        let fd_token = self.acquire_fd()?;
+        let file = self.open_inode(inode, flags as i32)?;
+        if flags & (libc::O_TRUNC as u32) != 0 {
+            self.invalidate_inode(inode);
+        }
+        let direct = if flags & (libc::O_DIRECTORY as u32) == 0 {
+            self.open_direct(&file)
+        } else {
+            None
+        };
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-        let data = HandleData { inode, file };
+        let data = HandleData {
+            inode,
+            file: RwLock::new(file),
+            direct,
+            _fd_token: fd_token,
+        };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
 
@@ -613,6 +965,11 @@ impl PassthroughFs {
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(libc::stat64, Duration)> {
+        if let Some(st) = self.cfg.cache.as_ref().and_then(|cache| cache.attr(inode)) {
+            return Ok((st, self.cfg.attr_timeout));
+        }
+        let generation = self.cache_generation();
+
         let data = self
             .inodes
             .read()
@@ -622,6 +979,9 @@ impl PassthroughFs {
             .ok_or_else(ebadf)?;
 
         let st = stat(&data.file)?;
+        if let Some(cache) = &self.cfg.cache {
+            cache.insert_attr(generation, inode, st);
+        }
 
         Ok((st, self.cfg.attr_timeout))
     }
@@ -638,6 +998,7 @@ impl PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(data.file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
+            self.invalidate_name(parent, name);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -689,6 +1050,7 @@ impl FileSystem for PassthroughFs {
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
         let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
 
+        let fd_token = self.acquire_fd()?;
         // Safe because this doesn't modify any memory and we check the return value.
         // We use `O_PATH` because we just want this for traversing the directory tree
         // and not for actually reading the contents.
@@ -700,7 +1062,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(self.open_error());
         }
 
         // Safe because we just opened this fd above.
@@ -726,10 +1088,16 @@ impl FileSystem for PassthroughFs {
                 inode: fuse::ROOT_ID,
                 file: f,
                 refcount: AtomicU64::new(2),
+                _fd_token: fd_token,
             }),
         );
 
-        let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        // Unless the lookups are batched, the FUSE client only lists directories with
+        // readdirplus when it expects the lookups of their entries to follow.
+        let mut opts = FsOptions::DO_READDIRPLUS;
+        if !self.cfg.lookup_cache.batch_lookups {
+            opts |= FsOptions::READDIRPLUS_AUTO;
+        }
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
@@ -765,6 +1133,7 @@ impl FileSystem for PassthroughFs {
 
     fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
         debug!("do_lookup: {:?}", name);
+        self.check_name(parent, name, "lookup")?;
         let init_name = unsafe { CStr::from_bytes_with_nul_unchecked(INIT_CSTR) };
 
         if self.init_inode != 0 && name == init_name {
@@ -782,6 +1151,7 @@ impl FileSystem for PassthroughFs {
             })
         } else {
             self.do_lookup(parent, name)
+                .or_else(|e| self.negative_entry(e))
         }
     }
 
@@ -826,6 +1196,7 @@ impl FileSystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "mkdir")?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -838,6 +1209,7 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::mkdirat(data.file.as_raw_fd(), name.as_ptr(), mode & !umask) };
         if res == 0 {
+            self.invalidate_name(parent, name);
             self.do_lookup(parent, name)
         } else {
             Err(io::Error::last_os_error())
@@ -845,6 +1217,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_name(parent, name, "rmdir")?;
         self.do_unlink(parent, name, libc::AT_REMOVEDIR)
     }
 
@@ -924,6 +1297,7 @@ impl FileSystem for PassthroughFs {
         flags: u32,
         umask: u32,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_name(parent, name, "create")?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -933,6 +1307,7 @@ impl FileSystem for PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
+        let fd_token = self.acquire_fd()?;
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
@@ -945,18 +1320,25 @@ impl FileSystem for PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(self.open_error());
         }
 
         // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = unsafe { File::from_raw_fd(fd) };
+        let direct = self.open_direct(&file);
 
+        self.invalidate_name(parent, name);
         let entry = self.do_lookup(parent, name)?;
+        if flags & (libc::O_TRUNC as u32) != 0 {
+            self.invalidate_inode(entry.inode);
+        }
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
             inode: entry.inode,
-            file,
+            file: RwLock::new(file),
+            direct,
+            _fd_token: fd_token,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -972,6 +1354,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_name(parent, name, "unlink")?;
         self.do_unlink(parent, name, 0)
     }
 
@@ -1000,6 +1383,34 @@ impl FileSystem for PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
+        if let Some(direct) = data
+            .direct
+            .as_ref()
+            .filter(|_| direct_io_aligned(offset, size))
+        {
+            let mut f = direct.file.try_clone()?;
+            match w.write_from(&mut f, size as usize, offset) {
+                // The buffers of the guest aren't aligned enough, and nothing was read.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                res => return res,
+            }
+        }
+
+        // The files opened for direct I/O bypass the cache of the host, so they bypass this one too.
+        if let Some(cache) = self
+            .cfg
+            .cache
+            .as_ref()
+            .filter(|cache| cache.caches_content() && data.direct.is_none())
+        {
+            let f = data.file.read().unwrap().try_clone()?;
+            let contents = cache.read(inode, offset, size as usize, |buf, offset| {
+                f.read_at(buf, offset)
+            })?;
+            w.write_all(&contents)?;
+            return Ok(contents.len());
+        }
+
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
         let mut f = data.file.read().unwrap().try_clone().unwrap();
@@ -1034,10 +1445,28 @@ impl FileSystem for PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
+        if let Some(direct) = data
+            .direct
+            .as_ref()
+            .filter(|_| direct_io_aligned(offset, size))
+        {
+            let mut f = direct.file.try_clone()?;
+            match r.read_to(&mut f, size as usize, offset) {
+                // The buffers of the guest aren't aligned enough, and nothing was written.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {}
+                res => {
+                    self.invalidate_inode(inode);
+                    return res;
+                }
+            }
+        }
+
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
         let mut f = data.file.read().unwrap().try_clone().unwrap();
-        r.read_to(&mut f, size as usize, offset)
+        let res = r.read_to(&mut f, size as usize, offset);
+        self.invalidate_inode(inode);
+        res
     }
 
     fn getattr(
@@ -1057,6 +1486,8 @@ impl FileSystem for PassthroughFs {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        // Dropped from the cache right away too, in case only some of the changes are made.
+        self.invalidate_inode(inode);
         let inode_data = self
             .inodes
             .read()
@@ -1137,13 +1568,12 @@ impl FileSystem for PassthroughFs {
         }
 
         if valid.contains(SetattrValid::SIZE) {
-            // Safe because this doesn't modify any memory and we check the return value.
             let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
+                Data::Handle(_, fd) => self.truncate(fd, attr.st_size),
                 _ => {
                     // There is no `ftruncateat` so we need to get a new fd and truncate it.
                     let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
-                    unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
+                    self.truncate(f.as_raw_fd(), attr.st_size)
                 }
             };
             if res < 0 {
@@ -1189,6 +1619,7 @@ impl FileSystem for PassthroughFs {
             }
         }
 
+        self.invalidate_inode(inode);
         self.do_getattr(inode)
     }
 
@@ -1201,6 +1632,8 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_name(olddir, oldname, "rename")?;
+        self.check_name(newdir, newname, "rename")?;
         let old_inode = self
             .inodes
             .read()
@@ -1230,6 +1663,8 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.invalidate_name(olddir, oldname);
+            self.invalidate_name(newdir, newname);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1245,6 +1680,7 @@ impl FileSystem for PassthroughFs {
         rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "mknod")?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -1267,6 +1703,7 @@ impl FileSystem for PassthroughFs {
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
+            self.invalidate_name(parent, name);
             self.do_lookup(parent, name)
         }
     }
@@ -1278,6 +1715,7 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        self.check_name(newparent, newname, "link")?;
         let data = self
             .inodes
             .read()
@@ -1307,6 +1745,8 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.invalidate_inode(inode);
+            self.invalidate_name(newparent, newname);
             self.do_lookup(newparent, newname)
         } else {
             Err(io::Error::last_os_error())
@@ -1320,6 +1760,7 @@ impl FileSystem for PassthroughFs {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "symlink")?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -1333,6 +1774,7 @@ impl FileSystem for PassthroughFs {
         let res =
             unsafe { libc::symlinkat(linkname.as_ptr(), data.file.as_raw_fd(), name.as_ptr()) };
         if res == 0 {
+            self.invalidate_name(parent, name);
             self.do_lookup(parent, name)
         } else {
             Err(io::Error::last_os_error())
@@ -1517,6 +1959,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1609,6 +2052,7 @@ impl FileSystem for PassthroughFs {
         let res = unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr()) };
 
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1644,6 +2088,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -1729,6 +2174,7 @@ impl FileSystem for PassthroughFs {
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
+            self.invalidate_inode(inode_out);
             Ok(res as usize)
         }
     }
@@ -1796,6 +2242,27 @@ impl FileSystem for PassthroughFs {
         }
 
         let file = self.open_inode(inode, open_flags as i32)?;
+        // The read-only mappings are taken from the dedup store, if any, which holds the chunk
+        // from its start.
+        let (file, foffset) = match self
+            .cfg
+            .dedup
+            .as_ref()
+            .filter(|_| open_flags == libc::O_RDONLY)
+        {
+            Some(dedup) => match dedup.chunk(&file, foffset, len) {
+                Ok(Some(chunk)) => (chunk, 0),
+                Ok(None) => (file, foffset),
+                Err(e) => {
+                    warn!(
+                        "setupmapping: unable to take the chunk from the dedup store: {}",
+                        e
+                    );
+                    (file, foffset)
+                }
+            },
+            None => (file, foffset),
+        };
         let fd = file.as_raw_fd();
 
         let ret = unsafe {
@@ -8,6 +8,7 @@ use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io;
 use std::mem::{self, MaybeUninit};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -25,7 +26,11 @@ use super::super::filesystem::{
 };
 use super::super::fuse;
 use super::super::multikey::MultikeyBTreeMap;
+use super::super::{EscapePolicy, LookupCacheConfig, ShareCache, ShareIoConfig};
 use super::linux_errno::{linux_error, LINUX_ERANGE};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
+use crate::fd_budget::{FdAccount, FdToken};
 
 const INIT_CSTR: &[u8] = b"init.krun\0";
 const XATTR_UID: &[u8] = b"virtiofs.uid\0";
@@ -60,6 +65,7 @@ struct HandleData {
     inode: Inode,
     file: RwLock<File>,
     dirstream: Mutex<DirStream>,
+    _fd_token: Option<FdToken>,
 }
 
 #[repr(C, packed)]
@@ -335,6 +341,42 @@ pub struct Config {
     ///
     /// The default in `None`.
     pub mapped_volumes: Option<Vec<(PathBuf, PathBuf)>>,
+
+    /// Optional account the file descriptors held for the guest are charged to, failing with
+    /// `EMFILE` once the budget it belongs to is spent.
+    ///
+    /// The default is `None`.
+    pub fd_account: Option<FdAccount>,
+
+    /// How the FUSE client caches its lookups. See the documentation of `LookupCacheConfig` for
+    /// more details.
+    ///
+    /// The default is not to cache the names that don't exist, and to batch the lookups only
+    /// when the FUSE client expects them.
+    pub lookup_cache: LookupCacheConfig,
+
+    /// How the I/O of the files is done. See the documentation of `ShareIoConfig` for more
+    /// details.
+    ///
+    /// The default is to go through the page cache of the host, and to leave the files sparse.
+    pub io: ShareIoConfig,
+
+    /// The tag of the share, identifying it in the audit log.
+    ///
+    /// The default is empty.
+    pub tag: String,
+
+    /// How the attempts of the guest to reach outside of the share are handled. See the
+    /// documentation of `EscapePolicy` for more details.
+    ///
+    /// The default is to resolve the names as the guest passes them.
+    pub escape_policy: EscapePolicy,
+
+    /// The cache the host keeps of the metadata and the contents of the share. See the
+    /// documentation of `ShareCache` for more details.
+    ///
+    /// The default is not to cache anything on the host.
+    pub cache: Option<ShareCache>,
 }
 
 impl Default for Config {
@@ -348,6 +390,12 @@ impl Default for Config {
             xattr: false,
             proc_sfd_rawfd: None,
             mapped_volumes: None,
+            fd_account: None,
+            lookup_cache: Default::default(),
+            io: Default::default(),
+            tag: String::new(),
+            escape_policy: Default::default(),
+            cache: None,
         }
     }
 }
@@ -396,6 +444,132 @@ impl PassthroughFs {
         })
     }
 
+    /// Sets how the FUSE client caches its lookups, taking effect once it's initialized.
+    pub fn set_lookup_cache(&mut self, lookup_cache: LookupCacheConfig) {
+        self.cfg.lookup_cache = lookup_cache;
+    }
+
+    /// Sets how the I/O of the files is done, taking effect for the files opened afterwards.
+    pub fn set_io_config(&mut self, io: ShareIoConfig) {
+        self.cfg.io = io;
+    }
+
+    /// Sets how the attempts of the guest to reach outside of the share are handled.
+    pub fn set_escape_policy(&mut self, escape_policy: EscapePolicy) {
+        self.cfg.escape_policy = escape_policy;
+    }
+
+    /// Sets the cache the host keeps of the metadata and the contents of the share.
+    pub fn set_cache(&mut self, cache: ShareCache) {
+        self.cfg.cache = Some(cache);
+    }
+
+    fn cache_generation(&self) -> u64 {
+        self.cfg.cache.as_ref().map_or(0, ShareCache::generation)
+    }
+
+    fn invalidate_name(&self, parent: Inode, name: &CStr) {
+        if let Some(cache) = &self.cfg.cache {
+            cache.invalidate_name(parent, name);
+        }
+    }
+
+    fn invalidate_inode(&self, inode: Inode) {
+        if let Some(cache) = &self.cfg.cache {
+            cache.invalidate_inode(inode);
+        }
+    }
+
+    /// Resolves `name` in the directory `parent` from the cache of the share, taking a reference
+    /// on the inode it was found as, if that's still known.
+    fn cached_lookup(&self, parent: Inode, name: &CStr) -> Option<Entry> {
+        let cache = self.cfg.cache.as_ref()?;
+        let inode = cache.name(parent, name)?;
+        let attr = cache.attr(inode)?;
+
+        // Holding the read lock keeps `forget` from dropping the inode in the meantime.
+        let inodes = self.inodes.read().unwrap();
+        let data = inodes.get(&inode)?;
+        // Matches with the release store in `forget`.
+        data.refcount.fetch_add(1, Ordering::Acquire);
+
+        Some(Entry {
+            inode,
+            generation: 0,
+            attr,
+            attr_timeout: self.cfg.attr_timeout,
+            entry_timeout: self.cfg.entry_timeout,
+        })
+    }
+
+    /// Checks that resolving `name` in the directory `parent` stays within the share, for the
+    /// operation `op` of the guest.
+    fn check_name(&self, parent: Inode, name: &CStr, op: &str) -> io::Result<()> {
+        self.cfg
+            .escape_policy
+            .check_name(&self.cfg.tag, parent, name, op)
+    }
+
+    /// Keeps the data of `file` out of the page cache of the host if the I/O bypasses it. Unlike
+    /// `O_DIRECT`, `F_NOCACHE` doesn't require the requests to be aligned.
+    fn set_nocache(&self, file: &File) {
+        if !self.cfg.io.direct_io {
+            return;
+        }
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } < 0 {
+            debug!(
+                "Unable to disable the caching of a file: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    /// Truncates the file `fd` to `size`, allocating the space it's extended by if the files are
+    /// preallocated. Returns the result of the system call, as `ftruncate`.
+    fn truncate(&self, fd: RawFd, size: libc::off_t) -> libc::c_int {
+        if self.cfg.io.preallocate {
+            let mut st = MaybeUninit::<libc::stat>::zeroed();
+            // Safe because the kernel will only write data in `st` and we check the return value.
+            if unsafe { libc::fstat(fd, st.as_mut_ptr()) } == 0 {
+                // Safe because the kernel guarantees that the struct is now fully initialized.
+                let current = unsafe { st.assume_init() }.st_size;
+                if size > current {
+                    let mut store = libc::fstore_t {
+                        fst_flags: libc::F_ALLOCATEALL,
+                        fst_posmode: libc::F_PEOFPOSMODE,
+                        fst_offset: 0,
+                        fst_length: size - current,
+                        fst_bytesalloc: 0,
+                    };
+                    // Safe because the kernel only writes to `store`. The file is left sparse if
+                    // the space can't be allocated.
+                    unsafe { libc::fcntl(fd, libc::F_PREALLOCATE, &mut store as *mut _) };
+                }
+            }
+        }
+        // Safe because this doesn't modify any memory.
+        unsafe { libc::ftruncate(fd, size) }
+    }
+
+    /// Turns the failure `e` of a lookup into a negative entry, for the FUSE client to cache, if
+    /// the name doesn't exist and negative entries are enabled.
+    fn negative_entry(&self, e: io::Error) -> io::Result<Entry> {
+        let timeout = self.cfg.lookup_cache.negative_timeout;
+        if e.raw_os_error() != Some(libc::ENOENT) || timeout.is_zero() {
+            return Err(e);
+        }
+        // An inode of 0 tells the FUSE client the name doesn't exist.
+        Ok(Entry {
+            inode: 0,
+            generation: 0,
+            // Safe because the attributes are plain data, and ignored for negative entries.
+            attr: unsafe { mem::zeroed() },
+            attr_timeout: Duration::from_secs(0),
+            entry_timeout: timeout,
+        })
+    }
+
     fn add_path(&self, inode: Inode, filepath: String) {
         debug!("add_path: inode={} filepath={}", inode, filepath);
 
@@ -478,6 +652,23 @@ impl PassthroughFs {
         }
     }
 
+    /// Accounts for a file descriptor about to be kept open for the guest.
+    fn acquire_fd(&self) -> io::Result<Option<FdToken>> {
+        match &self.cfg.fd_account {
+            Some(account) => account.acquire().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the error of a failed open, reporting the host running out of file descriptors.
+    fn open_error(&self) -> io::Error {
+        let err = io::Error::last_os_error();
+        if let Some(account) = &self.cfg.fd_account {
+            account.check_error(&err);
+        }
+        linux_error(err)
+    }
+
     fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
         // When writeback caching is enabled, the kernel may send read requests even if the
         // userspace program opened the file write-only. So we need to ensure that we have opened
@@ -509,11 +700,22 @@ impl PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
+            return Err(self.open_error());
         }
 
         // Safe because we just opened this fd.
-        Ok(unsafe { File::from_raw_fd(fd) })
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        #[cfg(feature = "fault-injection")]
+        {
+            if let Ok(path) = get_filepath(file.as_raw_fd()) {
+                if FAULTS.fs_path_fails(std::path::Path::new(&path)) {
+                    return Err(linux_error(io::Error::from_raw_os_error(libc::EIO)));
+                }
+            }
+        }
+
+        Ok(file)
     }
 
     fn lookup_host_volume(&self, name: &CStr) -> io::Result<Entry> {
@@ -546,6 +748,10 @@ impl PassthroughFs {
                 return Ok(entry);
             }
         }
+        if let Some(entry) = self.cached_lookup(parent, name) {
+            return Ok(entry);
+        }
+        let generation = self.cache_generation();
 
         let file = self.get_file(parent)?;
 
@@ -628,6 +834,10 @@ impl PassthroughFs {
 
         self.add_path(inode, get_filepath(fd)?);
 
+        if let Some(cache) = &self.cfg.cache {
+            cache.insert_entry(generation, parent, name, inode, st);
+        }
+
         Ok(Entry {
             inode,
             generation: 0,
@@ -734,7 +944,15 @@ impl PassthroughFs {
     fn do_open(&self, inode: Inode, flags: u32) -> io::Result<(Option<Handle>, OpenOptions)> {
         let flags = self.parse_open_flags(flags as i32);
 
-        let file = RwLock::new(self.open_inode(inode, flags as i32)?);
+        let fd_token = self.acquire_fd()?;
+        let file = self.open_inode(inode, flags as i32)?;
+        if flags & libc::O_TRUNC != 0 {
+            self.invalidate_inode(inode);
+        }
+        if flags & libc::O_DIRECTORY == 0 {
+            self.set_nocache(&file);
+        }
+        let file = RwLock::new(file);
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
@@ -744,6 +962,7 @@ impl PassthroughFs {
                 stream: 0,
                 offset: 0,
             }),
+            _fd_token: fd_token,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -781,8 +1000,16 @@ impl PassthroughFs {
     }
 
     fn do_getattr(&self, inode: Inode) -> io::Result<(bindings::stat64, Duration)> {
+        if let Some(st) = self.cfg.cache.as_ref().and_then(|cache| cache.attr(inode)) {
+            return Ok((st, self.cfg.attr_timeout));
+        }
+        let generation = self.cache_generation();
+
         let file = self.get_file(inode)?;
         let st = fstat(&file)?;
+        if let Some(cache) = &self.cfg.cache {
+            cache.insert_attr(generation, inode, st);
+        }
 
         Ok((st, self.cfg.attr_timeout))
     }
@@ -800,6 +1027,7 @@ impl PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
+            self.invalidate_name(parent, name);
             if let Some(entry) = entry {
                 let filepath = format!("{}/{}", self.get_path(parent)?, name.to_str().unwrap(),);
 
@@ -980,6 +1208,9 @@ impl FileSystem for PassthroughFs {
         }
 
         let mut opts = FsOptions::empty();
+        if self.cfg.lookup_cache.batch_lookups {
+            opts |= FsOptions::DO_READDIRPLUS;
+        }
         if self.cfg.writeback && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
@@ -1008,6 +1239,7 @@ impl FileSystem for PassthroughFs {
 
     fn lookup(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
         debug!("lookup: {:?}", name);
+        self.check_name(parent, name, "lookup")?;
         let init_name = unsafe { CStr::from_bytes_with_nul_unchecked(INIT_CSTR) };
 
         if self.init_inode != 0 && name == init_name {
@@ -1025,6 +1257,7 @@ impl FileSystem for PassthroughFs {
             })
         } else {
             self.do_lookup(parent, name)
+                .or_else(|e| self.negative_entry(e))
         }
     }
 
@@ -1087,6 +1320,7 @@ impl FileSystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "mkdir")?;
         let file = self.get_file(parent)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), 0o700) };
@@ -1094,6 +1328,7 @@ impl FileSystem for PassthroughFs {
             let filepath = format!("{}/{}", self.get_path(parent)?, name.to_str().unwrap(),);
             set_xattr_owner(&filepath, ctx.uid, ctx.gid);
             set_xattr_mode(&filepath, mode & !umask);
+            self.invalidate_name(parent, name);
             self.do_lookup(parent, name)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1101,6 +1336,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn rmdir(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_name(parent, name, "rmdir")?;
         self.do_unlink(ctx, parent, name, libc::AT_REMOVEDIR)
     }
 
@@ -1180,6 +1416,7 @@ impl FileSystem for PassthroughFs {
         flags: u32,
         umask: u32,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        self.check_name(parent, name, "create")?;
         let file = self.get_file(parent)?;
         let flags = self.parse_open_flags(flags as i32);
         let hostmode = if (flags & libc::O_DIRECTORY) != 0 {
@@ -1188,6 +1425,7 @@ impl FileSystem for PassthroughFs {
             0o600
         };
 
+        let fd_token = self.acquire_fd()?;
         // Safe because this doesn't modify any memory and we check the return value. We don't
         // really check `flags` because if the kernel can't handle poorly specified flags then we
         // have much bigger problems.
@@ -1200,16 +1438,22 @@ impl FileSystem for PassthroughFs {
             )
         };
         if fd < 0 {
-            return Err(linux_error(io::Error::last_os_error()));
+            return Err(self.open_error());
         }
 
         fset_xattr_owner(fd, ctx.uid, ctx.gid);
         fset_xattr_mode(fd, libc::S_IFREG as u32 | (mode & !(umask & 0o777)));
 
         // Safe because we just opened this fd.
-        let file = RwLock::new(unsafe { File::from_raw_fd(fd) });
+        let file = unsafe { File::from_raw_fd(fd) };
+        self.set_nocache(&file);
+        let file = RwLock::new(file);
 
+        self.invalidate_name(parent, name);
         let entry = self.do_lookup(parent, name)?;
+        if flags & libc::O_TRUNC != 0 {
+            self.invalidate_inode(entry.inode);
+        }
 
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         let data = HandleData {
@@ -1219,6 +1463,7 @@ impl FileSystem for PassthroughFs {
                 stream: 0,
                 offset: 0,
             }),
+            _fd_token: fd_token,
         };
 
         self.handles.write().unwrap().insert(handle, Arc::new(data));
@@ -1234,6 +1479,7 @@ impl FileSystem for PassthroughFs {
     }
 
     fn unlink(&self, ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        self.check_name(parent, name, "unlink")?;
         self.do_unlink(ctx, parent, name, 0)
     }
 
@@ -1262,6 +1508,20 @@ impl FileSystem for PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
+        if let Some(cache) = self
+            .cfg
+            .cache
+            .as_ref()
+            .filter(|cache| cache.caches_content())
+        {
+            let f = data.file.read().unwrap().try_clone()?;
+            let contents = cache.read(inode, offset, size as usize, |buf, offset| {
+                f.read_at(buf, offset)
+            })?;
+            w.write_all(&contents)?;
+            return Ok(contents.len());
+        }
+
         // This is safe because write_from uses preadv64, so the underlying file descriptor
         // offset is not affected by this operation.
         let mut f = data.file.read().unwrap().try_clone().unwrap();
@@ -1293,7 +1553,9 @@ impl FileSystem for PassthroughFs {
         // This is safe because read_to uses pwritev64, so the underlying file descriptor
         // offset is not affected by this operation.
         let mut f = data.file.read().unwrap().try_clone().unwrap();
-        r.read_to(&mut f, size as usize, offset)
+        let res = r.read_to(&mut f, size as usize, offset);
+        self.invalidate_inode(inode);
+        res
     }
 
     fn getattr(
@@ -1313,6 +1575,8 @@ impl FileSystem for PassthroughFs {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(bindings::stat64, Duration)> {
+        // Dropped from the cache right away too, in case only some of the changes are made.
+        self.invalidate_inode(inode);
         let file = self.get_file(inode)?;
 
         enum Data {
@@ -1372,13 +1636,12 @@ impl FileSystem for PassthroughFs {
         }
 
         if valid.contains(SetattrValid::SIZE) {
-            // Safe because this doesn't modify any memory and we check the return value.
             let res = match data {
-                Data::Handle(_, fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
+                Data::Handle(_, fd) => self.truncate(fd, attr.st_size),
                 _ => {
                     // There is no `ftruncateat` so we need to get a new fd and truncate it.
                     let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
-                    unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
+                    self.truncate(f.as_raw_fd(), attr.st_size)
                 }
             };
             if res < 0 {
@@ -1424,6 +1687,7 @@ impl FileSystem for PassthroughFs {
             }
         }
 
+        self.invalidate_inode(inode);
         self.do_getattr(inode)
     }
 
@@ -1436,6 +1700,8 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        self.check_name(olddir, oldname, "rename")?;
+        self.check_name(newdir, newname, "rename")?;
         let mut mflags: u32 = 0;
         if ((flags as i32) & bindings::LINUX_RENAME_NOREPLACE) != 0 {
             mflags |= libc::RENAME_EXCL;
@@ -1478,6 +1744,8 @@ impl FileSystem for PassthroughFs {
                 }
             }
 
+            self.invalidate_name(olddir, oldname);
+            self.invalidate_name(newdir, newname);
             let entry = self.do_lookup(newdir, newname)?;
             self.remove_path(
                 entry.inode,
@@ -1511,6 +1779,7 @@ impl FileSystem for PassthroughFs {
         _rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "mknod")?;
         let file = self.get_file(parent)?;
 
         let fd = unsafe {
@@ -1527,6 +1796,7 @@ impl FileSystem for PassthroughFs {
             fset_xattr_mode(fd, mode & !umask);
             fset_xattr_owner(fd, ctx.uid, ctx.gid);
             unsafe { libc::close(fd) };
+            self.invalidate_name(parent, name);
             self.do_lookup(parent, name)
         }
     }
@@ -1538,6 +1808,7 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        self.check_name(newparent, newname, "link")?;
         let newfullpath = CString::new(format!(
             "{}/{}",
             self.get_path(newparent)?,
@@ -1550,6 +1821,8 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::link(filepath.as_ptr(), newfullpath.as_ptr()) };
         if res == 0 {
+            self.invalidate_inode(inode);
+            self.invalidate_name(newparent, newname);
             self.do_lookup(newparent, newname)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1563,12 +1836,15 @@ impl FileSystem for PassthroughFs {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
+        self.check_name(parent, name, "symlink")?;
         let file = self.get_file(parent)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::symlinkat(linkname.as_ptr(), file.as_raw_fd(), name.as_ptr()) };
         if res == 0 {
+            self.invalidate_name(parent, name);
             let entry = self.do_lookup(parent, name)?;
             set_xattr_owner(&self.get_path(entry.inode)?, ctx.uid, ctx.gid);
+            self.invalidate_inode(entry.inode);
             Ok(entry)
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1742,6 +2018,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1870,6 +2147,7 @@ impl FileSystem for PassthroughFs {
         let res = unsafe { libc::fremovexattr(file.as_raw_fd(), name.as_ptr(), 0) };
 
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(linux_error(io::Error::last_os_error()))
@@ -1916,6 +2194,7 @@ impl FileSystem for PassthroughFs {
         let res = unsafe { libc::ftruncate(fd, (offset + length) as i64) };
 
         if res == 0 {
+            self.invalidate_inode(inode);
             Ok(())
         } else {
             Err(linux_error(io::Error::last_os_error()))
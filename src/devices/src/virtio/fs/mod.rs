@@ -1,6 +1,8 @@
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 mod bindings;
+mod cache;
+mod dedup;
 pub mod descriptor_utils;
 mod device;
 mod event_handler;
@@ -9,6 +11,7 @@ mod file_traits;
 mod filesystem;
 pub mod fuse;
 mod multikey;
+mod quota;
 mod server;
 
 #[cfg(target_os = "linux")]
@@ -20,8 +23,12 @@ pub mod macos;
 #[cfg(target_os = "macos")]
 pub use macos::passthrough;
 
+pub use self::cache::{ShareCache, ShareCacheConfig, CONTENT_BLOCK_SIZE};
+pub use self::dedup::{DedupStore, MAX_CHUNK_SIZE};
+pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
 pub use self::defs::uapi::VIRTIO_ID_FS as TYPE_FS;
 pub use self::device::Fs;
+pub use self::quota::ShareQuota;
 
 mod defs {
     pub const FS_DEV_ID: &str = "virtio_fs";
@@ -35,10 +42,93 @@ mod defs {
     }
 }
 
-use std::ffi::FromBytesWithNulError;
+use std::ffi::{CStr, FromBytesWithNulError};
+use std::fmt;
 use std::io;
+use std::time::Duration;
 
 use descriptor_utils::Error as DescriptorError;
+use logger::audit::AuditEvent;
+use logger::AUDIT;
+
+/// How the guest caches the lookups of an fs device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LookupCacheConfig {
+    /// How long the guest caches the names that don't exist, or zero not to cache them. The names
+    /// the guest creates replace its negative entries right away, but those created on the host
+    /// only show up once they expire.
+    pub negative_timeout: Duration,
+    /// Whether the guest always lists directories with `readdirplus`, looking up all their
+    /// entries at once, rather than only when it expects lookups to follow.
+    pub batch_lookups: bool,
+}
+
+/// How an fs device does the I/O of the files of its share.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ShareIoConfig {
+    /// Whether the data of the files bypasses the page cache of the host, so it's only cached by
+    /// the guest. The requests that aren't aligned enough still go through the page cache.
+    pub direct_io: bool,
+    /// Whether the space the files are extended by when truncated is allocated right away, rather
+    /// than left sparse.
+    pub preallocate: bool,
+}
+
+/// How an fs device guards against the guest reaching outside of its share, by passing names that
+/// go through the symlinks of the host or above the root of the share.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EscapePolicy {
+    /// The names are resolved as the guest passes them.
+    Off,
+    /// The attempts are recorded in the audit log, and allowed.
+    Audit,
+    /// The attempts are recorded in the audit log, and denied with `EACCES`. The lookups don't
+    /// follow any symlink of the host, where the kernel supports `openat2`.
+    Strict,
+}
+
+impl Default for EscapePolicy {
+    fn default() -> Self {
+        EscapePolicy::Off
+    }
+}
+
+impl EscapePolicy {
+    /// Checks that resolving `name` in the directory `parent` stays within the share tagged
+    /// `tag`, for the operation `op` of the guest. A FUSE client only passes single components,
+    /// so a name with a slash, or ".." in the root, can only come from a crafted request.
+    pub(crate) fn check_name(
+        self,
+        tag: &str,
+        parent: u64,
+        name: &CStr,
+        op: &str,
+    ) -> io::Result<()> {
+        let bytes = name.to_bytes();
+        if self == EscapePolicy::Off
+            || (!bytes.contains(&b'/') && !(parent == fuse::ROOT_ID && bytes == b".."))
+        {
+            return Ok(());
+        }
+        self.escape_attempt(tag, name, op)
+    }
+
+    /// Records the attempt of the guest to reach `name` outside of the share tagged `tag`, and
+    /// denies it in strict mode.
+    pub(crate) fn escape_attempt(self, tag: &str, name: &CStr, op: &str) -> io::Result<()> {
+        let denied = self == EscapePolicy::Strict;
+        AUDIT.record(AuditEvent::ShareEscapeAttempt {
+            tag,
+            operation: op,
+            name: &name.to_string_lossy(),
+            denied,
+        });
+        if denied {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum FsError {
@@ -46,6 +136,8 @@ pub enum FsError {
     DecodeMessage(io::Error),
     /// Failed to encode protocol messages.
     EncodeMessage(io::Error),
+    /// Failed to create the passthrough file system.
+    CreatePassthrough(io::Error),
     /// Failed to create event fd.
     EventFd(std::io::Error),
     /// One or more parameters are missing.
@@ -61,4 +153,75 @@ pub enum FsError {
     QueueWriter(DescriptorError),
 }
 
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FsError::*;
+        match self {
+            DecodeMessage(_) => write!(f, "Failed to decode the protocol message"),
+            EncodeMessage(_) => write!(f, "Failed to encode the protocol message"),
+            CreatePassthrough(_) => write!(f, "Failed to create the passthrough file system"),
+            EventFd(_) => write!(f, "Failed to create the event fd"),
+            MissingParameter => write!(f, "One or more parameters are missing"),
+            InvalidCString(_) => write!(f, "A C string parameter is invalid"),
+            InvalidHeaderLength => write!(f, "The `len` field of the header is too small"),
+            InvalidXattrSize((size, len)) => write!(
+                f,
+                "The xattr size {} doesn't match the length {} of the value",
+                size, len
+            ),
+            QueueReader(_) => write!(f, "Failed to read from the queue"),
+            QueueWriter(_) => write!(f, "Failed to write to the queue"),
+        }
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::FsError::*;
+        match self {
+            DecodeMessage(e) | EncodeMessage(e) | CreatePassthrough(e) | EventFd(e) => Some(e),
+            InvalidCString(e) => Some(e),
+            QueueReader(e) | QueueWriter(e) => Some(e),
+            MissingParameter | InvalidHeaderLength | InvalidXattrSize(_) => None,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, FsError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_policy() {
+        let name = |name: &[u8]| CStr::from_bytes_with_nul(name).unwrap().to_owned();
+        let denied = |res: io::Result<()>| res.unwrap_err().raw_os_error() == Some(libc::EACCES);
+
+        let strict = EscapePolicy::Strict;
+        assert!(strict
+            .check_name("data", 5, &name(b"file\0"), "lookup")
+            .is_ok());
+        assert!(strict
+            .check_name("data", 5, &name(b"..\0"), "lookup")
+            .is_ok());
+        assert!(denied(strict.check_name(
+            "data",
+            fuse::ROOT_ID,
+            &name(b"..\0"),
+            "lookup"
+        )));
+        assert!(denied(strict.check_name(
+            "data",
+            5,
+            &name(b"link/passwd\0"),
+            "create"
+        )));
+
+        for policy in &[EscapePolicy::Off, EscapePolicy::Audit] {
+            assert!(policy
+                .check_name("data", fuse::ROOT_ID, &name(b"../etc\0"), "lookup")
+                .is_ok());
+        }
+    }
+}
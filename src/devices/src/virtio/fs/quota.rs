@@ -0,0 +1,174 @@
+//! Quotas of the shares: the capacity the guest sees through `statfs`, rather than the totals of
+//! the host file system, and optionally a cap on the data it writes.
+//!
+//! The usage of a share is measured by walking it, at most every `USAGE_REFRESH_INTERVAL`, with
+//! the data written in between charged to it until the next walk. The cap is thus approximate,
+//! and doesn't account for the writes through DAX mappings.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::fuse::Kstatfs;
+
+/// How often the usage of a share is measured again, as walking it isn't cheap.
+const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How much of the host file system a share exposes to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShareQuota {
+    /// The size of the share reported to the guest, in bytes.
+    pub capacity: u64,
+    /// Whether the writes beyond the capacity fail with `ENOSPC`, rather than only being
+    /// reported.
+    pub enforce: bool,
+}
+
+struct Usage {
+    measured: Option<Instant>,
+    bytes: u64,
+}
+
+/// Keeps track of the usage of a share against its quota.
+pub(crate) struct QuotaTracker {
+    quota: ShareQuota,
+    root: PathBuf,
+    usage: Mutex<Usage>,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(root: PathBuf, quota: ShareQuota) -> Self {
+        QuotaTracker {
+            quota,
+            root,
+            usage: Mutex::new(Usage {
+                measured: None,
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Returns the bytes used by the share, measuring them again if they're stale.
+    fn used(&self) -> u64 {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.measured.map_or(true, |measured| {
+            measured.elapsed() >= USAGE_REFRESH_INTERVAL
+        }) {
+            usage.bytes = disk_usage(&self.root);
+            usage.measured = Some(Instant::now());
+        }
+        usage.bytes
+    }
+
+    /// Checks that `len` more bytes fit in the share, failing with `ENOSPC` otherwise if the
+    /// quota is enforced.
+    pub(crate) fn reserve(&self, len: u64) -> io::Result<()> {
+        if self.quota.enforce && self.used().saturating_add(len) > self.quota.capacity {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        Ok(())
+    }
+
+    /// Charges `len` bytes written to the share, until its usage is measured again.
+    pub(crate) fn charge(&self, len: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes = usage.bytes.saturating_add(len);
+    }
+
+    /// Replaces the totals of the host file system in `st` with those of the share. The free
+    /// space doesn't exceed the one left on the host.
+    pub(crate) fn apply(&self, st: &mut Kstatfs) {
+        let block_size = u64::from(if st.frsize != 0 { st.frsize } else { st.bsize }).max(1);
+        let free = self.quota.capacity.saturating_sub(self.used()) / block_size;
+        st.blocks = self.quota.capacity / block_size;
+        st.bfree = st.bfree.min(free);
+        st.bavail = st.bavail.min(free);
+    }
+}
+
+/// Returns the bytes allocated to the files under `root`, on the same file system, counting the
+/// files with several links once. The entries that can't be read are skipped.
+fn disk_usage(root: &Path) -> u64 {
+    let root_dev = match fs::symlink_metadata(root) {
+        Ok(metadata) => metadata.dev(),
+        Err(_) => return 0,
+    };
+
+    let mut total = 0;
+    let mut linked = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.dev() == root_dev => metadata,
+            _ => continue,
+        };
+        if metadata.nlink() > 1
+            && !metadata.is_dir()
+            && !linked.insert((metadata.dev(), metadata.ino()))
+        {
+            continue;
+        }
+        total += metadata.blocks() * 512;
+
+        if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(&path) {
+                pending.extend(entries.filter_map(|entry| entry.ok().map(|entry| entry.path())));
+            }
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_quota_tracker() {
+        let root = std::env::temp_dir().join(format!("krun-quota-{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        let mut file = fs::File::create(root.join("dir/file")).unwrap();
+        file.write_all(&[1; 64 * 1024]).unwrap();
+        file.sync_all().unwrap();
+        fs::hard_link(root.join("dir/file"), root.join("link")).unwrap();
+
+        let used = disk_usage(&root);
+        // The file is counted once, despite its two links.
+        assert!((64 * 1024..128 * 1024).contains(&used));
+
+        let tracker = QuotaTracker::new(
+            root.clone(),
+            ShareQuota {
+                capacity: used + 8192,
+                enforce: true,
+            },
+        );
+        let mut st = Kstatfs {
+            blocks: 1 << 30,
+            bfree: 1 << 29,
+            bavail: 1 << 29,
+            bsize: 4096,
+            frsize: 4096,
+            ..Default::default()
+        };
+        tracker.apply(&mut st);
+        assert_eq!(st.blocks, (used + 8192) / 4096);
+        assert_eq!(st.bfree, 2);
+        assert_eq!(st.bavail, 2);
+
+        assert!(tracker.reserve(8192).is_ok());
+        tracker.charge(8192);
+        assert_eq!(
+            tracker.reserve(1).unwrap_err().raw_os_error(),
+            Some(libc::ENOSPC)
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -7,6 +7,7 @@ use std::ffi::CStr;
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
 
 use vm_memory::ByteValued;
 
@@ -17,12 +18,54 @@ use super::filesystem::{
     ZeroCopyWriter,
 };
 use super::fuse::*;
+use super::quota::QuotaTracker;
 use super::{FsError as Error, Result};
 use crate::virtio::VirtioShmRegion;
 
 const MAX_BUFFER_SIZE: u32 = 1 << 20;
 const BUFFER_HEADER_SIZE: u32 = 0x1000;
 const DIRENT_PADDING: [u8; 8] = [0; 8];
+/// Enough for the two names of a rename, each up to `NAME_MAX` bytes and nul terminated.
+const INLINE_PAYLOAD_LEN: usize = 512;
+
+/// The variable length payload of a request, like the names it carries. Payloads fitting in
+/// `INLINE_PAYLOAD_LEN` bytes are read on the stack, so most requests don't allocate.
+enum Payload {
+    Inline([u8; INLINE_PAYLOAD_LEN], usize),
+    Heap(Vec<u8>),
+}
+
+impl Payload {
+    fn read(r: &mut Reader, len: usize) -> Result<Payload> {
+        let mut payload = if len <= INLINE_PAYLOAD_LEN {
+            Payload::Inline([0; INLINE_PAYLOAD_LEN], len)
+        } else {
+            Payload::Heap(vec![0; len])
+        };
+        r.read_exact(&mut payload).map_err(Error::DecodeMessage)?;
+        Ok(payload)
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Inline(buf, len) => &buf[..*len],
+            Payload::Heap(buf) => buf,
+        }
+    }
+}
+
+impl DerefMut for Payload {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Payload::Inline(buf, len) => &mut buf[..*len],
+            Payload::Heap(buf) => buf,
+        }
+    }
+}
 
 struct ZCReader<'a>(Reader<'a>);
 
@@ -56,13 +99,41 @@ impl<'a> io::Write for ZCWriter<'a> {
     }
 }
 
+// The mode of fallocate deallocating the range, as passed by the guest.
+const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+
 pub struct Server<F: FileSystem + Sync> {
     fs: F,
+    quota: Option<QuotaTracker>,
 }
 
 impl<F: FileSystem + Sync> Server<F> {
     pub fn new(fs: F) -> Server<F> {
-        Server { fs }
+        Server { fs, quota: None }
+    }
+
+    pub fn fs_mut(&mut self) -> &mut F {
+        &mut self.fs
+    }
+
+    /// Reports the usage of the share against `quota`, and caps the data written to it if
+    /// enforced.
+    pub(crate) fn set_quota(&mut self, quota: QuotaTracker) {
+        self.quota = Some(quota);
+    }
+
+    /// Checks that `len` more bytes fit in the quota of the share, if any.
+    fn reserve(&self, len: u64) -> io::Result<()> {
+        self.quota
+            .as_ref()
+            .map_or(Ok(()), |quota| quota.reserve(len))
+    }
+
+    /// Charges `len` bytes written to the quota of the share, if any.
+    fn charge(&self, len: u64) {
+        if let Some(quota) = &self.quota {
+            quota.charge(len);
+        }
     }
 
     #[allow(clippy::cognitive_complexity)]
@@ -149,11 +220,9 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
 
-        let mut buf = vec![0u8; namelen];
+        let buf = Payload::read(&mut r, namelen)?;
 
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
-
-        let name = bytes_to_cstr(buf.as_ref())?;
+        let name = bytes_to_cstr(&buf)?;
 
         match self
             .fs
@@ -256,9 +325,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let len = (in_header.len as usize)
             .checked_sub(size_of::<InHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut buf = vec![0; len];
-
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let buf = Payload::read(&mut r, len)?;
 
         // We want to include the '\0' byte in the first slice.
         let split_pos = buf
@@ -293,9 +360,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(size_of::<MknodIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         match self.fs.mknod(
             Context::from(in_header),
@@ -321,9 +386,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(size_of::<MkdirIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         match self.fs.mkdir(
             Context::from(in_header),
@@ -345,9 +408,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let namelen = (in_header.len as usize)
             .checked_sub(size_of::<InHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         match self.fs.unlink(
             Context::from(in_header),
@@ -363,9 +424,7 @@ impl<F: FileSystem + Sync> Server<F> {
         let namelen = (in_header.len as usize)
             .checked_sub(size_of::<InHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         match self.fs.rmdir(
             Context::from(in_header),
@@ -390,9 +449,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(msg_size))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut buf = vec![0; buflen];
-
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let buf = Payload::read(&mut r, buflen)?;
 
         // We want to include the '\0' byte in the first slice.
         let split_pos = buf
@@ -438,9 +495,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(size_of::<LinkIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         match self.fs.link(
             Context::from(in_header),
@@ -550,6 +605,10 @@ impl<F: FileSystem + Sync> Server<F> {
             );
         }
 
+        if let Err(e) = self.reserve(size.into()) {
+            return reply_error(e, in_header.unique, w);
+        }
+
         let owner = if write_flags & WRITE_LOCKOWNER != 0 {
             Some(lock_owner)
         } else {
@@ -574,6 +633,7 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
         ) {
             Ok(count) => {
+                self.charge(count as u64);
                 let out = WriteOut {
                     size: count as u32,
                     ..Default::default()
@@ -590,7 +650,13 @@ impl<F: FileSystem + Sync> Server<F> {
             .fs
             .statfs(Context::from(in_header), in_header.nodeid.into())
         {
-            Ok(st) => reply_ok(Some(Kstatfs::from(st)), None, in_header.unique, w),
+            Ok(st) => {
+                let mut out = Kstatfs::from(st);
+                if let Some(quota) = &self.quota {
+                    quota.apply(&mut out);
+                }
+                reply_ok(Some(out), None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -650,9 +716,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(size_of::<SetxattrIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut buf = vec![0; len];
-
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let buf = Payload::read(&mut r, len)?;
 
         // We want to include the '\0' byte in the first slice.
         let split_pos = buf
@@ -686,9 +750,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .and_then(|l| l.checked_sub(size_of::<GetxattrIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
-        let mut name = vec![0; namelen];
-
-        r.read_exact(&mut name).map_err(Error::DecodeMessage)?;
+        let name = Payload::read(&mut r, namelen)?;
 
         if size > MAX_BUFFER_SIZE {
             return reply_error(
@@ -750,9 +812,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .checked_sub(size_of::<InHeader>())
             .ok_or(Error::InvalidHeaderLength)?;
 
-        let mut buf = vec![0; namelen];
-
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let buf = Payload::read(&mut r, namelen)?;
 
         let name = bytes_to_cstr(&buf)?;
 
@@ -1030,9 +1090,7 @@ impl<F: FileSystem + Sync> Server<F> {
             .and_then(|l| l.checked_sub(size_of::<CreateIn>()))
             .ok_or(Error::InvalidHeaderLength)?;
 
-        let mut buf = vec![0; namelen];
-
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+        let buf = Payload::read(&mut r, namelen)?;
 
         let name = bytes_to_cstr(&buf)?;
 
@@ -1158,6 +1216,14 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        // Punching holes frees space rather than taking it.
+        let allocating = mode & FALLOC_FL_PUNCH_HOLE == 0;
+        if allocating {
+            if let Err(e) = self.reserve(length) {
+                return reply_error(e, in_header.unique, w);
+            }
+        }
+
         match self.fs.fallocate(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1166,7 +1232,12 @@ impl<F: FileSystem + Sync> Server<F> {
             offset,
             length,
         ) {
-            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Ok(()) => {
+                if allocating {
+                    self.charge(length);
+                }
+                reply_ok(None::<u8>, None, in_header.unique, w)
+            }
             Err(e) => reply_error(e, in_header.unique, w),
         }
     }
@@ -1204,6 +1275,10 @@ impl<F: FileSystem + Sync> Server<F> {
             ..
         } = r.read_obj().map_err(Error::DecodeMessage)?;
 
+        if let Err(e) = self.reserve(len) {
+            return reply_error(e, in_header.unique, w);
+        }
+
         match self.fs.copyfilerange(
             Context::from(in_header),
             in_header.nodeid.into(),
@@ -1216,6 +1291,7 @@ impl<F: FileSystem + Sync> Server<F> {
             flags,
         ) {
             Ok(count) => {
+                self.charge(count as u64);
                 let out = WriteOut {
                     size: count as u32,
                     ..Default::default()
@@ -1416,3 +1492,38 @@ fn add_dirent(
         Ok(total_len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtio::fs::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+    #[test]
+    fn test_payload_read() {
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        memory
+            .write_slice(&[b'a'; 0x800], GuestAddress(0x1000))
+            .unwrap();
+
+        for len in &[0, 16, INLINE_PAYLOAD_LEN, INLINE_PAYLOAD_LEN + 1] {
+            let chain = create_descriptor_chain(
+                &memory,
+                GuestAddress(0x0),
+                GuestAddress(0x1000),
+                vec![(DescriptorType::Readable, 0x800)],
+                0,
+            )
+            .unwrap();
+            let mut reader = Reader::new(&memory, chain).unwrap();
+
+            let payload = Payload::read(&mut reader, *len).unwrap();
+            match payload {
+                Payload::Inline(..) => assert!(*len <= INLINE_PAYLOAD_LEN),
+                Payload::Heap(_) => assert!(*len > INLINE_PAYLOAD_LEN),
+            }
+            assert_eq!(&payload[..], &vec![b'a'; *len][..]);
+            assert_eq!(reader.bytes_read(), *len);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checked access to the ranges of guest memory the guest hands to the devices.
+//!
+//! The addresses and lengths of the descriptors are controlled by the guest, so a range is only
+//! accessed once it's known to lie within a single region of the guest memory, the regions being
+//! mapped apart from each other in the host. Computing host addresses from the start of a region
+//! and a length taken from the guest would otherwise let a malicious driver reach the host memory
+//! past it.
+//!
+//! The ranges are handed out as `VolatileSlice`s, as the guest may change the memory behind them
+//! at any time. Raw host addresses are only for the system calls taking them, such as `madvise()`.
+
+use std::fmt;
+use std::ops::Deref;
+
+use vm_memory::{
+    Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, VolatileMemory,
+    VolatileSlice,
+};
+
+/// Errors associated with accessing a range of guest memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The range wraps around the end of the address space.
+    Overflow(GuestAddress, usize),
+    /// The range isn't within a single region of the guest memory.
+    OutOfBounds(GuestAddress, usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            Overflow(addr, len) => write!(
+                f,
+                "Guest memory range at 0x{:x} of {} bytes overflows",
+                addr.raw_value(),
+                len
+            ),
+            OutOfBounds(addr, len) => write!(
+                f,
+                "Guest memory range at 0x{:x} of {} bytes is out of bounds",
+                addr.raw_value(),
+                len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the `len` bytes of guest memory at `addr`, checked to lie within a single region.
+pub fn checked_slice(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    len: usize,
+) -> Result<VolatileSlice> {
+    addr.checked_add(len as u64)
+        .ok_or(Error::Overflow(addr, len))?;
+    let region = mem.find_region(addr).ok_or(Error::OutOfBounds(addr, len))?;
+    // Can't underflow, as the region holds `addr`.
+    let offset = addr.raw_value() - region.start_addr().raw_value();
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= region.len() => {}
+        _ => return Err(Error::OutOfBounds(addr, len)),
+    }
+    region
+        .deref()
+        .get_slice(offset as usize, len)
+        .map_err(|_| Error::OutOfBounds(addr, len))
+}
+
+/// Checks that the `len` bytes of guest memory at `addr` lie within a single region.
+pub fn check_range(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> Result<()> {
+    checked_slice(mem, addr, len).map(|_| ())
+}
+
+/// Returns the host address of the `len` bytes of guest memory at `addr`, checked to lie within a
+/// single region, so the host can access all of them from it.
+pub fn host_address(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> Result<*mut u8> {
+    checked_slice(mem, addr, len).map(|slice| slice.as_ptr())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use vm_memory::Bytes;
+
+    /// A xorshift generator, seeded so the failures of the fuzz tests can be reproduced.
+    pub(crate) struct FuzzRng(u64);
+
+    impl FuzzRng {
+        pub(crate) fn new(seed: u64) -> Self {
+            FuzzRng(seed | 1)
+        }
+
+        pub(crate) fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Returns a value biased towards the edges of `0..=max`, where the bounds checks are.
+        pub(crate) fn edgy(&mut self, max: u64) -> u64 {
+            match self.next_u64() % 4 {
+                0 => self.next_u64() % 16,
+                1 => max.saturating_sub(self.next_u64() % 16),
+                2 => self.next_u64(),
+                _ => self.next_u64() % max.saturating_add(1).max(1),
+            }
+        }
+    }
+
+    const REGIONS: [(u64, usize); 3] = [(0, 0x1000), (0x1000, 0x2000), (0x10000, 0x1000)];
+
+    fn mem() -> GuestMemoryMmap {
+        let ranges: Vec<_> = REGIONS
+            .iter()
+            .map(|&(start, size)| (GuestAddress(start), size))
+            .collect();
+        GuestMemoryMmap::from_ranges(&ranges).unwrap()
+    }
+
+    #[test]
+    fn test_checked_slice() {
+        let mem = mem();
+
+        assert_eq!(
+            checked_slice(&mem, GuestAddress(0), 0x1000).unwrap().len(),
+            0x1000
+        );
+        assert_eq!(
+            checked_slice(&mem, GuestAddress(0x2fff), 1).unwrap().len(),
+            1
+        );
+        assert!(checked_slice(&mem, GuestAddress(0x3000), 0).is_err());
+        assert_eq!(
+            host_address(&mem, GuestAddress(0x1800), 0x10).unwrap(),
+            unsafe {
+                mem.get_host_address(GuestAddress(0x1000))
+                    .unwrap()
+                    .add(0x800)
+            }
+        );
+
+        // Adjacent in the guest, but not in the host.
+        assert_eq!(
+            check_range(&mem, GuestAddress(0xf00), 0x200),
+            Err(Error::OutOfBounds(GuestAddress(0xf00), 0x200))
+        );
+        // Across the hole between two regions.
+        assert!(check_range(&mem, GuestAddress(0x2000), 0xf000).is_err());
+        assert_eq!(
+            check_range(&mem, GuestAddress(u64::MAX), 2),
+            Err(Error::Overflow(GuestAddress(u64::MAX), 2))
+        );
+    }
+
+    #[test]
+    fn test_fuzz_checked_slice() {
+        let mem = mem();
+        let mut rng = FuzzRng::new(0x6b72_756e);
+
+        for _ in 0..100_000 {
+            let addr = GuestAddress(rng.edgy(0x11000));
+            let len = rng.edgy(0x3000) as usize;
+
+            let expected = REGIONS.iter().any(|&(start, size)| {
+                addr.raw_value() >= start
+                    && addr.raw_value() < start + size as u64
+                    && addr
+                        .raw_value()
+                        .checked_add(len as u64)
+                        .map_or(false, |end| end <= start + size as u64)
+            });
+            match checked_slice(&mem, addr, len) {
+                Ok(slice) => {
+                    assert!(expected, "{:?}+{:#x} accepted", addr, len);
+                    assert_eq!(slice.len(), len);
+                    // The whole slice is accessible, through its first and last bytes.
+                    if len > 0 {
+                        let _: u8 = slice.read_obj(0).unwrap();
+                        let _: u8 = slice.read_obj(len - 1).unwrap();
+                    }
+                }
+                Err(_) => assert!(!expected, "{:?}+{:#x} refused", addr, len),
+            }
+        }
+    }
+}
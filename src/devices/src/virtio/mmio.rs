@@ -49,19 +49,29 @@ pub struct MmioTransport {
     pub(crate) queue_select: u32,
     pub(crate) device_status: u32,
     pub(crate) config_generation: u32,
+    // The changes of the configuration space made by the device at runtime.
+    device_config_generation: Option<ConfigGeneration>,
     mem: GuestMemoryMmap,
     pub(crate) interrupt_status: Arc<AtomicUsize>,
     queue_evts: HashMap<u32, EventFd>,
     shm_region_select: u32,
+    // Whether the device was found failed, after panicking with its lock held.
+    failed: bool,
+    // The guest memory the device may access, if restricted, which the driver must accept.
+    dma_windows: Option<DmaWindows>,
+    access_platform_acked: bool,
 }
 
 impl MmioTransport {
     /// Constructs a new MMIO transport for the given virtio device.
     pub fn new(mem: GuestMemoryMmap, device: Arc<Mutex<dyn VirtioDevice>>) -> MmioTransport {
-        let interrupt_status = device
-            .lock()
-            .expect("Poisoned device lock")
-            .interrupt_status();
+        let (interrupt_status, device_config_generation) = {
+            let locked_device = device.lock().expect("Poisoned device lock");
+            (
+                locked_device.interrupt_status(),
+                locked_device.config_generation(),
+            )
+        };
 
         MmioTransport {
             device,
@@ -70,13 +80,23 @@ impl MmioTransport {
             queue_select: 0,
             device_status: device_status::INIT,
             config_generation: 0,
+            device_config_generation,
             mem,
             interrupt_status,
             queue_evts: HashMap::new(),
             shm_region_select: 0,
+            failed: false,
+            dma_windows: None,
+            access_platform_acked: false,
         }
     }
 
+    /// Restricts the guest memory the device accesses through its queues to `dma_windows`. The
+    /// device then requires the driver to accept VIRTIO_F_ACCESS_PLATFORM.
+    pub fn set_dma_windows(&mut self, dma_windows: Option<DmaWindows>) {
+        self.dma_windows = dma_windows;
+    }
+
     pub fn locked_device(&self) -> MutexGuard<dyn VirtioDevice + 'static> {
         self.device.lock().expect("Poisoned device lock")
     }
@@ -90,6 +110,16 @@ impl MmioTransport {
         self.queue_evts.insert(id, queue_evt);
     }
 
+    /// Returns whether the device panicked, poisoning its lock. The driver then finds it needing
+    /// a reset, and the device is no longer accessed.
+    fn is_failed(&mut self) -> bool {
+        if !self.failed && self.device.is_poisoned() {
+            error!("virtio mmio device failed, ignoring further accesses");
+            self.failed = true;
+        }
+        self.failed
+    }
+
     fn check_device_status(&self, set: u32, clr: u32) -> bool {
         self.device_status & (set | clr) == set
     }
@@ -148,6 +178,7 @@ impl MmioTransport {
         }
         self.features_select = 0;
         self.acked_features_select = 0;
+        self.access_platform_acked = false;
         self.queue_select = 0;
         self.interrupt_status.store(0, Ordering::SeqCst);
         self.device_status = device_status::INIT;
@@ -156,7 +187,11 @@ impl MmioTransport {
         //   than supurious wakeups.
         // . Do not reset config_generation and keep it monotonically increasing
         for queue in self.locked_device().queues_mut() {
+            let recorder = queue.recorder.take();
+            let watermark = queue.watermark.take();
             *queue = Queue::new(queue.get_max_size());
+            queue.set_recorder(recorder);
+            queue.set_watermark(watermark);
         }
     }
 
@@ -179,7 +214,22 @@ impl MmioTransport {
                 self.device_status = status;
             }
             FEATURES_OK if self.device_status == (ACKNOWLEDGE | DRIVER) => {
+                // The driver not bouncing its buffers would find the device unable to use them.
+                if self.dma_windows.is_some() && !self.access_platform_acked {
+                    warn!("driver refused VIRTIO_F_ACCESS_PLATFORM, refusing the features");
+                    return;
+                }
                 self.device_status = status;
+                // The layout of the queues and their notifications are settled along with the
+                // features.
+                let mut device = self.locked_device();
+                let packed = device.acked_features() & (1 << VIRTIO_F_RING_PACKED) != 0;
+                let event_idx = device.acked_features() & (1 << VIRTIO_RING_F_EVENT_IDX) != 0;
+                for queue in device.queues_mut() {
+                    queue.set_packed(packed);
+                    queue.set_event_idx(event_idx);
+                    queue.set_dma_windows(self.dma_windows.clone());
+                }
             }
             DRIVER_OK if self.device_status == (ACKNOWLEDGE | DRIVER | FEATURES_OK) => {
                 self.device_status = status;
@@ -225,6 +275,19 @@ impl MmioTransport {
 
 impl BusDevice for MmioTransport {
     fn read(&mut self, _vcpuid: u64, offset: u64, data: &mut [u8]) {
+        if self.is_failed() {
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+            if offset == 0x70 && data.len() == 4 {
+                byte_order::write_le_u32(
+                    data,
+                    self.device_status | device_status::DEVICE_NEEDS_RESET,
+                );
+            }
+            return;
+        }
+
         match offset {
             0x00..=0xff if data.len() == 4 => {
                 let v = match offset {
@@ -238,6 +301,9 @@ impl BusDevice for MmioTransport {
                             .avail_features_by_page(self.features_select);
                         if self.features_select == 1 {
                             features |= 0x1; // enable support of VirtIO Version 1
+                            if self.dma_windows.is_some() {
+                                features |= 1 << (VIRTIO_F_ACCESS_PLATFORM - 32);
+                            }
                         }
                         features
                     }
@@ -245,7 +311,11 @@ impl BusDevice for MmioTransport {
                     0x44 => self.with_queue(0, |q| q.ready as u32),
                     0x60 => self.interrupt_status.load(Ordering::SeqCst) as u32,
                     0x70 => self.device_status,
-                    0xfc => self.config_generation,
+                    0xfc => self.config_generation.wrapping_add(
+                        self.device_config_generation
+                            .as_ref()
+                            .map_or(0, ConfigGeneration::get),
+                    ),
                     0xb0..=0xbc => {
                         // For no SHM region or invalid region the kernel looks for length of -1
                         let (shm_base, shm_len) = if self.shm_region_select != 0 {
@@ -294,6 +364,10 @@ impl BusDevice for MmioTransport {
             *v = (*v & !0xffff_ffff) | u64::from(x)
         }
 
+        if self.is_failed() {
+            return;
+        }
+
         match offset {
             0x00..=0xff if data.len() == 4 => {
                 let v = byte_order::read_le_u32(data);
@@ -304,6 +378,12 @@ impl BusDevice for MmioTransport {
                             device_status::DRIVER,
                             device_status::FEATURES_OK | device_status::FAILED,
                         ) {
+                            let mut v = v;
+                            if self.acked_features_select == 1 && self.dma_windows.is_some() {
+                                let access_platform = 1 << (VIRTIO_F_ACCESS_PLATFORM - 32);
+                                self.access_platform_acked = v & access_platform != 0;
+                                v &= !access_platform;
+                            }
                             self.locked_device()
                                 .ack_features_by_page(self.acked_features_select, v);
                         } else {
@@ -386,6 +466,7 @@ pub(crate) mod tests {
         queues: Vec<Queue>,
         device_activated: bool,
         config_bytes: [u8; 0xeff],
+        config_generation: ConfigGeneration,
     }
 
     impl DummyDevice {
@@ -402,6 +483,7 @@ pub(crate) mod tests {
                 queues: vec![Queue::new(16), Queue::new(32)],
                 device_activated: false,
                 config_bytes: [0; 0xeff],
+                config_generation: ConfigGeneration::default(),
             }
         }
 
@@ -467,6 +549,10 @@ pub(crate) mod tests {
         fn is_activated(&self) -> bool {
             self.device_activated
         }
+
+        fn config_generation(&self) -> Option<ConfigGeneration> {
+            Some(self.config_generation.clone())
+        }
     }
 
     fn set_device_status(d: &mut MmioTransport, status: u32) {
@@ -584,6 +670,69 @@ pub(crate) mod tests {
         assert_eq!(buf[..], buf_copy[..]);
     }
 
+    #[test]
+    fn test_config_change() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let dummy_dev = Arc::new(Mutex::new(DummyDevice::new()));
+        let mut d = MmioTransport::new(m, dummy_dev.clone());
+        let mut buf = vec![0; 4];
+
+        d.config_generation = 5;
+        {
+            let dummy = dummy_dev.lock().unwrap();
+            signal_config_change(
+                &dummy.config_generation,
+                &dummy.interrupt_status,
+                &dummy.interrupt_evt,
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(dummy.interrupt_evt.read().unwrap(), 1);
+        }
+
+        // The changes made by the device are counted in the generation read by the driver.
+        d.read(0, 0xfc, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 6);
+        d.read(0, 0x60, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), VIRTIO_MMIO_INT_CONFIG);
+    }
+
+    #[test]
+    fn test_restricted_dma() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let dummy_dev = Arc::new(Mutex::new(DummyDevice::new()));
+        let mut d = MmioTransport::new(m, dummy_dev);
+        let windows = DmaWindows::new(vec![DmaWindow {
+            start: GuestAddress(0x800),
+            size: 0x800,
+        }]);
+        d.set_dma_windows(Some(windows.clone()));
+        let mut buf = vec![0; 4];
+
+        write_le_u32(&mut buf[..], 1);
+        d.write(0, 0x14, &buf[..]);
+        d.read(0, 0x10, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0x3);
+
+        set_device_status(&mut d, device_status::ACKNOWLEDGE | device_status::DRIVER);
+        // The features are refused until the driver accepts to bounce its buffers.
+        let features_ok =
+            device_status::ACKNOWLEDGE | device_status::DRIVER | device_status::FEATURES_OK;
+        set_device_status(&mut d, features_ok);
+        assert_eq!(
+            d.device_status,
+            device_status::ACKNOWLEDGE | device_status::DRIVER
+        );
+
+        d.acked_features_select = 1;
+        write_le_u32(&mut buf[..], 0x3);
+        d.write(0, 0x20, &buf[..]);
+        set_device_status(&mut d, features_ok);
+        assert_eq!(d.device_status, features_ok);
+        assert_eq!(d.locked_device().queues()[0].dma_windows, Some(windows));
+    }
+
     #[test]
     #[allow(clippy::cognitive_complexity)]
     fn test_bus_device_write() {
@@ -885,6 +1034,36 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_bus_device_failed() {
+        let m = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let dummy_dev = Arc::new(Mutex::new(DummyDevice::new()));
+        let mut d = MmioTransport::new(m, dummy_dev.clone());
+        let mut buf = vec![0; 4];
+        activate_device(&mut d);
+
+        // Panic with the lock of the device held.
+        let _ = std::thread::spawn(move || {
+            let _guard = dummy_dev.lock().unwrap();
+            panic!("device failure");
+        })
+        .join();
+
+        d.read(0, 0x70, &mut buf[..]);
+        assert_eq!(
+            read_le_u32(&buf[..]),
+            d.device_status | device_status::DEVICE_NEEDS_RESET
+        );
+        d.read(0, 0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+
+        // Writes are ignored.
+        let status = d.device_status;
+        write_le_u32(&mut buf[..], 0);
+        d.write(0, 0x70, &buf[..]);
+        assert_eq!(d.device_status, status);
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
@@ -8,19 +8,31 @@
 //! Implements virtio devices, queues, and transport mechanisms.
 use std;
 use std::any::Any;
+use std::fmt;
 use std::io::Error as IOError;
 
 pub mod balloon;
+pub mod block;
 pub mod console;
+pub mod crypto;
 pub mod device;
+pub mod dma;
 pub mod fs;
+pub mod guest_memory;
 mod mmio;
 mod queue;
+pub mod record;
+pub mod replay;
 pub mod vsock;
+pub mod watermark;
+pub mod worker_pool;
 
 pub use self::balloon::*;
+pub use self::block::*;
 pub use self::console::*;
+pub use self::crypto::*;
 pub use self::device::*;
+pub use self::dma::*;
 pub use self::fs::*;
 pub use self::mmio::*;
 pub use self::queue::*;
@@ -41,6 +53,7 @@ mod device_status {
     pub const FAILED: u32 = 128;
     pub const FEATURES_OK: u32 = 8;
     pub const DRIVER_OK: u32 = 4;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
 }
 
 /// Types taken from linux/virtio_ids.h.
@@ -53,6 +66,23 @@ pub const TYPE_BLOCK: u32 = 2;
 pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
 pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x02;
 
+/// The device accesses the guest memory through the platform's restrictions, which the driver
+/// honors by bouncing the buffers through the memory the device may access. See
+/// linux/virtio_config.h.
+pub const VIRTIO_F_ACCESS_PLATFORM: u32 = 33;
+
+/// The driver may describe the buffers of a request with a table of descriptors held by a single
+/// descriptor of the queue. See linux/virtio_ring.h.
+pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+
+/// The driver and the device tell each other when they want to be notified, rather than being
+/// notified of every buffer. See linux/virtio_ring.h.
+pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+
+/// The device and the driver may use the packed layout of the virtqueues, rather than the split
+/// one. See linux/virtio_config.h.
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+
 /// Offset from the base MMIO address of a virtio device used by the guest to notify the device of
 /// queue events.
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
@@ -63,6 +93,24 @@ pub enum ActivateError {
     BadActivate,
 }
 
+impl fmt::Display for ActivateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActivateError::EpollCtl(_) => write!(f, "Failed to register the device events"),
+            ActivateError::BadActivate => write!(f, "The device was activated in a bad state"),
+        }
+    }
+}
+
+impl std::error::Error for ActivateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ActivateError::EpollCtl(e) => Some(e),
+            ActivateError::BadActivate => None,
+        }
+    }
+}
+
 pub type ActivateResult = std::result::Result<(), ActivateError>;
 
 /// Trait that helps in upcasting an object to Any
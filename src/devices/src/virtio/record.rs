@@ -0,0 +1,429 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Records the descriptor chains consumed by virtio devices, and the responses they produce, so
+//! they can be replayed later against a device instance without a guest (see `replay`).
+//!
+//! Records are written as lines of text, one per chain popped from a queue or returned to the
+//! used ring:
+//!
+//! ```text
+//! pop <device type> <queue> <head> [r:<hex data> | t:<len>:<hex data> | w:<len>]...
+//! used <device type> <queue> <head> <len> <hex data written by the device>
+//! ```
+//!
+//! The length of the descriptors is set by the guest, so only the first `MAX_RECORDED_LEN` bytes
+//! of a readable descriptor are recorded, and the longer ones are marked as truncated (`t:`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::DescriptorChain;
+
+/// The maximum number of bytes recorded for a readable descriptor.
+pub const MAX_RECORDED_LEN: u32 = 64 * 1024;
+
+/// A descriptor of a recorded chain.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordedDescriptor {
+    /// A descriptor the device reads from, along with the data it held when popped.
+    Readable(Vec<u8>),
+    /// A descriptor the device reads from, longer than `MAX_RECORDED_LEN`, along with its length
+    /// and the data it started with.
+    Truncated(u32, Vec<u8>),
+    /// A descriptor the device writes to, along with its length.
+    Writable(u32),
+}
+
+/// A single interaction between the driver and a device on one of its queues.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    /// The device popped a descriptor chain from the available ring.
+    Pop {
+        device_type: u32,
+        queue: u16,
+        head: u16,
+        descriptors: Vec<RecordedDescriptor>,
+    },
+    /// The device returned a descriptor chain to the used ring, after writing `data` to it.
+    Used {
+        device_type: u32,
+        queue: u16,
+        head: u16,
+        len: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Record {
+    /// Returns the type of the device this record belongs to.
+    pub fn device_type(&self) -> u32 {
+        match self {
+            Record::Pop { device_type, .. } | Record::Used { device_type, .. } => *device_type,
+        }
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    if data.is_empty() {
+        return write!(f, "-");
+    }
+    for byte in data {
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, ParseRecordError> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    if s.len() % 2 != 0 {
+        return Err(ParseRecordError::InvalidHex(s.to_string()));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| ParseRecordError::InvalidHex(s.to_string()))
+        })
+        .collect()
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::Pop {
+                device_type,
+                queue,
+                head,
+                descriptors,
+            } => {
+                write!(f, "pop {} {} {}", device_type, queue, head)?;
+                for desc in descriptors {
+                    match desc {
+                        RecordedDescriptor::Readable(data) => {
+                            write!(f, " r:")?;
+                            write_hex(f, data)?;
+                        }
+                        RecordedDescriptor::Truncated(len, data) => {
+                            write!(f, " t:{}:", len)?;
+                            write_hex(f, data)?;
+                        }
+                        RecordedDescriptor::Writable(len) => write!(f, " w:{}", len)?,
+                    }
+                }
+                Ok(())
+            }
+            Record::Used {
+                device_type,
+                queue,
+                head,
+                len,
+                data,
+            } => {
+                write!(f, "used {} {} {} {} ", device_type, queue, head, len)?;
+                write_hex(f, data)
+            }
+        }
+    }
+}
+
+/// Errors associated with parsing a recorded line.
+#[derive(Debug, PartialEq)]
+pub enum ParseRecordError {
+    /// A field holds an invalid hexadecimal string.
+    InvalidHex(String),
+    /// A field holds an invalid number.
+    InvalidNumber(String),
+    /// The line is missing some of the fields of the record.
+    MissingField,
+    /// A descriptor is neither readable nor writable.
+    UnknownDescriptor(String),
+    /// The line doesn't start with a known record kind.
+    UnknownRecord(String),
+}
+
+impl fmt::Display for ParseRecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseRecordError::*;
+        match self {
+            InvalidHex(s) => write!(f, "Invalid hex data: {}", s),
+            InvalidNumber(s) => write!(f, "Invalid number: {}", s),
+            MissingField => write!(f, "Missing field"),
+            UnknownDescriptor(s) => write!(f, "Unknown descriptor: {}", s),
+            UnknownRecord(s) => write!(f, "Unknown record: {}", s),
+        }
+    }
+}
+
+fn next_field<'a, I: Iterator<Item = &'a str>>(
+    fields: &mut I,
+) -> Result<&'a str, ParseRecordError> {
+    fields.next().ok_or(ParseRecordError::MissingField)
+}
+
+fn next_number<'a, T: FromStr, I: Iterator<Item = &'a str>>(
+    fields: &mut I,
+) -> Result<T, ParseRecordError> {
+    let field = next_field(fields)?;
+    field
+        .parse()
+        .map_err(|_| ParseRecordError::InvalidNumber(field.to_string()))
+}
+
+impl FromStr for Record {
+    type Err = ParseRecordError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+        match next_field(&mut fields)? {
+            "pop" => {
+                let device_type = next_number(&mut fields)?;
+                let queue = next_number(&mut fields)?;
+                let head = next_number(&mut fields)?;
+                let descriptors = fields
+                    .map(|field| {
+                        if let Some(data) = field.strip_prefix("r:") {
+                            parse_hex(data).map(RecordedDescriptor::Readable)
+                        } else if let Some(truncated) = field.strip_prefix("t:") {
+                            let sep = truncated.find(':').ok_or(ParseRecordError::MissingField)?;
+                            let len = &truncated[..sep];
+                            Ok(RecordedDescriptor::Truncated(
+                                len.parse().map_err(|_| {
+                                    ParseRecordError::InvalidNumber(len.to_string())
+                                })?,
+                                parse_hex(&truncated[sep + 1..])?,
+                            ))
+                        } else if let Some(len) = field.strip_prefix("w:") {
+                            len.parse()
+                                .map(RecordedDescriptor::Writable)
+                                .map_err(|_| ParseRecordError::InvalidNumber(len.to_string()))
+                        } else {
+                            Err(ParseRecordError::UnknownDescriptor(field.to_string()))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Record::Pop {
+                    device_type,
+                    queue,
+                    head,
+                    descriptors,
+                })
+            }
+            "used" => Ok(Record::Used {
+                device_type: next_number(&mut fields)?,
+                queue: next_number(&mut fields)?,
+                head: next_number(&mut fields)?,
+                len: next_number(&mut fields)?,
+                data: parse_hex(next_field(&mut fields)?)?,
+            }),
+            kind => Err(ParseRecordError::UnknownRecord(kind.to_string())),
+        }
+    }
+}
+
+/// Writes the records of all the queues it's attached to to a single output.
+pub struct Recorder {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Recorder {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Writes `record` to the output. Recording is best effort, so errors are only logged.
+    pub fn record(&self, record: &Record) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{}", record).and_then(|_| out.flush()) {
+            warn!("Failed to write virtio record: {:?}", e);
+        }
+    }
+}
+
+/// Records the interactions on a single queue of a device.
+#[derive(Clone)]
+pub struct QueueRecorder {
+    recorder: Arc<Recorder>,
+    device_type: u32,
+    queue: u16,
+    // Writable descriptors of the chains popped but not yet returned, by head index.
+    pending: HashMap<u16, Vec<(GuestAddress, u32)>>,
+}
+
+impl QueueRecorder {
+    pub fn new(recorder: Arc<Recorder>, device_type: u32, queue: u16) -> Self {
+        QueueRecorder {
+            recorder,
+            device_type,
+            queue,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn record_pop(&mut self, mem: &GuestMemoryMmap, chain: &DescriptorChain) {
+        // A chain still pending was put back with `Queue::undo_pop` and is being popped again.
+        if self.pending.contains_key(&chain.index) {
+            return;
+        }
+
+        let mut descriptors = Vec::new();
+        let mut writable = Vec::new();
+        for desc in chain.clone().into_iter() {
+            if desc.is_write_only() {
+                descriptors.push(RecordedDescriptor::Writable(desc.len));
+                writable.push((desc.addr, desc.len));
+            } else {
+                let mut data = vec![0u8; desc.len.min(MAX_RECORDED_LEN) as usize];
+                if let Err(e) = mem.read_slice(&mut data, desc.addr) {
+                    warn!("Failed to read descriptor for virtio record: {:?}", e);
+                }
+                descriptors.push(if desc.len > MAX_RECORDED_LEN {
+                    RecordedDescriptor::Truncated(desc.len, data)
+                } else {
+                    RecordedDescriptor::Readable(data)
+                });
+            }
+        }
+        self.pending.insert(chain.index, writable);
+
+        self.recorder.record(&Record::Pop {
+            device_type: self.device_type,
+            queue: self.queue,
+            head: chain.index,
+            descriptors,
+        });
+    }
+
+    pub(crate) fn record_used(&mut self, mem: &GuestMemoryMmap, head: u16, len: u32) {
+        let mut data = Vec::new();
+        let mut remaining = len as usize;
+        for (addr, desc_len) in self.pending.remove(&head).unwrap_or_default() {
+            if remaining == 0 {
+                break;
+            }
+            let mut chunk = vec![0u8; remaining.min(desc_len as usize)];
+            if let Err(e) = mem.read_slice(&mut chunk, addr) {
+                warn!("Failed to read descriptor for virtio record: {:?}", e);
+            }
+            remaining -= chunk.len();
+            data.extend_from_slice(&chunk);
+        }
+
+        self.recorder.record(&Record::Used {
+            device_type: self.device_type,
+            queue: self.queue,
+            head,
+            len,
+            data,
+        });
+    }
+}
+
+impl fmt::Debug for QueueRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "QueueRecorder {{ device_type: {}, queue: {} }}",
+            self.device_type, self.queue
+        )
+    }
+}
+
+impl PartialEq for QueueRecorder {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.recorder, &other.recorder)
+            && self.device_type == other.device_type
+            && self.queue == other.queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() {
+        let records = vec![
+            Record::Pop {
+                device_type: 26,
+                queue: 1,
+                head: 3,
+                descriptors: vec![
+                    RecordedDescriptor::Readable(vec![0x00, 0xab, 0xff]),
+                    RecordedDescriptor::Readable(Vec::new()),
+                    RecordedDescriptor::Truncated(MAX_RECORDED_LEN + 1, vec![0x01, 0x02]),
+                    RecordedDescriptor::Writable(4096),
+                ],
+            },
+            Record::Used {
+                device_type: 26,
+                queue: 1,
+                head: 3,
+                len: 2,
+                data: vec![0x12, 0x34],
+            },
+            Record::Used {
+                device_type: 3,
+                queue: 0,
+                head: 0,
+                len: 0,
+                data: Vec::new(),
+            },
+        ];
+
+        for record in records {
+            let line = record.to_string();
+            assert_eq!(line.parse::<Record>().unwrap(), record);
+        }
+
+        let line = "pop 26 1 3 r:00ab w:16";
+        assert_eq!(line.parse::<Record>().unwrap().to_string(), line);
+    }
+
+    #[test]
+    fn test_parse_invalid_record() {
+        assert_eq!("".parse::<Record>(), Err(ParseRecordError::MissingField));
+        assert_eq!(
+            "kick 3 0 0".parse::<Record>(),
+            Err(ParseRecordError::UnknownRecord("kick".to_string()))
+        );
+        assert_eq!(
+            "pop 3 0".parse::<Record>(),
+            Err(ParseRecordError::MissingField)
+        );
+        assert_eq!(
+            "pop 3 0 x".parse::<Record>(),
+            Err(ParseRecordError::InvalidNumber("x".to_string()))
+        );
+        assert_eq!(
+            "pop 3 0 0 r:abc".parse::<Record>(),
+            Err(ParseRecordError::InvalidHex("abc".to_string()))
+        );
+        assert_eq!(
+            "pop 3 0 0 t:10".parse::<Record>(),
+            Err(ParseRecordError::MissingField)
+        );
+        assert_eq!(
+            "pop 3 0 0 t:x:00".parse::<Record>(),
+            Err(ParseRecordError::InvalidNumber("x".to_string()))
+        );
+        assert_eq!(
+            "pop 3 0 0 x:10".parse::<Record>(),
+            Err(ParseRecordError::UnknownDescriptor("x:10".to_string()))
+        );
+        assert_eq!(
+            "used 3 0 0 4 zz".parse::<Record>(),
+            Err(ParseRecordError::InvalidHex("zz".to_string()))
+        );
+    }
+}
@@ -0,0 +1,658 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replays the descriptor chains captured by a `record::Recorder` against a device instance,
+//! without a guest, and compares the responses of the device with the recorded ones.
+//!
+//! The harness plays the role of the driver: it lays out the queues of the device in a private
+//! guest memory, activates the device, and then makes each recorded chain available in turn,
+//! notifying the device and servicing its events until it goes quiet. The device is responsible
+//! for any host side state it depends on, like the directory shared by a fs device.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::num::Wrapping;
+use std::result;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Mutex};
+
+use polly::event_manager::{self, EventManager, Subscriber};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use super::record::{ParseRecordError, Record, RecordedDescriptor};
+use super::{ActivateError, VirtioDevice};
+
+/// Size of the guest memory backing the queues and the buffers of the replayed chains.
+const REPLAY_MEM_SIZE: usize = 64 << 20;
+/// Space reserved for the rings of each queue, enough for queues of up to 1024 elements.
+const RING_AREA_SIZE: u64 = 0x10000;
+/// How long to wait for the device to produce more events before moving on to the next chain.
+const REPLAY_EVENT_TIMEOUT_MS: i32 = 50;
+/// Bounds the number of event loop iterations run for a single chain, in case the device keeps
+/// polling a host resource that's always ready.
+const MAX_EVENT_LOOPS: usize = 1000;
+
+/// Errors associated with replaying a recording.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// Unable to activate the device.
+    Activate(ActivateError),
+    /// A recorded chain doesn't fit in the memory of the harness.
+    ChainTooLarge(u16),
+    /// The event manager servicing the device failed.
+    EventManager(event_manager::Error),
+    /// Unable to create the guest memory of the harness.
+    GuestMemory(vm_memory::Error),
+    /// A recorded chain holds no descriptors, or more than its queue can hold.
+    InvalidChain(u16),
+    /// Unable to read the recording.
+    Io(io::Error),
+    /// Unable to notify the device of a new chain in one of its queues.
+    NotifyQueue(io::Error),
+    /// A line of the recording is invalid.
+    ParseRecord(usize, ParseRecordError),
+    /// A recorded queue doesn't exist in the device, or it has no free descriptors left.
+    QueueUnavailable(u16),
+    /// A recorded chain holds a descriptor whose data was truncated by the recorder.
+    TruncatedChain(u16),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReplayError::*;
+        match self {
+            Activate(e) => write!(f, "Unable to activate the device: {:?}", e),
+            ChainTooLarge(queue) => write!(f, "Chain recorded on queue {} is too large", queue),
+            EventManager(e) => write!(f, "Error in EventManager loop: {:?}", e),
+            GuestMemory(e) => write!(f, "Unable to create the replay memory: {:?}", e),
+            InvalidChain(queue) => write!(f, "Invalid chain recorded on queue {}", queue),
+            Io(e) => write!(f, "Unable to read the recording: {}", e),
+            NotifyQueue(e) => write!(f, "Unable to notify the device: {}", e),
+            ParseRecord(line, e) => write!(f, "Invalid record at line {}: {}", line, e),
+            QueueUnavailable(queue) => write!(f, "Queue {} is not available", queue),
+            TruncatedChain(queue) => write!(
+                f,
+                "Chain recorded on queue {} holds truncated descriptors",
+                queue
+            ),
+        }
+    }
+}
+
+type Result<T> = result::Result<T, ReplayError>;
+
+/// Reads the records of a recording, one per line.
+pub fn read_records<R: BufRead>(reader: R) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(ReplayError::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            line.parse()
+                .map_err(|e| ReplayError::ParseRecord(index + 1, e))?,
+        );
+    }
+    Ok(records)
+}
+
+/// What a device returned to the used ring for a chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    /// The length reported by the device.
+    pub len: u32,
+    /// The data written by the device, up to `len`.
+    pub data: Vec<u8>,
+}
+
+/// A chain for which the device responded differently than in the recording.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub queue: u16,
+    /// The head index of the chain in the recording.
+    pub head: u16,
+    /// The recorded response, or `None` if the chain was still pending when recording stopped.
+    pub expected: Option<Response>,
+    /// The response to the replayed chain, or `None` if the device didn't return it.
+    pub actual: Option<Response>,
+}
+
+/// The outcome of a replay.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Number of chains made available to the device.
+    pub chains: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Whether the device responded to every chain as recorded.
+    pub fn is_success(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+struct RecordedChain<'a> {
+    queue: u16,
+    head: u16,
+    descriptors: &'a [RecordedDescriptor],
+    expected: Option<Response>,
+    actual: Option<Response>,
+}
+
+// Pairs the chains popped in the recording with the responses of the device.
+fn recorded_chains(device_type: u32, records: &[Record]) -> Vec<RecordedChain> {
+    let mut chains: Vec<RecordedChain> = Vec::new();
+    let mut pending = HashMap::new();
+    for record in records.iter().filter(|r| r.device_type() == device_type) {
+        match record {
+            Record::Pop {
+                queue,
+                head,
+                descriptors,
+                ..
+            } => {
+                pending.insert((*queue, *head), chains.len());
+                chains.push(RecordedChain {
+                    queue: *queue,
+                    head: *head,
+                    descriptors,
+                    expected: None,
+                    actual: None,
+                });
+            }
+            Record::Used {
+                queue,
+                head,
+                len,
+                data,
+                ..
+            } => {
+                // Chains returned without being popped were made available before the
+                // recording started, and there's nothing to replay for them.
+                if let Some(index) = pending.remove(&(*queue, *head)) {
+                    chains[index].expected = Some(Response {
+                        len: *len,
+                        data: data.clone(),
+                    });
+                }
+            }
+        }
+    }
+    chains
+}
+
+// Hands out the buffers of the replayed chains, wrapping around once the memory is exhausted.
+struct BufferAllocator {
+    start: u64,
+    end: u64,
+    next: u64,
+}
+
+impl BufferAllocator {
+    fn alloc(&mut self, len: u32) -> Option<GuestAddress> {
+        let len = (u64::from(len) + 15) & !15;
+        if len > self.end - self.start {
+            return None;
+        }
+        if self.next + len > self.end {
+            self.next = self.start;
+        }
+        let addr = GuestAddress(self.next);
+        self.next += len;
+        Some(addr)
+    }
+}
+
+// The driver side of a queue of the device.
+struct DriverQueue {
+    size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    // Free descriptors, with the lowest indexes at the end.
+    free: Vec<u16>,
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    // Descriptors of the chains made available, along with their recorded chain, by head index.
+    in_flight: HashMap<u16, (usize, Vec<u16>)>,
+}
+
+impl DriverQueue {
+    fn new(start: GuestAddress, size: u16) -> Self {
+        let desc_table = start;
+        let avail_ring = desc_table.unchecked_add(16 * u64::from(size));
+        let used_ring = GuestAddress((avail_ring.raw_value() + 6 + 2 * u64::from(size) + 3) & !3);
+        DriverQueue {
+            size,
+            desc_table,
+            avail_ring,
+            used_ring,
+            free: (0..size).rev().collect(),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    fn write_desc(
+        &self,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        addr: GuestAddress,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let desc = self.desc_table.unchecked_add(16 * u64::from(index));
+        // The rings are within the memory of the harness, so these writes can't fail.
+        mem.write_obj(addr.raw_value(), desc).unwrap();
+        mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+        mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+        mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+    }
+
+    // Makes `chain` available to the device.
+    fn add_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        buffers: &mut BufferAllocator,
+        chain_index: usize,
+        chain: &RecordedChain,
+    ) -> Result<()> {
+        let count = chain.descriptors.len();
+        if count == 0 || count > usize::from(self.size) {
+            return Err(ReplayError::InvalidChain(chain.queue));
+        }
+        // The device would read something else than what it did when recording.
+        if chain
+            .descriptors
+            .iter()
+            .any(|desc| matches!(desc, RecordedDescriptor::Truncated(..)))
+        {
+            return Err(ReplayError::TruncatedChain(chain.queue));
+        }
+        if count > self.free.len() {
+            return Err(ReplayError::QueueUnavailable(chain.queue));
+        }
+        let indexes: Vec<u16> = (0..count).filter_map(|_| self.free.pop()).collect();
+
+        for (i, desc) in chain.descriptors.iter().enumerate() {
+            let (len, flags) = match desc {
+                RecordedDescriptor::Readable(data) => (data.len() as u32, 0),
+                RecordedDescriptor::Writable(len) => (*len, VIRTQ_DESC_F_WRITE),
+                // Rejected above.
+                RecordedDescriptor::Truncated(..) => unreachable!(),
+            };
+            let addr = buffers
+                .alloc(len)
+                .ok_or(ReplayError::ChainTooLarge(chain.queue))?;
+            if let RecordedDescriptor::Readable(data) = desc {
+                mem.write_slice(data, addr).unwrap();
+            }
+            let (flags, next) = match indexes.get(i + 1) {
+                Some(next) => (flags | VIRTQ_DESC_F_NEXT, *next),
+                None => (flags, 0),
+            };
+            self.write_desc(mem, indexes[i], addr, len, flags, next);
+        }
+
+        let head = indexes[0];
+        let slot = u64::from(self.next_avail.0 % self.size);
+        mem.write_obj(head, self.avail_ring.unchecked_add(4 + 2 * slot))
+            .unwrap();
+        self.next_avail += Wrapping(1);
+        // Make sure the descriptors are visible before the index update is.
+        fence(Ordering::Release);
+        mem.write_obj(self.next_avail.0, self.avail_ring.unchecked_add(2))
+            .unwrap();
+
+        self.in_flight.insert(head, (chain_index, indexes));
+        Ok(())
+    }
+
+    // Collects the chains returned by the device since the last call, along with their response.
+    fn take_used(&mut self, mem: &GuestMemoryMmap) -> Vec<(usize, Response)> {
+        let mut used = Vec::new();
+        let used_idx: u16 = mem.read_obj(self.used_ring.unchecked_add(2)).unwrap();
+        while self.next_used.0 != used_idx {
+            let slot = u64::from(self.next_used.0 % self.size);
+            let elem = self.used_ring.unchecked_add(4 + 8 * slot);
+            let head: u32 = mem.read_obj(elem).unwrap();
+            let len: u32 = mem.read_obj(elem.unchecked_add(4)).unwrap();
+            self.next_used += Wrapping(1);
+
+            let (chain_index, indexes) = match self.in_flight.remove(&(head as u16)) {
+                Some(chain) => chain,
+                None => {
+                    warn!("Device returned a chain that wasn't available: {}", head);
+                    continue;
+                }
+            };
+            let data = self.read_response(mem, &indexes, len);
+            self.free.extend(indexes.into_iter().rev());
+            used.push((chain_index, Response { len, data }));
+        }
+        used
+    }
+
+    fn read_response(&self, mem: &GuestMemoryMmap, indexes: &[u16], len: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut remaining = len as usize;
+        for index in indexes {
+            let desc = self.desc_table.unchecked_add(16 * u64::from(*index));
+            let flags: u16 = mem.read_obj(desc.unchecked_add(12)).unwrap();
+            if flags & VIRTQ_DESC_F_WRITE == 0 || remaining == 0 {
+                continue;
+            }
+            let addr: u64 = mem.read_obj(desc).unwrap();
+            let desc_len: u32 = mem.read_obj(desc.unchecked_add(8)).unwrap();
+            let mut chunk = vec![0u8; remaining.min(desc_len as usize)];
+            mem.read_slice(&mut chunk, GuestAddress(addr)).unwrap();
+            remaining -= chunk.len();
+            data.extend_from_slice(&chunk);
+        }
+        data
+    }
+}
+
+// Services the device until it stops producing events.
+fn run_until_idle(event_manager: &mut EventManager) -> Result<()> {
+    for _ in 0..MAX_EVENT_LOOPS {
+        let events = event_manager
+            .run_with_timeout(REPLAY_EVENT_TIMEOUT_MS)
+            .map_err(ReplayError::EventManager)?;
+        if events == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Replays the chains recorded for the type of `device` against it. The device must not have
+/// been activated yet.
+pub fn replay<D>(device: Arc<Mutex<D>>, records: &[Record]) -> Result<ReplayReport>
+where
+    D: VirtioDevice + Subscriber + 'static,
+{
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), REPLAY_MEM_SIZE)])
+        .map_err(ReplayError::GuestMemory)?;
+
+    let mut queues = Vec::new();
+    let mut chains = {
+        let mut locked_device = device.lock().unwrap();
+        let chains = recorded_chains(locked_device.device_type(), records);
+        for (index, queue) in locked_device.queues_mut().iter_mut().enumerate() {
+            let driver_queue = DriverQueue::new(
+                GuestAddress(index as u64 * RING_AREA_SIZE),
+                queue.get_max_size(),
+            );
+            queue.size = driver_queue.size;
+            queue.ready = true;
+            queue.desc_table = driver_queue.desc_table;
+            queue.avail_ring = driver_queue.avail_ring;
+            queue.used_ring = driver_queue.used_ring;
+            queues.push(driver_queue);
+        }
+        locked_device
+            .activate(mem.clone())
+            .map_err(ReplayError::Activate)?;
+        chains
+    };
+
+    let mut buffers = BufferAllocator {
+        start: queues.len() as u64 * RING_AREA_SIZE,
+        end: REPLAY_MEM_SIZE as u64,
+        next: queues.len() as u64 * RING_AREA_SIZE,
+    };
+
+    let mut event_manager = EventManager::new().map_err(ReplayError::EventManager)?;
+    event_manager
+        .add_subscriber(device.clone())
+        .map_err(ReplayError::EventManager)?;
+    // Let the device handle its activation.
+    run_until_idle(&mut event_manager)?;
+
+    for index in 0..chains.len() {
+        let queue = chains[index].queue;
+        let driver_queue = queues
+            .get_mut(usize::from(queue))
+            .ok_or(ReplayError::QueueUnavailable(queue))?;
+        driver_queue.add_chain(&mem, &mut buffers, index, &chains[index])?;
+
+        device.lock().unwrap().queue_events()[usize::from(queue)]
+            .write(1)
+            .map_err(ReplayError::NotifyQueue)?;
+        run_until_idle(&mut event_manager)?;
+
+        for driver_queue in queues.iter_mut() {
+            for (chain_index, response) in driver_queue.take_used(&mem) {
+                chains[chain_index].actual = Some(response);
+            }
+        }
+    }
+
+    Ok(ReplayReport {
+        chains: chains.len(),
+        mismatches: chains
+            .into_iter()
+            .filter(|chain| chain.expected != chain.actual)
+            .map(|chain| Mismatch {
+                queue: chain.queue,
+                head: chain.head,
+                expected: chain.expected,
+                actual: chain.actual,
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::super::record::{QueueRecorder, Recorder};
+    use super::super::{ActivateResult, DeviceState, Queue};
+    use super::*;
+    use utils::epoll::{EpollEvent, EventSet};
+    use utils::eventfd::EventFd;
+
+    // Returns the data read from each chain, reversed, in its writable descriptors.
+    struct ReverseDevice {
+        queues: Vec<Queue>,
+        queue_events: Vec<EventFd>,
+        interrupt_evt: EventFd,
+        device_state: DeviceState,
+    }
+
+    impl ReverseDevice {
+        fn new() -> Self {
+            ReverseDevice {
+                queues: vec![Queue::new(16)],
+                queue_events: vec![EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap()],
+                interrupt_evt: EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+                device_state: DeviceState::Inactive,
+            }
+        }
+    }
+
+    impl VirtioDevice for ReverseDevice {
+        fn avail_features(&self) -> u64 {
+            0
+        }
+
+        fn acked_features(&self) -> u64 {
+            0
+        }
+
+        fn set_acked_features(&mut self, _acked_features: u64) {}
+
+        fn device_type(&self) -> u32 {
+            42
+        }
+
+        fn queues(&self) -> &[Queue] {
+            &self.queues
+        }
+
+        fn queues_mut(&mut self) -> &mut [Queue] {
+            &mut self.queues
+        }
+
+        fn queue_events(&self) -> &[EventFd] {
+            &self.queue_events
+        }
+
+        fn interrupt_evt(&self) -> &EventFd {
+            &self.interrupt_evt
+        }
+
+        fn interrupt_status(&self) -> Arc<AtomicUsize> {
+            Arc::new(AtomicUsize::new(0))
+        }
+
+        fn set_irq_line(&mut self, _irq: u32) {}
+
+        fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+        fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+        fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+            self.device_state = DeviceState::Activated(mem);
+            Ok(())
+        }
+
+        fn is_activated(&self) -> bool {
+            match self.device_state {
+                DeviceState::Inactive => false,
+                DeviceState::Activated(_) => true,
+            }
+        }
+    }
+
+    impl Subscriber for ReverseDevice {
+        fn process(&mut self, _event: &EpollEvent, _event_manager: &mut EventManager) {
+            self.queue_events[0].read().unwrap();
+            let mem = match self.device_state {
+                DeviceState::Activated(ref mem) => mem,
+                DeviceState::Inactive => unreachable!(),
+            };
+
+            while let Some(head) = self.queues[0].pop(mem) {
+                let mut data = Vec::new();
+                for desc in head.clone().into_iter().readable() {
+                    let mut buf = vec![0u8; desc.len as usize];
+                    mem.read_slice(&mut buf, desc.addr).unwrap();
+                    data.extend_from_slice(&buf);
+                }
+                data.reverse();
+
+                let mut len = 0;
+                for desc in head.clone().into_iter().writable() {
+                    let count = data.len().min(desc.len as usize);
+                    mem.write_slice(&data[..count], desc.addr).unwrap();
+                    data.drain(..count);
+                    len += count as u32;
+                }
+                self.queues[0].add_used(mem, head.index, len);
+            }
+        }
+
+        fn interest_list(&self) -> Vec<EpollEvent> {
+            vec![EpollEvent::new(
+                EventSet::IN,
+                self.queue_events[0].as_raw_fd() as u64,
+            )]
+        }
+    }
+
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_records() -> Vec<Record> {
+        read_records(
+            &b"pop 42 0 0 r:616263 w:2 w:8
+               used 42 0 0 3 636261
+               pop 42 0 3 w:4
+               pop 1 0 0 r:00 w:1
+               used 1 0 0 1 00
+               pop 42 0 1 r:0102 w:8
+               used 42 0 1 2 0201
+               used 42 0 3 0 -"[..],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_replay() {
+        let device = Arc::new(Mutex::new(ReverseDevice::new()));
+        let report = replay(device, &test_records()).unwrap();
+        assert_eq!(report.chains, 3);
+        assert!(report.is_success());
+
+        // The device responds to a chain differently than recorded.
+        let mut records = test_records();
+        records[1] = "used 42 0 0 3 616263".parse().unwrap();
+        let device = Arc::new(Mutex::new(ReverseDevice::new()));
+        let report = replay(device, &records).unwrap();
+        assert!(!report.is_success());
+        assert_eq!(
+            report.mismatches,
+            vec![Mismatch {
+                queue: 0,
+                head: 0,
+                expected: Some(Response {
+                    len: 3,
+                    data: b"abc".to_vec(),
+                }),
+                actual: Some(Response {
+                    len: 3,
+                    data: b"cba".to_vec(),
+                }),
+            }]
+        );
+
+        // A recorded chain on a queue the device doesn't have.
+        let records = vec!["pop 42 1 0 w:1".parse().unwrap()];
+        let device = Arc::new(Mutex::new(ReverseDevice::new()));
+        assert!(matches!(
+            replay(device, &records),
+            Err(ReplayError::QueueUnavailable(1))
+        ));
+    }
+
+    #[test]
+    fn test_record_replayed_chains() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::new(Recorder::new(Box::new(SharedBuf(out.clone()))));
+        let device = Arc::new(Mutex::new(ReverseDevice::new()));
+        device.lock().unwrap().queues[0].set_recorder(Some(QueueRecorder::new(recorder, 42, 0)));
+
+        let records: Vec<Record> = vec![
+            "pop 42 0 0 r:616263 w:2 w:8".parse().unwrap(),
+            "used 42 0 0 3 636261".parse().unwrap(),
+            "pop 42 0 0 r:0102 w:8".parse().unwrap(),
+            "used 42 0 0 2 0201".parse().unwrap(),
+        ];
+        let report = replay(device, &records).unwrap();
+        assert!(report.is_success());
+
+        // Replaying in order reuses the same descriptors, so the recording matches the original.
+        let recorded = read_records(&out.lock().unwrap()[..]).unwrap();
+        assert_eq!(recorded, records);
+    }
+}
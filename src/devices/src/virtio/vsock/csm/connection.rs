@@ -90,10 +90,11 @@ use libc::{getpeername, sockaddr, socklen_t};
 
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
+use super::super::shm::ShmChannel;
 use super::super::{Result as VsockResult, VsockChannel, VsockEpollListener, VsockError};
 use super::defs;
 use super::txbuf::TxBuf;
-use super::{CommonStream, ConnState, Error, PendingRx, PendingRxSet, Result};
+use super::{CommonStream, ConnConfig, ConnState, Error, PendingRx, PendingRxSet, Result};
 
 /// A self-managing connection object, that handles communication between a guest-side AF_VSOCK
 /// socket and a host-side `Read + Write + AsRawFd` stream.
@@ -130,6 +131,10 @@ pub struct VsockConnection {
     /// Instant when this connection should be scheduled for immediate termination, due to some
     /// timeout condition having been fulfilled.
     expiry: Option<Instant>,
+    /// The shared-memory channel carrying the data of this connection, if any.
+    shm: Option<ShmChannel>,
+    /// The flow control parameters of this connection.
+    config: ConnConfig,
 }
 
 impl VsockChannel for VsockConnection {
@@ -165,13 +170,13 @@ impl VsockChannel for VsockConnection {
         // Next up: if we're due a connection confirmation, that's all we need to know to fill
         // in this packet.
         if self.pending_rx.remove(PendingRx::Response) {
-            self.state = ConnState::Established;
+            self.establish();
             pkt.set_op(uapi::VSOCK_OP_RESPONSE);
             return Ok(());
         }
 
         if self.pending_rx.remove(PendingRx::ResponseEx) {
-            self.state = ConnState::Established;
+            self.establish();
 
             let fd = self.as_raw_fd();
             let buf = pkt.buf_mut().ok_or(VsockError::PktBufMissing)?;
@@ -224,6 +229,22 @@ impl VsockChannel for VsockConnection {
             return Ok(());
         }
 
+        // Once established, the connection hands its shared-memory channel to the peer, before
+        // any data is exchanged.
+        if self.pending_rx.remove(PendingRx::ShmAttach) {
+            // It's safe to unwrap here, since a ShmAttach indication is only set for connections
+            // with a channel.
+            let index = self.shm.as_ref().unwrap().index();
+            pkt.set_op(uapi::VSOCK_OP_SHM_ATTACH).set_flags(index);
+            return Ok(());
+        }
+
+        if self.pending_rx.remove(PendingRx::ShmNotify) {
+            pkt.set_op(uapi::VSOCK_OP_SHM_NOTIFY);
+            self.last_fwd_cnt_to_peer = self.fwd_cnt;
+            return Ok(());
+        }
+
         // A credit update is basically a no-op, so we should only waste a perfectly fine RX
         // buffer on it if we really have nothing else to say.
         if self.pending_rx.remove(PendingRx::CreditUpdate) && !self.has_pending_rx() {
@@ -250,6 +271,32 @@ impl VsockChannel for VsockConnection {
             }
         }
 
+        // With a shared-memory channel, the data goes to its RX ring instead, and the peer only
+        // gets notified. The ring being full is the peer's way of asking us to wait.
+        if let Some(ref shm) = self.shm {
+            let stream = &mut self.stream;
+            match shm.rx.produce(|buf| stream.read(buf)) {
+                Ok((0, true)) => self.close_local(pkt),
+                Ok((0, false)) => return Err(VsockError::NoData),
+                Ok((_, eof)) => {
+                    if eof {
+                        // Let the peer drain the ring before it learns about the shutdown.
+                        self.pending_rx.insert(PendingRx::Rw);
+                    }
+                    pkt.set_op(uapi::VSOCK_OP_SHM_NOTIFY);
+                }
+                Err(err) => {
+                    error!(
+                        "vsock: error moving backing stream to shm: lp={}, pp={}, err={:?}",
+                        self.local_port, self.peer_port, err
+                    );
+                    pkt.set_op(uapi::VSOCK_OP_RST);
+                }
+            }
+            self.last_fwd_cnt_to_peer = self.fwd_cnt;
+            return Ok(());
+        }
+
         // Oh wait, before we start bringing in the big data, can our peer handle receiving so
         // much bytey goodness?
         if self.need_credit_update_from_peer() {
@@ -268,16 +315,8 @@ impl VsockChannel for VsockConnection {
         match self.stream.read(&mut buf[..max_len]) {
             Ok(read_cnt) => {
                 if read_cnt == 0 {
-                    // A 0-length read means the host stream was closed down. In that case,
-                    // we'll ask our peer to shut down the connection. We can neither send nor
-                    // receive any more data.
-                    self.state = ConnState::LocalClosed;
-                    self.expiry = Some(
-                        Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS),
-                    );
-                    pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
-                        .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV)
-                        .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
+                    // A 0-length read means the host stream was closed down.
+                    self.close_local(pkt);
                 } else {
                     // On a successful data read, we fill in the packet with the RW op, and
                     // length of the read data.
@@ -354,12 +393,20 @@ impl VsockChannel for VsockConnection {
             // stream.
             ConnState::LocalInit if pkt.op() == uapi::VSOCK_OP_RESPONSE => {
                 self.expiry = None;
-                self.state = ConnState::Established;
+                self.establish();
             }
 
             ConnState::LocalWrapInit if pkt.op() == uapi::VSOCK_OP_RESPONSE => {
                 self.expiry = None;
-                self.state = ConnState::Established;
+                self.establish();
+            }
+
+            // The peer produced data into the TX ring of our shared-memory channel, or consumed
+            // some from its RX ring. The latter only matters to the set of events we poll for.
+            ConnState::Established | ConnState::PeerClosed(_, false)
+                if pkt.op() == uapi::VSOCK_OP_SHM_NOTIFY && self.shm.is_some() =>
+            {
+                self.flush_shm_tx();
             }
 
             // The peer wants to shut down an established connection.  If they have nothing
@@ -371,7 +418,7 @@ impl VsockChannel for VsockConnection {
                 let send_off = pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_SEND != 0;
                 self.state = ConnState::PeerClosed(recv_off, send_off);
                 if recv_off && send_off {
-                    if self.tx_buf.is_empty() {
+                    if !self.has_pending_tx() {
                         self.pending_rx.insert(PendingRx::Rst);
                     } else {
                         self.expiry = Some(
@@ -397,7 +444,7 @@ impl VsockChannel for VsockConnection {
             {
                 *recv_off = *recv_off || (pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_RCV != 0);
                 *send_off = *send_off || (pkt.flags() & uapi::VSOCK_FLAGS_SHUTDOWN_SEND != 0);
-                if *recv_off && *send_off && self.tx_buf.is_empty() {
+                if *recv_off && *send_off && !self.has_pending_tx() {
                     self.pending_rx.insert(PendingRx::Rst);
                 }
             }
@@ -455,16 +502,16 @@ impl VsockEpollListener for VsockConnection {
     /// - data can be written to the host stream, and the TX buffer needs to be flushed.
     fn get_polled_evset(&self) -> EventSet {
         let mut evset = EventSet::empty();
-        if !self.tx_buf.is_empty() {
-            // There's data waiting in the TX buffer, so we are interested in being notified
-            // when writing to the host stream wouldn't block.
+        if self.has_pending_tx() {
+            // There's data waiting in the TX buffer or ring, so we are interested in being
+            // notified when writing to the host stream wouldn't block.
             evset.insert(EventSet::OUT);
         }
         // We're generally interested in being notified when data can be read from the host
         // stream, unless we're in a state which doesn't allow moving data from host to guest.
         match self.state {
             ConnState::Killed | ConnState::LocalClosed | ConnState::PeerClosed(true, _) => (),
-            _ if self.need_credit_update_from_peer() => (),
+            _ if self.rx_blocked() => (),
             _ => evset.insert(EventSet::IN),
         }
         evset
@@ -479,28 +526,32 @@ impl VsockEpollListener for VsockConnection {
         }
 
         if evset.contains(EventSet::OUT) {
-            // Data can be written to the host stream. Time to flush out the TX buffer.
+            // Data can be written to the host stream. Time to flush out the TX buffer, and then
+            // the TX ring.
             //
-            if self.tx_buf.is_empty() {
+            if !self.has_pending_tx() {
                 info!("vsock: connection received unexpected EPOLLOUT event");
                 return;
             }
-            let flushed = self
-                .tx_buf
-                .flush_to(&mut self.stream)
-                .unwrap_or_else(|err| {
-                    warn!(
-                        "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
-                        self.local_port, self.peer_port, err
-                    );
-                    self.kill();
-                    0
-                });
-            self.fwd_cnt += Wrapping(flushed as u32);
+            if !self.tx_buf.is_empty() {
+                let flushed = self
+                    .tx_buf
+                    .flush_to(&mut self.stream)
+                    .unwrap_or_else(|err| {
+                        warn!(
+                            "vsock: error flushing TX buf for (lp={}, pp={}): {:?}",
+                            self.local_port, self.peer_port, err
+                        );
+                        self.kill();
+                        0
+                    });
+                self.fwd_cnt += Wrapping(flushed as u32);
+            }
+            self.flush_shm_tx();
 
             // If this connection was shutting down, but is waiting to drain the TX buffer
             // before forceful termination, the wait might be over.
-            if self.state == ConnState::PeerClosed(true, true) && self.tx_buf.is_empty() {
+            if self.state == ConnState::PeerClosed(true, true) && !self.has_pending_tx() {
                 self.pending_rx.insert(PendingRx::Rst);
             } else if self.peer_needs_credit_update() {
                 // If we've freed up some more buffer space, we may need to let the peer know it
@@ -520,6 +571,7 @@ impl VsockConnection {
         local_port: u32,
         peer_port: u32,
         peer_buf_alloc: u32,
+        config: ConnConfig,
     ) -> Self {
         Self {
             local_cid,
@@ -528,7 +580,7 @@ impl VsockConnection {
             peer_port,
             stream,
             state: ConnState::PeerInit,
-            tx_buf: TxBuf::new(),
+            tx_buf: TxBuf::new(config.buf_size as usize),
             fwd_cnt: Wrapping(0),
             peer_buf_alloc,
             peer_fwd_cnt: Wrapping(0),
@@ -536,6 +588,8 @@ impl VsockConnection {
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::Response),
             expiry: None,
+            shm: None,
+            config,
         }
     }
 
@@ -547,6 +601,7 @@ impl VsockConnection {
         local_port: u32,
         peer_port: u32,
         peer_buf_alloc: u32,
+        config: ConnConfig,
     ) -> Self {
         Self {
             local_cid,
@@ -555,7 +610,7 @@ impl VsockConnection {
             peer_port,
             stream,
             state: ConnState::PeerInit,
-            tx_buf: TxBuf::new(),
+            tx_buf: TxBuf::new(config.buf_size as usize),
             fwd_cnt: Wrapping(0),
             peer_buf_alloc,
             peer_fwd_cnt: Wrapping(0),
@@ -563,6 +618,8 @@ impl VsockConnection {
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::ResponseEx),
             expiry: None,
+            shm: None,
+            config,
         }
     }
 
@@ -573,6 +630,7 @@ impl VsockConnection {
         peer_cid: u64,
         local_port: u32,
         peer_port: u32,
+        config: ConnConfig,
     ) -> Self {
         Self {
             local_cid,
@@ -581,7 +639,7 @@ impl VsockConnection {
             peer_port,
             stream,
             state: ConnState::LocalWrapInit,
-            tx_buf: TxBuf::new(),
+            tx_buf: TxBuf::new(config.buf_size as usize),
             fwd_cnt: Wrapping(0),
             peer_buf_alloc: 0,
             peer_fwd_cnt: Wrapping(0),
@@ -589,6 +647,8 @@ impl VsockConnection {
             last_fwd_cnt_to_peer: Wrapping(0),
             pending_rx: PendingRxSet::from(PendingRx::RequestEx),
             expiry: None,
+            shm: None,
+            config,
         }
     }
 
@@ -666,9 +726,76 @@ impl VsockConnection {
         self.state
     }
 
+    /// Move the data of this connection through a shared-memory channel, once established.
+    pub fn attach_shm(&mut self, shm: ShmChannel) {
+        self.shm = Some(shm);
+        if self.state == ConnState::Established {
+            self.pending_rx.insert(PendingRx::ShmAttach);
+        }
+    }
+
+    /// Move to the Established state, handing our shared-memory channel to the peer, if any.
+    fn establish(&mut self) {
+        self.state = ConnState::Established;
+        if self.shm.is_some() {
+            self.pending_rx.insert(PendingRx::ShmAttach);
+        }
+    }
+
+    /// Ask the peer to shut down the connection, after the host stream was closed down. We can
+    /// neither send nor receive any more data.
+    fn close_local(&mut self, pkt: &mut VsockPacket) {
+        self.state = ConnState::LocalClosed;
+        self.expiry = Some(Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS));
+        pkt.set_op(uapi::VSOCK_OP_SHUTDOWN)
+            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_RCV)
+            .set_flag(uapi::VSOCK_FLAGS_SHUTDOWN_SEND);
+    }
+
+    /// Forward the data the peer produced into the TX ring of our shared-memory channel to the
+    /// host stream. The ring waits for the TX buffer to be flushed first, so that the data keeps
+    /// its order.
+    fn flush_shm_tx(&mut self) {
+        if !self.tx_buf.is_empty() {
+            return;
+        }
+        let shm = match self.shm {
+            Some(ref shm) => shm,
+            None => return,
+        };
+        let stream = &mut self.stream;
+        match shm.tx.consume(|buf| stream.write(buf)) {
+            Ok(0) => (),
+            Ok(cnt) => {
+                self.fwd_cnt += Wrapping(cnt as u32);
+                self.pending_rx.insert(PendingRx::ShmNotify);
+            }
+            Err(err) => {
+                warn!(
+                    "vsock: error flushing shm TX ring for (lp={}, pp={}): {:?}",
+                    self.local_port, self.peer_port, err
+                );
+                self.kill();
+            }
+        }
+    }
+
+    /// Check if there is peer data waiting to be written to the host stream.
+    fn has_pending_tx(&self) -> bool {
+        !self.tx_buf.is_empty() || self.shm.as_ref().map_or(false, |shm| !shm.tx.is_empty())
+    }
+
+    /// Check if the peer has no room left for data from the host stream.
+    fn rx_blocked(&self) -> bool {
+        match self.shm {
+            Some(ref shm) => shm.rx.is_full(),
+            None => self.need_credit_update_from_peer(),
+        }
+    }
+
     /// Check if the credit information the peer has last received from us is outdated.
     fn peer_needs_credit_update(&self) -> bool {
-        (self.fwd_cnt - self.last_fwd_cnt_to_peer).0 as usize >= defs::CONN_CREDIT_UPDATE_THRESHOLD
+        (self.fwd_cnt - self.last_fwd_cnt_to_peer).0 >= self.config.credit_update_threshold
     }
 
     /// Check if we need to ask the peer for a credit update before sending any more data its
@@ -697,7 +824,7 @@ impl VsockConnection {
             .set_src_port(self.local_port)
             .set_dst_port(self.peer_port)
             .set_type(uapi::VSOCK_TYPE_STREAM)
-            .set_buf_alloc(defs::CONN_TX_BUF_SIZE as u32)
+            .set_buf_alloc(self.config.buf_size)
             .set_fwd_cnt(self.fwd_cnt.0)
     }
 }
@@ -711,11 +838,13 @@ mod tests {
     use utils::eventfd::EventFd;
 
     use super::super::super::defs::uapi;
+    use super::super::super::shm::{ShmChannels, VSOCK_SHM_SIZE};
     use super::super::super::tests::TestContext;
     use super::super::defs as csm_defs;
     use super::*;
 
     use crate::virtio::vsock::device::RXQ_INDEX;
+    use crate::virtio::VirtioShmRegion;
 
     const LOCAL_CID: u64 = 2;
     const PEER_CID: u64 = 3;
@@ -859,6 +988,7 @@ mod tests {
                     LOCAL_PORT,
                     PEER_PORT,
                     PEER_BUF_ALLOC,
+                    ConnConfig::default(),
                 ),
                 ConnState::Established => {
                     let mut conn = VsockConnection::new_peer_init(
@@ -868,6 +998,7 @@ mod tests {
                         LOCAL_PORT,
                         PEER_PORT,
                         PEER_BUF_ALLOC,
+                        ConnConfig::default(),
                     );
                     assert!(conn.has_pending_rx());
                     conn.recv_pkt(&mut pkt).unwrap();
@@ -1099,6 +1230,30 @@ mod tests {
         assert_eq!(ctx.conn.fwd_cnt, ctx.conn.last_fwd_cnt_to_peer);
     }
 
+    #[test]
+    fn test_conn_config() {
+        let mut ctx = CsmTestContext::new_established();
+        ctx.conn.config = ConnConfig::with_buf_size(16 * 1024);
+        ctx.conn.tx_buf = TxBuf::new(16 * 1024);
+
+        // The peer learns about our buffer space from any packet.
+        ctx.init_pkt(uapi::VSOCK_OP_CREDIT_REQUEST, 0);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.buf_alloc(), 16 * 1024);
+
+        // A credit update is due once three quarters of the buffer were forwarded.
+        ctx.conn.fwd_cnt = Wrapping(12 * 1024 - 4);
+        ctx.init_data_pkt(&[1, 2, 3]);
+        ctx.send();
+        assert!(!ctx.conn.has_pending_rx());
+        ctx.init_data_pkt(&[4]);
+        ctx.send();
+        assert!(ctx.conn.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_UPDATE);
+    }
+
     #[test]
     fn test_tx_buffering() {
         // Test case:
@@ -1201,4 +1356,58 @@ mod tests {
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
     }
+
+    #[test]
+    fn test_shm_data() {
+        let mut mem = vec![0u64; VSOCK_SHM_SIZE / 8];
+        let channels = ShmChannels::new(&VirtioShmRegion {
+            host_addr: mem.as_mut_ptr() as u64,
+            guest_addr: 0,
+            size: VSOCK_SHM_SIZE,
+        });
+        let mut ctx = CsmTestContext::new_established();
+
+        // The channel is handed to the peer before any data.
+        ctx.conn.attach_shm(channels.alloc().unwrap());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHM_ATTACH);
+        assert_eq!(ctx.pkt.flags(), 0);
+
+        // Host stream data goes to the RX ring, and the peer only gets notified.
+        let data = &[1, 2, 3, 4];
+        ctx.set_stream(TestStream::new_with_read_buf(data));
+        ctx.notify_epollin();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHM_NOTIFY);
+        assert_eq!(ctx.pkt.len(), 0);
+        let mut rx = Vec::new();
+        ctx.conn
+            .shm
+            .as_ref()
+            .unwrap()
+            .rx
+            .consume(|buf| {
+                rx.extend_from_slice(buf);
+                Ok(buf.len())
+            })
+            .unwrap();
+        assert_eq!(rx, data);
+
+        // Peer data in the TX ring is forwarded to the host stream once the peer notifies us.
+        let mut input = &data[..];
+        ctx.conn
+            .shm
+            .as_ref()
+            .unwrap()
+            .tx
+            .produce(|buf| input.read(buf))
+            .unwrap();
+        ctx.init_pkt(uapi::VSOCK_OP_SHM_NOTIFY, 0);
+        ctx.send();
+        assert_eq!(ctx.conn.stream.get_write_buf().unwrap(), data);
+        assert_eq!(ctx.conn.fwd_cnt.0, data.len() as u32);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_SHM_NOTIFY);
+        assert_eq!(ctx.pkt.fwd_cnt(), data.len() as u32);
+    }
 }
@@ -25,6 +25,37 @@ pub mod defs {
     pub const CONN_SHUTDOWN_TIMEOUT_MS: u64 = 2000;
 }
 
+/// The flow control parameters of the vsock connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnConfig {
+    /// The size of the TX buffer of each connection, which is the buffer space advertised to the
+    /// peer. A power of 2.
+    pub buf_size: u32,
+    /// The number of bytes forwarded to the host stream, since the peer last heard from us, that
+    /// make us send it a credit update.
+    pub credit_update_threshold: u32,
+}
+
+impl ConnConfig {
+    /// Creates a configuration with `buf_size` byte buffers, sending a credit update once three
+    /// quarters of a buffer were forwarded.
+    pub fn with_buf_size(buf_size: u32) -> Self {
+        ConnConfig {
+            buf_size,
+            credit_update_threshold: buf_size - buf_size / 4,
+        }
+    }
+}
+
+impl Default for ConnConfig {
+    fn default() -> Self {
+        ConnConfig {
+            buf_size: defs::CONN_TX_BUF_SIZE as u32,
+            credit_update_threshold: defs::CONN_CREDIT_UPDATE_THRESHOLD as u32,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// Attempted to push data to a full TX buffer.
@@ -83,6 +114,10 @@ enum PendingRx {
     RequestEx = 5,
     /// We need to yield a connection response packet with extended parameters (VSOCK_OP_RESPONSE_EX).
     ResponseEx = 6,
+    /// We need to yield a packet attaching a shared-memory channel (VSOCK_OP_SHM_ATTACH).
+    ShmAttach = 7,
+    /// We need to yield a shared-memory ring notification packet (VSOCK_OP_SHM_NOTIFY).
+    ShmNotify = 8,
 }
 impl PendingRx {
     /// Transform the enum value into a bitmask, that can be used for set operations.
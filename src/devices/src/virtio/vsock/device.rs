@@ -23,18 +23,24 @@ use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+use logger::METRICS;
 use utils::byte_order;
 use utils::eventfd::EventFd;
 use vm_memory::GuestMemoryMmap;
 
 use super::super::super::Error as DeviceError;
 use super::super::{
-    ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VsockError,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DeviceState, Queue as VirtQueue, VirtioDevice, VirtioShmRegion,
+    VsockError, VIRTIO_F_RING_PACKED, VIRTIO_MMIO_INT_VRING, VIRTIO_RING_F_EVENT_IDX,
+    VIRTIO_RING_F_INDIRECT_DESC,
 };
 use super::packet::VsockPacket;
-use super::VsockBackend;
+use super::shm::{ShmChannels, VSOCK_SHM_SIZE};
 use super::{defs, defs::uapi};
+use super::{ActivatedSockets, VsockBackend};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
+use crate::fd_budget::FdAccount;
 use crate::legacy::Gic;
 
 pub(crate) const RXQ_INDEX: usize = 0;
@@ -45,8 +51,14 @@ pub(crate) const EVQ_INDEX: usize = 2;
 /// - VIRTIO_F_VERSION_1: the device conforms to at least version 1.0 of the VirtIO spec.
 /// - VIRTIO_F_IN_ORDER: the device returns used buffers in the same order that the driver makes
 ///   them available.
-pub(crate) const AVAIL_FEATURES: u64 =
-    1 << uapi::VIRTIO_F_VERSION_1 as u64 | 1 << uapi::VIRTIO_F_IN_ORDER as u64;
+/// - VIRTIO_F_RING_PACKED: the virtqueues may use the packed layout.
+/// - VIRTIO_RING_F_INDIRECT_DESC: the driver may describe its buffers with indirect tables.
+/// - VIRTIO_RING_F_EVENT_IDX: the driver and the device tell each other when to notify.
+pub(crate) const AVAIL_FEATURES: u64 = 1 << uapi::VIRTIO_F_VERSION_1 as u64
+    | 1 << uapi::VIRTIO_F_IN_ORDER as u64
+    | 1 << VIRTIO_F_RING_PACKED as u64
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC as u64
+    | 1 << VIRTIO_RING_F_EVENT_IDX as u64;
 
 pub struct Vsock<B> {
     cid: u64,
@@ -66,6 +78,7 @@ pub struct Vsock<B> {
     pub(crate) device_state: DeviceState,
     intc: Option<Arc<Mutex<Gic>>>,
     irq_line: Option<u32>,
+    shm_region: Option<VirtioShmRegion>,
 }
 
 // TODO: Detect / handle queue deadlock:
@@ -103,6 +116,7 @@ where
             device_state: DeviceState::Inactive,
             intc: None,
             irq_line: None,
+            shm_region: None,
         })
     }
 
@@ -123,6 +137,24 @@ where
         self.intc = Some(intc);
     }
 
+    /// Offers the guest driver `VIRTIO_VSOCK_F_KRUN_SHM`, carrying the data of the connections
+    /// through `shm_region` rather than the virtqueues.
+    pub fn set_shm_region(&mut self, shm_region: VirtioShmRegion) {
+        assert!(shm_region.size >= VSOCK_SHM_SIZE);
+        self.shm_region = Some(shm_region);
+        self.avail_features |= 1 << uapi::VIRTIO_VSOCK_F_KRUN_SHM as u64;
+    }
+
+    /// Charges the file descriptors of the connections to `account`.
+    pub fn set_fd_account(&mut self, account: FdAccount) {
+        self.backend.set_fd_account(account);
+    }
+
+    /// Listens through `sockets` when the guest listens on the same host port or path.
+    pub fn set_activated_sockets(&mut self, sockets: ActivatedSockets) {
+        self.backend.set_activated_sockets(sockets);
+    }
+
     pub fn cid(&self) -> u64 {
         self.cid
     }
@@ -134,6 +166,8 @@ where
     /// Signal the guest driver that we've used some virtio buffers that it had previously made
     /// available.
     pub fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        #[cfg(feature = "fault-injection")]
+        FAULTS.delay_interrupt(uapi::VIRTIO_ID_VSOCK);
         debug!("vsock: raising IRQ");
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -165,6 +199,7 @@ where
             let used_len = match VsockPacket::from_rx_virtq_head(&head) {
                 Ok(mut pkt) => {
                     if self.backend.recv_pkt(&mut pkt).is_ok() {
+                        METRICS.vsock.rx_packets.inc();
                         pkt.hdr().len() as u32 + pkt.len()
                     } else {
                         // We are using a consuming iterator over the virtio buffers, so, if we can't
@@ -200,6 +235,15 @@ where
         let mut have_used = false;
 
         while let Some(head) = self.queues[TXQ_INDEX].pop(mem) {
+            #[cfg(feature = "fault-injection")]
+            {
+                if FAULTS.take_queue_error(uapi::VIRTIO_ID_VSOCK) {
+                    have_used = true;
+                    self.queues[TXQ_INDEX].add_used(mem, head.index, 0);
+                    continue;
+                }
+            }
+
             let pkt = match VsockPacket::from_tx_virtq_head(&head) {
                 Ok(pkt) => pkt,
                 Err(e) => {
@@ -215,6 +259,7 @@ where
                 break;
             }
 
+            METRICS.vsock.tx_packets.inc();
             have_used = true;
             self.queues[TXQ_INDEX].add_used(mem, head.index, 0);
         }
@@ -307,6 +352,13 @@ where
             return Err(ActivateError::BadActivate);
         }
 
+        if self.acked_features & (1 << uapi::VIRTIO_VSOCK_F_KRUN_SHM as u64) != 0 {
+            if let Some(ref shm_region) = self.shm_region {
+                debug!("vsock: using the shared-memory data path");
+                self.backend.set_shm(ShmChannels::new(shm_region));
+            }
+        }
+
         self.device_state = DeviceState::Activated(mem);
 
         Ok(())
@@ -318,6 +370,10 @@ where
             DeviceState::Activated(_) => true,
         }
     }
+
+    fn shm_region(&self) -> Option<&VirtioShmRegion> {
+        self.shm_region.as_ref()
+    }
 }
 
 #[cfg(test)]
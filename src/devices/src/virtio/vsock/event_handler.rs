@@ -206,7 +206,7 @@ where
                 }
                 _ => warn!("Unexpected vsock event received: {:?}", source),
             }
-            if raise_irq {
+            if raise_irq && self.device_state.needs_notification(&mut self.queues) {
                 self.signal_used_queue().unwrap_or_default();
             }
         } else {
@@ -223,6 +223,10 @@ where
             self.activate_evt.as_raw_fd() as u64,
         )]
     }
+
+    fn name(&self) -> String {
+        self.id().to_string()
+    }
 }
 
 #[cfg(test)]
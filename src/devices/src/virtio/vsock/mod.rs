@@ -9,18 +9,22 @@ mod csm;
 mod device;
 mod event_handler;
 mod packet;
+mod shm;
 mod unix;
 
 use std::os::unix::io::AsRawFd;
 
+pub use self::csm::ConnConfig as VsockConnConfig;
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::device::Vsock;
-pub use self::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
+pub use self::shm::VSOCK_SHM_SIZE;
+pub use self::unix::{ActivatedSockets, Error as VsockUnixBackendError, VsockUnixBackend};
 
 use utils::epoll::EventSet;
-use vm_memory::GuestMemoryError;
 
+use crate::fd_budget::FdAccount;
 use packet::VsockPacket;
+use shm::ShmChannels;
 
 mod defs {
     /// Device ID used in MMIO device identification.
@@ -46,6 +50,9 @@ mod defs {
         pub const VIRTIO_F_IN_ORDER: usize = 35;
         /// The device conforms to the virtio spec version 1.0.
         pub const VIRTIO_F_VERSION_1: u32 = 32;
+        /// The device exposes a shared-memory region carrying the data of the connections. Not
+        /// part of the virtio spec.
+        pub const VIRTIO_VSOCK_F_KRUN_SHM: u32 = 63;
 
         /// Virtio vsock device ID.
         /// Defined in `include/uapi/linux/virtio_ids.h`.
@@ -76,6 +83,11 @@ mod defs {
         pub const VSOCK_OP_WRAP_CLOSE: u16 = 10;
         /// Connection response.
         pub const VSOCK_OP_RESPONSE_EX: u16 = 11;
+        /// A channel of the shared-memory region, whose index is in `flags`, carries the data of
+        /// the connection from now on.
+        pub const VSOCK_OP_SHM_ATTACH: u16 = 12;
+        /// Data was produced into, or consumed from, a ring of the shared-memory region.
+        pub const VSOCK_OP_SHM_NOTIFY: u16 = 13;
 
         /// Vsock packet flags.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
@@ -105,8 +117,8 @@ pub enum VsockError {
     BufDescTooSmall,
     /// The vsock data/buffer virtio descriptor is expected, but missing.
     BufDescMissing,
-    /// Chained GuestMemoryMmap error.
-    GuestMemoryMmap(GuestMemoryError),
+    /// A buffer isn't within the guest memory.
+    GuestMemoryMmap(super::guest_memory::Error),
     /// Bounds check failed on guest memory pointer.
     GuestMemoryBounds,
     /// The vsock header descriptor length is too small.
@@ -126,6 +138,38 @@ pub enum VsockError {
     VsockUdsBackend(VsockUnixBackendError),
 }
 
+impl std::fmt::Display for VsockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use self::VsockError::*;
+        match self {
+            BufDescTooSmall => write!(f, "The buffer descriptor is too small"),
+            BufDescMissing => write!(f, "The buffer descriptor is missing"),
+            GuestMemoryMmap(_) => write!(f, "Guest memory error"),
+            GuestMemoryBounds => write!(f, "The guest memory pointer is out of bounds"),
+            HdrDescTooSmall(len) => write!(f, "The header descriptor is too small: {}", len),
+            InvalidPktLen(len) => write!(f, "Invalid packet length: {}", len),
+            NoData => write!(f, "No data available"),
+            PktBufMissing => write!(f, "The packet buffer is missing"),
+            UnreadableDescriptor => write!(f, "Unexpected write-only descriptor"),
+            UnwritableDescriptor => write!(f, "Unexpected read-only descriptor"),
+            EventFd(_) => write!(f, "Event fd error"),
+            VsockUdsBackend(_) => write!(f, "Unix socket backend error"),
+        }
+    }
+}
+
+impl std::error::Error for VsockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::VsockError::*;
+        match self {
+            GuestMemoryMmap(e) => Some(e),
+            EventFd(e) => Some(e),
+            VsockUdsBackend(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, VsockError>;
 
 /// A passive, event-driven object, that needs to be notified whenever an epoll-able event occurs.
@@ -164,7 +208,18 @@ pub trait VsockChannel {
 /// The vsock backend, which is basically an epoll-event-driven vsock channel.
 /// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
 /// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Hands the backend the channels of the shared-memory region, once the guest driver
+    /// negotiated `VIRTIO_VSOCK_F_KRUN_SHM`.
+    fn set_shm(&mut self, _shm: ShmChannels) {}
+
+    /// Charges the file descriptors of the connections to `account`.
+    fn set_fd_account(&mut self, _account: FdAccount) {}
+
+    /// Listens through `sockets`, rather than binding new ones, when the guest listens on the same
+    /// host port or path.
+    fn set_activated_sockets(&mut self, _sockets: ActivatedSockets) {}
+}
 
 #[cfg(test)]
 pub(crate) mod tests {
@@ -17,11 +17,10 @@
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::os::raw::c_char;
-use std::result;
 
 use utils::byte_order;
-use vm_memory::{self, GuestAddress, GuestMemory, GuestMemoryError};
 
+use super::super::guest_memory::host_address;
 use super::super::DescriptorChain;
 use super::defs;
 use super::{Result, VsockError};
@@ -100,14 +99,6 @@ pub struct VsockPacket {
     buf_size: usize,
 }
 
-fn get_host_address<T: GuestMemory>(
-    mem: &T,
-    guest_addr: GuestAddress,
-    size: usize,
-) -> result::Result<*mut u8, GuestMemoryError> {
-    Ok(mem.get_slice(guest_addr, size)?.as_ptr())
-}
-
 impl VsockPacket {
     /// Create the packet wrapper from a TX virtq chain head.
     ///
@@ -127,7 +118,7 @@ impl VsockPacket {
         }
 
         let mut pkt = Self {
-            hdr: get_host_address(head.mem, head.addr, VSOCK_PKT_HDR_SIZE)
+            hdr: host_address(head.mem, head.addr, VSOCK_PKT_HDR_SIZE)
                 .map_err(VsockError::GuestMemoryMmap)?,
             buf: None,
             buf_size: 0,
@@ -160,7 +151,7 @@ impl VsockPacket {
 
         pkt.buf_size = buf_desc.len as usize;
         pkt.buf = Some(
-            get_host_address(buf_desc.mem, buf_desc.addr, pkt.buf_size)
+            host_address(buf_desc.mem, buf_desc.addr, pkt.buf_size)
                 .map_err(VsockError::GuestMemoryMmap)?,
         );
 
@@ -191,10 +182,10 @@ impl VsockPacket {
         let buf_size = buf_desc.len as usize;
 
         Ok(Self {
-            hdr: get_host_address(head.mem, head.addr, VSOCK_PKT_HDR_SIZE)
+            hdr: host_address(head.mem, head.addr, VSOCK_PKT_HDR_SIZE)
                 .map_err(VsockError::GuestMemoryMmap)?,
             buf: Some(
-                get_host_address(buf_desc.mem, buf_desc.addr, buf_size)
+                host_address(buf_desc.mem, buf_desc.addr, buf_size)
                     .map_err(VsockError::GuestMemoryMmap)?,
             ),
             buf_size,
@@ -420,7 +411,7 @@ mod tests {
 
     fn set_pkt_len(len: u32, guest_desc: &GuestQDesc, mem: &GuestMemoryMmap) {
         let hdr_gpa = guest_desc.addr.get();
-        let hdr_ptr = get_host_address(mem, GuestAddress(hdr_gpa), VSOCK_PKT_HDR_SIZE).unwrap();
+        let hdr_ptr = host_address(mem, GuestAddress(hdr_gpa), VSOCK_PKT_HDR_SIZE).unwrap();
         let len_ptr = unsafe { hdr_ptr.add(HDROFF_LEN) };
 
         byte_order::write_le_u32(unsafe { std::slice::from_raw_parts_mut(len_ptr, 4) }, len);
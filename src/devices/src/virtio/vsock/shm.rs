@@ -0,0 +1,358 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+//
+/// This module implements the shared-memory data path of the vsock device, which spares the
+/// virtqueues the data of the connections, moving it through byte rings mapped by both the guest
+/// and the VMM instead.
+///
+/// The data path is only used once the guest driver acknowledges `VIRTIO_VSOCK_F_KRUN_SHM`, a
+/// feature of this device only. The device then exposes a shared-memory region (shmid 0), split
+/// into channels, each made of two single-producer, single-consumer byte rings: `rx`, from the
+/// host to the guest, and `tx`, from the guest to the host.
+///
+/// Once a connection is established, the device may attach a channel to it, with a
+/// VSOCK_OP_SHM_ATTACH packet holding the index of the channel in `flags`. From then on, the data
+/// of the connection flows through the rings of the channel rather than in VSOCK_OP_RW packets,
+/// and either end sends a VSOCK_OP_SHM_NOTIFY packet after producing data into, or consuming data
+/// from, a ring. The rings provide flow control on their own, so the credit of the connection
+/// only applies to the RW packets exchanged before the attachment. Connections without a channel,
+/// because the feature wasn't negotiated or all the channels are taken, keep using the
+/// virtqueues.
+///
+/// Layout of the region, all integers being little-endian:
+/// - at 0, the header: `SHM_MAGIC`, `SHM_VERSION`, the number of channels, and the size of a ring,
+///   as u32s;
+/// - at `RING_HDR_OFFSET + r * RING_HDR_SIZE`, the producer index of ring `r`, and its consumer
+///   index `CONSUMER_OFFSET` bytes further, as u32s counting the bytes ever produced and consumed;
+/// - at `RING_DATA_OFFSET + r * SHM_RING_SIZE`, the data of ring `r`.
+/// The rings of channel `c` are `2 * c`, its `rx` ring, and `2 * c + 1`, its `tx` ring.
+use std::cmp;
+use std::io::{self, ErrorKind};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::virtio::VirtioShmRegion;
+
+/// The size of the shared-memory region of the vsock device.
+pub const VSOCK_SHM_SIZE: usize = 16 << 20;
+
+/// "KSHM", telling the guest driver the region is laid out as described above.
+const SHM_MAGIC: u32 = 0x4d48_534b;
+const SHM_VERSION: u32 = 1;
+
+const RING_HDR_OFFSET: usize = 0x1000;
+/// The indexes of a ring are kept in separate cache lines, as they're written by different ends.
+const RING_HDR_SIZE: usize = 128;
+const CONSUMER_OFFSET: usize = 64;
+const RING_DATA_OFFSET: usize = 0x1_0000;
+
+/// The size of the data of a ring.
+pub const SHM_RING_SIZE: usize = 128 * 1024;
+/// The number of channels fitting in the region.
+pub const SHM_CHANNELS: usize = (VSOCK_SHM_SIZE - RING_DATA_OFFSET) / (2 * SHM_RING_SIZE);
+
+/// A byte ring of the shared-memory region.
+pub struct ShmRing {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    data: *mut u8,
+}
+
+// Safe because the ring only points into the shared-memory region, which is part of the guest
+// memory, and as such outlives the device.
+unsafe impl Send for ShmRing {}
+
+impl ShmRing {
+    /// Safe if `base` maps a region of at least `VSOCK_SHM_SIZE` bytes, and `index` is lower than
+    /// `2 * SHM_CHANNELS`.
+    unsafe fn new(base: *mut u8, index: usize) -> Self {
+        let hdr = base.add(RING_HDR_OFFSET + index * RING_HDR_SIZE);
+        ShmRing {
+            producer: hdr as *const AtomicU32,
+            consumer: hdr.add(CONSUMER_OFFSET) as *const AtomicU32,
+            data: base.add(RING_DATA_OFFSET + index * SHM_RING_SIZE),
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // Safe because the index is aligned, and lives in the region.
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // Safe because the index is aligned, and lives in the region.
+        unsafe { &*self.consumer }
+    }
+
+    /// Returns the number of bytes waiting in the ring, or EINVAL if its indexes, one of them being
+    /// written by the guest, are inconsistent.
+    pub fn len(&self) -> io::Result<usize> {
+        let used = self
+            .producer()
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.consumer().load(Ordering::Acquire)) as usize;
+        if used > SHM_RING_SIZE {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(used)
+    }
+
+    /// Whether nothing is waiting in the ring. A corrupted ring holds nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len().map_or(true, |used| used == 0)
+    }
+
+    /// Whether nothing more fits in the ring. A corrupted ring has no room.
+    pub fn is_full(&self) -> bool {
+        self.len().map_or(true, |used| used == SHM_RING_SIZE)
+    }
+
+    /// Fills the ring, as its producer, with the data `read` writes in the buffers it's given,
+    /// until the ring is full, or `read` would block, returns 0 or fails. Returns the number of
+    /// bytes produced, and whether `read` returned 0.
+    pub fn produce<F>(&self, mut read: F) -> io::Result<(usize, bool)>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<usize>,
+    {
+        let mut total = 0;
+        loop {
+            let used = self.len()?;
+            if used == SHM_RING_SIZE {
+                return Ok((total, false));
+            }
+            let producer = self.producer().load(Ordering::Relaxed);
+            let start = producer as usize % SHM_RING_SIZE;
+            let len = cmp::min(SHM_RING_SIZE - used, SHM_RING_SIZE - start);
+            // Safe because the buffer lies in the free space of the ring, which the guest doesn't
+            // access until the producer index moves past it.
+            let buf = unsafe { slice::from_raw_parts_mut(self.data.add(start), len) };
+            match read(buf) {
+                Ok(0) => return Ok((total, true)),
+                Ok(count) => {
+                    self.producer()
+                        .store(producer.wrapping_add(count as u32), Ordering::Release);
+                    total += count;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || total > 0 => {
+                    return Ok((total, false))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Drains the ring, as its consumer, handing the data waiting in it to `write`, until the
+    /// ring is empty, or `write` would block, returns 0 or fails. Returns the number of bytes
+    /// consumed.
+    pub fn consume<F>(&self, mut write: F) -> io::Result<usize>
+    where
+        F: FnMut(&[u8]) -> io::Result<usize>,
+    {
+        let mut total = 0;
+        loop {
+            let used = self.len()?;
+            if used == 0 {
+                return Ok(total);
+            }
+            let consumer = self.consumer().load(Ordering::Relaxed);
+            let start = consumer as usize % SHM_RING_SIZE;
+            let len = cmp::min(used, SHM_RING_SIZE - start);
+            // Safe because the buffer lies in the used space of the ring, which the guest doesn't
+            // modify until the consumer index moves past it.
+            let buf = unsafe { slice::from_raw_parts(self.data.add(start), len) };
+            match write(buf) {
+                Ok(0) => return Ok(total),
+                Ok(count) => {
+                    self.consumer()
+                        .store(consumer.wrapping_add(count as u32), Ordering::Release);
+                    total += count;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || total > 0 => return Ok(total),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn reset(&self) {
+        self.producer().store(0, Ordering::Release);
+        self.consumer().store(0, Ordering::Release);
+    }
+}
+
+/// A channel of the shared-memory region, attached to a connection. It's given back to the pool
+/// once dropped.
+pub struct ShmChannel {
+    index: usize,
+    /// The ring carrying the data from the host to the guest.
+    pub rx: ShmRing,
+    /// The ring carrying the data from the guest to the host.
+    pub tx: ShmRing,
+    free: Arc<Mutex<Vec<usize>>>,
+}
+
+impl ShmChannel {
+    pub fn index(&self) -> u32 {
+        self.index as u32
+    }
+}
+
+impl Drop for ShmChannel {
+    fn drop(&mut self) {
+        self.rx.reset();
+        self.tx.reset();
+        self.free.lock().unwrap().push(self.index);
+    }
+}
+
+/// The pool of channels of the shared-memory region.
+pub struct ShmChannels {
+    base: u64,
+    free: Arc<Mutex<Vec<usize>>>,
+}
+
+impl ShmChannels {
+    /// Lays out the channels over `region`, which must be at least `VSOCK_SHM_SIZE` bytes long.
+    pub fn new(region: &VirtioShmRegion) -> Self {
+        assert!(region.size >= VSOCK_SHM_SIZE);
+        let base = region.host_addr as *mut u8;
+
+        let hdr = [
+            SHM_MAGIC,
+            SHM_VERSION,
+            SHM_CHANNELS as u32,
+            SHM_RING_SIZE as u32,
+        ];
+        for (i, value) in hdr.iter().enumerate() {
+            // Safe because the header lives at the start of the region.
+            unsafe { ptr::write_volatile((base as *mut u32).add(i), value.to_le()) };
+        }
+        for index in 0..2 * SHM_CHANNELS {
+            // Safe because the region is large enough for all the rings.
+            unsafe { ShmRing::new(base, index) }.reset();
+        }
+
+        ShmChannels {
+            base: region.host_addr,
+            free: Arc::new(Mutex::new((0..SHM_CHANNELS).rev().collect())),
+        }
+    }
+
+    /// Takes a free channel from the pool, if any.
+    pub fn alloc(&self) -> Option<ShmChannel> {
+        let index = self.free.lock().unwrap().pop()?;
+        let base = self.base as *mut u8;
+        // Safe because the region is large enough for all the rings, as checked by `new()`.
+        let (rx, tx) = unsafe {
+            (
+                ShmRing::new(base, 2 * index),
+                ShmRing::new(base, 2 * index + 1),
+            )
+        };
+        Some(ShmChannel {
+            index,
+            rx,
+            tx,
+            free: self.free.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestRegion {
+        mem: Vec<u64>,
+    }
+
+    impl TestRegion {
+        fn new() -> Self {
+            TestRegion {
+                mem: vec![0u64; VSOCK_SHM_SIZE / 8],
+            }
+        }
+
+        fn region(&mut self) -> VirtioShmRegion {
+            VirtioShmRegion {
+                host_addr: self.mem.as_mut_ptr() as u64,
+                guest_addr: 0,
+                size: VSOCK_SHM_SIZE,
+            }
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut mem = TestRegion::new();
+        let channels = ShmChannels::new(&mem.region());
+        assert_eq!(mem.mem[0] as u32, SHM_MAGIC);
+
+        let first = channels.alloc().unwrap();
+        assert_eq!(first.index(), 0);
+        let mut taken = vec![first];
+        while let Some(channel) = channels.alloc() {
+            taken.push(channel);
+        }
+        assert_eq!(taken.len(), SHM_CHANNELS);
+
+        taken.truncate(1);
+        assert!(channels.alloc().is_some());
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut mem = TestRegion::new();
+        let channels = ShmChannels::new(&mem.region());
+        let channel = channels.alloc().unwrap();
+        let ring = &channel.rx;
+
+        let data: Vec<u8> = (0..SHM_RING_SIZE + 100).map(|i| i as u8).collect();
+        let mut input = &data[..];
+        let (count, eof) = ring.produce(|buf| io::Read::read(&mut input, buf)).unwrap();
+        assert_eq!((count, eof), (SHM_RING_SIZE, false));
+        assert!(ring.is_full());
+
+        // Drain part of the ring, so the next data wraps around.
+        let mut output = Vec::new();
+        let mut budget = 1000;
+        ring.consume(|buf| {
+            if budget == 0 {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            let count = cmp::min(budget, buf.len());
+            output.extend_from_slice(&buf[..count]);
+            budget -= count;
+            Ok(count)
+        })
+        .unwrap();
+        assert_eq!(ring.len().unwrap(), SHM_RING_SIZE - 1000);
+
+        let (count, eof) = ring.produce(|buf| io::Read::read(&mut input, buf)).unwrap();
+        assert_eq!((count, eof), (100, true));
+
+        ring.consume(|buf| {
+            output.extend_from_slice(buf);
+            Ok(buf.len())
+        })
+        .unwrap();
+        assert!(ring.is_empty());
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_corrupted_ring() {
+        let mut mem = TestRegion::new();
+        let channels = ShmChannels::new(&mem.region());
+        let channel = channels.alloc().unwrap();
+
+        // The guest moves the producer index of its ring past the end of the data.
+        channel
+            .tx
+            .producer()
+            .store(SHM_RING_SIZE as u32 + 1, Ordering::Release);
+        assert!(channel.tx.len().is_err());
+        assert!(channel.tx.consume(|buf| Ok(buf.len())).is_err());
+    }
+}
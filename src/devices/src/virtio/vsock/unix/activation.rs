@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Listening sockets handed over by the embedder, or by a service manager, used in place of those
+/// the muxer would bind when the guest listens on the same host port or Unix path.
+///
+/// The muxer gets a duplicate of the socket each time the guest listens, so the socket outlives
+/// the listeners of the guest, as socket activation expects.
+#[derive(Clone, Default)]
+pub struct ActivatedSockets {
+    tcp: HashMap<u16, Arc<TcpListener>>,
+    unix: HashMap<PathBuf, Arc<UnixListener>>,
+}
+
+impl ActivatedSockets {
+    /// Adds a TCP listener, used when the guest listens on its port.
+    pub fn add_tcp(&mut self, listener: TcpListener) -> io::Result<()> {
+        let port = listener.local_addr()?.port();
+        if self.tcp.contains_key(&port) {
+            return Err(io::Error::from_raw_os_error(libc::EADDRINUSE));
+        }
+        listener.set_nonblocking(true)?;
+        self.tcp.insert(port, Arc::new(listener));
+        Ok(())
+    }
+
+    /// Adds a Unix listener, used when the guest listens on its path. Unnamed and abstract
+    /// sockets are refused, as the guest can't listen on them.
+    pub fn add_unix(&mut self, listener: UnixListener) -> io::Result<()> {
+        let path = match listener.local_addr()?.as_pathname() {
+            Some(path) => path.to_path_buf(),
+            None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        if self.unix.contains_key(&path) {
+            return Err(io::Error::from_raw_os_error(libc::EADDRINUSE));
+        }
+        listener.set_nonblocking(true)?;
+        self.unix.insert(path, Arc::new(listener));
+        Ok(())
+    }
+
+    /// Returns a duplicate of the TCP listener on `port`, if any.
+    pub fn tcp(&self, port: u16) -> Option<io::Result<TcpListener>> {
+        self.tcp.get(&port).map(|listener| listener.try_clone())
+    }
+
+    /// Returns a duplicate of the Unix listener on `path`, if any.
+    pub fn unix(&self, path: &Path) -> Option<io::Result<UnixListener>> {
+        self.unix.get(path).map(|listener| listener.try_clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tcp.len() + self.unix.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until a connection is pending on any of the sockets, without accepting it, so the
+    /// guest can still accept it once it listens.
+    pub fn wait_connection(&self) -> io::Result<()> {
+        let mut fds: Vec<libc::pollfd> = self
+            .tcp
+            .values()
+            .map(|listener| listener.as_raw_fd())
+            .chain(self.unix.values().map(|listener| listener.as_raw_fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if fds.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        loop {
+            // Safe because fds is a valid array of pollfd, and we check the return value.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret > 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if ret < 0 && err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_activated_sockets() {
+        let mut sockets = ActivatedSockets::default();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        sockets.add_tcp(tcp.try_clone().unwrap()).unwrap();
+        assert!(sockets.add_tcp(tcp).is_err());
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("activated.sock");
+        let unix = UnixListener::bind(&path).unwrap();
+        sockets.add_unix(unix.try_clone().unwrap()).unwrap();
+        assert!(sockets.add_unix(unix).is_err());
+        assert_eq!(sockets.len(), 2);
+
+        // Each lookup hands out a new duplicate, and the original stays open.
+        let first = sockets.tcp(port).unwrap().unwrap();
+        let second = sockets.tcp(port).unwrap().unwrap();
+        assert_ne!(first.as_raw_fd(), second.as_raw_fd());
+        drop(first);
+        drop(second);
+        assert!(sockets.tcp(port).unwrap().is_ok());
+        assert!(sockets.unix(&path).unwrap().is_ok());
+        assert!(sockets.tcp(port.wrapping_add(1)).is_none());
+        assert!(sockets.unix(&dir.as_path().join("missing.sock")).is_none());
+    }
+
+    #[test]
+    fn test_wait_connection() {
+        let mut sockets = ActivatedSockets::default();
+        assert!(sockets.wait_connection().is_err());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        sockets.add_tcp(tcp).unwrap();
+        let _stream = std::net::TcpStream::connect(addr).unwrap();
+        sockets.wait_connection().unwrap();
+
+        // The connection is still pending, for the guest to accept.
+        assert!(sockets.tcp(addr.port()).unwrap().unwrap().accept().is_ok());
+    }
+}
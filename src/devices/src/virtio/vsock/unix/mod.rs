@@ -7,10 +7,12 @@
 /// `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
 /// handling vsock connection states.
 /// Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
+mod activation;
 mod muxer;
 mod muxer_killq;
 mod muxer_rxq;
 
+pub use activation::ActivatedSockets;
 pub use muxer::VsockMuxer as VsockUnixBackend;
 
 mod defs {
@@ -22,6 +24,9 @@ mod defs {
 
     /// Size of the muxer connection kill queue.
     pub const MUXER_KILLQ_SIZE: usize = 128;
+
+    /// Maximum number of events reaped from the muxer epoll FD on each kick.
+    pub const MUXER_EPOLL_EVENTS: usize = 32;
 }
 
 #[derive(Debug)]
@@ -40,6 +45,8 @@ pub enum Error {
     EpollAdd(std::io::Error),
     /// Error creating an epoll FD.
     EpollFdCreate(std::io::Error),
+    /// The fd budget of the device is spent.
+    FdBudget(std::io::Error),
     /// The host made an invalid vsock port connection request.
     InvalidPortRequest,
     /// Error accepting a new connection from the host-side Unix socket.
@@ -66,6 +73,53 @@ pub enum Error {
     WrapTcpPortMap,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AddressInvalidIpv4 => write!(f, "The IPv4 address is invalid"),
+            AddressInvalidBuffer => write!(f, "The address buffer is invalid"),
+            AddressInvalidFamily => write!(f, "The address family is invalid"),
+            AddressInvalidPath => write!(f, "The UNIX path is invalid"),
+            AddressInvalidPort => write!(f, "The IPv4 port is invalid"),
+            EpollAdd(_) => write!(f, "Failed to register an fd with epoll"),
+            EpollFdCreate(_) => write!(f, "Failed to create the epoll fd"),
+            FdBudget(_) => write!(f, "The fd budget of the device is spent"),
+            InvalidPortRequest => write!(f, "Invalid vsock port connection request"),
+            UnixAccept(_) => write!(f, "Failed to accept a connection on the Unix socket"),
+            UnixBind(_) => write!(f, "Failed to bind the Unix socket"),
+            UnixConnect(_) => write!(f, "Failed to connect to the Unix socket"),
+            UnixRead(_) => write!(f, "Failed to read from the Unix socket"),
+            TcpConnect(_) => write!(f, "Failed to connect to the TCP address"),
+            TooManyConnections => write!(f, "The connection limit was reached"),
+            WrapUnixAccept(_) => {
+                write!(
+                    f,
+                    "Failed to accept a connection on the wrapped Unix socket"
+                )
+            }
+            WrapUnixBind(_) => write!(f, "Failed to bind the wrapped Unix socket"),
+            WrapTcpAccept(_) => {
+                write!(f, "Failed to accept a connection on the wrapped TCP socket")
+            }
+            WrapTcpBind(_) => write!(f, "Failed to bind the wrapped TCP socket"),
+            WrapTcpPortMap => write!(f, "The guest port is not in the port map"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+        match self {
+            EpollAdd(e) | EpollFdCreate(e) | FdBudget(e) | UnixAccept(e) | UnixBind(e)
+            | UnixConnect(e) | UnixRead(e) | TcpConnect(e) | WrapUnixAccept(e)
+            | WrapUnixBind(e) | WrapTcpAccept(e) | WrapTcpBind(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 type MuxerConnection = super::csm::VsockConnection;
@@ -38,20 +38,28 @@ use std::net::{TcpListener, TcpStream};
 use std::os::raw::c_char;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
 
+use logger::audit::{AuditEvent, FlowDirection};
+use logger::AUDIT;
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 
-use super::super::csm::{CommonStream, ConnState, Error as CsmError};
+use super::super::csm::{CommonStream, ConnConfig, ConnState, Error as CsmError};
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
+use super::super::shm::ShmChannels;
 use super::super::{
     Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
 };
+use super::activation::ActivatedSockets;
 use super::defs;
 use super::muxer_killq::MuxerKillQ;
 use super::muxer_rxq::MuxerRxQ;
 use super::MuxerConnection;
 use super::{Error, Result};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FAULTS;
+use crate::fd_budget::{FdAccount, FdToken};
 
 impl CommonStream for UnixStream {
     fn get_write_buf(&self) -> Option<Vec<u8>> {
@@ -138,6 +146,22 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// The channels of the shared-memory region, once the guest driver negotiated the
+    /// shared-memory data path.
+    shm: Option<ShmChannels>,
+    /// The listening sockets used in place of those bound for the guest.
+    activated: ActivatedSockets,
+    /// The flow control parameters of the connections.
+    conn_config: ConnConfig,
+    /// Where the next walk of the connection pool, rebuilding the RX queue, starts.
+    rxq_cursor: usize,
+    /// The account the fds of the connections are charged to, if any.
+    fd_account: Option<FdAccount>,
+    /// The fds of the connections accounted for.
+    fd_tokens: HashMap<ConnMapKey, FdToken>,
+    /// The events reaped from the nested epoll FD, kept across kicks to avoid allocating them on
+    /// each one.
+    epoll_events: Vec<EpollEvent>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -153,7 +177,7 @@ impl VsockChannel for VsockMuxer {
         // the queue might be out-of-sync. If that's the case, we'll attempt to sync it first,
         // and then try to pop something out again.
         if self.rxq.is_empty() && !self.rxq.is_synced() {
-            self.rxq = MuxerRxQ::from_conn_map(&self.conn_map);
+            self.rxq = MuxerRxQ::from_conn_map(&self.conn_map, &mut self.rxq_cursor);
         }
 
         while let Some(rx) = self.rxq.pop() {
@@ -180,9 +204,16 @@ impl VsockChannel for VsockMuxer {
                 // to say.
                 MuxerRx::ConnRx(key) => {
                     let mut conn_res = Err(VsockError::NoData);
+                    let mut still_pending = false;
                     self.apply_conn_mutation(key, |conn| {
                         conn_res = conn.recv_pkt(pkt);
+                        still_pending = conn.has_pending_rx();
                     });
+                    // The connection was already popped from the RX queue, so it has to get
+                    // back in line if it has more to say.
+                    if still_pending {
+                        self.rxq.push(MuxerRx::ConnRx(key));
+                    }
                     conn_res
                 }
             };
@@ -243,8 +274,10 @@ impl VsockChannel for VsockMuxer {
             match pkt.op() {
                 uapi::VSOCK_OP_REQUEST_EX => {
                     // A connection request with extended parameters
-                    self.handle_peer_request_ex_pkt(&pkt)
-                        .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()))
+                    self.handle_peer_request_ex_pkt(&pkt).unwrap_or_else(|err| {
+                        self.check_fd_error(&err);
+                        self.enq_rst(pkt.dst_port(), pkt.src_port())
+                    })
                 }
                 uapi::VSOCK_OP_WRAP_LISTEN => {
                     // A listen request for wrapped socket with extended parameters
@@ -271,6 +304,14 @@ impl VsockChannel for VsockMuxer {
             return Ok(());
         }
 
+        #[cfg(feature = "fault-injection")]
+        {
+            if pkt.op() == uapi::VSOCK_OP_RW && FAULTS.take_vsock_reset() {
+                self.kill_connection(conn_key);
+                return Ok(());
+            }
+        }
+
         // Alright, everything looks in order - forward this packet to its owning connection.
         let mut res: VsockResult<()> = Ok(());
         self.apply_conn_mutation(conn_key, |conn| {
@@ -310,7 +351,8 @@ impl VsockEpollListener for VsockMuxer {
     fn notify(&mut self, _: EventSet) {
         debug!("vsock: muxer received kick");
 
-        let mut epoll_events = vec![EpollEvent::new(EventSet::empty(), 0); 32];
+        // The buffer is moved out while the events are handled, since handling them needs `self`.
+        let mut epoll_events = std::mem::take(&mut self.epoll_events);
         match self
             .epoll
             .wait(epoll_events.len(), 0, epoll_events.as_mut_slice())
@@ -330,14 +372,31 @@ impl VsockEpollListener for VsockMuxer {
                 warn!("vsock: failed to consume muxer epoll event: {}", e);
             }
         }
+        self.epoll_events = epoll_events;
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl VsockBackend for VsockMuxer {
+    fn set_shm(&mut self, shm: ShmChannels) {
+        self.shm = Some(shm);
+    }
+
+    fn set_fd_account(&mut self, account: FdAccount) {
+        self.fd_account = Some(account);
+    }
+
+    fn set_activated_sockets(&mut self, sockets: ActivatedSockets) {
+        self.activated = sockets;
+    }
+}
 
 impl VsockMuxer {
     /// Muxer constructor.
-    pub fn new(cid: u64, host_port_map: Option<HashMap<u16, u16>>) -> Result<Self> {
+    pub fn new(
+        cid: u64,
+        host_port_map: Option<HashMap<u16, u16>>,
+        conn_config: ConnConfig,
+    ) -> Result<Self> {
         #[allow(unused_mut)]
         let mut epoll = Epoll::new().map_err(Error::EpollFdCreate)?;
         #[cfg(target_os = "macos")]
@@ -354,6 +413,13 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            shm: None,
+            conn_config,
+            rxq_cursor: 0,
+            fd_account: None,
+            activated: ActivatedSockets::default(),
+            fd_tokens: HashMap::with_capacity(defs::MAX_CONNECTIONS),
+            epoll_events: vec![EpollEvent::new(EventSet::empty(), 0); defs::MUXER_EPOLL_EVENTS],
         };
 
         Ok(muxer)
@@ -389,7 +455,12 @@ impl VsockMuxer {
                 listener
                     .accept()
                     .map_err(Error::UnixAccept)
-                    .and_then(|(stream, _)| {
+                    .and_then(|(stream, addr)| {
+                        AUDIT.record(AuditEvent::NetworkFlowOpened {
+                            direction: FlowDirection::Inbound,
+                            protocol: "tcp",
+                            address: &addr.to_string(),
+                        });
                         stream
                             .set_nonblocking(true)
                             .map(|_| stream)
@@ -414,10 +485,12 @@ impl VsockMuxer {
                                 self.cid,
                                 local_port,
                                 peer_port,
+                                self.conn_config,
                             ),
                         )
                     })
                     .unwrap_or_else(|err| {
+                        self.check_fd_error(&err);
                         warn!("vsock: unable to accept wrapped TCP connection: {:?}", err);
                     });
             }
@@ -429,6 +502,18 @@ impl VsockMuxer {
                     .accept()
                     .map_err(Error::UnixAccept)
                     .and_then(|(stream, _)| {
+                        // Peers of a Unix socket are usually unnamed, so record the path the
+                        // connection came through instead.
+                        let address = listener
+                            .local_addr()
+                            .ok()
+                            .and_then(|addr| addr.as_pathname().map(|p| p.display().to_string()))
+                            .unwrap_or_default();
+                        AUDIT.record(AuditEvent::NetworkFlowOpened {
+                            direction: FlowDirection::Inbound,
+                            protocol: "unix",
+                            address: &address,
+                        });
                         stream
                             .set_nonblocking(true)
                             .map(|_| stream)
@@ -447,10 +532,12 @@ impl VsockMuxer {
                                 self.cid,
                                 local_port,
                                 peer_port,
+                                self.conn_config,
                             ),
                         )
                     })
                     .unwrap_or_else(|err| {
+                        self.check_fd_error(&err);
                         warn!("vsock: unable to accept wrapped unix connection: {:?}", err);
                     });
             }
@@ -461,7 +548,7 @@ impl VsockMuxer {
     }
 
     /// Add a new connection to the active connection pool.
-    fn add_connection(&mut self, key: ConnMapKey, conn: MuxerConnection) -> Result<()> {
+    fn add_connection(&mut self, key: ConnMapKey, mut conn: MuxerConnection) -> Result<()> {
         // We might need to make room for this new connection, so let's sweep the kill queue
         // first.  It's fine to do this here because:
         // - unless the kill queue is out of sync, this is a pretty inexpensive operation; and
@@ -477,6 +564,16 @@ impl VsockMuxer {
             return Err(Error::TooManyConnections);
         }
 
+        let fd_token = match &self.fd_account {
+            Some(account) => Some(account.acquire().map_err(Error::FdBudget)?),
+            None => None,
+        };
+
+        // Connections beyond the number of shared-memory channels use the virtqueues.
+        if let Some(channel) = self.shm.as_ref().and_then(|shm| shm.alloc()) {
+            conn.attach_shm(channel);
+        }
+
         self.add_listener(
             conn.as_raw_fd(),
             EpollListener::Connection {
@@ -492,6 +589,9 @@ impl VsockMuxer {
                 self.rxq.push(MuxerRx::ConnRx(key));
             }
             self.conn_map.insert(key, conn);
+            if let Some(fd_token) = fd_token {
+                self.fd_tokens.insert(key, fd_token);
+            }
             Ok(())
         })
     }
@@ -501,9 +601,24 @@ impl VsockMuxer {
         if let Some(conn) = self.conn_map.remove(&key) {
             self.remove_listener(conn.as_raw_fd());
         }
+        self.fd_tokens.remove(&key);
         self.free_local_port(key.local_port);
     }
 
+    /// Reports `err` to the fd account, if it means the host ran out of fds.
+    fn check_fd_error(&self, err: &Error) {
+        let io_err = match err {
+            Error::UnixAccept(e)
+            | Error::UnixConnect(e)
+            | Error::TcpConnect(e)
+            | Error::WrapUnixAccept(e) => e,
+            _ => return,
+        };
+        if let Some(account) = &self.fd_account {
+            account.check_error(io_err);
+        }
+    }
+
     /// Schedule a connection for immediate termination.
     /// I.e. as soon as we can also let our peer know we're dropping the connection, by sending
     /// it an RST packet.
@@ -593,10 +708,16 @@ impl VsockMuxer {
                 debug!("vsock ports src={} dst={}", pkt.src_port(), pkt.dst_port());
                 debug!("should connect to {}:{}", ipv4_addr, port);
 
-                TcpStream::connect(format!("{}:{}", ipv4_addr, port))
+                let address = format!("{}:{}", ipv4_addr, port);
+                TcpStream::connect(&address)
                     .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
                     .map_err(Error::TcpConnect)
                     .and_then(|stream| {
+                        AUDIT.record(AuditEvent::NetworkFlowOpened {
+                            direction: FlowDirection::Outbound,
+                            protocol: "tcp",
+                            address: &address,
+                        });
                         self.add_connection(
                             ConnMapKey {
                                 local_port: pkt.dst_port(),
@@ -609,6 +730,7 @@ impl VsockMuxer {
                                 pkt.dst_port(),
                                 pkt.src_port(),
                                 pkt.buf_alloc(),
+                                self.conn_config,
                             ),
                         )
                     })
@@ -621,6 +743,11 @@ impl VsockMuxer {
                     .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
                     .map_err(Error::UnixConnect)
                     .and_then(|stream| {
+                        AUDIT.record(AuditEvent::NetworkFlowOpened {
+                            direction: FlowDirection::Outbound,
+                            protocol: "unix",
+                            address: path,
+                        });
                         self.add_connection(
                             ConnMapKey {
                                 local_port: pkt.dst_port(),
@@ -633,6 +760,7 @@ impl VsockMuxer {
                                 pkt.dst_port(),
                                 pkt.src_port(),
                                 pkt.buf_alloc(),
+                                self.conn_config,
                             ),
                         )
                     })
@@ -666,10 +794,22 @@ impl VsockMuxer {
                 debug!("vsock ports src={} dst={}", pkt.src_port(), pkt.dst_port());
                 debug!("should listen at {}:{}", ipv4_addr, port);
 
-                TcpListener::bind(format!("{}:{}", ipv4_addr, port))
+                let address = format!("{}:{}", ipv4_addr, port);
+                let listener = match self.activated.tcp(port) {
+                    Some(listener) => {
+                        debug!("listening through the activated socket of port {}", port);
+                        listener
+                    }
+                    None => TcpListener::bind(&address),
+                };
+                listener
                     .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
                     .map_err(Error::WrapTcpBind)
                     .and_then(|sock| {
+                        AUDIT.record(AuditEvent::NetworkListenerOpened {
+                            protocol: "tcp",
+                            address: &address,
+                        });
                         let fd = sock.as_raw_fd();
                         self.add_listener(
                             fd,
@@ -687,15 +827,27 @@ impl VsockMuxer {
 
                 debug!("should listen to unix socket at: {:?}", path);
 
-                // HACK: FS is shared between VMM and guest, so if we don't
-                // unlink() the path , the bind() will receive "AddrInUse"
-                // error.
-                let _ = unsafe { unlink(path.as_ptr() as *const c_char) };
-
-                UnixListener::bind(&path)
+                let listener = match self.activated.unix(Path::new(path)) {
+                    Some(listener) => {
+                        debug!("listening through the activated socket of {:?}", path);
+                        listener
+                    }
+                    None => {
+                        // HACK: FS is shared between VMM and guest, so if we don't
+                        // unlink() the path , the bind() will receive "AddrInUse"
+                        // error.
+                        let _ = unsafe { unlink(path.as_ptr() as *const c_char) };
+                        UnixListener::bind(&path)
+                    }
+                };
+                listener
                     .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
                     .map_err(Error::WrapUnixBind)
                     .and_then(|sock| {
+                        AUDIT.record(AuditEvent::NetworkListenerOpened {
+                            protocol: "unix",
+                            address: path,
+                        });
                         let fd = sock.as_raw_fd();
                         self.add_listener(
                             fd,
@@ -891,7 +1043,7 @@ mod tests {
             )
             .unwrap();
 
-            let muxer = VsockMuxer::new(PEER_CID, None).unwrap();
+            let muxer = VsockMuxer::new(PEER_CID, None, ConnConfig::default()).unwrap();
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
                 pkt,
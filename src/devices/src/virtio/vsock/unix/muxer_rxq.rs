@@ -45,12 +45,30 @@ impl MuxerRxQ {
     /// Note: the resulting queue may still be desynchronized, if there are too many connections
     ///       that have pending RX data. In that case, the muxer will first drain this queue, and
     ///       then try again to build a synchronized one.
-    pub fn from_conn_map(conn_map: &HashMap<ConnMapKey, MuxerConnection>) -> Self {
+    ///
+    /// The walk of the pool starts at its `cursor`th connection, and `cursor` is moved to where
+    /// the walk stopped, so that the next one starts there. Otherwise, with too many busy
+    /// connections, the ones at the end of the pool would never get a chance to yield data.
+    pub fn from_conn_map(
+        conn_map: &HashMap<ConnMapKey, MuxerConnection>,
+        cursor: &mut usize,
+    ) -> Self {
         let mut q = VecDeque::new();
         let mut synced = true;
 
-        for (key, conn) in conn_map.iter() {
+        let start = if conn_map.is_empty() {
+            0
+        } else {
+            *cursor % conn_map.len()
+        };
+        let mut walked = 0;
+        for (key, conn) in conn_map
+            .iter()
+            .skip(start)
+            .chain(conn_map.iter().take(start))
+        {
             if !conn.has_pending_rx() {
+                walked += 1;
                 continue;
             }
             if q.len() >= Self::SIZE {
@@ -58,7 +76,9 @@ impl MuxerRxQ {
                 break;
             }
             q.push_back(MuxerRx::ConnRx(*key));
+            walked += 1;
         }
+        *cursor = start + walked;
         Self { q, synced }
     }
 
@@ -0,0 +1,190 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracks the depth of virtqueues, the number of descriptor chains the driver made available and
+//! the device didn't pop yet, and reports it crossing a high or a low watermark, so the embedder
+//! can throttle whatever feeds a device rather than letting data pile up in buffers.
+//!
+//! On a queue carrying data to the device, like a TX queue, reaching the high watermark means the
+//! device isn't keeping up with the guest. On a queue carrying data to the guest, like an RX
+//! queue, falling to the low watermark means the guest isn't handing buffers back fast enough,
+//! and the data for it is held on the host in the meantime.
+
+use std::fmt;
+use std::sync::Arc;
+
+use logger::METRICS;
+
+/// Which watermark a queue crossed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatermarkLevel {
+    /// The depth rose to the high watermark.
+    High,
+    /// The depth fell to the low watermark.
+    Low,
+}
+
+/// A queue crossing one of its watermarks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatermarkEvent {
+    /// The virtio type of the device.
+    pub device_type: u32,
+    /// The index of the queue in the device.
+    pub queue: u16,
+    /// The depth of the queue.
+    pub depth: u16,
+    /// The watermark crossed.
+    pub level: WatermarkLevel,
+}
+
+/// Callback invoked with every watermark crossing, from the thread of the device.
+pub type WatermarkCallback = Arc<dyn Fn(&WatermarkEvent) + Send + Sync>;
+
+/// The watermarks applied to the queues of the devices, and the callback they report to.
+#[derive(Clone)]
+pub struct QueueWatermarks {
+    low: u16,
+    high: u16,
+    callback: WatermarkCallback,
+}
+
+impl QueueWatermarks {
+    /// Creates watermarks reporting to `callback`. `low` must be lower than `high`.
+    pub fn new(low: u16, high: u16, callback: WatermarkCallback) -> Self {
+        assert!(low < high);
+        QueueWatermarks {
+            low,
+            high,
+            callback,
+        }
+    }
+
+    pub fn low(&self) -> u16 {
+        self.low
+    }
+
+    pub fn high(&self) -> u16 {
+        self.high
+    }
+}
+
+impl fmt::Debug for QueueWatermarks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueueWatermarks")
+            .field("low", &self.low)
+            .field("high", &self.high)
+            .finish()
+    }
+}
+
+/// The watermark state of a single queue.
+#[derive(Clone)]
+pub struct QueueWatermark {
+    watermarks: QueueWatermarks,
+    device_type: u32,
+    queue: u16,
+    /// The last watermark crossed. Queues start empty, so below the low one.
+    level: WatermarkLevel,
+    max_depth: u16,
+}
+
+impl QueueWatermark {
+    pub fn new(watermarks: QueueWatermarks, device_type: u32, queue: u16) -> Self {
+        QueueWatermark {
+            watermarks,
+            device_type,
+            queue,
+            level: WatermarkLevel::Low,
+            max_depth: 0,
+        }
+    }
+
+    /// Returns the largest depth the queue has reached.
+    pub fn max_depth(&self) -> u16 {
+        self.max_depth
+    }
+
+    /// Records the current depth of the queue, reporting any watermark it crossed.
+    pub(crate) fn update(&mut self, depth: u16) {
+        self.max_depth = self.max_depth.max(depth);
+
+        let level = if depth >= self.watermarks.high {
+            WatermarkLevel::High
+        } else if depth <= self.watermarks.low {
+            WatermarkLevel::Low
+        } else {
+            return;
+        };
+        if level == self.level {
+            return;
+        }
+        self.level = level;
+
+        match level {
+            WatermarkLevel::High => METRICS.queue.high_watermarks.inc(),
+            WatermarkLevel::Low => METRICS.queue.low_watermarks.inc(),
+        }
+        (self.watermarks.callback)(&WatermarkEvent {
+            device_type: self.device_type,
+            queue: self.queue,
+            depth,
+            level,
+        });
+    }
+}
+
+impl fmt::Debug for QueueWatermark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "QueueWatermark {{ device_type: {}, queue: {}, level: {:?} }}",
+            self.device_type, self.queue, self.level
+        )
+    }
+}
+
+impl PartialEq for QueueWatermark {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.watermarks.callback, &other.watermarks.callback)
+            && self.device_type == other.device_type
+            && self.queue == other.queue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_watermark_hysteresis() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let callback_events = events.clone();
+        let watermarks = QueueWatermarks::new(
+            2,
+            8,
+            Arc::new(move |event: &WatermarkEvent| {
+                callback_events.lock().unwrap().push(event.clone())
+            }),
+        );
+        let mut watermark = QueueWatermark::new(watermarks, 19, 1);
+
+        // Depths between the watermarks, or on the side last crossed, aren't reported.
+        for depth in &[0, 5, 7, 9, 12, 5, 3, 10, 2, 1, 0] {
+            watermark.update(*depth);
+        }
+        assert_eq!(watermark.max_depth(), 12);
+
+        let events = events.lock().unwrap();
+        let levels: Vec<(u16, WatermarkLevel)> = events
+            .iter()
+            .map(|event| (event.depth, event.level))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![(9, WatermarkLevel::High), (2, WatermarkLevel::Low),]
+        );
+        assert_eq!(events[0].device_type, 19);
+        assert_eq!(events[0].queue, 1);
+    }
+}
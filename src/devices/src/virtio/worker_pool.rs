@@ -0,0 +1,220 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A pool of threads shared by the devices, running the requests that issue blocking syscalls,
+//! like those of virtio-fs, off the thread of the event loop, so a share backed by a slow
+//! filesystem doesn't hold up the console or vsock.
+//!
+//! Each device submits its requests through a `WorkerGroup`, which caps the number of them in
+//! flight, so a single device can't take all the threads, and signals an `EventFd` as each of them
+//! completes, for the device to hand the results back to the guest from the event loop.
+//!
+//! A job panicking doesn't take its thread down. The panic is kept by the group, for the device to
+//! raise it again from the event loop, where it's handled like any other panic of the device.
+
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use utils::eventfd::{EventFd, EFD_NONBLOCK};
+
+/// The number of threads of the pool by default.
+pub const DEFAULT_WORKER_THREADS: usize = 4;
+/// The number of requests a device can have in flight by default.
+pub const DEFAULT_DEVICE_LIMIT: usize = 16;
+
+/// A request run by the pool.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// The threads running the jobs, and the queue feeding them.
+pub struct WorkerPool {
+    sender: Mutex<SyncSender<Job>>,
+    threads: usize,
+}
+
+impl WorkerPool {
+    /// Spawns `threads` threads, fed by a queue holding up to `queue_depth` jobs. The threads exit
+    /// once the pool, and every group created from it, is dropped.
+    pub fn new(threads: usize, queue_depth: usize) -> io::Result<Arc<WorkerPool>> {
+        let (sender, receiver) = sync_channel::<Job>(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("krun worker {}", i))
+                .spawn(move || Self::work(receiver))?;
+        }
+
+        Ok(Arc::new(WorkerPool {
+            sender: Mutex::new(sender),
+            threads,
+        }))
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            // The lock is released before running the job, so the other threads can pick the
+            // next ones.
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            job();
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Creates a group for a device, allowing it up to `limit` jobs in flight.
+    pub fn group(self: &Arc<Self>, limit: usize) -> io::Result<WorkerGroup> {
+        Ok(WorkerGroup {
+            pool: self.clone(),
+            limit,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            completion_evt: Arc::new(EventFd::new(EFD_NONBLOCK)?),
+            panic: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WorkerPool {{ threads: {} }}", self.threads)
+    }
+}
+
+/// The share of the pool given to a device.
+pub struct WorkerGroup {
+    pool: Arc<WorkerPool>,
+    limit: usize,
+    in_flight: Arc<AtomicUsize>,
+    completion_evt: Arc<EventFd>,
+    panic: Arc<Mutex<Option<Box<dyn Any + Send>>>>,
+}
+
+impl WorkerGroup {
+    /// Signaled after each job of the group completes.
+    pub fn completion_evt(&self) -> &EventFd {
+        &self.completion_evt
+    }
+
+    /// Returns the number of jobs of the group submitted and not completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Takes the payload of the first job of the group that panicked since the last call, to be
+    /// raised again with `std::panic::resume_unwind()`.
+    pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+        self.panic.lock().unwrap().take()
+    }
+
+    /// Returns whether the group can take another job.
+    pub fn has_capacity(&self) -> bool {
+        self.in_flight() < self.limit
+    }
+
+    /// Queues `job` on the pool. Returns false, dropping the job, if the group already has its
+    /// limit of jobs in flight or the queue of the pool is full, in which case the device should
+    /// hold the request back, and retry once one of its jobs completes.
+    pub fn try_submit<F: FnOnce() + Send + 'static>(&self, job: F) -> bool {
+        if !self.has_capacity() {
+            return false;
+        }
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+
+        // The job is accounted for before the completion is signaled, so a device woken up by
+        // the event always finds the room the job left.
+        let in_flight = self.in_flight.clone();
+        let completion_evt = self.completion_evt.clone();
+        let group_panic = self.panic.clone();
+        let wrapped: Job = Box::new(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                group_panic.lock().unwrap().get_or_insert(payload);
+            }
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+            if let Err(e) = completion_evt.write(1) {
+                error!("Failed to signal job completion: {:?}", e);
+            }
+        });
+
+        match self.pool.sender.lock().unwrap().try_send(wrapped) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Debug for WorkerGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "WorkerGroup {{ limit: {}, in_flight: {} }}",
+            self.limit,
+            self.in_flight()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_group_limit() {
+        let pool = WorkerPool::new(2, 8).unwrap();
+        let group = pool.group(1).unwrap();
+
+        // Hold the first job until the second is turned down.
+        let barrier = Arc::new(Barrier::new(2));
+        let job_barrier = barrier.clone();
+        assert!(group.try_submit(move || {
+            job_barrier.wait();
+        }));
+        assert_eq!(group.in_flight(), 1);
+        assert!(!group.try_submit(|| ()));
+        barrier.wait();
+
+        // Wait for the completion, then there's room for another job.
+        while group.completion_evt().read().is_err() {
+            thread::yield_now();
+        }
+        assert_eq!(group.in_flight(), 0);
+
+        let (sender, receiver) = channel();
+        assert!(group.try_submit(move || sender.send(42).unwrap()));
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_job_panic() {
+        let pool = WorkerPool::new(1, 8).unwrap();
+        let group = pool.group(2).unwrap();
+
+        assert!(group.try_submit(|| panic!("bad request")));
+        while group.completion_evt().read().is_err() {
+            thread::yield_now();
+        }
+        assert_eq!(group.in_flight(), 0);
+        let payload = group.take_panic().unwrap();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"bad request"));
+        assert!(group.take_panic().is_none());
+
+        // The thread survived the panic.
+        let (sender, receiver) = channel();
+        assert!(group.try_submit(move || sender.send(42).unwrap()));
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+}
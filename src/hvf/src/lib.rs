@@ -94,6 +94,9 @@ pub enum Error {
     VcpuSetSystemRegister,
     VcpuSetVtimerMask,
     VmCreate,
+    VmDenied,
+    VmMaxVcpuCount,
+    VmUnsupported,
 }
 
 impl Display for Error {
@@ -113,6 +116,12 @@ impl Display for Error {
             VcpuSetSystemRegister => write!(f, "Error setting HVF vCPU system register"),
             VcpuSetVtimerMask => write!(f, "Error setting HVF vCPU vtimer mask"),
             VmCreate => write!(f, "Error creating HVF VM instance"),
+            VmDenied => write!(
+                f,
+                "Access to HVF denied, is the process signed with the hypervisor entitlement?"
+            ),
+            VmMaxVcpuCount => write!(f, "Error getting the maximum number of HVF vCPUs"),
+            VmUnsupported => write!(f, "HVF is not supported on this host"),
         }
     }
 }
@@ -162,6 +171,33 @@ pub fn vcpu_set_vtimer_mask(vcpuid: u64, masked: bool) -> Result<(), Error> {
     }
 }
 
+/// Checks whether a VM can be created, without keeping it around. There can only be one VM per
+/// process, so an existing one also means HVF is usable.
+pub fn check_vm_create() -> Result<(), Error> {
+    let ret = unsafe { hv_vm_create(std::ptr::null_mut()) };
+    match ret {
+        HV_SUCCESS => {
+            unsafe { hv_vm_destroy() };
+            Ok(())
+        }
+        HV_BUSY => Ok(()),
+        HV_DENIED => Err(Error::VmDenied),
+        HV_UNSUPPORTED => Err(Error::VmUnsupported),
+        _ => Err(Error::VmCreate),
+    }
+}
+
+/// Returns the maximum number of vCPUs a VM can have.
+pub fn vm_max_vcpu_count() -> Result<u32, Error> {
+    let mut count: u32 = 0;
+    let ret = unsafe { hv_vm_get_max_vcpu_count(&mut count) };
+    if ret != HV_SUCCESS {
+        Err(Error::VmMaxVcpuCount)
+    } else {
+        Ok(count)
+    }
+}
+
 pub struct HvfVm {}
 
 impl HvfVm {
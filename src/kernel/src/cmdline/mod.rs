@@ -45,6 +45,8 @@ impl fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 /// Specialized Result type for command line operations.
 pub type Result<T> = result::Result<T, Error>;
 
@@ -0,0 +1,242 @@
+//! Command-line launcher running a microVM, configured from flags and an optional definition file,
+//! to try out kernels and shares without writing an embedder.
+
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process;
+
+use krun::config::{ConfigError, Exec, OptionValue, PortMapping, VmDefinition, Volume};
+use krun::vm::{self, ErrorChain, KrunVmBuilder};
+
+const USAGE: &str = "Usage: krun-run [OPTIONS] [--] COMMAND [ARGS...]
+
+Runs COMMAND inside a microVM.
+
+Options:
+  -c, --config FILE         Load a JSON or TOML microVM definition, overridden by the flags below
+  -r, --root DIR            Host directory to be used as the root of the microVM
+  -v, --volume HOST:GUEST   Map a host directory at a guest path (macOS only)
+  -p, --port HOST:GUEST     Expose a guest TCP port on the host
+  -m, --memory MIB          Amount of RAM, in MiB
+  -n, --cpus N              Number of vCPUs
+  -w, --workdir DIR         Working directory of COMMAND, relative to the root
+  -e, --env NAME=VALUE      Set an environment variable, instead of inheriting the environment
+  -o, --option KEY=VALUE    Set a microVM option, see krun_set_option()
+  -h, --help                Print this help
+
+--volume, --port, --env and --option can be given multiple times.";
+
+#[derive(Debug)]
+enum Error {
+    /// The microVM definition is invalid.
+    Config(ConfigError),
+    /// The command line is invalid.
+    Usage(String),
+    /// The microVM couldn't be built or stopped running.
+    Vm(vm::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "{}", ErrorChain(e)),
+            Error::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            Error::Vm(e) => write!(f, "{}", ErrorChain(e)),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn parse_pair(flag: &str, value: &str, separator: char) -> Result<(String, String)> {
+    let mut parts = value.splitn(2, separator);
+    match (parts.next(), parts.next()) {
+        (Some(first), Some(second)) if !first.is_empty() && !second.is_empty() => {
+            Ok((first.to_string(), second.to_string()))
+        }
+        _ => Err(Error::Usage(format!(
+            "Invalid value for {}: {}",
+            flag, value
+        ))),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::Usage(format!("Invalid value for {}: {}", flag, value)))
+}
+
+/// Builds the microVM definition from the command line arguments, without the program name.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<VmDefinition> {
+    let mut definition = VmDefinition::default();
+    let mut overrides = VmDefinition::default();
+    let mut env: Vec<String> = Vec::new();
+    let mut command: Vec<String> = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" || !arg.starts_with('-') {
+            if arg != "--" {
+                command.push(arg);
+            }
+            command.extend(args);
+            break;
+        }
+
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| Error::Usage(format!("Missing value for {}", arg)))?;
+        match arg.as_str() {
+            "-c" | "--config" => definition = VmDefinition::load(&value).map_err(Error::Config)?,
+            "-r" | "--root" => overrides.root = Some(PathBuf::from(value)),
+            "-v" | "--volume" => {
+                let (host, guest) = parse_pair(&arg, &value, ':')?;
+                overrides.volumes.push(Volume {
+                    host: host.into(),
+                    guest: guest.into(),
+                });
+            }
+            "-p" | "--port" => {
+                let (host, guest) = parse_pair(&arg, &value, ':')?;
+                overrides.ports.push(PortMapping {
+                    host: parse_number(&arg, &host)?,
+                    guest: parse_number(&arg, &guest)?,
+                });
+            }
+            "-m" | "--memory" => overrides.memory_mib = Some(parse_number(&arg, &value)?),
+            "-n" | "--cpus" => overrides.vcpus = Some(parse_number(&arg, &value)?),
+            "-w" | "--workdir" => overrides.workdir = Some(value),
+            "-e" | "--env" => env.push(value),
+            "-o" | "--option" => {
+                let (key, value) = parse_pair(&arg, &value, '=')?;
+                overrides.options.insert(key, OptionValue::String(value));
+            }
+            _ => return Err(Error::Usage(format!("Unknown option: {}", arg))),
+        }
+    }
+
+    // The flags take precedence over the definition file.
+    definition.vcpus = overrides.vcpus.or(definition.vcpus);
+    definition.memory_mib = overrides.memory_mib.or(definition.memory_mib);
+    definition.root = overrides.root.or(definition.root);
+    definition.workdir = overrides.workdir.or(definition.workdir);
+    definition.volumes.extend(overrides.volumes);
+    definition.ports.extend(overrides.ports);
+    definition.options.extend(overrides.options);
+
+    if let Some((path, args)) = command.split_first() {
+        definition.exec = Some(Exec {
+            path: path.clone(),
+            args: args.to_vec(),
+            env: definition.exec.and_then(|exec| exec.env),
+        });
+    }
+    match definition.exec.as_mut() {
+        Some(exec) if !env.is_empty() => exec.env = Some(env),
+        Some(_) => (),
+        None => return Err(Error::Usage("Missing COMMAND".to_string())),
+    }
+
+    definition.validate().map_err(Error::Config)?;
+    Ok(definition)
+}
+
+fn run() -> Result<()> {
+    let definition = parse_args(env::args().skip(1))?;
+    let builder = KrunVmBuilder::new().map_err(Error::Vm)?;
+    let mut vm = definition.apply(builder).build().map_err(Error::Vm)?;
+    // Only returns on error, the process exits once the guest shuts down.
+    vm.run().map_err(Error::Vm)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("krun-run: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let definition = parse_args(args(&[
+            "-r",
+            "/rootfs",
+            "--port",
+            "8080:80",
+            "-n",
+            "2",
+            "-e",
+            "PATH=/bin",
+            "-o",
+            "runtime.max_secs=60",
+            "/bin/sh",
+            "-c",
+            "true",
+        ]))
+        .unwrap();
+
+        assert_eq!(definition.root, Some(PathBuf::from("/rootfs")));
+        assert_eq!(
+            definition.ports,
+            vec![PortMapping {
+                host: 8080,
+                guest: 80
+            }]
+        );
+        assert_eq!(definition.vcpus, Some(2));
+        assert_eq!(
+            definition.exec,
+            Some(Exec {
+                path: "/bin/sh".to_string(),
+                args: vec!["-c".to_string(), "true".to_string()],
+                env: Some(vec!["PATH=/bin".to_string()]),
+            })
+        );
+        assert_eq!(
+            definition
+                .options
+                .get("runtime.max_secs")
+                .unwrap()
+                .to_string(),
+            "60"
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_args() {
+        assert!(matches!(parse_args(args(&[])), Err(Error::Usage(_))));
+        assert!(matches!(
+            parse_args(args(&["-p", "8080", "/bin/sh"])),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["--cpus"])),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["--frobnicate", "1", "/bin/sh"])),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["-p", "8080:80", "-p", "8080:81", "/bin/sh"])),
+            Err(Error::Config(ConfigError::DuplicatePort(8080)))
+        ));
+    }
+}
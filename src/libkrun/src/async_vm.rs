@@ -0,0 +1,322 @@
+//! Runtime-agnostic async interface over `vm::KrunVm`, for embedders building async services
+//! around microVMs.
+//!
+//! The microVM is built and serviced by a dedicated thread running the `EventManager` loop.
+//! Console output and lifecycle changes are delivered from that thread through streams that wake
+//! the task polling them, so they can be awaited from any executor (tokio, async-std, ...).
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use vmm::vmm_config::console::{CallbackInput, CallbackOutput, ConsoleBackend};
+use vmm::vmm_config::device_panic::DeviceFailure;
+use vmm::vmm_config::guest_panic::GuestPanic;
+
+use super::vm::{Error, KrunVmBuilder, Result};
+
+struct Channel<T> {
+    queue: VecDeque<T>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The sending half of an unbounded, single producer, single consumer, stream.
+struct Sender<T>(Arc<Mutex<Channel<T>>>);
+
+impl<T> Sender<T> {
+    fn send(&self, item: T) {
+        let mut channel = self.0.lock().unwrap();
+        channel.queue.push_back(item);
+        if let Some(waker) = channel.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut channel = self.0.lock().unwrap();
+        channel.closed = true;
+        if let Some(waker) = channel.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A stream of items produced by the VMM thread.
+pub struct Receiver<T>(Arc<Mutex<Channel<T>>>);
+
+impl<T> Receiver<T> {
+    /// Polls for the next item. Returns `Poll::Ready(None)` once the stream has been closed and
+    /// drained, which makes it straightforward to adapt to the `Stream` trait of any runtime.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut channel = self.0.lock().unwrap();
+        match channel.queue.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if channel.closed => Poll::Ready(None),
+            None => {
+                channel.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Returns a future resolving to the next item, or `None` if the stream has been closed.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+}
+
+fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Mutex::new(Channel {
+        queue: VecDeque::new(),
+        waker: None,
+        closed: false,
+    }));
+    (Sender(channel.clone()), Receiver(channel))
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct Recv<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Lifecycle events of a microVM.
+#[derive(Clone, Debug)]
+pub enum VmEvent {
+    /// The microVM has been built and its vCPUs are running.
+    Running,
+    /// A device panicked. Unless it was detached, the process terminates right after.
+    DeviceFailed(DeviceFailure),
+    /// The guest kernel panicked or oopsed, as detected with
+    /// `KrunVmBuilder::scan_console_for_panics`.
+    GuestPanicked(GuestPanic),
+    /// The microVM failed to start, or the event loop servicing it stopped.
+    Stopped(Arc<Error>),
+}
+
+#[derive(Clone)]
+enum VmState {
+    Starting,
+    Running,
+    Stopped(Arc<Error>),
+}
+
+struct Lifecycle {
+    state: VmState,
+    wakers: Vec<Waker>,
+    // Dropped once the microVM has stopped, which closes the stream of events.
+    events: Option<Sender<VmEvent>>,
+}
+
+impl Lifecycle {
+    fn send(&self, event: VmEvent) {
+        if let Some(events) = &self.events {
+            events.send(event);
+        }
+    }
+
+    fn transition(&mut self, state: VmState, event: VmEvent) {
+        self.send(event);
+        if let VmState::Stopped(_) = state {
+            self.events = None;
+        }
+        self.state = state;
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Future resolving once the microVM has reached a given lifecycle state.
+pub struct LifecycleFuture {
+    lifecycle: Arc<Mutex<Lifecycle>>,
+    wait_for_stop: bool,
+}
+
+impl Future for LifecycleFuture {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        match &lifecycle.state {
+            VmState::Running if !self.wait_for_stop => Poll::Ready(Ok(())),
+            VmState::Stopped(error) => Poll::Ready(Err(Error::Stopped(error.clone()))),
+            _ => {
+                lifecycle.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A microVM running on a dedicated VMM thread, controlled through non-blocking calls and
+/// futures.
+pub struct AsyncKrunVm {
+    console_input: CallbackInput,
+    console_output: Option<Receiver<Vec<u8>>>,
+    events: Option<Receiver<VmEvent>>,
+    lifecycle: Arc<Mutex<Lifecycle>>,
+}
+
+impl AsyncKrunVm {
+    /// Builds and runs the microVM configured by `builder` on a new thread. The console of the
+    /// microVM is connected to `console_output` and `write_console`.
+    pub fn spawn(builder: KrunVmBuilder) -> Result<Self> {
+        let console_input = CallbackInput::new().map_err(Error::CreateConsole)?;
+        let (output_tx, output_rx) = channel();
+        let console_output =
+            CallbackOutput::new(Box::new(move |buf: &[u8]| output_tx.send(buf.to_vec())));
+        let builder = builder.console(ConsoleBackend::Callbacks {
+            input: console_input.clone(),
+            output: console_output,
+        });
+
+        let (events_tx, events_rx) = channel();
+        let lifecycle = Arc::new(Mutex::new(Lifecycle {
+            state: VmState::Starting,
+            wakers: Vec::new(),
+            events: Some(events_tx),
+        }));
+
+        let failure_lifecycle = lifecycle.clone();
+        let builder = builder.on_device_failure(Box::new(move |failure: &DeviceFailure| {
+            failure_lifecycle
+                .lock()
+                .unwrap()
+                .send(VmEvent::DeviceFailed(failure.clone()))
+        }));
+
+        let panic_lifecycle = lifecycle.clone();
+        let builder = builder.on_guest_panic(Box::new(move |panic: &GuestPanic| {
+            panic_lifecycle
+                .lock()
+                .unwrap()
+                .send(VmEvent::GuestPanicked(panic.clone()))
+        }));
+
+        let thread_lifecycle = lifecycle.clone();
+        thread::Builder::new()
+            .name("krun vmm".to_string())
+            .spawn(move || {
+                let error = match builder.build() {
+                    Ok(mut vm) => {
+                        thread_lifecycle
+                            .lock()
+                            .unwrap()
+                            .transition(VmState::Running, VmEvent::Running);
+                        vm.run().unwrap_err()
+                    }
+                    Err(e) => e,
+                };
+                let error = Arc::new(error);
+                thread_lifecycle
+                    .lock()
+                    .unwrap()
+                    .transition(VmState::Stopped(error.clone()), VmEvent::Stopped(error));
+            })
+            .map_err(Error::SpawnVmmThread)?;
+
+        Ok(AsyncKrunVm {
+            console_input,
+            console_output: Some(output_rx),
+            events: Some(events_rx),
+            lifecycle,
+        })
+    }
+
+    /// Returns a future resolving once the microVM is running, or with the error that prevented
+    /// it from starting.
+    pub fn started(&self) -> LifecycleFuture {
+        LifecycleFuture {
+            lifecycle: self.lifecycle.clone(),
+            wait_for_stop: false,
+        }
+    }
+
+    /// Returns a future resolving with the error that stopped the microVM. Note that a clean
+    /// guest shutdown, or a device failure stopping the microVM, terminates the process instead.
+    pub fn stopped(&self) -> LifecycleFuture {
+        LifecycleFuture {
+            lifecycle: self.lifecycle.clone(),
+            wait_for_stop: true,
+        }
+    }
+
+    /// Takes the stream of data written by the guest to its console.
+    pub fn console_output(&mut self) -> Option<Receiver<Vec<u8>>> {
+        self.console_output.take()
+    }
+
+    /// Takes the stream of lifecycle events of the microVM, which ends after `VmEvent::Stopped`.
+    pub fn events(&mut self) -> Option<Receiver<VmEvent>> {
+        self.events.take()
+    }
+
+    /// Queues data to be read by the guest from its console without blocking. Returns the number
+    /// of bytes queued, which may be less than `data.len()` if the console buffer is full.
+    pub fn write_console(&self, data: &[u8]) -> io::Result<usize> {
+        self.console_input.push(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::task::{RawWaker, RawWakerVTable};
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        // Safe because the waker doesn't use its data.
+        unsafe { Waker::from_raw(clone(std::ptr::null())) }
+    }
+
+    #[test]
+    fn test_events_end_after_stopped() {
+        let (events_tx, mut events_rx) = channel();
+        let mut lifecycle = Lifecycle {
+            state: VmState::Starting,
+            wakers: Vec::new(),
+            events: Some(events_tx),
+        };
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        lifecycle.transition(VmState::Running, VmEvent::Running);
+        match events_rx.poll_recv(&mut cx) {
+            Poll::Ready(Some(VmEvent::Running)) => (),
+            _ => panic!("Expected the Running event"),
+        }
+        assert!(events_rx.poll_recv(&mut cx).is_pending());
+
+        let error = Arc::new(Error::UnsupportedKrunfw(0));
+        lifecycle.transition(VmState::Stopped(error.clone()), VmEvent::Stopped(error));
+        match events_rx.poll_recv(&mut cx) {
+            Poll::Ready(Some(VmEvent::Stopped(_))) => (),
+            _ => panic!("Expected the Stopped event"),
+        }
+        assert!(matches!(events_rx.poll_recv(&mut cx), Poll::Ready(None)));
+
+        // Events coming after the microVM stopped are dropped.
+        lifecycle.send(VmEvent::Running);
+        assert!(matches!(events_rx.poll_recv(&mut cx), Poll::Ready(None)));
+    }
+}
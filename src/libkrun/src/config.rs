@@ -0,0 +1,359 @@
+//! Loader for microVM definitions kept in JSON or TOML documents, so they can be version
+//! controlled instead of scripted through API calls.
+//!
+//! ```toml
+//! vcpus = 2
+//! memory_mib = 1024
+//! root = "/path/to/rootfs"
+//! workdir = "/"
+//!
+//! [exec]
+//! path = "/bin/sh"
+//! args = ["-c", "echo hello"]
+//! env = ["PATH=/bin"]
+//!
+//! [[ports]]
+//! host = 8080
+//! guest = 80
+//!
+//! [options]
+//! "runtime.max_secs" = 60
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::vm::KrunVmBuilder;
+
+/// Errors associated with loading a microVM definition.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A port is mapped more than once.
+    DuplicatePort(u16),
+    /// The JSON document is invalid, or doesn't match the schema.
+    Json(serde_json::Error),
+    /// Unable to read the document.
+    Read(PathBuf, io::Error),
+    /// The TOML document is invalid, or doesn't match the schema.
+    Toml(toml::de::Error),
+    /// The format of the document can't be told from its extension.
+    UnknownFormat(PathBuf),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConfigError::*;
+        match self {
+            DuplicatePort(port) => write!(f, "Port {} is mapped more than once", port),
+            // The errors of both parsers, their source, report the line and column of the
+            // offending item.
+            Json(_) => write!(f, "Invalid JSON configuration"),
+            Read(path, _) => write!(f, "Unable to read {}", path.display()),
+            Toml(_) => write!(f, "Invalid TOML configuration"),
+            UnknownFormat(path) => write!(
+                f,
+                "Unknown configuration format for {}, expected a .json or .toml file",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::ConfigError::*;
+        match self {
+            Json(e) => Some(e),
+            Read(_, e) => Some(e),
+            Toml(e) => Some(e),
+            DuplicatePort(_) | UnknownFormat(_) => None,
+        }
+    }
+}
+
+/// The formats microVM definitions can be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Tells the format of a document from the extension of its path.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// A host directory made available in the guest. Only supported on macOS.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Volume {
+    pub host: PathBuf,
+    pub guest: PathBuf,
+}
+
+/// A guest TCP port exposed on the host.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PortMapping {
+    pub host: u16,
+    pub guest: u16,
+}
+
+/// The workload run inside the microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Exec {
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The environment of the workload. If missing, the environment of the current process is
+    /// used.
+    pub env: Option<Vec<String>>,
+}
+
+/// The value of an option, see `vmm::vmm_config::options`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Integer(u64),
+    String(String),
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionValue::Bool(value) => write!(f, "{}", value),
+            OptionValue::Integer(value) => write!(f, "{}", value),
+            OptionValue::String(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A microVM definition. Every field is optional, leaving the corresponding default in place.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VmDefinition {
+    pub vcpus: Option<u8>,
+    pub memory_mib: Option<u32>,
+    /// The host path to be used as root for the microVM.
+    pub root: Option<PathBuf>,
+    #[serde(default)]
+    pub volumes: Vec<Volume>,
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    /// The rlimits to be configured in the guest, with format "RESOURCE=RLIM_CUR:RLIM_MAX".
+    #[serde(default)]
+    pub rlimits: Vec<String>,
+    /// The limits of the cgroup of the workload, with format "controller.file=value".
+    #[serde(default)]
+    pub cgroup_limits: Vec<String>,
+    /// The working directory of the workload, relative to the root.
+    pub workdir: Option<String>,
+    pub exec: Option<Exec>,
+    /// Options set by key, see `vmm::vmm_config::options`.
+    #[serde(default)]
+    pub options: BTreeMap<String, OptionValue>,
+}
+
+impl VmDefinition {
+    /// Parses a definition written in `format`.
+    pub fn parse(document: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        let definition: VmDefinition = match format {
+            ConfigFormat::Json => serde_json::from_str(document).map_err(ConfigError::Json)?,
+            ConfigFormat::Toml => toml::from_str(document).map_err(ConfigError::Toml)?,
+        };
+        definition.validate()?;
+        Ok(definition)
+    }
+
+    /// Reads and parses a definition from a file, in the format given by its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let format =
+            ConfigFormat::from_path(path).ok_or_else(|| ConfigError::UnknownFormat(path.into()))?;
+        let document = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.into(), e))?;
+        Self::parse(&document, format)
+    }
+
+    /// Checks the consistency of the definition beyond its schema, like ports mapped only once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut host_ports = HashMap::new();
+        let mut guest_ports = HashMap::new();
+        for port in self.ports.iter() {
+            if host_ports.insert(port.host, port.guest).is_some() {
+                return Err(ConfigError::DuplicatePort(port.host));
+            }
+            if guest_ports.insert(port.guest, port.host).is_some() {
+                return Err(ConfigError::DuplicatePort(port.guest));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies this definition to `builder`. Invalid values are reported by `builder.build()`.
+    pub fn apply(&self, mut builder: KrunVmBuilder) -> KrunVmBuilder {
+        if let Some(vcpus) = self.vcpus {
+            builder = builder.option("machine.vcpus", &vcpus.to_string());
+        }
+        if let Some(memory_mib) = self.memory_mib {
+            builder = builder.option("machine.mem_mib", &memory_mib.to_string());
+        }
+        if let Some(root) = &self.root {
+            builder = builder.root(root);
+        }
+        if !self.volumes.is_empty() {
+            builder = builder.mapped_volumes(
+                self.volumes
+                    .iter()
+                    .map(|volume| (volume.host.clone(), volume.guest.clone()))
+                    .collect(),
+            );
+        }
+        if !self.ports.is_empty() {
+            builder = builder.port_map(
+                self.ports
+                    .iter()
+                    .map(|port| (port.guest, port.host))
+                    .collect(),
+            );
+        }
+        if !self.rlimits.is_empty() {
+            let rlimits: Vec<&str> = self.rlimits.iter().map(String::as_str).collect();
+            builder = builder.rlimits(&rlimits);
+        }
+        if !self.cgroup_limits.is_empty() {
+            let limits: Vec<&str> = self.cgroup_limits.iter().map(String::as_str).collect();
+            builder = builder.cgroup_limits(&limits);
+        }
+        if let Some(workdir) = &self.workdir {
+            builder = builder.workdir(workdir);
+        }
+        if let Some(exec) = &self.exec {
+            let args: Vec<&str> = exec.args.iter().map(String::as_str).collect();
+            let env: Option<Vec<&str>> = exec
+                .env
+                .as_ref()
+                .map(|env| env.iter().map(String::as_str).collect());
+            builder = builder.exec(&exec.path, &args, env.as_deref());
+        }
+        for (key, value) in self.options.iter() {
+            builder = builder.option(key, &value.to_string());
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expected_definition() -> VmDefinition {
+        let mut options = BTreeMap::new();
+        options.insert("runtime.max_secs".to_string(), OptionValue::Integer(60));
+        options.insert("idle.interval_ms".to_string(), OptionValue::Integer(500));
+        options.insert("idle.reclaim_memory".to_string(), OptionValue::Bool(true));
+        VmDefinition {
+            vcpus: Some(2),
+            memory_mib: Some(1024),
+            root: Some(PathBuf::from("/rootfs")),
+            ports: vec![PortMapping {
+                host: 8080,
+                guest: 80,
+            }],
+            exec: Some(Exec {
+                path: "/bin/sh".to_string(),
+                args: vec!["-c".to_string(), "true".to_string()],
+                env: None,
+            }),
+            options,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let document = r#"{
+            "vcpus": 2,
+            "memory_mib": 1024,
+            "root": "/rootfs",
+            "ports": [{ "host": 8080, "guest": 80 }],
+            "exec": { "path": "/bin/sh", "args": ["-c", "true"] },
+            "options": {
+                "runtime.max_secs": 60,
+                "idle.interval_ms": 500,
+                "idle.reclaim_memory": true
+            }
+        }"#;
+        assert_eq!(
+            VmDefinition::parse(document, ConfigFormat::Json).unwrap(),
+            expected_definition()
+        );
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let document = r#"
+            vcpus = 2
+            memory_mib = 1024
+            root = "/rootfs"
+
+            [exec]
+            path = "/bin/sh"
+            args = ["-c", "true"]
+
+            [[ports]]
+            host = 8080
+            guest = 80
+
+            [options]
+            "runtime.max_secs" = 60
+            "idle.interval_ms" = 500
+            "idle.reclaim_memory" = true
+        "#;
+        assert_eq!(
+            VmDefinition::parse(document, ConfigFormat::Toml).unwrap(),
+            expected_definition()
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // Unknown fields are rejected, along with their position.
+        let error = VmDefinition::parse("{\n  \"vcpu\": 2\n}", ConfigFormat::Json).unwrap_err();
+        assert!(matches!(error, ConfigError::Json(ref e) if e.line() == 2));
+        assert!(error.to_string().contains("unknown field `vcpu`"));
+
+        let error = VmDefinition::parse("vcpus = 2\nmemory_mib = \"a lot\"", ConfigFormat::Toml)
+            .unwrap_err();
+        assert!(matches!(error, ConfigError::Toml(_)));
+        assert!(error.to_string().contains("memory_mib"));
+
+        let error = VmDefinition::parse(
+            r#"{ "ports": [{ "host": 8080, "guest": 80 }, { "host": 8080, "guest": 81 }] }"#,
+            ConfigFormat::Json,
+        )
+        .unwrap_err();
+        assert!(matches!(error, ConfigError::DuplicatePort(8080)));
+
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("vm.toml")),
+            Some(ConfigFormat::Toml)
+        );
+        assert!(matches!(
+            VmDefinition::load("vm.yaml"),
+            Err(ConfigError::UnknownFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loader for libkrunfw, located and opened at runtime instead of being linked, so libkrun can be
+//! packaged apart from the kernel, and the embedder can point it at the build it ships.
+//!
+//! The candidates are tried in order: the search path set by the embedder, then the one in the
+//! `KRUN_KRUNFW_PATH` environment variable, colon-separated, and last the search path of the
+//! dynamic loader itself. An entry of a search path is either a directory, holding the library
+//! under one of its sonames, or the library itself. The first library whose version is within the
+//! accepted range is kept for the lifetime of the process, as the kernel it maps is used in place.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use libc::{c_char, c_void, size_t};
+use once_cell::sync::Lazy;
+
+/// The oldest version of libkrunfw supported.
+pub const KRUNFW_MIN_VERSION: u32 = 1;
+/// The newest version of libkrunfw known, which the sonames tried start from when no maximum is
+/// set. libkrunfw bumps its soname along with its version.
+const KRUNFW_LATEST_VERSION: u32 = 4;
+
+const KRUNFW_PATH_ENV: &str = "KRUN_KRUNFW_PATH";
+
+type GetKernelFn = unsafe extern "C" fn(load_addr: *mut u64, size: *mut size_t) -> *mut c_char;
+type GetVersionFn = unsafe extern "C" fn() -> u32;
+
+/// Errors associated with locating and loading libkrunfw.
+#[derive(Debug)]
+pub enum KrunfwError {
+    /// The library was already loaded, so the way it's located can't change anymore.
+    AlreadyLoaded,
+    /// The minimum version accepted is above the maximum one.
+    InvalidVersionRange(u32, u32),
+    /// Only libraries lacking one of the symbols libkrun uses, or of versions outside the accepted
+    /// range, were found, and the first of them lacks this symbol.
+    MissingSymbol(PathBuf, &'static str),
+    /// No candidate could be opened. Holds each candidate tried, along with the reason given by
+    /// the dynamic loader.
+    NotFound(Vec<(PathBuf, String)>),
+    /// Only libraries of versions outside the accepted range, or lacking one of the symbols
+    /// libkrun uses, were found, and the first of them is of this version.
+    UnsupportedVersion(PathBuf, u32),
+}
+
+impl fmt::Display for KrunfwError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KrunfwError::*;
+        match self {
+            AlreadyLoaded => write!(f, "libkrunfw is already loaded"),
+            InvalidVersionRange(min, max) => write!(
+                f,
+                "Invalid libkrunfw version range: minimum {} above maximum {}",
+                min, max
+            ),
+            MissingSymbol(path, symbol) => {
+                write!(f, "{} doesn't export {}", path.display(), symbol)
+            }
+            NotFound(attempts) => {
+                write!(f, "Unable to find libkrunfw")?;
+                for (path, reason) in attempts {
+                    write!(f, "\n  {}: {}", path.display(), reason)?;
+                }
+                Ok(())
+            }
+            UnsupportedVersion(path, version) => write!(
+                f,
+                "Unsupported libkrunfw version {} in {}",
+                version,
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KrunfwError {}
+
+impl KrunfwError {
+    /// Returns the error number reporting the error through the C API.
+    pub fn errno(&self) -> i32 {
+        use self::KrunfwError::*;
+        match self {
+            AlreadyLoaded => libc::EBUSY,
+            InvalidVersionRange(..) => libc::EINVAL,
+            MissingSymbol(..) => libc::ENOEXEC,
+            NotFound(_) => libc::ENOENT,
+            UnsupportedVersion(..) => libc::ENOTSUP,
+        }
+    }
+}
+
+/// How libkrunfw is located, and which of its versions are accepted.
+#[derive(Clone, Debug)]
+pub struct KrunfwLoaderConfig {
+    /// Searched before the path in `KRUN_KRUNFW_PATH` and the one of the dynamic loader.
+    pub search_path: Vec<PathBuf>,
+    pub min_version: u32,
+    /// No maximum when `None`.
+    pub max_version: Option<u32>,
+}
+
+impl Default for KrunfwLoaderConfig {
+    fn default() -> Self {
+        KrunfwLoaderConfig {
+            search_path: Vec::new(),
+            min_version: KRUNFW_MIN_VERSION,
+            max_version: None,
+        }
+    }
+}
+
+impl KrunfwLoaderConfig {
+    /// Returns the file names libkrunfw may have, newest first.
+    fn sonames(&self) -> Vec<String> {
+        let newest = self
+            .max_version
+            .unwrap_or(KRUNFW_LATEST_VERSION)
+            .min(KRUNFW_LATEST_VERSION);
+        let mut sonames: Vec<String> = (self.min_version..=newest)
+            .rev()
+            .map(|version| {
+                if cfg!(target_os = "macos") {
+                    format!("libkrunfw.{}.dylib", version)
+                } else {
+                    format!("libkrunfw.so.{}", version)
+                }
+            })
+            .collect();
+        sonames.push(if cfg!(target_os = "macos") {
+            "libkrunfw.dylib".to_string()
+        } else {
+            "libkrunfw.so".to_string()
+        });
+        sonames
+    }
+
+    /// Returns the paths to try, in order. The bare sonames are left to the dynamic loader.
+    fn candidates(&self, env_path: Option<&OsStr>) -> Vec<PathBuf> {
+        let sonames = self.sonames();
+        let env_path = env_path
+            .map(|path| std::env::split_paths(path).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut candidates = Vec::new();
+        for entry in self.search_path.iter().chain(env_path.iter()) {
+            if entry.as_os_str().is_empty() {
+                continue;
+            }
+            if entry.is_dir() {
+                candidates.extend(sonames.iter().map(|soname| entry.join(soname)));
+            } else {
+                candidates.push(entry.clone());
+            }
+        }
+        candidates.extend(sonames.iter().map(PathBuf::from));
+        candidates
+    }
+
+    fn accepts(&self, version: u32) -> bool {
+        version >= self.min_version && self.max_version.map_or(true, |max| version <= max)
+    }
+}
+
+/// The loaded libkrunfw.
+#[derive(Clone, Debug)]
+pub struct Krunfw {
+    path: PathBuf,
+    version: u32,
+    get_kernel: GetKernelFn,
+}
+
+impl Krunfw {
+    /// Returns the path the library was loaded from, as given to the dynamic loader.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Returns the host address of the kernel the library maps, its guest address and its size.
+    pub fn kernel(&self) -> (u64, u64, usize) {
+        let mut guest_addr: u64 = 0;
+        let mut size: usize = 0;
+        // Safe because the library was checked to export the symbol, with the signature of every
+        // version supported, and it only writes to the two variables.
+        let host_addr = unsafe { (self.get_kernel)(&mut guest_addr, &mut size) };
+        (host_addr as u64, guest_addr, size)
+    }
+}
+
+enum OpenError {
+    Dlopen(String),
+    MissingSymbol(&'static str),
+    Version(u32),
+}
+
+fn dlerror() -> String {
+    // Safe because the message returned, if any, is a valid C string, copied right away.
+    unsafe {
+        let msg = libc::dlerror();
+        if msg.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(msg).to_string_lossy().into_owned()
+        }
+    }
+}
+
+fn open(path: &Path, config: &KrunfwLoaderConfig) -> Result<Krunfw, OpenError> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| OpenError::Dlopen("path contains a NUL byte".to_string()))?;
+    // Safe because the path is a valid C string, and libkrunfw has no initializers with side
+    // effects.
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(OpenError::Dlopen(dlerror()));
+    }
+
+    let symbol = |name: &'static str| -> Result<*mut c_void, OpenError> {
+        let c_name = CString::new(name).unwrap();
+        // Safe because the handle was just opened, and the name is a valid C string.
+        let sym = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+        if sym.is_null() {
+            Err(OpenError::MissingSymbol(name))
+        } else {
+            Ok(sym)
+        }
+    };
+    let result = symbol("krunfw_get_version").and_then(|get_version| {
+        let get_kernel = symbol("krunfw_get_kernel")?;
+        // Safe because both symbols are functions with these signatures in every version of
+        // libkrunfw.
+        let (get_version, get_kernel) = unsafe {
+            (
+                std::mem::transmute::<*mut c_void, GetVersionFn>(get_version),
+                std::mem::transmute::<*mut c_void, GetKernelFn>(get_kernel),
+            )
+        };
+        // Safe because the function has no arguments and just returns the version.
+        let version = unsafe { get_version() };
+        if !config.accepts(version) {
+            return Err(OpenError::Version(version));
+        }
+        Ok(Krunfw {
+            path: path.to_path_buf(),
+            version,
+            get_kernel,
+        })
+    });
+
+    // The library kept is never closed, as the kernel it maps is used in place.
+    if result.is_err() {
+        // Safe because the handle was opened above, and nothing it holds is used anymore.
+        unsafe { libc::dlclose(handle) };
+    }
+    result
+}
+
+struct Loader {
+    config: KrunfwLoaderConfig,
+    krunfw: Option<Krunfw>,
+}
+
+static LOADER: Lazy<Mutex<Loader>> = Lazy::new(|| {
+    Mutex::new(Loader {
+        config: KrunfwLoaderConfig::default(),
+        krunfw: None,
+    })
+});
+
+/// Returns how libkrunfw is located, and which of its versions are accepted.
+pub fn loader_config() -> KrunfwLoaderConfig {
+    LOADER.lock().unwrap().config.clone()
+}
+
+/// Sets how libkrunfw is located, and which of its versions are accepted. Only possible before it
+/// was loaded, which happens as the first configuration context is created.
+pub fn configure(mut config: KrunfwLoaderConfig) -> Result<(), KrunfwError> {
+    // The versions older than the oldest one supported are never accepted.
+    config.min_version = config.min_version.max(KRUNFW_MIN_VERSION);
+    if let Some(max) = config.max_version {
+        if config.min_version > max {
+            return Err(KrunfwError::InvalidVersionRange(config.min_version, max));
+        }
+    }
+    let mut loader = LOADER.lock().unwrap();
+    if loader.krunfw.is_some() {
+        return Err(KrunfwError::AlreadyLoaded);
+    }
+    loader.config = config;
+    Ok(())
+}
+
+/// Returns libkrunfw, locating and loading it on first use. A failure isn't remembered, so the
+/// embedder can fix the search path and try again.
+pub fn load() -> Result<Krunfw, KrunfwError> {
+    let mut loader = LOADER.lock().unwrap();
+    if let Some(krunfw) = &loader.krunfw {
+        return Ok(krunfw.clone());
+    }
+
+    let env_path = std::env::var_os(KRUNFW_PATH_ENV);
+    let mut attempts = Vec::new();
+    // The first library found but not usable, reported if no candidate is.
+    let mut unusable = None;
+    for path in loader.config.candidates(env_path.as_deref()) {
+        match open(&path, &loader.config) {
+            Ok(krunfw) => {
+                debug!(
+                    "Loaded libkrunfw {} from {}",
+                    krunfw.version,
+                    path.display()
+                );
+                loader.krunfw = Some(krunfw.clone());
+                return Ok(krunfw);
+            }
+            Err(OpenError::Dlopen(reason)) => attempts.push((path, reason)),
+            Err(OpenError::MissingSymbol(symbol)) => {
+                debug!(
+                    "Skipping {}, which doesn't export {}",
+                    path.display(),
+                    symbol
+                );
+                unusable.get_or_insert(KrunfwError::MissingSymbol(path, symbol));
+            }
+            Err(OpenError::Version(version)) => {
+                debug!("Skipping libkrunfw {} from {}", version, path.display());
+                unusable.get_or_insert(KrunfwError::UnsupportedVersion(path, version));
+            }
+        }
+    }
+
+    Err(unusable.unwrap_or(KrunfwError::NotFound(attempts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_candidates() {
+        let config = KrunfwLoaderConfig {
+            search_path: Vec::new(),
+            min_version: 2,
+            max_version: Some(3),
+        };
+        let soname = |version: &str| {
+            if cfg!(target_os = "macos") {
+                format!("libkrunfw{}.dylib", version)
+            } else {
+                format!("libkrunfw.so{}", version)
+            }
+        };
+        let (v3, v2, bare) = (soname(".3"), soname(".2"), soname(""));
+        assert_eq!(config.sonames(), vec![v3.clone(), v2.clone(), bare.clone()]);
+        assert!(config.accepts(2) && config.accepts(3));
+        assert!(!config.accepts(1) && !config.accepts(4));
+
+        // The directories are searched for each soname, the files taken as they are, and the bare
+        // sonames come last.
+        let dir = TempDir::new().unwrap();
+        let dir_path = dir.as_path().to_path_buf();
+        let config = KrunfwLoaderConfig {
+            search_path: vec![PathBuf::from("/opt/krunfw/libkrunfw-sev.so")],
+            ..config
+        };
+        let env_path = std::env::join_paths(&[&dir_path]).unwrap();
+        assert_eq!(
+            config.candidates(Some(&env_path)),
+            vec![
+                PathBuf::from("/opt/krunfw/libkrunfw-sev.so"),
+                dir_path.join(&v3),
+                dir_path.join(&v2),
+                dir_path.join(&bare),
+                PathBuf::from(v3),
+                PathBuf::from(v2),
+                PathBuf::from(bare),
+            ]
+        );
+
+        assert!(matches!(
+            configure(KrunfwLoaderConfig {
+                min_version: 3,
+                max_version: Some(2),
+                ..Default::default()
+            }),
+            Err(KrunfwError::InvalidVersionRange(3, 2))
+        ));
+    }
+}
@@ -1,32 +1,96 @@
 #[macro_use]
 extern crate logger;
 
-use std::collections::hash_map::Entry;
+pub mod async_vm;
+pub mod config;
+pub mod krunfw;
+pub mod monitor;
+pub mod vm;
+
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::env;
-use std::ffi::CStr;
-#[cfg(target_os = "linux")]
-use std::ffi::CString;
-use std::path::Path;
+use std::ffi::{CStr, CString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::slice;
-use std::sync::atomic::{AtomicI32, Ordering};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use libc::{c_char, size_t};
+use libc::{c_char, c_void, size_t};
 use logger::{LevelFilter, LOGGER};
 use once_cell::sync::Lazy;
 use polly::event_manager::EventManager;
+#[cfg(feature = "fault-injection")]
+use vmm::fault_injection::FAULTS;
+use vmm::host;
 use vmm::resources::VmResources;
 use vmm::vmm_config::boot_source::{BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
+use vmm::vmm_config::boot_timeline::BootTimeline;
+use vmm::vmm_config::clipboard::{
+    clipboard_guest_device, Clipboard, ClipboardCallback, ClipboardDirection, ClipboardError,
+};
+use vmm::vmm_config::cloud_init::CloudInitConfig;
+use vmm::vmm_config::console::{
+    CallbackInput, CallbackOutput, ConsoleAttach, ConsoleBackend, ConsoleOutputConfig,
+    ResizeSource, SessionRecorder, TerminalConfig, DEFAULT_SCROLLBACK_SIZE,
+};
+use vmm::vmm_config::device_panic::{DeviceFailure, DevicePanicPolicy};
+use vmm::vmm_config::device_window::DeviceWindowConfig;
+use vmm::vmm_config::disk::{BlockDevices, DiskResizeError};
+use vmm::vmm_config::earlycon::EarlyconBuffer;
+use vmm::vmm_config::exec::{
+    capability_mask, ExecEnvConfig, ExecSecurity, ExecUser, RestartPolicy, SeccompProfile,
+    SecurityProfile, ServiceConfig, ServiceError, ServiceState,
+};
+use vmm::vmm_config::exit::{ExitHandle, ExitReason};
+use vmm::vmm_config::fd_budget::{FdBudget, FdEvent, FdEventKind};
 use vmm::vmm_config::fs::FsDeviceConfig;
-use vmm::vmm_config::kernel_bundle::KernelBundle;
-use vmm::vmm_config::machine_config::VmConfig;
-use vmm::vmm_config::vsock::VsockDeviceConfig;
+use vmm::vmm_config::fsfreeze::{FsFreeze, FsFreezeError};
+use vmm::vmm_config::guest_network::{GuestNetwork, GuestNetworkError};
+use vmm::vmm_config::guest_panic::{GuestPanic, GuestPanicKind};
+use vmm::vmm_config::idle::IdlePolicyConfig;
+use vmm::vmm_config::kernel_bundle::{KernelBundle, KernelFlavors};
+use vmm::vmm_config::kernel_signature::{PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use vmm::vmm_config::kvm_tuning::{KvmTuningConfig, MemslotPacking, TimerPolicy};
+use vmm::vmm_config::limits::{CgroupLimit, Rlimit};
+use vmm::vmm_config::log_channel::{write_log_record, LogChannelConfig, LOG_CHANNEL_GUEST_DEVICE};
+use vmm::vmm_config::machine_config::{CpuTopology, VmConfig};
+use vmm::vmm_config::options::{self, OptionError, OptionType};
+use vmm::vmm_config::probes::{ProbeAction, ProbeConfig, ProbeKind, ProbeState};
+use vmm::vmm_config::profiling::{ProfileReports, ProfilingConfig};
+use vmm::vmm_config::restricted_dma::RestrictedDmaConfig;
+use vmm::vmm_config::runtime_limit::RuntimeLimitConfig;
+use vmm::vmm_config::serial_passthrough::SerialPassthroughConfig;
+use vmm::vmm_config::shares::{
+    DedupStore, EscapePolicy, MountPropagation, ShareCache, ShareCacheConfig, ShareError,
+    ShareIoConfig, ShareMount, ShareQuota,
+};
+use vmm::vmm_config::socket_activation::{self, ListenSocket, CONSOLE_FD_NAME};
+use vmm::vmm_config::swap::{SwapConfig, SWAP_DEVICE_ID};
+use vmm::vmm_config::sysctl::Sysctl;
+use vmm::vmm_config::tmpfs::TmpfsConfig;
+use vmm::vmm_config::user_device::{UserAccess, UserDeviceConfig, UserDeviceError, UserRegionKind};
+use vmm::vmm_config::vm_state::{VmState, VmStateHandle};
+use vmm::vmm_config::vsock::{
+    GuestCid, VsockConfigError, VsockDeviceConfig, DEFAULT_GUEST_CID, VMADDR_CID_ANY,
+};
+use vmm::vmm_config::watermarks::{WatermarkEvent, WatermarkLevel};
+use vmm::vmm_config::worker_pool::WorkerPoolConfig;
+use vmm::vmm_config::workload::WorkloadStartedCallback;
+use vmm::Vmm;
+
+use crate::config::VmDefinition;
+use crate::krunfw::{KrunfwError, KrunfwLoaderConfig};
+use crate::monitor::Monitor;
+use crate::vm::{ErrorChain, KrunVmBuilder};
 
-// Minimum krunfw version we require.
-const KRUNFW_MIN_VERSION: u32 = 1;
 // Value returned on success. We use libc's errors otherwise.
 const KRUN_SUCCESS: i32 = 0;
 // Maximum number of arguments/environment variables we allow
@@ -46,9 +110,10 @@ struct ContextConfig {
     exec_path: Option<String>,
     env: Option<String>,
     args: Option<String>,
-    rlimits: Option<String>,
     fs_cfg: Option<FsDeviceConfig>,
     port_map: Option<HashMap<u16, u16>>,
+    boot_on_demand: Option<IpAddr>,
+    monitor: Option<UnixListener>,
 }
 
 impl ContextConfig {
@@ -96,17 +161,6 @@ impl ContextConfig {
         }
     }
 
-    fn set_rlimits(&mut self, rlimits: String) {
-        self.rlimits = Some(rlimits);
-    }
-
-    fn get_rlimits(&self) -> String {
-        match &self.rlimits {
-            Some(rlimits) => format!("KRUN_RLIMITS={}", rlimits),
-            None => "".to_string(),
-        }
-    }
-
     fn set_fs_cfg(&mut self, fs_cfg: FsDeviceConfig) {
         self.fs_cfg = Some(fs_cfg);
     }
@@ -122,17 +176,198 @@ impl ContextConfig {
     fn get_port_map(&self) -> Option<HashMap<u16, u16>> {
         self.port_map.clone()
     }
+
+    fn set_boot_on_demand(&mut self, listen_addr: IpAddr) {
+        self.boot_on_demand = Some(listen_addr);
+    }
+
+    fn set_monitor(&mut self, listener: UnixListener) {
+        self.monitor = Some(listener);
+    }
 }
 
-static CTX_MAP: Lazy<Mutex<HashMap<u32, ContextConfig>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-static CTX_IDS: AtomicI32 = AtomicI32::new(0);
+/// Lifecycle of a context. Transitions only happen in the order below, and the configuration can
+/// only be modified while in `Configuring`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+enum ContextState {
+    Configuring = 0,
+    Running = 1,
+    Stopped = 2,
+}
+
+impl From<u8> for ContextState {
+    fn from(state: u8) -> Self {
+        match state {
+            0 => ContextState::Configuring,
+            1 => ContextState::Running,
+            _ => ContextState::Stopped,
+        }
+    }
+}
+
+/// Handles on the parts of a microVM the embedder keeps using once it's running, when its
+/// configuration has been taken by the VMM thread. They're released along with the context.
+struct ContextHandles {
+    fd_budget: FdBudget,
+    boot_timeline: BootTimeline,
+    block_devices: BlockDevices,
+    vm_state: VmStateHandle,
+    console_input: Option<CallbackInput>,
+    earlycon: Option<EarlyconBuffer>,
+    console_recorder: Option<SessionRecorder>,
+    console_attach: Option<ConsoleAttach>,
+    clipboard: Option<Clipboard>,
+    fsfreeze: Option<FsFreeze>,
+    guest_network: Option<GuestNetwork>,
+    // By tag, so the embedder can invalidate them.
+    share_caches: HashMap<String, ShareCache>,
+    // Only set when the embedder picked the CID of the guest.
+    vsock_cid: Option<u32>,
+    profile_reports: Option<ProfileReports>,
+    exit_handle: Option<ExitHandle>,
+}
+
+impl ContextHandles {
+    fn new(vmr: &VmResources) -> Self {
+        ContextHandles {
+            fd_budget: vmr.fd_budget.clone(),
+            boot_timeline: vmr.boot_timeline.clone(),
+            block_devices: vmr.block_devices.clone(),
+            vm_state: vmr.vm_state.clone(),
+            console_input: None,
+            earlycon: None,
+            console_recorder: None,
+            console_attach: None,
+            clipboard: None,
+            fsfreeze: None,
+            guest_network: None,
+            share_caches: HashMap::new(),
+            vsock_cid: None,
+            profile_reports: None,
+            exit_handle: None,
+        }
+    }
+}
+
+struct Context {
+    state: AtomicU8,
+    cfg: Mutex<ContextConfig>,
+    // Always locked after the configuration, when both are.
+    handles: Mutex<ContextHandles>,
+}
+
+impl Context {
+    fn new(cfg: ContextConfig) -> Self {
+        Context {
+            state: AtomicU8::new(ContextState::Configuring as u8),
+            handles: Mutex::new(ContextHandles::new(&cfg.vmr)),
+            cfg: Mutex::new(cfg),
+        }
+    }
 
-#[link(name = "krunfw")]
-extern "C" {
-    fn krunfw_get_kernel(load_addr: *mut u64, size: *mut size_t) -> *mut c_char;
-    fn krunfw_get_version() -> u32;
+    fn state(&self) -> ContextState {
+        self.state.load(Ordering::SeqCst).into()
+    }
+
+    /// Moves the context from `from` to `to`, failing if it's not currently in `from`.
+    fn transition(&self, from: ContextState, to: ContextState) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
 }
 
+// The map lock is only held while looking up a context, so calls on different contexts don't
+// contend with each other, and calls on the same context serialize on its configuration lock.
+static CTX_MAP: Lazy<Mutex<HashMap<u32, Arc<Context>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static CTX_IDS: AtomicI32 = AtomicI32::new(0);
+// The keys of the options, kept as C strings so krun_get_option_key can hand out pointers to them
+// for the lifetime of the library.
+static OPTION_KEYS: Lazy<Vec<CString>> = Lazy::new(|| {
+    options::OPTIONS
+        .iter()
+        .map(|option| CString::new(option.key).unwrap())
+        .collect()
+});
+
+// The kernel flavors aren't tied to a context, each microVM picking the one it boots.
+pub(crate) static KERNEL_FLAVORS: Lazy<Mutex<KernelFlavors>> =
+    Lazy::new(|| Mutex::new(KernelFlavors::default()));
+
+type ConsoleOutputCallback = unsafe extern "C" fn(opaque: *mut c_void, buf: *const u8, len: size_t);
+
+// The embedder is responsible for making the opaque pointer usable from the VMM thread.
+struct ConsoleOpaque(*mut c_void);
+unsafe impl Send for ConsoleOpaque {}
+
+type WatermarkCallback =
+    unsafe extern "C" fn(opaque: *mut c_void, device_type: u32, queue: u32, depth: u32, level: u32);
+
+// The callback may be invoked from the threads of several devices, so the embedder is also
+// responsible for making the opaque pointer usable from them concurrently.
+struct WatermarkOpaque(*mut c_void);
+unsafe impl Send for WatermarkOpaque {}
+unsafe impl Sync for WatermarkOpaque {}
+
+type FdEventCallback =
+    unsafe extern "C" fn(opaque: *mut c_void, device: *const c_char, in_use: u32, kind: u32);
+
+// Same as for the watermarks, the callback may be invoked from the threads of several devices.
+struct FdEventOpaque(*mut c_void);
+unsafe impl Send for FdEventOpaque {}
+unsafe impl Sync for FdEventOpaque {}
+
+type DeviceFailureCallback =
+    unsafe extern "C" fn(opaque: *mut c_void, device: *const c_char, message: *const c_char);
+
+struct DeviceFailureOpaque(*mut c_void);
+unsafe impl Send for DeviceFailureOpaque {}
+unsafe impl Sync for DeviceFailureOpaque {}
+
+type GuestPanicCallback =
+    unsafe extern "C" fn(opaque: *mut c_void, kind: u32, message: *const c_char);
+
+struct GuestPanicOpaque(*mut c_void);
+unsafe impl Send for GuestPanicOpaque {}
+unsafe impl Sync for GuestPanicOpaque {}
+
+type LogRecordCallback = unsafe extern "C" fn(opaque: *mut c_void, record: *const u8, len: size_t);
+
+struct LogRecordOpaque(*mut c_void);
+unsafe impl Send for LogRecordOpaque {}
+unsafe impl Sync for LogRecordOpaque {}
+
+type ProbeCallback = unsafe extern "C" fn(opaque: *mut c_void, kind: u32, state: u32);
+
+// Each probe runs on a thread of its own, so the callback may be invoked from both.
+struct ProbeOpaque(*mut c_void);
+unsafe impl Send for ProbeOpaque {}
+unsafe impl Sync for ProbeOpaque {}
+
+type ServiceCallback = unsafe extern "C" fn(opaque: *mut c_void, name: *const c_char, state: u32);
+
+// The events of the services are reported through any vCPU, so the callback may be invoked from
+// several threads.
+struct ServiceOpaque(*mut c_void);
+unsafe impl Send for ServiceOpaque {}
+unsafe impl Sync for ServiceOpaque {}
+
+type UserDeviceCallback = unsafe extern "C" fn(
+    opaque: *mut c_void,
+    vcpu: u32,
+    is_write: u32,
+    offset: u64,
+    data: *mut u8,
+    len: u32,
+);
+
+// The accesses are dispatched from the vCPU making them, so the callback may be invoked from
+// several threads, though never concurrently for the same region.
+struct UserDeviceOpaque(*mut c_void);
+unsafe impl Send for UserDeviceOpaque {}
+unsafe impl Sync for UserDeviceOpaque {}
+
 #[no_mangle]
 pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
     let log_level = match level {
@@ -155,50 +390,354 @@ pub extern "C" fn krun_set_log_level(level: u32) -> i32 {
     KRUN_SUCCESS
 }
 
+fn new_ctx_config() -> Result<ContextConfig, vm::Error> {
+    let krunfw = krunfw::load().map_err(|e| match e {
+        KrunfwError::UnsupportedVersion(_, version) => vm::Error::UnsupportedKrunfw(version),
+        e => vm::Error::Krunfw(e),
+    })?;
+    let (kernel_host_addr, kernel_guest_addr, kernel_size) = krunfw.kernel();
+
+    let mut ctx_cfg = ContextConfig::default();
+
+    // Safe because libkrunfw is never unloaded, and keeps the kernel mapped for the lifetime of
+    // the process.
+    let kernel_bundle =
+        unsafe { KernelBundle::from_krunfw(kernel_host_addr, kernel_guest_addr, kernel_size) }
+            .map_err(vm::Error::KernelBundle)?;
+    ctx_cfg.vmr.set_kernel_bundle(kernel_bundle);
+
+    Ok(ctx_cfg)
+}
+
 #[no_mangle]
-pub extern "C" fn krun_create_ctx() -> i32 {
-    let krunfw_version = unsafe { krunfw_get_version() };
-    if krunfw_version < KRUNFW_MIN_VERSION {
-        warn!("Unsupported libkrunfw version: {}", krunfw_version);
+pub extern "C" fn krun_serve_metrics(listener_fd: i32) -> i32 {
+    if listener_fd < 0 {
         return -libc::EINVAL;
     }
 
-    let mut kernel_guest_addr: u64 = 0;
-    let mut kernel_size: usize = 0;
-    let kernel_host_addr = unsafe {
-        krunfw_get_kernel(
-            &mut kernel_guest_addr as *mut u64,
-            &mut kernel_size as *mut usize,
-        )
+    match logger::exporter::serve_on_listener(listener_fd) {
+        Ok(_) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("Unable to start the metrics exporter: {}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_write_metrics(c_path: *const c_char, interval_ms: u32) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
     };
 
-    let mut ctx_cfg = ContextConfig::default();
+    if interval_ms == 0 {
+        return -libc::EINVAL;
+    }
+
+    match logger::exporter::write_periodically(path, Duration::from_millis(interval_ms.into())) {
+        Ok(_) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("Unable to start the metrics exporter: {}", e);
+            -libc::EINVAL
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_audit_fd(fd: i32) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let file = unsafe { File::from_raw_fd(fd) };
+    logger::AUDIT.init(Box::new(file));
+    KRUN_SUCCESS
+}
 
-    let kernel_bundle = KernelBundle {
-        host_addr: kernel_host_addr as u64,
-        guest_addr: kernel_guest_addr,
-        size: kernel_size,
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_audit_file(c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => {
+            logger::AUDIT.init(Box::new(file));
+            KRUN_SUCCESS
+        }
+        Err(e) => {
+            warn!("Unable to open the audit log {}: {}", path, e);
+            -e.raw_os_error().unwrap_or(libc::EINVAL)
+        }
+    }
+}
+
+// Flags of krun_host_caps.
+const KRUN_HOST_HYPERVISOR: u32 = 1 << 0;
+const KRUN_HOST_NESTED: u32 = 1 << 1;
+const KRUN_HOST_SEV: u32 = 1 << 2;
+const KRUN_HOST_TDX: u32 = 1 << 3;
+
+/// Mirrors `struct krun_host_caps` in libkrun.h.
+#[repr(C)]
+pub struct KrunHostCaps {
+    flags: u32,
+    max_vcpus: u32,
+    max_memslots: u32,
+    max_mem_mib: u64,
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_check_host(caps: *mut KrunHostCaps) -> i32 {
+    let host_caps = host::probe();
+
+    let mut flags = 0;
+    for (enabled, flag) in [
+        (host_caps.hypervisor.is_ok(), KRUN_HOST_HYPERVISOR),
+        (host_caps.nested, KRUN_HOST_NESTED),
+        (host_caps.sev, KRUN_HOST_SEV),
+        (host_caps.tdx, KRUN_HOST_TDX),
+    ]
+    .iter()
+    {
+        if *enabled {
+            flags |= flag;
+        }
+    }
+
+    if !caps.is_null() {
+        *caps = KrunHostCaps {
+            flags,
+            max_vcpus: host_caps.max_vcpus,
+            max_memslots: host_caps.max_memslots,
+            max_mem_mib: host_caps.max_mem_mib,
+        };
+    }
+
+    match host_caps.hypervisor {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("The hypervisor is unusable: {}", e);
+            -e.errno()
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_krunfw_search_path(c_search_path: *const *const c_char) -> i32 {
+    let mut search_path = Vec::new();
+    if !c_search_path.is_null() {
+        let array: &[*const c_char] = slice::from_raw_parts(c_search_path, MAX_ARGS);
+        for item in array.iter().take_while(|item| !item.is_null()) {
+            match CStr::from_ptr(*item).to_str() {
+                Ok(path) => search_path.push(PathBuf::from(path)),
+                Err(_) => return -libc::EINVAL,
+            }
+        }
+    }
+
+    // Only the search path changes, not the versions accepted.
+    let mut config = krunfw::loader_config();
+    config.search_path = search_path;
+    match krunfw::configure(config) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -e.errno()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_krunfw_version(min_version: u32, max_version: u32) -> i32 {
+    let config = KrunfwLoaderConfig {
+        min_version,
+        max_version: if max_version == 0 {
+            None
+        } else {
+            Some(max_version)
+        },
+        ..krunfw::loader_config()
+    };
+    match krunfw::configure(config) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -e.errno()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_get_krunfw_version() -> i32 {
+    match krunfw::load() {
+        Ok(krunfw) => krunfw.version() as i32,
+        Err(e) => {
+            warn!("{}", e);
+            -e.errno()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_create_ctx() -> i32 {
+    let ctx_cfg = match new_ctx_config() {
+        Ok(ctx_cfg) => ctx_cfg,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            return match e {
+                vm::Error::Krunfw(e) => -e.errno(),
+                _ => -libc::EINVAL,
+            };
+        }
     };
-    ctx_cfg.vmr.set_kernel_bundle(kernel_bundle).unwrap();
 
     let ctx_id = CTX_IDS.fetch_add(1, Ordering::SeqCst);
     if ctx_id == i32::MAX || CTX_MAP.lock().unwrap().contains_key(&(ctx_id as u32)) {
         // libkrun is not intended to be used as a daemon for managing VMs.
         panic!("Context ID namespace exhausted");
     }
-    CTX_MAP.lock().unwrap().insert(ctx_id as u32, ctx_cfg);
+    CTX_MAP
+        .lock()
+        .unwrap()
+        .insert(ctx_id as u32, Arc::new(Context::new(ctx_cfg)));
 
     ctx_id
 }
 
 #[no_mangle]
 pub extern "C" fn krun_free_ctx(ctx_id: u32) -> i32 {
-    match CTX_MAP.lock().unwrap().remove(&ctx_id) {
-        Some(_) => KRUN_SUCCESS,
+    let mut ctx_map = CTX_MAP.lock().unwrap();
+    match ctx_map.get(&ctx_id) {
+        // The VMM thread still depends on the context.
+        Some(ctx) if ctx.state() == ContextState::Running => -libc::EBUSY,
+        Some(_) => {
+            ctx_map.remove(&ctx_id);
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_get_ctx_state(ctx_id: u32) -> i32 {
+    match get_ctx(ctx_id) {
+        Some(ctx) => ctx.state() as i32,
         None => -libc::ENOENT,
     }
 }
 
+// States of the microVM returned by krun_get_vm_state.
+const KRUN_VM_STATE_CONFIGURED: i32 = 0;
+const KRUN_VM_STATE_BOOTING: i32 = 1;
+const KRUN_VM_STATE_RUNNING: i32 = 2;
+const KRUN_VM_STATE_PAUSED: i32 = 3;
+const KRUN_VM_STATE_SHUTTING_DOWN: i32 = 4;
+const KRUN_VM_STATE_STOPPED: i32 = 5;
+
+#[no_mangle]
+pub extern "C" fn krun_get_vm_state(ctx_id: u32) -> i32 {
+    let state = match ctx_handle(ctx_id, |handles| Some(handles.vm_state.get())) {
+        Some(state) => state,
+        None => return -libc::ENOENT,
+    };
+    match state {
+        VmState::Configured => KRUN_VM_STATE_CONFIGURED,
+        VmState::Booting => KRUN_VM_STATE_BOOTING,
+        VmState::Running => KRUN_VM_STATE_RUNNING,
+        VmState::Paused => KRUN_VM_STATE_PAUSED,
+        VmState::ShuttingDown => KRUN_VM_STATE_SHUTTING_DOWN,
+        VmState::Stopped(_) => KRUN_VM_STATE_STOPPED,
+    }
+}
+
+fn get_ctx(ctx_id: u32) -> Option<Arc<Context>> {
+    CTX_MAP.lock().unwrap().get(&ctx_id).cloned()
+}
+
+/// Runs `f` on the configuration of the context, as long as it hasn't been started yet.
+fn with_ctx_config<F>(ctx_id: u32, f: F) -> i32
+where
+    F: FnOnce(&mut ContextConfig) -> i32,
+{
+    with_ctx_config_and_handles(ctx_id, |cfg, _| f(cfg))
+}
+
+/// Same as `with_ctx_config`, also handing `f` the handles of the context.
+fn with_ctx_config_and_handles<F>(ctx_id: u32, f: F) -> i32
+where
+    F: FnOnce(&mut ContextConfig, &mut ContextHandles) -> i32,
+{
+    let ctx = match get_ctx(ctx_id) {
+        Some(ctx) => ctx,
+        None => return -libc::ENOENT,
+    };
+
+    // The state is checked with the configuration locked, and krun_start_enter only moves the
+    // context to Running while holding that same lock.
+    let mut cfg = ctx.cfg.lock().unwrap();
+    if ctx.state() != ContextState::Configuring {
+        return -libc::EBUSY;
+    }
+
+    let mut handles = ctx.handles.lock().unwrap();
+    f(&mut cfg, &mut handles)
+}
+
+/// Runs `f` on the handles of the context, whatever its state.
+fn with_ctx_handles<F>(ctx_id: u32, f: F) -> i32
+where
+    F: FnOnce(&ContextHandles) -> i32,
+{
+    let ctx = match get_ctx(ctx_id) {
+        Some(ctx) => ctx,
+        None => return -libc::ENOENT,
+    };
+    let handles = ctx.handles.lock().unwrap();
+    f(&handles)
+}
+
+/// Gets what `f` takes from the handles of the context, so they're not locked while it's used.
+fn ctx_handle<T, F>(ctx_id: u32, f: F) -> Option<T>
+where
+    F: FnOnce(&ContextHandles) -> Option<T>,
+{
+    let ctx = get_ctx(ctx_id)?;
+    let handles = ctx.handles.lock().unwrap();
+    f(&handles)
+}
+
+fn new_vm_config(vcpu_count: u8, mem_size_mib: usize) -> VmConfig {
+    VmConfig {
+        vcpu_count: Some(vcpu_count),
+        mem_size_mib: Some(mem_size_mib),
+        ht_enabled: None,
+        topology: None,
+        cpu_template: None,
+    }
+}
+
+fn new_cpu_topology_config(sockets: u8, cores_per_socket: u8, threads_per_core: u8) -> VmConfig {
+    VmConfig {
+        vcpu_count: None,
+        mem_size_mib: None,
+        ht_enabled: None,
+        topology: Some(CpuTopology {
+            sockets,
+            cores_per_socket,
+            threads_per_core,
+        }),
+        cpu_template: None,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u32, ram_mib: u32) -> i32 {
     let mem_size_mib: usize = match ram_mib.try_into() {
@@ -209,23 +748,230 @@ pub extern "C" fn krun_set_vm_config(ctx_id: u32, num_vcpus: u32, ram_mib: u32)
         }
     };
 
-    let vm_config = VmConfig {
-        vcpu_count: Some(num_vcpus as u8),
-        mem_size_mib: Some(mem_size_mib),
-        ht_enabled: Some(false),
-        cpu_template: None,
+    let vm_config = new_vm_config(num_vcpus as u8, mem_size_mib);
+
+    with_ctx_config(ctx_id, |cfg| {
+        if cfg.vmr.set_vm_config(&vm_config).is_err() {
+            return -libc::EINVAL;
+        }
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_cpu_topology(
+    ctx_id: u32,
+    sockets: u8,
+    cores_per_socket: u8,
+    threads_per_core: u8,
+) -> i32 {
+    let vm_config = new_cpu_topology_config(sockets, cores_per_socket, threads_per_core);
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_vm_config(&vm_config) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+/// Reads the digest `c_sha256` points to, if it's not NULL.
+unsafe fn kernel_bundle_digest(c_sha256: *const u8) -> Option<[u8; 32]> {
+    if c_sha256.is_null() {
+        return None;
+    }
+    let mut sha256 = [0u8; 32];
+    sha256.copy_from_slice(slice::from_raw_parts(c_sha256, sha256.len()));
+    Some(sha256)
+}
+
+/// Returns the bundle of the kernel held by `fd` at `offset`, taking the ownership of `fd`.
+unsafe fn kernel_bundle_from_fd(
+    fd: i32,
+    offset: u64,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> Result<KernelBundle, i32> {
+    if fd < 0 {
+        return Err(-libc::EINVAL);
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let file = File::from_raw_fd(fd);
+    let sha256 = kernel_bundle_digest(c_sha256);
+    KernelBundle::from_file(file, offset, guest_addr, size, sha256).map_err(|e| {
+        warn!("{}", ErrorChain(&e));
+        -libc::EINVAL
+    })
+}
+
+/// Returns the bundle of a copy of the kernel in `buf`.
+unsafe fn kernel_bundle_from_buffer(
+    buf: *const u8,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> Result<KernelBundle, i32> {
+    if buf.is_null() {
+        return Err(-libc::EINVAL);
+    }
+
+    let sha256 = kernel_bundle_digest(c_sha256);
+    // The kernel is copied, so the buffer only has to be valid for the duration of the call.
+    let data = slice::from_raw_parts(buf, size);
+    KernelBundle::from_buffer(data, guest_addr, sha256).map_err(|e| {
+        warn!("{}", ErrorChain(&e));
+        -libc::EINVAL
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel_bundle_fd(
+    ctx_id: u32,
+    fd: i32,
+    offset: u64,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> i32 {
+    let kernel_bundle = match kernel_bundle_from_fd(fd, offset, size, guest_addr, c_sha256) {
+        Ok(kernel_bundle) => kernel_bundle,
+        Err(e) => return e,
     };
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            if ctx_cfg.get_mut().vmr.set_vm_config(&vm_config).is_err() {
-                return -libc::EINVAL;
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_kernel_bundle(kernel_bundle);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel_bundle(
+    ctx_id: u32,
+    buf: *const u8,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> i32 {
+    let kernel_bundle = match kernel_bundle_from_buffer(buf, size, guest_addr, c_sha256) {
+        Ok(kernel_bundle) => kernel_bundle,
+        Err(e) => return e,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_kernel_bundle(kernel_bundle);
+        KRUN_SUCCESS
+    })
+}
+
+/// Registers `kernel_bundle` as the flavor `c_name`.
+unsafe fn register_kernel_flavor(c_name: *const c_char, kernel_bundle: KernelBundle) -> i32 {
+    if c_name.is_null() {
+        return -libc::EINVAL;
+    }
+    let name = match CStr::from_ptr(c_name).to_str() {
+        Ok(name) => name,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    match KERNEL_FLAVORS.lock().unwrap().register(name, kernel_bundle) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            -libc::EINVAL
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_register_kernel_flavor_fd(
+    c_name: *const c_char,
+    fd: i32,
+    offset: u64,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> i32 {
+    match kernel_bundle_from_fd(fd, offset, size, guest_addr, c_sha256) {
+        Ok(kernel_bundle) => register_kernel_flavor(c_name, kernel_bundle),
+        Err(e) => e,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_register_kernel_flavor(
+    c_name: *const c_char,
+    buf: *const u8,
+    size: usize,
+    guest_addr: u64,
+    c_sha256: *const u8,
+) -> i32 {
+    match kernel_bundle_from_buffer(buf, size, guest_addr, c_sha256) {
+        Ok(kernel_bundle) => register_kernel_flavor(c_name, kernel_bundle),
+        Err(e) => e,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel_flavor(ctx_id: u32, c_name: *const c_char) -> i32 {
+    if c_name.is_null() {
+        return -libc::EINVAL;
+    }
+    let name = match CStr::from_ptr(c_name).to_str() {
+        Ok(name) => name,
+        Err(_) => return -libc::EINVAL,
+    };
+    let kernel_bundle = match KERNEL_FLAVORS.lock().unwrap().get(name) {
+        Some(kernel_bundle) => kernel_bundle,
+        None => return -libc::ENOENT,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_kernel_bundle(kernel_bundle);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_trusted_kernel_key(ctx_id: u32, c_key: *const u8) -> i32 {
+    if c_key.is_null() {
+        return -libc::EINVAL;
+    }
+    let mut key = [0u8; PUBLIC_KEY_SIZE];
+    key.copy_from_slice(slice::from_raw_parts(c_key, key.len()));
+
+    with_ctx_config(ctx_id, |cfg| {
+        match cfg.vmr.kernel_signature.add_trusted_key(&key) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                warn!("{}", e);
+                -libc::EINVAL
             }
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_kernel_signature(ctx_id: u32, c_signature: *const u8) -> i32 {
+    if c_signature.is_null() {
+        return -libc::EINVAL;
     }
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature.copy_from_slice(slice::from_raw_parts(c_signature, signature.len()));
 
-    KRUN_SUCCESS
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.kernel_signature.set_signature(signature);
+        KRUN_SUCCESS
+    })
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -239,27 +985,29 @@ pub unsafe extern "C" fn krun_set_root(ctx_id: u32, c_root_path: *const c_char)
     let fs_id = "/dev/root".to_string();
     let shared_dir = root_path.to_string();
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            let fs_device_config = match cfg.get_fs_cfg() {
-                Some(fs_cfg) => FsDeviceConfig {
-                    fs_id,
-                    shared_dir,
-                    mapped_volumes: fs_cfg.mapped_volumes,
-                },
-                None => FsDeviceConfig {
-                    fs_id,
-                    shared_dir,
-                    mapped_volumes: None,
-                },
-            };
-            cfg.set_fs_cfg(fs_device_config);
-        }
-        Entry::Vacant(_) => return -libc::ENOENT,
-    }
+    with_ctx_config(ctx_id, |cfg| {
+        let fs_device_config = match cfg.get_fs_cfg() {
+            Some(fs_cfg) => FsDeviceConfig {
+                fs_id,
+                shared_dir,
+                mapped_volumes: fs_cfg.mapped_volumes,
+            },
+            None => FsDeviceConfig {
+                fs_id,
+                shared_dir,
+                mapped_volumes: None,
+            },
+        };
+        cfg.set_fs_cfg(fs_device_config);
+        KRUN_SUCCESS
+    })
+}
 
-    KRUN_SUCCESS
+fn is_valid_mapped_volume(host_vol: &Path, guest_vol: &Path) -> bool {
+    host_vol.is_absolute()
+        && host_vol.exists()
+        && guest_vol.is_absolute()
+        && guest_vol.components().count() == 2
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -285,11 +1033,7 @@ pub unsafe extern "C" fn krun_set_mapped_volumes(
             let host_vol = Path::new(vol_tuple[0]);
             let guest_vol = Path::new(vol_tuple[1]);
 
-            if !host_vol.is_absolute()
-                || !host_vol.exists()
-                || !guest_vol.is_absolute()
-                || guest_vol.components().count() != 2
-            {
+            if !is_valid_mapped_volume(host_vol, guest_vol) {
                 return -libc::EINVAL;
             }
 
@@ -297,27 +1041,243 @@ pub unsafe extern "C" fn krun_set_mapped_volumes(
         }
     }
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            let fs_device_config = match cfg.get_fs_cfg() {
-                Some(fs_cfg) => FsDeviceConfig {
-                    fs_id: fs_cfg.fs_id.clone(),
-                    shared_dir: fs_cfg.shared_dir.clone(),
-                    mapped_volumes: Some(mapped_volumes),
-                },
-                None => FsDeviceConfig {
-                    fs_id: String::new(),
-                    shared_dir: String::new(),
-                    mapped_volumes: Some(mapped_volumes),
-                },
-            };
-            cfg.set_fs_cfg(fs_device_config);
-        }
-        Entry::Vacant(_) => return -libc::ENOENT,
+    with_ctx_config(ctx_id, |cfg| {
+        let fs_device_config = match cfg.get_fs_cfg() {
+            Some(fs_cfg) => FsDeviceConfig {
+                fs_id: fs_cfg.fs_id.clone(),
+                shared_dir: fs_cfg.shared_dir.clone(),
+                mapped_volumes: Some(mapped_volumes),
+            },
+            None => FsDeviceConfig {
+                fs_id: String::new(),
+                shared_dir: String::new(),
+                mapped_volumes: Some(mapped_volumes),
+            },
+        };
+        cfg.set_fs_cfg(fs_device_config);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_add_share.
+const KRUN_SHARE_READ_ONLY: u32 = 1 << 0;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_share(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_host_path: *const c_char,
+    c_guest_path: *const c_char,
+    flags: u32,
+    propagation: u32,
+    order: u32,
+) -> i32 {
+    let (tag, host_path, guest_path) = match (
+        CStr::from_ptr(c_tag).to_str(),
+        CStr::from_ptr(c_host_path).to_str(),
+        CStr::from_ptr(c_guest_path).to_str(),
+    ) {
+        (Ok(tag), Ok(host_path), Ok(guest_path)) => (tag, Path::new(host_path), guest_path),
+        _ => return -libc::EINVAL,
+    };
+    if flags & !KRUN_SHARE_READ_ONLY != 0 {
+        return -libc::EINVAL;
     }
+    let propagation = match propagation {
+        0 => MountPropagation::Private,
+        1 => MountPropagation::Shared,
+        2 => MountPropagation::Slave,
+        3 => MountPropagation::Unbindable,
+        _ => return -libc::EINVAL,
+    };
 
-    KRUN_SUCCESS
+    let mount = match ShareMount::new(tag, guest_path) {
+        Ok(mount) => ShareMount {
+            read_only: flags & KRUN_SHARE_READ_ONLY != 0,
+            propagation,
+            order,
+            ..mount
+        },
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.add_share(host_path, mount) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e @ ShareError::DuplicateGuestPath(_)) | Err(e @ ShareError::DuplicateTag(_)) => {
+            warn!("{}", e);
+            -libc::EEXIST
+        }
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            -libc::EINVAL
+        }
+    })
+}
+
+// Flags of krun_set_share_quota.
+const KRUN_SHARE_QUOTA_ENFORCE: u32 = 1 << 0;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_share_quota(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    capacity_bytes: u64,
+    flags: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if flags & !KRUN_SHARE_QUOTA_ENFORCE != 0 {
+        return -libc::EINVAL;
+    }
+    let quota = ShareQuota {
+        capacity: capacity_bytes,
+        enforce: flags & KRUN_SHARE_QUOTA_ENFORCE != 0,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_share_quota(tag, quota) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            -libc::EINVAL
+        }
+    })
+}
+
+// Flags of krun_set_share_io.
+const KRUN_SHARE_IO_DIRECT: u32 = 1 << 0;
+const KRUN_SHARE_IO_PREALLOCATE: u32 = 1 << 1;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_share_io(ctx_id: u32, c_tag: *const c_char, flags: u32) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    if flags & !(KRUN_SHARE_IO_DIRECT | KRUN_SHARE_IO_PREALLOCATE) != 0 {
+        return -libc::EINVAL;
+    }
+    let io = ShareIoConfig {
+        direct_io: flags & KRUN_SHARE_IO_DIRECT != 0,
+        preallocate: flags & KRUN_SHARE_IO_PREALLOCATE != 0,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_share_io(tag, io);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_share_escape_policy(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    policy: u32,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let policy = match policy {
+        0 => EscapePolicy::Off,
+        1 => EscapePolicy::Audit,
+        2 => EscapePolicy::Strict,
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_share_escape_policy(tag, policy);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_share_cache(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    metadata_ttl_ms: u32,
+    max_entries: u32,
+    content_ttl_ms: u32,
+    max_content_bytes: u64,
+) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let max_content_size: usize = match max_content_bytes.try_into() {
+        Ok(size) => size,
+        Err(_) => return -libc::EINVAL,
+    };
+    let cache = ShareCache::new(ShareCacheConfig {
+        metadata_ttl: Duration::from_millis(metadata_ttl_ms.into()),
+        max_entries: max_entries as usize,
+        content_ttl: Duration::from_millis(content_ttl_ms.into()),
+        max_content_size,
+    });
+
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_share_cache(tag, cache.clone());
+        handles.share_caches.insert(tag.to_string(), cache);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_share_cache_invalidate(ctx_id: u32, c_tag: *const c_char) -> i32 {
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_handles(ctx_id, |handles| match handles.share_caches.get(tag) {
+        Some(cache) => {
+            cache.invalidate();
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_share_dedup(
+    ctx_id: u32,
+    c_tag: *const c_char,
+    c_store_dir: *const c_char,
+) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+    let tag = match CStr::from_ptr(c_tag).to_str() {
+        Ok(tag) => tag,
+        Err(_) => return -libc::EINVAL,
+    };
+    let store_dir = match CStr::from_ptr(c_store_dir).to_str() {
+        Ok(store_dir) => store_dir,
+        Err(_) => return -libc::EINVAL,
+    };
+    let dedup = match DedupStore::new(store_dir) {
+        Ok(dedup) => dedup,
+        Err(e) => {
+            warn!("Unable to open the dedup store {}: {}", store_dir, e);
+            return -e.raw_os_error().unwrap_or(libc::EACCES);
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_share_dedup(tag, dedup);
+        KRUN_SUCCESS
+    })
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -358,49 +1318,177 @@ pub unsafe extern "C" fn krun_set_port_map(ctx_id: u32, c_port_map: *const *cons
         }
     }
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_port_map(port_map);
-        }
-        Entry::Vacant(_) => return -libc::ENOENT,
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.set_port_map(port_map);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_boot_on_demand(ctx_id: u32, c_listen_addr: *const c_char) -> i32 {
+    let listen_addr = match CStr::from_ptr(c_listen_addr).to_str().map(str::parse) {
+        Ok(Ok(listen_addr)) => listen_addr,
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.set_boot_on_demand(listen_addr);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_vsock_guest_cid.
+const KRUN_VSOCK_CID_CLAIM: u32 = 1 << 0;
+
+#[no_mangle]
+pub extern "C" fn krun_set_vsock_guest_cid(ctx_id: u32, cid: u32, flags: u32) -> i32 {
+    if flags & !KRUN_VSOCK_CID_CLAIM != 0 {
+        return -libc::EINVAL;
     }
 
-    KRUN_SUCCESS
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        // The previous claim, if any, is released first, so the CID can be claimed again.
+        cfg.vmr.vsock_cid = GuestCid::default();
+        let guest_cid = if flags & KRUN_VSOCK_CID_CLAIM != 0 || cid == VMADDR_CID_ANY {
+            GuestCid::claim(cid)
+        } else {
+            GuestCid::new(cid)
+        };
+        match guest_cid {
+            Ok(guest_cid) => {
+                handles.vsock_cid = Some(guest_cid.cid());
+                cfg.vmr.vsock_cid = guest_cid;
+                KRUN_SUCCESS
+            }
+            Err(e) => {
+                warn!("{}", ErrorChain(&e));
+                handles.vsock_cid = None;
+                match e {
+                    VsockConfigError::GuestCidInUse(_) => -libc::EADDRINUSE,
+                    VsockConfigError::NoFreeGuestCid => -libc::EADDRNOTAVAIL,
+                    VsockConfigError::ClaimGuestCid(_, e) => -e.raw_os_error().unwrap_or(libc::EIO),
+                    _ => -libc::EINVAL,
+                }
+            }
+        }
+    })
 }
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
-    let rlimits = if c_rlimits.is_null() {
+pub unsafe extern "C" fn krun_get_vsock_guest_cid(ctx_id: u32, cid: *mut u32) -> i32 {
+    if cid.is_null() {
         return -libc::EINVAL;
-    } else {
-        let mut strvec = Vec::new();
-
-        let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
-        for item in array.iter().take(MAX_ARGS) {
-            if item.is_null() {
-                break;
-            } else {
-                let s = match CStr::from_ptr(*item).to_str() {
-                    Ok(s) => s,
-                    Err(_) => return -libc::EINVAL,
-                };
-                strvec.push(s);
-            }
+    }
+    with_ctx_handles(ctx_id, |handles| {
+        *cid = handles.vsock_cid.unwrap_or(DEFAULT_GUEST_CID);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_add_listen_fd.
+const KRUN_LISTEN_FD_CONSOLE: u32 = 1 << 0;
+
+fn add_listen_socket(cfg: &mut ContextConfig, socket: ListenSocket, console: bool) -> i32 {
+    match cfg.vmr.add_activated_socket(socket, console) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("Unable to use the activated socket: {}", e);
+            -e.raw_os_error().unwrap_or(libc::EINVAL)
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_add_listen_fd(ctx_id: u32, fd: i32, flags: u32) -> i32 {
+    if fd < 0 || flags & !KRUN_LISTEN_FD_CONSOLE != 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let socket = match unsafe { ListenSocket::from_raw_fd(fd) } {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            return -libc::ENOTSOCK;
         }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        add_listen_socket(cfg, socket, flags & KRUN_LISTEN_FD_CONSOLE != 0)
+    })
+}
 
-        format!("\"{}\"", strvec.join(","))
+#[no_mangle]
+pub extern "C" fn krun_add_sd_listen_fds(ctx_id: u32) -> i32 {
+    let fds = match socket_activation::sd_listen_fds() {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            return -libc::EINVAL;
+        }
     };
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_rlimits(rlimits.to_string());
+    // All of them are taken over first, so they're closed on failure rather than leaked.
+    let sockets: Vec<_> = fds
+        .into_iter()
+        .map(|(fd, name)| {
+            // Safe because the service manager handed the fd over to this process.
+            let socket = unsafe { ListenSocket::from_raw_fd(fd) };
+            (socket, name.as_deref() == Some(CONSOLE_FD_NAME))
+        })
+        .collect();
+    let mut activated = Vec::with_capacity(sockets.len());
+    for (socket, console) in sockets {
+        match socket {
+            Ok(socket) => activated.push((socket, console)),
+            Err(e) => {
+                warn!("{}", ErrorChain(&e));
+                return -libc::ENOTSOCK;
+            }
         }
-        Entry::Vacant(_) => return -libc::ENOENT,
     }
 
-    KRUN_SUCCESS
+    with_ctx_config(ctx_id, |cfg| {
+        let count = activated.len() as i32;
+        for (socket, console) in activated {
+            let ret = add_listen_socket(cfg, socket, console);
+            if ret != KRUN_SUCCESS {
+                return ret;
+            }
+        }
+        count
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_rlimits(ctx_id: u32, c_rlimits: *const *const c_char) -> i32 {
+    if c_rlimits.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let mut rlimits = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_rlimits, MAX_ARGS);
+    for item in array.iter().take_while(|item| !item.is_null()) {
+        let rlimit = match CStr::from_ptr(*item).to_str() {
+            Ok(s) => Rlimit::parse(s),
+            Err(_) => return -libc::EINVAL,
+        };
+        match rlimit {
+            Ok(rlimit) => rlimits.push(rlimit),
+            Err(e) => {
+                warn!("{}", e);
+                return -libc::EINVAL;
+            }
+        }
+    }
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.rlimits = rlimits;
+        KRUN_SUCCESS
+    })
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -411,14 +1499,10 @@ pub unsafe extern "C" fn krun_set_workdir(ctx_id: u32, c_workdir_path: *const c_
         Err(_) => return -libc::EINVAL,
     };
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            ctx_cfg.get_mut().set_workdir(workdir_path.to_string());
-        }
-        Entry::Vacant(_) => return -libc::ENOENT,
-    }
-
-    KRUN_SUCCESS
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.set_workdir(workdir_path.to_string());
+        KRUN_SUCCESS
+    })
 }
 
 unsafe fn collapse_str_array(array: &[*const c_char]) -> Result<String, std::str::Utf8Error> {
@@ -480,87 +1564,2175 @@ pub unsafe extern "C" fn krun_set_exec(
             .collect()
     };
 
-    match CTX_MAP.lock().unwrap().entry(ctx_id) {
-        Entry::Occupied(mut ctx_cfg) => {
-            let cfg = ctx_cfg.get_mut();
-            cfg.set_exec_path(exec_path.to_string());
-            cfg.set_env(env);
-            cfg.set_args(args);
-        }
-        Entry::Vacant(_) => return -libc::ENOENT,
-    }
-
-    KRUN_SUCCESS
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.set_exec_path(exec_path.to_string());
+        cfg.set_env(env);
+        cfg.set_args(args);
+        KRUN_SUCCESS
+    })
 }
 
+// Flags of krun_set_exec_env.
+const KRUN_EXEC_LOGIN_SHELL: u32 = 1 << 0;
+
+#[allow(clippy::missing_safety_doc)]
 #[no_mangle]
-pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
-    #[cfg(target_os = "linux")]
-    {
-        let prname = match env::var("HOSTNAME") {
-            Ok(val) => CString::new(format!("VM:{}", val)).unwrap(),
-            Err(_) => CString::new("libkrun VM").unwrap(),
-        };
-        unsafe { libc::prctl(libc::PR_SET_NAME, prname.as_ptr()) };
+pub unsafe extern "C" fn krun_set_exec_env(
+    ctx_id: u32,
+    flags: u32,
+    c_path: *const c_char,
+    c_locale: *const c_char,
+) -> i32 {
+    if flags & !KRUN_EXEC_LOGIN_SHELL != 0 {
+        return -libc::EINVAL;
     }
 
-    let mut event_manager = match EventManager::new() {
-        Ok(em) => em,
+    let optional_str = |ptr: *const c_char| {
+        if ptr.is_null() {
+            Ok(None)
+        } else {
+            CStr::from_ptr(ptr).to_str().map(Some)
+        }
+    };
+    let (path, locale) = match (optional_str(c_path), optional_str(c_locale)) {
+        (Ok(path), Ok(locale)) => (path, locale),
+        _ => return -libc::EINVAL,
+    };
+
+    let exec_env = match ExecEnvConfig::new(flags & KRUN_EXEC_LOGIN_SHELL != 0, path, locale) {
+        Ok(exec_env) => exec_env,
         Err(e) => {
-            warn!("Unable to create EventManager: {:?}", e);
+            warn!("{}", e);
             return -libc::EINVAL;
         }
     };
 
-    let mut ctx_cfg = match CTX_MAP.lock().unwrap().remove(&ctx_id) {
-        Some(ctx_cfg) => ctx_cfg,
-        None => return -libc::ENOENT,
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.exec_env = exec_env;
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_exec_user(
+    ctx_id: u32,
+    uid: u32,
+    gid: u32,
+    groups: *const u32,
+    ngroups: size_t,
+) -> i32 {
+    let groups = if ngroups == 0 {
+        Vec::new()
+    } else if groups.is_null() {
+        return -libc::EINVAL;
+    } else {
+        slice::from_raw_parts(groups, ngroups).to_vec()
     };
 
-    if let Some(fs_cfg) = ctx_cfg.get_fs_cfg() {
-        if ctx_cfg.vmr.set_fs_device(fs_cfg).is_err() {
+    let user = match ExecUser::new(uid, gid, groups) {
+        Ok(user) => user,
+        Err(e) => {
+            warn!("{}", e);
             return -libc::EINVAL;
         }
-    }
+    };
 
-    let mut boot_source = BootSourceConfig::default();
-    boot_source.kernel_cmdline_prolog = Some(format!(
-        "{} init={} KRUN_INIT={} KRUN_WORKDIR={} {} {}",
-        DEFAULT_KERNEL_CMDLINE,
-        INIT_PATH,
-        ctx_cfg.get_exec_path(),
-        ctx_cfg.get_workdir(),
-        ctx_cfg.get_rlimits(),
-        ctx_cfg.get_env(),
-    ));
-    boot_source.kernel_cmdline_epilog = Some(format!(" -- {}", ctx_cfg.get_args()));
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.exec_user = Some(user);
+        KRUN_SUCCESS
+    })
+}
+
+// Profiles of krun_set_exec_security_profile.
+const KRUN_SECURITY_PRIVILEGED: u32 = 0;
+const KRUN_SECURITY_DEFAULT: u32 = 1;
+const KRUN_SECURITY_RESTRICTED: u32 = 2;
+
+#[no_mangle]
+pub extern "C" fn krun_set_exec_security_profile(ctx_id: u32, profile: u32) -> i32 {
+    let profile = match profile {
+        KRUN_SECURITY_PRIVILEGED => SecurityProfile::Privileged,
+        KRUN_SECURITY_DEFAULT => SecurityProfile::Default,
+        KRUN_SECURITY_RESTRICTED => SecurityProfile::Restricted,
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.exec_security = ExecSecurity::from_profile(profile);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_exec_capabilities(
+    ctx_id: u32,
+    c_caps: *const *const c_char,
+) -> i32 {
+    let capabilities = if c_caps.is_null() {
+        None
+    } else {
+        let mut names = Vec::new();
+        let array: &[*const c_char] = slice::from_raw_parts(c_caps, MAX_ARGS);
+        for item in array.iter().take_while(|item| !item.is_null()) {
+            match CStr::from_ptr(*item).to_str() {
+                Ok(name) => names.push(name),
+                Err(_) => return -libc::EINVAL,
+            }
+        }
+        match capability_mask(&names) {
+            Ok(mask) => Some(mask),
+            Err(e) => {
+                warn!("{}", e);
+                return -libc::EINVAL;
+            }
+        }
+    };
 
-    if ctx_cfg.vmr.set_boot_source(boot_source).is_err() {
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.exec_security.capabilities = capabilities;
+        KRUN_SUCCESS
+    })
+}
+
+// Seccomp profiles and flags of krun_set_exec_seccomp.
+const KRUN_SECCOMP_UNCONFINED: u32 = 0;
+const KRUN_SECCOMP_DEFAULT: u32 = 1;
+const KRUN_SECCOMP_STRICT: u32 = 2;
+const KRUN_EXEC_NO_NEW_PRIVS: u32 = 1 << 0;
+
+#[no_mangle]
+pub extern "C" fn krun_set_exec_seccomp(ctx_id: u32, profile: u32, flags: u32) -> i32 {
+    let seccomp = match profile {
+        KRUN_SECCOMP_UNCONFINED => SeccompProfile::Unconfined,
+        KRUN_SECCOMP_DEFAULT => SeccompProfile::Default,
+        KRUN_SECCOMP_STRICT => SeccompProfile::Strict,
+        _ => return -libc::EINVAL,
+    };
+    if flags & !KRUN_EXEC_NO_NEW_PRIVS != 0 {
         return -libc::EINVAL;
     }
 
-    let vsock_device_config = VsockDeviceConfig {
-        vsock_id: "vsock0".to_string(),
-        guest_cid: 3,
-        host_port_map: ctx_cfg.get_port_map(),
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.exec_security.seccomp = seccomp;
+        cfg.vmr.exec_security.no_new_privs = flags & KRUN_EXEC_NO_NEW_PRIVS != 0;
+        KRUN_SUCCESS
+    })
+}
+
+// Restart policies of krun_add_service.
+const KRUN_RESTART_ONESHOT: u32 = 0;
+const KRUN_RESTART_ALWAYS: u32 = 1;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_service(
+    ctx_id: u32,
+    c_name: *const c_char,
+    c_argv: *const *const c_char,
+    restart: u32,
+) -> i32 {
+    if c_name.is_null() || c_argv.is_null() {
+        return -libc::EINVAL;
+    }
+    let restart = match restart {
+        KRUN_RESTART_ONESHOT => RestartPolicy::OneShot,
+        KRUN_RESTART_ALWAYS => RestartPolicy::Always,
+        _ => return -libc::EINVAL,
+    };
+    let name = match CStr::from_ptr(c_name).to_str() {
+        Ok(name) => name,
+        Err(_) => return -libc::EINVAL,
     };
-    ctx_cfg.vmr.set_vsock_device(vsock_device_config).unwrap();
 
-    let _vmm = match vmm::builder::build_microvm(&ctx_cfg.vmr, &mut event_manager) {
-        Ok(vmm) => vmm,
+    let mut args = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
+    for item in array.iter().take_while(|item| !item.is_null()) {
+        match CStr::from_ptr(*item).to_str() {
+            Ok(arg) => args.push(arg.to_string()),
+            Err(_) => return -libc::EINVAL,
+        }
+    }
+
+    let service = match ServiceConfig::new(name, args, restart) {
+        Ok(service) => service,
         Err(e) => {
-            warn!("Building the microVM failed: {:?}", e);
+            warn!("{}", e);
             return -libc::EINVAL;
         }
     };
 
-    loop {
-        match event_manager.run() {
-            Ok(_) => {}
-            Err(e) => {
-                warn!("Error in EventManager loop: {:?}", e);
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.add_service(service) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            match e {
+                ServiceError::DuplicateName(_) => -libc::EEXIST,
+                _ => -libc::ENOSPC,
+            }
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_service_callback(
+    ctx_id: u32,
+    service_cb: Option<ServiceCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let service_cb = match service_cb {
+        Some(service_cb) => service_cb,
+        None => return -libc::EINVAL,
+    };
+
+    let opaque = ServiceOpaque(opaque);
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.service_callback = Some(Arc::new(move |name: &str, state: ServiceState| {
+            let state = match state {
+                ServiceState::Running => 0,
+                ServiceState::Exited => 1,
+                ServiceState::Failed => 2,
+            };
+            // The names of the services are validated, so they hold no NUL.
+            let name = CString::new(name).unwrap();
+            service_cb(opaque.0, name.as_ptr(), state)
+        }));
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_console_callbacks(
+    ctx_id: u32,
+    output_cb: Option<ConsoleOutputCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let output_cb = match output_cb {
+        Some(cb) => cb,
+        None => return -libc::EINVAL,
+    };
+
+    let input = match CallbackInput::new() {
+        Ok(input) => input,
+        Err(e) => {
+            warn!("Unable to create console input buffer: {:?}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    let opaque = ConsoleOpaque(opaque);
+    let output = CallbackOutput::new(Box::new(move |buf: &[u8]| {
+        output_cb(opaque.0, buf.as_ptr(), buf.len())
+    }));
+
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_console_backend(ConsoleBackend::Callbacks {
+            input: input.clone(),
+            output,
+        });
+        handles.console_input = Some(input);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_console_output.
+const KRUN_CONSOLE_LINE_BUFFERED: u32 = 1 << 0;
+const KRUN_CONSOLE_TIMESTAMPS: u32 = 1 << 1;
+const KRUN_CONSOLE_STRIP_ANSI: u32 = 1 << 2;
+
+#[no_mangle]
+pub extern "C" fn krun_set_console_output(ctx_id: u32, flags: u32, max_line_length: u32) -> i32 {
+    let known_flags =
+        KRUN_CONSOLE_LINE_BUFFERED | KRUN_CONSOLE_TIMESTAMPS | KRUN_CONSOLE_STRIP_ANSI;
+    if flags & !known_flags != 0 {
+        return -libc::EINVAL;
+    }
+
+    let config = ConsoleOutputConfig {
+        line_buffered: flags & KRUN_CONSOLE_LINE_BUFFERED != 0,
+        timestamps: flags & KRUN_CONSOLE_TIMESTAMPS != 0,
+        strip_ansi: flags & KRUN_CONSOLE_STRIP_ANSI != 0,
+        max_line_length: match max_line_length {
+            0 => None,
+            max_line_length => Some(max_line_length as usize),
+        },
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_console_output(config);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_console_terminal.
+const KRUN_TERMINAL_ALTERNATE_SCREEN: u32 = 1 << 0;
+
+#[no_mangle]
+pub extern "C" fn krun_set_console_terminal(ctx_id: u32, flags: u32) -> i32 {
+    if flags & !KRUN_TERMINAL_ALTERNATE_SCREEN != 0 {
+        return -libc::EINVAL;
+    }
+
+    let config = TerminalConfig {
+        alternate_screen: flags & KRUN_TERMINAL_ALTERNATE_SCREEN != 0,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_console_terminal(config);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_console_resize_fd(ctx_id: u32, fd: i32) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let file = unsafe { File::from_raw_fd(fd) };
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_console_resize(ResizeSource::Fd(Arc::new(file)));
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_console_recording.
+const KRUN_RECORDING_PAUSED: u32 = 1 << 0;
+
+#[no_mangle]
+pub extern "C" fn krun_set_console_recording(ctx_id: u32, fd: i32, flags: u32) -> i32 {
+    if fd < 0 || flags & !KRUN_RECORDING_PAUSED != 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let file = unsafe { File::from_raw_fd(fd) };
+    let recorder = SessionRecorder::new(Box::new(file), flags & KRUN_RECORDING_PAUSED == 0);
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.console_recorder = Some(recorder.clone());
+        handles.console_recorder = Some(recorder);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_console_recording_enabled(ctx_id: u32, enabled: bool) -> i32 {
+    with_ctx_handles(ctx_id, |handles| match &handles.console_recorder {
+        Some(recorder) => {
+            recorder.set_enabled(enabled);
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_console_attach(
+    ctx_id: u32,
+    c_path: *const c_char,
+    scrollback_size: size_t,
+) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+    let scrollback_size = match scrollback_size {
+        0 => DEFAULT_SCROLLBACK_SIZE,
+        size => size,
+    };
+
+    let attach = match UnixListener::bind(path)
+        .and_then(|listener| ConsoleAttach::new(listener, scrollback_size))
+    {
+        Ok(attach) => attach,
+        Err(e) => {
+            warn!("Unable to listen for console clients on {}: {}", path, e);
+            return -e.raw_os_error().unwrap_or(libc::EIO);
+        }
+    };
+
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr
+            .set_console_backend(ConsoleBackend::Attach(attach.clone()));
+        handles.console_attach = Some(attach);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_console_detach(ctx_id: u32) -> i32 {
+    with_ctx_handles(ctx_id, |handles| match &handles.console_attach {
+        Some(attach) => {
+            attach.detach();
+            KRUN_SUCCESS
+        }
+        None => -libc::ENOENT,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_console_is_attached(ctx_id: u32) -> i32 {
+    with_ctx_handles(ctx_id, |handles| match &handles.console_attach {
+        Some(attach) => attach.is_attached() as i32,
+        None => -libc::ENOENT,
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_console_write(ctx_id: u32, buf: *const u8, len: size_t) -> i32 {
+    if buf.is_null() || len > i32::MAX as usize {
+        return -libc::EINVAL;
+    }
+
+    let data = slice::from_raw_parts(buf, len);
+    with_ctx_handles(ctx_id, |handles| match &handles.console_input {
+        Some(input) => match input.push(data) {
+            Ok(count) => count as i32,
+            Err(e) => {
+                warn!("Error writing to the console input buffer: {:?}", e);
+                -libc::EIO
+            }
+        },
+        None => -libc::ENOENT,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_earlycon_buffer(ctx_id: u32, size: size_t) -> i32 {
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        if cfg.vmr.set_earlycon_buffer(size).is_err() {
+            return -libc::EINVAL;
+        }
+        handles.earlycon = cfg.vmr.earlycon().cloned();
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_earlycon_log(ctx_id: u32, buf: *mut u8, len: size_t) -> i32 {
+    let contents = match ctx_handle(ctx_id, |handles| {
+        handles.earlycon.as_ref().map(EarlyconBuffer::contents)
+    }) {
+        Some(contents) => contents,
+        None => return -libc::ENOENT,
+    };
+
+    if buf.is_null() {
+        return contents.len() as i32;
+    }
+
+    let count = std::cmp::min(len, contents.len());
+    slice::from_raw_parts_mut(buf, count).copy_from_slice(&contents[..count]);
+
+    count as i32
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_max_runtime(
+    ctx_id: u32,
+    max_runtime_secs: u32,
+    grace_period_secs: u32,
+) -> i32 {
+    let runtime_limit = match RuntimeLimitConfig::new(
+        Duration::from_secs(max_runtime_secs.into()),
+        Duration::from_secs(grace_period_secs.into()),
+    ) {
+        Ok(runtime_limit) => runtime_limit,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_runtime_limit(runtime_limit);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_idle_policy(
+    ctx_id: u32,
+    idle_interval_ms: u32,
+    reclaim_memory: bool,
+) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+
+    let idle_policy = match IdlePolicyConfig::new(
+        Duration::from_millis(idle_interval_ms.into()),
+        reclaim_memory,
+    ) {
+        Ok(idle_policy) => idle_policy,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_idle_policy(idle_policy);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_profiling.
+const KRUN_PROFILING_PMU: u32 = 1 << 0;
+
+#[no_mangle]
+pub extern "C" fn krun_set_profiling(ctx_id: u32, interval_ms: u32, flags: u32) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+    if flags & !KRUN_PROFILING_PMU != 0 {
+        return -libc::EINVAL;
+    }
+
+    let profiling = match ProfilingConfig::new(
+        Duration::from_millis(interval_ms.into()),
+        flags & KRUN_PROFILING_PMU != 0,
+    ) {
+        Ok(profiling) => profiling,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_profiling(profiling);
+        handles.profile_reports = Some(cfg.vmr.profile_reports.clone());
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_profile(ctx_id: u32, buf: *mut c_char, len: size_t) -> i32 {
+    let report = match ctx_handle(ctx_id, |handles| {
+        handles.profile_reports.as_ref().map(ProfileReports::latest)
+    }) {
+        Some(report) => report,
+        None => return -libc::ENOENT,
+    };
+    let json = match report {
+        Some(report) => report.to_json(),
+        None => return -libc::EAGAIN,
+    };
+
+    // The string is returned NUL-terminated, so the length doesn't include the terminator.
+    if buf.is_null() || len <= json.len() {
+        return json.len() as i32;
+    }
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, json.len() + 1);
+    buf[..json.len()].copy_from_slice(json.as_bytes());
+    buf[json.len()] = 0;
+
+    json.len() as i32
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_boot_timeline(ctx_id: u32, buf: *mut c_char, len: size_t) -> i32 {
+    let report = match ctx_handle(ctx_id, |handles| Some(handles.boot_timeline.report())) {
+        Some(report) => report,
+        None => return -libc::ENOENT,
+    };
+    let json = match report {
+        Some(report) => report.to_json(),
+        None => return -libc::EAGAIN,
+    };
+
+    // The string is returned NUL-terminated, so the length doesn't include the terminator.
+    if buf.is_null() || len <= json.len() {
+        return json.len() as i32;
+    }
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, json.len() + 1);
+    buf[..json.len()].copy_from_slice(json.as_bytes());
+    buf[json.len()] = 0;
+
+    json.len() as i32
+}
+
+// Flags of krun_set_kvm_tuning.
+const KRUN_KVM_DIRTY_RING: u32 = 1 << 0;
+const KRUN_KVM_HALT_POLL: u32 = 1 << 1;
+const KRUN_KVM_MLOCK: u32 = 1 << 2;
+const KRUN_KVM_SLOT_PER_REGION: u32 = 1 << 3;
+const KRUN_KVM_NO_PIT: u32 = 1 << 4;
+
+#[no_mangle]
+pub extern "C" fn krun_set_kvm_tuning(
+    ctx_id: u32,
+    flags: u32,
+    dirty_ring_entries: u32,
+    halt_poll_ns: u32,
+) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+    if flags
+        & !(KRUN_KVM_DIRTY_RING
+            | KRUN_KVM_HALT_POLL
+            | KRUN_KVM_MLOCK
+            | KRUN_KVM_SLOT_PER_REGION
+            | KRUN_KVM_NO_PIT)
+        != 0
+    {
+        return -libc::EINVAL;
+    }
+
+    let kvm_tuning = KvmTuningConfig {
+        dirty_ring_entries: Some(dirty_ring_entries).filter(|_| flags & KRUN_KVM_DIRTY_RING != 0),
+        halt_poll_ns: Some(halt_poll_ns).filter(|_| flags & KRUN_KVM_HALT_POLL != 0),
+        mlock: flags & KRUN_KVM_MLOCK != 0,
+        memslot_packing: if flags & KRUN_KVM_SLOT_PER_REGION != 0 {
+            MemslotPacking::PerRegion
+        } else {
+            MemslotPacking::Coalesce
+        },
+        timer_policy: if flags & KRUN_KVM_NO_PIT != 0 {
+            TimerPolicy::None
+        } else {
+            TimerPolicy::Pit
+        },
+    };
+    if let Err(e) = kvm_tuning.validate() {
+        warn!("{}", e);
+        return -libc::EINVAL;
+    }
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.kvm_tuning = kvm_tuning;
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_virtio_recording(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match File::create(path) {
+        Ok(file) => {
+            cfg.vmr.set_virtio_recording(Box::new(file));
+            KRUN_SUCCESS
+        }
+        Err(e) => {
+            warn!("Unable to create the virtio recording {}: {}", path, e);
+            -e.raw_os_error().unwrap_or(libc::EINVAL)
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_cloud_init(
+    ctx_id: u32,
+    c_user_data: *const c_char,
+    c_meta_data: *const c_char,
+    c_network_config: *const c_char,
+) -> i32 {
+    let optional_str = |ptr: *const c_char| {
+        if ptr.is_null() {
+            Ok(None)
+        } else {
+            CStr::from_ptr(ptr).to_str().map(|s| Some(s.to_string()))
+        }
+    };
+
+    let config = match (
+        optional_str(c_user_data),
+        optional_str(c_meta_data),
+        optional_str(c_network_config),
+    ) {
+        (Ok(Some(user_data)), Ok(meta_data), Ok(network_config)) => CloudInitConfig {
+            user_data,
+            meta_data,
+            network_config,
+        },
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_cloud_init(&config) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_kernel_module(
+    ctx_id: u32,
+    c_path: *const c_char,
+    c_params: *const c_char,
+) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return -libc::EINVAL,
+    };
+    let params = if c_params.is_null() {
+        ""
+    } else {
+        match CStr::from_ptr(c_params).to_str() {
+            Ok(params) => params,
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        match cfg.vmr.add_kernel_module(path, params) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                warn!("{}", e);
+                -libc::EINVAL
+            }
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_ca_certs(ctx_id: u32, c_bundle_path: *const c_char) -> i32 {
+    let bundle_path = if c_bundle_path.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_bundle_path).to_str() {
+            Ok(path) => Some(Path::new(path)),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_ca_certs(bundle_path) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_hostname(ctx_id: u32, c_hostname: *const c_char) -> i32 {
+    let hostname = match CStr::from_ptr(c_hostname).to_str() {
+        Ok(hostname) => hostname,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_hostname(hostname) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_machine_id(ctx_id: u32, c_machine_id: *const c_char) -> i32 {
+    let machine_id = match CStr::from_ptr(c_machine_id).to_str() {
+        Ok(machine_id) => machine_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_machine_id(machine_id) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_etc_file(
+    ctx_id: u32,
+    c_name: *const c_char,
+    c_contents: *const c_char,
+) -> i32 {
+    let (name, contents) = match (
+        CStr::from_ptr(c_name).to_str(),
+        CStr::from_ptr(c_contents).to_str(),
+    ) {
+        (Ok(name), Ok(contents)) => (name, contents),
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_etc_file(name, contents) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_sysctls(ctx_id: u32, c_sysctls: *const *const c_char) -> i32 {
+    if c_sysctls.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let mut sysctls = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_sysctls, MAX_ARGS);
+    for item in array.iter().take_while(|item| !item.is_null()) {
+        let sysctl = match CStr::from_ptr(*item).to_str() {
+            Ok(s) => Sysctl::parse(s),
+            Err(_) => return -libc::EINVAL,
+        };
+        match sysctl {
+            Ok(sysctl) => sysctls.push(sysctl),
+            Err(e) => {
+                warn!("{}", e);
+                return -libc::EINVAL;
+            }
+        }
+    }
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.sysctls.clear();
+        for sysctl in sysctls {
+            cfg.vmr.add_sysctl(sysctl);
+        }
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_workload_cgroup(
+    ctx_id: u32,
+    c_limits: *const *const c_char,
+) -> i32 {
+    if c_limits.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let mut limits = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_limits, MAX_ARGS);
+    for item in array.iter().take_while(|item| !item.is_null()) {
+        let limit = match CStr::from_ptr(*item).to_str() {
+            Ok(s) => CgroupLimit::parse(s),
+            Err(_) => return -libc::EINVAL,
+        };
+        match limit {
+            Ok(limit) => limits.push(limit),
+            Err(e) => {
+                warn!("{}", e);
+                return -libc::EINVAL;
+            }
+        }
+    }
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.cgroup_limits = limits;
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_tpm(ctx_id: u32, c_socket_path: *const c_char) -> i32 {
+    let socket_path = match CStr::from_ptr(c_socket_path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_tpm(socket_path) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_secret(
+    ctx_id: u32,
+    c_name: *const c_char,
+    value: *const c_void,
+    value_len: size_t,
+) -> i32 {
+    let name = match CStr::from_ptr(c_name).to_str() {
+        Ok(name) => name,
+        Err(_) => return -libc::EINVAL,
+    };
+    let value = if value_len == 0 {
+        &[][..]
+    } else if value.is_null() {
+        return -libc::EINVAL;
+    } else {
+        slice::from_raw_parts(value as *const u8, value_len)
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.add_secret(name, value) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_secrets_ttl(ctx_id: u32, ttl_secs: u32) -> i32 {
+    if ttl_secs == 0 {
+        return -libc::EINVAL;
+    }
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr
+            .set_secrets_ttl(Duration::from_secs(ttl_secs.into()));
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_queue_watermarks(
+    ctx_id: u32,
+    low: u32,
+    high: u32,
+    watermark_cb: Option<WatermarkCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let watermark_cb = match watermark_cb {
+        Some(cb) => cb,
+        None => return -libc::EINVAL,
+    };
+
+    let opaque = WatermarkOpaque(opaque);
+    let callback = Arc::new(move |event: &WatermarkEvent| {
+        let level = match event.level {
+            WatermarkLevel::Low => 0,
+            WatermarkLevel::High => 1,
+        };
+        watermark_cb(
+            opaque.0,
+            event.device_type,
+            event.queue.into(),
+            event.depth.into(),
+            level,
+        )
+    });
+
+    with_ctx_config(ctx_id, |cfg| {
+        match cfg.vmr.set_queue_watermarks(low, high, callback.clone()) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                warn!("{}", e);
+                -libc::EINVAL
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_device_window(
+    ctx_id: u32,
+    mmio_start: u64,
+    irq_base: u32,
+    irq_max: u32,
+) -> i32 {
+    let device_window = DeviceWindowConfig {
+        mmio_start,
+        irq_base,
+        irq_max,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        match cfg.vmr.set_device_window(device_window) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(e) => {
+                warn!("{}", e);
+                -libc::EINVAL
+            }
+        }
+    })
+}
+
+// Address spaces of krun_add_user_device.
+const KRUN_USER_DEVICE_MMIO: u32 = 0;
+const KRUN_USER_DEVICE_PIO: u32 = 1;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_user_device(
+    ctx_id: u32,
+    kind: u32,
+    base: u64,
+    len: u64,
+    access_cb: Option<UserDeviceCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let kind = match kind {
+        KRUN_USER_DEVICE_MMIO => UserRegionKind::Mmio,
+        KRUN_USER_DEVICE_PIO => UserRegionKind::Pio,
+        _ => return -libc::EINVAL,
+    };
+    let access_cb = match access_cb {
+        Some(access_cb) => access_cb,
+        None => return -libc::EINVAL,
+    };
+
+    let opaque = UserDeviceOpaque(opaque);
+    let callback = Arc::new(move |vcpu: u64, offset: u64, access: UserAccess| {
+        // The reads are handed a buffer the callback fills, and the writes the bytes written,
+        // which the callback mustn't change.
+        let (is_write, data, len) = match access {
+            UserAccess::Read(data) => (0, data.as_mut_ptr(), data.len()),
+            UserAccess::Write(data) => (1, data.as_ptr() as *mut u8, data.len()),
+        };
+        access_cb(opaque.0, vcpu as u32, is_write, offset, data, len as u32)
+    });
+    let config = match UserDeviceConfig::new(kind, base, len, callback) {
+        Ok(config) => config,
+        Err(e @ UserDeviceError::PioNotSupported) => {
+            warn!("{}", e);
+            return -libc::ENOTSUP;
+        }
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.add_user_device(config) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EEXIST
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_worker_pool(ctx_id: u32, threads: u32, device_limit: u32) -> i32 {
+    let worker_pool = WorkerPoolConfig {
+        threads: threads as usize,
+        device_limit: device_limit as usize,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_worker_pool(worker_pool) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_fd_budget(
+    ctx_id: u32,
+    max_fds: u32,
+    fd_event_cb: Option<FdEventCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let limit = match max_fds {
+        0 => None,
+        max_fds => Some(max_fds as usize),
+    };
+
+    let opaque = FdEventOpaque(opaque);
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.fd_budget.set_limit(limit);
+        if let Some(fd_event_cb) = fd_event_cb {
+            cfg.vmr
+                .fd_budget
+                .set_callback(Arc::new(move |event: &FdEvent| {
+                    let kind = match event.kind {
+                        FdEventKind::BudgetExhausted => 0,
+                        FdEventKind::HostExhausted => 1,
+                    };
+                    // Device names are set by the embedder and can't hold a NUL.
+                    let device = CString::new(event.device.clone()).unwrap_or_default();
+                    fd_event_cb(opaque.0, device.as_ptr(), event.in_use as u32, kind)
+                }));
+        }
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_fd_usage(ctx_id: u32, c_device: *const c_char) -> i32 {
+    let fd_budget = match ctx_handle(ctx_id, |handles| Some(handles.fd_budget.clone())) {
+        Some(fd_budget) => fd_budget,
+        None => return -libc::ENOENT,
+    };
+
+    if c_device.is_null() {
+        return fd_budget.in_use() as i32;
+    }
+
+    let device = match CStr::from_ptr(c_device).to_str() {
+        Ok(device) => device,
+        Err(_) => return -libc::EINVAL,
+    };
+    match fd_budget
+        .usage()
+        .iter()
+        .find(|usage| usage.device == device)
+    {
+        Some(usage) => usage.in_use as i32,
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_device_panic_policy(
+    ctx_id: u32,
+    policy: u32,
+    device_failure_cb: Option<DeviceFailureCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let policy = match policy {
+        0 => DevicePanicPolicy::StopVm,
+        1 => DevicePanicPolicy::DetachDevice,
+        _ => return -libc::EINVAL,
+    };
+
+    let opaque = DeviceFailureOpaque(opaque);
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.device_panic.policy = policy;
+        if let Some(device_failure_cb) = device_failure_cb {
+            cfg.vmr.device_panic.callback = Some(Arc::new(move |failure: &DeviceFailure| {
+                // Panic messages may hold a NUL, unlike device names.
+                let device = CString::new(failure.device.clone()).unwrap_or_default();
+                let message = CString::new(failure.message.replace('\0', "")).unwrap_or_default();
+                device_failure_cb(opaque.0, device.as_ptr(), message.as_ptr())
+            }));
+        }
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_guest_panic_callback.
+const KRUN_GUEST_PANIC_SCAN_CONSOLE: u32 = 1 << 0;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_guest_panic_callback(
+    ctx_id: u32,
+    flags: u32,
+    guest_panic_cb: Option<GuestPanicCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    if flags & !KRUN_GUEST_PANIC_SCAN_CONSOLE != 0 {
+        return -libc::EINVAL;
+    }
+
+    let opaque = GuestPanicOpaque(opaque);
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.guest_panic.scan_console = flags & KRUN_GUEST_PANIC_SCAN_CONSOLE != 0;
+        if let Some(guest_panic_cb) = guest_panic_cb {
+            cfg.vmr.guest_panic.callback = Some(Arc::new(move |panic: &GuestPanic| {
+                let kind = match panic.kind {
+                    GuestPanicKind::Panic => 0,
+                    GuestPanicKind::Oops => 1,
+                };
+                // The console output may hold a NUL.
+                let message = CString::new(panic.message.replace('\0', "")).unwrap_or_default();
+                guest_panic_cb(opaque.0, kind, message.as_ptr())
+            }));
+        }
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_log_callback(
+    ctx_id: u32,
+    log_record_cb: Option<LogRecordCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let log_record_cb = match log_record_cb {
+        Some(log_record_cb) => log_record_cb,
+        None => return -libc::EINVAL,
+    };
+
+    let opaque = LogRecordOpaque(opaque);
+    let config = LogChannelConfig::new(Arc::new(move |record: &[u8]| {
+        log_record_cb(opaque.0, record.as_ptr(), record.len())
+    }));
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_log_channel(config);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_log_fd(ctx_id: u32, fd: i32) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let file = unsafe { File::from_raw_fd(fd) };
+    let config = LogChannelConfig::new(Arc::new(move |record: &[u8]| {
+        if let Err(e) = write_log_record(&mut &file, record) {
+            warn!("Unable to deliver a log record of the guest: {}", e);
+        }
+    }));
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_log_channel(config);
+        KRUN_SUCCESS
+    })
+}
+
+// Flags of krun_set_clipboard.
+const KRUN_CLIPBOARD_HOST_TO_GUEST: u32 = 1 << 0;
+const KRUN_CLIPBOARD_GUEST_TO_HOST: u32 = 1 << 1;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_clipboard(
+    ctx_id: u32,
+    flags: u32,
+    max_size: size_t,
+    clipboard_cb: Option<LogRecordCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let direction = match flags {
+        KRUN_CLIPBOARD_HOST_TO_GUEST => ClipboardDirection::HostToGuest,
+        KRUN_CLIPBOARD_GUEST_TO_HOST => ClipboardDirection::GuestToHost,
+        f if f == KRUN_CLIPBOARD_HOST_TO_GUEST | KRUN_CLIPBOARD_GUEST_TO_HOST => {
+            ClipboardDirection::Both
+        }
+        _ => return -libc::EINVAL,
+    };
+    // Without a callback, there's nothing to hand the contents copied in the guest to.
+    if direction.guest_to_host() && clipboard_cb.is_none() {
+        return -libc::EINVAL;
+    }
+
+    let callback = clipboard_cb.map(|clipboard_cb| {
+        let opaque = LogRecordOpaque(opaque);
+        Arc::new(move |contents: &[u8]| clipboard_cb(opaque.0, contents.as_ptr(), contents.len()))
+            as ClipboardCallback
+    });
+    let clipboard = match Clipboard::new(direction, max_size, callback) {
+        Ok(clipboard) => clipboard,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_clipboard(clipboard.clone());
+        handles.clipboard = Some(clipboard);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_clipboard_copy(ctx_id: u32, buf: *const u8, len: size_t) -> i32 {
+    if buf.is_null() && len > 0 {
+        return -libc::EINVAL;
+    }
+
+    let contents = if len > 0 {
+        slice::from_raw_parts(buf, len)
+    } else {
+        &[]
+    };
+    match ctx_handle(ctx_id, |handles| handles.clipboard.clone()) {
+        Some(clipboard) => match clipboard.copy_to_guest(contents) {
+            Ok(()) => KRUN_SUCCESS,
+            Err(ClipboardError::Denied) => -libc::EPERM,
+            Err(ClipboardError::TooLarge(_)) => -libc::EFBIG,
+            Err(ClipboardError::Busy) => -libc::EAGAIN,
+            Err(e) => {
+                warn!("{}", e);
+                -libc::EIO
+            }
+        },
+        None => -libc::ENOENT,
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_add_serial_passthrough(
+    ctx_id: u32,
+    c_name: *const c_char,
+    c_path: *const c_char,
+    baud_rate: u32,
+) -> i32 {
+    if c_name.is_null() || c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let (name, path) = match (
+        CStr::from_ptr(c_name).to_str(),
+        CStr::from_ptr(c_path).to_str(),
+    ) {
+        (Ok(name), Ok(path)) => (name, PathBuf::from(path)),
+        _ => return -libc::EINVAL,
+    };
+    let baud_rate = if baud_rate == 0 {
+        None
+    } else {
+        Some(baud_rate)
+    };
+
+    let serial = match SerialPassthroughConfig::new(name, path, baud_rate) {
+        Ok(serial) => serial,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.add_serial_passthrough(serial) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EEXIST
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_fsfreeze(ctx_id: u32, thaw_timeout_secs: u32) -> i32 {
+    let fsfreeze = match FsFreeze::new(Duration::from_secs(thaw_timeout_secs.into())) {
+        Ok(fsfreeze) => fsfreeze,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_fsfreeze(fsfreeze.clone());
+        handles.fsfreeze = Some(fsfreeze);
+        KRUN_SUCCESS
+    })
+}
+
+fn fsfreeze_request(
+    ctx_id: u32,
+    request: impl FnOnce(&FsFreeze) -> std::result::Result<(), FsFreezeError>,
+) -> i32 {
+    // Not holding the lock while waiting for the guest.
+    let fsfreeze = match ctx_handle(ctx_id, |handles| handles.fsfreeze.clone()) {
+        Some(fsfreeze) => fsfreeze,
+        None => return -libc::ENOENT,
+    };
+    match request(&fsfreeze) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(FsFreezeError::Timeout) => -libc::ETIMEDOUT,
+        Err(FsFreezeError::Expired) => -libc::ESTALE,
+        Err(FsFreezeError::Guest(errno)) => -errno,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EIO
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn krun_fs_freeze(ctx_id: u32, timeout_ms: u32) -> i32 {
+    fsfreeze_request(ctx_id, |fsfreeze| {
+        fsfreeze.freeze(Duration::from_millis(timeout_ms.into()))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_fs_thaw(ctx_id: u32, timeout_ms: u32) -> i32 {
+    fsfreeze_request(ctx_id, |fsfreeze| {
+        fsfreeze.thaw(Duration::from_millis(timeout_ms.into()))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_guest_network(ctx_id: u32) -> i32 {
+    let guest_network = match GuestNetwork::new() {
+        Ok(guest_network) => guest_network,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EIO;
+        }
+    };
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        cfg.vmr.set_guest_network(guest_network.clone());
+        handles.guest_network = Some(guest_network);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_guest_network(
+    ctx_id: u32,
+    timeout_ms: u32,
+    buf: *mut c_char,
+    len: size_t,
+) -> i32 {
+    // Not holding the lock while waiting for the guest.
+    let guest_network = match ctx_handle(ctx_id, |handles| handles.guest_network.clone()) {
+        Some(guest_network) => guest_network,
+        None => return -libc::ENOENT,
+    };
+    let json = match guest_network.query(Duration::from_millis(timeout_ms.into())) {
+        Ok(info) => info.to_json(),
+        Err(GuestNetworkError::Timeout) => return -libc::ETIMEDOUT,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EIO;
+        }
+    };
+
+    // The string is returned NUL-terminated, so the length doesn't include the terminator.
+    if buf.is_null() || len <= json.len() {
+        return json.len() as i32;
+    }
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, json.len() + 1);
+    buf[..json.len()].copy_from_slice(json.as_bytes());
+    buf[json.len()] = 0;
+
+    json.len() as i32
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_tmpfs_sizes(ctx_id: u32, tmp_size_mib: u32, shm_size_mib: u32) -> i32 {
+    let size = |size_mib| match size_mib {
+        0 => None,
+        size_mib => Some(size_mib),
+    };
+    let tmpfs = TmpfsConfig {
+        tmp_size_mib: size(tmp_size_mib),
+        shm_size_mib: size(shm_size_mib),
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.tmpfs = tmpfs;
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_swap(ctx_id: u32, size_mib: u32, c_dir: *const c_char) -> i32 {
+    let dir = if c_dir.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(c_dir).to_str() {
+            Ok(dir) => Some(PathBuf::from(dir)),
+            Err(_) => return -libc::EINVAL,
+        }
+    };
+
+    let swap = match SwapConfig::new(size_mib, dir) {
+        Ok(swap) => swap,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.swap = Some(swap);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_restricted_dma(ctx_id: u32, pool_size_mib: u32) -> i32 {
+    let restricted_dma = match RestrictedDmaConfig::new(pool_size_mib) {
+        Ok(restricted_dma) => restricted_dma,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.restricted_dma = Some(restricted_dma);
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_resize_disk(
+    ctx_id: u32,
+    c_block_id: *const c_char,
+    size: u64,
+) -> i32 {
+    if c_block_id.is_null() {
+        return -libc::EINVAL;
+    }
+    let block_id = match CStr::from_ptr(c_block_id).to_str() {
+        Ok(block_id) => block_id,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let block_devices = match ctx_handle(ctx_id, |handles| Some(handles.block_devices.clone())) {
+        Some(block_devices) => block_devices,
+        None => return -libc::ENOENT,
+    };
+    match block_devices.resize(block_id, size) {
+        Ok(_) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            match e {
+                DiskResizeError::UnknownDevice(_) => -libc::ENOENT,
+                DiskResizeError::Shrink(_) => -libc::EINVAL,
+                DiskResizeError::Resize(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+            }
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_monitor(ctx_id: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Unable to listen for monitor clients on {}: {}", path, e);
+            return -e.raw_os_error().unwrap_or(libc::EIO);
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.set_monitor(listener);
+        KRUN_SUCCESS
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_workload_started_fd(ctx_id: u32, fd: i32) -> i32 {
+    if fd < 0 {
+        return -libc::EINVAL;
+    }
+
+    // Safe because the caller transfers the ownership of the fd to us.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let callback = WorkloadStartedCallback::new(Box::new(move || {
+        // Eight bytes, so it can be read from an eventfd too.
+        if let Err(e) = file.write_all(&1u64.to_ne_bytes()) {
+            warn!("Unable to signal the workload has been started: {}", e);
+        }
+    }));
+
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.set_workload_started_callback(callback);
+        KRUN_SUCCESS
+    })
+}
+
+fn probe_kind(kind: u32) -> Option<ProbeKind> {
+    match kind {
+        0 => Some(ProbeKind::Readiness),
+        1 => Some(ProbeKind::Liveness),
+        _ => None,
+    }
+}
+
+fn set_probe(ctx_id: u32, kind: u32, action: ProbeAction) -> i32 {
+    let kind = match probe_kind(kind) {
+        Some(kind) => kind,
+        None => return -libc::EINVAL,
+    };
+    let probe = match ProbeConfig::new(kind, action) {
+        Ok(probe) => probe,
+        Err(e) => {
+            warn!("{}", e);
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_probe(probe) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_probe_exec(
+    ctx_id: u32,
+    kind: u32,
+    c_argv: *const *const c_char,
+) -> i32 {
+    if c_argv.is_null() {
+        return -libc::EINVAL;
+    }
+
+    let mut args = Vec::new();
+    let array: &[*const c_char] = slice::from_raw_parts(c_argv, MAX_ARGS);
+    for item in array.iter().take_while(|item| !item.is_null()) {
+        match CStr::from_ptr(*item).to_str() {
+            Ok(arg) => args.push(arg.to_string()),
+            Err(_) => return -libc::EINVAL,
+        }
+    }
+
+    set_probe(ctx_id, kind, ProbeAction::Exec(args))
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_probe_tcp(ctx_id: u32, kind: u32, port: u16) -> i32 {
+    if port == 0 {
+        return -libc::EINVAL;
+    }
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    set_probe(ctx_id, kind, ProbeAction::TcpConnect(addr))
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_probe_file(ctx_id: u32, kind: u32, c_path: *const c_char) -> i32 {
+    if c_path.is_null() {
+        return -libc::EINVAL;
+    }
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return -libc::EINVAL,
+    };
+
+    set_probe(ctx_id, kind, ProbeAction::FileExists(path))
+}
+
+#[no_mangle]
+pub extern "C" fn krun_set_probe_timing(
+    ctx_id: u32,
+    kind: u32,
+    initial_delay_ms: u32,
+    period_ms: u32,
+    timeout_ms: u32,
+    success_threshold: u32,
+    failure_threshold: u32,
+) -> i32 {
+    let kind = match probe_kind(kind) {
+        Some(kind) => kind,
+        None => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        let probe = match cfg.vmr.probe_mut(kind) {
+            Some(probe) => probe,
+            None => return -libc::ENOENT,
+        };
+        let mut timing = probe.timing;
+        timing.initial_delay = Duration::from_millis(initial_delay_ms.into());
+        timing.period = Duration::from_millis(period_ms.into());
+        timing.timeout = Duration::from_millis(timeout_ms.into());
+        timing.success_threshold = success_threshold;
+        timing.failure_threshold = failure_threshold;
+
+        let previous = std::mem::replace(&mut probe.timing, timing);
+        if let Err(e) = probe.validate() {
+            warn!("{}", e);
+            probe.timing = previous;
+            return -libc::EINVAL;
+        }
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_probe_callback(
+    ctx_id: u32,
+    probe_cb: Option<ProbeCallback>,
+    opaque: *mut c_void,
+) -> i32 {
+    let probe_cb = match probe_cb {
+        Some(probe_cb) => probe_cb,
+        None => return -libc::EINVAL,
+    };
+
+    let opaque = ProbeOpaque(opaque);
+    with_ctx_config(ctx_id, |cfg| {
+        cfg.vmr.probe_callback = Some(Arc::new(move |kind: ProbeKind, state: ProbeState| {
+            let kind = match kind {
+                ProbeKind::Readiness => 0,
+                ProbeKind::Liveness => 1,
+            };
+            let state = match state {
+                ProbeState::Unknown => 0,
+                ProbeState::Success => 1,
+                ProbeState::Failure => 2,
+            };
+            probe_cb(opaque.0, kind, state)
+        }));
+        KRUN_SUCCESS
+    })
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_set_option(
+    ctx_id: u32,
+    c_key: *const c_char,
+    c_value: *const c_char,
+) -> i32 {
+    let (key, value) = match (
+        CStr::from_ptr(c_key).to_str(),
+        CStr::from_ptr(c_value).to_str(),
+    ) {
+        (Ok(key), Ok(value)) => (key, value),
+        _ => return -libc::EINVAL,
+    };
+
+    with_ctx_config(ctx_id, |cfg| match cfg.vmr.set_option(key, value) {
+        Ok(()) => KRUN_SUCCESS,
+        Err(e @ OptionError::UnknownOption(_)) => {
+            warn!("{}", e);
+            -libc::ENOENT
+        }
+        Err(e) => {
+            warn!("{}", e);
+            -libc::EINVAL
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn krun_get_option_key(index: u32) -> *const c_char {
+    match OPTION_KEYS.get(index as usize) {
+        Some(key) => key.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_option_info(
+    c_key: *const c_char,
+    option_type: *mut u32,
+    since_version: *mut u32,
+) -> i32 {
+    let option = match CStr::from_ptr(c_key)
+        .to_str()
+        .ok()
+        .and_then(options::find_option)
+    {
+        Some(option) => option,
+        None => return -libc::ENOENT,
+    };
+
+    if !option_type.is_null() {
+        *option_type = match option.kind {
+            OptionType::Bool => 0,
+            OptionType::U32 => 1,
+        };
+    }
+    if !since_version.is_null() {
+        *since_version = option.since;
+    }
+    KRUN_SUCCESS
+}
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_load_config(ctx_id: u32, c_path: *const c_char) -> i32 {
+    let path = match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => path,
+        Err(_) => return -libc::EINVAL,
+    };
+
+    let definition = match VmDefinition::load(path) {
+        Ok(definition) => definition,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            return -libc::EINVAL;
+        }
+    };
+
+    with_ctx_config(ctx_id, |cfg| {
+        let builder = KrunVmBuilder::from_ctx_config(std::mem::take(cfg));
+        let (ctx_cfg, error) = definition.apply(builder).into_ctx_config();
+        *cfg = ctx_cfg;
+        match error {
+            Some(e) => {
+                warn!(
+                    "Unable to apply the configuration in {}: {}",
+                    path,
+                    ErrorChain(&e)
+                );
+                -libc::EINVAL
+            }
+            None => KRUN_SUCCESS,
+        }
+    })
+}
+
+#[cfg(feature = "fault-injection")]
+#[no_mangle]
+pub extern "C" fn krun_inject_queue_error(device_type: u32, count: u32) -> i32 {
+    FAULTS.fail_queue(device_type, count as usize);
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "fault-injection")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_inject_fs_eio(c_path: *const c_char) -> i32 {
+    match CStr::from_ptr(c_path).to_str() {
+        Ok(path) => {
+            FAULTS.fail_fs_path(PathBuf::from(path));
+            KRUN_SUCCESS
+        }
+        Err(_) => -libc::EINVAL,
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+#[no_mangle]
+pub extern "C" fn krun_inject_vsock_reset(count: u32) -> i32 {
+    FAULTS.reset_vsock_connections(count as usize);
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "fault-injection")]
+#[no_mangle]
+pub extern "C" fn krun_inject_irq_delay(device_type: u32, delay_ms: u32) -> i32 {
+    FAULTS.delay_interrupts(device_type, Duration::from_millis(delay_ms.into()));
+    KRUN_SUCCESS
+}
+
+#[cfg(feature = "fault-injection")]
+#[no_mangle]
+pub extern "C" fn krun_clear_faults() -> i32 {
+    FAULTS.clear();
+    KRUN_SUCCESS
+}
+
+/// Binds the host ports of the port map not handed over already, and waits for a connection on
+/// any of the activated sockets. The connections wait in the backlog of their socket until the
+/// workload listens on it.
+fn wait_for_demand(ctx_cfg: &mut ContextConfig, listen_addr: IpAddr) -> io::Result<()> {
+    let mut host_ports: Vec<u16> = ctx_cfg
+        .port_map
+        .iter()
+        .flat_map(|port_map| port_map.values().copied())
+        .collect();
+    host_ports.sort_unstable();
+    for port in host_ports {
+        if ctx_cfg.vmr.activated_sockets.tcp(port).is_none() {
+            let listener = TcpListener::bind((listen_addr, port))?;
+            ctx_cfg
+                .vmr
+                .add_activated_socket(ListenSocket::Tcp(listener), false)?;
+        }
+    }
+
+    info!(
+        "Waiting for a connection on {} sockets to boot the microVM",
+        ctx_cfg.vmr.activated_sockets.len()
+    );
+    ctx_cfg.vmr.activated_sockets.wait_connection()
+}
+
+fn build_ctx_microvm(
+    ctx_cfg: &mut ContextConfig,
+    event_manager: &mut EventManager,
+) -> Result<Arc<Mutex<Vmm>>, vm::Error> {
+    if let Some(listen_addr) = ctx_cfg.boot_on_demand {
+        wait_for_demand(ctx_cfg, listen_addr).map_err(vm::Error::BootOnDemand)?;
+    }
+
+    if let Some(fs_cfg) = ctx_cfg.get_fs_cfg() {
+        ctx_cfg
+            .vmr
+            .set_fs_device(fs_cfg)
+            .map_err(vm::Error::FsDevice)?;
+    }
+
+    // Tells init to set up the features needing its cooperation: mounting the seed where
+    // cloud-init looks for it, mounting the shares, loading the extra kernel modules, setting the
+    // identity of the guest and its CA certificates, reading the secrets, forwarding the logs,
+    // sizing the tmpfs, enabling the swap, applying the sysctls and the limits of the workload,
+    // setting up the environment, the user and the privileges it's executed with, and running
+    // the services alongside it.
+    let mut init_flags = Vec::new();
+    if ctx_cfg.vmr.cloud_init.is_some() {
+        init_flags.push("KRUN_CLOUD_INIT=1".to_string());
+    }
+    if ctx_cfg.vmr.kernel_modules.is_some() {
+        init_flags.push("KRUN_KERNEL_MODULES=1".to_string());
+    }
+    if let Some(hostname) = &ctx_cfg.vmr.hostname {
+        init_flags.push(format!("KRUN_HOSTNAME={}", hostname));
+    }
+    if ctx_cfg.vmr.etc_overrides.is_some() {
+        init_flags.push("KRUN_ETC=1".to_string());
+    }
+    if ctx_cfg.vmr.ca_certs.is_some() {
+        init_flags.push("KRUN_CA_CERTS=1".to_string());
+    }
+    if let Some(secrets) = &ctx_cfg.vmr.secrets {
+        if !secrets.lock().unwrap().is_empty() {
+            init_flags.push("KRUN_SECRETS=1".to_string());
+        }
+    }
+    if ctx_cfg.vmr.log_channel.is_some() {
+        init_flags.push(format!("KRUN_LOG_CHANNEL={}", LOG_CHANNEL_GUEST_DEVICE));
+    }
+    if ctx_cfg.vmr.clipboard.is_some() {
+        let device = clipboard_guest_device(ctx_cfg.vmr.log_channel.is_some());
+        init_flags.push(format!("KRUN_CLIPBOARD={}", device));
+    }
+    let serials: Vec<String> = ctx_cfg
+        .vmr
+        .serial_passthrough_devices()
+        .iter()
+        .map(|(name, device)| format!("{}={}", name, device))
+        .collect();
+    if !serials.is_empty() {
+        init_flags.push(format!("KRUN_SERIALS=\"{}\"", serials.join(",")));
+    }
+    if let Some(fsfreeze) = &ctx_cfg.vmr.fsfreeze {
+        init_flags.push(format!(
+            "KRUN_FSFREEZE=\"{},{}\"",
+            ctx_cfg.vmr.fsfreeze_device(),
+            fsfreeze.thaw_timeout().as_secs()
+        ));
+    }
+    if ctx_cfg.vmr.guest_network.is_some() {
+        init_flags.push(format!(
+            "KRUN_GUEST_NETWORK={}",
+            ctx_cfg.vmr.guest_network_device()
+        ));
+    }
+    if !ctx_cfg.vmr.shares.is_empty() {
+        let mounts: Vec<String> = ctx_cfg
+            .vmr
+            .share_mounts()
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+        init_flags.push(format!("KRUN_SHARES=\"{}\"", mounts.join(",")));
+    }
+    for probe in ctx_cfg.vmr.probes.iter() {
+        if let Some(command) = probe.guest_command() {
+            init_flags.push(format!("{}=\"{}\"", probe.kind.guest_env(), command));
+        }
+    }
+    if let Some(size) = ctx_cfg.vmr.tmpfs.tmp_size_mib {
+        init_flags.push(format!("KRUN_TMP_SIZE_MIB={}", size));
+    }
+    if let Some(size) = ctx_cfg.vmr.tmpfs.shm_size_mib {
+        init_flags.push(format!("KRUN_SHM_SIZE_MIB={}", size));
+    }
+    if ctx_cfg.vmr.swap.is_some() {
+        init_flags.push(format!("KRUN_SWAP={}", SWAP_DEVICE_ID));
+    }
+    if !ctx_cfg.vmr.sysctls.is_empty() {
+        let sysctls: Vec<String> = ctx_cfg.vmr.sysctls.iter().map(|s| s.to_string()).collect();
+        init_flags.push(format!("KRUN_SYSCTLS=\"{}\"", sysctls.join(",")));
+    }
+    if !ctx_cfg.vmr.rlimits.is_empty() {
+        let rlimits: Vec<String> = ctx_cfg.vmr.rlimits.iter().map(|r| r.to_string()).collect();
+        init_flags.push(format!("KRUN_RLIMITS=\"{}\"", rlimits.join(",")));
+    }
+    if !ctx_cfg.vmr.cgroup_limits.is_empty() {
+        let limits: Vec<String> = ctx_cfg
+            .vmr
+            .cgroup_limits
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        init_flags.push(format!("KRUN_CGROUP=\"{}\"", limits.join(",")));
+    }
+    init_flags.extend(ctx_cfg.vmr.exec_env.guest_env());
+    if let Some(user) = &ctx_cfg.vmr.exec_user {
+        init_flags.push(user.guest_env());
+    }
+    init_flags.extend(ctx_cfg.vmr.exec_security.guest_env());
+    for (index, service) in ctx_cfg.vmr.services.iter().enumerate() {
+        init_flags.push(service.guest_env(index));
+    }
+
+    let mut boot_source = BootSourceConfig::default();
+    boot_source.kernel_cmdline_prolog = Some(format!(
+        "{} init={} KRUN_INIT={} KRUN_WORKDIR={} {} {}",
+        DEFAULT_KERNEL_CMDLINE,
+        INIT_PATH,
+        ctx_cfg.get_exec_path(),
+        ctx_cfg.get_workdir(),
+        init_flags.join(" "),
+        ctx_cfg.get_env(),
+    ));
+    boot_source.kernel_cmdline_epilog = Some(format!(" -- {}", ctx_cfg.get_args()));
+
+    ctx_cfg
+        .vmr
+        .set_boot_source(boot_source)
+        .map_err(vm::Error::BootSource)?;
+
+    let vsock_device_config = VsockDeviceConfig {
+        vsock_id: "vsock0".to_string(),
+        guest_cid: ctx_cfg.vmr.vsock_cid.cid(),
+        host_port_map: ctx_cfg.get_port_map(),
+        conn_config: ctx_cfg.vmr.vsock_conn,
+    };
+    ctx_cfg
+        .vmr
+        .set_vsock_device(vsock_device_config)
+        .map_err(vm::Error::VsockDevice)?;
+
+    let vmm = vmm::builder::build_microvm(&ctx_cfg.vmr, event_manager)
+        .map_err(vm::Error::StartMicrovm)?;
+
+    if let Some(listener) = ctx_cfg.monitor.take() {
+        let mem_size_mib = ctx_cfg.vmr.vm_config().mem_size_mib.unwrap_or_default();
+        Monitor::new(
+            vmm.clone(),
+            ctx_cfg.vmr.block_devices.clone(),
+            (mem_size_mib as u64) << 20,
+        )
+        .start(listener)
+        .map_err(vm::Error::Monitor)?;
+    }
+
+    Ok(vmm)
+}
+
+#[no_mangle]
+pub extern "C" fn krun_start_enter(ctx_id: u32) -> i32 {
+    #[cfg(target_os = "linux")]
+    {
+        let prname = match env::var("HOSTNAME") {
+            Ok(val) => CString::new(format!("VM:{}", val)).unwrap(),
+            Err(_) => CString::new("libkrun VM").unwrap(),
+        };
+        unsafe { libc::prctl(libc::PR_SET_NAME, prname.as_ptr()) };
+    }
+
+    let mut event_manager = match EventManager::new() {
+        Ok(em) => em,
+        Err(e) => {
+            warn!("Unable to create EventManager: {}", ErrorChain(&e));
+            return -libc::EINVAL;
+        }
+    };
+
+    let ctx = match get_ctx(ctx_id) {
+        Some(ctx) => ctx,
+        None => return -libc::ENOENT,
+    };
+
+    let mut ctx_cfg = {
+        let mut cfg = ctx.cfg.lock().unwrap();
+        if !ctx.transition(ContextState::Configuring, ContextState::Running) {
+            return -libc::EBUSY;
+        }
+        std::mem::take(&mut *cfg)
+    };
+
+    let vmm = match build_ctx_microvm(&mut ctx_cfg, &mut event_manager) {
+        Ok(vmm) => vmm,
+        Err(e) => {
+            warn!("{}", ErrorChain(&e));
+            ctx.transition(ContextState::Running, ContextState::Stopped);
+            return -libc::EINVAL;
+        }
+    };
+
+    loop {
+        match event_manager.run() {
+            // The VMM only stops without exiting the process if the embedder has an exit handle.
+            Ok(_) if vmm.lock().unwrap().has_exited() => {
+                ctx.transition(ContextState::Running, ContextState::Stopped);
+                return KRUN_SUCCESS;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Error in EventManager loop: {}", ErrorChain(&e));
+                ctx.transition(ContextState::Running, ContextState::Stopped);
                 return -libc::EINVAL;
             }
         }
     }
 }
+
+#[no_mangle]
+pub extern "C" fn krun_get_exit_fd(ctx_id: u32) -> i32 {
+    if cfg!(not(target_os = "linux")) {
+        return -libc::ENOTSUP;
+    }
+
+    with_ctx_config_and_handles(ctx_id, |cfg, handles| {
+        if let Some(handle) = &handles.exit_handle {
+            return handle.as_raw_fd();
+        }
+        let handle = match ExitHandle::new() {
+            Ok(handle) => handle,
+            Err(e) => {
+                warn!("Unable to create the exit handle: {}", e);
+                return -libc::EINVAL;
+            }
+        };
+        cfg.vmr.set_exit_handle(handle.clone());
+        let fd = handle.as_raw_fd();
+        handles.exit_handle = Some(handle);
+        fd
+    })
+}
+
+// Reasons returned by krun_get_exit_status.
+const KRUN_EXIT_GUEST_SHUTDOWN: i32 = 0;
+const KRUN_EXIT_MAX_RUNTIME: i32 = 1;
+const KRUN_EXIT_DEVICE_FAILURE: i32 = 2;
+const KRUN_EXIT_VCPU_ERROR: i32 = 3;
+
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn krun_get_exit_status(ctx_id: u32, exit_code: *mut i32) -> i32 {
+    let status = match ctx_handle(ctx_id, |handles| {
+        handles.exit_handle.as_ref().map(ExitHandle::status)
+    }) {
+        Some(status) => status,
+        None => return -libc::ENOENT,
+    };
+    let status = match status {
+        Some(status) => status,
+        None => return -libc::EAGAIN,
+    };
+
+    if !exit_code.is_null() {
+        *exit_code = status.code;
+    }
+    match status.reason {
+        ExitReason::GuestShutdown => KRUN_EXIT_GUEST_SHUTDOWN,
+        ExitReason::MaxRuntime => KRUN_EXIT_MAX_RUNTIME,
+        ExitReason::DeviceFailure => KRUN_EXIT_DEVICE_FAILURE,
+        ExitReason::VcpuError => KRUN_EXIT_VCPU_ERROR,
+    }
+}
@@ -0,0 +1,549 @@
+//! Monitor controlling a running microVM over a Unix socket, with a subset of the commands of
+//! QMP, the QEMU Machine Protocol, so the tools speaking it can drive it.
+//!
+//! Each client is greeted as by QEMU, with the version of libkrun in place of the one of QEMU,
+//! then sends one JSON command per line, each answered on a line of its own, with the `id` of the
+//! command if it has one:
+//!
+//! ```text
+//! <- {"QMP":{"capabilities":[],"version":{"package":"libkrun","qemu":{"major":0,...}}}}
+//! -> {"execute": "qmp_capabilities"}
+//! <- {"return":{}}
+//! -> {"execute": "query-status"}
+//! <- {"return":{"running":true,"status":"running"}}
+//! -> {"execute": "balloon", "arguments": {"value": 536870912}, "id": 1}
+//! <- {"id":1,"return":{}}
+//! -> {"execute": "device_del", "arguments": {"id": "virtio_balloon"}}
+//! <- {"error":{"class":"GenericError","desc":"..."}}
+//! ```
+//!
+//! The commands are `qmp_capabilities`, `query-status`, `query-devices`, `stop`, `cont`,
+//! `query-balloon`, `balloon`, `block_resize`, `system_powerdown` and `quit`. There are no
+//! capabilities to negotiate, so the other commands are also accepted before `qmp_capabilities`.
+//! `device_add` and `device_del` are refused, as the devices of the microVM are fixed once it's
+//! built.
+//!
+//! Up to 8 clients are served at once, and a client sending a line longer than 64 KiB is
+//! disconnected.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use vmm::vmm_config::disk::{BlockDevices, DiskResizeError};
+use vmm::vmm_config::vm_state::VmState;
+use vmm::{DeviceType, Vmm};
+
+use crate::vm::ErrorChain;
+
+// The time a client has to take a reply, before it's dropped.
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+// The clients served at once, each from a thread of its own. The others are turned away.
+const MAX_CLIENTS: usize = 8;
+// The longest line of a command, newline included. Clients sending longer ones are disconnected.
+const MAX_LINE: usize = 64 * 1024;
+// The pages the guest gives up to the balloon are counted in 4 KiB.
+const BALLOON_PAGE_SIZE: u64 = 4096;
+
+/// Errors replied to a command, in the classes of QMP.
+#[derive(Debug, PartialEq)]
+enum CommandError {
+    /// The command isn't supported.
+    CommandNotFound(String),
+    /// The device the command is given doesn't exist.
+    DeviceNotFound(String),
+    /// The command failed.
+    Failed(String),
+    /// The command, or its arguments, is malformed.
+    InvalidRequest(String),
+}
+
+impl CommandError {
+    fn class(&self) -> &'static str {
+        match self {
+            CommandError::CommandNotFound(_) => "CommandNotFound",
+            CommandError::DeviceNotFound(_) => "DeviceNotFound",
+            CommandError::Failed(_) | CommandError::InvalidRequest(_) => "GenericError",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CommandError::*;
+        match self {
+            CommandNotFound(command) => write!(f, "The command {} has not been found", command),
+            DeviceNotFound(id) => write!(f, "Device '{}' not found", id),
+            Failed(desc) | InvalidRequest(desc) => write!(f, "{}", desc),
+        }
+    }
+}
+
+/// A command sent by a client.
+#[derive(Debug, PartialEq)]
+struct Request {
+    command: String,
+    arguments: Map<String, Value>,
+    id: Option<Value>,
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Self, CommandError> {
+        let mut request = match serde_json::from_str(line) {
+            Ok(Value::Object(request)) => request,
+            Ok(_) => {
+                return Err(CommandError::InvalidRequest(
+                    "Expected a JSON object".to_string(),
+                ))
+            }
+            Err(e) => return Err(CommandError::InvalidRequest(format!("Invalid JSON: {}", e))),
+        };
+        let command = match request.remove("execute") {
+            Some(Value::String(command)) => command,
+            _ => {
+                return Err(CommandError::InvalidRequest(
+                    "Expected a command in 'execute'".to_string(),
+                ))
+            }
+        };
+        let arguments = match request.remove("arguments") {
+            Some(Value::Object(arguments)) => arguments,
+            None => Map::new(),
+            Some(_) => {
+                return Err(CommandError::InvalidRequest(
+                    "Expected an object in 'arguments'".to_string(),
+                ))
+            }
+        };
+
+        Ok(Request {
+            command,
+            arguments,
+            id: request.remove("id"),
+        })
+    }
+
+    fn str_argument(&self, name: &str) -> Result<&str, CommandError> {
+        self.arguments
+            .get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| missing_argument(name))
+    }
+
+    fn u64_argument(&self, name: &str) -> Result<u64, CommandError> {
+        self.arguments
+            .get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| missing_argument(name))
+    }
+}
+
+fn missing_argument(name: &str) -> CommandError {
+    CommandError::InvalidRequest(format!("Parameter '{}' is missing or invalid", name))
+}
+
+/// Returns the greeting of QMP, which has no capabilities to offer.
+fn greeting() -> Value {
+    let version = |part: &str| part.parse::<u64>().unwrap_or(0);
+    json!({
+        "QMP": {
+            "version": {
+                "qemu": {
+                    "major": version(env!("CARGO_PKG_VERSION_MAJOR")),
+                    "minor": version(env!("CARGO_PKG_VERSION_MINOR")),
+                    "micro": version(env!("CARGO_PKG_VERSION_PATCH")),
+                },
+                "package": "libkrun",
+            },
+            "capabilities": [],
+        }
+    })
+}
+
+/// Returns the line answering a command, with its `id` if it has one.
+fn reply(id: Option<Value>, result: Result<Value, CommandError>) -> String {
+    let mut reply = match result {
+        Ok(value) => json!({ "return": value }),
+        Err(e) => json!({ "error": { "class": e.class(), "desc": e.to_string() } }),
+    };
+    if let Some(id) = id {
+        reply["id"] = id;
+    }
+    reply.to_string()
+}
+
+/// Returns the name of the type of a device, as in linux/virtio_ids.h for the virtio devices.
+fn device_type_name(device_type: &DeviceType) -> String {
+    match device_type {
+        DeviceType::Virtio(1) => "virtio-net".to_string(),
+        DeviceType::Virtio(2) => "virtio-blk".to_string(),
+        DeviceType::Virtio(3) => "virtio-console".to_string(),
+        DeviceType::Virtio(4) => "virtio-rng".to_string(),
+        DeviceType::Virtio(5) => "virtio-balloon".to_string(),
+        DeviceType::Virtio(16) => "virtio-gpu".to_string(),
+        DeviceType::Virtio(19) => "virtio-vsock".to_string(),
+        DeviceType::Virtio(20) => "virtio-crypto".to_string(),
+        DeviceType::Virtio(25) => "virtio-snd".to_string(),
+        DeviceType::Virtio(26) => "virtio-fs".to_string(),
+        DeviceType::Virtio(id) => format!("virtio-{}", id),
+        #[allow(unreachable_patterns)]
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+/// Returns the number of pages the guest gives up to the balloon so `value` bytes of its
+/// `mem_size` are left to it.
+fn balloon_pages(mem_size: u64, value: u64) -> Result<u32, CommandError> {
+    if value == 0 || value > mem_size {
+        return Err(CommandError::Failed(format!(
+            "Parameter 'value' expects a size between 1 and {} bytes",
+            mem_size
+        )));
+    }
+    Ok(((mem_size - value) / BALLOON_PAGE_SIZE) as u32)
+}
+
+// Reads the next line of a client into `line`, returning false once the client disconnected. At
+// most MAX_LINE bytes are read, so a client can't make the monitor buffer a line of any size.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    if reader.take(MAX_LINE as u64 + 1).read_line(line)? == 0 {
+        return Ok(false);
+    }
+    if line.len() > MAX_LINE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Commands are limited to {} bytes", MAX_LINE),
+        ));
+    }
+    Ok(true)
+}
+
+/// The monitor of a microVM, serving its clients from threads of their own.
+#[derive(Clone)]
+pub struct Monitor {
+    vmm: Arc<Mutex<Vmm>>,
+    block_devices: BlockDevices,
+    // The size of the guest memory, in bytes.
+    mem_size: u64,
+    // The clients being served.
+    clients: Arc<AtomicUsize>,
+}
+
+impl Monitor {
+    pub fn new(vmm: Arc<Mutex<Vmm>>, block_devices: BlockDevices, mem_size: u64) -> Self {
+        Monitor {
+            vmm,
+            block_devices,
+            mem_size,
+            clients: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Serves the clients connecting to `listener`, for as long as the process runs.
+    pub fn start(self, listener: UnixListener) -> io::Result<()> {
+        thread::Builder::new()
+            .name("monitor".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("monitor: unable to accept a client: {}", e);
+                            continue;
+                        }
+                    };
+                    if self.clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                        self.clients.fetch_sub(1, Ordering::SeqCst);
+                        warn!(
+                            "monitor: turning a client away, {} already served",
+                            MAX_CLIENTS
+                        );
+                        continue;
+                    }
+                    let monitor = self.clone();
+                    let spawned = thread::Builder::new()
+                        .name("monitor client".to_string())
+                        .spawn(move || {
+                            if let Err(e) = monitor.serve(stream) {
+                                debug!("monitor: client error: {}", e);
+                            }
+                            monitor.clients.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    if let Err(e) = spawned {
+                        self.clients.fetch_sub(1, Ordering::SeqCst);
+                        warn!("monitor: unable to serve a client: {}", e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    // Answers the commands of a client, until it disconnects.
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        writeln!(writer, "{}", greeting())?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            match read_line(&mut reader, &mut line) {
+                Ok(true) => (),
+                Ok(false) => break,
+                // The rest of the line can't be told apart from the next command, so the client is
+                // only told why it's disconnected.
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let e = CommandError::InvalidRequest(e.to_string());
+                    writeln!(writer, "{}", reply(None, Err(e)))?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (id, result, quit) = match Request::parse(&line) {
+                Ok(request) => {
+                    let result = self.execute(&request);
+                    let quit = request.command == "quit" && result.is_ok();
+                    (request.id, result, quit)
+                }
+                Err(e) => (None, Err(e), false),
+            };
+            writeln!(writer, "{}", reply(id, result))?;
+
+            // Only stopped once the client is told, as it may be the last thing it hears.
+            if quit {
+                info!("monitor: stopping the microVM");
+                if let Err(e) = self.vmm.lock().unwrap().request_exit() {
+                    error!("monitor: unable to stop the microVM: {}", ErrorChain(&e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&self, request: &Request) -> Result<Value, CommandError> {
+        debug!("monitor: {}", request.command);
+        match request.command.as_str() {
+            // Capabilities are negotiated by QMP clients, though there are none to negotiate.
+            "qmp_capabilities" | "quit" => Ok(json!({})),
+            "query-status" => {
+                let state = self.vmm.lock().unwrap().state();
+                let mut status = json!({
+                    "status": state.as_str(),
+                    "running": state == VmState::Running,
+                });
+                if let VmState::Stopped(Some(reason)) = state {
+                    status["reason"] = json!(reason.as_str());
+                }
+                Ok(status)
+            }
+            "query-devices" => {
+                let devices: Vec<Value> = self
+                    .vmm
+                    .lock()
+                    .unwrap()
+                    .devices()
+                    .iter()
+                    .map(|(device_type, id)| {
+                        json!({ "id": id, "type": device_type_name(device_type) })
+                    })
+                    .collect();
+                Ok(Value::Array(devices))
+            }
+            "stop" => self.pause(),
+            "cont" => self.resume(),
+            "query-balloon" => {
+                let (_, actual) = self
+                    .vmm
+                    .lock()
+                    .unwrap()
+                    .balloon_pages()
+                    .map_err(|e| CommandError::Failed(ErrorChain(&e).to_string()))?;
+                let actual = self
+                    .mem_size
+                    .saturating_sub(u64::from(actual) * BALLOON_PAGE_SIZE);
+                Ok(json!({ "actual": actual }))
+            }
+            "balloon" => {
+                let num_pages = balloon_pages(self.mem_size, request.u64_argument("value")?)?;
+                self.vmm
+                    .lock()
+                    .unwrap()
+                    .set_balloon_target(num_pages)
+                    .map_err(|e| CommandError::Failed(ErrorChain(&e).to_string()))?;
+                Ok(json!({}))
+            }
+            "block_resize" => {
+                let device = request.str_argument("device")?;
+                let size = request.u64_argument("size")?;
+                match self.block_devices.resize(device, size) {
+                    Ok(_) => Ok(json!({})),
+                    Err(DiskResizeError::UnknownDevice(id)) => {
+                        Err(CommandError::DeviceNotFound(id))
+                    }
+                    Err(e) => Err(CommandError::Failed(ErrorChain(&e).to_string())),
+                }
+            }
+            "device_add" | "device_del" => Err(CommandError::Failed(
+                "The devices of the microVM are fixed once it's built".to_string(),
+            )),
+            "system_powerdown" => self.powerdown(),
+            command => Err(CommandError::CommandNotFound(command.to_string())),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn pause(&self) -> Result<Value, CommandError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .pause()
+            .map_err(|e| CommandError::Failed(ErrorChain(&e).to_string()))?;
+        Ok(json!({}))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn resume(&self) -> Result<Value, CommandError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .resume()
+            .map_err(|e| CommandError::Failed(ErrorChain(&e).to_string()))?;
+        Ok(json!({}))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn pause(&self) -> Result<Value, CommandError> {
+        Err(CommandError::Failed(
+            "Pausing the microVM isn't supported on this platform".to_string(),
+        ))
+    }
+
+    #[cfg(target_os = "macos")]
+    fn resume(&self) -> Result<Value, CommandError> {
+        self.pause()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn powerdown(&self) -> Result<Value, CommandError> {
+        self.vmm
+            .lock()
+            .unwrap()
+            .send_ctrl_alt_del()
+            .map_err(|e| CommandError::Failed(ErrorChain(&e).to_string()))?;
+        Ok(json!({}))
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn powerdown(&self) -> Result<Value, CommandError> {
+        // There's no device through which the guest can be asked to shut down.
+        Err(CommandError::Failed(
+            "The guest can't be asked to shut down on this architecture".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            Request::parse(r#"{"execute": "balloon", "arguments": {"value": 4096}, "id": "a"}"#)
+                .unwrap();
+        assert_eq!(request.command, "balloon");
+        assert_eq!(request.u64_argument("value").unwrap(), 4096);
+        assert!(request.str_argument("value").is_err());
+        assert_eq!(request.id, Some(json!("a")));
+
+        let request = Request::parse(r#"{"execute": "query-status"}"#).unwrap();
+        assert!(request.arguments.is_empty());
+        assert_eq!(request.id, None);
+
+        for line in &[
+            "query-status",
+            "[]",
+            r#"{"arguments": {}}"#,
+            r#"{"execute": "balloon", "arguments": 1}"#,
+        ] {
+            assert_eq!(Request::parse(line).unwrap_err().class(), "GenericError");
+        }
+    }
+
+    #[test]
+    fn test_greeting() {
+        let greeting = greeting();
+        assert_eq!(greeting["QMP"]["capabilities"], json!([]));
+        let version = &greeting["QMP"]["version"];
+        assert_eq!(version["package"], "libkrun");
+        assert_eq!(
+            format!(
+                "{}.{}.{}",
+                version["qemu"]["major"], version["qemu"]["minor"], version["qemu"]["micro"]
+            ),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    #[test]
+    fn test_reply() {
+        assert_eq!(reply(None, Ok(json!({}))), r#"{"return":{}}"#);
+        assert_eq!(
+            reply(Some(json!(1)), Ok(json!({"status": "running"}))),
+            r#"{"id":1,"return":{"status":"running"}}"#
+        );
+        assert_eq!(
+            reply(
+                None,
+                Err(CommandError::CommandNotFound("migrate".to_string()))
+            ),
+            r#"{"error":{"class":"CommandNotFound","desc":"The command migrate has not been found"}}"#
+        );
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut line = String::new();
+        let mut reader = io::Cursor::new(b"{}\n\n{}".to_vec());
+        assert!(read_line(&mut reader, &mut line).unwrap());
+        assert_eq!(line, "{}\n");
+        assert!(read_line(&mut reader, &mut line).unwrap());
+        assert_eq!(line, "\n");
+        assert!(read_line(&mut reader, &mut line).unwrap());
+        assert_eq!(line, "{}");
+        assert!(!read_line(&mut reader, &mut line).unwrap());
+
+        let mut data = vec![b' '; MAX_LINE - 1];
+        data.push(b'\n');
+        let mut reader = io::Cursor::new(data.clone());
+        assert!(read_line(&mut reader, &mut line).unwrap());
+        assert_eq!(line.len(), MAX_LINE);
+
+        data.insert(0, b' ');
+        let mut reader = io::Cursor::new(data);
+        assert_eq!(
+            read_line(&mut reader, &mut line).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_balloon_pages() {
+        let mem_size = 512 << 20;
+        assert_eq!(balloon_pages(mem_size, mem_size).unwrap(), 0);
+        assert_eq!(balloon_pages(mem_size, 256 << 20).unwrap(), 65536);
+        assert!(balloon_pages(mem_size, 0).is_err());
+        assert!(balloon_pages(mem_size, mem_size + 1).is_err());
+        assert_eq!(device_type_name(&DeviceType::Virtio(5)), "virtio-balloon");
+        assert_eq!(device_type_name(&DeviceType::Virtio(42)), "virtio-42");
+    }
+}
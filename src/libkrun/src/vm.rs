@@ -0,0 +1,1209 @@
+//! Safe Rust interface for configuring and running a microVM, for embedders that don't want to go
+//! through the C API.
+//!
+//! ```no_run
+//! use krun::vm::KrunVmBuilder;
+//!
+//! let mut vm = KrunVmBuilder::new()?
+//!     .vm_config(1, 512)
+//!     .root("/path/to/rootfs")
+//!     .exec("/bin/sh", &["-c", "echo hello"], Some(&["PATH=/bin"]))
+//!     .build()?;
+//! vm.run()?;
+//! # Ok::<(), krun::vm::Error>(())
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use polly::event_manager::{self, EventManager};
+use vmm::builder::StartMicrovmError;
+use vmm::vmm_config::boot_source::BootSourceConfigError;
+use vmm::vmm_config::boot_timeline::BootTimeline;
+use vmm::vmm_config::ca_certs::CaCertsError;
+use vmm::vmm_config::clipboard::Clipboard;
+use vmm::vmm_config::cloud_init::{CloudInitConfig, CloudInitError};
+use vmm::vmm_config::console::{
+    ConsoleBackend, ConsoleOutputConfig, ResizeSource, SessionRecorder, TerminalConfig,
+};
+use vmm::vmm_config::device_panic::{DeviceFailure, DeviceFailureCallback, DevicePanicPolicy};
+use vmm::vmm_config::disk::{BlockDevices, DiskResizeError};
+use vmm::vmm_config::etc::EtcError;
+use vmm::vmm_config::exec::{
+    capability_mask, ExecEnvConfig, ExecEnvError, ExecSecurity, ExecUser, RestartPolicy,
+    ServiceConfig, ServiceError, ServiceState,
+};
+use vmm::vmm_config::exit::ExitHandle;
+use vmm::vmm_config::fs::{FsConfigError, FsDeviceConfig};
+use vmm::vmm_config::fsfreeze::FsFreeze;
+use vmm::vmm_config::guest_network::GuestNetwork;
+use vmm::vmm_config::guest_panic::{GuestPanic, GuestPanicCallback};
+use vmm::vmm_config::idle::{IdlePolicyConfig, IdlePolicyConfigError};
+use vmm::vmm_config::kernel_bundle::{KernelBundle, KernelBundleError};
+use vmm::vmm_config::kernel_modules::KernelModulesError;
+use vmm::vmm_config::kernel_signature::{KernelSignatureError, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+use vmm::vmm_config::kvm_tuning::{KvmTuningConfig, KvmTuningConfigError};
+use vmm::vmm_config::latency_profile::LatencyProfile;
+use vmm::vmm_config::limits::{CgroupLimit, LimitsError, Rlimit};
+use vmm::vmm_config::log_channel::LogChannelConfig;
+use vmm::vmm_config::machine_config::VmConfigError;
+use vmm::vmm_config::options::OptionError;
+use vmm::vmm_config::probes::{ProbeConfig, ProbeConfigError, ProbeKind, ProbeState};
+use vmm::vmm_config::profiling::{ProfileReports, ProfilingConfig, ProfilingConfigError};
+use vmm::vmm_config::restricted_dma::{RestrictedDmaConfig, RestrictedDmaConfigError};
+use vmm::vmm_config::runtime_limit::{RuntimeLimitConfig, RuntimeLimitConfigError};
+use vmm::vmm_config::secrets::SecretsError;
+use vmm::vmm_config::serial_passthrough::{SerialPassthroughConfig, SerialPassthroughError};
+use vmm::vmm_config::shares::{
+    DedupStore, EscapePolicy, ShareCache, ShareError, ShareIoConfig, ShareMount, ShareQuota,
+};
+use vmm::vmm_config::socket_activation::ListenSocket;
+use vmm::vmm_config::swap::{SwapConfig, SwapConfigError};
+use vmm::vmm_config::sysctl::{Sysctl, SysctlError};
+use vmm::vmm_config::tmpfs::TmpfsConfig;
+use vmm::vmm_config::tpm::TpmConfigError;
+use vmm::vmm_config::user_device::{
+    UserDeviceCallback, UserDeviceConfig, UserDeviceError, UserRegionKind,
+};
+use vmm::vmm_config::vm_state::{VmState, VmStateHandle};
+use vmm::vmm_config::vsock::{GuestCid, VsockConfigError, VMADDR_CID_ANY};
+use vmm::vmm_config::workload::WorkloadStartedCallback;
+use vmm::Vmm;
+
+use super::krunfw::KrunfwError;
+use super::ContextConfig;
+use super::{
+    build_ctx_microvm, is_valid_mapped_volume, new_cpu_topology_config, new_ctx_config,
+    new_vm_config, KERNEL_FLAVORS,
+};
+
+/// Displays an error followed by the chain of its sources, for embedders not using a crate
+/// reporting them already.
+pub use utils::error::ErrorChain;
+
+/// Errors associated with configuring and running a microVM.
+#[derive(Debug)]
+pub enum Error {
+    /// A listening socket handed over can't be used by the vsock device.
+    ActivatedSocket(io::Error),
+    /// Unable to bind the host ports of the port map, or to wait for a connection on them, to boot
+    /// the microVM on demand.
+    BootOnDemand(io::Error),
+    /// The boot source configuration is invalid.
+    BootSource(BootSourceConfigError),
+    /// The CA certificates couldn't be shared.
+    CaCerts(CaCertsError),
+    /// The cloud-init seed couldn't be set up.
+    CloudInit(CloudInitError),
+    /// Unable to create the buffer for the console input.
+    CreateConsole(io::Error),
+    /// Unable to create the event manager.
+    CreateEventManager(event_manager::Error),
+    /// The identity of the guest, or an override of its "/etc", is invalid.
+    Etc(EtcError),
+    /// The event loop failed while the microVM was running.
+    EventLoop(event_manager::Error),
+    /// The environment of the workload is invalid.
+    ExecEnv(ExecEnvError),
+    /// The Fs device configuration is invalid.
+    FsDevice(FsConfigError),
+    /// The idle policy configuration is invalid.
+    IdlePolicy(IdlePolicyConfigError),
+    /// A mapped volume is not a pair of absolute paths with an existing host path and a guest
+    /// path directly under "/".
+    InvalidMappedVolume(PathBuf, PathBuf),
+    /// An option couldn't be set.
+    InvalidOption(OptionError),
+    /// The kernel bundle provided by libkrunfw is invalid.
+    KernelBundle(KernelBundleError),
+    /// libkrunfw can't be located or loaded.
+    Krunfw(KrunfwError),
+    /// A kernel module couldn't be staged.
+    KernelModule(KernelModulesError),
+    /// A key trusted to sign the kernel is invalid.
+    KernelSignature(KernelSignatureError),
+    /// The KVM tuning configuration is invalid.
+    KvmTuning(KvmTuningConfigError),
+    /// An rlimit or a cgroup limit of the workload is invalid.
+    Limits(LimitsError),
+    /// Unable to listen for the clients of the monitor, or to serve them.
+    Monitor(io::Error),
+    /// A probe of the workload is invalid.
+    Probe(ProbeConfigError),
+    /// The profiling configuration is invalid.
+    Profiling(ProfilingConfigError),
+    /// A disk can't be resized.
+    ResizeDisk(DiskResizeError),
+    /// The restricted DMA configuration is invalid.
+    RestrictedDma(RestrictedDmaConfigError),
+    /// The runtime limit configuration is invalid.
+    RuntimeLimit(RuntimeLimitConfigError),
+    /// A secret is invalid.
+    Secrets(SecretsError),
+    /// A serial device passed through is invalid, or can't be added.
+    SerialPassthrough(SerialPassthroughError),
+    /// A service is invalid, or can't be added.
+    Service(ServiceError),
+    /// A share is invalid, or can't be created.
+    Share(ShareError),
+    /// Unable to spawn the thread running the VMM.
+    SpawnVmmThread(io::Error),
+    /// Unable to build or start the microVM.
+    StartMicrovm(StartMicrovmError),
+    /// The microVM stopped, or never started, because of the inner error.
+    Stopped(Arc<Error>),
+    /// The swap configuration is invalid.
+    Swap(SwapConfigError),
+    /// A sysctl is invalid.
+    Sysctl(SysctlError),
+    /// The TPM configuration is invalid.
+    Tpm(TpmConfigError),
+    /// No kernel bundle is registered as the flavor.
+    UnknownKernelFlavor(String),
+    /// The libkrunfw library is older than the minimum version supported.
+    UnsupportedKrunfw(u32),
+    /// A region handed to the embedder is invalid, or overlaps another one.
+    UserDevice(UserDeviceError),
+    /// The vCPU or memory configuration is invalid.
+    VmConfig(VmConfigError),
+    /// The vsock device configuration is invalid.
+    VsockDevice(VsockConfigError),
+}
+
+impl Error {
+    /// Returns a code identifying the variant, for embedders to match on across releases. Codes
+    /// are never reused nor renumbered, and new variants get the next one. The variant causing an
+    /// error of a lower layer is available through `source()`, like `StartMicrovmError::code()`.
+    pub fn code(&self) -> u32 {
+        use self::Error::*;
+        match self {
+            BootSource(_) => 1,
+            CaCerts(_) => 2,
+            CloudInit(_) => 3,
+            CreateConsole(_) => 4,
+            CreateEventManager(_) => 5,
+            Etc(_) => 6,
+            EventLoop(_) => 7,
+            FsDevice(_) => 8,
+            IdlePolicy(_) => 9,
+            InvalidMappedVolume(..) => 10,
+            InvalidOption(_) => 11,
+            KernelBundle(_) => 12,
+            KernelModule(_) => 13,
+            Limits(_) => 14,
+            RuntimeLimit(_) => 15,
+            Secrets(_) => 16,
+            SpawnVmmThread(_) => 17,
+            StartMicrovm(_) => 18,
+            Stopped(_) => 19,
+            Sysctl(_) => 20,
+            Tpm(_) => 21,
+            UnsupportedKrunfw(_) => 22,
+            VmConfig(_) => 23,
+            VsockDevice(_) => 24,
+            Share(_) => 25,
+            Swap(_) => 26,
+            KvmTuning(_) => 27,
+            ActivatedSocket(_) => 28,
+            Probe(_) => 29,
+            Profiling(_) => 30,
+            ExecEnv(_) => 31,
+            Service(_) => 32,
+            ResizeDisk(_) => 33,
+            RestrictedDma(_) => 34,
+            KernelSignature(_) => 35,
+            UnknownKernelFlavor(_) => 36,
+            Krunfw(_) => 37,
+            SerialPassthrough(_) => 38,
+            BootOnDemand(_) => 39,
+            UserDevice(_) => 40,
+            Monitor(_) => 41,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            ActivatedSocket(_) => write!(f, "Unable to use the activated socket"),
+            BootOnDemand(_) => write!(f, "Unable to wait for a connection to boot the microVM"),
+            BootSource(_) => write!(f, "Invalid boot source configuration"),
+            CaCerts(e) => write!(f, "{}", e),
+            CloudInit(_) => write!(f, "Invalid cloud-init configuration"),
+            CreateConsole(_) => write!(f, "Unable to create console input buffer"),
+            CreateEventManager(_) => write!(f, "Unable to create EventManager"),
+            Etc(e) => write!(f, "{}", e),
+            EventLoop(_) => write!(f, "Error in EventManager loop"),
+            ExecEnv(e) => write!(f, "{}", e),
+            FsDevice(_) => write!(f, "Invalid fs device configuration"),
+            IdlePolicy(_) => write!(f, "Invalid idle policy"),
+            InvalidMappedVolume(host, guest) => write!(
+                f,
+                "Invalid mapped volume: {}:{}",
+                host.display(),
+                guest.display()
+            ),
+            InvalidOption(e) => write!(f, "{}", e),
+            KernelBundle(_) => write!(f, "Invalid kernel bundle"),
+            Krunfw(_) => write!(f, "Unable to load libkrunfw"),
+            KernelModule(e) => write!(f, "{}", e),
+            KernelSignature(e) => write!(f, "{}", e),
+            KvmTuning(e) => write!(f, "{}", e),
+            Limits(e) => write!(f, "{}", e),
+            Monitor(_) => write!(f, "Unable to run the monitor"),
+            Probe(e) => write!(f, "{}", e),
+            Profiling(e) => write!(f, "{}", e),
+            ResizeDisk(e) => write!(f, "{}", e),
+            RestrictedDma(e) => write!(f, "{}", e),
+            RuntimeLimit(_) => write!(f, "Invalid runtime limit"),
+            Secrets(e) => write!(f, "{}", e),
+            SerialPassthrough(e) => write!(f, "{}", e),
+            Service(e) => write!(f, "{}", e),
+            Share(e) => write!(f, "{}", e),
+            SpawnVmmThread(_) => write!(f, "Unable to spawn the VMM thread"),
+            StartMicrovm(_) => write!(f, "Building the microVM failed"),
+            Stopped(_) => write!(f, "The microVM stopped"),
+            Swap(e) => write!(f, "{}", e),
+            Sysctl(e) => write!(f, "{}", e),
+            Tpm(_) => write!(f, "Invalid TPM configuration"),
+            UnknownKernelFlavor(name) => write!(f, "Unknown kernel flavor: {}", name),
+            UnsupportedKrunfw(version) => write!(f, "Unsupported libkrunfw version: {}", version),
+            UserDevice(e) => write!(f, "{}", e),
+            VmConfig(_) => write!(f, "Invalid VM configuration"),
+            VsockDevice(_) => write!(f, "Invalid vsock device configuration"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+        match self {
+            // These are displayed in place of the error wrapping them, so their source is its
+            // source.
+            CaCerts(e) => std::error::Error::source(e),
+            Etc(e) => std::error::Error::source(e),
+            ExecEnv(e) => std::error::Error::source(e),
+            InvalidOption(e) => std::error::Error::source(e),
+            KernelModule(e) => std::error::Error::source(e),
+            KernelSignature(e) => std::error::Error::source(e),
+            KvmTuning(e) => std::error::Error::source(e),
+            Limits(e) => std::error::Error::source(e),
+            Probe(e) => std::error::Error::source(e),
+            Profiling(e) => std::error::Error::source(e),
+            ResizeDisk(e) => std::error::Error::source(e),
+            RestrictedDma(e) => std::error::Error::source(e),
+            Secrets(e) => std::error::Error::source(e),
+            SerialPassthrough(e) => std::error::Error::source(e),
+            Service(e) => std::error::Error::source(e),
+            Share(e) => std::error::Error::source(e),
+            Swap(e) => std::error::Error::source(e),
+            Sysctl(e) => std::error::Error::source(e),
+            UserDevice(e) => std::error::Error::source(e),
+
+            BootSource(e) => Some(e),
+            CloudInit(e) => Some(e),
+            ActivatedSocket(e) | BootOnDemand(e) | CreateConsole(e) | Monitor(e)
+            | SpawnVmmThread(e) => Some(e),
+            CreateEventManager(e) | EventLoop(e) => Some(e),
+            FsDevice(e) => Some(e),
+            IdlePolicy(e) => Some(e),
+            KernelBundle(e) => Some(e),
+            Krunfw(e) => Some(e),
+            RuntimeLimit(e) => Some(e),
+            StartMicrovm(e) => Some(e),
+            Stopped(e) => Some(e.as_ref()),
+            Tpm(e) => Some(e),
+            VmConfig(e) => Some(e),
+            VsockDevice(e) => Some(e),
+            InvalidMappedVolume(..) | UnknownKernelFlavor(_) | UnsupportedKrunfw(_) => None,
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Registers `kernel_bundle` as the flavor `name`, which the microVMs built from then on can pick
+/// with `KrunVmBuilder::kernel_flavor()`.
+pub fn register_kernel_flavor(name: &str, kernel_bundle: KernelBundle) -> Result<()> {
+    KERNEL_FLAVORS
+        .lock()
+        .unwrap()
+        .register(name, kernel_bundle)
+        .map_err(Error::KernelBundle)
+}
+
+/// Builder for a `KrunVm`, equivalent to a configuration context in the C API.
+///
+/// Configuration errors are deferred until `build()`, so calls can be chained.
+pub struct KrunVmBuilder {
+    ctx_cfg: ContextConfig,
+    error: Option<Error>,
+}
+
+impl KrunVmBuilder {
+    /// Creates a new builder with the kernel bundle provided by libkrunfw.
+    pub fn new() -> Result<Self> {
+        Ok(KrunVmBuilder {
+            ctx_cfg: new_ctx_config()?,
+            error: None,
+        })
+    }
+
+    pub(crate) fn from_ctx_config(ctx_cfg: ContextConfig) -> Self {
+        KrunVmBuilder {
+            ctx_cfg,
+            error: None,
+        }
+    }
+
+    /// Returns the configuration context, along with the first configuration error, if any.
+    pub(crate) fn into_ctx_config(self) -> (ContextConfig, Option<Error>) {
+        (self.ctx_cfg, self.error)
+    }
+
+    fn fail(mut self, error: Error) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+
+    /// Sets the number of vCPUs and the amount of RAM, in MiB, of the microVM.
+    pub fn vm_config(mut self, num_vcpus: u8, ram_mib: usize) -> Self {
+        match self
+            .ctx_cfg
+            .vmr
+            .set_vm_config(&new_vm_config(num_vcpus, ram_mib))
+        {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::VmConfig(e)),
+        }
+    }
+
+    /// Lays the vCPUs of the microVM out in `sockets`, with `cores_per_socket` cores of
+    /// `threads_per_core` threads each, as exposed to the guest. This sets the number of vCPUs.
+    pub fn cpu_topology(mut self, sockets: u8, cores_per_socket: u8, threads_per_core: u8) -> Self {
+        match self.ctx_cfg.vmr.set_vm_config(&new_cpu_topology_config(
+            sockets,
+            cores_per_socket,
+            threads_per_core,
+        )) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::VmConfig(e)),
+        }
+    }
+
+    /// Replaces the kernel bundle provided by libkrunfw with the `size` bytes of `file` at
+    /// `offset`, placed at `guest_addr`. The kernel is read, and checked against `sha256` if any,
+    /// as the microVM is built.
+    pub fn kernel_bundle_file(
+        mut self,
+        file: File,
+        offset: u64,
+        size: usize,
+        guest_addr: u64,
+        sha256: Option<[u8; 32]>,
+    ) -> Self {
+        match KernelBundle::from_file(file, offset, guest_addr, size, sha256) {
+            Ok(kernel_bundle) => {
+                self.ctx_cfg.vmr.set_kernel_bundle(kernel_bundle);
+                self
+            }
+            Err(e) => self.fail(Error::KernelBundle(e)),
+        }
+    }
+
+    /// Replaces the kernel bundle provided by libkrunfw with a copy of `data`, placed at
+    /// `guest_addr`, once checked against `sha256` if any.
+    pub fn kernel_bundle_buffer(
+        mut self,
+        data: &[u8],
+        guest_addr: u64,
+        sha256: Option<[u8; 32]>,
+    ) -> Self {
+        match KernelBundle::from_buffer(data, guest_addr, sha256) {
+            Ok(kernel_bundle) => {
+                self.ctx_cfg.vmr.set_kernel_bundle(kernel_bundle);
+                self
+            }
+            Err(e) => self.fail(Error::KernelBundle(e)),
+        }
+    }
+
+    /// Replaces the kernel bundle provided by libkrunfw with the one registered as the flavor
+    /// `name`, placed at the guest address it was registered with.
+    pub fn kernel_flavor(mut self, name: &str) -> Self {
+        let kernel_bundle = KERNEL_FLAVORS.lock().unwrap().get(name);
+        match kernel_bundle {
+            Some(kernel_bundle) => {
+                self.ctx_cfg.vmr.set_kernel_bundle(kernel_bundle);
+                self
+            }
+            None => self.fail(Error::UnknownKernelFlavor(name.to_string())),
+        }
+    }
+
+    /// Trusts `key`, an Ed25519 public key, to sign the kernel bundle. Once a key is trusted, the
+    /// microVM only boots a kernel signed by one of them.
+    pub fn trusted_kernel_key(mut self, key: [u8; PUBLIC_KEY_SIZE]) -> Self {
+        match self.ctx_cfg.vmr.kernel_signature.add_trusted_key(&key) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::KernelSignature(e)),
+        }
+    }
+
+    /// Sets the detached Ed25519 signature of the kernel bundle, checked against the trusted keys
+    /// before the kernel is placed in the guest memory.
+    pub fn kernel_signature(mut self, signature: [u8; SIGNATURE_SIZE]) -> Self {
+        self.ctx_cfg.vmr.kernel_signature.set_signature(signature);
+        self
+    }
+
+    /// Sets the host path to be used as root for the microVM.
+    pub fn root<P: AsRef<Path>>(mut self, root_path: P) -> Self {
+        let mapped_volumes = self.ctx_cfg.get_fs_cfg().and_then(|cfg| cfg.mapped_volumes);
+        self.ctx_cfg.set_fs_cfg(FsDeviceConfig {
+            fs_id: "/dev/root".to_string(),
+            shared_dir: root_path.as_ref().to_string_lossy().into_owned(),
+            mapped_volumes,
+        });
+        self
+    }
+
+    /// Sets the host paths to be mapped at the given guest paths. Only supported on macOS.
+    pub fn mapped_volumes(mut self, mapped_volumes: Vec<(PathBuf, PathBuf)>) -> Self {
+        for (host_vol, guest_vol) in mapped_volumes.iter() {
+            if !is_valid_mapped_volume(host_vol, guest_vol) {
+                return self.fail(Error::InvalidMappedVolume(
+                    host_vol.clone(),
+                    guest_vol.clone(),
+                ));
+            }
+        }
+
+        let fs_device_config = match self.ctx_cfg.get_fs_cfg() {
+            Some(fs_cfg) => FsDeviceConfig {
+                mapped_volumes: Some(mapped_volumes),
+                ..fs_cfg
+            },
+            None => FsDeviceConfig {
+                fs_id: String::new(),
+                shared_dir: String::new(),
+                mapped_volumes: Some(mapped_volumes),
+            },
+        };
+        self.ctx_cfg.set_fs_cfg(fs_device_config);
+        self
+    }
+
+    /// Sets the map of guest to host TCP ports.
+    pub fn port_map(mut self, port_map: HashMap<u16, u16>) -> Self {
+        self.ctx_cfg.set_port_map(port_map);
+        self
+    }
+
+    /// Binds the host ports of the port map on `listen_addr`, and boots the microVM only once a
+    /// connection arrives on them, or on any socket handed over, so `build` blocks until then.
+    /// The connections wait in the backlog of their socket until the workload listens.
+    pub fn boot_on_demand(mut self, listen_addr: IpAddr) -> Self {
+        self.ctx_cfg.set_boot_on_demand(listen_addr);
+        self
+    }
+
+    /// Hands over a listening socket, used by the vsock device when the guest listens on the same
+    /// TCP port or Unix path, or attached to the console if it is a Unix socket and `console` is
+    /// set, as with socket activation.
+    pub fn activated_socket(mut self, socket: ListenSocket, console: bool) -> Self {
+        match self.ctx_cfg.vmr.add_activated_socket(socket, console) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::ActivatedSocket(e)),
+        }
+    }
+
+    /// Sets the CID of the guest on the vsock device. With `claim`, or if `cid` is
+    /// `VMADDR_CID_ANY`, the CID is claimed right away so another microVM claiming it fails, and
+    /// `guest_cid` tells the one assigned.
+    pub fn vsock_guest_cid(mut self, cid: u32, claim: bool) -> Self {
+        // The previous claim, if any, is released first, so the CID can be claimed again.
+        self.ctx_cfg.vmr.vsock_cid = GuestCid::default();
+        let guest_cid = if claim || cid == VMADDR_CID_ANY {
+            GuestCid::claim(cid)
+        } else {
+            GuestCid::new(cid)
+        };
+        match guest_cid {
+            Ok(guest_cid) => {
+                self.ctx_cfg.vmr.vsock_cid = guest_cid;
+                self
+            }
+            Err(e) => self.fail(Error::VsockDevice(e)),
+        }
+    }
+
+    /// Returns the CID of the guest on the vsock device.
+    pub fn guest_cid(&self) -> u32 {
+        self.ctx_cfg.vmr.vsock_cid.cid()
+    }
+
+    /// Sets the rlimits to be configured in the guest, with format "RESOURCE=RLIM_CUR:RLIM_MAX",
+    /// where "RESOURCE" is a number or a name like "NOFILE".
+    pub fn rlimits(mut self, rlimits: &[&str]) -> Self {
+        match rlimits
+            .iter()
+            .map(|r| Rlimit::parse(r))
+            .collect::<result::Result<Vec<_>, _>>()
+        {
+            Ok(rlimits) => {
+                self.ctx_cfg.vmr.rlimits = rlimits;
+                self
+            }
+            Err(e) => self.fail(Error::Limits(e)),
+        }
+    }
+
+    /// Moves the workload to a cgroup with the given limits, with format "controller.file=value",
+    /// like "memory.max=512M" or "pids.max=1024".
+    pub fn cgroup_limits(mut self, limits: &[&str]) -> Self {
+        match limits
+            .iter()
+            .map(|l| CgroupLimit::parse(l))
+            .collect::<result::Result<Vec<_>, _>>()
+        {
+            Ok(limits) => {
+                self.ctx_cfg.vmr.cgroup_limits = limits;
+                self
+            }
+            Err(e) => self.fail(Error::Limits(e)),
+        }
+    }
+
+    /// Sets the working directory for the executable, relative to the root.
+    pub fn workdir(mut self, workdir: &str) -> Self {
+        self.ctx_cfg.set_workdir(workdir.to_string());
+        self
+    }
+
+    /// Sets the executable to be run inside the microVM, its arguments and its environment.
+    /// If `env` is `None`, the environment of the current process is used.
+    pub fn exec(mut self, exec_path: &str, args: &[&str], env: Option<&[&str]>) -> Self {
+        let quote = |items: &[&str]| {
+            items
+                .iter()
+                .map(|item| format!("\"{}\"", item))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        let env = match env {
+            Some(env) => quote(env),
+            None => env::vars()
+                .map(|(key, value)| format!(" {}=\"{}\"", key, value))
+                .collect(),
+        };
+
+        self.ctx_cfg.set_exec_path(exec_path.to_string());
+        self.ctx_cfg.set_args(quote(args));
+        self.ctx_cfg.set_env(env);
+        self
+    }
+
+    /// Executes the workload through a login shell with `login_shell`, so the profiles of the
+    /// guest set up its environment, and overrides its search path with `path` and its locale
+    /// with `locale`.
+    pub fn exec_env(mut self, login_shell: bool, path: Option<&str>, locale: Option<&str>) -> Self {
+        match ExecEnvConfig::new(login_shell, path, locale) {
+            Ok(exec_env) => {
+                self.ctx_cfg.vmr.exec_env = exec_env;
+                self
+            }
+            Err(e) => self.fail(Error::ExecEnv(e)),
+        }
+    }
+
+    /// Executes the workload as the user `uid`, with the primary group `gid` and the
+    /// supplementary `groups`, rather than root.
+    pub fn exec_user(mut self, uid: u32, gid: u32, groups: &[u32]) -> Self {
+        match ExecUser::new(uid, gid, groups.to_vec()) {
+            Ok(user) => {
+                self.ctx_cfg.vmr.exec_user = Some(user);
+                self
+            }
+            Err(e) => self.fail(Error::ExecEnv(e)),
+        }
+    }
+
+    /// Sets the privileges the workload is executed with, such as those of a `SecurityProfile`
+    /// with `ExecSecurity::from_profile()`.
+    pub fn exec_security(mut self, security: ExecSecurity) -> Self {
+        self.ctx_cfg.vmr.exec_security = security;
+        self
+    }
+
+    /// Drops all the capabilities of the workload but `capabilities`, such as "CAP_NET_RAW",
+    /// replacing those of the security profile.
+    pub fn exec_capabilities(mut self, capabilities: &[&str]) -> Self {
+        match capability_mask(capabilities) {
+            Ok(mask) => {
+                self.ctx_cfg.vmr.exec_security.capabilities = Some(mask);
+                self
+            }
+            Err(e) => self.fail(Error::ExecEnv(e)),
+        }
+    }
+
+    /// Sets the backend used by the console device.
+    pub fn console(mut self, backend: ConsoleBackend) -> Self {
+        self.ctx_cfg.vmr.set_console_backend(backend);
+        self
+    }
+
+    /// Processes the output of the console before it reaches its backend, for instance to strip
+    /// the ANSI escape sequences from logs.
+    pub fn console_output(mut self, config: ConsoleOutputConfig) -> Self {
+        self.ctx_cfg.vmr.set_console_output(config);
+        self
+    }
+
+    /// Sets how the terminal of the process is handed over to the console, for instance to run
+    /// it on the alternate screen, with the default backend.
+    pub fn console_terminal(mut self, config: TerminalConfig) -> Self {
+        self.ctx_cfg.vmr.set_console_terminal(config);
+        self
+    }
+
+    /// Sets where the console learns the terminal of the process has been resized from, SIGWINCH
+    /// by default.
+    pub fn console_resize(mut self, source: ResizeSource) -> Self {
+        self.ctx_cfg.vmr.set_console_resize(source);
+        self
+    }
+
+    /// Records the session of the console with `recorder`, in the asciicast v2 format. A clone of
+    /// the recorder can pause and resume the recording while the microVM runs.
+    pub fn console_recorder(mut self, recorder: SessionRecorder) -> Self {
+        self.ctx_cfg.vmr.console_recorder = Some(recorder);
+        self
+    }
+
+    /// Invokes `callback`, from the thread running the event loop, with each record the guest
+    /// sends to `/dev/log`. The records go through a console of their own, so they don't
+    /// interleave with the output of the interactive console.
+    pub fn on_log_record(mut self, callback: Box<dyn Fn(&[u8]) + Send + Sync>) -> Self {
+        self.ctx_cfg
+            .vmr
+            .set_log_channel(LogChannelConfig::new(Arc::from(callback)));
+        self
+    }
+
+    /// Shares `clipboard` with the agent of the guest, which finds the device it's shared through
+    /// in the `KRUN_CLIPBOARD` environment variable. A clone of the clipboard copies to the guest
+    /// while the microVM runs.
+    pub fn clipboard(mut self, clipboard: Clipboard) -> Self {
+        self.ctx_cfg.vmr.set_clipboard(clipboard);
+        self
+    }
+
+    /// Binds the character device of the host at `path`, such as a serial adapter, to a console of
+    /// the guest. The guest finds the device of the console named `name` in the `KRUN_SERIALS`
+    /// environment variable. A terminal is set to raw mode, and to `baud_rate` if any.
+    pub fn serial_passthrough(mut self, name: &str, path: &Path, baud_rate: Option<u32>) -> Self {
+        let result = SerialPassthroughConfig::new(name, path.to_path_buf(), baud_rate)
+            .and_then(|serial| self.ctx_cfg.vmr.add_serial_passthrough(serial));
+        match result {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::SerialPassthrough(e)),
+        }
+    }
+
+    /// Hands the accesses of the guest to the `len` bytes at `base`, in the address space of
+    /// `kind`, to `callback`, given the vCPU making each one and its offset in the region. The
+    /// MMIO regions must be within the range reserved for the devices, but clear of the window
+    /// of the MMIO devices.
+    pub fn user_device(
+        mut self,
+        kind: UserRegionKind,
+        base: u64,
+        len: u64,
+        callback: UserDeviceCallback,
+    ) -> Self {
+        let result = UserDeviceConfig::new(kind, base, len, callback)
+            .and_then(|user_device| self.ctx_cfg.vmr.add_user_device(user_device));
+        match result {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::UserDevice(e)),
+        }
+    }
+
+    /// Lets the host freeze the filesystems of the guest through `fsfreeze`, whose clone freezes
+    /// and thaws them while the microVM runs, around the snapshots of the disk images.
+    pub fn fsfreeze(mut self, fsfreeze: FsFreeze) -> Self {
+        self.ctx_cfg.vmr.set_fsfreeze(fsfreeze);
+        self
+    }
+
+    /// Lets the host query the network configuration of the guest through `guest_network`, whose
+    /// clone reports its addresses, routes and nameservers while the microVM runs.
+    pub fn guest_network(mut self, guest_network: GuestNetwork) -> Self {
+        self.ctx_cfg.vmr.set_guest_network(guest_network);
+        self
+    }
+
+    /// Sets a wall-clock limit on the runtime of the microVM. Once `max_runtime` has elapsed, the
+    /// guest is asked to shut down, and it's forcibly stopped after `grace_period`.
+    pub fn max_runtime(mut self, max_runtime: Duration, grace_period: Duration) -> Self {
+        match RuntimeLimitConfig::new(max_runtime, grace_period) {
+            Ok(runtime_limit) => {
+                self.ctx_cfg.vmr.set_runtime_limit(runtime_limit);
+                self
+            }
+            Err(e) => self.fail(Error::RuntimeLimit(e)),
+        }
+    }
+
+    /// Signals the exit of the microVM on `handle`, rather than exiting the process, `run()`
+    /// returning once the microVM has exited. Only supported on Linux.
+    pub fn exit_handle(mut self, handle: ExitHandle) -> Self {
+        self.ctx_cfg.vmr.set_exit_handle(handle);
+        self
+    }
+
+    /// Freezes the microVM once it has been idle for `idle_interval`, optionally asking the host
+    /// to reclaim its memory. Only supported on Linux.
+    pub fn idle_policy(mut self, idle_interval: Duration, reclaim_memory: bool) -> Self {
+        match IdlePolicyConfig::new(idle_interval, reclaim_memory) {
+            Ok(idle_policy) => {
+                self.ctx_cfg.vmr.set_idle_policy(idle_policy);
+                self
+            }
+            Err(e) => self.fail(Error::IdlePolicy(e)),
+        }
+    }
+
+    /// Profiles the vCPUs every `interval`, including the guest events counted by the PMU of the
+    /// host if `pmu`. The reports are retrieved through `profile_reports`. Only supported on
+    /// Linux.
+    pub fn profiling(mut self, interval: Duration, pmu: bool) -> Self {
+        match ProfilingConfig::new(interval, pmu) {
+            Ok(profiling) => {
+                self.ctx_cfg.vmr.set_profiling(profiling);
+                self
+            }
+            Err(e) => self.fail(Error::Profiling(e)),
+        }
+    }
+
+    /// Returns where the profiles of the vCPUs are published once the microVM runs.
+    pub fn profile_reports(&self) -> ProfileReports {
+        self.ctx_cfg.vmr.profile_reports.clone()
+    }
+
+    /// Returns where the timeline of the boot is recorded, as the microVM is built and boots.
+    pub fn boot_timeline(&self) -> BootTimeline {
+        self.ctx_cfg.vmr.boot_timeline.clone()
+    }
+
+    /// Tunes KVM to lower the tail latency of the guest, at the expense of the host. Only
+    /// supported on Linux.
+    pub fn kvm_tuning(mut self, kvm_tuning: KvmTuningConfig) -> Self {
+        match kvm_tuning.validate() {
+            Ok(()) => {
+                self.ctx_cfg.vmr.kvm_tuning = kvm_tuning;
+                self
+            }
+            Err(e) => self.fail(Error::KvmTuning(e)),
+        }
+    }
+
+    /// Trades host CPU time for the wakeup latency of the guest, see the `machine.low_latency`
+    /// option. Only has an effect on Linux.
+    pub fn latency_profile(mut self, latency_profile: LatencyProfile) -> Self {
+        self.ctx_cfg.vmr.latency_profile = latency_profile;
+        self
+    }
+
+    /// Sets an option by key, see `vmm::vmm_config::options`.
+    pub fn option(mut self, key: &str, value: &str) -> Self {
+        match self.ctx_cfg.vmr.set_option(key, value) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::InvalidOption(e)),
+        }
+    }
+
+    /// Records the descriptor chains processed by the virtio devices, and their responses, to
+    /// `out`.
+    pub fn virtio_recording(mut self, out: Box<dyn Write + Send>) -> Self {
+        self.ctx_cfg.vmr.set_virtio_recording(out);
+        self
+    }
+
+    /// Makes the guest trust the CA certificates of the host, so TLS works out of the box behind
+    /// proxies using custom CAs.
+    pub fn host_ca_certs(mut self) -> Self {
+        match self.ctx_cfg.vmr.set_ca_certs(None) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::CaCerts(e)),
+        }
+    }
+
+    /// Makes the guest trust the CA certificates of the PEM bundle at `bundle_path`, instead of
+    /// those of its root.
+    pub fn ca_certs<P: AsRef<Path>>(mut self, bundle_path: P) -> Self {
+        match self.ctx_cfg.vmr.set_ca_certs(Some(bundle_path.as_ref())) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::CaCerts(e)),
+        }
+    }
+
+    /// Attaches a TPM 2.0 to the microVM, emulated by swtpm listening on `socket_path`.
+    pub fn tpm<P: AsRef<Path>>(mut self, socket_path: P) -> Self {
+        match self.ctx_cfg.vmr.set_tpm(socket_path.as_ref()) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Tpm(e)),
+        }
+    }
+
+    /// Hands a secret to the guest, which reads it once into "/run/secrets/`name`", before it's
+    /// wiped from the memory of the VMM.
+    pub fn secret(mut self, name: &str, value: &[u8]) -> Self {
+        match self.ctx_cfg.vmr.add_secret(name, value) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Secrets(e)),
+        }
+    }
+
+    /// Sets how long the guest has to read the secrets, from the start of the microVM, after which
+    /// they're wiped. Defaults to 60 seconds.
+    pub fn secrets_ttl(mut self, ttl: Duration) -> Self {
+        self.ctx_cfg.vmr.set_secrets_ttl(ttl);
+        self
+    }
+
+    /// Provisions the guest through the cloud-init NoCloud datasource. The guest needs to run
+    /// cloud-init after the init process of libkrun, like when executing "/sbin/init".
+    pub fn cloud_init(mut self, config: CloudInitConfig) -> Self {
+        match self.ctx_cfg.vmr.set_cloud_init(&config) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::CloudInit(e)),
+        }
+    }
+
+    /// Loads the kernel module at `path`, with the space-separated `params`, before executing the
+    /// workload. Modules are loaded in the order they were added, so dependencies go first.
+    pub fn kernel_module<P: AsRef<Path>>(mut self, path: P, params: &str) -> Self {
+        match self.ctx_cfg.vmr.add_kernel_module(path.as_ref(), params) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::KernelModule(e)),
+        }
+    }
+
+    /// Sets the hostname of the guest, also overriding "/etc/hostname".
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        match self.ctx_cfg.vmr.set_hostname(hostname) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Etc(e)),
+        }
+    }
+
+    /// Sets the machine ID of the guest, 32 lowercase hexadecimal characters, by overriding
+    /// "/etc/machine-id". Clones of a root should each be given their own.
+    pub fn machine_id(mut self, machine_id: &str) -> Self {
+        match self.ctx_cfg.vmr.set_machine_id(machine_id) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Etc(e)),
+        }
+    }
+
+    /// Overrides "/etc/`name`" in the guest with `contents`, without modifying the root. Only
+    /// files already existing in the guest are overridden.
+    pub fn etc_file(mut self, name: &str, contents: &str) -> Self {
+        match self.ctx_cfg.vmr.set_etc_file(name, contents) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Etc(e)),
+        }
+    }
+
+    /// Shares `host_path` with the guest, whose init process mounts it as described by `mount`
+    /// before executing the workload. Unlike the mapped volumes, the share can be mounted anywhere,
+    /// even within another share.
+    pub fn share<P: AsRef<Path>>(mut self, host_path: P, mount: ShareMount) -> Self {
+        match self.ctx_cfg.vmr.add_share(host_path.as_ref(), mount) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Share(e)),
+        }
+    }
+
+    /// Makes the share tagged `tag`, or the root with "/dev/root", report a size of `capacity`
+    /// bytes to the guest rather than the one of the host file system. With `enforce`, the writes
+    /// beyond it fail with `ENOSPC`.
+    pub fn share_quota(mut self, tag: &str, capacity: u64, enforce: bool) -> Self {
+        match self
+            .ctx_cfg
+            .vmr
+            .set_share_quota(tag, ShareQuota { capacity, enforce })
+        {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Share(e)),
+        }
+    }
+
+    /// Makes the share tagged `tag`, or the root with "/dev/root", bypass the page cache of the
+    /// host with `direct_io`, and allocate the space its files are extended by when truncated
+    /// with `preallocate`.
+    pub fn share_io(mut self, tag: &str, direct_io: bool, preallocate: bool) -> Self {
+        self.ctx_cfg.vmr.set_share_io(
+            tag,
+            ShareIoConfig {
+                direct_io,
+                preallocate,
+            },
+        );
+        self
+    }
+
+    /// Sets how the share tagged `tag`, or the root with "/dev/root", handles the attempts of the
+    /// guest to reach outside of it through the symlinks of the host or "..".
+    pub fn share_escape_policy(mut self, tag: &str, policy: EscapePolicy) -> Self {
+        self.ctx_cfg.vmr.set_share_escape_policy(tag, policy);
+        self
+    }
+
+    /// Makes the host cache the metadata and the contents of the share tagged `tag`, or of the root
+    /// with "/dev/root". A clone of `cache` kept by the caller invalidates it while the microVM
+    /// runs.
+    pub fn share_cache(mut self, tag: &str, cache: ShareCache) -> Self {
+        self.ctx_cfg.vmr.set_share_cache(tag, cache);
+        self
+    }
+
+    /// Takes the read-only DAX mappings of the share tagged `tag`, or of the root with
+    /// "/dev/root", from the content-addressed store `dedup`, so the microVMs sharing it map the
+    /// identical chunks of their images from the same files of the host. Experimental, and only
+    /// meant for shares which don't change. Ignored on macOS, which lacks DAX.
+    pub fn share_dedup(mut self, tag: &str, dedup: DedupStore) -> Self {
+        self.ctx_cfg.vmr.set_share_dedup(tag, dedup);
+        self
+    }
+
+    /// Mounts a tmpfs of `tmp_size_mib` at `/tmp`, and sizes `/dev/shm` to `shm_size_mib`, in the
+    /// guest. Without a size, `/tmp` is left to the root, and `/dev/shm` is limited to half of the
+    /// RAM.
+    pub fn tmpfs_sizes(mut self, tmp_size_mib: Option<u32>, shm_size_mib: Option<u32>) -> Self {
+        self.ctx_cfg.vmr.tmpfs = TmpfsConfig {
+            tmp_size_mib,
+            shm_size_mib,
+        };
+        self
+    }
+
+    /// Gives the guest a swap of `size_mib`, which its init process formats and enables before
+    /// executing the workload. It's backed by a sparse file created in `dir`, or the temporary
+    /// directory of the host, and removed once the microVM stops.
+    pub fn swap(mut self, size_mib: u32, dir: Option<&Path>) -> Self {
+        match SwapConfig::new(size_mib, dir.map(Path::to_path_buf)) {
+            Ok(swap) => {
+                self.ctx_cfg.vmr.swap = Some(swap);
+                self
+            }
+            Err(e) => self.fail(Error::Swap(e)),
+        }
+    }
+
+    /// Restricts the virtio devices to a pool of `pool_size_mib` of guest memory, through which
+    /// the guest bounces their buffers, so the rest of its memory is never handed to them. Only
+    /// supported on Linux aarch64, and the balloon device is left out.
+    pub fn restricted_dma(mut self, pool_size_mib: u32) -> Self {
+        match RestrictedDmaConfig::new(pool_size_mib) {
+            Ok(restricted_dma) => {
+                self.ctx_cfg.vmr.restricted_dma = Some(restricted_dma);
+                self
+            }
+            Err(e) => self.fail(Error::RestrictedDma(e)),
+        }
+    }
+
+    /// Sets the kernel tunable `name`, such as "vm.overcommit_memory", to `value` in the guest
+    /// before executing the workload.
+    pub fn sysctl(mut self, name: &str, value: &str) -> Self {
+        match Sysctl::new(name, value) {
+            Ok(sysctl) => {
+                self.ctx_cfg.vmr.add_sysctl(sysctl);
+                self
+            }
+            Err(e) => self.fail(Error::Sysctl(e)),
+        }
+    }
+
+    /// Invokes `callback`, from a vCPU thread, once the init process of the guest reports it's
+    /// starting the workload.
+    pub fn on_workload_started(mut self, callback: Box<dyn FnOnce() + Send>) -> Self {
+        self.ctx_cfg
+            .vmr
+            .set_workload_started_callback(WorkloadStartedCallback::new(callback));
+        self
+    }
+
+    /// Sets the probe of the kind of `probe`, replacing the previous one, if any.
+    pub fn probe(mut self, probe: ProbeConfig) -> Self {
+        match self.ctx_cfg.vmr.set_probe(probe) {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Probe(e)),
+        }
+    }
+
+    /// Invokes `callback`, from the thread running the probe, when the state of a probe changes.
+    pub fn on_probe_state(
+        mut self,
+        callback: Box<dyn Fn(ProbeKind, ProbeState) + Send + Sync>,
+    ) -> Self {
+        self.ctx_cfg.vmr.probe_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Runs `args` in the guest alongside the workload as the service `name`, supervised
+    /// according to `restart`. The services are started in the order they were added, before the
+    /// workload.
+    pub fn service(mut self, name: &str, args: &[&str], restart: RestartPolicy) -> Self {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        match ServiceConfig::new(name, args, restart)
+            .and_then(|service| self.ctx_cfg.vmr.add_service(service))
+        {
+            Ok(()) => self,
+            Err(e) => self.fail(Error::Service(e)),
+        }
+    }
+
+    /// Invokes `callback`, from a vCPU thread, when the state of a service changes.
+    pub fn on_service_state(
+        mut self,
+        callback: Box<dyn Fn(&str, ServiceState) + Send + Sync>,
+    ) -> Self {
+        self.ctx_cfg.vmr.service_callback = Some(Arc::from(callback));
+        self
+    }
+
+    /// Sets what happens when a device panics. By default, the microVM is stopped.
+    pub fn device_panic_policy(mut self, policy: DevicePanicPolicy) -> Self {
+        self.ctx_cfg.vmr.device_panic.policy = policy;
+        self
+    }
+
+    /// Invokes `callback`, from the thread running the event loop, for each device panicking,
+    /// after any callback set by a previous call.
+    pub fn on_device_failure(
+        mut self,
+        callback: Box<dyn Fn(&DeviceFailure) + Send + Sync>,
+    ) -> Self {
+        let device_panic = &mut self.ctx_cfg.vmr.device_panic;
+        let callback: DeviceFailureCallback = match device_panic.callback.take() {
+            Some(previous) => Arc::new(move |failure: &DeviceFailure| {
+                previous(failure);
+                callback(failure);
+            }),
+            None => Arc::from(callback),
+        };
+        device_panic.callback = Some(callback);
+        self
+    }
+
+    /// Detects the guest kernel panicking or oopsing from the output of the console, for guests
+    /// with no other way of reporting it. A workload printing the same messages as the kernel is
+    /// taken for it.
+    pub fn scan_console_for_panics(mut self) -> Self {
+        self.ctx_cfg.vmr.guest_panic.scan_console = true;
+        self
+    }
+
+    /// Invokes `callback`, from the thread running the event loop, for each panic or oops of the
+    /// guest kernel, after any callback set by a previous call.
+    pub fn on_guest_panic(mut self, callback: Box<dyn Fn(&GuestPanic) + Send + Sync>) -> Self {
+        let guest_panic = &mut self.ctx_cfg.vmr.guest_panic;
+        let callback: GuestPanicCallback = match guest_panic.callback.take() {
+            Some(previous) => Arc::new(move |panic: &GuestPanic| {
+                previous(panic);
+                callback(panic);
+            }),
+            None => Arc::from(callback),
+        };
+        guest_panic.callback = Some(callback);
+        self
+    }
+
+    /// Serves a monitor on the Unix socket `path`, through which the running microVM can be
+    /// queried, paused and resumed, its balloon inflated and its disks resized. See
+    /// `krun::monitor` for the protocol.
+    pub fn monitor<P: AsRef<Path>>(mut self, path: P) -> Self {
+        match UnixListener::bind(path) {
+            Ok(listener) => {
+                self.ctx_cfg.set_monitor(listener);
+                self
+            }
+            Err(e) => self.fail(Error::Monitor(e)),
+        }
+    }
+
+    /// Creates the microVM and its devices. The vCPUs start running right away, but the devices
+    /// won't be serviced until `KrunVm::run` is called.
+    pub fn build(mut self) -> Result<KrunVm> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let mut event_manager = EventManager::new().map_err(Error::CreateEventManager)?;
+        let vmm = build_ctx_microvm(&mut self.ctx_cfg, &mut event_manager)?;
+
+        Ok(KrunVm {
+            vmm,
+            event_manager,
+            block_devices: self.ctx_cfg.vmr.block_devices.clone(),
+            vm_state: self.ctx_cfg.vmr.vm_state.clone(),
+        })
+    }
+}
+
+/// A running microVM.
+pub struct KrunVm {
+    vmm: Arc<Mutex<Vmm>>,
+    event_manager: EventManager,
+    block_devices: BlockDevices,
+    vm_state: VmStateHandle,
+}
+
+impl KrunVm {
+    /// Returns the VMM driving this microVM.
+    pub fn vmm(&self) -> &Arc<Mutex<Vmm>> {
+        &self.vmm
+    }
+
+    /// Returns where the microVM is in its lifecycle.
+    pub fn state(&self) -> VmState {
+        self.vm_state.get()
+    }
+
+    /// Grows the disk of the block device `id` to `size` bytes, and notifies the guest of its new
+    /// capacity. Returns the size exposed to the guest, rounded down to a whole sector.
+    pub fn resize_disk(&self, id: &str, size: u64) -> Result<u64> {
+        self.block_devices
+            .resize(id, size)
+            .map_err(Error::ResizeDisk)
+    }
+
+    /// Services the devices of the microVM. Unless it was given an exit handle, only returns on
+    /// error, as the VMM exits the process once the guest shuts down.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            self.event_manager.run().map_err(Error::EventLoop)?;
+            if self.vmm.lock().unwrap().has_exited() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Audit log recording security-relevant events as JSON lines.
+//!
+//! Each record is a single line holding a JSON object with, at least, the `timestamp` (in
+//! milliseconds since the UNIX epoch) and the `event` fields. The audit log is disabled until a
+//! destination is set with `AUDIT.init()`.
+
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::error;
+
+use super::buf_guard;
+
+/// Direction of a network flow, from the point of view of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowDirection {
+    /// The guest connected to a host or remote endpoint.
+    Outbound,
+    /// A host or remote endpoint connected to the guest.
+    Inbound,
+}
+
+impl FlowDirection {
+    fn as_str(self) -> &'static str {
+        match self {
+            FlowDirection::Outbound => "outbound",
+            FlowDirection::Inbound => "inbound",
+        }
+    }
+}
+
+/// A security-relevant event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditEvent<'a> {
+    /// A host directory has been shared with the guest.
+    ShareAttached {
+        /// The tag used by the guest to mount the share.
+        tag: &'a str,
+        /// The directory on the host.
+        host_path: &'a str,
+    },
+    /// A device has been attached to the microVM.
+    DeviceAttached {
+        /// The identifier of the device.
+        id: &'a str,
+        /// The virtio device type.
+        device_type: u32,
+    },
+    /// The guest has started listening for connections on a host address.
+    NetworkListenerOpened {
+        /// The transport protocol, "tcp" or "unix".
+        protocol: &'a str,
+        /// The address being listened on.
+        address: &'a str,
+    },
+    /// A connection between the guest and a host or remote endpoint has been established.
+    NetworkFlowOpened {
+        /// Who initiated the connection.
+        direction: FlowDirection,
+        /// The transport protocol, "tcp" or "unix".
+        protocol: &'a str,
+        /// The address of the endpoint outside the guest.
+        address: &'a str,
+    },
+    /// The guest attempted to reach outside of a share, through a symlink of the host or "..".
+    ShareEscapeAttempt {
+        /// The tag used by the guest to mount the share.
+        tag: &'a str,
+        /// The operation the guest attempted, such as "lookup" or "rename".
+        operation: &'a str,
+        /// The name passed by the guest, relative to the directory it's resolved in.
+        name: &'a str,
+        /// Whether the operation was denied, rather than only recorded.
+        denied: bool,
+    },
+    /// The microVM is shutting down.
+    Shutdown {
+        /// The exit code of the VMM.
+        exit_code: i32,
+        /// A description of why the microVM is shutting down.
+        reason: &'a str,
+    },
+}
+
+fn escape(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl<'a> AuditEvent<'a> {
+    /// Serializes the event as a single-line JSON object.
+    pub fn to_json(&self, timestamp_ms: u128) -> String {
+        let mut out = format!("{{\"timestamp\":{},\"event\":", timestamp_ms);
+        let mut field = |out: &mut String, name: &str, value: &str| {
+            let _ = write!(out, ",\"{}\":", name);
+            escape(out, value);
+        };
+
+        match self {
+            AuditEvent::ShareAttached { tag, host_path } => {
+                out.push_str("\"share_attached\"");
+                field(&mut out, "tag", tag);
+                field(&mut out, "host_path", host_path);
+            }
+            AuditEvent::DeviceAttached { id, device_type } => {
+                out.push_str("\"device_attached\"");
+                field(&mut out, "id", id);
+                let _ = write!(out, ",\"device_type\":{}", device_type);
+            }
+            AuditEvent::NetworkListenerOpened { protocol, address } => {
+                out.push_str("\"network_listener_opened\"");
+                field(&mut out, "protocol", protocol);
+                field(&mut out, "address", address);
+            }
+            AuditEvent::NetworkFlowOpened {
+                direction,
+                protocol,
+                address,
+            } => {
+                out.push_str("\"network_flow_opened\"");
+                field(&mut out, "direction", direction.as_str());
+                field(&mut out, "protocol", protocol);
+                field(&mut out, "address", address);
+            }
+            AuditEvent::ShareEscapeAttempt {
+                tag,
+                operation,
+                name,
+                denied,
+            } => {
+                out.push_str("\"share_escape_attempt\"");
+                field(&mut out, "tag", tag);
+                field(&mut out, "operation", operation);
+                field(&mut out, "name", name);
+                let _ = write!(out, ",\"denied\":{}", denied);
+            }
+            AuditEvent::Shutdown { exit_code, reason } => {
+                out.push_str("\"shutdown\"");
+                let _ = write!(out, ",\"exit_code\":{}", exit_code);
+                field(&mut out, "reason", reason);
+            }
+        }
+
+        out.push('}');
+        out
+    }
+}
+
+/// The destination of the audit records.
+pub struct AuditLog {
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl AuditLog {
+    fn new() -> Self {
+        AuditLog {
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Starts recording events to `writer`, replacing any previous destination.
+    pub fn init(&self, writer: Box<dyn Write + Send>) {
+        *buf_guard(&self.writer) = Some(writer);
+    }
+
+    /// Records `event`, if the audit log has been initialized.
+    pub fn record(&self, event: AuditEvent) {
+        let mut guard = buf_guard(&self.writer);
+        if let Some(writer) = guard.as_mut() {
+            let timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0);
+            let mut line = event.to_json(timestamp_ms);
+            line.push('\n');
+            // Write the record in a single call, so it can't get interleaved with the writes of
+            // other processes sharing the same file.
+            if let Err(e) = writer
+                .write_all(line.as_bytes())
+                .and_then(|_| writer.flush())
+            {
+                error!("Failed to write audit record: {}", e);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// The audit log of this process.
+    pub static ref AUDIT: AuditLog = AuditLog::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            AuditEvent::ShareAttached {
+                tag: "/dev/root",
+                host_path: "/srv/\"root\"\n",
+            }
+            .to_json(1),
+            r#"{"timestamp":1,"event":"share_attached","tag":"/dev/root","host_path":"/srv/\"root\"\n"}"#
+        );
+        assert_eq!(
+            AuditEvent::NetworkFlowOpened {
+                direction: FlowDirection::Inbound,
+                protocol: "tcp",
+                address: "127.0.0.1:8080",
+            }
+            .to_json(2),
+            r#"{"timestamp":2,"event":"network_flow_opened","direction":"inbound","protocol":"tcp","address":"127.0.0.1:8080"}"#
+        );
+        assert_eq!(
+            AuditEvent::Shutdown {
+                exit_code: 0,
+                reason: "guest\u{1}",
+            }
+            .to_json(3),
+            r#"{"timestamp":3,"event":"shutdown","exit_code":0,"reason":"guest\u0001"}"#
+        );
+        assert_eq!(
+            AuditEvent::ShareEscapeAttempt {
+                tag: "data",
+                operation: "lookup",
+                name: "../etc",
+                denied: true,
+            }
+            .to_json(4),
+            r#"{"timestamp":4,"event":"share_escape_attempt","tag":"data","operation":"lookup","name":"../etc","denied":true}"#
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let audit = AuditLog::new();
+        // Disabled until initialized.
+        audit.record(AuditEvent::DeviceAttached {
+            id: "hvc0",
+            device_type: 3,
+        });
+
+        let buf = SharedBuf::default();
+        audit.init(Box::new(buf.clone()));
+        audit.record(AuditEvent::DeviceAttached {
+            id: "hvc0",
+            device_type: 3,
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.ends_with("\"event\":\"device_attached\",\"id\":\"hvc0\",\"device_type\":3}\n"));
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exporters making `METRICS` available to external collectors in the OpenMetrics format, either
+//! over HTTP on a listening socket, or through a file rewritten periodically.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, warn};
+
+use super::metrics::METRICS;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+// The time a client has to send its request and to take the response. Connections are served one
+// at a time, so a stalled client would otherwise hold off all the others.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+// The time to wait before accepting connections again after failing to, so the exporter doesn't
+// spin while the process is out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn set_timeouts(fd: RawFd, timeout: Duration) -> io::Result<()> {
+    let tv = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    for option in &[libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+        // Safe because we pass the size of `tv`, which outlives the call.
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &tv as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Whether accept() failing with `err` means the listener is unusable, rather than the process
+// running short of resources or the connection being aborted, which may not last.
+fn is_fatal_accept_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EBADF) | Some(libc::EINVAL) | Some(libc::ENOTSOCK) | Some(libc::EOPNOTSUPP)
+    )
+}
+
+fn serve_connection(mut conn: File) -> io::Result<()> {
+    // We answer with the metrics regardless of the request, so just drain what fits in a single
+    // read to avoid resetting the connection before the client is done writing.
+    let mut request = [0u8; 1024];
+    conn.read(&mut request)?;
+
+    let mut body = Vec::new();
+    METRICS.write_openmetrics(&mut body)?;
+
+    write!(
+        conn,
+        "HTTP/1.0 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        OPENMETRICS_CONTENT_TYPE,
+        body.len()
+    )?;
+    conn.write_all(&body)
+}
+
+/// Spawns a thread answering every connection accepted on `listener_fd`, which must be a
+/// listening stream socket of any family, with an HTTP response carrying the current metrics.
+/// The thread takes ownership of `listener_fd`.
+pub fn serve_on_listener(listener_fd: RawFd) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("krun metrics".to_string())
+        .spawn(move || loop {
+            // Safe because we don't ask for the peer address.
+            let fd = unsafe { libc::accept(listener_fd, ptr::null_mut(), ptr::null_mut()) };
+            if fd < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if is_fatal_accept_error(&err) {
+                    error!("Metrics exporter failed to accept a connection: {}", err);
+                    break;
+                }
+                warn!("Metrics exporter failed to accept a connection: {}", err);
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+
+            // Safe because we own the fd just returned by accept().
+            let conn = unsafe { File::from_raw_fd(fd) };
+            if let Err(e) =
+                set_timeouts(fd, CONNECTION_TIMEOUT).and_then(|_| serve_connection(conn))
+            {
+                warn!("Metrics exporter failed to serve a connection: {}", e);
+            }
+        })
+}
+
+fn write_file(path: &PathBuf, tmp_path: &PathBuf) -> io::Result<()> {
+    let mut file = File::create(tmp_path)?;
+    METRICS.write_openmetrics(&mut file)?;
+    // Rename so readers never observe a partially written file.
+    fs::rename(tmp_path, path)
+}
+
+/// Spawns a thread rewriting `path` with the current metrics every `interval`.
+pub fn write_periodically(path: PathBuf, interval: Duration) -> io::Result<thread::JoinHandle<()>> {
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    thread::Builder::new()
+        .name("krun metrics".to_string())
+        .spawn(move || loop {
+            if let Err(e) = write_file(&path, &tmp_path) {
+                warn!("Metrics exporter failed to write {:?}: {}", path, e);
+            }
+            thread::sleep(interval);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_serve_on_listener() {
+        let dir = TempDir::new().unwrap();
+        let sock_path = dir.as_path().join("metrics.sock");
+        let listener = UnixListener::bind(&sock_path).unwrap();
+        serve_on_listener(listener.into_raw_fd()).unwrap();
+
+        let mut stream = UnixStream::connect(&sock_path).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.contains(OPENMETRICS_CONTENT_TYPE));
+        assert!(response.ends_with("# EOF\n"));
+
+        // A client that never sends its request doesn't keep the others from being answered.
+        let _stalled = UnixStream::connect(&sock_path).unwrap();
+        let mut stream = UnixStream::connect(&sock_path).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        for errno in &[
+            libc::EMFILE,
+            libc::ENFILE,
+            libc::ECONNABORTED,
+            libc::ENOBUFS,
+        ] {
+            assert!(!is_fatal_accept_error(&io::Error::from_raw_os_error(
+                *errno
+            )));
+        }
+        for errno in &[libc::EBADF, libc::EINVAL] {
+            assert!(is_fatal_accept_error(&io::Error::from_raw_os_error(*errno)));
+        }
+
+        // A socket that isn't listening can't be served, so the thread stops.
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        serve_on_listener(socket.into_raw_fd())
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_write_periodically() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("metrics.txt");
+        write_periodically(path.clone(), Duration::from_millis(10)).unwrap();
+
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(fs::read_to_string(&path).unwrap().ends_with("# EOF\n"));
+    }
+}
@@ -9,11 +9,16 @@ extern crate libc;
 extern crate log;
 extern crate utils;
 
+pub mod audit;
+pub mod exporter;
 mod logger;
+pub mod metrics;
 
+pub use audit::AUDIT;
 pub use log::Level::*;
 pub use log::*;
 pub use logger::{LoggerError, LOGGER};
+pub use metrics::METRICS;
 
 use std::io::Write;
 use std::sync::{Mutex, MutexGuard};
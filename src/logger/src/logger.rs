@@ -471,6 +471,8 @@ impl fmt::Display for LoggerError {
     }
 }
 
+impl std::error::Error for LoggerError {}
+
 /// Implements the "Log" trait from the externally used "log" crate.
 impl Log for Logger {
     // This is currently not used.
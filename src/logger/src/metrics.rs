@@ -0,0 +1,420 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Process-wide counters describing the activity of the microVM.
+//!
+//! The counters are plain atomics, so they can be updated from any thread without locking, and
+//! are exposed in the OpenMetrics text format through `Metrics::write_openmetrics`.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// A monotonically increasing counter that can be shared between threads.
+pub struct SharedIncMetric(AtomicUsize);
+
+impl SharedIncMetric {
+    /// Creates a counter starting at zero.
+    pub const fn new() -> Self {
+        SharedIncMetric(AtomicUsize::new(0))
+    }
+
+    /// Increments the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Increments the counter by `value`.
+    pub fn add(&self, value: usize) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of the counter.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The CPU time consumed by a set of threads, in microseconds. It's read from the CPU clocks of
+/// the threads when sampled, so it also accounts for the time they spend where none of our code
+/// runs, such as a halted vCPU polling for a wakeup in KVM.
+pub struct SharedCpuTimeMetric {
+    /// The CPU clocks of the threads still running.
+    clocks: Mutex<Vec<libc::clockid_t>>,
+    /// The CPU time of the threads which have ended.
+    ended: SharedIncMetric,
+}
+
+/// Keeps the calling thread tracked by a `SharedCpuTimeMetric` until dropped, which the thread
+/// must do itself, as its CPU clock is gone once it ends.
+#[cfg(target_os = "linux")]
+pub struct CpuTimeGuard<'a> {
+    metric: &'a SharedCpuTimeMetric,
+    clock: libc::clockid_t,
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CpuTimeGuard<'_> {
+    fn drop(&mut self) {
+        let mut clocks = self.metric.clocks.lock().unwrap();
+        clocks.retain(|clock| *clock != self.clock);
+        self.metric.ended.add(clock_us(self.clock).unwrap_or(0));
+    }
+}
+
+fn clock_us(clock: libc::clockid_t) -> Option<usize> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because we pass a valid pointer, and an invalid clock is just refused.
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as usize * 1_000_000 + ts.tv_nsec as usize / 1_000)
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    // Not exposed by the libc crate version we use.
+    fn pthread_getcpuclockid(
+        thread: libc::pthread_t,
+        clock_id: *mut libc::clockid_t,
+    ) -> libc::c_int;
+}
+
+impl SharedCpuTimeMetric {
+    /// Creates a metric tracking no thread.
+    pub const fn new() -> Self {
+        SharedCpuTimeMetric {
+            clocks: Mutex::new(Vec::new()),
+            ended: SharedIncMetric::new(),
+        }
+    }
+
+    /// Adds the CPU time of the calling thread to the metric, from its start and until the
+    /// returned guard is dropped.
+    #[cfg(target_os = "linux")]
+    pub fn track_current_thread(&self) -> io::Result<CpuTimeGuard<'_>> {
+        let mut clock: libc::clockid_t = 0;
+        // Safe because the thread is the calling one, and we pass a valid pointer.
+        let ret = unsafe { pthread_getcpuclockid(libc::pthread_self(), &mut clock) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        self.clocks.lock().unwrap().push(clock);
+        Ok(CpuTimeGuard {
+            metric: self,
+            clock,
+        })
+    }
+
+    /// Returns the CPU time consumed so far by the threads tracked, in microseconds.
+    pub fn count(&self) -> usize {
+        // The threads can't end, releasing their clocks, while we hold the lock.
+        let clocks = self.clocks.lock().unwrap();
+        self.ended.count()
+            + clocks
+                .iter()
+                .filter_map(|clock| clock_us(*clock))
+                .sum::<usize>()
+    }
+}
+
+/// Metrics of the console device.
+pub struct ConsoleMetrics {
+    /// Bytes delivered to the guest.
+    pub rx_bytes: SharedIncMetric,
+    /// Bytes written by the guest.
+    pub tx_bytes: SharedIncMetric,
+}
+
+/// Metrics of the crypto device.
+pub struct CryptoMetrics {
+    /// Cipher requests completed on behalf of the guest.
+    pub requests: SharedIncMetric,
+    /// Bytes encrypted or decrypted for the guest.
+    pub bytes: SharedIncMetric,
+    /// Requests, on either queue, which failed.
+    pub failures: SharedIncMetric,
+}
+
+/// Metrics of the fs device.
+pub struct FsMetrics {
+    /// Requests processed on behalf of the guest.
+    pub requests: SharedIncMetric,
+}
+
+/// Metrics of the virtqueues of all the devices.
+pub struct QueueMetrics {
+    /// Times a queue rose to its high watermark.
+    pub high_watermarks: SharedIncMetric,
+    /// Times a queue fell back to its low watermark.
+    pub low_watermarks: SharedIncMetric,
+    /// Used buffer notifications sent to the guest.
+    pub notifications: SharedIncMetric,
+    /// Used buffer notifications skipped as the guest asked, which would have been sent
+    /// otherwise.
+    pub suppressed_notifications: SharedIncMetric,
+}
+
+/// Metrics of the vCPUs.
+pub struct VcpuMetrics {
+    /// Exits caused by PIO reads.
+    pub exit_io_in: SharedIncMetric,
+    /// Exits caused by PIO writes.
+    pub exit_io_out: SharedIncMetric,
+    /// Exits caused by MMIO reads.
+    pub exit_mmio_read: SharedIncMetric,
+    /// Exits caused by MMIO writes.
+    pub exit_mmio_write: SharedIncMetric,
+    /// Exits that couldn't be handled.
+    pub failures: SharedIncMetric,
+    /// CPU time consumed by the threads of the vCPUs, in microseconds.
+    pub cpu_time_us: SharedCpuTimeMetric,
+}
+
+/// Metrics of the vsock device.
+pub struct VsockMetrics {
+    /// Packets delivered to the guest.
+    pub rx_packets: SharedIncMetric,
+    /// Packets sent by the guest.
+    pub tx_packets: SharedIncMetric,
+}
+
+/// All the metrics of the process.
+pub struct Metrics {
+    /// Console device metrics.
+    pub console: ConsoleMetrics,
+    /// Crypto device metrics.
+    pub crypto: CryptoMetrics,
+    /// Fs device metrics.
+    pub fs: FsMetrics,
+    /// Virtqueue metrics.
+    pub queue: QueueMetrics,
+    /// vCPU metrics.
+    pub vcpu: VcpuMetrics,
+    /// Vsock device metrics.
+    pub vsock: VsockMetrics,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            console: ConsoleMetrics {
+                rx_bytes: SharedIncMetric::new(),
+                tx_bytes: SharedIncMetric::new(),
+            },
+            crypto: CryptoMetrics {
+                requests: SharedIncMetric::new(),
+                bytes: SharedIncMetric::new(),
+                failures: SharedIncMetric::new(),
+            },
+            fs: FsMetrics {
+                requests: SharedIncMetric::new(),
+            },
+            queue: QueueMetrics {
+                high_watermarks: SharedIncMetric::new(),
+                low_watermarks: SharedIncMetric::new(),
+                notifications: SharedIncMetric::new(),
+                suppressed_notifications: SharedIncMetric::new(),
+            },
+            vcpu: VcpuMetrics {
+                exit_io_in: SharedIncMetric::new(),
+                exit_io_out: SharedIncMetric::new(),
+                exit_mmio_read: SharedIncMetric::new(),
+                exit_mmio_write: SharedIncMetric::new(),
+                failures: SharedIncMetric::new(),
+                cpu_time_us: SharedCpuTimeMetric::new(),
+            },
+            vsock: VsockMetrics {
+                rx_packets: SharedIncMetric::new(),
+                tx_packets: SharedIncMetric::new(),
+            },
+        }
+    }
+
+    fn counters(&self) -> [(&'static str, &'static str, usize); 18] {
+        [
+            (
+                "krun_console_rx_bytes",
+                "Bytes delivered to the guest console.",
+                self.console.rx_bytes.count(),
+            ),
+            (
+                "krun_console_tx_bytes",
+                "Bytes written by the guest to its console.",
+                self.console.tx_bytes.count(),
+            ),
+            (
+                "krun_crypto_requests",
+                "Cipher requests completed by the crypto device.",
+                self.crypto.requests.count(),
+            ),
+            (
+                "krun_crypto_bytes",
+                "Bytes encrypted or decrypted by the crypto device.",
+                self.crypto.bytes.count(),
+            ),
+            (
+                "krun_crypto_failures",
+                "Requests to the crypto device which failed.",
+                self.crypto.failures.count(),
+            ),
+            (
+                "krun_fs_requests",
+                "Requests processed by the fs device.",
+                self.fs.requests.count(),
+            ),
+            (
+                "krun_queue_high_watermarks",
+                "Times a virtqueue rose to its high watermark.",
+                self.queue.high_watermarks.count(),
+            ),
+            (
+                "krun_queue_low_watermarks",
+                "Times a virtqueue fell back to its low watermark.",
+                self.queue.low_watermarks.count(),
+            ),
+            (
+                "krun_queue_notifications",
+                "Used buffer notifications sent to the guest.",
+                self.queue.notifications.count(),
+            ),
+            (
+                "krun_queue_suppressed_notifications",
+                "Used buffer notifications the guest asked to skip.",
+                self.queue.suppressed_notifications.count(),
+            ),
+            (
+                "krun_vcpu_exit_io_in",
+                "vCPU exits caused by PIO reads.",
+                self.vcpu.exit_io_in.count(),
+            ),
+            (
+                "krun_vcpu_exit_io_out",
+                "vCPU exits caused by PIO writes.",
+                self.vcpu.exit_io_out.count(),
+            ),
+            (
+                "krun_vcpu_exit_mmio_read",
+                "vCPU exits caused by MMIO reads.",
+                self.vcpu.exit_mmio_read.count(),
+            ),
+            (
+                "krun_vcpu_exit_mmio_write",
+                "vCPU exits caused by MMIO writes.",
+                self.vcpu.exit_mmio_write.count(),
+            ),
+            (
+                "krun_vcpu_failures",
+                "vCPU exits that couldn't be handled.",
+                self.vcpu.failures.count(),
+            ),
+            (
+                "krun_vcpu_cpu_time_us",
+                "CPU time consumed by the vCPU threads, in microseconds. Idle vCPUs keep consuming \
+                 it with the low-latency profile, as they poll for a wakeup instead of halting.",
+                self.vcpu.cpu_time_us.count(),
+            ),
+            (
+                "krun_vsock_rx_packets",
+                "Vsock packets delivered to the guest.",
+                self.vsock.rx_packets.count(),
+            ),
+            (
+                "krun_vsock_tx_packets",
+                "Vsock packets sent by the guest.",
+                self.vsock.tx_packets.count(),
+            ),
+        ]
+    }
+
+    /// Returns the sum of the counters tracking device activity. It changes whenever data moves
+    /// between the guest and the host, so it can be sampled to tell if the guest is busy.
+    pub fn device_activity(&self) -> usize {
+        self.console.rx_bytes.count()
+            + self.console.tx_bytes.count()
+            + self.crypto.requests.count()
+            + self.fs.requests.count()
+            + self.vsock.rx_packets.count()
+            + self.vsock.tx_packets.count()
+    }
+
+    /// Writes all the metrics in the OpenMetrics text format.
+    pub fn write_openmetrics<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (name, help, count) in self.counters().iter() {
+            writeln!(out, "# TYPE {} counter", name)?;
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "{}_total {}", name, count)?;
+        }
+        writeln!(out, "# EOF")
+    }
+}
+
+/// The metrics of this process.
+pub static METRICS: Metrics = Metrics::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_inc_metric() {
+        let metric = SharedIncMetric::new();
+        metric.inc();
+        metric.add(41);
+        assert_eq!(metric.count(), 42);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shared_cpu_time_metric() {
+        let metric = SharedCpuTimeMetric::new();
+        assert_eq!(metric.count(), 0);
+
+        let running = std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = metric.track_current_thread().unwrap();
+                // Burn some CPU time, read while the thread is running.
+                let start = std::time::Instant::now();
+                while start.elapsed() < std::time::Duration::from_millis(20) {}
+                metric.count()
+            })
+            .join()
+            .unwrap()
+        });
+        assert!(running > 0);
+        // The time of the thread is kept once it has ended.
+        let ended = metric.count();
+        assert!(ended >= running);
+        assert_eq!(metric.count(), ended);
+    }
+
+    #[test]
+    fn test_write_openmetrics() {
+        let metrics = Metrics::new();
+        metrics.console.tx_bytes.add(10);
+
+        let mut out = Vec::new();
+        metrics.write_openmetrics(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains(
+            "# TYPE krun_console_tx_bytes counter\n\
+             # HELP krun_console_tx_bytes Bytes written by the guest to its console.\n\
+             krun_console_tx_bytes_total 10\n"
+        ));
+        assert!(out.contains("krun_vsock_rx_packets_total 0\n"));
+        assert!(out.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_device_activity() {
+        let metrics = Metrics::new();
+        metrics.fs.requests.inc();
+        metrics.vsock.tx_packets.add(2);
+        metrics.vcpu.exit_mmio_read.add(10);
+        assert_eq!(metrics.device_activity(), 3);
+    }
+}
@@ -1,11 +1,13 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::any::Any;
 use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
 
 use utils::epoll::{self, Epoll, EpollEvent};
 
@@ -22,6 +24,8 @@ pub enum Error {
     AlreadyExists(Pollable),
     /// The specified pollable is not registered.
     NotFound(Pollable),
+    /// A subscriber panicked, and the panic policy is to stop.
+    SubscriberPanicked(SubscriberPanic),
 }
 
 impl std::fmt::Debug for Error {
@@ -41,10 +45,70 @@ impl std::fmt::Debug for Error {
                 "A handler for the specified pollable {} was not found.",
                 pollable
             ),
+            SubscriberPanicked(panic) => write!(
+                f,
+                "The subscriber {} panicked: {}",
+                panic.subscriber, panic.message
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+
+        match self {
+            EpollCreate(_) => write!(f, "Unable to create epoll fd"),
+            Poll(_) => write!(f, "Error during epoll call"),
+            AlreadyExists(_) | NotFound(_) | SubscriberPanicked(_) => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+
+        match self {
+            EpollCreate(err) | Poll(err) => Some(err),
+            AlreadyExists(_) | NotFound(_) | SubscriberPanicked(_) => None,
         }
     }
 }
 
+/// A subscriber panicking while processing an event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubscriberPanic {
+    /// The name of the subscriber.
+    pub subscriber: String,
+    /// The message the subscriber panicked with.
+    pub message: String,
+}
+
+/// What the `EventManager` does with a subscriber that panicked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PanicPolicy {
+    /// Stop processing events, returning `Error::SubscriberPanicked` from `run()`.
+    Stop,
+    /// Unregister all the pollables of the subscriber, and keep processing the other events.
+    Detach,
+}
+
+/// Decides what to do with each subscriber panicking.
+pub type PanicHandler = Box<dyn FnMut(&SubscriberPanic) -> PanicPolicy>;
+
+/// Returns the message of a panic, from the payload `std::panic::catch_unwind()` returned.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
 /// A trait to express the ability to respond to I/O event readiness
 /// using callbacks.
 pub trait Subscriber {
@@ -61,6 +125,11 @@ pub trait Subscriber {
 
     /// Returns a list of `EpollEvent` that this subscriber is interested in.
     fn interest_list(&self) -> Vec<EpollEvent>;
+
+    /// Returns the name of the subscriber, reported if it panics.
+    fn name(&self) -> String {
+        "unnamed".to_string()
+    }
 }
 
 /// Manages I/O notifications using epoll mechanism.
@@ -68,6 +137,7 @@ pub struct EventManager {
     epoll: Epoll,
     subscribers: HashMap<RawFd, Arc<Mutex<dyn Subscriber>>>,
     ready_events: Vec<EpollEvent>,
+    panic_handler: Option<PanicHandler>,
 }
 
 impl AsRawFd for EventManager {
@@ -90,9 +160,16 @@ impl EventManager {
             // We preallocate memory for this buffer in order to not repeat this
             // operation every time `run()` loop is executed.
             ready_events: vec![epoll::EpollEvent::default(); EventManager::EVENT_BUFFER_SIZE],
+            panic_handler: None,
         })
     }
 
+    /// Sets the handler deciding what to do with the subscribers panicking while processing an
+    /// event. Without one, the `EventManager` stops.
+    pub fn set_panic_handler(&mut self, handler: PanicHandler) {
+        self.panic_handler = Some(handler);
+    }
+
     /// Returns a clone of the subscriber associated with the `fd`.
     pub fn subscriber(&self, fd: Pollable) -> Result<Arc<Mutex<dyn Subscriber>>> {
         self.subscribers
@@ -170,6 +247,11 @@ impl EventManager {
         Ok(())
     }
 
+    /// Returns the pollables registered.
+    pub fn pollables(&self) -> Vec<Pollable> {
+        self.subscribers.keys().copied().collect()
+    }
+
     /// Check if a file descriptor is pollable
     pub fn is_pollable(&mut self, pollable: Pollable) -> bool {
         self.epoll
@@ -207,27 +289,68 @@ impl EventManager {
             Err(e) if e.raw_os_error() == Some(libc::EINTR) => 0,
             Err(e) => return Err(Error::Poll(e)),
         };
-        self.dispatch_events(event_count);
+        self.dispatch_events(event_count)?;
 
         Ok(event_count)
     }
 
-    fn dispatch_events(&mut self, event_count: usize) {
+    fn dispatch_events(&mut self, event_count: usize) -> Result<()> {
         // Use the temporary, pre-allocated buffer to check ready events.
         for ev_index in 0..event_count {
             let event = &self.ready_events[ev_index].clone();
             let pollable = event.fd();
 
-            if self.subscribers.contains_key(&pollable) {
-                self.subscribers
-                    .get_mut(&pollable)
-                    .unwrap()
-                    .clone()
-                    .lock()
-                    .unwrap()
-                    .process(&event, self);
+            // The subscriber may have been detached while processing a previous event.
+            let subscriber = match self.subscribers.get(&pollable) {
+                Some(subscriber) => subscriber.clone(),
+                None => continue,
+            };
+            // A panic is caught here rather than unwinding into the embedder, which aborts the
+            // process when called through the C API.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                subscriber.lock().unwrap().process(&event, self)
+            }));
+            if let Err(payload) = result {
+                self.handle_panic(subscriber, payload)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_panic(
+        &mut self,
+        subscriber: Arc<Mutex<dyn Subscriber>>,
+        payload: Box<dyn Any + Send>,
+    ) -> Result<()> {
+        // The lock was held by the subscriber when it panicked, so it's poisoned.
+        let name = subscriber
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .name();
+        let panic = SubscriberPanic {
+            subscriber: name,
+            message: panic_message(payload.as_ref()),
+        };
+
+        let policy = match self.panic_handler.as_mut() {
+            Some(handler) => handler(&panic),
+            None => PanicPolicy::Stop,
+        };
+        match policy {
+            PanicPolicy::Stop => Err(Error::SubscriberPanicked(panic)),
+            PanicPolicy::Detach => {
+                let pollables: Vec<Pollable> = self
+                    .subscribers
+                    .iter()
+                    .filter(|(_, s)| Arc::ptr_eq(s, &subscriber))
+                    .map(|(pollable, _)| *pollable)
+                    .collect();
+                for pollable in pollables {
+                    self.unregister(pollable)?;
+                }
+                Ok(())
             }
-            // TODO: Should we log an error in case the subscriber does not exist?
         }
     }
 }
@@ -519,5 +642,62 @@ mod tests {
         let dummy_fd = dummy_subscriber.lock().unwrap().event_fd_1.as_raw_fd();
         assert!(event_manager.subscriber(dummy_fd).is_ok());
         assert!(event_manager.subscriber(-1).is_err());
+        assert_eq!(event_manager.pollables(), vec![dummy_fd]);
+    }
+
+    struct PanickingSubscriber {
+        event_fd: EventFd,
+    }
+
+    impl Subscriber for PanickingSubscriber {
+        fn process(&mut self, _event: &EpollEvent, _event_manager: &mut EventManager) {
+            panic!("bad descriptor");
+        }
+
+        fn interest_list(&self) -> Vec<EpollEvent> {
+            vec![EpollEvent::new(
+                EventSet::IN,
+                self.event_fd.as_raw_fd() as u64,
+            )]
+        }
+
+        fn name(&self) -> String {
+            "panicking".to_string()
+        }
+    }
+
+    #[test]
+    fn test_subscriber_panic() {
+        let mut event_manager = EventManager::new().unwrap();
+        let subscriber = Arc::new(Mutex::new(PanickingSubscriber {
+            event_fd: EventFd::new(0).unwrap(),
+        }));
+        event_manager.add_subscriber(subscriber.clone()).unwrap();
+        let event_fd = subscriber.lock().unwrap().event_fd.as_raw_fd();
+        subscriber.lock().unwrap().event_fd.write(1).unwrap();
+
+        // Without a handler, the panic stops the event manager.
+        match event_manager.run_with_timeout(50) {
+            Err(Error::SubscriberPanicked(panic)) => assert_eq!(
+                panic,
+                SubscriberPanic {
+                    subscriber: "panicking".to_string(),
+                    message: "bad descriptor".to_string(),
+                }
+            ),
+            _ => unreachable!(),
+        }
+
+        // Detaching the subscriber leaves the event manager running.
+        let panics = Arc::new(Mutex::new(0));
+        let handler_panics = panics.clone();
+        event_manager.set_panic_handler(Box::new(move |_| {
+            *handler_panics.lock().unwrap() += 1;
+            PanicPolicy::Detach
+        }));
+        assert_eq!(event_manager.run_with_timeout(50).unwrap(), 1);
+        assert_eq!(*panics.lock().unwrap(), 1);
+        assert!(event_manager.subscriber(event_fd).is_err());
+        assert_eq!(event_manager.run_with_timeout(10).unwrap(), 0);
     }
 }
@@ -0,0 +1,119 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of Ed25519 signatures, as specified in RFC 8032 and computed by the `ed25519-dalek`
+//! crate, for checking the images handed over by the embedder were signed by a key it trusts.
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+/// The size of a public key, in bytes.
+pub const PUBLIC_KEY_SIZE: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+/// The size of a signature, in bytes.
+pub const SIGNATURE_SIZE: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+/// An Ed25519 public key, known to encode a point of the curve that isn't of small order.
+#[derive(Clone, Debug)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    /// Returns the key encoded in `bytes`, if it's a point of the curve and not a weak key any
+    /// signature would match.
+    pub fn from_bytes(bytes: &[u8; PUBLIC_KEY_SIZE]) -> Option<Self> {
+        VerifyingKey::from_bytes(bytes)
+            .ok()
+            .filter(|key| !key.is_weak())
+            .map(PublicKey)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        self.0.as_bytes()
+    }
+
+    /// Checks `signature` is the signature of `message` under this key. Signatures whose scalar
+    /// isn't reduced are rejected, so they can't be made malleable.
+    pub fn verify(&self, message: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+        self.0
+            .verify_strict(message, &Signature::from_bytes(signature))
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex<T: Default + AsMut<[u8]>>(hex: &str) -> T {
+        let mut bytes = T::default();
+        for (byte, i) in bytes.as_mut().iter_mut().zip((0..hex.len()).step_by(2)) {
+            *byte = u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn signature(hex: &str) -> [u8; SIGNATURE_SIZE] {
+        let mut sig = [0u8; SIGNATURE_SIZE];
+        sig[..32].copy_from_slice(&unhex::<[u8; 32]>(&hex[..64]));
+        sig[32..].copy_from_slice(&unhex::<[u8; 32]>(&hex[64..]));
+        sig
+    }
+
+    #[test]
+    fn test_verify() {
+        // The test vectors 1 and 2 of RFC 8032.
+        let key = PublicKey::from_bytes(&unhex(
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+        ))
+        .unwrap();
+        let sig = signature(
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555\
+             fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        );
+        assert!(key.verify(b"", &sig));
+        assert!(!key.verify(b"\x00", &sig));
+
+        let key = PublicKey::from_bytes(&unhex(
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+        ))
+        .unwrap();
+        let mut sig = signature(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        );
+        assert!(key.verify(b"\x72", &sig));
+        assert!(!key.verify(b"\x73", &sig));
+        sig[0] ^= 1;
+        assert!(!key.verify(b"\x72", &sig));
+
+        // Adding the order L of the base point to S gives the same point, but the signature isn't
+        // canonical anymore.
+        let mut sig = signature(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        );
+        let l: [u8; 32] = unhex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+        let mut carry = 0u16;
+        for (byte, l) in sig[32..].iter_mut().zip(l.iter()) {
+            let sum = u16::from(*byte) + u16::from(*l) + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!key.verify(b"\x72", &sig));
+    }
+
+    #[test]
+    fn test_public_key() {
+        // y = 2 isn't the ordinate of any point of the curve.
+        let mut bytes = [0u8; PUBLIC_KEY_SIZE];
+        bytes[0] = 2;
+        assert!(PublicKey::from_bytes(&bytes).is_none());
+        // The neutral element, here in a non-canonical encoding of y = 1, is of small order.
+        let mut bytes = [0xffu8; PUBLIC_KEY_SIZE];
+        bytes[0] = 0xee;
+        bytes[31] = 0x7f;
+        assert!(PublicKey::from_bytes(&bytes).is_none());
+        // The base point.
+        let mut bytes = [0x66u8; PUBLIC_KEY_SIZE];
+        bytes[0] = 0x58;
+        assert!(PublicKey::from_bytes(&bytes).is_some());
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for the errors of the crates, which describe only their own failure in `Display`, and
+//! chain to the failure causing it through `source()`.
+
+use std::error::Error;
+use std::fmt;
+
+/// Displays an error followed by the chain of its sources, separated by colons, as in
+/// "Cannot initialize a MMIO Fs Device: failed to perform bus operation: New device overlaps".
+pub struct ErrorChain<'a>(pub &'a (dyn Error + 'static));
+
+impl fmt::Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {}", err)?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[derive(Debug)]
+    struct Outer(io::Error);
+
+    impl fmt::Display for Outer {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Cannot open the file")
+        }
+    }
+
+    impl Error for Outer {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_error_chain() {
+        let err = Outer(io::Error::new(io::ErrorKind::Other, "no space left"));
+        assert_eq!(
+            ErrorChain(&err).to_string(),
+            "Cannot open the file: no space left"
+        );
+    }
+}
@@ -10,17 +10,24 @@ pub use vmm_sys_util::{eventfd, ioctl};
 
 pub mod arg_parser;
 pub mod byte_order;
+pub mod ed25519;
+pub mod error;
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "linux")]
 pub use linux::epoll;
+#[cfg(target_os = "linux")]
+pub use linux::signalfd;
 #[cfg(target_os = "macos")]
 pub mod macos;
 #[cfg(target_os = "macos")]
 pub use macos::epoll;
 #[cfg(target_os = "macos")]
 pub use macos::eventfd;
+#[cfg(target_os = "macos")]
+pub use macos::signalfd;
 pub mod rand;
+pub mod sha256;
 #[cfg(target_os = "linux")]
 pub mod signal;
 pub mod sm;
@@ -0,0 +1,97 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signals received through a file descriptor, polled along the other events, rather than caught
+//! by a handler installed for the whole process.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{io, mem};
+
+use libc::c_int;
+
+/// A signalfd receiving a set of signals.
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    /// Creates a signalfd receiving `signals`. The signals are blocked in the calling thread, and
+    /// so in the threads it starts afterwards, as a signal is only queued for the signalfd if it
+    /// isn't delivered. A signal sent to the process may still be delivered to a thread of the
+    /// embedder not blocking it.
+    pub fn new(signals: &[c_int]) -> io::Result<SignalFd> {
+        // Safe because the set is initialized by sigemptyset(), and we check the return values.
+        unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            for signal in signals {
+                if libc::sigaddset(&mut mask, *signal) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(SignalFd { fd })
+        }
+    }
+
+    /// Returns the signals received since the last call, without blocking.
+    pub fn read(&self) -> io::Result<Vec<c_int>> {
+        let mut signals = Vec::new();
+        loop {
+            // Safe because signalfd_siginfo is plain data, and we pass its size.
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let ret = unsafe {
+                libc::read(
+                    self.fd,
+                    &mut info as *mut _ as *mut libc::c_void,
+                    mem::size_of::<libc::signalfd_siginfo>(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(signals);
+                }
+                return Err(err);
+            }
+            signals.push(info.ssi_signo as c_int);
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        // Safe because we own the fd.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signalfd() {
+        let signalfd = SignalFd::new(&[libc::SIGWINCH]).unwrap();
+        assert!(signalfd.read().unwrap().is_empty());
+
+        // Blocked, the signal is left for the signalfd.
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGWINCH) };
+        assert_eq!(signalfd.read().unwrap(), vec![libc::SIGWINCH]);
+        assert!(signalfd.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signals received through a file descriptor, polled along the other events, rather than caught
+//! by a handler installed for the whole process.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::{io, ptr};
+
+use libc::c_int;
+
+// The events fetched at once by read().
+const EVENTS_PER_READ: usize = 16;
+
+/// A kqueue receiving a set of signals through EVFILT_SIGNAL, mimicking a signalfd.
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    /// Creates a kqueue receiving `signals`. Unlike a signalfd, it observes the signals without
+    /// taking them over, so they're still delivered to their handlers, if any, and the signals
+    /// ignored by default, like SIGWINCH, don't need to be blocked.
+    pub fn new(signals: &[c_int]) -> io::Result<SignalFd> {
+        // Safe because kqueue() has no arguments, and we check the return value.
+        let fd = unsafe { libc::kqueue() };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let signalfd = SignalFd { fd };
+
+        let changes: Vec<libc::kevent> = signals
+            .iter()
+            .map(|signal| libc::kevent {
+                ident: *signal as libc::uintptr_t,
+                filter: libc::EVFILT_SIGNAL,
+                flags: libc::EV_ADD | libc::EV_ENABLE,
+                fflags: 0,
+                data: 0,
+                udata: ptr::null_mut(),
+            })
+            .collect();
+        // Safe because we pass the changes along with their length, and no event list.
+        let ret = unsafe {
+            libc::kevent(
+                fd,
+                changes.as_ptr(),
+                changes.len() as c_int,
+                ptr::null_mut(),
+                0,
+                ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(signalfd)
+    }
+
+    /// Returns the signals received since the last call, without blocking.
+    pub fn read(&self) -> io::Result<Vec<c_int>> {
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let mut signals = Vec::new();
+        loop {
+            // Safe because kevent is plain data, filled in by kevent() up to the returned count.
+            let mut events: [libc::kevent; EVENTS_PER_READ] = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::kevent(
+                    self.fd,
+                    ptr::null(),
+                    0,
+                    events.as_mut_ptr(),
+                    EVENTS_PER_READ as c_int,
+                    &timeout,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            signals.extend(
+                events[..ret as usize]
+                    .iter()
+                    .map(|event| event.ident as c_int),
+            );
+            if (ret as usize) < EVENTS_PER_READ {
+                return Ok(signals);
+            }
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        // Safe because we own the fd.
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::io;
+
 use crate::time;
 
 /// Generates pseudo random u32 numbers based on the current timestamp.
@@ -12,6 +14,40 @@ pub fn xor_rng_u32() -> u32 {
     t ^ (t << 5)
 }
 
+/// Fills `buf` with bytes from the cryptographically secure generator of the host.
+pub fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let chunk = &mut buf[filled..];
+
+        // Safe because getrandom() writes at most `chunk.len()` bytes to `chunk`.
+        #[cfg(target_os = "linux")]
+        let ret =
+            unsafe { libc::getrandom(chunk.as_mut_ptr() as *mut libc::c_void, chunk.len(), 0) };
+
+        // getentropy() fills at most 256 bytes per call, all of them on success. Safe because
+        // it writes at most `len` bytes to `chunk`.
+        #[cfg(target_os = "macos")]
+        let ret = {
+            let len = chunk.len().min(256);
+            match unsafe { libc::getentropy(chunk.as_mut_ptr() as *mut libc::c_void, len) } {
+                0 => len as isize,
+                _ => -1,
+            }
+        };
+
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+            continue;
+        }
+        filled += ret as usize;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -22,4 +58,14 @@ mod tests {
             assert_ne!(xor_rng_u32(), xor_rng_u32());
         }
     }
+
+    #[test]
+    fn test_fill_random() {
+        let mut a = [0u8; 1024];
+        let mut b = [0u8; 1024];
+        fill_random(&mut a).unwrap();
+        fill_random(&mut b).unwrap();
+        assert_ne!(a[..], b[..]);
+        assert_ne!(a[..], [0u8; 1024][..]);
+    }
 }
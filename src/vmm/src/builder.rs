@@ -3,48 +3,105 @@
 
 //! Enables pre-boot setup, instantiation and booting of a Firecracker VMM.
 
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::fs;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 #[cfg(target_os = "macos")]
 use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
 
-use super::{Error, Vmm};
+use super::{Error, Vmm, FC_EXIT_CODE_DEVICE_FAILURE, VMM_SUBSCRIBER_NAME};
 
+use console_resize::ResizeForwarder;
 #[cfg(target_arch = "x86_64")]
 use device_manager::legacy::PortIODeviceManager;
 use device_manager::mmio::MMIODeviceManager;
 use devices::legacy::Gic;
+use devices::legacy::ReadableFd;
 use devices::legacy::Serial;
-use devices::virtio::{MmioTransport, VirtioShmRegion, Vsock, VsockUnixBackend};
+use devices::legacy::{SecretsMailbox, SwtpmBackend, TpmTis, UserDevice, SECRETS_MAILBOX_SIZE};
+#[cfg(target_os = "linux")]
+use devices::virtio::guest_memory;
+use devices::virtio::record::QueueRecorder;
+use devices::virtio::watermark::QueueWatermark;
+use devices::virtio::{
+    BlockError, CallbackInput, DmaWindow, DmaWindows, LogRecordDecoder, LookupCacheConfig,
+    MmioTransport, VirtioDevice, VirtioShmRegion, Vsock, VsockUnixBackend, TYPE_CONSOLE,
+    TYPE_VSOCK, VSOCK_SHM_SIZE,
+};
 
 use arch::ArchMemoryInfo;
-use polly::event_manager::{Error as EventManagerError, EventManager};
-#[cfg(target_os = "linux")]
-use signal_handler::register_sigwinch_handler;
+use host_serial::HostSerial;
+use logger::audit::AuditEvent;
+use logger::AUDIT;
+use polly::event_manager::{
+    Error as EventManagerError, EventManager, PanicPolicy, Subscriber, SubscriberPanic,
+};
+use teardown::Teardown;
+use terminal::SavedTerminal;
+use utils::error::ErrorChain;
 use utils::eventfd::EventFd;
 use utils::terminal::Terminal;
 use utils::time::TimestampUs;
-#[cfg(target_os = "macos")]
-use vm_memory::Bytes;
-#[cfg(target_os = "linux")]
-use vm_memory::{mmap::GuestRegionMmap, GuestMemory};
+use vm_memory::mmap::GuestRegionMmap;
 use vm_memory::{mmap::MmapRegion, GuestAddress, GuestMemoryMmap};
 use vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
+use vmm_config::boot_timeline::BootTimeline;
+use vmm_config::clipboard::Clipboard;
+use vmm_config::console::{
+    ConsoleBackend, ConsoleOutputConfig, ResizeSource, SessionRecorder, TerminalConfig,
+};
+use vmm_config::device_panic::{DeviceFailure, DevicePanicConfig, DevicePanicPolicy};
+use vmm_config::device_window::DeviceWindowConfig;
+use vmm_config::disk::BlockDevices;
+use vmm_config::exec::ServiceMonitor;
+use vmm_config::fd_budget::FdBudget;
 use vmm_config::fs::FsBuilder;
+use vmm_config::fsfreeze::FsFreeze;
+use vmm_config::guest_network::GuestNetwork;
+use vmm_config::guest_panic::{GuestPanic, GuestPanicConfig};
+use vmm_config::kernel_bundle::{KernelBundleError, KernelBundleLoadError};
+use vmm_config::kernel_signature::KernelSignatureError;
+use vmm_config::kvm_tuning::KvmTuningConfig;
+use vmm_config::latency_profile::LatencyProfile;
+use vmm_config::log_channel::LogChannelConfig;
+use vmm_config::probes::ProbeReports;
+use vmm_config::serial_passthrough::SerialPassthroughConfig;
+use vmm_config::shares::ShareSettings;
+use vmm_config::swap::{SwapConfig, SWAP_DEVICE_ID};
+use vmm_config::tpm::TpmConfig;
+#[cfg(target_arch = "x86_64")]
+use vmm_config::user_device::UserRegionKind;
+use vmm_config::user_device::{UserDeviceConfig, UserDeviceError};
+use vmm_config::vm_state::{VmState, VmStateError};
+use vmm_config::worker_pool::{WorkerPool, WorkerPoolConfig};
 #[cfg(target_os = "linux")]
 use vstate::KvmContext;
 use vstate::{Vcpu, VcpuConfig, Vm};
 use {device_manager, VmmEventsObserver};
 
+/// The number of vCPUs from which they're configured concurrently. Below it, spawning the
+/// threads costs more than it saves.
+#[cfg(target_os = "linux")]
+const PARALLEL_VCPU_SETUP_THRESHOLD: usize = 4;
+
 /// Errors associated with starting the instance.
 #[derive(Debug)]
 pub enum StartMicrovmError {
+    /// Cannot accept the client of the console socket.
+    AcceptConsole(io::Error),
     /// Unable to attach block device to Vmm.
     AttachBlockDevice(io::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Cannot spawn the threads of the worker pool.
+    CreateWorkerPool(io::Error),
     /// Memory regions are overlapping or mmap fails.
     GuestMemoryMmap(vm_memory::Error),
     /// Cannot load initrd due to an invalid memory configuration.
@@ -54,11 +111,15 @@ pub enum StartMicrovmError {
     /// Internal error encountered while starting a microVM.
     Internal(Error),
     /// The kernel command line is invalid.
-    KernelCmdline(String),
+    KernelCmdline(kernel::cmdline::Error),
     /// Cannot inject the kernel into the guest memory due to a problem with the bundle.
     KernelBundle(vm_memory::mmap::MmapRegionError),
+    /// The kernel bundle isn't signed by a trusted key.
+    KernelSignature(KernelSignatureError),
     /// Cannot load command line string.
     LoadCommandline(kernel::cmdline::Error),
+    /// Cannot read the kernel bundle, or it doesn't match its digest.
+    LoadKernelBundle(KernelBundleError),
     /// The start command was issued more than once.
     MicroVMAlreadyRunning,
     /// Cannot start the VM because the kernel was not configured.
@@ -69,28 +130,110 @@ pub enum StartMicrovmError {
     NetDeviceNotConfigured,
     /// Cannot open the block device backing file.
     OpenBlockDevice(io::Error),
+    /// Cannot open or set up a serial device of the host passed through to the guest.
+    OpenSerialPassthrough(PathBuf, io::Error),
     /// Cannot initialize a MMIO Balloon device or add a device to the MMIO Bus.
     RegisterBalloonDevice(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
     RegisterBlockDevice(device_manager::mmio::Error),
+    /// Cannot initialize a MMIO Crypto Device or add a device to the MMIO Bus.
+    RegisterCryptoDevice(device_manager::mmio::Error),
+    /// Cannot initialize an embedder-provided MMIO Device or add a device to the MMIO Bus.
+    RegisterCustomDevice(device_manager::mmio::Error),
+    /// Cannot add the console sharing the clipboard to the MMIO Bus.
+    RegisterClipboard(device_manager::mmio::Error),
     /// Cannot register an EventHandler.
     RegisterEvent(EventManagerError),
     /// Cannot initialize a MMIO Fs Device or add ad device to the MMIO Bus.
     RegisterFsDevice(device_manager::mmio::Error),
-    /// Cannot register SIGWINCH event file descriptor.
-    #[cfg(target_os = "linux")]
-    RegisterFsSigwinch(kvm_ioctls::Error),
+    /// Cannot add the console freezing the filesystems of the guest to the MMIO Bus.
+    RegisterFsFreeze(device_manager::mmio::Error),
+    /// Cannot add the console reporting the network configuration of the guest to the MMIO Bus.
+    RegisterGuestNetwork(device_manager::mmio::Error),
+    /// Cannot set up the source of the resizes of the console.
+    RegisterConsoleResize(io::Error),
+    /// Cannot add the console carrying the logs of the guest to the MMIO Bus.
+    RegisterLogChannel(device_manager::mmio::Error),
     /// Cannot initialize a MMIO Network Device or add a device to the MMIO Bus.
     RegisterNetDevice(device_manager::mmio::Error),
+    /// Cannot add the secrets mailbox to the bus.
+    RegisterSecretsMailbox(devices::BusError),
+    /// Cannot add the console bound to a serial device of the host to the MMIO Bus.
+    RegisterSerialPassthrough(device_manager::mmio::Error),
+    /// Cannot add the TPM device to the MMIO Bus.
+    RegisterTpmDevice(device_manager::mmio::Error),
+    /// Cannot add a region handed to the embedder to its bus.
+    RegisterUserDevice(UserDeviceError),
     /// Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus.
     RegisterVsockDevice(device_manager::mmio::Error),
+    /// The restricted DMA pool doesn't fit in the guest memory, or isn't supported here.
+    RestrictedDmaPool,
+    /// Cannot spawn the thread wiping the secrets once expired.
+    SecretsExpirySpawn(io::Error),
+    /// The shared memory region isn't within the guest memory.
+    ShmRegion(devices::virtio::guest_memory::Error),
+    /// Cannot connect to the emulator backing the TPM device.
+    TpmBackend(io::Error),
+    /// The microVM was already built, or is being built.
+    VmState(VmStateError),
 }
 
 /// It's convenient to automatically convert `kernel::cmdline::Error`s
 /// to `StartMicrovmError`s.
 impl std::convert::From<kernel::cmdline::Error> for StartMicrovmError {
     fn from(e: kernel::cmdline::Error) -> StartMicrovmError {
-        StartMicrovmError::KernelCmdline(e.to_string())
+        StartMicrovmError::KernelCmdline(e)
+    }
+}
+
+impl StartMicrovmError {
+    /// Returns a code identifying the variant, for embedders to match on across releases. Codes
+    /// are never reused nor renumbered, and new variants get the next one.
+    pub fn code(&self) -> u32 {
+        use self::StartMicrovmError::*;
+        match *self {
+            AttachBlockDevice(_) => 1,
+            CreateRateLimiter(_) => 2,
+            CreateWorkerPool(_) => 3,
+            GuestMemoryMmap(_) => 4,
+            InitrdLoad => 5,
+            InitrdRead(_) => 6,
+            Internal(_) => 7,
+            KernelCmdline(_) => 8,
+            KernelBundle(_) => 9,
+            LoadCommandline(_) => 10,
+            MicroVMAlreadyRunning => 11,
+            MissingKernelConfig => 12,
+            MissingMemSizeConfig => 13,
+            NetDeviceNotConfigured => 14,
+            OpenBlockDevice(_) => 15,
+            RegisterBalloonDevice(_) => 16,
+            RegisterBlockDevice(_) => 17,
+            RegisterCryptoDevice(_) => 18,
+            RegisterCustomDevice(_) => 19,
+            RegisterEvent(_) => 20,
+            RegisterFsDevice(_) => 21,
+            RegisterConsoleResize(_) => 22,
+            RegisterNetDevice(_) => 23,
+            RegisterSecretsMailbox(_) => 24,
+            RegisterTpmDevice(_) => 25,
+            RegisterVsockDevice(_) => 26,
+            SecretsExpirySpawn(_) => 27,
+            TpmBackend(_) => 28,
+            RegisterLogChannel(_) => 29,
+            AcceptConsole(_) => 30,
+            RestrictedDmaPool => 31,
+            ShmRegion(_) => 32,
+            LoadKernelBundle(_) => 33,
+            KernelSignature(_) => 34,
+            RegisterClipboard(_) => 35,
+            OpenSerialPassthrough(..) => 36,
+            RegisterSerialPassthrough(_) => 37,
+            RegisterFsFreeze(_) => 38,
+            RegisterUserDevice(_) => 39,
+            VmState(_) => 40,
+            RegisterGuestNetwork(_) => 41,
+        }
     }
 }
 
@@ -98,112 +241,142 @@ impl Display for StartMicrovmError {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         use self::StartMicrovmError::*;
         match *self {
-            AttachBlockDevice(ref err) => {
-                write!(f, "Unable to attach block device to Vmm. Error: {}", err)
-            }
-            CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
-            GuestMemoryMmap(ref err) => {
-                // Remove imbricated quotes from error message.
-                let mut err_msg = format!("{:?}", err);
-                err_msg = err_msg.replace("\"", "");
-                write!(f, "Invalid Memory Configuration: {}", err_msg)
-            }
+            AcceptConsole(_) => write!(f, "Cannot accept the client of the console socket"),
+            AttachBlockDevice(_) => write!(f, "Unable to attach block device to Vmm"),
+            CreateRateLimiter(_) => write!(f, "Cannot create RateLimiter"),
+            CreateWorkerPool(_) => write!(f, "Cannot create the worker pool"),
+            GuestMemoryMmap(_) => write!(f, "Invalid Memory Configuration"),
             InitrdLoad => write!(
                 f,
-                "Cannot load initrd due to an invalid memory configuration."
+                "Cannot load initrd due to an invalid memory configuration"
             ),
-            InitrdRead(ref err) => write!(f, "Cannot load initrd due to an invalid image: {}", err),
-            Internal(ref err) => write!(f, "Internal error while starting microVM: {:?}", err),
-            KernelCmdline(ref err) => write!(f, "Invalid kernel command line: {}", err),
-            KernelBundle(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-                write!(
-                    f,
-                    "Cannot inject the kernel into the guest memory due to a problem with the \
-                     bundle. {}",
-                    err_msg
-                )
-            }
-            LoadCommandline(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-                write!(f, "Cannot load command line string. {}", err_msg)
-            }
-            MicroVMAlreadyRunning => write!(f, "Microvm already running."),
-            MissingKernelConfig => write!(f, "Cannot start microvm without kernel configuration."),
-            MissingMemSizeConfig => {
-                write!(f, "Cannot start microvm without guest mem_size config.")
-            }
+            InitrdRead(_) => write!(f, "Cannot load initrd due to an invalid image"),
+            Internal(_) => write!(f, "Internal error while starting microVM"),
+            KernelCmdline(_) => write!(f, "Invalid kernel command line"),
+            KernelBundle(_) => write!(
+                f,
+                "Cannot inject the kernel into the guest memory due to a problem with the bundle"
+            ),
+            KernelSignature(_) => write!(f, "Cannot verify the signature of the kernel bundle"),
+            LoadCommandline(_) => write!(f, "Cannot load command line string"),
+            LoadKernelBundle(_) => write!(f, "Cannot load the kernel bundle"),
+            MicroVMAlreadyRunning => write!(f, "Microvm already running"),
+            MissingKernelConfig => write!(f, "Cannot start microvm without kernel configuration"),
+            MissingMemSizeConfig => write!(f, "Cannot start microvm without guest mem_size config"),
             NetDeviceNotConfigured => {
-                write!(f, "The net device configuration is missing the tap device.")
-            }
-            OpenBlockDevice(ref err) => {
-                let mut err_msg = format!("{:?}", err);
-                err_msg = err_msg.replace("\"", "");
-
-                write!(f, "Cannot open the block device backing file. {}", err_msg)
-            }
-            RegisterBalloonDevice(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-                write!(
-                    f,
-                    "Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus. {}",
-                    err_msg
-                )
-            }
-            RegisterBlockDevice(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-                write!(
-                    f,
-                    "Cannot initialize a MMIO Block Device or add a device to the MMIO Bus. {}",
-                    err_msg
-                )
+                write!(f, "The net device configuration is missing the tap device")
             }
-            RegisterEvent(ref err) => write!(f, "Cannot register EventHandler. {:?}", err),
-            RegisterFsDevice(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-
-                write!(
-                    f,
-                    "Cannot initialize a MMIO Fs Device or add a device to the MMIO Bus. {}",
-                    err_msg
-                )
+            OpenBlockDevice(_) => write!(f, "Cannot open the block device backing file"),
+            OpenSerialPassthrough(ref path, _) => {
+                write!(f, "Cannot open the serial device {}", path.display())
             }
-            #[cfg(target_os = "linux")]
-            RegisterFsSigwinch(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-
-                write!(
-                    f,
-                    "Cannot register SIGWINCH file descriptor for Fs Device. {}",
-                    err_msg
-                )
+            RegisterBalloonDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Balloon Device or add a device to the MMIO Bus"
+            ),
+            RegisterBlockDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Block Device or add a device to the MMIO Bus"
+            ),
+            RegisterCryptoDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Crypto Device or add a device to the MMIO Bus"
+            ),
+            RegisterCustomDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Custom Device or add a device to the MMIO Bus"
+            ),
+            RegisterEvent(_) => write!(f, "Cannot register EventHandler"),
+            RegisterFsDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Fs Device or add a device to the MMIO Bus"
+            ),
+            RegisterClipboard(_) => write!(f, "Cannot add the clipboard to the MMIO Bus"),
+            RegisterFsFreeze(_) => write!(
+                f,
+                "Cannot add the console freezing the filesystems to the MMIO Bus"
+            ),
+            RegisterGuestNetwork(_) => write!(
+                f,
+                "Cannot add the console reporting the guest network to the MMIO Bus"
+            ),
+            RegisterConsoleResize(_) => {
+                write!(f, "Cannot set up the source of the console resizes")
             }
-            RegisterNetDevice(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
-
+            RegisterLogChannel(_) => write!(f, "Cannot add the log channel to the MMIO Bus"),
+            RegisterSerialPassthrough(_) => {
                 write!(
                     f,
-                    "Cannot initialize a MMIO Network Device or add a device to the MMIO Bus. {}",
-                    err_msg
+                    "Cannot add the console of a serial device to the MMIO Bus"
                 )
             }
-            RegisterVsockDevice(ref err) => {
-                let mut err_msg = format!("{}", err);
-                err_msg = err_msg.replace("\"", "");
+            RegisterNetDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Network Device or add a device to the MMIO Bus"
+            ),
+            RegisterSecretsMailbox(_) => write!(f, "Cannot add the secrets mailbox to the bus"),
+            RegisterTpmDevice(_) => write!(f, "Cannot add the TPM Device to the MMIO Bus"),
+            RegisterUserDevice(_) => write!(f, "Cannot add the user device"),
+            RegisterVsockDevice(_) => write!(
+                f,
+                "Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus"
+            ),
+            RestrictedDmaPool => write!(
+                f,
+                "The restricted DMA pool doesn't fit in the guest memory or isn't supported"
+            ),
+            SecretsExpirySpawn(_) => write!(f, "Cannot spawn the secrets expiry thread"),
+            ShmRegion(_) => write!(f, "The shared memory region isn't within the guest memory"),
+            TpmBackend(_) => write!(f, "Cannot connect to the TPM emulator"),
+            VmState(_) => write!(f, "Cannot build the microVM"),
+        }
+    }
+}
 
-                write!(
-                    f,
-                    "Cannot initialize a MMIO Vsock Device or add a device to the MMIO Bus. {}",
-                    err_msg
-                )
-            }
+impl std::error::Error for StartMicrovmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::StartMicrovmError::*;
+        match *self {
+            AcceptConsole(ref e)
+            | AttachBlockDevice(ref e)
+            | CreateRateLimiter(ref e)
+            | CreateWorkerPool(ref e)
+            | InitrdRead(ref e)
+            | OpenBlockDevice(ref e)
+            | OpenSerialPassthrough(_, ref e)
+            | SecretsExpirySpawn(ref e)
+            | TpmBackend(ref e) => Some(e),
+            GuestMemoryMmap(ref e) => Some(e),
+            Internal(ref e) => Some(e),
+            KernelCmdline(ref e) | LoadCommandline(ref e) => Some(e),
+            KernelBundle(ref e) => Some(e),
+            KernelSignature(ref e) => Some(e),
+            LoadKernelBundle(ref e) => Some(e),
+            RegisterBalloonDevice(ref e)
+            | RegisterBlockDevice(ref e)
+            | RegisterClipboard(ref e)
+            | RegisterCryptoDevice(ref e)
+            | RegisterCustomDevice(ref e)
+            | RegisterFsDevice(ref e)
+            | RegisterFsFreeze(ref e)
+            | RegisterGuestNetwork(ref e)
+            | RegisterLogChannel(ref e)
+            | RegisterNetDevice(ref e)
+            | RegisterSerialPassthrough(ref e)
+            | RegisterTpmDevice(ref e)
+            | RegisterVsockDevice(ref e) => Some(e),
+            RegisterEvent(ref e) => Some(e),
+            RegisterConsoleResize(ref e) => Some(e),
+            RegisterSecretsMailbox(ref e) => Some(e),
+            RegisterUserDevice(ref e) => Some(e),
+            ShmRegion(ref e) => Some(e),
+            VmState(ref e) => Some(e),
+            InitrdLoad
+            | MicroVMAlreadyRunning
+            | MissingKernelConfig
+            | MissingMemSizeConfig
+            | NetDeviceNotConfigured
+            | RestrictedDmaPool => None,
         }
     }
 }
@@ -217,11 +390,6 @@ impl SerialStdin {
         stdin.lock().set_raw_mode().unwrap();
         SerialStdin(stdin)
     }
-
-    pub fn restore() {
-        let stdin = io::stdin();
-        stdin.lock().set_canon_mode().unwrap();
-    }
 }
 
 impl io::Read for SerialStdin {
@@ -238,6 +406,23 @@ impl AsRawFd for SerialStdin {
 
 impl devices::legacy::ReadableFd for SerialStdin {}
 
+// Wrapper over the client of the console socket that implements `Serial::ReadableFd`.
+struct SocketConsoleInput(UnixStream);
+
+impl io::Read for SocketConsoleInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.0, buf)
+    }
+}
+
+impl AsRawFd for SocketConsoleInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl devices::legacy::ReadableFd for SocketConsoleInput {}
+
 impl VmmEventsObserver for SerialStdin {
     fn on_vmm_boot(&mut self) -> std::result::Result<(), utils::errno::Error> {
         // Set raw mode for stdin.
@@ -254,27 +439,194 @@ impl VmmEventsObserver for SerialStdin {
     }
 }
 
+/// Result of the first build stage: the guest memory has been created and the kernel bundle
+/// has been placed in it.
+pub struct GuestMemoryStage {
+    guest_memory: GuestMemoryMmap,
+    arch_memory_info: ArchMemoryInfo,
+    kernel_guest_addr: u64,
+    request_ts: TimestampUs,
+}
+
+impl GuestMemoryStage {
+    /// Starts building a microVM on guest memory created by the embedder, in place of
+    /// `build_guest_memory`. The kernel must already be in `guest_memory` at `kernel_guest_addr`,
+    /// and `arch_memory_info` must describe the layout of `guest_memory`. As the kernel comes
+    /// with the memory, its signature isn't verified.
+    pub fn from_guest_memory(
+        vm_resources: &super::resources::VmResources,
+        guest_memory: GuestMemoryMmap,
+        arch_memory_info: ArchMemoryInfo,
+        kernel_guest_addr: u64,
+    ) -> std::result::Result<Self, StartMicrovmError> {
+        vm_resources
+            .vm_state
+            .transition(VmState::Booting)
+            .map_err(StartMicrovmError::VmState)?;
+        vm_resources.boot_timeline.start();
+        Ok(GuestMemoryStage {
+            guest_memory,
+            arch_memory_info,
+            kernel_guest_addr,
+            request_ts: TimestampUs::default(),
+        })
+    }
+
+    /// Returns a reference to the guest memory that will back the microVM.
+    pub fn guest_memory(&self) -> &GuestMemoryMmap {
+        &self.guest_memory
+    }
+
+    /// Returns the architecture specific memory layout.
+    pub fn arch_memory_info(&self) -> &ArchMemoryInfo {
+        &self.arch_memory_info
+    }
+}
+
+/// Result of the second build stage: the VM, its interrupt controller, the legacy devices and
+/// the vCPUs have been created.
+///
+/// The vCPUs are created in this stage because on aarch64 the interrupt controller can only be
+/// set up after them, and the virtio devices need the interrupt controller to be in place.
+pub struct VmStage {
+    vmm: Vmm,
+    vcpus: Vec<Vcpu>,
+    intc: Option<Arc<Mutex<Gic>>>,
+    shm_region: Option<VirtioShmRegion>,
+}
+
+impl VmStage {
+    /// Returns a reference to the `Vmm` being built.
+    pub fn vmm(&self) -> &Vmm {
+        &self.vmm
+    }
+
+    /// Returns a mutable reference to the `Vmm` being built.
+    pub fn vmm_mut(&mut self) -> &mut Vmm {
+        &mut self.vmm
+    }
+
+    /// Returns the vCPUs created for this microVM.
+    pub fn vcpus(&self) -> &[Vcpu] {
+        &self.vcpus
+    }
+
+    /// Returns the userspace interrupt controller, if the platform uses one.
+    pub fn intc(&self) -> Option<Arc<Mutex<Gic>>> {
+        self.intc.clone()
+    }
+
+    /// Attaches an embedder-provided virtio device to the microVM, registering `subscriber`
+    /// (if any) in the `EventManager`.
+    ///
+    /// On platforms with a userspace interrupt controller, the device is responsible for
+    /// raising its interrupts through the one returned by `intc()`.
+    pub fn attach_virtio_device(
+        &mut self,
+        id: String,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        subscriber: Option<Arc<Mutex<dyn Subscriber + Send>>>,
+        event_manager: &mut EventManager,
+    ) -> std::result::Result<(), StartMicrovmError> {
+        attach_custom_device(&mut self.vmm, id, device, subscriber, event_manager)
+    }
+}
+
+/// Result of the third build stage: all the devices configured in `VmResources` have been
+/// attached to the microVM, which is ready to be started.
+pub struct DevicesStage {
+    vmm: Vmm,
+    vcpus: Vec<Vcpu>,
+}
+
+impl DevicesStage {
+    /// Returns a reference to the `Vmm` being built.
+    pub fn vmm(&self) -> &Vmm {
+        &self.vmm
+    }
+}
+
 /// Builds and starts a microVM based on the current Firecracker VmResources configuration.
 ///
 /// This is the default build recipe, one could build other microVM flavors by using the
-/// independent functions in this module instead of calling this recipe.
+/// independent functions in this module instead of calling this recipe. The recipe is split
+/// in the `build_guest_memory`, `build_vm`, `build_devices` and `start_microvm` stages, which
+/// can be called individually to customize the microVM between them.
 ///
 /// An `Arc` reference of the built `Vmm` is also plugged in the `EventManager`, while another
 /// is returned.
+///
+/// If any stage fails, what was built so far is released: the `Vmm` being built is dropped, and
+/// the devices registered with the `EventManager` are removed from it.
 pub fn build_microvm(
     vm_resources: &super::resources::VmResources,
     event_manager: &mut EventManager,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    let registered = event_manager.pollables();
+    let result = build_microvm_stages(vm_resources, event_manager);
+    if result.is_err() {
+        release_registrations(event_manager, &registered);
+        // Unless it was already built, the microVM failed to boot.
+        if vm_resources.vm_state.get() == VmState::Booting {
+            let _ = vm_resources.vm_state.transition(VmState::Stopped(None));
+        }
+    }
+    result
+}
+
+fn build_microvm_stages(
+    vm_resources: &super::resources::VmResources,
+    event_manager: &mut EventManager,
+) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    let memory_stage = build_guest_memory(vm_resources)?;
+    let vm_stage = build_vm(vm_resources, memory_stage)?;
+    let devices_stage = build_devices(vm_resources, vm_stage, event_manager)?;
+    start_microvm(vm_resources, devices_stage, event_manager)
+}
+
+/// Unregisters the pollables registered since `registered` was taken, dropping the references
+/// the `EventManager` holds to the devices of a microVM that failed to build, and with them their
+/// fds.
+fn release_registrations(event_manager: &mut EventManager, registered: &[RawFd]) {
+    for pollable in event_manager.pollables() {
+        if registered.contains(&pollable) {
+            continue;
+        }
+        if let Err(e) = event_manager.unregister(pollable) {
+            warn!("Unable to unregister {}: {}", pollable, ErrorChain(&e));
+        }
+    }
+}
+
+/// First build stage: creates the guest memory and places the kernel bundle in it.
+pub fn build_guest_memory(
+    vm_resources: &super::resources::VmResources,
+) -> std::result::Result<GuestMemoryStage, StartMicrovmError> {
+    vm_resources
+        .vm_state
+        .transition(VmState::Booting)
+        .map_err(StartMicrovmError::VmState)?;
     // Timestamp for measuring microVM boot duration.
     let request_ts = TimestampUs::default();
+    let started = Instant::now();
+    vm_resources.boot_timeline.start();
 
     let kernel_bundle = vm_resources
         .kernel_bundle()
         .ok_or(StartMicrovmError::MissingKernelConfig)?;
-    let kernel_region = unsafe {
-        MmapRegion::build_raw(kernel_bundle.host_addr as *mut u8, kernel_bundle.size, 0, 0)
-            .map_err(StartMicrovmError::KernelBundle)?
-    };
+    let kernel_region = kernel_bundle.load().map_err(|e| match e {
+        KernelBundleLoadError::Bundle(e) => StartMicrovmError::LoadKernelBundle(e),
+        KernelBundleLoadError::Mmap(e) => StartMicrovmError::KernelBundle(e),
+    })?;
+    if vm_resources.kernel_signature.is_enforced() {
+        // Safe because the region was just loaded, and the guest can't access it yet.
+        let kernel =
+            unsafe { std::slice::from_raw_parts(kernel_region.as_ptr(), kernel_region.size()) };
+        vm_resources
+            .kernel_signature
+            .verify(kernel)
+            .map_err(StartMicrovmError::KernelSignature)?;
+    }
 
     let (guest_memory, arch_memory_info) = create_guest_memory(
         vm_resources
@@ -285,6 +637,31 @@ pub fn build_microvm(
         kernel_bundle.guest_addr,
         kernel_bundle.size,
     )?;
+
+    vm_resources
+        .boot_timeline
+        .record_stage("guest_memory", started.elapsed());
+    Ok(GuestMemoryStage {
+        guest_memory,
+        arch_memory_info,
+        kernel_guest_addr: kernel_bundle.guest_addr,
+        request_ts,
+    })
+}
+
+/// Second build stage: creates the VM, the interrupt controller, the legacy devices and the
+/// vCPUs on top of the guest memory created in the previous stage.
+pub fn build_vm(
+    vm_resources: &super::resources::VmResources,
+    memory_stage: GuestMemoryStage,
+) -> std::result::Result<VmStage, StartMicrovmError> {
+    let GuestMemoryStage {
+        guest_memory,
+        arch_memory_info,
+        kernel_guest_addr,
+        request_ts,
+    } = memory_stage;
+    let started = Instant::now();
     let vcpu_config = vm_resources.vcpu_config();
 
     // Clone the command-line so that a failed boot doesn't pollute the original.
@@ -294,7 +671,24 @@ pub fn build_microvm(
         None => kernel_cmdline.insert_str(DEFAULT_KERNEL_CMDLINE).unwrap(),
         Some(s) => kernel_cmdline.insert_str(s).unwrap(),
     };
-    let mut vm = setup_vm(&guest_memory)?;
+    // The epilog is only appended in the last stage, but it may select the idle loop as well.
+    let idle_option = vm_resources.latency_profile.guest_idle_option(&format!(
+        "{} {}",
+        kernel_cmdline.as_str(),
+        vm_resources
+            .boot_config
+            .kernel_cmdline_epilog
+            .as_deref()
+            .unwrap_or("")
+    ));
+    if let Some((key, value)) = idle_option {
+        kernel_cmdline.insert(key, value)?;
+    }
+    let mut vm = setup_vm(
+        &guest_memory,
+        &vm_resources.kvm_tuning,
+        vm_resources.latency_profile,
+    )?;
 
     // On x86_64 always create a serial device,
     // while on aarch64 only create it if 'console=' is specified in the boot args.
@@ -312,34 +706,70 @@ pub fn build_microvm(
     };
     */
 
-    let serial_device = None;
+    // The serial device is only used for capturing the early console output, if requested,
+    // since the virtio-console takes over once probed.
+    let serial_device = match vm_resources.earlycon() {
+        Some(earlycon) => {
+            let interrupt_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)
+                .map_err(Error::EventFd)
+                .map_err(StartMicrovmError::Internal)?;
+            #[cfg(target_arch = "x86_64")]
+            kernel_cmdline.insert("earlycon", "uart,io,0x3f8")?;
+            Some(Arc::new(Mutex::new(Serial::new_out(
+                interrupt_evt,
+                Box::new(earlycon.clone()),
+            ))))
+        }
+        None => None,
+    };
 
     let exit_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)
         .map_err(Error::EventFd)
         .map_err(StartMicrovmError::Internal)?;
 
+    // Without the i8042, the guest has to reset the machine through the reset control instead.
     #[cfg(target_arch = "x86_64")]
-    // Safe to unwrap 'serial_device' as it's always 'Some' on x86_64.
-    // x86_64 uses the i8042 reset event as the Vmm exit event.
-    let mut pio_device_manager = PortIODeviceManager::new(
-        serial_device,
-        exit_evt
-            .try_clone()
-            .map_err(Error::EventFd)
-            .map_err(StartMicrovmError::Internal)?,
-    )
-    .map_err(Error::CreateLegacyDevice)
-    .map_err(StartMicrovmError::Internal)?;
+    if vm_resources.no_i8042 {
+        kernel_cmdline.insert("reboot", "pci")?;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    let mut pio_device_manager = PortIODeviceManager::new(serial_device, !vm_resources.no_i8042)
+        .map_err(Error::CreateLegacyDevice)
+        .map_err(StartMicrovmError::Internal)?;
 
     // Instantiate the MMIO device manager.
     // 'mmio_base' address has to be an address which is protected by the kernel
     // and is architectural specific.
+    // The window defaults to the architectural one, but may be moved by the embedder.
+    let device_window = vm_resources.device_window;
+    let mut mmio_base = device_window.mmio_start;
     #[allow(unused_mut)]
     let mut mmio_device_manager = MMIODeviceManager::new(
-        &mut (arch::MMIO_MEM_START as u64),
-        (arch::IRQ_BASE, arch::IRQ_MAX),
+        &mut mmio_base,
+        (device_window.irq_base, device_window.irq_max),
     );
 
+    // The buses are handed to the vCPUs as they're created, so the mailbox must be in place by
+    // then.
+    if let Some(secrets) = &vm_resources.secrets {
+        #[cfg(target_arch = "x86_64")]
+        let bus = &mut pio_device_manager.io_bus;
+        #[cfg(target_arch = "aarch64")]
+        let bus = &mut mmio_device_manager.bus;
+        attach_secrets_mailbox(bus, secrets)?;
+    }
+    for user_device in vm_resources.user_devices.iter() {
+        #[cfg(target_arch = "x86_64")]
+        let bus = match user_device.kind {
+            UserRegionKind::Mmio => &mut mmio_device_manager.bus,
+            UserRegionKind::Pio => &mut pio_device_manager.io_bus,
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        let bus = &mut mmio_device_manager.bus;
+        attach_user_device(bus, user_device, &device_window)?;
+    }
+
     #[cfg(target_os = "linux")]
     let intc = None;
     #[cfg(target_os = "macos")]
@@ -357,7 +787,7 @@ pub fn build_microvm(
             &vm,
             &vcpu_config,
             &guest_memory,
-            GuestAddress(kernel_bundle.guest_addr),
+            GuestAddress(kernel_guest_addr),
             request_ts,
             &pio_device_manager.io_bus,
             &exit_evt,
@@ -375,7 +805,7 @@ pub fn build_microvm(
             &vm,
             &vcpu_config,
             &guest_memory,
-            GuestAddress(kernel_bundle.guest_addr),
+            GuestAddress(kernel_guest_addr),
             request_ts,
             &exit_evt,
         )
@@ -396,7 +826,7 @@ pub fn build_microvm(
             &vm,
             &vcpu_config,
             &guest_memory,
-            GuestAddress(kernel_bundle.guest_addr),
+            GuestAddress(kernel_guest_addr),
             request_ts,
             &exit_evt,
             intc.clone().unwrap(),
@@ -415,16 +845,19 @@ pub fn build_microvm(
 
     #[cfg(target_os = "linux")]
     let shm_region = Some(VirtioShmRegion {
-        host_addr: guest_memory
-            .get_host_address(GuestAddress(arch_memory_info.shm_start_addr))
-            .unwrap() as u64,
+        host_addr: guest_memory::host_address(
+            &guest_memory,
+            GuestAddress(arch_memory_info.shm_start_addr),
+            arch_memory_info.shm_size as usize,
+        )
+        .map_err(StartMicrovmError::ShmRegion)? as u64,
         guest_addr: arch_memory_info.shm_start_addr,
         size: arch_memory_info.shm_size as usize,
     });
     #[cfg(target_os = "macos")]
     let shm_region = None;
 
-    let mut vmm = Vmm {
+    let vmm = Vmm {
         //events_observer: Some(Box::new(SerialStdin::get())),
         guest_memory,
         arch_memory_info,
@@ -432,23 +865,234 @@ pub fn build_microvm(
         vcpus_handles: Vec::new(),
         exit_evt,
         vm,
+        runtime_limit_evt: None,
+        runtime_limit_expirations: 0,
+        #[cfg(target_os = "linux")]
+        idle_monitor: None,
+        #[cfg(target_os = "linux")]
+        profiler: None,
         mmio_device_manager,
+        balloon: None,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        virtio_recorder: None,
+        queue_watermarks: None,
+        dma_pool: None,
+        exit_handle: None,
+        state: vm_resources.vm_state.clone(),
+        #[cfg(target_arch = "aarch64")]
+        cpu_topology: vcpu_config.topology,
+        teardown: Teardown::new(),
     };
 
-    attach_balloon_device(&mut vmm, event_manager, intc.clone())?;
-    attach_console_devices(&mut vmm, event_manager, intc.clone())?;
-    attach_fs_devices(
-        &mut vmm,
-        &vm_resources.fs,
-        event_manager,
+    vm_resources
+        .boot_timeline
+        .record_stage("vm", started.elapsed());
+    Ok(VmStage {
+        vmm,
+        vcpus,
+        intc,
         shm_region,
-        intc.clone(),
-    )?;
+    })
+}
+
+/// Third build stage: attaches the devices configured in `VmResources` to the microVM.
+pub fn build_devices(
+    vm_resources: &super::resources::VmResources,
+    vm_stage: VmStage,
+    event_manager: &mut EventManager,
+) -> std::result::Result<DevicesStage, StartMicrovmError> {
+    let VmStage {
+        mut vmm,
+        vcpus,
+        intc,
+        mut shm_region,
+    } = vm_stage;
+    let started = Instant::now();
+
+    // The shared-memory data path of vsock takes the end of the region, leaving the rest to the
+    // DAX windows of the fs devices.
+    let mut vsock_shm_region = None;
+    if vm_resources.vsock_shm && vm_resources.vsock.get().is_some() {
+        if let Some(ref mut shm) = shm_region {
+            shm.size -= VSOCK_SHM_SIZE;
+            vsock_shm_region = Some(VirtioShmRegion {
+                host_addr: shm.host_addr + shm.size as u64,
+                guest_addr: shm.guest_addr + shm.size as u64,
+                size: VSOCK_SHM_SIZE,
+            });
+        }
+    }
+
+    vmm.virtio_recorder = vm_resources.virtio_recorder.clone();
+    vmm.queue_watermarks = vm_resources.queue_watermarks.clone();
+    vmm.exit_handle = vm_resources.exit_handle.clone();
+    if let Some(restricted_dma) = &vm_resources.restricted_dma {
+        vmm.dma_pool = Some(restricted_dma_pool(&vmm, restricted_dma.pool_size())?);
+    }
+    if let Some(seed) = &vm_resources.cloud_init {
+        remove_on_teardown(&mut vmm, seed.path());
+    }
+    if let Some(modules) = &vm_resources.kernel_modules {
+        remove_on_teardown(&mut vmm, modules.path());
+    }
+    if let Some(overrides) = &vm_resources.etc_overrides {
+        remove_on_teardown(&mut vmm, overrides.path());
+    }
+    if let Some(certs) = &vm_resources.ca_certs {
+        remove_on_teardown(&mut vmm, certs.path());
+    }
+    // The devices the workload doesn't need are left out, sparing their setup from the boot.
+    let timeline = &vm_resources.boot_timeline;
+    // The balloon hands the guest pages themselves over, which can't be bounced through the
+    // restricted DMA pool.
+    if vm_resources.no_balloon || vmm.dma_pool.is_some() {
+        timeline.record_skipped("balloon");
+    } else {
+        timed_attach(timeline, "balloon", || {
+            attach_balloon_device(&mut vmm, event_manager, intc.clone())
+        })?;
+    }
+    timed_attach(timeline, "console", || {
+        attach_console_devices(
+            &mut vmm,
+            &vm_resources.console,
+            vm_resources.console_output,
+            vm_resources.console_terminal,
+            &vm_resources.console_resize,
+            vm_resources.console_recorder.as_ref(),
+            &vm_resources.guest_panic,
+            event_manager,
+            intc.clone(),
+        )
+    })?;
+    if let Some(log_channel) = &vm_resources.log_channel {
+        timed_attach(timeline, "log_channel", || {
+            attach_log_channel_device(&mut vmm, log_channel, event_manager, intc.clone())
+        })?;
+    }
+    if let Some(clipboard) = &vm_resources.clipboard {
+        timed_attach(timeline, "clipboard", || {
+            attach_clipboard_device(&mut vmm, clipboard, event_manager, intc.clone())
+        })?;
+    }
+    if !vm_resources.serial_passthroughs.is_empty() {
+        timed_attach(timeline, "serial_passthrough", || {
+            attach_serial_passthrough_devices(
+                &mut vmm,
+                &vm_resources.serial_passthroughs,
+                event_manager,
+                intc.clone(),
+            )
+        })?;
+    }
+    if let Some(fsfreeze) = &vm_resources.fsfreeze {
+        timed_attach(timeline, "fsfreeze", || {
+            attach_fsfreeze_device(&mut vmm, fsfreeze, event_manager, intc.clone())
+        })?;
+    }
+    if let Some(guest_network) = &vm_resources.guest_network {
+        timed_attach(timeline, "guest_network", || {
+            attach_guest_network_device(&mut vmm, guest_network, event_manager, intc.clone())
+        })?;
+    }
+    if vm_resources.crypto {
+        timed_attach(timeline, "crypto", || {
+            attach_crypto_device(&mut vmm, event_manager, intc.clone())
+        })?;
+    }
+    if let Some(tpm) = &vm_resources.tpm {
+        timed_attach(timeline, "tpm", || attach_tpm_device(&mut vmm, tpm))?;
+    }
+    if let Some(swap) = &vm_resources.swap {
+        timed_attach(timeline, "swap", || {
+            attach_swap_device(
+                &mut vmm,
+                swap,
+                &vm_resources.block_devices,
+                event_manager,
+                intc.clone(),
+            )
+        })?;
+    }
+    timed_attach(timeline, "fs", || {
+        attach_fs_devices(
+            &mut vmm,
+            &vm_resources.fs,
+            &vm_resources.worker_pool,
+            vm_resources.fs_lookup_cache,
+            &vm_resources.share_settings,
+            event_manager,
+            shm_region,
+            intc.clone(),
+        )
+    })?;
     if let Some(vsock) = vm_resources.vsock.get() {
-        attach_unixsock_vsock_device(&mut vmm, vsock, event_manager, intc)?;
+        vsock
+            .lock()
+            .unwrap()
+            .set_activated_sockets(vm_resources.activated_sockets.clone());
+        timed_attach(timeline, "vsock", || {
+            attach_unixsock_vsock_device(
+                &mut vmm,
+                vsock,
+                &vm_resources.fd_budget,
+                event_manager,
+                vsock_shm_region,
+                intc,
+            )
+        })?;
     }
+    for custom in vm_resources.custom_devices.list.iter() {
+        timed_attach(timeline, &custom.id, || {
+            attach_custom_device(
+                &mut vmm,
+                custom.id.clone(),
+                custom.device.clone(),
+                custom.subscriber.clone(),
+                event_manager,
+            )
+        })?;
+    }
+
+    timeline.record_stage("devices", started.elapsed());
+    Ok(DevicesStage { vmm, vcpus })
+}
+
+/// Runs `attach`, recording in `timeline` how long attaching the device `name` took.
+fn timed_attach<F>(
+    timeline: &BootTimeline,
+    name: &str,
+    attach: F,
+) -> std::result::Result<(), StartMicrovmError>
+where
+    F: FnOnce() -> std::result::Result<(), StartMicrovmError>,
+{
+    let started = Instant::now();
+    attach()?;
+    timeline.record_device(name, started.elapsed());
+    Ok(())
+}
+
+/// Removes `dir`, staged on the host for the guest, once the microVM is torn down.
+fn remove_on_teardown(vmm: &mut Vmm, dir: &Path) {
+    let dir = dir.to_path_buf();
+    vmm.teardown.push("staged directory", move || {
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            warn!("Unable to remove the staged {}: {}", dir.display(), e);
+        }
+    });
+}
+
+/// Last build stage: completes the kernel command line, configures the system for boot and
+/// starts the vCPUs.
+pub fn start_microvm(
+    vm_resources: &super::resources::VmResources,
+    devices_stage: DevicesStage,
+    event_manager: &mut EventManager,
+) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
+    let DevicesStage { mut vmm, mut vcpus } = devices_stage;
+    let started = Instant::now();
 
     if let Some(s) = &vm_resources.boot_config.kernel_cmdline_epilog {
         vmm.kernel_cmdline.insert_str(s).unwrap();
@@ -461,19 +1105,121 @@ pub fn build_microvm(
 
     vmm.configure_system(vcpus.as_slice(), &None)
         .map_err(StartMicrovmError::Internal)?;
+    if let Some(callback) = &vm_resources.workload_started {
+        for vcpu in vcpus.iter_mut() {
+            vcpu.set_workload_started_callback(callback.clone());
+        }
+    }
+    for vcpu in vcpus.iter_mut() {
+        vcpu.set_boot_timeline(vm_resources.boot_timeline.clone());
+    }
+    let probe_reports = ProbeReports::default();
+    if !vm_resources.probes.is_empty() {
+        for vcpu in vcpus.iter_mut() {
+            vcpu.set_probe_reports(probe_reports.clone());
+        }
+    }
+    if !vm_resources.services.is_empty() {
+        let monitor = ServiceMonitor::new(
+            &vm_resources.services,
+            vm_resources.service_callback.clone(),
+        );
+        for vcpu in vcpus.iter_mut() {
+            vcpu.set_service_monitor(monitor.clone());
+        }
+    }
     vmm.start_vcpus(vcpus)
         .map_err(StartMicrovmError::Internal)?;
+    vm_resources
+        .vm_state
+        .transition(VmState::Running)
+        .map_err(StartMicrovmError::VmState)?;
+    vmm.start_probes(
+        &vm_resources.probes,
+        &probe_reports,
+        vm_resources.probe_callback.as_ref(),
+    )
+    .map_err(StartMicrovmError::Internal)?;
+    if let Some(runtime_limit) = vm_resources.runtime_limit {
+        vmm.start_runtime_limit(runtime_limit)
+            .map_err(StartMicrovmError::Internal)?;
+    }
+    if let Some(idle_policy) = vm_resources.idle_policy {
+        #[cfg(target_os = "linux")]
+        vmm.start_idle_monitor(idle_policy)
+            .map_err(StartMicrovmError::Internal)?;
+        #[cfg(target_os = "macos")]
+        warn!(
+            "Ignoring the idle policy {:?}, not supported on this platform",
+            idle_policy
+        );
+    }
+    if let Some(profiling) = vm_resources.profiling {
+        #[cfg(target_os = "linux")]
+        vmm.start_profiler(profiling, vm_resources.profile_reports.clone())
+            .map_err(StartMicrovmError::Internal)?;
+        #[cfg(target_os = "macos")]
+        warn!(
+            "Ignoring the profiling {:?}, not supported on this platform",
+            profiling
+        );
+    }
 
     let vmm = Arc::new(Mutex::new(vmm));
     event_manager
         .add_subscriber(vmm.clone())
         .map_err(StartMicrovmError::RegisterEvent)?;
+    set_device_panic_handler(&vm_resources.device_panic, vmm.clone(), event_manager);
 
+    vm_resources
+        .boot_timeline
+        .record_stage("start", started.elapsed());
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
-#[cfg(target_os = "linux")]
+/// Handles the panics of the devices, caught by the event manager, according to `config`.
+fn set_device_panic_handler(
+    config: &DevicePanicConfig,
+    vmm: Arc<Mutex<Vmm>>,
+    event_manager: &mut EventManager,
+) {
+    let policy = config.policy;
+    let callback = config.callback.clone();
+    event_manager.set_panic_handler(Box::new(move |panic: &SubscriberPanic| {
+        // The Vmm can't be detached from its own event loop.
+        let detached =
+            policy == DevicePanicPolicy::DetachDevice && panic.subscriber != VMM_SUBSCRIBER_NAME;
+        error!(
+            "Device {} panicked: {}, {}",
+            panic.subscriber,
+            panic.message,
+            if detached {
+                "detaching it"
+            } else {
+                "stopping the microVM"
+            }
+        );
+        if let Some(callback) = &callback {
+            callback(&DeviceFailure {
+                device: panic.subscriber.clone(),
+                message: panic.message.clone(),
+                detached,
+            });
+        }
+
+        if detached {
+            return PanicPolicy::Detach;
+        }
+        // The lock is poisoned if the Vmm itself panicked.
+        vmm.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stop(i32::from(FC_EXIT_CODE_DEVICE_FAILURE));
+        PanicPolicy::Stop
+    }));
+}
+
+/// Creates GuestMemory of `mem_size_mib` MiB in size. The kernel bundle is mapped in place, at
+/// `kernel_load_addr`, instead of being copied into guest memory.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     kernel_region: MmapRegion,
@@ -497,27 +1243,6 @@ pub fn create_guest_memory(
     ))
 }
 
-#[cfg(target_os = "macos")]
-pub fn create_guest_memory(
-    mem_size_mib: usize,
-    kernel_region: MmapRegion,
-    kernel_load_addr: u64,
-    kernel_size: usize,
-) -> std::result::Result<(GuestMemoryMmap, ArchMemoryInfo), StartMicrovmError> {
-    let mem_size = mem_size_mib << 20;
-    let (arch_mem_info, arch_mem_regions) =
-        arch::arch_memory_regions(mem_size, kernel_load_addr, kernel_size);
-
-    let guest_mem = GuestMemoryMmap::from_ranges(&arch_mem_regions)
-        .map_err(StartMicrovmError::GuestMemoryMmap)?;
-
-    let kernel_data = unsafe { std::slice::from_raw_parts(kernel_region.as_ptr(), kernel_size) };
-    guest_mem
-        .write(kernel_data, GuestAddress(kernel_load_addr as u64))
-        .unwrap();
-    Ok((guest_mem, arch_mem_info))
-}
-
 #[cfg(target_arch = "x86_64")]
 fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
     kernel::loader::load_cmdline(
@@ -533,21 +1258,38 @@ fn load_cmdline(vmm: &Vmm) -> std::result::Result<(), StartMicrovmError> {
 #[cfg(target_os = "linux")]
 pub(crate) fn setup_vm(
     guest_memory: &GuestMemoryMmap,
+    kvm_tuning: &KvmTuningConfig,
+    latency_profile: LatencyProfile,
 ) -> std::result::Result<Vm, StartMicrovmError> {
     let kvm = KvmContext::new()
         .map_err(Error::KvmContext)
         .map_err(StartMicrovmError::Internal)?;
+    let kvm_tuning = &latency_profile.kvm_tuning(kvm_tuning, kvm.supports_halt_poll());
     let mut vm = Vm::new(kvm.fd())
         .map_err(Error::Vm)
         .map_err(StartMicrovmError::Internal)?;
+    vm.set_memslot_packing(kvm_tuning.memslot_packing);
+    #[cfg(target_arch = "x86_64")]
+    vm.set_timer_policy(kvm_tuning.timer_policy);
     vm.memory_init(&guest_memory, kvm.max_memslots())
         .map_err(Error::Vm)
         .map_err(StartMicrovmError::Internal)?;
+    vm.tune(&kvm, kvm_tuning)
+        .map_err(Error::Vm)
+        .map_err(StartMicrovmError::Internal)?;
+    if kvm_tuning.mlock {
+        vm.lock_memory(&guest_memory)
+            .map_err(Error::Vm)
+            .map_err(StartMicrovmError::Internal)?;
+    }
     Ok(vm)
 }
+/// The tunings and the latency profile are specific to KVM, so they're left to their defaults.
 #[cfg(target_os = "macos")]
 pub(crate) fn setup_vm(
     guest_memory: &GuestMemoryMmap,
+    _kvm_tuning: &KvmTuningConfig,
+    _latency_profile: LatencyProfile,
 ) -> std::result::Result<Vm, StartMicrovmError> {
     let mut vm = Vm::new()
         .map_err(Error::Vm)
@@ -700,11 +1442,11 @@ fn create_vcpus_x86_64(
         )
         .map_err(Error::Vcpu)?;
 
-        vcpu.configure_x86_64(guest_mem, entry_addr, vcpu_config)
-            .map_err(Error::Vcpu)?;
-
         vcpus.push(vcpu);
     }
+    configure_vcpus(&mut vcpus, |vcpu| {
+        vcpu.configure_x86_64(guest_mem, entry_addr, vcpu_config)
+    })?;
     Ok(vcpus)
 }
 
@@ -719,7 +1461,7 @@ fn create_vcpus_aarch64(
 ) -> super::Result<Vec<Vcpu>> {
     let mut vcpus = Vec::with_capacity(vcpu_config.vcpu_count as usize);
     for cpu_index in 0..vcpu_config.vcpu_count {
-        let mut vcpu = Vcpu::new_aarch64(
+        let vcpu = Vcpu::new_aarch64(
             cpu_index,
             vm.fd(),
             exit_evt.try_clone().map_err(Error::EventFd)?,
@@ -727,11 +1469,11 @@ fn create_vcpus_aarch64(
         )
         .map_err(Error::Vcpu)?;
 
-        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr)
-            .map_err(Error::Vcpu)?;
-
         vcpus.push(vcpu);
     }
+    configure_vcpus(&mut vcpus, |vcpu| {
+        vcpu.configure_aarch64(vm.fd(), guest_mem, entry_addr)
+    })?;
     Ok(vcpus)
 }
 
@@ -777,11 +1519,59 @@ fn create_vcpus_aarch64(
     Ok(vcpus)
 }
 
+/// Configures the vCPUs with `configure`, concurrently on microVMs with enough of them, as the
+/// setup of each one takes a series of ioctls that otherwise add up. The vCPUs are still created
+/// one after the other beforehand, in the order of their ids.
+#[cfg(target_os = "linux")]
+fn configure_vcpus<F>(vcpus: &mut [Vcpu], configure: F) -> super::Result<()>
+where
+    F: Fn(&mut Vcpu) -> vstate::Result<()> + Sync,
+{
+    if vcpus.len() < PARALLEL_VCPU_SETUP_THRESHOLD {
+        return vcpus
+            .iter_mut()
+            .try_for_each(|vcpu| configure(vcpu).map_err(Error::Vcpu));
+    }
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = vcpus.len().div_ceil(threads);
+    let configure = &configure;
+    thread::scope(|scope| {
+        let handles: Vec<_> = vcpus
+            .chunks_mut(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter_mut().try_for_each(configure)))
+            .collect();
+        handles.into_iter().try_for_each(|handle| {
+            handle
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                .map_err(Error::Vcpu)
+        })
+    })
+}
+
+/// Places the restricted DMA pool of `size` bytes in the guest memory, returning its start and
+/// size. Only the device tree of Linux on aarch64 can tell the guest about it.
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+fn restricted_dma_pool(vmm: &Vmm, size: u64) -> std::result::Result<(u64, u64), StartMicrovmError> {
+    arch::aarch64::restricted_dma_pool(&vmm.arch_memory_info, size)
+        .map(|start| (start, size))
+        .ok_or(StartMicrovmError::RestrictedDmaPool)
+}
+
+#[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+fn restricted_dma_pool(
+    _vmm: &Vmm,
+    _size: u64,
+) -> std::result::Result<(u64, u64), StartMicrovmError> {
+    Err(StartMicrovmError::RestrictedDmaPool)
+}
+
 /// Attaches an MmioTransport device to the device manager.
 fn attach_mmio_device(
     vmm: &mut Vmm,
     id: String,
-    device: MmioTransport,
+    mut device: MmioTransport,
 ) -> std::result::Result<(), device_manager::mmio::Error> {
     let type_id = device
         .device()
@@ -789,6 +1579,38 @@ fn attach_mmio_device(
         .expect("Poisoned device lock")
         .device_type();
     let _cmdline = &mut vmm.kernel_cmdline;
+    let audit_id = id.clone();
+
+    if let Some(recorder) = &vmm.virtio_recorder {
+        let mut locked_device = device.device().lock().expect("Poisoned device lock");
+        for (index, queue) in locked_device.queues_mut().iter_mut().enumerate() {
+            queue.set_recorder(Some(QueueRecorder::new(
+                recorder.clone(),
+                type_id,
+                index as u16,
+            )));
+        }
+    }
+
+    if let Some(watermarks) = &vmm.queue_watermarks {
+        if type_id == TYPE_CONSOLE || type_id == TYPE_VSOCK {
+            let mut locked_device = device.device().lock().expect("Poisoned device lock");
+            for (index, queue) in locked_device.queues_mut().iter_mut().enumerate() {
+                queue.set_watermark(Some(QueueWatermark::new(
+                    watermarks.clone(),
+                    type_id,
+                    index as u16,
+                )));
+            }
+        }
+    }
+
+    if let Some((start, size)) = vmm.dma_pool {
+        device.set_dma_windows(Some(DmaWindows::new(vec![DmaWindow {
+            start: GuestAddress(start),
+            size,
+        }])));
+    }
 
     #[cfg(target_os = "linux")]
     let (_mmio_base, _irq) =
@@ -803,20 +1625,55 @@ fn attach_mmio_device(
     vmm.mmio_device_manager
         .add_device_to_cmdline(_cmdline, _mmio_base, _irq)?;
 
+    AUDIT.record(AuditEvent::DeviceAttached {
+        id: &audit_id,
+        device_type: type_id,
+    });
+
     Ok(())
 }
 
 fn attach_fs_devices(
     vmm: &mut Vmm,
     fs_devs: &FsBuilder,
+    worker_pool: &WorkerPoolConfig,
+    lookup_cache: LookupCacheConfig,
+    share_settings: &HashMap<String, ShareSettings>,
     event_manager: &mut EventManager,
     shm_region: Option<VirtioShmRegion>,
     intc: Option<Arc<Mutex<Gic>>>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
+    for tag in share_settings.keys() {
+        if !fs_devs
+            .list
+            .iter()
+            .any(|fs| fs.lock().unwrap().tag() == tag)
+        {
+            warn!("No fs device is tagged {}, ignoring its settings", tag);
+        }
+    }
+
+    // The fs devices share a single pool, each limited to its own number of requests in flight.
+    let pool = if worker_pool.threads > 0 && !fs_devs.list.is_empty() {
+        Some(
+            WorkerPool::new(
+                worker_pool.threads,
+                worker_pool.device_limit * fs_devs.list.len(),
+            )
+            .map_err(CreateWorkerPool)?,
+        )
+    } else {
+        None
+    };
+
     for fs in fs_devs.list.iter() {
         let id = String::from(fs.lock().unwrap().id());
+        let (tag, shared_dir) = {
+            let fs = fs.lock().unwrap();
+            (fs.tag().to_string(), fs.shared_dir().to_string())
+        };
 
         if let Some(ref intc) = intc {
             fs.lock().unwrap().set_intc(intc.clone());
@@ -826,6 +1683,34 @@ fn attach_fs_devices(
             fs.lock().unwrap().set_shm_region(shm.clone());
         }
 
+        fs.lock().unwrap().set_lookup_cache(lookup_cache);
+
+        if let Some(settings) = share_settings.get(&tag) {
+            let mut fs = fs.lock().unwrap();
+            if let Some(quota) = settings.quota {
+                fs.set_quota(quota);
+            }
+            fs.set_io_config(settings.io);
+            fs.set_escape_policy(settings.escape_policy);
+            if let Some(cache) = &settings.cache {
+                fs.set_cache(cache.clone());
+            }
+            // DAX is only supported on Linux.
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(dedup) = &settings.dedup {
+                    fs.set_dedup(dedup.clone());
+                }
+            }
+        }
+
+        if let Some(ref pool) = pool {
+            let group = pool
+                .group(worker_pool.device_limit)
+                .map_err(CreateWorkerPool)?;
+            fs.lock().unwrap().set_worker_group(group);
+        }
+
         event_manager
             .add_subscriber(fs.clone())
             .map_err(RegisterEvent)?;
@@ -837,40 +1722,129 @@ fn attach_fs_devices(
             MmioTransport::new(vmm.guest_memory().clone(), fs.clone()),
         )
         .map_err(RegisterFsDevice)?;
+
+        AUDIT.record(AuditEvent::ShareAttached {
+            tag: &tag,
+            host_path: &shared_dir,
+        });
     }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn attach_console_devices(
     vmm: &mut Vmm,
+    backend: &ConsoleBackend,
+    output_config: ConsoleOutputConfig,
+    terminal_config: TerminalConfig,
+    resize_source: &ResizeSource,
+    recorder: Option<&SessionRecorder>,
+    guest_panic: &GuestPanicConfig,
     event_manager: &mut EventManager,
     intc: Option<Arc<Mutex<Gic>>>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
 
+    let (input, output): (Box<dyn ReadableFd + Send>, Box<dyn io::Write + Send>) = match backend {
+        ConsoleBackend::Stdio => {
+            // Saved before the console sets it to raw mode.
+            let terminal = SavedTerminal::save(
+                io::stdin().as_raw_fd(),
+                io::stdout().as_raw_fd(),
+                terminal_config,
+            );
+            let stdin = SerialStdin::get();
+            if let Err(e) = terminal.enter(&mut io::stdout()) {
+                warn!("Cannot prepare the terminal for the console: {}", e);
+            }
+            vmm.teardown.push("terminal mode", move || {
+                if let Err(e) = terminal.restore(&mut io::stdout()) {
+                    warn!("Cannot restore the terminal: {}", e);
+                }
+            });
+            (Box::new(stdin), Box::new(io::stdout()))
+        }
+        ConsoleBackend::Callbacks { input, output } => {
+            (Box::new(input.clone()), Box::new(output.clone()))
+        }
+        ConsoleBackend::Socket(listener) => {
+            // The listener may have been handed over non-blocking.
+            let stream = listener
+                .set_nonblocking(false)
+                .and_then(|_| listener.accept())
+                .map(|(stream, _)| stream)
+                .map_err(AcceptConsole)?;
+            let input = stream.try_clone().map_err(AcceptConsole)?;
+            (Box::new(SocketConsoleInput(input)), Box::new(stream))
+        }
+        ConsoleBackend::Attach(attach) => {
+            if let Some(path) = attach.path() {
+                let path = path.clone();
+                vmm.teardown.push("console socket", move || {
+                    let _ = fs::remove_file(&path);
+                });
+            }
+            (Box::new(attach.input()), Box::new(attach.clone()))
+        }
+    };
+
     let console = Arc::new(Mutex::new(
-        devices::virtio::Console::new(Box::new(SerialStdin::get()), Box::new(io::stdout()))
-            .unwrap(),
+        devices::virtio::Console::new(input, output).unwrap(),
     ));
 
+    console.lock().unwrap().set_output_config(output_config);
+    if let Some(recorder) = recorder {
+        console.lock().unwrap().set_recorder(recorder.clone());
+    }
+    if guest_panic.scan_console {
+        let callback = guest_panic.callback.clone();
+        console
+            .lock()
+            .unwrap()
+            .set_panic_scanner(Box::new(move |panic: &GuestPanic| {
+                error!("The guest kernel failed: {}", panic.message);
+                if let Some(callback) = &callback {
+                    callback(panic);
+                }
+            }));
+    }
+    if let ConsoleBackend::Attach(attach) = backend {
+        let mut console = console.lock().unwrap();
+        let size_source = attach.clone();
+        console.set_size_provider(Box::new(move || size_source.size()));
+        attach.set_resize_evt(
+            console
+                .sigwinch_evt()
+                .map_err(|e| Internal(Error::EventFd(e)))?,
+        );
+    }
     if let Some(intc) = intc {
         console.lock().unwrap().set_intc(intc);
     }
 
     // Stdin may not be pollable (i.e. when running a container without "-i"). If that's
     // the case, disable the interactive mode in the console.
-    if !event_manager.is_pollable(io::stdin().as_raw_fd()) {
-        console.lock().unwrap().set_interactive(false)
+    if let ConsoleBackend::Stdio = backend {
+        if !event_manager.is_pollable(io::stdin().as_raw_fd()) {
+            console.lock().unwrap().set_interactive(false)
+        }
     }
 
     event_manager
         .add_subscriber(console.clone())
         .map_err(RegisterEvent)?;
 
-    #[cfg(target_os = "linux")]
-    register_sigwinch_handler(console.lock().unwrap().get_sigwinch_fd())
-        .map_err(RegisterFsSigwinch)?;
+    let console_evt = console
+        .lock()
+        .unwrap()
+        .sigwinch_evt()
+        .map_err(|e| Internal(Error::EventFd(e)))?;
+    let resize_forwarder =
+        ResizeForwarder::new(resize_source, console_evt).map_err(RegisterConsoleResize)?;
+    event_manager
+        .add_subscriber(Arc::new(Mutex::new(resize_forwarder)))
+        .map_err(RegisterEvent)?;
 
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(
@@ -883,10 +1857,218 @@ fn attach_console_devices(
     Ok(())
 }
 
+/// Attaches a console the guest only writes its logs to, framed by `write_log_record`. Being
+/// attached right after the interactive console, it's the second one the guest finds.
+fn attach_log_channel_device(
+    vmm: &mut Vmm,
+    config: &LogChannelConfig,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    // The guest never reads from the channel, so its input is never fed.
+    let input = CallbackInput::new().map_err(|e| Internal(Error::EventFd(e)))?;
+    let callback = config.callback.clone();
+    let output = LogRecordDecoder::new(move |record: &[u8]| callback(record));
+
+    let console = Arc::new(Mutex::new(
+        devices::virtio::Console::new(Box::new(input), Box::new(output)).unwrap(),
+    ));
+    console.lock().unwrap().set_id("log_channel");
+    console.lock().unwrap().set_interactive(false);
+    if let Some(intc) = intc {
+        console.lock().unwrap().set_intc(intc);
+    }
+
+    event_manager
+        .add_subscriber(console.clone())
+        .map_err(RegisterEvent)?;
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        "hvc1".to_string(),
+        MmioTransport::new(vmm.guest_memory().clone(), console),
+    )
+    .map_err(RegisterLogChannel)?;
+
+    Ok(())
+}
+
+/// Attaches a console sharing `clipboard` with the agent of the guest. Being attached right after
+/// the log channel, if any, it's the device `clipboard_guest_device` returns.
+fn attach_clipboard_device(
+    vmm: &mut Vmm,
+    clipboard: &Clipboard,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let receiver = clipboard.clone();
+    let output = LogRecordDecoder::with_max_size(clipboard.max_size(), move |contents: &[u8]| {
+        receiver.copied_in_guest(contents)
+    });
+
+    let console = Arc::new(Mutex::new(
+        devices::virtio::Console::new(Box::new(clipboard.input()), Box::new(output)).unwrap(),
+    ));
+    // Unlike the log channel, the input is registered, carrying the contents copied on the host.
+    console.lock().unwrap().set_id("clipboard");
+    if let Some(intc) = intc {
+        console.lock().unwrap().set_intc(intc);
+    }
+
+    event_manager
+        .add_subscriber(console.clone())
+        .map_err(RegisterEvent)?;
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        "clipboard".to_string(),
+        MmioTransport::new(vmm.guest_memory().clone(), console),
+    )
+    .map_err(RegisterClipboard)?;
+
+    Ok(())
+}
+
+/// Attaches a console carrying the requests of `fsfreeze` to init, and its replies. Being attached
+/// right after the serial devices passed through, it's the device `fsfreeze_device` returns.
+fn attach_fsfreeze_device(
+    vmm: &mut Vmm,
+    fsfreeze: &FsFreeze,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let console = Arc::new(Mutex::new(
+        devices::virtio::Console::new(Box::new(fsfreeze.input()), Box::new(fsfreeze.output()))
+            .unwrap(),
+    ));
+    console.lock().unwrap().set_id("fsfreeze");
+    if let Some(intc) = intc {
+        console.lock().unwrap().set_intc(intc);
+    }
+
+    event_manager
+        .add_subscriber(console.clone())
+        .map_err(RegisterEvent)?;
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        "fsfreeze".to_string(),
+        MmioTransport::new(vmm.guest_memory().clone(), console),
+    )
+    .map_err(RegisterFsFreeze)?;
+
+    Ok(())
+}
+
+/// Attaches a console carrying the queries of `guest_network` to init, and its replies. Being
+/// attached right after the one freezing the filesystems, if any, it's the device
+/// `guest_network_device` returns.
+fn attach_guest_network_device(
+    vmm: &mut Vmm,
+    guest_network: &GuestNetwork,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let console = Arc::new(Mutex::new(
+        devices::virtio::Console::new(
+            Box::new(guest_network.input()),
+            Box::new(guest_network.output()),
+        )
+        .unwrap(),
+    ));
+    console.lock().unwrap().set_id("guest_network");
+    if let Some(intc) = intc {
+        console.lock().unwrap().set_intc(intc);
+    }
+
+    event_manager
+        .add_subscriber(console.clone())
+        .map_err(RegisterEvent)?;
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        "guest_network".to_string(),
+        MmioTransport::new(vmm.guest_memory().clone(), console),
+    )
+    .map_err(RegisterGuestNetwork)?;
+
+    Ok(())
+}
+
+/// Binds each character device of the host in `serials` to a console of its own. Being attached
+/// right after the clipboard, if any, they're the devices `serial_passthrough_devices` returns.
+fn attach_serial_passthrough_devices(
+    vmm: &mut Vmm,
+    serials: &[SerialPassthroughConfig],
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    for config in serials {
+        let serial =
+            HostSerial::open(config).map_err(|e| OpenSerialPassthrough(config.path.clone(), e))?;
+        let streams = serial
+            .input()
+            .and_then(|input| Ok((input, serial.output()?)));
+        // Given back even if the build fails from here on.
+        let path = config.path.clone();
+        vmm.teardown.push("serial device", move || {
+            if let Err(e) = serial.release() {
+                warn!(
+                    "Unable to restore the serial device {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        });
+        let (input, output) = streams.map_err(|e| OpenSerialPassthrough(config.path.clone(), e))?;
+
+        let console = Arc::new(Mutex::new(
+            devices::virtio::Console::new(Box::new(input), Box::new(output)).unwrap(),
+        ));
+        let id = format!("serial_{}", config.name);
+        console.lock().unwrap().set_id(&id);
+        // The device may be unplugged while the microVM runs.
+        console.lock().unwrap().set_exit_on_hangup(false);
+        if let Some(intc) = intc.clone() {
+            console.lock().unwrap().set_intc(intc);
+        }
+
+        event_manager
+            .add_subscriber(console.clone())
+            .map_err(RegisterEvent)?;
+
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_mmio_device(
+            vmm,
+            id,
+            MmioTransport::new(vmm.guest_memory().clone(), console),
+        )
+        .map_err(RegisterSerialPassthrough)?;
+    }
+
+    Ok(())
+}
+
 fn attach_unixsock_vsock_device(
     vmm: &mut Vmm,
     unix_vsock: &Arc<Mutex<Vsock<VsockUnixBackend>>>,
+    fd_budget: &FdBudget,
     event_manager: &mut EventManager,
+    shm_region: Option<VirtioShmRegion>,
     intc: Option<Arc<Mutex<Gic>>>,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
@@ -896,11 +2078,19 @@ fn attach_unixsock_vsock_device(
         .map_err(RegisterEvent)?;
 
     let id = String::from(unix_vsock.lock().unwrap().id());
+    unix_vsock
+        .lock()
+        .unwrap()
+        .set_fd_account(fd_budget.account(&id));
 
     if let Some(intc) = intc {
         unix_vsock.lock().unwrap().set_intc(intc);
     }
 
+    if let Some(shm) = shm_region {
+        unix_vsock.lock().unwrap().set_shm_region(shm);
+    }
+
     // The device mutex mustn't be locked here otherwise it will deadlock.
     attach_mmio_device(
         vmm,
@@ -912,6 +2102,32 @@ fn attach_unixsock_vsock_device(
     Ok(())
 }
 
+fn attach_custom_device(
+    vmm: &mut Vmm,
+    id: String,
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    subscriber: Option<Arc<Mutex<dyn Subscriber + Send>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    if let Some(subscriber) = subscriber {
+        event_manager
+            .add_subscriber(subscriber)
+            .map_err(RegisterEvent)?;
+    }
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        id,
+        MmioTransport::new(vmm.guest_memory().clone(), device),
+    )
+    .map_err(RegisterCustomDevice)?;
+
+    Ok(())
+}
+
 fn attach_balloon_device(
     vmm: &mut Vmm,
     event_manager: &mut EventManager,
@@ -935,9 +2151,164 @@ fn attach_balloon_device(
     attach_mmio_device(
         vmm,
         id,
-        MmioTransport::new(vmm.guest_memory().clone(), balloon),
+        MmioTransport::new(vmm.guest_memory().clone(), balloon.clone()),
     )
     .map_err(RegisterBalloonDevice)?;
+    vmm.set_balloon(balloon);
+
+    Ok(())
+}
+
+fn attach_crypto_device(
+    vmm: &mut Vmm,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let crypto = Arc::new(Mutex::new(devices::virtio::Crypto::new().unwrap()));
+    {
+        let algos = crypto.lock().unwrap().algos().to_vec();
+        if algos.is_empty() {
+            warn!("No cipher is available from the host, the crypto device won't offer any");
+        } else {
+            debug!("Offering {:?} through the crypto device", algos);
+        }
+    }
+
+    event_manager
+        .add_subscriber(crypto.clone())
+        .map_err(RegisterEvent)?;
+
+    let id = String::from(crypto.lock().unwrap().id());
+
+    if let Some(intc) = intc {
+        crypto.lock().unwrap().set_intc(intc);
+    }
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        id,
+        MmioTransport::new(vmm.guest_memory().clone(), crypto),
+    )
+    .map_err(RegisterCryptoDevice)?;
+
+    Ok(())
+}
+
+fn attach_swap_device(
+    vmm: &mut Vmm,
+    swap: &SwapConfig,
+    block_devices: &BlockDevices,
+    event_manager: &mut EventManager,
+    intc: Option<Arc<Mutex<Gic>>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let disk = swap.create_file().map_err(OpenBlockDevice)?;
+    let block = devices::virtio::Block::new(SWAP_DEVICE_ID, disk).map_err(|e| match e {
+        BlockError::EventFd(e) | BlockError::DiskSize(e) => AttachBlockDevice(e),
+    })?;
+    debug!("Attaching a swap of {} bytes", block.disk_size());
+    let block = Arc::new(Mutex::new(block));
+
+    event_manager
+        .add_subscriber(block.clone())
+        .map_err(RegisterEvent)?;
+
+    let id = String::from(block.lock().unwrap().id());
+
+    if let Some(intc) = intc {
+        block.lock().unwrap().set_intc(intc);
+    }
+    block_devices.register(block.clone());
+
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_mmio_device(
+        vmm,
+        id,
+        MmioTransport::new(vmm.guest_memory().clone(), block),
+    )
+    .map_err(RegisterBlockDevice)?;
+
+    Ok(())
+}
+
+fn attach_tpm_device(
+    vmm: &mut Vmm,
+    tpm_config: &TpmConfig,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let backend = SwtpmBackend::connect(&tpm_config.socket_path).map_err(TpmBackend)?;
+    let tpm = Arc::new(Mutex::new(TpmTis::new(Box::new(backend))));
+    vmm.mmio_device_manager
+        .register_mmio_tpm(tpm)
+        .map_err(RegisterTpmDevice)?;
+
+    // Without an ACPI table describing the TPM, the driver only probes it when forced to, while
+    // the device tree describes it on aarch64. Either way, the device doesn't raise interrupts.
+    #[cfg(target_arch = "x86_64")]
+    vmm.kernel_cmdline
+        .insert("tpm_tis.force", "1")
+        .map_err(LoadCommandline)?;
+    vmm.kernel_cmdline
+        .insert("tpm_tis.interrupts", "0")
+        .map_err(LoadCommandline)?;
+
+    Ok(())
+}
+
+fn attach_user_device(
+    bus: &mut devices::Bus,
+    user_device: &UserDeviceConfig,
+    device_window: &DeviceWindowConfig,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    // The window may have been moved since the region was added.
+    user_device
+        .validate(device_window)
+        .map_err(RegisterUserDevice)?;
+    let device = Arc::new(Mutex::new(UserDevice::new(user_device.callback.clone())));
+    bus.insert(device, user_device.base, user_device.len)
+        .map_err(|_| RegisterUserDevice(UserDeviceError::Overlap(user_device.base)))
+}
+
+fn attach_secrets_mailbox(
+    bus: &mut devices::Bus,
+    mailbox: &Arc<Mutex<SecretsMailbox>>,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let ttl = {
+        let mut mailbox = mailbox.lock().unwrap();
+        if mailbox.is_empty() {
+            return Ok(());
+        }
+        mailbox.arm();
+        mailbox.ttl()
+    };
+
+    // The mailbox sits at a fixed address, known by init: next to the port the guest signals the
+    // VMM through on x86_64, and in the page preceding the MMIO devices on aarch64.
+    #[cfg(target_arch = "x86_64")]
+    let addr = arch::x86_64::layout::SECRETS_MAILBOX_PORT;
+    #[cfg(target_arch = "aarch64")]
+    let addr = arch::aarch64::layout::SECRETS_MAILBOX_START;
+    bus.insert(mailbox.clone(), addr, SECRETS_MAILBOX_SIZE)
+        .map_err(RegisterSecretsMailbox)?;
+
+    // Wipe the secrets once expired, even if the guest never looks at the mailbox.
+    let mailbox = mailbox.clone();
+    thread::Builder::new()
+        .name("secrets expiry".to_string())
+        .spawn(move || {
+            thread::sleep(ttl);
+            mailbox.lock().unwrap().expire();
+        })
+        .map_err(SecretsExpirySpawn)?;
 
     Ok(())
 }
@@ -949,8 +2320,11 @@ pub mod tests {
     use devices::virtio::TYPE_VSOCK;
     use kernel::cmdline::Cmdline;
     use polly::event_manager::EventManager;
+    use resources::VmResources;
     use utils::tempfile::TempFile;
+    use vm_memory::GuestMemory;
     use vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
+    use vmm_config::vm_state::VmStateHandle;
     use vmm_config::vsock::tests::{default_config, TempSockFile};
     use vmm_config::vsock::VsockBuilder;
 
@@ -967,7 +2341,7 @@ pub mod tests {
             Some(Arc::new(Mutex::new(Serial::new_sink(
                 EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
             )))),
-            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            true,
         )
         .unwrap()
     }
@@ -1001,7 +2375,12 @@ pub mod tests {
             .map_err(StartMicrovmError::Internal)
             .unwrap();
 
-        let vm = setup_vm(&guest_memory).unwrap();
+        let vm = setup_vm(
+            &guest_memory,
+            &KvmTuningConfig::default(),
+            LatencyProfile::Default,
+        )
+        .unwrap();
         let mmio_device_manager = default_mmio_device_manager();
         #[cfg(target_arch = "x86_64")]
         let pio_device_manager = default_portio_device_manager();
@@ -1014,9 +2393,24 @@ pub mod tests {
             vcpus_handles: Vec::new(),
             exit_evt,
             vm,
+            runtime_limit_evt: None,
+            runtime_limit_expirations: 0,
+            #[cfg(target_os = "linux")]
+            idle_monitor: None,
+            #[cfg(target_os = "linux")]
+            profiler: None,
             mmio_device_manager,
+            balloon: None,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            virtio_recorder: None,
+            queue_watermarks: None,
+            dma_pool: None,
+            exit_handle: None,
+            state: VmStateHandle::default(),
+            #[cfg(target_arch = "aarch64")]
+            cpu_topology: arch::CpuTopology::single_socket(1, false),
+            teardown: Teardown::new(),
         }
     }
 
@@ -1029,43 +2423,60 @@ pub mod tests {
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_create_vcpus_x86_64() {
-        let vcpu_count = 2;
-
-        let (guest_memory, _arch_memory_info) = default_guest_memory(128).unwrap();
-        let mut vm = setup_vm(&guest_memory).unwrap();
-        setup_interrupt_controller(&mut vm).unwrap();
-        let vcpu_config = VcpuConfig {
-            vcpu_count,
-            ht_enabled: false,
-            cpu_template: None,
-        };
-
-        // Dummy entry_addr, vcpus will not boot.
-        let entry_addr = GuestAddress(0);
-        let bus = devices::Bus::new();
-        let vcpu_vec = create_vcpus_x86_64(
-            &vm,
-            &vcpu_config,
-            &guest_memory,
-            entry_addr,
-            TimestampUs::default(),
-            &bus,
-            &EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
-        )
-        .unwrap();
-        assert_eq!(vcpu_vec.len(), vcpu_count as usize);
+        // Below and above the threshold of the concurrent configuration.
+        for vcpu_count in [2, 8] {
+            let (guest_memory, _arch_memory_info) = default_guest_memory(128).unwrap();
+            let mut vm = setup_vm(
+                &guest_memory,
+                &KvmTuningConfig::default(),
+                LatencyProfile::Default,
+            )
+            .unwrap();
+            setup_interrupt_controller(&mut vm).unwrap();
+            let vcpu_config = VcpuConfig {
+                vcpu_count,
+                ht_enabled: false,
+                topology: arch::CpuTopology::single_socket(vcpu_count, false),
+                cpu_template: None,
+            };
+
+            // Dummy entry_addr, vcpus will not boot.
+            let entry_addr = GuestAddress(0);
+            let bus = devices::Bus::new();
+            let vcpu_vec = create_vcpus_x86_64(
+                &vm,
+                &vcpu_config,
+                &guest_memory,
+                entry_addr,
+                TimestampUs::default(),
+                &bus,
+                &EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(vcpu_vec.len(), vcpu_count as usize);
+            assert!(vcpu_vec
+                .iter()
+                .enumerate()
+                .all(|(index, vcpu)| vcpu.cpu_index() as usize == index));
+        }
     }
 
     #[test]
     #[cfg(target_arch = "aarch64")]
     fn test_create_vcpus_aarch64() {
         let guest_memory = create_guest_memory(128).unwrap();
-        let vm = setup_vm(&guest_memory).unwrap();
+        let vm = setup_vm(
+            &guest_memory,
+            &KvmTuningConfig::default(),
+            LatencyProfile::Default,
+        )
+        .unwrap();
         let vcpu_count = 2;
 
         let vcpu_config = VcpuConfig {
             vcpu_count,
             ht_enabled: false,
+            topology: arch::CpuTopology::single_socket(vcpu_count, false),
             cpu_template: None,
         };
 
@@ -1100,7 +2511,15 @@ pub mod tests {
         let vsock_dev_id = vsock_config.vsock_id.clone();
         let vsock = VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
-        assert!(attach_unixsock_vsock_device(&mut vmm, &vsock, &mut event_manager, None).is_ok());
+        assert!(attach_unixsock_vsock_device(
+            &mut vmm,
+            &vsock,
+            &FdBudget::new(),
+            &mut event_manager,
+            None,
+            None
+        )
+        .is_ok());
 
         assert!(vmm
             .mmio_device_manager
@@ -1108,19 +2527,66 @@ pub mod tests {
             .is_some());
     }
 
+    #[test]
+    fn test_build_guest_memory_without_kernel() {
+        let vm_resources = VmResources::default();
+        assert!(matches!(
+            build_guest_memory(&vm_resources),
+            Err(StartMicrovmError::MissingKernelConfig)
+        ));
+    }
+
+    #[test]
+    fn test_guest_memory_stage_from_guest_memory() {
+        let vm_resources = VmResources::default();
+        let (guest_memory, arch_memory_info) = default_guest_memory(128).unwrap();
+        let ram_last_addr = arch_memory_info.ram_last_addr;
+        let num_regions = guest_memory.num_regions();
+
+        let memory_stage = GuestMemoryStage::from_guest_memory(
+            &vm_resources,
+            guest_memory,
+            arch_memory_info,
+            0x1000,
+        )
+        .unwrap();
+        assert_eq!(memory_stage.guest_memory().num_regions(), num_regions);
+        assert_eq!(memory_stage.arch_memory_info().ram_last_addr, ram_last_addr);
+        assert_eq!(memory_stage.kernel_guest_addr, 0x1000);
+        assert_eq!(vm_resources.vm_state.get(), VmState::Booting);
+
+        // The microVM is only built once.
+        let (guest_memory, arch_memory_info) = default_guest_memory(128).unwrap();
+        assert!(matches!(
+            GuestMemoryStage::from_guest_memory(
+                &vm_resources,
+                guest_memory,
+                arch_memory_info,
+                0x1000
+            ),
+            Err(StartMicrovmError::VmState(_))
+        ));
+    }
+
     #[test]
     fn test_error_messages() {
         use builder::StartMicrovmError::*;
+        let err = AcceptConsole(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
         let err = AttachBlockDevice(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
         let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = CreateWorkerPool(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
         let err = Internal(Error::Serial(io::Error::from_raw_os_error(0)));
         let _ = format!("{}{:?}", err, err);
 
-        let err = KernelCmdline(String::from("dummy --cmdline"));
+        let err = KernelCmdline(kernel::cmdline::Error::HasSpace);
         let _ = format!("{}{:?}", err, err);
 
         let err = KernelBundle(vm_memory::mmap::MmapRegionError::InvalidPointer);
@@ -1149,6 +2615,11 @@ pub mod tests {
         ));
         let _ = format!("{}{:?}", err, err);
 
+        let err = RegisterCustomDevice(device_manager::mmio::Error::EventFd(
+            io::Error::from_raw_os_error(0),
+        ));
+        let _ = format!("{}{:?}", err, err);
+
         let err = RegisterEvent(EventManagerError::EpollCreate(
             io::Error::from_raw_os_error(0),
         ));
@@ -1165,6 +2636,26 @@ pub mod tests {
         let _ = format!("{}{:?}", err, err);
     }
 
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as StdError;
+        use utils::error::ErrorChain;
+
+        let err = StartMicrovmError::RegisterFsDevice(device_manager::mmio::Error::BusError(
+            devices::BusError::Overlap,
+        ));
+        assert_eq!(err.code(), 21);
+        assert_eq!(
+            ErrorChain(&err).to_string(),
+            "Cannot initialize a MMIO Fs Device or add a device to the MMIO Bus: failed to \
+             perform bus operation: New device overlaps with an old device."
+        );
+        let mmio_err = err.source().unwrap();
+        assert!(mmio_err.source().unwrap().source().is_none());
+
+        assert!(StartMicrovmError::MissingKernelConfig.source().is_none());
+    }
+
     #[test]
     fn test_kernel_cmdline_err_to_startuvm_err() {
         let err = StartMicrovmError::from(kernel::cmdline::Error::HasSpace);
@@ -0,0 +1,138 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forwards the resizes of the terminal of the VMM to the console, from SIGWINCH or from a file
+//! descriptor of the embedder, polled by the event manager like any other event.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use polly::event_manager::{EventManager, Subscriber};
+use utils::epoll::{EpollEvent, EventSet};
+use utils::eventfd::EventFd;
+use utils::signalfd::SignalFd;
+use vmm_config::console::ResizeSource;
+
+enum Source {
+    Signal(SignalFd),
+    Fd(Arc<File>),
+}
+
+/// Tells the console about the resizes notified by a `ResizeSource`.
+pub struct ResizeForwarder {
+    source: Source,
+    console_evt: EventFd,
+}
+
+impl ResizeForwarder {
+    /// Creates a forwarder signaling `console_evt` whenever `source` notifies a resize.
+    pub fn new(source: &ResizeSource, console_evt: EventFd) -> io::Result<Self> {
+        let source = match source {
+            ResizeSource::Signal => Source::Signal(SignalFd::new(&[libc::SIGWINCH])?),
+            ResizeSource::Fd(file) => {
+                set_nonblocking(file.as_raw_fd())?;
+                Source::Fd(file.clone())
+            }
+        };
+        Ok(ResizeForwarder {
+            source,
+            console_evt,
+        })
+    }
+
+    fn source_fd(&self) -> RawFd {
+        match &self.source {
+            Source::Signal(signalfd) => signalfd.as_raw_fd(),
+            Source::Fd(file) => file.as_raw_fd(),
+        }
+    }
+
+    // Drains the notifications of the source, returning whether it's still open.
+    fn drain(&self) -> io::Result<bool> {
+        match &self.source {
+            Source::Signal(signalfd) => signalfd.read().map(|_| true),
+            Source::Fd(file) => {
+                let mut buf = [0u8; 64];
+                loop {
+                    match (&**file).read(&mut buf) {
+                        Ok(0) => return Ok(false),
+                        Ok(_) => (),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // Safe because we only change the flags of the fd, and check the return values.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Subscriber for ResizeForwarder {
+    fn process(&mut self, event: &EpollEvent, event_manager: &mut EventManager) {
+        match self.drain() {
+            Ok(true) => {
+                if let Err(e) = self.console_evt.write(1) {
+                    error!("Failed to notify the console of a resize: {:?}", e);
+                }
+            }
+            // Without a writer left, the fd would be reported readable forever.
+            Ok(false) => {
+                debug!("The source of the console resizes was closed");
+                if let Err(e) = event_manager.unregister(event.fd()) {
+                    error!("Failed to unregister the console resize source: {:?}", e);
+                }
+            }
+            Err(e) => error!("Failed to read the console resize source: {}", e),
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.source_fd() as u64)]
+    }
+
+    fn name(&self) -> String {
+        "console resize".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn test_forward_fd() {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the fds were just created, and are owned by the files alone.
+        let (reader, mut writer) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let console_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap();
+        let forwarder =
+            ResizeForwarder::new(&ResizeSource::Fd(Arc::new(reader)), console_evt).unwrap();
+        assert_eq!(forwarder.interest_list()[0].fd(), fds[0]);
+
+        // Notified twice before being polled, the resizes are drained at once.
+        writer.write_all(b"\x01\x01").unwrap();
+        assert!(forwarder.drain().unwrap());
+        assert!(forwarder.drain().unwrap());
+
+        drop(writer);
+        assert!(!forwarder.drain().unwrap());
+    }
+}
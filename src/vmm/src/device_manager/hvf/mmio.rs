@@ -11,6 +11,8 @@ use std::{fmt, io};
 
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::DeviceInfoForFDT;
+#[cfg(target_arch = "x86_64")]
+use arch::x86_64::layout::TPM_TIS_START;
 use arch::DeviceType;
 use devices;
 
@@ -46,11 +48,9 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::BusError(ref e) => write!(f, "failed to perform bus operation: {}", e),
-            Error::Cmdline(ref e) => {
-                write!(f, "unable to add device to kernel command line: {}", e)
-            }
-            Error::EventFd(ref e) => write!(f, "failed to create or clone event descriptor: {}", e),
+            Error::BusError(_) => write!(f, "failed to perform bus operation"),
+            Error::Cmdline(_) => write!(f, "unable to add device to kernel command line"),
+            Error::EventFd(_) => write!(f, "failed to create or clone event descriptor"),
             Error::IrqsExhausted => write!(f, "no more IRQs are available"),
             Error::RegisterIoEvent => write!(f, "failed to register IO event"),
             Error::RegisterIrqFd => write!(f, "failed to register irqfd"),
@@ -60,6 +60,17 @@ impl fmt::Display for Error {
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::BusError(ref e) => Some(e),
+            Error::Cmdline(ref e) => Some(e),
+            Error::EventFd(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 type Result<T> = ::std::result::Result<T, Error>;
 
 /// This represents the size of the mmio device specified to the kernel as a cmdline option
@@ -222,6 +233,35 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Register a MMIO TPM device. It doesn't have an IRQ, as the guest driver polls it.
+    pub fn register_mmio_tpm(&mut self, tpm: Arc<Mutex<devices::legacy::TpmTis>>) -> Result<()> {
+        self.bus
+            .insert(tpm, self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+
+        self.id_to_dev_info.insert(
+            (DeviceType::Tpm, "tpm".to_string()),
+            MMIODeviceInfo {
+                addr: self.mmio_base,
+                len: MMIO_LEN,
+                irq: 0,
+            },
+        );
+
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Register a MMIO TPM device at the address the guest driver probes.
+    pub fn register_mmio_tpm(&mut self, tpm: Arc<Mutex<devices::legacy::TpmTis>>) -> Result<()> {
+        self.bus
+            .insert(tpm, TPM_TIS_START, devices::legacy::TPM_TIS_SIZE)
+            .map_err(Error::BusError)
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
@@ -276,6 +316,7 @@ mod tests {
     use std::sync::atomic::AtomicUsize;
     use std::sync::Arc;
     use utils::errno;
+    use utils::error::ErrorChain;
     use utils::eventfd::EventFd;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
@@ -476,6 +517,10 @@ mod tests {
         );
         assert_eq!(
             format!("{}", e),
+            "unable to add device to kernel command line"
+        );
+        assert_eq!(
+            format!("{}", ErrorChain(&e)),
             format!(
                 "unable to add device to kernel command line: {}",
                 kernel_cmdline::Error::HasEquals
@@ -486,7 +531,10 @@ mod tests {
             "failed to update the mmio device"
         );
         assert_eq!(
-            format!("{}", Error::BusError(devices::BusError::Overlap)),
+            format!(
+                "{}",
+                ErrorChain(&Error::BusError(devices::BusError::Overlap))
+            ),
             format!(
                 "failed to perform bus operation: {}",
                 devices::BusError::Overlap
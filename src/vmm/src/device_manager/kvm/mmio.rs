@@ -11,6 +11,8 @@ use std::{fmt, io};
 
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::DeviceInfoForFDT;
+#[cfg(target_arch = "x86_64")]
+use arch::x86_64::layout::TPM_TIS_START;
 use arch::DeviceType;
 use devices;
 
@@ -44,20 +46,30 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::BusError(ref e) => write!(f, "failed to perform bus operation: {}", e),
-            Error::Cmdline(ref e) => {
-                write!(f, "unable to add device to kernel command line: {}", e)
-            }
-            Error::EventFd(ref e) => write!(f, "failed to create or clone event descriptor: {}", e),
+            Error::BusError(_) => write!(f, "failed to perform bus operation"),
+            Error::Cmdline(_) => write!(f, "unable to add device to kernel command line"),
+            Error::EventFd(_) => write!(f, "failed to create or clone event descriptor"),
             Error::IrqsExhausted => write!(f, "no more IRQs are available"),
-            Error::RegisterIoEvent(ref e) => write!(f, "failed to register IO event: {}", e),
-            Error::RegisterIrqFd(ref e) => write!(f, "failed to register irqfd: {}", e),
+            Error::RegisterIoEvent(_) => write!(f, "failed to register IO event"),
+            Error::RegisterIrqFd(_) => write!(f, "failed to register irqfd"),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
     }
 }
 
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::BusError(ref e) => Some(e),
+            Error::Cmdline(ref e) => Some(e),
+            Error::EventFd(ref e) => Some(e),
+            Error::RegisterIoEvent(ref e) | Error::RegisterIrqFd(ref e) => Some(e),
+            Error::IrqsExhausted | Error::DeviceNotFound | Error::UpdateFailed => None,
+        }
+    }
+}
+
 type Result<T> = ::std::result::Result<T, Error>;
 
 /// This represents the size of the mmio device specified to the kernel as a cmdline option
@@ -230,6 +242,35 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Register a MMIO TPM device. It doesn't have an IRQ, as the guest driver polls it.
+    pub fn register_mmio_tpm(&mut self, tpm: Arc<Mutex<devices::legacy::TpmTis>>) -> Result<()> {
+        self.bus
+            .insert(tpm, self.mmio_base, MMIO_LEN)
+            .map_err(Error::BusError)?;
+
+        self.id_to_dev_info.insert(
+            (DeviceType::Tpm, "tpm".to_string()),
+            MMIODeviceInfo {
+                addr: self.mmio_base,
+                len: MMIO_LEN,
+                irq: 0,
+            },
+        );
+
+        self.mmio_base += MMIO_LEN;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    /// Register a MMIO TPM device at the address the guest driver probes.
+    pub fn register_mmio_tpm(&mut self, tpm: Arc<Mutex<devices::legacy::TpmTis>>) -> Result<()> {
+        self.bus
+            .insert(tpm, TPM_TIS_START, devices::legacy::TPM_TIS_SIZE)
+            .map_err(Error::BusError)
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
@@ -284,6 +325,7 @@ mod tests {
     use std::sync::atomic::AtomicUsize;
     use std::sync::Arc;
     use utils::errno;
+    use utils::error::ErrorChain;
     use utils::eventfd::EventFd;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
@@ -397,7 +439,8 @@ mod tests {
         let start_addr2 = GuestAddress(0x1000);
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
-        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut vm =
+            builder::setup_vm(&guest_mem, &Default::default(), Default::default()).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
 
@@ -419,7 +462,8 @@ mod tests {
         let start_addr2 = GuestAddress(0x1000);
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
-        let mut vm = builder::setup_vm(&guest_mem).unwrap();
+        let mut vm =
+            builder::setup_vm(&guest_mem, &Default::default(), Default::default()).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
 
@@ -486,6 +530,10 @@ mod tests {
         );
         assert_eq!(
             format!("{}", e),
+            "unable to add device to kernel command line"
+        );
+        assert_eq!(
+            format!("{}", ErrorChain(&e)),
             format!(
                 "unable to add device to kernel command line: {}",
                 kernel_cmdline::Error::HasEquals
@@ -496,7 +544,10 @@ mod tests {
             "failed to update the mmio device"
         );
         assert_eq!(
-            format!("{}", Error::BusError(devices::BusError::Overlap)),
+            format!(
+                "{}",
+                ErrorChain(&Error::BusError(devices::BusError::Overlap))
+            ),
             format!(
                 "failed to perform bus operation: {}",
                 devices::BusError::Overlap
@@ -507,11 +558,17 @@ mod tests {
             "no more IRQs are available"
         );
         assert_eq!(
-            format!("{}", Error::RegisterIoEvent(errno::Error::new(0))),
+            format!(
+                "{}",
+                ErrorChain(&Error::RegisterIoEvent(errno::Error::new(0)))
+            ),
             format!("failed to register IO event: {}", errno::Error::new(0))
         );
         assert_eq!(
-            format!("{}", Error::RegisterIrqFd(errno::Error::new(0))),
+            format!(
+                "{}",
+                ErrorChain(&Error::RegisterIrqFd(errno::Error::new(0)))
+            ),
             format!("failed to register irqfd: {}", errno::Error::new(0))
         );
     }
@@ -522,7 +579,7 @@ mod tests {
         let start_addr2 = GuestAddress(0x1000);
         let guest_mem =
             GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
-        let vm = builder::setup_vm(&guest_mem).unwrap();
+        let vm = builder::setup_vm(&guest_mem, &Default::default(), Default::default()).unwrap();
         let mut device_manager =
             MMIODeviceManager::new(&mut 0xd000_0000, (arch::IRQ_BASE, arch::IRQ_MAX));
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
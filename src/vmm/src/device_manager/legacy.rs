@@ -26,8 +26,19 @@ impl fmt::Display for Error {
         use self::Error::*;
 
         match *self {
-            BusError(ref err) => write!(f, "Failed to add legacy device to Bus: {}", err),
-            EventFd(ref err) => write!(f, "Failed to create EventFd: {}", err),
+            BusError(_) => write!(f, "Failed to add legacy device to Bus"),
+            EventFd(_) => write!(f, "Failed to create EventFd"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+
+        match *self {
+            BusError(ref err) => Some(err),
+            EventFd(ref err) => Some(err),
         }
     }
 }
@@ -35,23 +46,28 @@ impl fmt::Display for Error {
 type Result<T> = ::std::result::Result<T, Error>;
 
 /// The `PortIODeviceManager` is a wrapper that is used for registering legacy devices
-/// on an I/O Bus. It currently manages the uart and i8042 devices.
+/// on an I/O Bus. It currently manages the uart, the reset control and the optional i8042
+/// devices.
 /// The `LegacyDeviceManger` should be initialized only by using the constructor.
 pub struct PortIODeviceManager {
     pub io_bus: devices::Bus,
     pub stdio_serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
-    pub i8042: Arc<Mutex<devices::legacy::I8042Device>>,
+    /// Left out for guests that don't need PS/2 emulation.
+    pub i8042: Option<Arc<Mutex<devices::legacy::I8042Device>>>,
 
     pub com_evt_1_3: EventFd,
     pub com_evt_2_4: EventFd,
     pub kbd_evt: EventFd,
+    /// Signaled when the guest resets the machine, through the reset control or the i8042.
+    pub reset_evt: EventFd,
 }
 
 impl PortIODeviceManager {
-    /// Create a new DeviceManager handling legacy devices (uart, i8042).
+    /// Create a new DeviceManager handling legacy devices (uart, reset control and, if `i8042`,
+    /// the i8042).
     pub fn new(
         stdio_serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
-        i8042_reset_evfd: EventFd,
+        i8042: bool,
     ) -> Result<Self> {
         let io_bus = devices::Bus::new();
         let com_evt_1_3 = if let Some(serial) = &stdio_serial {
@@ -66,11 +82,16 @@ impl PortIODeviceManager {
         };
         let com_evt_2_4 = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
         let kbd_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let reset_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
 
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
-            i8042_reset_evfd,
-            kbd_evt.try_clone().map_err(Error::EventFd)?,
-        )));
+        let i8042 = if i8042 {
+            Some(Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+                reset_evt.try_clone().map_err(Error::EventFd)?,
+                kbd_evt.try_clone().map_err(Error::EventFd)?,
+            ))))
+        } else {
+            None
+        };
 
         Ok(PortIODeviceManager {
             io_bus,
@@ -79,6 +100,7 @@ impl PortIODeviceManager {
             com_evt_1_3,
             com_evt_2_4,
             kbd_evt,
+            reset_evt,
         })
     }
 
@@ -117,8 +139,19 @@ impl PortIODeviceManager {
             )
             .map_err(Error::BusError)?;
         self.io_bus
-            .insert(self.i8042.clone(), 0x060, 0x5)
+            .insert(
+                Arc::new(Mutex::new(devices::legacy::ResetControl::new(
+                    self.reset_evt.try_clone().map_err(Error::EventFd)?,
+                ))),
+                devices::legacy::RESET_CONTROL_PORT,
+                0x1,
+            )
             .map_err(Error::BusError)?;
+        if let Some(i8042) = &self.i8042 {
+            self.io_bus
+                .insert(i8042.clone(), 0x060, 0x5)
+                .map_err(Error::BusError)?;
+        }
         Ok(())
     }
 }
@@ -126,30 +159,48 @@ impl PortIODeviceManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use utils::error::ErrorChain;
 
     #[test]
     fn test_register_legacy_devices() {
         let serial =
             devices::legacy::Serial::new_sink(EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap());
-        let ldm = PortIODeviceManager::new(
-            Some(Arc::new(Mutex::new(serial))),
-            EventFd::new(utils::eventfd::EFD_NONBLOCK).unwrap(),
-        );
+        let ldm = PortIODeviceManager::new(Some(Arc::new(Mutex::new(serial))), true);
         assert!(ldm.is_ok());
         assert!(&ldm.unwrap().register_devices().is_ok());
     }
 
+    #[test]
+    fn test_register_without_i8042() {
+        let mut ldm = PortIODeviceManager::new(None, false).unwrap();
+        assert!(ldm.i8042.is_none());
+        ldm.register_devices().unwrap();
+
+        // The guest can still reset the machine through the reset control.
+        ldm.io_bus.write(0, 0x64, &[0xfe]);
+        assert!(ldm.reset_evt.read().is_err());
+        ldm.io_bus
+            .write(0, devices::legacy::RESET_CONTROL_PORT, &[0x06]);
+        assert_eq!(ldm.reset_evt.read().unwrap(), 1);
+    }
+
     #[test]
     fn test_debug_error() {
         assert_eq!(
-            format!("{}", Error::BusError(devices::BusError::Overlap)),
+            format!(
+                "{}",
+                ErrorChain(&Error::BusError(devices::BusError::Overlap))
+            ),
             format!(
                 "Failed to add legacy device to Bus: {}",
                 devices::BusError::Overlap
             )
         );
         assert_eq!(
-            format!("{}", Error::EventFd(std::io::Error::from_raw_os_error(1))),
+            format!(
+                "{}",
+                ErrorChain(&Error::EventFd(std::io::Error::from_raw_os_error(1)))
+            ),
             format!(
                 "Failed to create EventFd: {}",
                 std::io::Error::from_raw_os_error(1)
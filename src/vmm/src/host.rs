@@ -0,0 +1,169 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Probing of the virtualization capabilities of the host, so setup issues can be diagnosed before
+//! trying to boot a microVM.
+
+use std::cmp;
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs;
+
+use vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
+#[cfg(target_os = "linux")]
+use vstate::{self, KvmContext};
+
+/// Reasons for the hypervisor to be unusable.
+#[derive(Debug)]
+pub enum HypervisorError {
+    #[cfg(target_os = "macos")]
+    /// HVF is missing, or the process isn't allowed to use it.
+    Hvf(hvf::Error),
+    #[cfg(target_os = "linux")]
+    /// KVM is missing, inaccessible, or lacks required capabilities.
+    Kvm(vstate::Error),
+}
+
+impl HypervisorError {
+    /// Returns the errno value that best describes the error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            #[cfg(target_os = "macos")]
+            HypervisorError::Hvf(hvf::Error::VmDenied) => libc::EACCES,
+            #[cfg(target_os = "macos")]
+            HypervisorError::Hvf(hvf::Error::VmUnsupported) => libc::ENOTSUP,
+            #[cfg(target_os = "macos")]
+            HypervisorError::Hvf(_) => libc::EIO,
+            #[cfg(target_os = "linux")]
+            HypervisorError::Kvm(vstate::Error::KvmOpen(e)) => e.errno(),
+            #[cfg(target_os = "linux")]
+            HypervisorError::Kvm(_) => libc::ENOTSUP,
+        }
+    }
+}
+
+impl fmt::Display for HypervisorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(target_os = "macos")]
+            HypervisorError::Hvf(e) => write!(f, "{}", e),
+            #[cfg(target_os = "linux")]
+            HypervisorError::Kvm(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// The virtualization capabilities of the host.
+#[derive(Debug)]
+pub struct HostCapabilities {
+    /// Whether the hypervisor (KVM or HVF) can be used by this process, or why not.
+    pub hypervisor: Result<(), HypervisorError>,
+    /// The maximum number of vCPUs of a microVM, bounded by both the hypervisor and the VMM.
+    pub max_vcpus: u32,
+    /// The maximum number of memory slots, or 0 if the hypervisor doesn't limit them.
+    pub max_memslots: u32,
+    /// The maximum amount of RAM of a microVM, in MiB, bounded by the memory of the host.
+    pub max_mem_mib: u64,
+    /// Whether the hypervisor lets guests run hypervisors of their own.
+    pub nested: bool,
+    /// Whether AMD SEV is enabled in the hypervisor.
+    pub sev: bool,
+    /// Whether Intel TDX is enabled in the hypervisor.
+    pub tdx: bool,
+}
+
+// Tells if a boolean kernel module parameter, as read from sysfs, is enabled.
+#[cfg(any(target_os = "linux", test))]
+fn param_enabled(value: &str) -> bool {
+    matches!(value.trim(), "Y" | "y" | "1")
+}
+
+#[cfg(target_os = "linux")]
+fn any_param_enabled(paths: &[&str]) -> bool {
+    paths.iter().any(|path| {
+        fs::read_to_string(path)
+            .map(|value| param_enabled(&value))
+            .unwrap_or(false)
+    })
+}
+
+fn host_mem_mib() -> u64 {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages < 0 || page_size < 0 {
+        return 0;
+    }
+    (pages as u64 * page_size as u64) >> 20
+}
+
+#[cfg(target_os = "linux")]
+fn probe_hypervisor(caps: &mut HostCapabilities) {
+    let kvm = match KvmContext::new() {
+        Ok(kvm) => kvm,
+        Err(e) => {
+            caps.hypervisor = Err(HypervisorError::Kvm(e));
+            return;
+        }
+    };
+    caps.max_vcpus = cmp::min(kvm.fd().get_max_vcpus() as u32, MAX_SUPPORTED_VCPUS as u32);
+    caps.max_memslots = kvm.max_memslots() as u32;
+    caps.nested = any_param_enabled(&[
+        "/sys/module/kvm_intel/parameters/nested",
+        "/sys/module/kvm_amd/parameters/nested",
+    ]);
+    caps.sev = any_param_enabled(&["/sys/module/kvm_amd/parameters/sev"]);
+    caps.tdx = any_param_enabled(&["/sys/module/kvm_intel/parameters/tdx"]);
+}
+
+#[cfg(target_os = "macos")]
+fn probe_hypervisor(caps: &mut HostCapabilities) {
+    if let Err(e) = hvf::check_vm_create() {
+        caps.hypervisor = Err(HypervisorError::Hvf(e));
+        return;
+    }
+    // Fall back to our own limit if HVF is unable to report its own.
+    let max_vcpus = hvf::vm_max_vcpu_count().unwrap_or(MAX_SUPPORTED_VCPUS as u32);
+    caps.max_vcpus = cmp::min(max_vcpus, MAX_SUPPORTED_VCPUS as u32);
+}
+
+/// Probes the capabilities of the host. Nothing is left behind, so this can be called at any time.
+pub fn probe() -> HostCapabilities {
+    let mut caps = HostCapabilities {
+        hypervisor: Ok(()),
+        max_vcpus: 0,
+        max_memslots: 0,
+        max_mem_mib: host_mem_mib(),
+        nested: false,
+        sev: false,
+        tdx: false,
+    };
+    probe_hypervisor(&mut caps);
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_param_enabled() {
+        assert!(param_enabled("Y\n"));
+        assert!(param_enabled("1\n"));
+        assert!(!param_enabled("N\n"));
+        assert!(!param_enabled("0\n"));
+        assert!(!param_enabled(""));
+    }
+
+    #[test]
+    fn test_probe() {
+        let caps = probe();
+        assert!(caps.max_mem_mib > 0);
+        match caps.hypervisor {
+            Ok(()) => assert!(caps.max_vcpus > 0 && caps.max_vcpus <= MAX_SUPPORTED_VCPUS as u32),
+            Err(e) => {
+                assert_eq!(caps.max_vcpus, 0);
+                assert!(e.errno() > 0);
+            }
+        }
+    }
+}
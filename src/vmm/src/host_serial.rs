@@ -0,0 +1,256 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Claims the character devices of the host passed through to the consoles of the guest, such as
+//! serial adapters, and hands them back once the microVM is done with them.
+//!
+//! A terminal is set to raw mode, so the bytes go through untouched, and to ignore the carrier
+//! detect line, so it can be used without a modem. A console port carries no modem control
+//! signals, so DTR and RTS are raised while the microVM runs and dropped once it stops, telling
+//! the other end whether anyone is listening, which is as much as can be mapped.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use devices::legacy::ReadableFd;
+use vmm_config::serial_passthrough::SerialPassthroughConfig;
+
+/// A character device of the host claimed for the guest.
+pub struct HostSerial {
+    file: File,
+    termios: Option<libc::termios>,
+}
+
+impl HostSerial {
+    /// Opens the device of `config`, claiming it exclusively if it's a terminal.
+    pub fn open(config: &SerialPassthroughConfig) -> io::Result<Self> {
+        // Not waiting for the carrier, which the device is told to ignore right after.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&config.path)?;
+        if !file.metadata()?.file_type().is_char_device() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a character device",
+            ));
+        }
+        let fd = file.as_raw_fd();
+
+        // Safe because termios is plain data, filled in by tcgetattr() if it succeeds.
+        let mut saved: libc::termios = unsafe { mem::zeroed() };
+        let termios = if unsafe { libc::tcgetattr(fd, &mut saved) } == 0 {
+            Some(saved)
+        } else {
+            None
+        };
+
+        if let Some(saved) = &termios {
+            let mut raw = *saved;
+            // Safe because raw is a valid termios, and we check the return values.
+            unsafe {
+                libc::cfmakeraw(&mut raw);
+                raw.c_cflag |= libc::CLOCAL | libc::CREAD;
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                if let Some(speed) = config.speed() {
+                    if libc::cfsetspeed(&mut raw, speed) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Keep other processes from opening it while the guest uses it.
+                if libc::ioctl(fd, libc::TIOCEXCL as _) != 0 {
+                    warn!(
+                        "Unable to claim {} exclusively: {}",
+                        config.path.display(),
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+            // Pseudo-terminals have no modem control lines, and fail to change them.
+            let _ = set_modem_lines(fd, true);
+        }
+
+        // The output is written whole, so the device is blocking from now on. The input is only
+        // read once it's readable.
+        clear_nonblocking(fd)?;
+
+        Ok(HostSerial { file, termios })
+    }
+
+    /// Returns the input of the console the device is bound to.
+    pub fn input(&self) -> io::Result<HostSerialInput> {
+        Ok(HostSerialInput(self.file.try_clone()?))
+    }
+
+    /// Returns the output of the console the device is bound to.
+    pub fn output(&self) -> io::Result<HostSerialOutput> {
+        Ok(HostSerialOutput {
+            file: self.file.try_clone()?,
+            failed: false,
+        })
+    }
+
+    /// Drops DTR and RTS, and restores the settings the device had, if it's a terminal.
+    pub fn release(&self) -> io::Result<()> {
+        let termios = match &self.termios {
+            Some(termios) => termios,
+            None => return Ok(()),
+        };
+        let fd = self.file.as_raw_fd();
+        let _ = set_modem_lines(fd, false);
+        // Safe because termios is valid, and we check the return value. The output is drained
+        // first, so what the guest wrote last isn't lost.
+        unsafe {
+            libc::ioctl(fd, libc::TIOCNXCL as _);
+            if libc::tcsetattr(fd, libc::TCSADRAIN, termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn set_modem_lines(fd: RawFd, raised: bool) -> io::Result<()> {
+    let lines: libc::c_int = libc::TIOCM_DTR | libc::TIOCM_RTS;
+    let request = if raised {
+        libc::TIOCMBIS
+    } else {
+        libc::TIOCMBIC
+    };
+    // Safe because the kernel only reads the lines, and we check the return value.
+    if unsafe { libc::ioctl(fd, request as _, &lines) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn clear_nonblocking(fd: RawFd) -> io::Result<()> {
+    // Safe because we only change the flags of the fd, and check the return values.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The input of a console bound to a character device of the host.
+pub struct HostSerialInput(File);
+
+impl Read for HostSerialInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl AsRawFd for HostSerialInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl ReadableFd for HostSerialInput {}
+
+/// The output of a console bound to a character device of the host. Once writing to the device
+/// fails, as when it's unplugged, the output of the guest is dropped rather than failing the
+/// console.
+pub struct HostSerialOutput {
+    file: File,
+    failed: bool,
+}
+
+impl Write for HostSerialOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.failed {
+            if let Err(e) = self.file.write_all(buf) {
+                warn!(
+                    "Unable to write to the serial device, dropping its output: {}",
+                    e
+                );
+                self.failed = true;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::path::PathBuf;
+    use std::ptr;
+
+    fn openpty() -> (File, PathBuf) {
+        let (mut master, mut slave) = (-1, -1);
+        // Safe because we pass valid pointers, and null for the optional arguments.
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(ret, 0);
+        // Safe because ttyname() returns a NUL-terminated string, copied right away.
+        let path = unsafe { CStr::from_ptr(libc::ttyname(slave)) }
+            .to_str()
+            .unwrap()
+            .into();
+        unsafe { libc::close(slave) };
+        // Safe because the fd was just created, and is owned by the file alone.
+        (
+            unsafe { std::os::unix::io::FromRawFd::from_raw_fd(master) },
+            path,
+        )
+    }
+
+    fn lflag(fd: RawFd) -> libc::tcflag_t {
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::tcgetattr(fd, &mut termios) }, 0);
+        termios.c_lflag
+    }
+
+    #[test]
+    fn test_host_serial() {
+        let (mut master, path) = openpty();
+        let config = SerialPassthroughConfig::new("pty", path, Some(115_200)).unwrap();
+
+        let serial = HostSerial::open(&config).unwrap();
+        let fd = serial.file.as_raw_fd();
+        assert_eq!(lflag(fd) & (libc::ECHO | libc::ICANON), 0);
+
+        // The bytes go through untouched, both ways.
+        master.write_all(b"\r\n\x03").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(serial.input().unwrap().read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"\r\n\x03");
+        serial.output().unwrap().write_all(b"\n").unwrap();
+        assert_eq!(master.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'\n');
+
+        serial.release().unwrap();
+        assert_ne!(lflag(fd) & libc::ICANON, 0);
+    }
+
+    #[test]
+    fn test_not_a_character_device() {
+        let config = SerialPassthroughConfig::new("tmp", PathBuf::from("/tmp"), None).unwrap();
+        assert!(HostSerial::open(&config).is_err());
+    }
+}
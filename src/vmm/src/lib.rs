@@ -32,18 +32,40 @@ extern crate vm_memory;
 
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
+/// Forwarding of the resizes of the terminal to the console.
+mod console_resize;
 pub(crate) mod device_manager;
+/// Probing of the virtualization capabilities of the host.
+pub mod host;
+/// Claiming of the character devices of the host passed through to the guest.
+mod host_serial;
+/// Readiness and liveness probes of the workload.
+mod probes;
 /// Resource store for configured microVM resources.
 pub mod resources;
 /// Signal handling utilities.
 #[cfg(target_os = "linux")]
 pub mod signal_handler;
+/// Release of the resources of a microVM on stop, or on a failed build.
+pub mod teardown;
+/// Save and restore of the terminal the console takes over.
+mod terminal;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 
+/// The types of the devices attached to the microVM.
+pub use arch::DeviceType;
+/// Test-mode injection of failures into running devices.
+#[cfg(feature = "fault-injection")]
+pub use devices::fault_injection;
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
+use linux::idle::{self, IdleAction, IdleMonitor};
+#[cfg(target_os = "linux")]
+use linux::profiler::Profiler;
+#[cfg(target_os = "linux")]
 use linux::vstate;
 #[cfg(target_os = "macos")]
 mod macos;
@@ -53,25 +75,41 @@ use macos::vstate;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::thread;
 #[cfg(target_os = "linux")]
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use arch::ArchMemoryInfo;
-use arch::DeviceType;
 use arch::InitrdConfig;
 #[cfg(target_arch = "x86_64")]
 use device_manager::legacy::PortIODeviceManager;
 use device_manager::mmio::MMIODeviceManager;
+use devices::virtio::record::Recorder;
+use devices::virtio::watermark::QueueWatermarks;
+use devices::virtio::Balloon;
 use devices::BusDevice;
 use kernel::cmdline::Cmdline as KernelCmdline;
-use logger::LoggerError;
+use logger::audit::AuditEvent;
+use logger::{LoggerError, AUDIT};
 use polly::event_manager::{self, EventManager, Subscriber};
+use teardown::Teardown;
 use utils::epoll::{EpollEvent, EventSet};
+use utils::error::ErrorChain;
 use utils::eventfd::EventFd;
 use utils::time::TimestampUs;
 use vm_memory::GuestMemoryMmap;
 #[cfg(target_os = "linux")]
+use vmm_config::exit::ExitStatus;
+use vmm_config::exit::{ExitHandle, ExitReason};
+#[cfg(target_os = "linux")]
+use vmm_config::idle::IdlePolicyConfig;
+use vmm_config::probes::{ProbeCallback, ProbeConfig, ProbeReports};
+#[cfg(target_os = "linux")]
+use vmm_config::profiling::{ProfileReports, ProfilingConfig};
+use vmm_config::runtime_limit::RuntimeLimitConfig;
+use vmm_config::vm_state::{VmState, VmStateError, VmStateHandle};
+#[cfg(target_os = "linux")]
 use vstate::VcpuEvent;
 use vstate::{Vcpu, VcpuHandle, VcpuResponse, Vm};
 
@@ -87,16 +125,26 @@ pub const FC_EXIT_CODE_BAD_SYSCALL: u8 = 148;
 pub const FC_EXIT_CODE_SIGBUS: u8 = 149;
 /// Firecracker was shut down after intercepting `SIGSEGV`.
 pub const FC_EXIT_CODE_SIGSEGV: u8 = 150;
+/// The microVM was shut down after one of its devices panicked.
+pub const FC_EXIT_CODE_DEVICE_FAILURE: u8 = 151;
 /// Bad configuration for microvm's resources, when using a single json.
 pub const FC_EXIT_CODE_BAD_CONFIGURATION: u8 = 152;
 /// Command line arguments parsing error.
 pub const FC_EXIT_CODE_ARG_PARSING: u8 = 153;
+/// The microVM was shut down after exceeding its maximum runtime. Matches the exit code of
+/// timeout(1).
+pub const FC_EXIT_CODE_MAX_RUNTIME: u8 = 124;
+
+/// The name the `Vmm` has as a subscriber of the event manager.
+const VMM_SUBSCRIBER_NAME: &str = "vmm";
 
 /// Errors associated with the VMM internal logic. These errors cannot be generated by direct user
 /// input, but can result from bad configuration of the host (for example if Firecracker doesn't
 /// have permissions to open the KVM fd).
 #[derive(Debug)]
 pub enum Error {
+    /// Cannot change the target of the balloon.
+    BalloonTarget(devices::Error),
     /// This error is thrown by the minimal boot loader implementation.
     ConfigureSystem(arch::Error),
     /// Legacy devices work with Event file descriptors and the creation can fail because
@@ -109,6 +157,9 @@ pub enum Error {
     EventManager(event_manager::Error),
     /// I8042 Error.
     I8042Error(devices::legacy::I8042DeviceError),
+    /// Cannot start monitoring the activity of the microVM.
+    #[cfg(target_os = "linux")]
+    IdleMonitor(io::Error),
     /// Cannot access kernel file.
     KernelFile(io::Error),
     /// Cannot open /dev/kvm. Either the host does not have KVM or Firecracker does not have
@@ -121,10 +172,24 @@ pub enum Error {
     LoadCommandline(kernel::cmdline::Error),
     /// Internal logger error.
     Logger(LoggerError),
+    /// The microVM has no balloon device.
+    NoBalloonDevice,
+    /// The microVM has no i8042 controller.
+    #[cfg(target_arch = "x86_64")]
+    NoI8042Device,
+    /// Cannot spawn the thread running a probe.
+    ProbeSpawn(io::Error),
+    /// Cannot start profiling the vCPUs.
+    #[cfg(target_os = "linux")]
+    Profiler(io::Error),
     /// Cannot add a device to the MMIO Bus.
     RegisterMMIODevice(device_manager::mmio::Error),
+    /// Cannot generate the RNG seed of the guest.
+    RngSeed(io::Error),
     /// Write to the serial console failed.
     Serial(io::Error),
+    /// Cannot spawn the thread enforcing the maximum runtime.
+    RuntimeLimitSpawn(io::Error),
     /// Cannot create Timer file descriptor.
     TimerFd(io::Error),
     /// Vcpu error.
@@ -133,12 +198,16 @@ pub enum Error {
     VcpuEvent(vstate::Error),
     /// Cannot create a vCPU handle.
     VcpuHandle(vstate::Error),
+    /// vCPU pause failed.
+    VcpuPause,
     /// vCPU resume failed.
     VcpuResume,
     /// Cannot spawn a new Vcpu thread.
     VcpuSpawn(std::io::Error),
     /// Vm error.
     Vm(vstate::Error),
+    /// The microVM can't move to the state requested from its current one.
+    VmState(VmStateError),
     /// Error thrown by observer object on Vmm initialization.
     VmmObserverInit(utils::errno::Error),
     /// Error thrown by observer object on Vmm teardown.
@@ -150,35 +219,71 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            BalloonTarget(_) => write!(f, "Cannot change the target of the balloon"),
+            // The errors of `arch` don't implement `std::error::Error`, so can't be a source.
             ConfigureSystem(e) => write!(f, "System configuration error: {:?}", e),
             #[cfg(target_arch = "x86_64")]
-            CreateLegacyDevice(e) => write!(f, "Error creating legacy device: {:?}", e),
-            EventFd(e) => write!(f, "Event fd error: {}", e),
-            EventManager(e) => write!(f, "Event manager error: {:?}", e),
-            I8042Error(e) => write!(f, "I8042 error: {}", e),
-            KernelFile(e) => write!(f, "Cannot access kernel file: {}", e),
-            KvmContext(e) => write!(f, "Failed to validate KVM support: {:?}", e),
+            CreateLegacyDevice(_) => write!(f, "Error creating legacy device"),
+            EventFd(_) => write!(f, "Event fd error"),
+            EventManager(_) => write!(f, "Event manager error"),
+            I8042Error(_) => write!(f, "I8042 error"),
+            #[cfg(target_os = "linux")]
+            IdleMonitor(_) => write!(f, "Cannot start the idle monitor"),
+            KernelFile(_) => write!(f, "Cannot access kernel file"),
+            KvmContext(_) => write!(f, "Failed to validate KVM support"),
             #[cfg(target_arch = "x86_64")]
-            LegacyIOBus(e) => write!(f, "Cannot add devices to the legacy I/O Bus. {}", e),
-            LoadCommandline(e) => write!(f, "Cannot load command line: {}", e),
-            Logger(e) => write!(f, "Logger error: {}", e),
-            RegisterMMIODevice(e) => write!(f, "Cannot add a device to the MMIO Bus. {}", e),
-            Serial(e) => write!(f, "Error writing to the serial console: {:?}", e),
-            TimerFd(e) => write!(f, "Error creating timer fd: {}", e),
-            Vcpu(e) => write!(f, "Vcpu error: {}", e),
-            VcpuEvent(e) => write!(f, "Cannot send event to vCPU. {:?}", e),
-            VcpuHandle(e) => write!(f, "Cannot create a vCPU handle. {}", e),
+            LegacyIOBus(_) => write!(f, "Cannot add devices to the legacy I/O Bus"),
+            LoadCommandline(_) => write!(f, "Cannot load command line"),
+            Logger(_) => write!(f, "Logger error"),
+            NoBalloonDevice => write!(f, "The microVM has no balloon device"),
+            #[cfg(target_arch = "x86_64")]
+            NoI8042Device => write!(f, "The microVM has no i8042 controller"),
+            ProbeSpawn(_) => write!(f, "Cannot spawn probe thread"),
+            #[cfg(target_os = "linux")]
+            Profiler(_) => write!(f, "Cannot start the profiler"),
+            RegisterMMIODevice(_) => write!(f, "Cannot add a device to the MMIO Bus"),
+            RngSeed(_) => write!(f, "Cannot generate the RNG seed of the guest"),
+            Serial(_) => write!(f, "Error writing to the serial console"),
+            RuntimeLimitSpawn(_) => write!(f, "Cannot spawn runtime limit thread"),
+            TimerFd(_) => write!(f, "Error creating timer fd"),
+            Vcpu(_) => write!(f, "Vcpu error"),
+            VcpuEvent(_) => write!(f, "Cannot send event to vCPU"),
+            VcpuHandle(_) => write!(f, "Cannot create a vCPU handle"),
+            VcpuPause => write!(f, "vCPUs pause failed."),
             VcpuResume => write!(f, "vCPUs resume failed."),
-            VcpuSpawn(e) => write!(f, "Cannot spawn Vcpu thread: {}", e),
-            Vm(e) => write!(f, "Vm error: {}", e),
-            VmmObserverInit(e) => write!(
-                f,
-                "Error thrown by observer object on Vmm initialization: {}",
-                e
-            ),
-            VmmObserverTeardown(e) => {
-                write!(f, "Error thrown by observer object on Vmm teardown: {}", e)
+            VcpuSpawn(_) => write!(f, "Cannot spawn Vcpu thread"),
+            Vm(_) => write!(f, "Vm error"),
+            VmState(e) => write!(f, "{}", e),
+            VmmObserverInit(_) => {
+                write!(f, "Error thrown by observer object on Vmm initialization")
             }
+            VmmObserverTeardown(_) => write!(f, "Error thrown by observer object on Vmm teardown"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::Error::*;
+
+        match self {
+            ConfigureSystem(_) | NoBalloonDevice | VcpuPause | VcpuResume | VmState(_) => None,
+            #[cfg(target_arch = "x86_64")]
+            NoI8042Device => None,
+            #[cfg(target_arch = "x86_64")]
+            CreateLegacyDevice(e) | LegacyIOBus(e) => Some(e),
+            #[cfg(target_os = "linux")]
+            IdleMonitor(e) | Profiler(e) => Some(e),
+            EventFd(e) | KernelFile(e) | ProbeSpawn(e) | RngSeed(e) | Serial(e)
+            | RuntimeLimitSpawn(e) | TimerFd(e) | VcpuSpawn(e) => Some(e),
+            EventManager(e) => Some(e),
+            BalloonTarget(e) => Some(e),
+            I8042Error(e) => Some(e),
+            KvmContext(e) | Vcpu(e) | VcpuEvent(e) | VcpuHandle(e) | Vm(e) => Some(e),
+            LoadCommandline(e) => Some(e),
+            Logger(e) => Some(e),
+            RegisterMMIODevice(e) => Some(e),
+            VmmObserverInit(e) | VmmObserverTeardown(e) => Some(e),
         }
     }
 }
@@ -212,10 +317,39 @@ pub struct Vmm {
     exit_evt: EventFd,
     vm: Vm,
 
+    // Signaled once the maximum runtime is exceeded, and again once the grace period expires.
+    runtime_limit_evt: Option<EventFd>,
+    runtime_limit_expirations: u64,
+
+    #[cfg(target_os = "linux")]
+    idle_monitor: Option<IdleMonitor>,
+    #[cfg(target_os = "linux")]
+    profiler: Option<Profiler>,
+
     // Guest VM devices.
     mmio_device_manager: MMIODeviceManager,
+    balloon: Option<Arc<Mutex<Balloon>>>,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+    // Records the interactions of the virtio devices with the guest, if enabled.
+    virtio_recorder: Option<Arc<Recorder>>,
+    // Reports the depth of the console and vsock queues crossing watermarks, if enabled.
+    queue_watermarks: Option<QueueWatermarks>,
+    // The start and the size of the pool of guest memory the virtio devices are restricted to,
+    // if any.
+    dma_pool: Option<(u64, u64)>,
+    // Where the exit is signaled, if the embedder outlives the microVM rather than the process
+    // exiting on stop.
+    exit_handle: Option<ExitHandle>,
+    // Where the microVM is in its lifecycle, shared with the embedder.
+    state: VmStateHandle,
+    // How the vCPUs are laid out in sockets, cores and threads, as described to the guest in the
+    // FDT.
+    #[cfg(target_arch = "aarch64")]
+    cpu_topology: arch::CpuTopology,
+    // Releases what the microVM leaves behind, such as the host directories staged for the
+    // guest, on stop or if the build fails.
+    teardown: Teardown,
 }
 
 impl Vmm {
@@ -253,6 +387,26 @@ impl Vmm {
         Ok(())
     }
 
+    /// Sends a pause command to the vcpus.
+    #[cfg(target_os = "linux")]
+    pub fn pause_vcpus(&mut self) -> Result<()> {
+        for handle in self.vcpus_handles.iter() {
+            handle
+                .send_event(VcpuEvent::Pause)
+                .map_err(Error::VcpuEvent)?;
+        }
+        for handle in self.vcpus_handles.iter() {
+            match handle
+                .response_receiver()
+                .recv_timeout(Duration::from_millis(1000))
+            {
+                Ok(VcpuResponse::Paused) => (),
+                _ => return Err(Error::VcpuPause),
+            }
+        }
+        Ok(())
+    }
+
     /// Sends a resume command to the vcpus.
     #[cfg(target_os = "linux")]
     pub fn resume_vcpus(&mut self) -> Result<()> {
@@ -278,8 +432,106 @@ impl Vmm {
         Ok(())
     }
 
+    /// Pauses the running microVM until `resume()`, which the idle monitor doesn't thaw it from.
+    #[cfg(target_os = "linux")]
+    pub fn pause(&mut self) -> Result<()> {
+        if !self.check_transition(VmState::Paused)? {
+            return Ok(());
+        }
+        if let Err(e) = self.pause_vcpus() {
+            // Some vCPUs may have been paused already.
+            let _ = self.resume_vcpus();
+            return Err(e);
+        }
+        self.state
+            .transition(VmState::Paused)
+            .map_err(Error::VmState)
+    }
+
+    /// Resumes the microVM paused by `pause()`.
+    #[cfg(target_os = "linux")]
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.check_transition(VmState::Running)? {
+            return Ok(());
+        }
+        self.resume_vcpus()?;
+        self.state
+            .transition(VmState::Running)
+            .map_err(Error::VmState)
+    }
+
+    /// Checks the microVM can move to `to`, returning false if it's already there.
+    #[cfg(target_os = "linux")]
+    fn check_transition(&self, to: VmState) -> Result<bool> {
+        let state = self.state.get();
+        if state == to {
+            return Ok(false);
+        }
+        if !state.can_transition_to(to) {
+            return Err(Error::VmState(VmStateError::IllegalTransition(state, to)));
+        }
+        Ok(true)
+    }
+
+    /// Returns where the microVM is in its lifecycle.
+    pub fn state(&self) -> VmState {
+        self.state.get()
+    }
+
+    /// Returns the type and the id of the MMIO devices of the microVM, sorted by id.
+    pub fn devices(&self) -> Vec<(DeviceType, String)> {
+        let mut devices: Vec<_> = self
+            .mmio_device_manager
+            .get_device_info()
+            .keys()
+            .cloned()
+            .collect();
+        devices.sort_by(|a, b| a.1.cmp(&b.1));
+        devices
+    }
+
+    pub fn set_balloon(&mut self, balloon: Arc<Mutex<Balloon>>) {
+        self.balloon = Some(balloon);
+    }
+
+    /// Asks the guest to give up `num_pages` pages of 4 KiB to the balloon.
+    pub fn set_balloon_target(&self, num_pages: u32) -> Result<()> {
+        self.balloon
+            .as_ref()
+            .ok_or(Error::NoBalloonDevice)?
+            .lock()
+            .unwrap()
+            .set_target(num_pages)
+            .map_err(Error::BalloonTarget)
+    }
+
+    /// Returns the number of pages the guest is asked to give up to the balloon, and the number
+    /// it has given up.
+    pub fn balloon_pages(&self) -> Result<(u32, u32)> {
+        let balloon = self
+            .balloon
+            .as_ref()
+            .ok_or(Error::NoBalloonDevice)?
+            .lock()
+            .unwrap();
+        Ok((balloon.target(), balloon.actual()))
+    }
+
+    /// Asks the event loop to stop the microVM, as when the guest shuts it down.
+    pub fn request_exit(&self) -> Result<()> {
+        self.exit_evt.write(1).map_err(Error::EventFd)?;
+        // Already shutting down if the guest was asked to first.
+        let _ = self.state.transition(VmState::ShuttingDown);
+        Ok(())
+    }
+
     /// Configures the system for boot.
     pub fn configure_system(&self, vcpus: &[Vcpu], initrd: &Option<InitrdConfig>) -> Result<()> {
+        // Seeding the generator of the guest kernel from the host spares it from stalling on
+        // early boot until it gathers enough entropy of its own.
+        let mut rng_seed = [0u8; arch::RNG_SEED_SIZE];
+        utils::rand::fill_random(&mut rng_seed).map_err(Error::RngSeed)?;
+
         #[cfg(target_arch = "x86_64")]
         arch::x86_64::configure_system(
             &self.guest_memory,
@@ -288,6 +540,7 @@ impl Vmm {
             self.kernel_cmdline.len() + 1,
             initrd,
             vcpus.len() as u8,
+            Some(&rng_seed),
         )
         .map_err(Error::ConfigureSystem)?;
 
@@ -302,9 +555,12 @@ impl Vmm {
                     .as_cstring()
                     .map_err(Error::LoadCommandline)?,
                 vcpu_mpidr,
+                &self.cpu_topology,
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
+                Some(&rng_seed),
+                self.dma_pool,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -320,9 +576,12 @@ impl Vmm {
                     .as_cstring()
                     .map_err(Error::LoadCommandline)?,
                 vcpu_mpidr,
+                &self.cpu_topology,
                 self.mmio_device_manager.get_device_info(),
                 self.vm.get_irqchip(),
                 initrd,
+                Some(&rng_seed),
+                None,
             )
             .map_err(Error::ConfigureSystem)?;
         }
@@ -334,18 +593,216 @@ impl Vmm {
         &self.guest_memory
     }
 
-    /// Injects CTRL+ALT+DEL keystroke combo in the i8042 device.
+    /// Injects CTRL+ALT+DEL keystroke combo in the i8042 device, if the microVM has one, which
+    /// the guest shuts down on.
     #[cfg(target_arch = "x86_64")]
     pub fn send_ctrl_alt_del(&mut self) -> Result<()> {
         self.pio_device_manager
             .i8042
+            .as_ref()
+            .ok_or(Error::NoI8042Device)?
             .lock()
             .expect("i8042 lock was poisoned")
             .trigger_ctrl_alt_del()
-            .map_err(Error::I8042Error)
+            .map_err(Error::I8042Error)?;
+        let _ = self.state.transition(VmState::ShuttingDown);
+        Ok(())
+    }
+
+    /// Starts enforcing a wall-clock limit on the runtime of the microVM.
+    pub fn start_runtime_limit(&mut self, config: RuntimeLimitConfig) -> Result<()> {
+        let evt = EventFd::new(utils::eventfd::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let thread_evt = evt.try_clone().map_err(Error::EventFd)?;
+
+        thread::Builder::new()
+            .name("runtime limit".to_string())
+            .spawn(move || {
+                thread::sleep(config.max_runtime);
+                let _ = thread_evt.write(1);
+                thread::sleep(config.grace_period);
+                let _ = thread_evt.write(1);
+            })
+            .map_err(Error::RuntimeLimitSpawn)?;
+
+        self.runtime_limit_evt = Some(evt);
+        Ok(())
+    }
+
+    /// Starts running the probes of the workload, each on a thread of its own. The results of the
+    /// commands run by the guest are collected from `reports`.
+    pub fn start_probes(
+        &mut self,
+        probes: &[ProbeConfig],
+        reports: &ProbeReports,
+        callback: Option<&ProbeCallback>,
+    ) -> Result<()> {
+        for config in probes {
+            let config = config.clone();
+            let reports = reports.clone();
+            let callback = callback.cloned();
+            thread::Builder::new()
+                .name(format!("{:?} probe", config.kind).to_lowercase())
+                .spawn(move || probes::run_probe(config, reports, callback))
+                .map_err(Error::ProbeSpawn)?;
+        }
+        Ok(())
+    }
+
+    /// Starts freezing the microVM while it's idle, according to `config`.
+    #[cfg(target_os = "linux")]
+    pub fn start_idle_monitor(&mut self, config: IdlePolicyConfig) -> Result<()> {
+        self.idle_monitor = Some(IdleMonitor::new(config).map_err(Error::IdleMonitor)?);
+        Ok(())
+    }
+
+    /// Starts profiling the vCPUs, publishing the reports to `reports`.
+    #[cfg(target_os = "linux")]
+    pub fn start_profiler(
+        &mut self,
+        config: ProfilingConfig,
+        reports: ProfileReports,
+    ) -> Result<()> {
+        let tids: Vec<libc::pid_t> = self.vcpus_handles.iter().map(|h| h.tid()).collect();
+        self.profiler = Some(Profiler::new(config, reports, &tids).map_err(Error::Profiler)?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn handle_profiler_tick(&mut self) {
+        let cpu_times: Vec<Option<Duration>> = self
+            .vcpus_handles
+            .iter()
+            .map(|handle| handle.cpu_time())
+            .collect();
+        if let Some(profiler) = self.profiler.as_mut() {
+            let _ = profiler.tick_evt().read();
+            profiler.sample(Instant::now(), &cpu_times);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_profiler_tick(&self, source: i32) -> bool {
+        self.profiler
+            .as_ref()
+            .map_or(false, |profiler| profiler.tick_evt().as_raw_fd() == source)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn is_profiler_tick(&self, _source: i32) -> bool {
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    fn handle_idle_tick(&mut self) {
+        let cpu_time = self
+            .vcpus_handles
+            .iter()
+            .filter_map(|handle| handle.cpu_time())
+            .sum();
+        let num_vcpus = self.vcpus_handles.len();
+        let paused = self.state.get() == VmState::Paused;
+
+        let (action, reclaim_memory) = match self.idle_monitor.as_mut() {
+            // The vCPUs paused on request are left alone until resumed.
+            Some(monitor) if paused => {
+                let _ = monitor.tick_evt().read();
+                return;
+            }
+            Some(monitor) => {
+                let _ = monitor.tick_evt().read();
+                let action = monitor.sample(
+                    Instant::now(),
+                    cpu_time,
+                    num_vcpus,
+                    logger::METRICS.device_activity(),
+                );
+                (action, monitor.reclaim_memory())
+            }
+            None => return,
+        };
+
+        match action {
+            IdleAction::Freeze => {
+                info!("The microVM is idle, freezing it.");
+                if let Err(e) = self.pause_vcpus() {
+                    error!("Unable to freeze the microVM: {}", ErrorChain(&e));
+                    // Some vCPUs may have been paused already.
+                    let _ = self.resume_vcpus();
+                    if let Some(monitor) = self.idle_monitor.as_mut() {
+                        monitor.cancel_freeze(Instant::now());
+                    }
+                } else if reclaim_memory {
+                    idle::reclaim_memory(&self.guest_memory);
+                }
+            }
+            IdleAction::Thaw => {
+                info!("Activity on the frozen microVM, thawing it.");
+                if let Err(e) = self.resume_vcpus() {
+                    error!("Unable to thaw the microVM: {}", ErrorChain(&e));
+                }
+            }
+            IdleAction::None => (),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn is_idle_tick(&self, source: i32) -> bool {
+        self.idle_monitor
+            .as_ref()
+            .map_or(false, |monitor| monitor.tick_evt().as_raw_fd() == source)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn is_idle_tick(&self, _source: i32) -> bool {
+        false
     }
 
-    /// Waits for all vCPUs to exit and terminates the Firecracker process.
+    #[cfg(target_arch = "x86_64")]
+    fn is_guest_reset(&self, source: i32) -> bool {
+        self.pio_device_manager.reset_evt.as_raw_fd() == source
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn is_guest_reset(&self, _source: i32) -> bool {
+        // The guest resets the machine through PSCI or SBI, which stops the vCPUs.
+        false
+    }
+
+    fn handle_runtime_limit(&mut self, expirations: u64) {
+        let first = self.runtime_limit_expirations == 0;
+        self.runtime_limit_expirations += expirations;
+
+        if self.runtime_limit_expirations > 1 {
+            warn!("The guest didn't shut down within the grace period, stopping it.");
+            self.stop(i32::from(FC_EXIT_CODE_MAX_RUNTIME));
+        } else if first {
+            warn!("The microVM exceeded its maximum runtime, shutting it down.");
+            if !self.request_shutdown() {
+                self.stop(i32::from(FC_EXIT_CODE_MAX_RUNTIME));
+            }
+        }
+    }
+
+    /// Asks the guest to shut down. Returns false if that isn't possible.
+    #[cfg(target_arch = "x86_64")]
+    fn request_shutdown(&mut self) -> bool {
+        match self.send_ctrl_alt_del() {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Unable to request a graceful shutdown: {}", ErrorChain(&e));
+                false
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn request_shutdown(&mut self) -> bool {
+        // There's no device through which the guest can be asked to shut down.
+        false
+    }
+
+    /// Waits for all vCPUs to exit and terminates the Firecracker process, unless the microVM has
+    /// an exit handle, which its exit is signaled on instead.
     pub fn stop(&mut self, exit_code: i32) {
         info!("Vmm is stopping.");
 
@@ -355,7 +812,37 @@ impl Vmm {
         //    }
         //}
 
-        builder::SerialStdin::restore();
+        let reason = if exit_code == i32::from(FC_EXIT_CODE_OK) {
+            ExitReason::GuestShutdown
+        } else if exit_code == i32::from(FC_EXIT_CODE_MAX_RUNTIME) {
+            ExitReason::MaxRuntime
+        } else if exit_code == i32::from(FC_EXIT_CODE_DEVICE_FAILURE) {
+            ExitReason::DeviceFailure
+        } else {
+            ExitReason::VcpuError
+        };
+        AUDIT.record(AuditEvent::Shutdown {
+            exit_code,
+            reason: reason.as_str(),
+        });
+
+        // The vCPUs of macOS can't be finished yet, so the process has to exit there.
+        #[cfg(target_os = "linux")]
+        if let Some(handle) = self.exit_handle.clone() {
+            for vcpu in self.vcpus_handles.iter_mut() {
+                vcpu.finish();
+            }
+            self.teardown.run();
+            let _ = self.state.transition(VmState::Stopped(Some(reason)));
+            handle.notify(ExitStatus {
+                code: exit_code,
+                reason,
+            });
+            return;
+        }
+
+        self.teardown.run();
+        let _ = self.state.transition(VmState::Stopped(Some(reason)));
 
         // Exit from Firecracker using the provided exit code. Safe because we're terminating
         // the process anyway.
@@ -364,6 +851,14 @@ impl Vmm {
         }
     }
 
+    /// Returns whether the microVM has stopped without exiting the process, in which case the
+    /// event loop should return.
+    pub fn has_exited(&self) -> bool {
+        self.exit_handle
+            .as_ref()
+            .map_or(false, |handle| handle.status().is_some())
+    }
+
     #[cfg(target_os = "linux")]
     fn log_boot_time(t0_ts: &TimestampUs) {
         let now_tm_us = TimestampUs::default();
@@ -385,6 +880,20 @@ impl Vmm {
     }
 }
 
+impl Drop for Vmm {
+    // Only reached if the microVM fails to build, or once it has exited if it has an exit handle,
+    // since `stop()` exits the process otherwise. The vCPU threads hold the VM, and the devices
+    // through the bus, so they're finished first, though those of macOS can't be yet and keep
+    // them alive. The fields are dropped afterwards, running the teardown, then closing the VM
+    // and freeing its memory.
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        for handle in self.vcpus_handles.iter_mut() {
+            handle.finish();
+        }
+    }
+}
+
 impl Subscriber for Vmm {
     /// Handle a read event (EPOLLIN).
     fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
@@ -394,9 +903,7 @@ impl Subscriber for Vmm {
         if source == self.exit_evt.as_raw_fd() && event_set == EventSet::IN {
             let _ = self.exit_evt.read();
             // Query each vcpu for the exit_code.
-            // If the exit_code can't be found on any vcpu, it means that the exit signal
-            // has been issued by the i8042 controller in which case we exit with
-            // FC_EXIT_CODE_OK.
+            // If the exit_code can't be found on any vcpu, we exit with FC_EXIT_CODE_OK.
             let exit_code = self
                 .vcpus_handles
                 .iter()
@@ -405,16 +912,78 @@ impl Subscriber for Vmm {
                     _ => None,
                 })
                 .unwrap_or(FC_EXIT_CODE_OK);
+            // Report a clean shutdown requested because of the runtime limit as such.
+            let exit_code = if exit_code == FC_EXIT_CODE_OK && self.runtime_limit_expirations > 0 {
+                FC_EXIT_CODE_MAX_RUNTIME
+            } else {
+                exit_code
+            };
+            self.stop(i32::from(exit_code));
+        } else if self.is_guest_reset(source) {
+            #[cfg(target_arch = "x86_64")]
+            let _ = self.pio_device_manager.reset_evt.read();
+            info!("The guest reset the machine.");
+            // A reset is how the guest shuts down, as requested when the runtime limit is
+            // exceeded.
+            let exit_code = if self.runtime_limit_expirations > 0 {
+                FC_EXIT_CODE_MAX_RUNTIME
+            } else {
+                FC_EXIT_CODE_OK
+            };
             self.stop(i32::from(exit_code));
+        } else if self
+            .runtime_limit_evt
+            .as_ref()
+            .map_or(false, |evt| evt.as_raw_fd() == source)
+        {
+            let expirations = self
+                .runtime_limit_evt
+                .as_ref()
+                .and_then(|evt| evt.read().ok())
+                .unwrap_or(0);
+            self.handle_runtime_limit(expirations);
+        } else if self.is_idle_tick(source) {
+            #[cfg(target_os = "linux")]
+            self.handle_idle_tick();
+        } else if self.is_profiler_tick(source) {
+            #[cfg(target_os = "linux")]
+            self.handle_profiler_tick();
         } else {
             error!("Spurious EventManager event for handler: Vmm");
         }
     }
 
     fn interest_list(&self) -> Vec<EpollEvent> {
-        vec![EpollEvent::new(
+        let mut interest_list = vec![EpollEvent::new(
             EventSet::IN,
             self.exit_evt.as_raw_fd() as u64,
-        )]
+        )];
+        #[cfg(target_arch = "x86_64")]
+        interest_list.push(EpollEvent::new(
+            EventSet::IN,
+            self.pio_device_manager.reset_evt.as_raw_fd() as u64,
+        ));
+        if let Some(evt) = &self.runtime_limit_evt {
+            interest_list.push(EpollEvent::new(EventSet::IN, evt.as_raw_fd() as u64));
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(monitor) = &self.idle_monitor {
+            interest_list.push(EpollEvent::new(
+                EventSet::IN,
+                monitor.tick_evt().as_raw_fd() as u64,
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(profiler) = &self.profiler {
+            interest_list.push(EpollEvent::new(
+                EventSet::IN,
+                profiler.tick_evt().as_raw_fd() as u64,
+            ));
+        }
+        interest_list
+    }
+
+    fn name(&self) -> String {
+        VMM_SUBSCRIBER_NAME.to_string()
     }
 }
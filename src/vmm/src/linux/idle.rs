@@ -0,0 +1,200 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of idle microVMs, so they can be frozen until there's activity again.
+//!
+//! The vCPUs are considered idle when their threads barely consume CPU time, which means they
+//! spend nearly all their time halted in KVM, and the devices are considered idle when no data
+//! moves between the guest and the host, as reported by `METRICS.device_activity()`.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::eventfd::EventFd;
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_config::idle::IdlePolicyConfig;
+
+/// How often the activity of the microVM is sampled. This also bounds the time it takes to thaw
+/// a frozen microVM once there's activity.
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The vCPUs are considered busy when they consume more than this percentage of the time.
+const BUSY_CPU_PERCENT: u32 = 1;
+
+// Not exposed by the libc crate version we use. Available since Linux 5.4.
+const MADV_PAGEOUT: libc::c_int = 21;
+
+/// What should be done with the microVM after a sample.
+#[derive(Debug, PartialEq)]
+pub enum IdleAction {
+    /// Nothing changed.
+    None,
+    /// The microVM has been idle long enough, pause its vCPUs.
+    Freeze,
+    /// There's activity on a frozen microVM, resume its vCPUs.
+    Thaw,
+}
+
+/// Tracks the activity of the microVM to decide when to freeze and thaw it.
+pub struct IdleMonitor {
+    config: IdlePolicyConfig,
+    tick_evt: EventFd,
+    last_sample: Instant,
+    last_cpu_time: Duration,
+    last_activity: usize,
+    idle_since: Instant,
+    frozen: bool,
+}
+
+impl IdleMonitor {
+    /// Creates a monitor whose `tick_evt` is signaled every `IDLE_POLL_INTERVAL`.
+    pub fn new(config: IdlePolicyConfig) -> io::Result<Self> {
+        let tick_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)?;
+        let thread_evt = tick_evt.try_clone()?;
+
+        thread::Builder::new()
+            .name("idle monitor".to_string())
+            .spawn(move || loop {
+                thread::sleep(IDLE_POLL_INTERVAL);
+                if thread_evt.write(1).is_err() {
+                    break;
+                }
+            })?;
+
+        let now = Instant::now();
+        Ok(IdleMonitor {
+            config,
+            tick_evt,
+            last_sample: now,
+            last_cpu_time: Duration::from_secs(0),
+            last_activity: 0,
+            idle_since: now,
+            frozen: false,
+        })
+    }
+
+    /// The event signaled when the activity of the microVM should be sampled.
+    pub fn tick_evt(&self) -> &EventFd {
+        &self.tick_evt
+    }
+
+    /// Whether the memory of the guest should be reclaimed when freezing it.
+    pub fn reclaim_memory(&self) -> bool {
+        self.config.reclaim_memory
+    }
+
+    /// Records a failure to freeze the microVM, so it's considered running again.
+    pub fn cancel_freeze(&mut self, now: Instant) {
+        self.frozen = false;
+        self.idle_since = now;
+    }
+
+    /// Records a sample of the CPU time consumed by the `num_vcpus` vCPU threads, and of the
+    /// device activity counter, returning what should be done with the microVM.
+    pub fn sample(
+        &mut self,
+        now: Instant,
+        cpu_time: Duration,
+        num_vcpus: usize,
+        activity: usize,
+    ) -> IdleAction {
+        let elapsed = now.duration_since(self.last_sample);
+        let cpu_delta = cpu_time
+            .checked_sub(self.last_cpu_time)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let active = activity != self.last_activity;
+        let busy = active || cpu_delta * 100 > elapsed * (num_vcpus as u32 * BUSY_CPU_PERCENT);
+
+        self.last_sample = now;
+        self.last_cpu_time = cpu_time;
+        self.last_activity = activity;
+
+        if self.frozen {
+            if active {
+                self.frozen = false;
+                self.idle_since = now;
+                return IdleAction::Thaw;
+            }
+        } else if busy {
+            self.idle_since = now;
+        } else if now.duration_since(self.idle_since) >= self.config.idle_interval {
+            self.frozen = true;
+            return IdleAction::Freeze;
+        }
+
+        IdleAction::None
+    }
+}
+
+/// Asks the host to reclaim the memory of a frozen guest. The contents of the memory are
+/// preserved, as the pages are written back to swap, or to their file, before being dropped.
+pub fn reclaim_memory(guest_mem: &GuestMemoryMmap) {
+    let _: Result<(), ()> = guest_mem.with_regions(|_, region| {
+        // It's safe to unwrap because the guest address is valid.
+        let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
+        // Safe because the range is fully contained in the guest memory mapping, and
+        // MADV_PAGEOUT doesn't change its contents.
+        let ret = unsafe {
+            libc::madvise(
+                host_addr as *mut libc::c_void,
+                region.len() as usize,
+                MADV_PAGEOUT,
+            )
+        };
+        if ret != 0 {
+            warn!(
+                "Unable to reclaim guest memory: {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_monitor() {
+        let config = IdlePolicyConfig::new(Duration::from_secs(1), false).unwrap();
+        let mut monitor = IdleMonitor::new(config).unwrap();
+        let t0 = monitor.last_sample;
+        let ms = Duration::from_millis;
+
+        // A vCPU running half of the time is busy.
+        assert_eq!(
+            monitor.sample(t0 + ms(500), ms(250), 1, 0),
+            IdleAction::None
+        );
+        assert_eq!(monitor.idle_since, t0 + ms(500));
+
+        // Barely any CPU time is idle, but not for long enough yet.
+        assert_eq!(
+            monitor.sample(t0 + ms(1000), ms(251), 1, 0),
+            IdleAction::None
+        );
+        assert_eq!(
+            monitor.sample(t0 + ms(1500), ms(252), 1, 0),
+            IdleAction::Freeze
+        );
+
+        // A frozen microVM is only thawed by device activity.
+        assert_eq!(
+            monitor.sample(t0 + ms(2000), ms(252), 1, 0),
+            IdleAction::None
+        );
+        assert_eq!(
+            monitor.sample(t0 + ms(2500), ms(252), 1, 1),
+            IdleAction::Thaw
+        );
+
+        // Device activity keeps the microVM running.
+        assert_eq!(
+            monitor.sample(t0 + ms(4000), ms(252), 1, 2),
+            IdleAction::None
+        );
+        assert_eq!(monitor.idle_since, t0 + ms(4000));
+    }
+}
@@ -0,0 +1,139 @@
+//! Packing of the guest memory into the memory slots of KVM, whose number is limited by the
+//! host, and the allocation of those slots as regions come and go.
+
+use vmm_config::kvm_tuning::MemslotPacking;
+
+/// A range of the guest memory, contiguous in both the guest physical and the host virtual
+/// address spaces, mapped through a single memory slot of KVM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Memslot {
+    pub guest_addr: u64,
+    pub host_addr: u64,
+    pub size: u64,
+}
+
+impl Memslot {
+    /// Tells if `next` starts right where the slot ends, in both address spaces.
+    fn is_followed_by(&self, next: &Memslot) -> bool {
+        self.guest_addr.checked_add(self.size) == Some(next.guest_addr)
+            && self.host_addr.checked_add(self.size) == Some(next.host_addr)
+    }
+}
+
+/// Packs the regions of the guest memory, sorted by guest address, into memory slots.
+pub fn pack_regions<I: IntoIterator<Item = Memslot>>(
+    regions: I,
+    packing: MemslotPacking,
+) -> Vec<Memslot> {
+    let mut slots: Vec<Memslot> = Vec::new();
+    for region in regions {
+        match slots.last_mut() {
+            Some(last) if packing == MemslotPacking::Coalesce && last.is_followed_by(&region) => {
+                last.size += region.size
+            }
+            _ => slots.push(region),
+        }
+    }
+    slots
+}
+
+/// Hands out the memory slots of a VM, so the regions added once it's running, like hotplugged
+/// memory, reuse the slots of those removed rather than exhausting them.
+#[derive(Debug, Default)]
+pub struct MemslotAllocator {
+    max: u32,
+    // The slots below `next` which have been released, reused first.
+    released: Vec<u32>,
+    next: u32,
+}
+
+impl MemslotAllocator {
+    pub fn new(max: u32) -> Self {
+        MemslotAllocator {
+            max,
+            released: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns the lowest free slot, or `None` if they're all in use.
+    pub fn allocate(&mut self) -> Option<u32> {
+        if let Some(slot) = self.released.pop() {
+            return Some(slot);
+        }
+        if self.next < self.max {
+            self.next += 1;
+            return Some(self.next - 1);
+        }
+        None
+    }
+
+    /// Hands `slot` back, once its region has been removed.
+    pub fn release(&mut self, slot: u32) {
+        debug_assert!(slot < self.next && !self.released.contains(&slot));
+        self.released.push(slot);
+        // Kept sorted from the highest, so the lowest is reused first.
+        self.released.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    /// Returns the number of slots still free.
+    pub fn available(&self) -> u32 {
+        self.max - self.next + self.released.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(guest_addr: u64, host_addr: u64, size: u64) -> Memslot {
+        Memslot {
+            guest_addr,
+            host_addr,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_pack_regions() {
+        let regions = vec![
+            slot(0, 0x10_0000, 0x1000),
+            // Contiguous in both address spaces.
+            slot(0x1000, 0x10_1000, 0x2000),
+            // Only contiguous in the guest.
+            slot(0x3000, 0x20_0000, 0x1000),
+            // Only contiguous on the host.
+            slot(0x5000, 0x20_1000, 0x1000),
+        ];
+
+        assert_eq!(
+            pack_regions(regions.clone(), MemslotPacking::Coalesce),
+            vec![
+                slot(0, 0x10_0000, 0x3000),
+                slot(0x3000, 0x20_0000, 0x1000),
+                slot(0x5000, 0x20_1000, 0x1000),
+            ]
+        );
+        assert_eq!(
+            pack_regions(regions.clone(), MemslotPacking::PerRegion),
+            regions
+        );
+    }
+
+    #[test]
+    fn test_allocator() {
+        let mut allocator = MemslotAllocator::new(3);
+        assert_eq!(allocator.allocate(), Some(0));
+        assert_eq!(allocator.allocate(), Some(1));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), None);
+        assert_eq!(allocator.available(), 0);
+
+        allocator.release(2);
+        allocator.release(0);
+        assert_eq!(allocator.available(), 2);
+        assert_eq!(allocator.allocate(), Some(0));
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), None);
+    }
+}
@@ -1 +1,4 @@
+pub mod idle;
+pub mod memslots;
+pub mod profiler;
 pub mod vstate;
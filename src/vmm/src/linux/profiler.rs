@@ -0,0 +1,264 @@
+//! Periodic profiles of the vCPUs, taken from the host, so the workload can be profiled without
+//! any tooling in the guest.
+//!
+//! The CPU time of the threads of the vCPUs and their exits to the VMM are always sampled. The
+//! cycles and instructions spent in the guest are counted by the PMU of the host, through perf
+//! events excluding the host, which needs hardware counters and a permissive
+//! perf_event_paranoid.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use logger::METRICS;
+use utils::eventfd::EventFd;
+use vmm_config::profiling::{
+    ExitCounts, ProfileReport, ProfileReports, ProfilingConfig, VcpuProfile,
+};
+
+// Not exposed by the libc crate version we use.
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+// The bits of the flags of `PerfEventAttr`.
+const PERF_ATTR_EXCLUDE_HV: u64 = 1 << 6;
+const PERF_ATTR_EXCLUDE_HOST: u64 = 1 << 19;
+
+/// The layout of `struct perf_event_attr` as of PERF_ATTR_SIZE_VER5, which any kernel with
+/// guest-only counting accepts.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+/// Opens a counter of the hardware event `config` of the thread `tid`, only counting while it
+/// runs the guest.
+fn open_guest_counter(tid: libc::pid_t, config: u64) -> io::Result<File> {
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_HARDWARE,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        flags: PERF_ATTR_EXCLUDE_HV | PERF_ATTR_EXCLUDE_HOST,
+        ..Default::default()
+    };
+    // Safe because the attributes are valid for the duration of the call, and the return value
+    // is checked.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            tid,
+            -1,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the file descriptor was just opened, and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+fn read_counter(counter: &mut File) -> Option<u64> {
+    let mut value = [0u8; 8];
+    counter.read_exact(&mut value).ok()?;
+    Some(u64::from_ne_bytes(value))
+}
+
+/// The guest cycles and instructions counters of a vCPU, along with their last values.
+struct GuestCounters {
+    cycles: File,
+    instructions: File,
+    last: (u64, u64),
+}
+
+impl GuestCounters {
+    fn open(tid: libc::pid_t) -> io::Result<Self> {
+        Ok(GuestCounters {
+            cycles: open_guest_counter(tid, PERF_COUNT_HW_CPU_CYCLES)?,
+            instructions: open_guest_counter(tid, PERF_COUNT_HW_INSTRUCTIONS)?,
+            last: (0, 0),
+        })
+    }
+
+    /// Returns the cycles and the instructions since the previous call.
+    fn sample(&mut self) -> Option<(u64, u64)> {
+        let current = (
+            read_counter(&mut self.cycles)?,
+            read_counter(&mut self.instructions)?,
+        );
+        let delta = (
+            current.0.saturating_sub(self.last.0),
+            current.1.saturating_sub(self.last.1),
+        );
+        self.last = current;
+        Some(delta)
+    }
+}
+
+fn exit_counts() -> ExitCounts {
+    ExitCounts {
+        io_in: METRICS.vcpu.exit_io_in.count() as u64,
+        io_out: METRICS.vcpu.exit_io_out.count() as u64,
+        mmio_read: METRICS.vcpu.exit_mmio_read.count() as u64,
+        mmio_write: METRICS.vcpu.exit_mmio_write.count() as u64,
+    }
+}
+
+/// Samples the vCPUs every interval into the reports of the microVM.
+pub struct Profiler {
+    tick_evt: EventFd,
+    reports: ProfileReports,
+    counters: Vec<Option<GuestCounters>>,
+    sequence: u64,
+    last_sample: Instant,
+    last_cpu_times: Vec<Duration>,
+    last_exits: ExitCounts,
+}
+
+impl Profiler {
+    /// Creates a profiler of the vCPUs running on the threads `vcpu_tids`, whose `tick_evt` is
+    /// signaled every interval of `config`.
+    pub fn new(
+        config: ProfilingConfig,
+        reports: ProfileReports,
+        vcpu_tids: &[libc::pid_t],
+    ) -> io::Result<Self> {
+        let counters = vcpu_tids
+            .iter()
+            .map(|tid| {
+                if !config.pmu {
+                    return None;
+                }
+                GuestCounters::open(*tid)
+                    .map_err(|e| warn!("Unable to count the guest events of a vCPU: {}", e))
+                    .ok()
+            })
+            .collect();
+
+        let tick_evt = EventFd::new(utils::eventfd::EFD_NONBLOCK)?;
+        let thread_evt = tick_evt.try_clone()?;
+        thread::Builder::new()
+            .name("profiler".to_string())
+            .spawn(move || loop {
+                thread::sleep(config.interval);
+                if thread_evt.write(1).is_err() {
+                    break;
+                }
+            })?;
+
+        Ok(Profiler {
+            tick_evt,
+            reports,
+            counters,
+            sequence: 0,
+            last_sample: Instant::now(),
+            last_cpu_times: vec![Duration::from_secs(0); vcpu_tids.len()],
+            last_exits: exit_counts(),
+        })
+    }
+
+    /// The event signaled when the vCPUs should be sampled.
+    pub fn tick_evt(&self) -> &EventFd {
+        &self.tick_evt
+    }
+
+    /// Samples the vCPUs, given the CPU time consumed by their threads so far, and publishes the
+    /// report of what they did since the previous sample.
+    pub fn sample(&mut self, now: Instant, cpu_times: &[Option<Duration>]) {
+        let vcpus = self
+            .counters
+            .iter_mut()
+            .zip(self.last_cpu_times.iter_mut())
+            .zip(cpu_times)
+            .map(|((counters, last_cpu_time), cpu_time)| {
+                let cpu_time = cpu_time.unwrap_or(*last_cpu_time);
+                let delta = cpu_time
+                    .checked_sub(*last_cpu_time)
+                    .unwrap_or_else(|| Duration::from_secs(0));
+                *last_cpu_time = cpu_time;
+
+                let events = counters.as_mut().and_then(|counters| counters.sample());
+                VcpuProfile {
+                    cpu_time: delta,
+                    guest_cycles: events.map(|(cycles, _)| cycles),
+                    guest_instructions: events.map(|(_, instructions)| instructions),
+                }
+            })
+            .collect();
+
+        let exits = exit_counts();
+        self.sequence += 1;
+        self.reports.publish(ProfileReport {
+            sequence: self.sequence,
+            period: now.duration_since(self.last_sample),
+            vcpus,
+            exits: exits.since(&self.last_exits),
+        });
+        self.last_sample = now;
+        self.last_exits = exits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler() {
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 112);
+
+        let config = ProfilingConfig::new(Duration::from_secs(60), false).unwrap();
+        let reports = ProfileReports::default();
+        let mut profiler = Profiler::new(config, reports.clone(), &[1, 2]).unwrap();
+        let t0 = profiler.last_sample;
+        let ms = Duration::from_millis;
+
+        profiler.sample(t0 + ms(500), &[Some(ms(100)), None]);
+        METRICS.vcpu.exit_mmio_write.add(2);
+        profiler.sample(t0 + ms(1500), &[Some(ms(150)), Some(ms(10))]);
+
+        let report = reports.latest().unwrap();
+        assert_eq!(report.sequence, 2);
+        assert_eq!(report.period, ms(1000));
+        assert_eq!(
+            report.vcpus,
+            vec![
+                VcpuProfile {
+                    cpu_time: ms(50),
+                    ..Default::default()
+                },
+                VcpuProfile {
+                    cpu_time: ms(10),
+                    ..Default::default()
+                },
+            ]
+        );
+        // Other tests may exit concurrently.
+        assert!(report.exits.mmio_write >= 2);
+    }
+}
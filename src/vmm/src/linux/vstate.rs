@@ -7,18 +7,21 @@
 
 use libc::{c_int, c_void, siginfo_t};
 use std::cell::Cell;
+use std::cmp;
 use std::fmt::{Display, Formatter};
 use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::thread::JoinHandleExt;
 use std::result;
 use std::sync::atomic::{fence, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-#[cfg(not(test))]
-use std::sync::Barrier;
 use std::thread;
+use std::time::Duration;
 
 use super::super::TimestampUs;
 use super::super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
 
+use super::memslots::{self, Memslot, MemslotAllocator};
 use arch;
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
@@ -31,31 +34,68 @@ use kvm_bindings::{
     Msrs, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
     KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY,
 };
-use kvm_bindings::{kvm_userspace_memory_region, KVM_API_VERSION};
+use kvm_bindings::{kvm_enable_cap, kvm_userspace_memory_region, KVM_API_VERSION};
 use kvm_ioctls::*;
+use logger::METRICS;
 use utils::eventfd::EventFd;
 use utils::signal::{register_signal_handler, sigrtmin, Killable};
 use utils::sm::StateMachine;
 use vm_memory::{
     Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
+use vmm_config::boot_timeline::BootTimeline;
+use vmm_config::exec::ServiceMonitor;
+#[cfg(target_arch = "x86_64")]
+use vmm_config::kvm_tuning::TimerPolicy;
+use vmm_config::kvm_tuning::{KvmTuningConfig, MemslotPacking};
 use vmm_config::machine_config::CpuFeaturesTemplate;
+use vmm_config::probes::{self, ProbeReports};
+use vmm_config::workload::WorkloadStartedCallback;
 
 #[cfg(target_arch = "x86_64")]
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u64 = 0x03f0;
 #[cfg(target_arch = "aarch64")]
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u64 = 0x40000000;
 const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
+// Written by the init process of the guest right before executing the workload.
+const MAGIC_VALUE_SIGNAL_WORKLOAD_STARTED: u8 = 124;
 
 /// Signal number (SIGRTMIN) used to kick Vcpus.
 pub(crate) const VCPU_RTSIG_OFFSET: i32 = 0;
 
+// The capabilities tuning the VM, and the ioctls handling them, missing from kvm-ioctls.
+const KVM_CAP_HALT_POLL: u32 = 182;
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+// _IO(KVMIO, 0x03).
+const KVM_CHECK_EXTENSION: u64 = 0xae03;
+// _IOW(KVMIO, 0xa3, struct kvm_enable_cap).
+const KVM_ENABLE_CAP: u64 = 0x4068_aea3;
+// The size of an entry of the dirty ring, a struct kvm_dirty_gfn.
+const KVM_DIRTY_GFN_SIZE: u32 = 16;
+
+/// Returns what KVM reports for the capability `cap`, or zero if it's unknown to the host.
+fn check_extension_raw<F: AsRawFd>(fd: &F, cap: u32) -> u32 {
+    // Safe because the ioctl only takes the capability by value.
+    let ret = unsafe {
+        libc::ioctl(
+            fd.as_raw_fd(),
+            KVM_CHECK_EXTENSION as _,
+            cap as libc::c_ulong,
+        )
+    };
+    cmp::max(ret, 0) as u32
+}
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// A call to cpuid instruction failed.
     CpuId(cpuid::Error),
+    /// The host doesn't support a dirty ring of that many entries, or at all.
+    DirtyRingEntries(u32),
+    /// Cannot enable a KVM capability tuning the VM.
+    EnableCap(u32, io::Error),
     #[cfg(target_arch = "x86_64")]
     /// Error configuring the floating point related registers
     FPUConfiguration(arch::x86_64::regs::Error),
@@ -72,14 +112,18 @@ pub enum Error {
     KvmApiVersion(i32),
     /// Cannot initialize the KVM context due to missing capabilities.
     KvmCap(kvm_ioctls::Cap),
+    /// Cannot open the KVM device.
+    KvmOpen(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
     /// Cannot set the local interruption due to bad configuration.
     LocalIntConfiguration(arch::x86_64::interrupts::Error),
+    /// Cannot lock the guest memory in the RAM of the host.
+    LockMemory(io::Error),
     #[cfg(target_arch = "x86_64")]
     /// Error configuring the MSR registers
     MSRSConfiguration(arch::x86_64::msr::Error),
-    /// The number of configured slots is bigger than the maximum reported by KVM.
-    NotEnoughMemorySlots,
+    /// The guest memory needs more slots, the first number, than are free, the second.
+    NotEnoughMemorySlots(usize, usize),
     #[cfg(target_arch = "aarch64")]
     /// Error configuring the general purpose aarch64 registers.
     REGSConfiguration(arch::aarch64::regs::Error),
@@ -204,6 +248,12 @@ impl Display for Error {
         match self {
             #[cfg(target_arch = "x86_64")]
             CpuId(e) => write!(f, "Cpuid error: {:?}", e),
+            DirtyRingEntries(entries) => write!(
+                f,
+                "The host doesn't support a dirty ring of {} entries",
+                entries
+            ),
+            EnableCap(cap, e) => write!(f, "Cannot enable the KVM capability {}: {}", cap, e),
             GuestMemoryMmap(e) => write!(f, "Guest memory error: {:?}", e),
             #[cfg(target_arch = "x86_64")]
             GuestMSRs(e) => write!(f, "Retrieving supported guest MSRs fails: {:?}", e),
@@ -214,14 +264,17 @@ impl Display for Error {
                 v
             ),
             KvmCap(cap) => write!(f, "Missing KVM capabilities: {:?}", cap),
+            KvmOpen(e) => write!(f, "Cannot open /dev/kvm: {}", e),
             VcpuCountNotInitialized => write!(f, "vCPU count is not initialized"),
             VmFd(e) => write!(f, "Cannot open the VM file descriptor: {}", e),
             VcpuFd(e) => write!(f, "Cannot open the VCPU file descriptor: {}", e),
             VmSetup(e) => write!(f, "Cannot configure the microvm: {}", e),
             VcpuRun(e) => write!(f, "Cannot run the VCPUs: {}", e),
-            NotEnoughMemorySlots => write!(
+            NotEnoughMemorySlots(needed, free) => write!(
                 f,
-                "The number of configured slots is bigger than the maximum reported by KVM"
+                "The guest memory needs {} memory slots, but only {} of those reported by KVM are \
+                 free",
+                needed, free
             ),
             #[cfg(target_arch = "x86_64")]
             LocalIntConfiguration(e) => write!(
@@ -229,6 +282,7 @@ impl Display for Error {
                 "Cannot set the local interruption due to bad configuration: {:?}",
                 e
             ),
+            LockMemory(e) => write!(f, "Cannot lock the guest memory: {}", e),
             SetUserMemoryRegion(e) => write!(f, "Cannot set the memory regions: {}", e),
             SignalVcpu(e) => write!(f, "Failed to signal Vcpu: {}", e),
             #[cfg(target_arch = "x86_64")]
@@ -324,6 +378,10 @@ impl Display for Error {
     }
 }
 
+// The wrapped errors are reported as part of the message, as those of `arch` don't implement
+// `std::error::Error`.
+impl std::error::Error for Error {}
+
 pub type Result<T> = result::Result<T, Error>;
 
 /// Describes a KVM context that gets attached to the microVM.
@@ -332,12 +390,14 @@ pub type Result<T> = result::Result<T, Error>;
 pub struct KvmContext {
     kvm: Kvm,
     max_memslots: usize,
+    max_dirty_ring_entries: u32,
+    halt_poll: bool,
 }
 
 impl KvmContext {
     pub fn new() -> Result<Self> {
         use kvm_ioctls::Cap::*;
-        let kvm = Kvm::new().expect("Error creating the Kvm object");
+        let kvm = Kvm::new().map_err(Error::KvmOpen)?;
 
         // Check that KVM has the correct version.
         if kvm.get_api_version() != KVM_API_VERSION as i32 {
@@ -358,7 +418,16 @@ impl KvmContext {
         {
             None => {
                 let max_memslots = kvm.get_nr_memslots();
-                Ok(KvmContext { kvm, max_memslots })
+                // KVM reports the size of the largest dirty ring, in bytes.
+                let max_dirty_ring_entries =
+                    check_extension_raw(&kvm, KVM_CAP_DIRTY_LOG_RING) / KVM_DIRTY_GFN_SIZE;
+                let halt_poll = check_extension_raw(&kvm, KVM_CAP_HALT_POLL) != 0;
+                Ok(KvmContext {
+                    kvm,
+                    max_memslots,
+                    max_dirty_ring_entries,
+                    halt_poll,
+                })
             }
 
             Some(c) => Err(Error::KvmCap(*c)),
@@ -373,17 +442,32 @@ impl KvmContext {
     pub fn max_memslots(&self) -> usize {
         self.max_memslots
     }
+
+    /// Returns the largest dirty ring of a vCPU supported by the host, in entries, or zero if
+    /// the host doesn't support the dirty ring.
+    pub fn max_dirty_ring_entries(&self) -> u32 {
+        self.max_dirty_ring_entries
+    }
+
+    /// Tells if the host lets the halt polling be tuned for each VM.
+    pub fn supports_halt_poll(&self) -> bool {
+        self.halt_poll
+    }
 }
 
 /// A wrapper around creating and using a VM.
 pub struct Vm {
     fd: VmFd,
+    memslots: MemslotAllocator,
+    memslot_packing: MemslotPacking,
 
     // X86 specific fields.
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     supported_cpuid: CpuId,
     #[cfg(target_arch = "x86_64")]
     supported_msrs: MsrList,
+    #[cfg(target_arch = "x86_64")]
+    timer_policy: TimerPolicy,
 
     // Arm specific fields.
     // On aarch64 we need to keep around the fd obtained by creating the VGIC device.
@@ -407,10 +491,14 @@ impl Vm {
 
         Ok(Vm {
             fd: vm_fd,
+            memslots: MemslotAllocator::default(),
+            memslot_packing: MemslotPacking::default(),
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             supported_cpuid,
             #[cfg(target_arch = "x86_64")]
             supported_msrs,
+            #[cfg(target_arch = "x86_64")]
+            timer_policy: TimerPolicy::default(),
             #[cfg(target_arch = "aarch64")]
             irqchip_handle: None,
         })
@@ -428,32 +516,58 @@ impl Vm {
         &self.supported_msrs
     }
 
+    /// Sets how `memory_init` packs the regions of the guest memory into memory slots.
+    pub fn set_memslot_packing(&mut self, packing: MemslotPacking) {
+        self.memslot_packing = packing;
+    }
+
+    /// Sets the legacy timers `setup_irqchip` creates.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_timer_policy(&mut self, timer_policy: TimerPolicy) {
+        self.timer_policy = timer_policy;
+    }
+
+    /// Returns the number of memory slots still free, for the regions added once the guest
+    /// memory is initialized.
+    pub fn free_memslots(&self) -> usize {
+        self.memslots.available() as usize
+    }
+
     /// Initializes the guest memory.
     pub fn memory_init(
         &mut self,
         guest_mem: &GuestMemoryMmap,
         kvm_max_memslots: usize,
     ) -> Result<()> {
-        if guest_mem.num_regions() > kvm_max_memslots {
-            return Err(Error::NotEnoughMemorySlots);
+        self.memslots = MemslotAllocator::new(kvm_max_memslots as u32);
+
+        let regions = guest_mem.iter().map(|region| {
+            // It's safe to unwrap because the guest address is valid.
+            let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
+            info!("Guest memory starts at {:x?}", host_addr);
+            Memslot {
+                guest_addr: region.start_addr().raw_value(),
+                host_addr: host_addr as u64,
+                size: region.len() as u64,
+            }
+        });
+        let slots = memslots::pack_regions(regions, self.memslot_packing);
+        if slots.len() > self.free_memslots() {
+            return Err(Error::NotEnoughMemorySlots(
+                slots.len(),
+                self.free_memslots(),
+            ));
+        }
+        debug!(
+            "Packed {} guest memory regions into {} memory slots",
+            guest_mem.num_regions(),
+            slots.len()
+        );
+        for slot in slots {
+            // Safe because we mapped the memory region, we made sure that the regions are not
+            // overlapping.
+            unsafe { self.add_memory_region(slot) }?;
         }
-        guest_mem
-            .with_regions(|index, region| {
-                // It's safe to unwrap because the guest address is valid.
-                let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
-                info!("Guest memory starts at {:x?}", host_addr);
-                let memory_region = kvm_userspace_memory_region {
-                    slot: index as u32,
-                    guest_phys_addr: region.start_addr().raw_value() as u64,
-                    memory_size: region.len() as u64,
-                    userspace_addr: host_addr as u64,
-                    flags: 0,
-                };
-                // Safe because we mapped the memory region, we made sure that the regions
-                // are not overlapping.
-                unsafe { self.fd.set_user_memory_region(memory_region) }
-            })
-            .map_err(Error::SetUserMemoryRegion)?;
 
         #[cfg(target_arch = "x86_64")]
         self.fd
@@ -463,10 +577,114 @@ impl Vm {
         Ok(())
     }
 
-    /// Creates the irq chip and an in-kernel device model for the PIT.
+    fn enable_cap(&self, cap: u32, arg: u64) -> Result<()> {
+        let mut enable_cap = kvm_enable_cap {
+            cap,
+            ..Default::default()
+        };
+        enable_cap.args[0] = arg;
+        // Safe because the kernel only reads the struct, which outlives the call.
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                KVM_ENABLE_CAP as _,
+                &enable_cap as *const kvm_enable_cap,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::EnableCap(cap, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Applies the tunings of KVM in `tuning`, checking the host supports them through `kvm`.
+    /// It must be called before the vCPUs are created.
+    pub fn tune(&self, kvm: &KvmContext, tuning: &KvmTuningConfig) -> Result<()> {
+        if let Some(entries) = tuning.dirty_ring_entries {
+            if entries > kvm.max_dirty_ring_entries() {
+                return Err(Error::DirtyRingEntries(entries));
+            }
+            self.enable_cap(
+                KVM_CAP_DIRTY_LOG_RING,
+                u64::from(entries) * u64::from(KVM_DIRTY_GFN_SIZE),
+            )?;
+        }
+        if let Some(halt_poll_ns) = tuning.halt_poll_ns {
+            if !kvm.supports_halt_poll() {
+                return Err(Error::EnableCap(
+                    KVM_CAP_HALT_POLL,
+                    io::Error::from_raw_os_error(libc::ENOTSUP),
+                ));
+            }
+            self.enable_cap(KVM_CAP_HALT_POLL, u64::from(halt_poll_ns))?;
+        }
+        Ok(())
+    }
+
+    /// Faults in and locks the guest memory in the RAM of the host, so it's never faulted in
+    /// lazily nor swapped out. It's bound by the RLIMIT_MEMLOCK of the process.
+    pub fn lock_memory(&self, guest_mem: &GuestMemoryMmap) -> Result<()> {
+        guest_mem
+            .with_regions(|_, region| {
+                // It's safe to unwrap because the guest address is valid.
+                let host_addr = guest_mem.get_host_address(region.start_addr()).unwrap();
+                // Safe because the region is mapped for its whole length.
+                if unsafe { libc::mlock(host_addr as *const c_void, region.len() as usize) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .map_err(Error::LockMemory)
+    }
+
+    /// Maps `region` into the guest through a free memory slot, returning the slot. Regions can
+    /// be added, and removed, once the guest is running, like hotplugged memory.
+    ///
+    /// # Safety
+    ///
+    /// The host range of `region` must be mapped, and must outlive the slot. Its guest range must
+    /// not overlap with another region.
+    pub unsafe fn add_memory_region(&mut self, region: Memslot) -> Result<u32> {
+        let slot = match self.memslots.allocate() {
+            Some(slot) => slot,
+            None => return Err(Error::NotEnoughMemorySlots(1, 0)),
+        };
+        let memory_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: region.guest_addr,
+            memory_size: region.size,
+            userspace_addr: region.host_addr,
+            flags: 0,
+        };
+        if let Err(e) = self.fd.set_user_memory_region(memory_region) {
+            self.memslots.release(slot);
+            return Err(Error::SetUserMemoryRegion(e));
+        }
+        Ok(slot)
+    }
+
+    /// Unmaps the region added through `slot` from the guest, freeing the slot.
+    pub fn remove_memory_region(&mut self, slot: u32) -> Result<()> {
+        // A slot is deleted by setting its size to zero.
+        let memory_region = kvm_userspace_memory_region {
+            slot,
+            ..Default::default()
+        };
+        // Safe because removing a slot doesn't give the guest access to any memory.
+        unsafe { self.fd.set_user_memory_region(memory_region) }
+            .map_err(Error::SetUserMemoryRegion)?;
+        self.memslots.release(slot);
+        Ok(())
+    }
+
+    /// Creates the irq chip and, unless the timer policy leaves it out, an in-kernel device model
+    /// for the PIT.
     #[cfg(target_arch = "x86_64")]
     pub fn setup_irqchip(&self) -> Result<()> {
         self.fd.create_irq_chip().map_err(Error::VmSetup)?;
+        if self.timer_policy == TimerPolicy::None {
+            return Ok(());
+        }
         let mut pit_config = kvm_pit_config::default();
         // We need to enable the emulation of a dummy speaker port stub so that writing to port 0x61
         // (i.e. KVM_SPEAKER_BASE_ADDRESS) does not trigger an exit to user space.
@@ -498,7 +716,10 @@ impl Vm {
     #[cfg(target_arch = "x86_64")]
     /// Saves and returns the Kvm Vm state.
     pub fn save_state(&self) -> Result<VmState> {
-        let pitstate = self.fd.get_pit2().map_err(Error::VmGetPit2)?;
+        let pitstate = match self.timer_policy {
+            TimerPolicy::Pit => Some(self.fd.get_pit2().map_err(Error::VmGetPit2)?),
+            TimerPolicy::None => None,
+        };
 
         let mut clock = self.fd.get_clock().map_err(Error::VmGetClock)?;
         // This bit is not accepted in SET_CLOCK, clear it.
@@ -535,9 +756,9 @@ impl Vm {
     #[cfg(target_arch = "x86_64")]
     /// Restores the Kvm Vm state.
     pub fn restore_state(&self, state: &VmState) -> Result<()> {
-        self.fd
-            .set_pit2(&state.pitstate)
-            .map_err(Error::VmSetPit2)?;
+        if let Some(pitstate) = &state.pitstate {
+            self.fd.set_pit2(pitstate).map_err(Error::VmSetPit2)?;
+        }
         self.fd.set_clock(&state.clock).map_err(Error::VmSetClock)?;
         self.fd
             .set_irqchip(&state.pic_master)
@@ -556,7 +777,7 @@ impl Vm {
 #[cfg(target_arch = "x86_64")]
 /// Structure holding VM kvm state.
 pub struct VmState {
-    pitstate: kvm_pit_state2,
+    pitstate: Option<kvm_pit_state2>,
     clock: kvm_clock_data,
     pic_master: kvm_irqchip,
     pic_slave: kvm_irqchip,
@@ -570,6 +791,8 @@ pub struct VcpuConfig {
     pub vcpu_count: u8,
     /// Enable hyperthreading in the CPUID configuration.
     pub ht_enabled: bool,
+    /// How the guest VCPUs are laid out in sockets, cores and threads.
+    pub topology: arch::CpuTopology,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
 }
@@ -585,6 +808,10 @@ pub struct Vcpu {
     mmio_bus: Option<devices::Bus>,
     #[cfg_attr(all(test, target_arch = "aarch64"), allow(unused))]
     exit_evt: EventFd,
+    workload_started: Option<WorkloadStartedCallback>,
+    probe_reports: Option<ProbeReports>,
+    service_monitor: Option<ServiceMonitor>,
+    boot_timeline: Option<BootTimeline>,
 
     #[cfg(target_arch = "x86_64")]
     io_bus: devices::Bus,
@@ -721,6 +948,10 @@ impl Vcpu {
             create_ts,
             mmio_bus: None,
             exit_evt,
+            workload_started: None,
+            probe_reports: None,
+            service_monitor: None,
+            boot_timeline: None,
             io_bus,
             cpuid,
             msr_list,
@@ -756,6 +987,10 @@ impl Vcpu {
             create_ts,
             mmio_bus: None,
             exit_evt,
+            workload_started: None,
+            probe_reports: None,
+            service_monitor: None,
+            boot_timeline: None,
             mpidr: 0,
             event_receiver,
             event_sender: Some(event_sender),
@@ -780,6 +1015,26 @@ impl Vcpu {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Sets the callback invoked once the guest reports the workload has been started.
+    pub fn set_workload_started_callback(&mut self, callback: WorkloadStartedCallback) {
+        self.workload_started = Some(callback);
+    }
+
+    /// Sets where the results of the probes run by the guest are reported.
+    pub fn set_probe_reports(&mut self, reports: ProbeReports) {
+        self.probe_reports = Some(reports);
+    }
+
+    /// Sets what handles the events of the services run by the guest.
+    pub fn set_service_monitor(&mut self, monitor: ServiceMonitor) {
+        self.service_monitor = Some(monitor);
+    }
+
+    /// Sets where the milestones of the boot reported by the guest are recorded.
+    pub fn set_boot_timeline(&mut self, timeline: BootTimeline) {
+        self.boot_timeline = Some(timeline);
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Configures a x86_64 specific vcpu and should be called once per vcpu.
     ///
@@ -794,8 +1049,14 @@ impl Vcpu {
         kernel_start_addr: GuestAddress,
         vcpu_config: &VcpuConfig,
     ) -> Result<()> {
-        let cpuid_vm_spec = VmSpec::new(self.id, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
-            .map_err(Error::CpuId)?;
+        let topology = &vcpu_config.topology;
+        let cpuid_vm_spec = VmSpec::with_topology(
+            self.id,
+            topology.sockets,
+            topology.cores_per_socket,
+            topology.threads_per_core,
+        )
+        .map_err(Error::CpuId)?;
 
         filter_cpuid(&mut self.cpuid, &cpuid_vm_spec).map_err(|e| {
             error!("Failure in configuring CPUID for vcpu {}: {:?}", self.id, e);
@@ -874,15 +1135,23 @@ impl Vcpu {
                 self.init_thread_local_data()
                     .expect("Cannot cleanly initialize vcpu TLS.");
 
+                // Safe because gettid can't fail.
+                let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
                 init_tls_sender
-                    .send(true)
+                    .send(tid)
                     .expect("Cannot notify vcpu TLS initialization.");
 
+                let _cpu_time = METRICS
+                    .vcpu
+                    .cpu_time_us
+                    .track_current_thread()
+                    .map_err(|e| warn!("Unable to track the CPU time of the vcpu: {}", e))
+                    .ok();
                 self.run();
             })
             .map_err(Error::VcpuSpawn)?;
 
-        init_tls_receiver
+        let tid = init_tls_receiver
             .recv()
             .expect("Error waiting for TLS initialization.");
 
@@ -890,14 +1159,39 @@ impl Vcpu {
             event_sender,
             response_receiver,
             vcpu_thread,
+            tid,
         ))
     }
 
-    fn check_boot_complete_signal(&self, addr: u64, data: &[u8]) {
-        if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
-            && data[0] == MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE
-        {
-            super::super::Vmm::log_boot_time(&self.create_ts);
+    fn check_guest_signal(&self, addr: u64, data: &[u8]) {
+        if addr != MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE {
+            return;
+        }
+        match data[0] {
+            MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE => {
+                super::super::Vmm::log_boot_time(&self.create_ts);
+                if let Some(timeline) = &self.boot_timeline {
+                    timeline.mark("boot_complete");
+                }
+            }
+            MAGIC_VALUE_SIGNAL_WORKLOAD_STARTED => {
+                info!("The guest reported the workload has been started");
+                if let Some(timeline) = &self.boot_timeline {
+                    timeline.mark("workload_started");
+                }
+                if let Some(callback) = &self.workload_started {
+                    callback.notify();
+                }
+            }
+            value => {
+                if let (Some((kind, success)), Some(reports)) =
+                    (probes::guest_result(value), &self.probe_reports)
+                {
+                    reports.report(kind, success);
+                } else if let Some(monitor) = &self.service_monitor {
+                    monitor.report(value);
+                }
+            }
         }
     }
 
@@ -1025,26 +1319,30 @@ impl Vcpu {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
+                    METRICS.vcpu.exit_io_in.inc();
                     self.io_bus.read(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoOut(addr, data) => {
-                    self.check_boot_complete_signal(u64::from(addr), data);
+                    METRICS.vcpu.exit_io_out.inc();
+                    self.check_guest_signal(u64::from(addr), data);
 
                     self.io_bus.write(0, u64::from(addr), data);
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    METRICS.vcpu.exit_mmio_read.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.read(0, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    METRICS.vcpu.exit_mmio_write.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         #[cfg(target_arch = "aarch64")]
-                        self.check_boot_complete_signal(addr, data);
+                        self.check_guest_signal(addr, data);
 
                         mmio_bus.write(0, addr, data);
                     }
@@ -1061,16 +1359,19 @@ impl Vcpu {
                 // Documentation specifies that below kvm exits are considered
                 // errors.
                 VcpuExit::FailEntry => {
+                    METRICS.vcpu.failures.inc();
                     error!("Received KVM_EXIT_FAIL_ENTRY signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 VcpuExit::InternalError => {
+                    METRICS.vcpu.failures.inc();
                     error!("Received KVM_EXIT_INTERNAL_ERROR signal");
                     Err(Error::VcpuUnhandledKvmExit)
                 }
                 r => {
                     // TODO: Are we sure we want to finish running a vcpu upon
                     // receiving a vm exit that is not necessarily an error?
+                    METRICS.vcpu.failures.inc();
                     error!("Unexpected exit reason on vcpu run: {:?}", r);
                     Err(Error::VcpuUnhandledKvmExit)
                 }
@@ -1150,6 +1451,8 @@ impl Vcpu {
                     .send(VcpuResponse::Resumed)
                     .expect("failed to send resume status");
             }
+            // Running ---- Finish ----> end
+            Ok(VcpuEvent::Finish) => state = StateMachine::finish(),
             // Unhandled exit of the other end.
             Err(TryRecvError::Disconnected) => {
                 // Move to 'exited' state.
@@ -1174,6 +1477,8 @@ impl Vcpu {
                 // Move to 'running' state.
                 StateMachine::next(Self::running)
             }
+            // Paused ---- Finish ----> end
+            Ok(VcpuEvent::Finish) => StateMachine::finish(),
             // All other events have no effect on current 'paused' state.
             Ok(_) => StateMachine::next(Self::paused),
             // Unhandled exit of the other end.
@@ -1202,12 +1507,14 @@ impl Vcpu {
     #[cfg(not(test))]
     // This is the main loop of the `Exited` state.
     fn exited(&mut self) -> StateMachine<Self> {
-        // Wait indefinitely.
-        // The VMM thread will kill the entire process.
-        let barrier = Barrier::new(2);
-        barrier.wait();
-
-        StateMachine::finish()
+        // Wait for the VMM thread to kill the entire process, or to finish the Vcpu if the
+        // microVM is torn down instead.
+        loop {
+            match self.event_receiver.recv() {
+                Ok(VcpuEvent::Finish) | Err(_) => return StateMachine::finish(),
+                Ok(_) => (),
+            }
+        }
     }
 
     #[cfg(test)]
@@ -1250,6 +1557,8 @@ pub enum VcpuEvent {
     Pause,
     /// Event that should resume the Vcpu.
     Resume,
+    /// End the thread of the Vcpu, releasing it.
+    Finish,
     // Serialize and Deserialize to follow after we get the support from kvm-ioctls.
 }
 
@@ -1271,6 +1580,8 @@ pub struct VcpuHandle {
     // Rust JoinHandles have to be wrapped in Option if you ever plan on 'join()'ing them.
     // We want to be able to join these threads in tests.
     vcpu_thread: Option<thread::JoinHandle<()>>,
+    // The id of the thread in the kernel, for the interfaces taking one.
+    tid: libc::pid_t,
 }
 
 impl VcpuHandle {
@@ -1278,14 +1589,21 @@ impl VcpuHandle {
         event_sender: Sender<VcpuEvent>,
         response_receiver: Receiver<VcpuResponse>,
         vcpu_thread: thread::JoinHandle<()>,
+        tid: libc::pid_t,
     ) -> Self {
         Self {
             event_sender,
             response_receiver,
             vcpu_thread: Some(vcpu_thread),
+            tid,
         }
     }
 
+    /// Returns the id of the thread of the vCPU in the kernel.
+    pub fn tid(&self) -> libc::pid_t {
+        self.tid
+    }
+
     pub fn send_event(&self, event: VcpuEvent) -> Result<()> {
         // Use expect() to crash if the other thread closed this channel.
         self.event_sender
@@ -1301,9 +1619,53 @@ impl VcpuHandle {
         Ok(())
     }
 
+    /// Ends the thread of the Vcpu, whatever its state, and waits for it, releasing the Vcpu.
+    pub fn finish(&mut self) {
+        let vcpu_thread = match self.vcpu_thread.take() {
+            Some(vcpu_thread) => vcpu_thread,
+            None => return,
+        };
+        // The thread is gone already if the channel is closed.
+        if self.event_sender.send(VcpuEvent::Finish).is_ok() {
+            // Kick the vcpu out of KVM_RUN so it picks up the message.
+            if let Err(e) = vcpu_thread.kill(sigrtmin() + VCPU_RTSIG_OFFSET) {
+                error!("Failed to signal the vcpu to finish: {}", e);
+                return;
+            }
+        }
+        if vcpu_thread.join().is_err() {
+            error!("The vcpu thread panicked");
+        }
+    }
+
     pub fn response_receiver(&self) -> &Receiver<VcpuResponse> {
         &self.response_receiver
     }
+
+    /// Returns the CPU time consumed by the vCPU thread so far.
+    pub fn cpu_time(&self) -> Option<Duration> {
+        let thread = self.vcpu_thread.as_ref()?.as_pthread_t();
+        let mut clock_id: libc::clockid_t = 0;
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safe because the thread can't be joined while we hold its handle, and we pass valid
+        // pointers.
+        unsafe {
+            if pthread_getcpuclockid(thread, &mut clock_id) != 0
+                || libc::clock_gettime(clock_id, &mut ts) != 0
+            {
+                return None;
+            }
+        }
+        Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+}
+
+extern "C" {
+    // Not exposed by the libc crate version we use.
+    fn pthread_getcpuclockid(thread: libc::pthread_t, clock_id: *mut libc::clockid_t) -> c_int;
 }
 
 enum VcpuEmulation {
@@ -1331,6 +1693,10 @@ mod tests {
     // In tests we need to close any pending Vcpu threads on test completion.
     impl Drop for VcpuHandle {
         fn drop(&mut self) {
+            if self.vcpu_thread.is_none() {
+                // The Vcpu was finished already.
+                return;
+            }
             // Make sure the Vcpu is out of KVM_RUN.
             self.send_event(VcpuEvent::Pause).unwrap();
             // Close the original channel so that the Vcpu thread errors and goes to exit state.
@@ -1420,6 +1786,53 @@ mod tests {
         assert!(vm.memory_init(&gm, kvm_context.max_memslots()).is_err());
     }
 
+    #[test]
+    fn test_add_memory_region() {
+        let kvm_context = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        vm.memory_init(&gm, 2).unwrap();
+        assert_eq!(vm.free_memslots(), 1);
+
+        // The slot of a removed region is reused by the next one.
+        let hotplug = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x10_0000), 0x1000)]).unwrap();
+        let host_addr = hotplug.get_host_address(GuestAddress(0x10_0000)).unwrap();
+        let region = Memslot {
+            guest_addr: 0x10_0000,
+            host_addr: host_addr as u64,
+            size: 0x1000,
+        };
+        let slot = unsafe { vm.add_memory_region(region) }.unwrap();
+        assert_eq!(vm.free_memslots(), 0);
+        match unsafe { vm.add_memory_region(region) } {
+            Err(Error::NotEnoughMemorySlots(1, 0)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        vm.remove_memory_region(slot).unwrap();
+        assert_eq!(unsafe { vm.add_memory_region(region) }.unwrap(), slot);
+    }
+
+    #[test]
+    fn test_vm_tune() {
+        let kvm_context = KvmContext::new().unwrap();
+        let vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+        assert!(vm.tune(&kvm_context, &KvmTuningConfig::default()).is_ok());
+
+        // A dirty ring larger than the host supports is refused before reaching KVM.
+        let entries = (kvm_context.max_dirty_ring_entries() + 1).next_power_of_two();
+        let tuning = KvmTuningConfig {
+            dirty_ring_entries: Some(entries),
+            ..Default::default()
+        };
+        match vm.tune(&kvm_context, &tuning) {
+            Err(Error::DirtyRingEntries(e)) => assert_eq!(e, entries),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        assert!(vm.lock_memory(&gm).is_ok());
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_setup_irqchip() {
@@ -1474,6 +1887,7 @@ mod tests {
         let mut vcpu_config = VcpuConfig {
             vcpu_count: 1,
             ht_enabled: false,
+            topology: arch::CpuTopology::single_socket(1, false),
             cpu_template: None,
         };
 
@@ -1673,7 +2087,7 @@ mod tests {
         let (vm, _, _mem) = setup_vcpu(0x1000);
         let vm_state = vm.save_state().unwrap();
         assert_eq!(
-            vm_state.pitstate.flags | KVM_PIT_SPEAKER_DUMMY,
+            vm_state.pitstate.unwrap().flags | KVM_PIT_SPEAKER_DUMMY,
             KVM_PIT_SPEAKER_DUMMY
         );
         assert_eq!(vm_state.clock.flags & KVM_CLOCK_TSC_STABLE, 0);
@@ -1683,6 +2097,16 @@ mod tests {
 
         let (vm, _, _mem) = setup_vcpu(0x1000);
         assert!(vm.restore_state(&vm_state).is_ok());
+
+        // Without a PIT, there's no state of it to save.
+        let kvm = KvmContext::new().unwrap();
+        let mut vm = Vm::new(kvm.fd()).unwrap();
+        vm.set_timer_policy(TimerPolicy::None);
+        vm.setup_irqchip().unwrap();
+        assert!(vm.fd().get_pit2().is_err());
+        let vm_state = vm.save_state().unwrap();
+        assert!(vm_state.pitstate.is_none());
+        assert!(vm.restore_state(&vm_state).is_ok());
     }
 
     #[cfg(target_arch = "x86_64")]
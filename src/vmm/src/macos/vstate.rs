@@ -22,11 +22,20 @@ use arch;
 use arch::aarch64::gic::GICDevice;
 use devices::legacy::Gic;
 use hvf::{HvfVcpu, HvfVm, VcpuExit};
+use logger::METRICS;
 use utils::eventfd::EventFd;
 use vm_memory::{
     Address, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
 };
+use vmm_config::boot_timeline::BootTimeline;
+use vmm_config::exec::ServiceMonitor;
 use vmm_config::machine_config::CpuFeaturesTemplate;
+use vmm_config::probes::{self, ProbeReports};
+use vmm_config::workload::WorkloadStartedCallback;
+
+// Written by the init process of the guest right before executing the workload.
+const MAGIC_MMIO_SIGNAL_GUEST: u64 = 0x4000_0000;
+const MAGIC_VALUE_SIGNAL_WORKLOAD_STARTED: u8 = 124;
 
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
@@ -98,6 +107,10 @@ impl Display for Error {
     }
 }
 
+// The wrapped errors are reported as part of the message, as those of `arch` don't implement
+// `std::error::Error`.
+impl std::error::Error for Error {}
+
 pub type Result<T> = result::Result<T, Error>;
 
 /// A wrapper around creating and using a VM.
@@ -159,6 +172,8 @@ pub struct VcpuConfig {
     pub vcpu_count: u8,
     /// Enable hyperthreading in the CPUID configuration.
     pub ht_enabled: bool,
+    /// How the guest VCPUs are laid out in sockets, cores and threads.
+    pub topology: arch::CpuTopology,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
 }
@@ -176,6 +191,10 @@ pub struct Vcpu {
     mmio_bus: Option<devices::Bus>,
     #[cfg_attr(all(test, target_arch = "aarch64"), allow(unused))]
     exit_evt: EventFd,
+    workload_started: Option<WorkloadStartedCallback>,
+    probe_reports: Option<ProbeReports>,
+    service_monitor: Option<ServiceMonitor>,
+    boot_timeline: Option<BootTimeline>,
 
     #[cfg(target_arch = "aarch64")]
     mpidr: u64,
@@ -277,6 +296,10 @@ impl Vcpu {
             fdt_addr: 0,
             mmio_bus: None,
             exit_evt,
+            workload_started: None,
+            probe_reports: None,
+            service_monitor: None,
+            boot_timeline: None,
             mpidr: 0,
             event_receiver,
             event_sender: Some(event_sender),
@@ -301,6 +324,47 @@ impl Vcpu {
         self.mmio_bus = Some(mmio_bus);
     }
 
+    /// Sets the callback invoked once the guest reports the workload has been started.
+    pub fn set_workload_started_callback(&mut self, callback: WorkloadStartedCallback) {
+        self.workload_started = Some(callback);
+    }
+
+    /// Sets where the results of the probes run by the guest are reported.
+    pub fn set_probe_reports(&mut self, reports: ProbeReports) {
+        self.probe_reports = Some(reports);
+    }
+
+    /// Sets what handles the events of the services run by the guest.
+    pub fn set_service_monitor(&mut self, monitor: ServiceMonitor) {
+        self.service_monitor = Some(monitor);
+    }
+
+    /// Sets where the milestones of the boot reported by the guest are recorded.
+    pub fn set_boot_timeline(&mut self, timeline: BootTimeline) {
+        self.boot_timeline = Some(timeline);
+    }
+
+    fn check_guest_signal(&self, addr: u64, data: &[u8]) {
+        if addr != MAGIC_MMIO_SIGNAL_GUEST {
+            return;
+        }
+        if data[0] == MAGIC_VALUE_SIGNAL_WORKLOAD_STARTED {
+            info!("The guest reported the workload has been started");
+            if let Some(timeline) = &self.boot_timeline {
+                timeline.mark("workload_started");
+            }
+            if let Some(callback) = &self.workload_started {
+                callback.notify();
+            }
+        } else if let (Some((kind, success)), Some(reports)) =
+            (probes::guest_result(data[0]), &self.probe_reports)
+        {
+            reports.report(kind, success);
+        } else if let Some(monitor) = &self.service_monitor {
+            monitor.report(data[0]);
+        }
+    }
+
     pub fn set_boot_senders(&mut self, boot_senders: Vec<Sender<u64>>) {
         self.boot_senders = Some(boot_senders);
     }
@@ -389,13 +453,17 @@ impl Vcpu {
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    METRICS.vcpu.exit_mmio_read.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
                         mmio_bus.read(vcpuid, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    METRICS.vcpu.exit_mmio_write.inc();
                     if let Some(ref mmio_bus) = self.mmio_bus {
+                        self.check_guest_signal(addr, data);
+
                         mmio_bus.write(vcpuid, addr, data);
                     }
                     Ok(VcpuEmulation::Handled)
@@ -754,6 +822,7 @@ mod tests {
         let mut vcpu_config = VcpuConfig {
             vcpu_count: 1,
             ht_enabled: false,
+            topology: arch::CpuTopology::single_socket(1, false),
             cpu_template: None,
         };
 
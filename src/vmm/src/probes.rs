@@ -0,0 +1,56 @@
+use std::net::TcpStream;
+use std::thread;
+use std::time::Instant;
+
+use vmm_config::probes::{
+    ProbeAction, ProbeCallback, ProbeConfig, ProbeReports, ProbeState, ProbeTracker,
+};
+
+/// Runs the probe described by `config` for as long as the VMM runs, invoking `callback`, or
+/// logging, whenever its state changes.
+///
+/// The commands run in the guest are run on the schedule of the guest, so their results are
+/// collected from `reports` once a period. Before the guest reports the first result the probe
+/// stays unknown, as the guest may still be booting. After that, going a period and a timeout
+/// without a report counts as a failure, as the guest may be stuck.
+pub(crate) fn run_probe(
+    config: ProbeConfig,
+    reports: ProbeReports,
+    callback: Option<ProbeCallback>,
+) {
+    let timing = config.timing;
+    let mut tracker = ProbeTracker::new(timing);
+    let mut last_report: Option<Instant> = None;
+
+    thread::sleep(timing.initial_delay);
+    loop {
+        let started = Instant::now();
+        let success = match &config.action {
+            ProbeAction::Exec(_) => match reports.take(config.kind) {
+                Some(success) => {
+                    last_report = Some(started);
+                    Some(success)
+                }
+                None => last_report
+                    .filter(|last| started.duration_since(*last) > timing.period + timing.timeout)
+                    .map(|_| false),
+            },
+            ProbeAction::TcpConnect(addr) => {
+                Some(TcpStream::connect_timeout(addr, timing.timeout).is_ok())
+            }
+            ProbeAction::FileExists(path) => Some(path.exists()),
+        };
+
+        if let Some(state) = success.and_then(|success| tracker.record(success)) {
+            match &callback {
+                Some(callback) => callback(config.kind, state),
+                None if state == ProbeState::Failure => {
+                    warn!("The {:?} probe of the workload is failing", config.kind)
+                }
+                None => info!("The {:?} probe of the workload is succeeding", config.kind),
+            }
+        }
+
+        thread::sleep(timing.period.saturating_sub(started.elapsed()));
+    }
+}
@@ -3,12 +3,73 @@
 
 //#![deny(warnings)]
 
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arch::CpuTopology;
+use devices::legacy::SecretsMailbox;
+use devices::virtio::record::Recorder;
+use devices::virtio::{LookupCacheConfig, VirtioDevice};
+use polly::event_manager::Subscriber;
 use vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
+use vmm_config::boot_timeline::BootTimeline;
+use vmm_config::ca_certs::{CaCerts, CaCertsError};
+use vmm_config::clipboard::Clipboard;
+use vmm_config::cloud_init::{CloudInitConfig, CloudInitError, CloudInitSeed};
+use vmm_config::console::{
+    ConsoleBackend, ConsoleOutputConfig, ResizeSource, SessionRecorder, TerminalConfig,
+};
+use vmm_config::custom_device::CustomDeviceBuilder;
+use vmm_config::device_panic::DevicePanicConfig;
+use vmm_config::device_window::{DeviceWindowConfig, DeviceWindowError};
+use vmm_config::disk::BlockDevices;
+use vmm_config::earlycon::{EarlyconBuffer, EarlyconConfigError};
+use vmm_config::etc::{self, EtcError, EtcOverrides};
+use vmm_config::exec::{
+    ExecEnvConfig, ExecSecurity, ExecUser, ServiceConfig, ServiceError, ServiceStateCallback,
+    MAX_SERVICES,
+};
+use vmm_config::exit::ExitHandle;
+use vmm_config::fd_budget::FdBudget;
 use vmm_config::fs::*;
-use vmm_config::kernel_bundle::{KernelBundle, KernelBundleError};
+use vmm_config::fsfreeze::FsFreeze;
+use vmm_config::guest_network::GuestNetwork;
+use vmm_config::guest_panic::GuestPanicConfig;
+use vmm_config::idle::IdlePolicyConfig;
+use vmm_config::kernel_bundle::KernelBundle;
+use vmm_config::kernel_modules::{KernelModules, KernelModulesError};
+use vmm_config::kernel_signature::KernelSignatureConfig;
+use vmm_config::kvm_tuning::KvmTuningConfig;
+use vmm_config::latency_profile::LatencyProfile;
+use vmm_config::limits::{CgroupLimit, Rlimit};
+use vmm_config::log_channel::LogChannelConfig;
 use vmm_config::logger::LoggerConfigError;
 use vmm_config::machine_config::{VmConfig, VmConfigError};
+use vmm_config::options::{self, OptionError};
+use vmm_config::probes::{ProbeCallback, ProbeConfig, ProbeConfigError, ProbeKind};
+use vmm_config::profiling::{ProfileReports, ProfilingConfig};
+use vmm_config::restricted_dma::RestrictedDmaConfig;
+use vmm_config::runtime_limit::RuntimeLimitConfig;
+use vmm_config::secrets::SecretsError;
+use vmm_config::serial_passthrough::{SerialPassthroughConfig, SerialPassthroughError};
+use vmm_config::shares::{
+    self, DedupStore, EscapePolicy, ShareCache, ShareError, ShareIoConfig, ShareMount, ShareQuota,
+    ShareSettings,
+};
+use vmm_config::socket_activation::ListenSocket;
+use vmm_config::swap::SwapConfig;
+use vmm_config::sysctl::Sysctl;
+use vmm_config::tmpfs::TmpfsConfig;
+use vmm_config::tpm::{TpmConfig, TpmConfigError};
+use vmm_config::user_device::{UserDeviceConfig, UserDeviceError};
+use vmm_config::vm_state::VmStateHandle;
 use vmm_config::vsock::*;
+use vmm_config::watermarks::{self, QueueWatermarks, QueueWatermarksError, WatermarkCallback};
+use vmm_config::worker_pool::{WorkerPoolConfig, WorkerPoolError};
+use vmm_config::workload::WorkloadStartedCallback;
 use vstate::VcpuConfig;
 
 type Result<E> = std::result::Result<(), E>;
@@ -40,10 +101,136 @@ pub struct VmResources {
     pub boot_config: BootSourceConfig,
     /// The parameters for the kernel bundle to be loaded in this microVM.
     pub kernel_bundle: Option<KernelBundle>,
+    /// The keys the kernel bundle must be signed with, and its signature.
+    pub kernel_signature: KernelSignatureConfig,
     /// The fs device.
     pub fs: FsBuilder,
     /// The vsock device.
     pub vsock: VsockBuilder,
+    /// The embedder-provided virtio devices.
+    pub custom_devices: CustomDeviceBuilder,
+    /// The backend for the console device.
+    pub console: ConsoleBackend,
+    /// How the output of the console device is processed before reaching its backend.
+    pub console_output: ConsoleOutputConfig,
+    /// How the terminal of the VMM is handed over to the console, with the `Stdio` backend.
+    pub console_terminal: TerminalConfig,
+    /// Where the console learns the terminal of the VMM has been resized from.
+    pub console_resize: ResizeSource,
+    /// The recorder of the session of the console, if any.
+    pub console_recorder: Option<SessionRecorder>,
+    /// How the panics of the guest kernel are detected and reported.
+    pub guest_panic: GuestPanicConfig,
+    /// The console the guest forwards its logs through, if any.
+    pub log_channel: Option<LogChannelConfig>,
+    /// The buffer capturing the early console output, if enabled.
+    pub earlycon: Option<EarlyconBuffer>,
+    /// The wall-clock limit on the runtime of the microVM, if any.
+    pub runtime_limit: Option<RuntimeLimitConfig>,
+    /// The policy for freezing the microVM while idle, if any.
+    pub idle_policy: Option<IdlePolicyConfig>,
+    /// The profiling of the vCPUs, if enabled.
+    pub profiling: Option<ProfilingConfig>,
+    /// Where the profiles of the vCPUs are published.
+    pub profile_reports: ProfileReports,
+    /// Where the timeline of the boot of the microVM is recorded.
+    pub boot_timeline: BootTimeline,
+    /// The tunings of KVM applied to the microVM.
+    pub kvm_tuning: KvmTuningConfig,
+    /// The trade-off between the wakeup latency of the guest and its CPU time while idle.
+    pub latency_profile: LatencyProfile,
+    /// The recorder of the interactions between the guest and the virtio devices, if enabled.
+    pub virtio_recorder: Option<Arc<Recorder>>,
+    /// The handle the exit of the microVM is signaled on, if the embedder outlives it.
+    pub exit_handle: Option<ExitHandle>,
+    /// Where the microVM is in its lifecycle, shared with the VMM once built.
+    pub vm_state: VmStateHandle,
+    /// The clipboard shared with the guest, if any.
+    pub clipboard: Option<Clipboard>,
+    /// The character devices of the host bound to consoles of the guest.
+    pub serial_passthroughs: Vec<SerialPassthroughConfig>,
+    /// The channel freezing the filesystems of the guest, if any.
+    pub fsfreeze: Option<FsFreeze>,
+    /// The channel reporting the network configuration of the guest, if any.
+    pub guest_network: Option<GuestNetwork>,
+    /// The callback invoked once the guest reports the workload has been started, if any.
+    pub workload_started: Option<WorkloadStartedCallback>,
+    /// The probes of the workload, at most one of each kind.
+    pub probes: Vec<ProbeConfig>,
+    /// The callback invoked when the state of a probe changes, if any.
+    pub probe_callback: Option<ProbeCallback>,
+    /// The cloud-init NoCloud seed exposed to the guest, if any.
+    pub cloud_init: Option<CloudInitSeed>,
+    /// The extra kernel modules loaded by the guest, if any.
+    pub kernel_modules: Option<KernelModules>,
+    /// The shares mounted by the guest at a path of their own, in the order they were added.
+    pub shares: Vec<ShareMount>,
+    /// The kernel tunables applied by the guest before executing the workload.
+    pub sysctls: Vec<Sysctl>,
+    /// The resource limits set by the guest before executing the workload.
+    pub rlimits: Vec<Rlimit>,
+    /// The limits of the cgroup the guest moves the workload to, if any.
+    pub cgroup_limits: Vec<CgroupLimit>,
+    /// The sizes of the tmpfs mounted by the guest.
+    pub tmpfs: TmpfsConfig,
+    /// The swap enabled by the guest, if any.
+    pub swap: Option<SwapConfig>,
+    /// How the guest executes the workload.
+    pub exec_env: ExecEnvConfig,
+    /// The user the guest executes the workload as, if not root.
+    pub exec_user: Option<ExecUser>,
+    /// The privileges the guest drops before executing the workload.
+    pub exec_security: ExecSecurity,
+    /// The services run by the guest alongside the workload, in the order they're started.
+    pub services: Vec<ServiceConfig>,
+    /// The callback invoked when the state of a service changes, if any.
+    pub service_callback: Option<ServiceStateCallback>,
+    /// The hostname set by the guest, if any.
+    pub hostname: Option<String>,
+    /// The files overriding those in "/etc" of the guest, if any.
+    pub etc_overrides: Option<EtcOverrides>,
+    /// The CA certificates trusted by the guest, if any.
+    pub ca_certs: Option<CaCerts>,
+    /// Whether to expose the ciphers of the host to the guest through a virtio-crypto device.
+    pub crypto: bool,
+    /// The TPM of the microVM, if any.
+    pub tpm: Option<TpmConfig>,
+    /// The secrets handed to the guest once, if any.
+    pub secrets: Option<Arc<Mutex<SecretsMailbox>>>,
+    /// Whether to offer the guest a shared-memory data path for vsock connections.
+    pub vsock_shm: bool,
+    /// Whether to leave the i8042 controller out of the microVM on x86_64, for guests that don't
+    /// need PS/2 emulation. They reset the machine through the reset control of the chipset.
+    pub no_i8042: bool,
+    /// Whether to leave the balloon device out of the microVM, for workloads that don't need
+    /// their memory reclaimed by the host, sparing its setup from the boot.
+    pub no_balloon: bool,
+    /// How the guest caches the lookups of the fs devices.
+    pub fs_lookup_cache: LookupCacheConfig,
+    /// The settings of the fs devices, by tag.
+    pub share_settings: HashMap<String, ShareSettings>,
+    /// The flow control parameters of the vsock connections.
+    pub vsock_conn: VsockConnConfig,
+    /// The CID of the guest on the vsock device.
+    pub vsock_cid: GuestCid,
+    /// The listening sockets the vsock device uses rather than binding its own.
+    pub activated_sockets: ActivatedSockets,
+    /// The watermarks reported on by the queues of the console and vsock devices, if any.
+    pub queue_watermarks: Option<QueueWatermarks>,
+    /// Where the MMIO devices are placed, and which IRQs they use.
+    pub device_window: DeviceWindowConfig,
+    /// The threads running the blocking requests of the devices.
+    pub worker_pool: WorkerPoolConfig,
+    /// The file descriptors the devices can hold, and the ones they hold.
+    pub fd_budget: FdBudget,
+    /// What happens when a device panics.
+    pub device_panic: DevicePanicConfig,
+    /// The block devices, registered as they're attached, so their disks can be grown.
+    pub block_devices: BlockDevices,
+    /// The pool of guest memory the virtio devices are restricted to, if any.
+    pub restricted_dma: Option<RestrictedDmaConfig>,
+    /// The regions whose accesses by the guest are handed to the embedder.
+    pub user_devices: Vec<UserDeviceConfig>,
 }
 
 impl VmResources {
@@ -54,6 +241,12 @@ impl VmResources {
         VcpuConfig {
             vcpu_count: self.vm_config().vcpu_count.unwrap(),
             ht_enabled: self.vm_config().ht_enabled.unwrap(),
+            topology: self.vm_config().topology.unwrap_or_else(|| {
+                CpuTopology::single_socket(
+                    self.vm_config().vcpu_count.unwrap(),
+                    self.vm_config().ht_enabled.unwrap(),
+                )
+            }),
             cpu_template: self.vm_config().cpu_template,
         }
     }
@@ -73,12 +266,21 @@ impl VmResources {
             return Err(VmConfigError::InvalidMemorySize);
         }
 
+        if let Some(topology) = machine_config.topology {
+            if !topology.is_valid() {
+                return Err(VmConfigError::InvalidCpuTopology);
+            }
+        }
+
+        // A new topology sets the vcpu count and hyperthreading.
         let ht_enabled = machine_config
             .ht_enabled
+            .or_else(|| machine_config.topology.map(|t| t.threads_per_core > 1))
             .unwrap_or_else(|| self.vm_config.ht_enabled.unwrap());
 
         let vcpu_count_value = machine_config
             .vcpu_count
+            .or_else(|| machine_config.topology.and_then(|t| t.vcpu_count()))
             .unwrap_or_else(|| self.vm_config.vcpu_count.unwrap());
 
         // If hyperthreading is enabled or is to be enabled in this call
@@ -87,9 +289,19 @@ impl VmResources {
             return Err(VmConfigError::InvalidVcpuCount);
         }
 
+        let topology = machine_config.topology.or(self.vm_config.topology);
+        if let Some(topology) = topology {
+            if topology.vcpu_count() != Some(vcpu_count_value)
+                || (topology.threads_per_core > 1) != ht_enabled
+            {
+                return Err(VmConfigError::InvalidCpuTopology);
+            }
+        }
+
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
+        self.vm_config.topology = topology;
 
         if machine_config.mem_size_mib.is_some() {
             self.vm_config.mem_size_mib = machine_config.mem_size_mib;
@@ -115,43 +327,452 @@ impl VmResources {
         self.kernel_bundle.as_ref()
     }
 
-    pub fn set_kernel_bundle(&mut self, kernel_bundle: KernelBundle) -> Result<KernelBundleError> {
-        // Safe because this call just returns the page size and doesn't have any side effects.
-        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    /// Sets the kernel bundle, which its constructors already validated.
+    pub fn set_kernel_bundle(&mut self, kernel_bundle: KernelBundle) {
+        self.kernel_bundle = Some(kernel_bundle);
+    }
+
+    pub fn set_fs_device(&mut self, config: FsDeviceConfig) -> Result<FsConfigError> {
+        let fd_account = self.fd_budget.account(&config.fs_id);
+        self.fs.insert(config, fd_account)
+    }
 
-        if kernel_bundle.host_addr == 0 || (kernel_bundle.host_addr as usize) & (page_size - 1) != 0
+    /// Shares `host_path` with the guest, whose init process mounts it as described by `mount`.
+    pub fn add_share(&mut self, host_path: &Path, mount: ShareMount) -> Result<ShareError> {
+        mount.validate()?;
+        if self
+            .fs
+            .list
+            .iter()
+            .any(|fs| fs.lock().unwrap().id() == mount.tag)
         {
-            return Err(KernelBundleError::InvalidHostAddress);
+            return Err(ShareError::DuplicateTag(mount.tag));
+        }
+        if self
+            .shares
+            .iter()
+            .any(|share| share.guest_path == mount.guest_path)
+        {
+            return Err(ShareError::DuplicateGuestPath(mount.guest_path));
         }
 
-        if (kernel_bundle.guest_addr as usize) & (page_size - 1) != 0 {
-            return Err(KernelBundleError::InvalidGuestAddress);
+        self.set_fs_device(FsDeviceConfig {
+            fs_id: mount.tag.clone(),
+            shared_dir: host_path.to_string_lossy().into_owned(),
+            mapped_volumes: None,
+        })
+        .map_err(ShareError::FsDevice)?;
+        self.shares.push(mount);
+        Ok(())
+    }
+
+    /// Sets the quota of the fs device tagged `tag`, replacing any previous one. The device may be
+    /// added afterwards.
+    pub fn set_share_quota(&mut self, tag: &str, quota: ShareQuota) -> Result<ShareError> {
+        if quota.capacity == 0 {
+            return Err(ShareError::InvalidQuota);
         }
+        self.share_settings
+            .entry(tag.to_string())
+            .or_default()
+            .quota = Some(quota);
+        Ok(())
+    }
+
+    /// Sets how the fs device tagged `tag` does the I/O of its files, replacing any previous
+    /// configuration. The device may be added afterwards.
+    pub fn set_share_io(&mut self, tag: &str, io: ShareIoConfig) {
+        self.share_settings.entry(tag.to_string()).or_default().io = io;
+    }
+
+    /// Sets how the fs device tagged `tag` handles the attempts of the guest to reach outside of
+    /// its share, replacing any previous policy. The device may be added afterwards.
+    pub fn set_share_escape_policy(&mut self, tag: &str, escape_policy: EscapePolicy) {
+        self.share_settings
+            .entry(tag.to_string())
+            .or_default()
+            .escape_policy = escape_policy;
+    }
+
+    /// Sets the cache the host keeps of the share of the fs device tagged `tag`, replacing any
+    /// previous one. The device may be added afterwards.
+    pub fn set_share_cache(&mut self, tag: &str, cache: ShareCache) {
+        self.share_settings
+            .entry(tag.to_string())
+            .or_default()
+            .cache = Some(cache);
+    }
+
+    /// Sets the content-addressed store the read-only DAX mappings of the fs device tagged `tag`
+    /// are taken from, replacing any previous one. The device may be added afterwards.
+    pub fn set_share_dedup(&mut self, tag: &str, dedup: DedupStore) {
+        self.share_settings
+            .entry(tag.to_string())
+            .or_default()
+            .dedup = Some(dedup);
+    }
+
+    /// Returns the shares in the order the guest mounts them.
+    pub fn share_mounts(&self) -> Vec<ShareMount> {
+        let mut mounts = self.shares.clone();
+        shares::sort_mounts(&mut mounts);
+        mounts
+    }
 
-        if kernel_bundle.size & (page_size - 1) != 0 {
-            return Err(KernelBundleError::InvalidSize);
+    /// Writes a cloud-init NoCloud seed from `config`, and shares it with the guest.
+    pub fn set_cloud_init(&mut self, config: &CloudInitConfig) -> Result<CloudInitError> {
+        if self.cloud_init.is_some() {
+            return Err(CloudInitError::AlreadySet);
         }
+        let seed = CloudInitSeed::new(config)?;
+        self.set_fs_device(seed.fs_config())
+            .map_err(CloudInitError::FsDevice)?;
+        self.cloud_init = Some(seed);
+        Ok(())
+    }
 
-        self.kernel_bundle = Some(kernel_bundle);
+    /// Stages the kernel module at `path` to be loaded by the guest with `params`, after any
+    /// previously added module. The modules are shared with the guest on the first call.
+    pub fn add_kernel_module(&mut self, path: &Path, params: &str) -> Result<KernelModulesError> {
+        if self.kernel_modules.is_none() {
+            let modules = KernelModules::new()?;
+            self.set_fs_device(modules.fs_config())
+                .map_err(KernelModulesError::FsDevice)?;
+            self.kernel_modules = Some(modules);
+        }
+        self.kernel_modules.as_mut().unwrap().add(path, params)
+    }
+
+    /// Shares the CA bundle at `bundle_path`, or the trust store of the host if it's `None`, for
+    /// the guest to trust.
+    pub fn set_ca_certs(&mut self, bundle_path: Option<&Path>) -> Result<CaCertsError> {
+        if self.ca_certs.is_some() {
+            return Err(CaCertsError::AlreadySet);
+        }
+        let certs = CaCerts::new(bundle_path)?;
+        self.set_fs_device(certs.fs_config())
+            .map_err(CaCertsError::FsDevice)?;
+        self.ca_certs = Some(certs);
         Ok(())
     }
 
-    pub fn set_fs_device(&mut self, config: FsDeviceConfig) -> Result<FsConfigError> {
-        self.fs.insert(config)
+    /// Attaches a TPM to the microVM, emulated by swtpm listening on `socket_path`.
+    pub fn set_tpm(&mut self, socket_path: &Path) -> Result<TpmConfigError> {
+        self.tpm = Some(TpmConfig::new(socket_path)?);
+        Ok(())
+    }
+
+    /// Hands a secret to the guest, which reads it once into "/run/secrets/`name`". The VMM
+    /// keeps no copy of it past that, or past the time to live of the secrets.
+    pub fn add_secret(&mut self, name: &str, value: &[u8]) -> Result<SecretsError> {
+        self.secrets_mailbox().lock().unwrap().add(name, value)
+    }
+
+    /// Sets how long the guest has to read the secrets, from the start of the microVM.
+    pub fn set_secrets_ttl(&mut self, ttl: Duration) {
+        self.secrets_mailbox().lock().unwrap().set_ttl(ttl);
+    }
+
+    fn secrets_mailbox(&mut self) -> &Arc<Mutex<SecretsMailbox>> {
+        self.secrets
+            .get_or_insert_with(|| Arc::new(Mutex::new(SecretsMailbox::new())))
+    }
+
+    /// Overrides "/etc/`name`" in the guest with `contents`. The overrides are shared with the
+    /// guest on the first call.
+    pub fn set_etc_file(&mut self, name: &str, contents: &str) -> Result<EtcError> {
+        if self.etc_overrides.is_none() {
+            let overrides = EtcOverrides::new()?;
+            self.set_fs_device(overrides.fs_config())
+                .map_err(EtcError::FsDevice)?;
+            self.etc_overrides = Some(overrides);
+        }
+        self.etc_overrides.as_ref().unwrap().set(name, contents)
+    }
+
+    /// Sets the hostname of the guest, also overriding "/etc/hostname".
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<EtcError> {
+        etc::validate_hostname(hostname)?;
+        self.set_etc_file("hostname", &format!("{}\n", hostname))?;
+        self.hostname = Some(hostname.to_string());
+        Ok(())
+    }
+
+    /// Sets the machine ID of the guest by overriding "/etc/machine-id", so clones of a root
+    /// don't share it.
+    pub fn set_machine_id(&mut self, machine_id: &str) -> Result<EtcError> {
+        etc::validate_machine_id(machine_id)?;
+        self.set_etc_file("machine-id", &format!("{}\n", machine_id))
+    }
+
+    /// Adds a kernel tunable to be applied by the guest, replacing any previous value.
+    pub fn add_sysctl(&mut self, sysctl: Sysctl) {
+        self.sysctls.retain(|existing| existing.name != sysctl.name);
+        self.sysctls.push(sysctl);
     }
 
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<VsockConfigError> {
         self.vsock.insert(config)
     }
+
+    /// Sets the backend used by the console device.
+    pub fn set_console_backend(&mut self, backend: ConsoleBackend) {
+        self.console = backend;
+    }
+
+    /// Hands over a listening socket, used by the vsock device when the guest listens on the same
+    /// TCP port or Unix path, or, if `console` is set, attached to the console, which only Unix
+    /// sockets can be.
+    pub fn add_activated_socket(&mut self, socket: ListenSocket, console: bool) -> io::Result<()> {
+        match socket {
+            ListenSocket::Unix(listener) if console => {
+                self.console = ConsoleBackend::Socket(Arc::new(listener));
+                Ok(())
+            }
+            ListenSocket::Tcp(_) if console => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            ListenSocket::Tcp(listener) => self.activated_sockets.add_tcp(listener),
+            ListenSocket::Unix(listener) => self.activated_sockets.add_unix(listener),
+        }
+    }
+
+    /// Sets how the output of the console device is processed before reaching its backend.
+    pub fn set_console_output(&mut self, config: ConsoleOutputConfig) {
+        self.console_output = config;
+    }
+
+    /// Sets how the terminal of the VMM is handed over to the console.
+    pub fn set_console_terminal(&mut self, config: TerminalConfig) {
+        self.console_terminal = config;
+    }
+
+    /// Sets where the console learns the terminal of the VMM has been resized from.
+    pub fn set_console_resize(&mut self, source: ResizeSource) {
+        self.console_resize = source;
+    }
+
+    /// Signals the exit of the microVM on `handle`, rather than exiting the process.
+    pub fn set_exit_handle(&mut self, handle: ExitHandle) {
+        self.exit_handle = Some(handle);
+    }
+
+    /// Shares `clipboard` with the agent of the guest, through a console of its own.
+    pub fn set_clipboard(&mut self, clipboard: Clipboard) {
+        self.clipboard = Some(clipboard);
+    }
+
+    /// Binds the character device of `config` to a console of the guest.
+    pub fn add_serial_passthrough(
+        &mut self,
+        config: SerialPassthroughConfig,
+    ) -> Result<SerialPassthroughError> {
+        if self
+            .serial_passthroughs
+            .iter()
+            .any(|serial| serial.name == config.name)
+        {
+            return Err(SerialPassthroughError::DuplicateName(config.name));
+        }
+        self.serial_passthroughs.push(config);
+        Ok(())
+    }
+
+    /// Returns the names of the serial devices passed through, along with the devices of their
+    /// consoles as seen by the guest. They're attached after the interactive console, the log
+    /// channel and the clipboard.
+    pub fn serial_passthrough_devices(&self) -> Vec<(&str, String)> {
+        let first = 1 + self.log_channel.is_some() as usize + self.clipboard.is_some() as usize;
+        self.serial_passthroughs
+            .iter()
+            .enumerate()
+            .map(|(i, serial)| (serial.name.as_str(), format!("/dev/hvc{}", first + i)))
+            .collect()
+    }
+
+    /// Lets the host freeze the filesystems of the guest through `fsfreeze`, on a console of its
+    /// own.
+    pub fn set_fsfreeze(&mut self, fsfreeze: FsFreeze) {
+        self.fsfreeze = Some(fsfreeze);
+    }
+
+    /// Returns the device of the console freezing the filesystems, as seen by the guest. It's
+    /// attached after the serial devices passed through.
+    pub fn fsfreeze_device(&self) -> String {
+        let index = 1
+            + self.log_channel.is_some() as usize
+            + self.clipboard.is_some() as usize
+            + self.serial_passthroughs.len();
+        format!("/dev/hvc{}", index)
+    }
+
+    /// Lets the host query the network configuration of the guest through `guest_network`, on a
+    /// console of its own.
+    pub fn set_guest_network(&mut self, guest_network: GuestNetwork) {
+        self.guest_network = Some(guest_network);
+    }
+
+    /// Returns the device of the console reporting the network configuration, as seen by the
+    /// guest. It's attached after the one freezing the filesystems.
+    pub fn guest_network_device(&self) -> String {
+        let index = 1
+            + self.log_channel.is_some() as usize
+            + self.clipboard.is_some() as usize
+            + self.serial_passthroughs.len()
+            + self.fsfreeze.is_some() as usize;
+        format!("/dev/hvc{}", index)
+    }
+
+    /// Adds a console the guest forwards the records sent to `/dev/log` through.
+    pub fn set_log_channel(&mut self, config: LogChannelConfig) {
+        self.log_channel = Some(config);
+    }
+
+    /// Returns the buffer capturing the early console output, if enabled.
+    pub fn earlycon(&self) -> Option<&EarlyconBuffer> {
+        self.earlycon.as_ref()
+    }
+
+    /// Enables the capture of the early console output into a buffer of `size` bytes.
+    pub fn set_earlycon_buffer(&mut self, size: usize) -> Result<EarlyconConfigError> {
+        self.earlycon = Some(EarlyconBuffer::new(size)?);
+        Ok(())
+    }
+
+    /// Sets the wall-clock limit on the runtime of the microVM.
+    pub fn set_runtime_limit(&mut self, runtime_limit: RuntimeLimitConfig) {
+        self.runtime_limit = Some(runtime_limit);
+    }
+
+    /// Sets the policy for freezing the microVM while idle.
+    pub fn set_idle_policy(&mut self, idle_policy: IdlePolicyConfig) {
+        self.idle_policy = Some(idle_policy);
+    }
+
+    /// Enables the profiling of the vCPUs, whose reports are published to `profile_reports`.
+    pub fn set_profiling(&mut self, profiling: ProfilingConfig) {
+        self.profiling = Some(profiling);
+    }
+
+    /// Records the descriptor chains processed by the virtio devices, and their responses, to
+    /// `out`, so they can be replayed with `devices::virtio::replay`.
+    pub fn set_virtio_recording(&mut self, out: Box<dyn Write + Send>) {
+        self.virtio_recorder = Some(Arc::new(Recorder::new(out)));
+    }
+
+    /// Reports the depth of the queues of the console and vsock devices reaching `high`, or
+    /// falling back to `low`, to `callback`.
+    pub fn set_queue_watermarks(
+        &mut self,
+        low: u32,
+        high: u32,
+        callback: WatermarkCallback,
+    ) -> Result<QueueWatermarksError> {
+        self.queue_watermarks = Some(watermarks::queue_watermarks(low, high, callback)?);
+        Ok(())
+    }
+
+    /// Places the MMIO devices from `mmio_start`, using the IRQs from `irq_base` to `irq_max`, so
+    /// they don't collide with those of other VMMs sharing the process.
+    pub fn set_device_window(
+        &mut self,
+        device_window: DeviceWindowConfig,
+    ) -> Result<DeviceWindowError> {
+        device_window.validate()?;
+        self.device_window = device_window;
+        Ok(())
+    }
+
+    /// Runs the blocking requests of the devices on a pool of `worker_pool.threads` threads, with
+    /// up to `worker_pool.device_limit` requests of each device in flight.
+    pub fn set_worker_pool(&mut self, worker_pool: WorkerPoolConfig) -> Result<WorkerPoolError> {
+        worker_pool.validate()?;
+        self.worker_pool = worker_pool;
+        Ok(())
+    }
+
+    /// Sets the callback to be invoked once the guest reports the workload has been started.
+    pub fn set_workload_started_callback(&mut self, callback: WorkloadStartedCallback) {
+        self.workload_started = Some(callback);
+    }
+
+    /// Sets the probe of the kind of `probe`, replacing the previous one, if any.
+    pub fn set_probe(&mut self, probe: ProbeConfig) -> Result<ProbeConfigError> {
+        probe.validate()?;
+        self.probes.retain(|p| p.kind != probe.kind);
+        self.probes.push(probe);
+        Ok(())
+    }
+
+    /// Adds a service run by the guest alongside the workload, after those already added.
+    pub fn add_service(&mut self, service: ServiceConfig) -> Result<ServiceError> {
+        if self.services.iter().any(|s| s.name == service.name) {
+            return Err(ServiceError::DuplicateName(service.name));
+        }
+        if self.services.len() >= MAX_SERVICES {
+            return Err(ServiceError::TooManyServices);
+        }
+        self.services.push(service);
+        Ok(())
+    }
+
+    /// Returns the probe of kind `kind`, if any.
+    pub fn probe_mut(&mut self, kind: ProbeKind) -> Option<&mut ProbeConfig> {
+        self.probes.iter_mut().find(|p| p.kind == kind)
+    }
+
+    /// Sets the option named `key`, from the registry in `vmm_config::options`, to `value`.
+    pub fn set_option(&mut self, key: &str, value: &str) -> Result<OptionError> {
+        options::set_option(self, key, value)
+    }
+
+    /// Adds an embedder-provided virtio device, which also handles its own events, to be
+    /// attached when the VM starts.
+    pub fn add_custom_device<T>(&mut self, id: String, device: Arc<Mutex<T>>)
+    where
+        T: VirtioDevice + Subscriber + 'static,
+    {
+        self.custom_devices.insert(id, device);
+    }
+
+    /// Adds an embedder-provided virtio device, with an optional independent event subscriber,
+    /// to be attached when the VM starts.
+    pub fn add_custom_virtio_device(
+        &mut self,
+        id: String,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        subscriber: Option<Arc<Mutex<dyn Subscriber + Send>>>,
+    ) {
+        self.custom_devices.insert_device(id, device, subscriber);
+    }
+
+    /// Hands the accesses of the guest to the region of `config` to its callback.
+    pub fn add_user_device(&mut self, config: UserDeviceConfig) -> Result<UserDeviceError> {
+        if self
+            .user_devices
+            .iter()
+            .any(|user_device| user_device.overlaps(&config))
+        {
+            return Err(UserDeviceError::Overlap(config.base));
+        }
+        self.user_devices.push(config);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use resources::VmResources;
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
     use vmm_config::boot_source::BootSourceConfig;
+    use vmm_config::exec::{RestartPolicy, ServiceConfig, ServiceError, MAX_SERVICES};
     use vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
+    use vmm_config::shares::{
+        DedupStore, EscapePolicy, ShareCache, ShareCacheConfig, ShareError, ShareIoConfig,
+        ShareQuota, ShareSettings,
+    };
     use vmm_config::vsock::tests::{default_config, TempSockFile};
     use vstate::VcpuConfig;
 
@@ -167,8 +788,70 @@ mod tests {
             vm_config: VmConfig::default(),
             boot_config: default_boot_cfg(),
             kernel_bundle: Default::default(),
+            kernel_signature: Default::default(),
             fs: Default::default(),
             vsock: Default::default(),
+            custom_devices: Default::default(),
+            console: Default::default(),
+            console_output: Default::default(),
+            console_terminal: Default::default(),
+            console_resize: Default::default(),
+            console_recorder: None,
+            guest_panic: Default::default(),
+            log_channel: None,
+            earlycon: None,
+            runtime_limit: None,
+            idle_policy: None,
+            profiling: None,
+            profile_reports: Default::default(),
+            boot_timeline: Default::default(),
+            kvm_tuning: Default::default(),
+            latency_profile: Default::default(),
+            virtio_recorder: None,
+            exit_handle: None,
+            vm_state: Default::default(),
+            clipboard: None,
+            serial_passthroughs: Vec::new(),
+            fsfreeze: None,
+            guest_network: None,
+            workload_started: None,
+            probes: Vec::new(),
+            probe_callback: None,
+            cloud_init: None,
+            kernel_modules: None,
+            shares: Vec::new(),
+            sysctls: Vec::new(),
+            rlimits: Vec::new(),
+            cgroup_limits: Vec::new(),
+            tmpfs: Default::default(),
+            swap: None,
+            exec_env: Default::default(),
+            exec_user: None,
+            exec_security: Default::default(),
+            services: Vec::new(),
+            service_callback: None,
+            hostname: None,
+            etc_overrides: None,
+            ca_certs: None,
+            crypto: false,
+            tpm: None,
+            secrets: None,
+            vsock_shm: false,
+            no_i8042: false,
+            no_balloon: false,
+            fs_lookup_cache: Default::default(),
+            share_settings: HashMap::new(),
+            vsock_conn: VsockConnConfig::default(),
+            vsock_cid: Default::default(),
+            activated_sockets: Default::default(),
+            queue_watermarks: None,
+            device_window: Default::default(),
+            worker_pool: Default::default(),
+            fd_budget: Default::default(),
+            device_panic: Default::default(),
+            block_devices: Default::default(),
+            restricted_dma: None,
+            user_devices: Vec::new(),
         }
     }
 
@@ -178,6 +861,7 @@ mod tests {
         let expected_vcpu_config = VcpuConfig {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
+            topology: CpuTopology::single_socket(1, false),
             cpu_template: vm_resources.vm_config().cpu_template,
         };
 
@@ -200,6 +884,7 @@ mod tests {
             vcpu_count: Some(32),
             mem_size_mib: Some(512),
             ht_enabled: Some(true),
+            topology: None,
             cpu_template: Some(CpuFeaturesTemplate::T2),
         };
 
@@ -228,6 +913,218 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_set_cpu_topology() {
+        let mut vm_resources = default_vm_resources();
+        // 2 sockets of 2 cores of 2 threads.
+        let topology = CpuTopology {
+            sockets: 2,
+            cores_per_socket: 2,
+            threads_per_core: 2,
+        };
+        let topology_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            topology: Some(topology),
+            cpu_template: None,
+        };
+
+        // The topology sets the vcpu count and hyperthreading.
+        vm_resources.set_vm_config(&topology_config).unwrap();
+        assert_eq!(vm_resources.vm_config().vcpu_count, Some(8));
+        assert_eq!(vm_resources.vm_config().ht_enabled, Some(true));
+        assert_eq!(vm_resources.vcpu_config().topology, topology);
+
+        // Which can only be set again to match it.
+        let mut aux_vm_config = VmConfig {
+            vcpu_count: Some(8),
+            mem_size_mib: Some(512),
+            ht_enabled: None,
+            topology: None,
+            cpu_template: None,
+        };
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        aux_vm_config.vcpu_count = Some(4);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
+        aux_vm_config.vcpu_count = None;
+        aux_vm_config.ht_enabled = Some(false);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
+        assert_eq!(vm_resources.vm_config().vcpu_count, Some(8));
+
+        // Several sockets with 3 cores can't be told apart in the IDs of the vCPUs.
+        let mut invalid_config = topology_config.clone();
+        invalid_config.topology = Some(CpuTopology {
+            cores_per_socket: 3,
+            ..topology
+        });
+        assert_eq!(
+            vm_resources.set_vm_config(&invalid_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
+        assert_eq!(vm_resources.vcpu_config().topology, topology);
+    }
+
+    #[test]
+    fn test_set_share_settings() {
+        let mut vm_resources = default_vm_resources();
+        let quota = ShareQuota {
+            capacity: 0,
+            enforce: true,
+        };
+        assert!(matches!(
+            vm_resources.set_share_quota("data", quota),
+            Err(ShareError::InvalidQuota)
+        ));
+        let quota = ShareQuota {
+            capacity: 1 << 30,
+            ..quota
+        };
+        vm_resources.set_share_quota("data", quota).unwrap();
+        vm_resources.set_share_escape_policy("data", EscapePolicy::Strict);
+        let cache = ShareCache::new(ShareCacheConfig {
+            metadata_ttl: Duration::from_secs(30),
+            max_entries: 1024,
+            ..Default::default()
+        });
+        vm_resources.set_share_cache("data", cache.clone());
+        let tmp = TempDir::new().unwrap();
+        let dedup = DedupStore::new(tmp.as_path()).unwrap();
+        vm_resources.set_share_dedup("data", dedup.clone());
+        assert_eq!(
+            vm_resources.share_settings.get("data"),
+            Some(&ShareSettings {
+                quota: Some(quota),
+                escape_policy: EscapePolicy::Strict,
+                cache: Some(cache),
+                dedup: Some(dedup),
+                ..Default::default()
+            })
+        );
+
+        let io = ShareIoConfig {
+            direct_io: true,
+            preallocate: false,
+        };
+        vm_resources.set_share_io("/dev/root", io);
+        vm_resources.set_share_io("/dev/root", ShareIoConfig::default());
+        assert_eq!(
+            vm_resources.share_settings.get("/dev/root"),
+            Some(&ShareSettings::default())
+        );
+    }
+
+    #[test]
+    fn test_add_service() {
+        let mut vm_resources = default_vm_resources();
+        let service = |name: &str| {
+            ServiceConfig::new(name, vec!["/bin/true".to_string()], RestartPolicy::OneShot).unwrap()
+        };
+
+        vm_resources.add_service(service("sidecar")).unwrap();
+        assert_eq!(
+            vm_resources.add_service(service("sidecar")),
+            Err(ServiceError::DuplicateName("sidecar".to_string()))
+        );
+        for index in 1..MAX_SERVICES {
+            vm_resources
+                .add_service(service(&format!("sidecar-{}", index)))
+                .unwrap();
+        }
+        assert_eq!(
+            vm_resources.add_service(service("one-too-many")),
+            Err(ServiceError::TooManyServices)
+        );
+        assert_eq!(vm_resources.services[0].name, "sidecar");
+    }
+
+    #[test]
+    fn test_add_activated_socket() {
+        use std::net::TcpListener;
+        use std::os::unix::net::UnixListener;
+        use vmm_config::console::ConsoleBackend;
+        use vmm_config::socket_activation::ListenSocket;
+
+        let mut vm_resources = default_vm_resources();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(vm_resources
+            .add_activated_socket(ListenSocket::Tcp(tcp.try_clone().unwrap()), true)
+            .is_err());
+        vm_resources
+            .add_activated_socket(ListenSocket::Tcp(tcp), false)
+            .unwrap();
+        assert_eq!(vm_resources.activated_sockets.len(), 1);
+
+        let dir = utils::tempdir::TempDir::new().unwrap();
+        let unix = UnixListener::bind(dir.as_path().join("console.sock")).unwrap();
+        vm_resources
+            .add_activated_socket(ListenSocket::Unix(unix), true)
+            .unwrap();
+        assert!(matches!(vm_resources.console, ConsoleBackend::Socket(_)));
+        assert_eq!(vm_resources.activated_sockets.len(), 1);
+    }
+
+    #[test]
+    fn test_add_serial_passthrough() {
+        use std::path::PathBuf;
+        use vmm_config::serial_passthrough::{SerialPassthroughConfig, SerialPassthroughError};
+
+        let mut vm_resources = default_vm_resources();
+        let serial = |name: &str| {
+            SerialPassthroughConfig::new(name, PathBuf::from("/dev/ttyUSB0"), None).unwrap()
+        };
+
+        vm_resources.add_serial_passthrough(serial("gps")).unwrap();
+        assert_eq!(
+            vm_resources.add_serial_passthrough(serial("gps")),
+            Err(SerialPassthroughError::DuplicateName("gps".to_string()))
+        );
+        vm_resources
+            .add_serial_passthrough(serial("modem"))
+            .unwrap();
+        assert_eq!(
+            vm_resources.serial_passthrough_devices(),
+            vec![
+                ("gps", "/dev/hvc1".to_string()),
+                ("modem", "/dev/hvc2".to_string())
+            ]
+        );
+        assert_eq!(vm_resources.fsfreeze_device(), "/dev/hvc3");
+        assert_eq!(vm_resources.guest_network_device(), "/dev/hvc3");
+        vm_resources.set_fsfreeze(
+            vmm_config::fsfreeze::FsFreeze::new(std::time::Duration::from_secs(60)).unwrap(),
+        );
+        assert_eq!(vm_resources.guest_network_device(), "/dev/hvc4");
+    }
+
+    #[test]
+    fn test_add_user_device() {
+        use std::sync::Arc;
+        use vmm_config::device_window::MMIO_WINDOW_RANGE;
+        use vmm_config::user_device::{UserDeviceConfig, UserDeviceError, UserRegionKind};
+
+        let mut vm_resources = default_vm_resources();
+        let base = arch::MMIO_MEM_START + MMIO_WINDOW_RANGE - 0x2000;
+        let region = |base: u64| {
+            UserDeviceConfig::new(UserRegionKind::Mmio, base, 0x1000, Arc::new(|_, _, _| ()))
+                .unwrap()
+        };
+
+        vm_resources.add_user_device(region(base)).unwrap();
+        assert_eq!(
+            vm_resources.add_user_device(region(base + 0x800)),
+            Err(UserDeviceError::Overlap(base + 0x800))
+        );
+        vm_resources.add_user_device(region(base + 0x1000)).unwrap();
+        assert_eq!(vm_resources.user_devices.len(), 2);
+    }
+
     #[test]
     fn test_set_vsock_device() {
         let mut vm_resources = default_vm_resources();
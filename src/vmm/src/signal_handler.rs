@@ -1,10 +1,7 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicI32, Ordering};
-
-use libc::{_exit, c_int, c_void, siginfo_t, SIGBUS, SIGSEGV, SIGSYS, SIGWINCH};
+use libc::{_exit, c_int, c_void, siginfo_t, SIGBUS, SIGSEGV, SIGSYS};
 use utils::signal::register_signal_handler;
 
 // The offset of `si_syscall` (offending syscall identifier) within the siginfo structure
@@ -16,8 +13,6 @@ const SI_OFF_SYSCALL: isize = 6;
 
 const SYS_SECCOMP_CODE: i32 = 1;
 
-static CONSOLE_SIGWINCH_FD: AtomicI32 = AtomicI32::new(-1);
-
 /// Signal handler for `SIGSYS`.
 ///
 /// Increments the `seccomp.num_faults` metric, logs an error message and terminates the process
@@ -79,29 +74,6 @@ extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused:
     };
 }
 
-extern "C" fn sigwinch_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
-    // Safe because we're just reading some fields from a supposedly valid argument.
-    let si_signo = unsafe { (*info).si_signo };
-
-    // Sanity check. The condition should never be true.
-    if num != si_signo || num != SIGWINCH {
-        // Safe because we're terminating the process anyway.
-        unsafe { _exit(i32::from(super::FC_EXIT_CODE_UNEXPECTED_ERROR)) };
-    }
-
-    let val: u64 = 1;
-    let console_fd = CONSOLE_SIGWINCH_FD.load(Ordering::Relaxed);
-    let _ = unsafe { libc::write(console_fd, &val as *const _ as *const c_void, 8) };
-}
-
-pub fn register_sigwinch_handler(console_fd: RawFd) -> utils::errno::Result<()> {
-    CONSOLE_SIGWINCH_FD.store(console_fd, Ordering::Relaxed);
-
-    register_signal_handler(SIGWINCH, sigwinch_handler)?;
-
-    Ok(())
-}
-
 /// Registers all the required signal handlers.
 ///
 /// Custom handlers are installed for: `SIGBUS`, `SIGSEGV`, `SIGSYS`.
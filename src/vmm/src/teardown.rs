@@ -0,0 +1,98 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Releases what a microVM leaves behind outside of its own objects, like the directories staged
+//! for the guest or the mode of the terminal, whether it's stopped or fails midway through being
+//! built. The process exits without unwinding on stop, so the actions are run explicitly there,
+//! and on drop otherwise.
+
+use std::fmt;
+
+type Action = Box<dyn FnOnce() + Send>;
+
+/// The actions releasing the resources of a microVM, run in the reverse order they were
+/// registered.
+#[derive(Default)]
+pub struct Teardown {
+    actions: Vec<(&'static str, Action)>,
+}
+
+impl Teardown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `action`, releasing `resource`, to be run before the actions registered so far.
+    pub fn push<F: FnOnce() + Send + 'static>(&mut self, resource: &'static str, action: F) {
+        self.actions.push((resource, Box::new(action)));
+    }
+
+    /// Returns the number of actions left to run.
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Runs the actions registered, most recent first. Each action is run only once.
+    pub fn run(&mut self) {
+        while let Some((resource, action)) = self.actions.pop() {
+            debug!("Releasing the {}", resource);
+            action();
+        }
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        self.run();
+    }
+}
+
+impl fmt::Debug for Teardown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let resources: Vec<&str> = self.actions.iter().map(|(resource, _)| *resource).collect();
+        f.debug_struct("Teardown")
+            .field("resources", &resources)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_teardown_order() {
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let mut teardown = Teardown::new();
+        for &resource in &["memory", "socket", "staged dir"] {
+            let released = released.clone();
+            teardown.push(resource, move || released.lock().unwrap().push(resource));
+        }
+        assert_eq!(teardown.len(), 3);
+
+        // Dropping the teardown runs the actions left, in reverse.
+        drop(teardown);
+        assert_eq!(
+            *released.lock().unwrap(),
+            vec!["staged dir", "socket", "memory"]
+        );
+    }
+
+    #[test]
+    fn test_teardown_runs_once() {
+        let count = Arc::new(Mutex::new(0));
+        let mut teardown = Teardown::new();
+        let action_count = count.clone();
+        teardown.push("staged dir", move || *action_count.lock().unwrap() += 1);
+
+        teardown.run();
+        assert!(teardown.is_empty());
+        drop(teardown);
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hands the terminal of the VMM back the way it was found once the console is done with it.
+//!
+//! Switching the terminal back to canonical mode isn't enough: the guest may leave it with any
+//! attributes, modes or scroll region, hiding the cursor or breaking the scrollback of the shell
+//! the VMM was started from. The settings of the terminal are saved before it's set to raw mode,
+//! and the modes a guest commonly changes are reset on restore. With an alternate screen, the
+//! guest doesn't even draw over the screen of the shell, which gets it back untouched.
+
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use vmm_config::console::TerminalConfig;
+
+// Switches to the alternate screen, saving the cursor and clearing it.
+const ENTER_ALTERNATE_SCREEN: &[u8] = b"\x1b[?1049h";
+// Switches back to the normal screen, restoring the cursor.
+const LEAVE_ALTERNATE_SCREEN: &[u8] = b"\x1b[?1049l";
+// Resets the modes a guest may have left changed: the attributes, the visibility of the cursor,
+// autowrap, the cursor keys and keypad, bracketed paste and the mouse reporting. The scroll
+// region is reset last, between saving and restoring the cursor, as resetting it moves the
+// cursor home.
+const RESET_MODES: &[u8] = b"\x1b[0m\x1b[?25h\x1b[?7h\x1b[?1l\x1b>\x1b[?2004l\
+      \x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b7\x1b[r\x1b8";
+
+/// The state of a terminal saved before the console takes it over.
+pub struct SavedTerminal {
+    fd: RawFd,
+    termios: Option<libc::termios>,
+    // Whether the screen is written to through a terminal, rather than redirected.
+    screen: bool,
+    config: TerminalConfig,
+}
+
+impl SavedTerminal {
+    /// Saves the settings of the terminal behind `fd`, if it's one. The escape sequences are only
+    /// written if `out_fd`, which the screen is written to, is a terminal as well.
+    pub fn save(fd: RawFd, out_fd: RawFd, config: TerminalConfig) -> Self {
+        // Safe because termios is plain data, filled in by tcgetattr() if it succeeds.
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        let termios = if unsafe { libc::tcgetattr(fd, &mut termios) } == 0 {
+            Some(termios)
+        } else {
+            None
+        };
+        SavedTerminal {
+            fd,
+            termios,
+            // Safe because isatty() just checks the fd.
+            screen: unsafe { libc::isatty(out_fd) } == 1,
+            config,
+        }
+    }
+
+    /// Returns whether the terminal behind the fd was saved, which it only is if it's one.
+    pub fn is_terminal(&self) -> bool {
+        self.termios.is_some()
+    }
+
+    /// Prepares the screen of the terminal for the console, writing to `out`.
+    pub fn enter<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.is_terminal() && self.screen && self.config.alternate_screen {
+            out.write_all(ENTER_ALTERNATE_SCREEN)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Resets the screen of the terminal, writing to `out`, and restores its settings.
+    pub fn restore<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let termios = match &self.termios {
+            Some(termios) => termios,
+            None => return Ok(()),
+        };
+        if self.screen {
+            out.write_all(RESET_MODES)?;
+            if self.config.alternate_screen {
+                out.write_all(LEAVE_ALTERNATE_SCREEN)?;
+            }
+            out.flush()?;
+        }
+        // Safe because termios is valid, and we check the return value. The output is drained
+        // first, so the sequences above aren't interpreted in the restored mode.
+        if unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn openpty() -> (RawFd, RawFd) {
+        let (mut master, mut slave) = (-1, -1);
+        // Safe because we pass valid pointers, and null for the optional arguments.
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(ret, 0);
+        (master, slave)
+    }
+
+    fn lflag(fd: RawFd) -> libc::tcflag_t {
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        assert_eq!(unsafe { libc::tcgetattr(fd, &mut termios) }, 0);
+        termios.c_lflag
+    }
+
+    #[test]
+    fn test_restore_terminal() {
+        let (master, slave) = openpty();
+        let original = lflag(slave);
+        assert_ne!(original & libc::ECHO, 0);
+
+        let saved = SavedTerminal::save(
+            slave,
+            slave,
+            TerminalConfig {
+                alternate_screen: true,
+            },
+        );
+        assert!(saved.is_terminal());
+        let mut out = Vec::new();
+        saved.enter(&mut out).unwrap();
+        assert_eq!(out, ENTER_ALTERNATE_SCREEN);
+
+        // Set to raw mode by the console.
+        let mut termios: libc::termios = unsafe { mem::zeroed() };
+        unsafe {
+            libc::tcgetattr(slave, &mut termios);
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+        }
+        assert_eq!(lflag(slave) & libc::ECHO, 0);
+
+        let mut out = Vec::new();
+        saved.restore(&mut out).unwrap();
+        assert!(out.starts_with(RESET_MODES));
+        assert!(out.ends_with(LEAVE_ALTERNATE_SCREEN));
+        assert_eq!(lflag(slave), original);
+
+        unsafe {
+            libc::close(master);
+            libc::close(slave);
+        }
+    }
+
+    #[test]
+    fn test_not_a_terminal() {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let saved = SavedTerminal::save(
+            fds[0],
+            fds[1],
+            TerminalConfig {
+                alternate_screen: true,
+            },
+        );
+        assert!(!saved.is_terminal());
+        // Nothing is written to what isn't a terminal.
+        let mut out = Vec::new();
+        saved.enter(&mut out).unwrap();
+        saved.restore(&mut out).unwrap();
+        assert!(out.is_empty());
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -50,3 +50,5 @@ impl Display for BootSourceConfigError {
         }
     }
 }
+
+impl std::error::Error for BootSourceConfigError {}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a step of the build of the microVM took.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootStep {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// A point of the boot of the guest, as reported by it, and when it was reached since the build
+/// of the microVM started.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootMilestone {
+    pub name: &'static str,
+    pub elapsed: Duration,
+}
+
+/// Where the time to boot the microVM went: the stages of its build, the devices attached during
+/// them, the devices left out because the workload doesn't need them, and the milestones of the
+/// boot of the guest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BootReport {
+    pub stages: Vec<BootStep>,
+    pub devices: Vec<BootStep>,
+    pub skipped: Vec<String>,
+    pub milestones: Vec<BootMilestone>,
+}
+
+impl BootReport {
+    /// Serializes the report as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let steps = |out: &mut String, key: &str, steps: &[BootStep]| {
+            let _ = write!(out, "\"{}\":[", key);
+            for (index, step) in steps.iter().enumerate() {
+                if index > 0 {
+                    out.push(',');
+                }
+                out.push_str("{\"name\":");
+                push_json_string(out, &step.name);
+                let _ = write!(out, ",\"duration_us\":{}}}", step.duration.as_micros());
+            }
+            out.push(']');
+        };
+
+        let mut out = String::from("{");
+        steps(&mut out, "stages", &self.stages);
+        out.push(',');
+        steps(&mut out, "devices", &self.devices);
+        out.push_str(",\"skipped\":[");
+        for (index, name) in self.skipped.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            push_json_string(&mut out, name);
+        }
+        out.push_str("],\"milestones\":[");
+        for (index, milestone) in self.milestones.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"elapsed_us\":{}}}",
+                milestone.name,
+                milestone.elapsed.as_micros()
+            );
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Appends `value` to `out` as a JSON string, as the names of the devices come from the embedder.
+pub(crate) fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[derive(Default)]
+struct TimelineState {
+    started: Option<Instant>,
+    report: BootReport,
+}
+
+/// The timeline of the boot of the microVM, recorded by the VMM and read by the embedder.
+#[derive(Clone, Default)]
+pub struct BootTimeline(Arc<Mutex<TimelineState>>);
+
+impl BootTimeline {
+    /// Starts the timeline over, as the build of the microVM starts.
+    pub fn start(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.started = Some(Instant::now());
+        state.report = BootReport::default();
+    }
+
+    /// Records the build stage `name` having taken `duration`.
+    pub fn record_stage(&self, name: &str, duration: Duration) {
+        self.0.lock().unwrap().report.stages.push(BootStep {
+            name: name.to_string(),
+            duration,
+        });
+    }
+
+    /// Records attaching the device `name` having taken `duration`.
+    pub fn record_device(&self, name: &str, duration: Duration) {
+        self.0.lock().unwrap().report.devices.push(BootStep {
+            name: name.to_string(),
+            duration,
+        });
+    }
+
+    /// Records the device `name` having been left out of the microVM.
+    pub fn record_skipped(&self, name: &str) {
+        self.0.lock().unwrap().report.skipped.push(name.to_string());
+    }
+
+    /// Records the guest reaching the milestone `name`. Only the first time counts.
+    pub fn mark(&self, name: &'static str) {
+        let mut state = self.0.lock().unwrap();
+        let elapsed = match state.started {
+            Some(started) => started.elapsed(),
+            None => return,
+        };
+        if state.report.milestones.iter().any(|m| m.name == name) {
+            return;
+        }
+        state
+            .report
+            .milestones
+            .push(BootMilestone { name, elapsed });
+    }
+
+    /// Returns the report of the boot so far, if the build of the microVM has started.
+    pub fn report(&self) -> Option<BootReport> {
+        let state = self.0.lock().unwrap();
+        state.started.map(|_| state.report.clone())
+    }
+}
+
+impl fmt::Debug for BootTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BootTimeline")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_timeline() {
+        let timeline = BootTimeline::default();
+        timeline.mark("boot_complete");
+        assert!(timeline.report().is_none());
+
+        timeline.start();
+        timeline.record_stage("devices", Duration::from_micros(1500));
+        timeline.record_device("console", Duration::from_micros(300));
+        timeline.record_skipped("balloon");
+        timeline.record_device("my \"gpu\"", Duration::from_micros(20));
+        timeline.clone().mark("boot_complete");
+        timeline.mark("boot_complete");
+
+        let report = timeline.report().unwrap();
+        assert_eq!(report.milestones.len(), 1);
+        let elapsed = report.milestones[0].elapsed.as_micros();
+        assert_eq!(
+            report.to_json(),
+            format!(
+                "{{\"stages\":[{{\"name\":\"devices\",\"duration_us\":1500}}],\
+                 \"devices\":[{{\"name\":\"console\",\"duration_us\":300}},\
+                 {{\"name\":\"my \\\"gpu\\\"\",\"duration_us\":20}}],\
+                 \"skipped\":[\"balloon\"],\
+                 \"milestones\":[{{\"name\":\"boot_complete\",\"elapsed_us\":{}}}]}}",
+                elapsed
+            )
+        );
+
+        // Starting over forgets the previous boot.
+        timeline.start();
+        assert_eq!(timeline.report(), Some(BootReport::default()));
+    }
+}
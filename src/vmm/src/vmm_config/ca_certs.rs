@@ -0,0 +1,180 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(target_os = "macos")]
+use std::process::Command;
+
+use utils::tempdir::TempDir;
+use vmm_config::fs::{FsConfigError, FsDeviceConfig};
+
+/// The tag of the virtio-fs share holding the bundle. The init process of the guest mounts it,
+/// and bind-mounts `CA_BUNDLE_FILE` over the bundles of the common distributions found in the
+/// guest, or points `SSL_CERT_FILE` at it if there's none.
+pub const CA_CERTS_FS_TAG: &str = "krun-certs";
+
+/// The name of the bundle in the share.
+const CA_BUNDLE_FILE: &str = "ca-certificates.crt";
+
+/// The locations of the trust store of the common distributions, as a PEM bundle.
+#[cfg(target_os = "linux")]
+const HOST_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/tls/cacert.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// The keychains holding the system roots, and the CAs trusted by the administrator.
+#[cfg(target_os = "macos")]
+const HOST_KEYCHAINS: &[&str] = &[
+    "/System/Library/Keychains/SystemRootCertificates.keychain",
+    "/Library/Keychains/System.keychain",
+];
+
+const PEM_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Errors associated with the CA certificates configuration.
+#[derive(Debug)]
+pub enum CaCertsError {
+    /// The CA certificates have already been set, and they can only be shared once.
+    AlreadySet,
+    /// Unable to create the directory holding the bundle.
+    CreateStagingDir(utils::errno::Error),
+    /// Unable to create the share exposing the bundle.
+    FsDevice(FsConfigError),
+    /// The trust store of the host couldn't be found.
+    HostTrustStoreNotFound,
+    /// The bundle doesn't hold any PEM certificate.
+    InvalidBundle(PathBuf),
+    /// Unable to read the bundle, or to export the trust store of the host.
+    ReadBundle(PathBuf, io::Error),
+    /// Unable to write the bundle to the staging directory.
+    WriteBundle(io::Error),
+}
+
+impl fmt::Display for CaCertsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CaCertsError::*;
+        match self {
+            AlreadySet => write!(f, "The CA certificates have already been set"),
+            CreateStagingDir(e) => write!(f, "Unable to create the CA staging directory: {}", e),
+            FsDevice(e) => write!(f, "Unable to share the CA certificates: {}", e),
+            HostTrustStoreNotFound => write!(f, "Unable to find the trust store of the host"),
+            InvalidBundle(path) => write!(f, "{} doesn't hold any PEM certificate", path.display()),
+            ReadBundle(path, e) => write!(f, "Unable to read {}: {}", path.display(), e),
+            WriteBundle(e) => write!(f, "Unable to stage the CA certificates: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaCertsError {}
+
+type Result<T> = std::result::Result<T, CaCertsError>;
+
+#[cfg(target_os = "linux")]
+fn read_host_bundle() -> Result<(PathBuf, Vec<u8>)> {
+    let path = HOST_CA_BUNDLES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .ok_or(CaCertsError::HostTrustStoreNotFound)?;
+    let bundle = fs::read(&path).map_err(|e| CaCertsError::ReadBundle(path.clone(), e))?;
+    Ok((path, bundle))
+}
+
+#[cfg(target_os = "macos")]
+fn read_host_bundle() -> Result<(PathBuf, Vec<u8>)> {
+    // The trust store lives in keychains rather than in a file, so it's exported in PEM form.
+    let security = PathBuf::from("/usr/bin/security");
+    let output = Command::new(&security)
+        .args(&["find-certificate", "-a", "-p"])
+        .args(HOST_KEYCHAINS)
+        .output()
+        .map_err(|e| CaCertsError::ReadBundle(security.clone(), e))?;
+    if !output.status.success() {
+        return Err(CaCertsError::HostTrustStoreNotFound);
+    }
+    Ok((security, output.stdout))
+}
+
+/// A bundle of CA certificates, in PEM form, written to a private host directory which is removed
+/// when dropped.
+pub struct CaCerts {
+    dir: TempDir,
+}
+
+impl CaCerts {
+    /// Stages the bundle at `bundle_path`, or the trust store of the host if it's `None`.
+    pub fn new(bundle_path: Option<&Path>) -> Result<Self> {
+        let (path, bundle) = match bundle_path {
+            Some(path) => {
+                let bundle =
+                    fs::read(path).map_err(|e| CaCertsError::ReadBundle(path.to_path_buf(), e))?;
+                (path.to_path_buf(), bundle)
+            }
+            None => read_host_bundle()?,
+        };
+        if !String::from_utf8_lossy(&bundle).contains(PEM_CERTIFICATE) {
+            return Err(CaCertsError::InvalidBundle(path));
+        }
+
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("krun-certs-"))
+            .map_err(CaCertsError::CreateStagingDir)?;
+        fs::write(dir.as_path().join(CA_BUNDLE_FILE), &bundle)
+            .map_err(CaCertsError::WriteBundle)?;
+        Ok(CaCerts { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Returns the configuration of the share exposing the bundle to the guest.
+    pub fn fs_config(&self) -> FsDeviceConfig {
+        FsDeviceConfig {
+            fs_id: CA_CERTS_FS_TAG.to_string(),
+            shared_dir: self.path().to_string_lossy().into_owned(),
+            mapped_volumes: None,
+        }
+    }
+}
+
+impl fmt::Debug for CaCerts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CaCerts {{ dir: {:?} }}", self.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_ca_certs_bundle() {
+        let bundle = TempFile::new().unwrap();
+        let contents = format!("{}\nMIIB\n-----END CERTIFICATE-----\n", PEM_CERTIFICATE);
+        fs::write(bundle.as_path(), &contents).unwrap();
+
+        let certs = CaCerts::new(Some(bundle.as_path())).unwrap();
+        assert_eq!(
+            fs::read_to_string(certs.path().join(CA_BUNDLE_FILE)).unwrap(),
+            contents
+        );
+        assert_eq!(certs.fs_config().fs_id, CA_CERTS_FS_TAG);
+
+        fs::write(bundle.as_path(), "not a certificate").unwrap();
+        assert!(matches!(
+            CaCerts::new(Some(bundle.as_path())),
+            Err(CaCertsError::InvalidBundle(_))
+        ));
+        assert!(matches!(
+            CaCerts::new(Some(Path::new("/nonexistent/ca.crt"))),
+            Err(CaCertsError::ReadBundle(_, _))
+        ));
+    }
+}
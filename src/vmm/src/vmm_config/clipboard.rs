@@ -0,0 +1,222 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use devices::virtio::{write_log_record, CallbackInput, CALLBACK_INPUT_BUFFER_SIZE};
+
+/// The largest contents the clipboard can carry, each way. The contents copied on the host are
+/// buffered whole, along with the length preceding them, until the guest reads them.
+pub const CLIPBOARD_MAX_SIZE: usize = CALLBACK_INPUT_BUFFER_SIZE - 4;
+
+/// Returns the device, as seen by the guest, the clipboard is shared through. It's the console
+/// attached right after the log channel, if any, or else right after the interactive one.
+pub fn clipboard_guest_device(log_channel: bool) -> &'static str {
+    if log_channel {
+        "/dev/hvc2"
+    } else {
+        "/dev/hvc1"
+    }
+}
+
+/// Callback invoked with the contents copied in the guest, from the thread of the event loop.
+pub type ClipboardCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// The directions the contents of the clipboard are allowed to flow in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipboardDirection {
+    HostToGuest,
+    GuestToHost,
+    Both,
+}
+
+impl ClipboardDirection {
+    pub fn host_to_guest(self) -> bool {
+        self != ClipboardDirection::GuestToHost
+    }
+
+    pub fn guest_to_host(self) -> bool {
+        self != ClipboardDirection::HostToGuest
+    }
+}
+
+/// Errors associated with the clipboard.
+#[derive(Debug)]
+pub enum ClipboardError {
+    /// The maximum size is zero, or larger than `CLIPBOARD_MAX_SIZE`.
+    InvalidMaxSize(usize),
+    /// The contents are larger than the maximum size.
+    TooLarge(usize),
+    /// The policy doesn't allow the contents to flow from the host to the guest.
+    Denied,
+    /// The guest hasn't read enough of the contents copied before to make room.
+    Busy,
+    /// Failed to create the input of the clipboard, or to signal it.
+    Input(io::Error),
+}
+
+impl fmt::Display for ClipboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ClipboardError::*;
+        match self {
+            InvalidMaxSize(size) => write!(
+                f,
+                "The maximum size of the clipboard must be between 1 and {} bytes, not {}",
+                CLIPBOARD_MAX_SIZE, size
+            ),
+            TooLarge(size) => write!(
+                f,
+                "The contents of {} bytes are larger than the clipboard allows",
+                size
+            ),
+            Denied => write!(
+                f,
+                "The clipboard can't be shared from the host to the guest"
+            ),
+            Busy => write!(
+                f,
+                "The guest hasn't read the previous contents of the clipboard yet"
+            ),
+            Input(e) => write!(f, "Error with the input of the clipboard: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClipboardError {}
+
+/// A clipboard shared between the host and the guest through a console of its own, by an agent
+/// running in the guest. Each copy is a frame of the contents, preceded by their length as a
+/// 32-bit little-endian integer, written to the device by the side it's copied on.
+///
+/// Clones share the clipboard, so the embedder can keep one to copy to the guest while the
+/// microVM runs.
+#[derive(Clone)]
+pub struct Clipboard {
+    direction: ClipboardDirection,
+    max_size: usize,
+    input: CallbackInput,
+    callback: Option<ClipboardCallback>,
+}
+
+impl Clipboard {
+    /// Creates a clipboard carrying contents of up to `max_size` bytes in `direction`, the ones
+    /// copied in the guest being handed to `callback`.
+    pub fn new(
+        direction: ClipboardDirection,
+        max_size: usize,
+        callback: Option<ClipboardCallback>,
+    ) -> std::result::Result<Self, ClipboardError> {
+        if max_size == 0 || max_size > CLIPBOARD_MAX_SIZE {
+            return Err(ClipboardError::InvalidMaxSize(max_size));
+        }
+
+        Ok(Clipboard {
+            direction,
+            max_size,
+            input: CallbackInput::new().map_err(ClipboardError::Input)?,
+            callback,
+        })
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns the input of the console the clipboard is shared through.
+    pub fn input(&self) -> CallbackInput {
+        self.input.clone()
+    }
+
+    /// Copies `contents` to the clipboard of the guest. Fails with `Busy` if the guest hasn't
+    /// read enough of the contents copied before to make room for them.
+    pub fn copy_to_guest(&self, contents: &[u8]) -> std::result::Result<(), ClipboardError> {
+        if !self.direction.host_to_guest() {
+            return Err(ClipboardError::Denied);
+        }
+        if contents.len() > self.max_size {
+            return Err(ClipboardError::TooLarge(contents.len()));
+        }
+
+        let mut frame = Vec::with_capacity(contents.len() + 4);
+        write_log_record(&mut frame, contents).map_err(ClipboardError::Input)?;
+        match self.input.push_all(&frame) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ClipboardError::Busy),
+            Err(e) => Err(ClipboardError::Input(e)),
+        }
+    }
+
+    /// Hands `contents`, copied in the guest, to the embedder, if the policy allows it.
+    pub fn copied_in_guest(&self, contents: &[u8]) {
+        if !self.direction.guest_to_host() {
+            debug!("Dropping the contents of the clipboard copied in the guest");
+            return;
+        }
+        if let Some(callback) = &self.callback {
+            callback(contents);
+        }
+    }
+}
+
+impl fmt::Debug for Clipboard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Clipboard")
+            .field("direction", &self.direction)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_clipboard_config() {
+        for size in &[0, CLIPBOARD_MAX_SIZE + 1] {
+            assert!(matches!(
+                Clipboard::new(ClipboardDirection::Both, *size, None),
+                Err(ClipboardError::InvalidMaxSize(_))
+            ));
+        }
+        assert_eq!(clipboard_guest_device(false), "/dev/hvc1");
+        assert_eq!(clipboard_guest_device(true), "/dev/hvc2");
+    }
+
+    #[test]
+    fn test_copy_to_guest() {
+        let clipboard = Clipboard::new(ClipboardDirection::HostToGuest, 8, None).unwrap();
+        assert!(matches!(
+            clipboard.copy_to_guest(b"123456789"),
+            Err(ClipboardError::TooLarge(9))
+        ));
+        clipboard.copy_to_guest(b"text").unwrap();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(clipboard.input().read(&mut buf).unwrap(), 8);
+        assert_eq!(&buf[..8], b"\x04\x00\x00\x00text");
+
+        let clipboard = Clipboard::new(ClipboardDirection::GuestToHost, 8, None).unwrap();
+        assert!(matches!(
+            clipboard.copy_to_guest(b"text"),
+            Err(ClipboardError::Denied)
+        ));
+    }
+
+    #[test]
+    fn test_copied_in_guest() {
+        let copied = Arc::new(Mutex::new(Vec::new()));
+        let sink = copied.clone();
+        let callback: ClipboardCallback =
+            Arc::new(move |contents: &[u8]| sink.lock().unwrap().push(contents.to_vec()));
+
+        let clipboard =
+            Clipboard::new(ClipboardDirection::Both, 8, Some(callback.clone())).unwrap();
+        clipboard.copied_in_guest(b"text");
+        let clipboard = Clipboard::new(ClipboardDirection::HostToGuest, 8, Some(callback)).unwrap();
+        clipboard.copied_in_guest(b"dropped");
+
+        assert_eq!(*copied.lock().unwrap(), vec![b"text".to_vec()]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use utils::tempdir::TempDir;
+use vmm_config::fs::{FsConfigError, FsDeviceConfig};
+
+/// The tag of the virtio-fs share holding the seed. The init process of the guest mounts it where
+/// cloud-init looks for a local NoCloud seed, "/var/lib/cloud/seed/nocloud".
+pub const CLOUD_INIT_FS_TAG: &str = "cidata";
+
+/// Used when no meta-data is provided, since cloud-init requires an instance ID.
+const DEFAULT_META_DATA: &str = "instance-id: libkrun\n";
+
+/// Errors associated with the cloud-init configuration.
+#[derive(Debug)]
+pub enum CloudInitError {
+    /// The seed has already been set, and it can only be shared once.
+    AlreadySet,
+    /// Unable to create the directory holding the seed.
+    CreateSeedDir(utils::errno::Error),
+    /// Unable to create the share exposing the seed.
+    FsDevice(FsConfigError),
+    /// Unable to write one of the seed files.
+    WriteSeedFile(&'static str, io::Error),
+}
+
+impl fmt::Display for CloudInitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CloudInitError::*;
+        match self {
+            AlreadySet => write!(f, "The cloud-init seed has already been set"),
+            CreateSeedDir(e) => write!(f, "Unable to create the cloud-init seed directory: {}", e),
+            FsDevice(e) => write!(f, "Unable to share the cloud-init seed: {}", e),
+            WriteSeedFile(name, e) => write!(f, "Unable to write cloud-init {}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for CloudInitError {}
+
+/// The documents provisioning a guest through the cloud-init NoCloud datasource.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CloudInitConfig {
+    pub user_data: String,
+    /// If missing, a fixed instance ID is used.
+    pub meta_data: Option<String>,
+    pub network_config: Option<String>,
+}
+
+/// A NoCloud seed written to a private host directory, which is removed when dropped.
+pub struct CloudInitSeed {
+    dir: TempDir,
+}
+
+impl CloudInitSeed {
+    pub fn new(config: &CloudInitConfig) -> Result<Self, CloudInitError> {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("krun-cidata-"))
+            .map_err(CloudInitError::CreateSeedDir)?;
+
+        let meta_data = config.meta_data.as_deref().unwrap_or(DEFAULT_META_DATA);
+        let files = [
+            ("user-data", Some(config.user_data.as_str())),
+            ("meta-data", Some(meta_data)),
+            ("network-config", config.network_config.as_deref()),
+        ];
+        for (name, contents) in files.iter() {
+            if let Some(contents) = contents {
+                fs::write(dir.as_path().join(name), contents)
+                    .map_err(|e| CloudInitError::WriteSeedFile(*name, e))?;
+            }
+        }
+
+        Ok(CloudInitSeed { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Returns the configuration of the share exposing the seed to the guest.
+    pub fn fs_config(&self) -> FsDeviceConfig {
+        FsDeviceConfig {
+            fs_id: CLOUD_INIT_FS_TAG.to_string(),
+            shared_dir: self.path().to_string_lossy().into_owned(),
+            mapped_volumes: None,
+        }
+    }
+}
+
+impl fmt::Debug for CloudInitSeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CloudInitSeed {{ dir: {:?} }}", self.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_init_seed() {
+        let config = CloudInitConfig {
+            user_data: "#cloud-config\nhostname: test\n".to_string(),
+            meta_data: None,
+            network_config: None,
+        };
+        let seed = CloudInitSeed::new(&config).unwrap();
+        let path = seed.path().to_path_buf();
+
+        assert_eq!(
+            fs::read_to_string(path.join("user-data")).unwrap(),
+            config.user_data
+        );
+        assert_eq!(
+            fs::read_to_string(path.join("meta-data")).unwrap(),
+            DEFAULT_META_DATA
+        );
+        assert!(!path.join("network-config").exists());
+        assert_eq!(seed.fs_config().fs_id, CLOUD_INIT_FS_TAG);
+
+        drop(seed);
+        assert!(!path.exists());
+    }
+}
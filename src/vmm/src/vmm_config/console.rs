@@ -1,52 +1,58 @@
-use std::collections::VecDeque;
-use std::fmt;
-use std::sync::{Arc, Mutex};
-
-use devices::virtio::{Console, ConsoleError};
-
-#[derive(Debug)]
-pub enum ConsoleConfigError {
-    /// Failed to create the console device.
-    CreateConsoleDevice(ConsoleError),
+use std::fs::File;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+
+pub use devices::virtio::{
+    CallbackInput, CallbackOutput, ConsoleAttach, ConsoleOutputConfig, SessionRecorder,
+};
+
+/// Where the console device takes its input from and sends its output to.
+#[derive(Clone)]
+pub enum ConsoleBackend {
+    /// Use the VMM's stdin and stdout.
+    Stdio,
+    /// Exchange data with the embedder through an in-memory buffer and a callback.
+    Callbacks {
+        input: CallbackInput,
+        output: CallbackOutput,
+    },
+    /// Exchange data with the first client connecting to a listening Unix socket, which the start
+    /// of the microVM waits for.
+    Socket(Arc<UnixListener>),
+    /// Exchange data with the clients attaching to, and detaching from, a Unix socket while the
+    /// microVM runs, keeping the output while none is attached.
+    Attach(ConsoleAttach),
 }
 
-impl fmt::Display for ConsoleConfigError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::ConsoleConfigError::*;
-        match *self {
-            CreateConsoleDevice(ref e) => write!(f, "Cannot create console device: {:?}", e),
-        }
+impl Default for ConsoleBackend {
+    fn default() -> Self {
+        ConsoleBackend::Stdio
     }
 }
 
-type Result<T> = std::result::Result<T, ConsoleConfigError>;
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct ConsoleDeviceConfig {
-    pub fs_id: String,
-    pub shared_dir: String,
+/// How the terminal of the VMM is handed over to the console, with the `Stdio` backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TerminalConfig {
+    /// Whether the console runs on the alternate screen of the terminal, so the screen and the
+    /// scrollback of the shell are left as they were once the microVM exits.
+    pub alternate_screen: bool,
 }
 
-#[derive(Default)]
-pub struct FsBuilder {
-    pub list: VecDeque<Arc<Mutex<Fs>>>,
+/// Where the console learns the terminal of the VMM has been resized from.
+#[derive(Clone, Debug)]
+pub enum ResizeSource {
+    /// SIGWINCH, received through a signalfd on Linux, or a kqueue on macOS, polled along the
+    /// other events rather than caught by a handler installed for the whole process. On Linux,
+    /// the signal is blocked in the thread building the microVM, and so in the threads it starts.
+    Signal,
+    /// A file descriptor the embedder makes readable, such as an eventfd or a pipe, when it's
+    /// told about a resize, for instance by its own SIGWINCH handler. It's drained on each
+    /// notification.
+    Fd(Arc<File>),
 }
 
-impl FsBuilder {
-    pub fn new() -> Self {
-        Self {
-            list: VecDeque::<Arc<Mutex<Fs>>>::new(),
-        }
-    }
-
-    pub fn insert(&mut self, config: FsDeviceConfig) -> Result<()> {
-        let fs_dev = Arc::new(Mutex::new(Self::create_fs(config)?));
-        self.list.push_back(fs_dev);
-        Ok(())
-    }
-
-    pub fn create_fs(config: FsDeviceConfig) -> Result<Fs> {
-        Ok(devices::virtio::Fs::new(config.fs_id, config.shared_dir)
-            .map_err(FsConfigError::CreateFsDevice)?)
+impl Default for ResizeSource {
+    fn default() -> Self {
+        ResizeSource::Signal
     }
 }
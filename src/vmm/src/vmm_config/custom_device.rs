@@ -0,0 +1,60 @@
+// Copyright 2021, Red Hat Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::VirtioDevice;
+use polly::event_manager::Subscriber;
+
+/// A virtio device provided by the embedder, to be attached to the microVM alongside the
+/// built-in ones.
+#[derive(Clone)]
+pub struct CustomDevice {
+    /// Identifier used to register the device in the MMIO device manager.
+    pub id: String,
+    /// The device itself.
+    pub device: Arc<Mutex<dyn VirtioDevice>>,
+    /// The object to be registered in the `EventManager` on behalf of the device, if any.
+    pub subscriber: Option<Arc<Mutex<dyn Subscriber + Send>>>,
+}
+
+/// A builder of embedder-provided virtio devices.
+#[derive(Default)]
+pub struct CustomDeviceBuilder {
+    pub list: VecDeque<CustomDevice>,
+}
+
+impl CustomDeviceBuilder {
+    pub fn new() -> Self {
+        Self {
+            list: VecDeque::<CustomDevice>::new(),
+        }
+    }
+
+    /// Adds a device that also handles its own events through the `EventManager`.
+    pub fn insert<T>(&mut self, id: String, device: Arc<Mutex<T>>)
+    where
+        T: VirtioDevice + Subscriber + 'static,
+    {
+        self.list.push_back(CustomDevice {
+            id,
+            device: device.clone(),
+            subscriber: Some(device),
+        });
+    }
+
+    /// Adds a device with an optional, independent, event subscriber.
+    pub fn insert_device(
+        &mut self,
+        id: String,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        subscriber: Option<Arc<Mutex<dyn Subscriber + Send>>>,
+    ) {
+        self.list.push_back(CustomDevice {
+            id,
+            device,
+            subscriber,
+        });
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// What happens to the microVM when one of its devices panics.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DevicePanicPolicy {
+    /// Stop the microVM, exiting with `FC_EXIT_CODE_DEVICE_FAILURE`.
+    StopVm,
+    /// Detach the device from the event loop and keep the microVM running. The guest finds the
+    /// device needing a reset.
+    DetachDevice,
+}
+
+impl Default for DevicePanicPolicy {
+    fn default() -> Self {
+        DevicePanicPolicy::StopVm
+    }
+}
+
+/// A device panicking.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceFailure {
+    /// The id of the device.
+    pub device: String,
+    /// The message the device panicked with.
+    pub message: String,
+    /// Whether the device was detached, rather than the microVM stopped.
+    pub detached: bool,
+}
+
+/// Callback invoked with every device failure, from the thread of the event loop, before the
+/// microVM is stopped.
+pub type DeviceFailureCallback = Arc<dyn Fn(&DeviceFailure) + Send + Sync>;
+
+/// How the panics of the devices are handled.
+#[derive(Clone, Default)]
+pub struct DevicePanicConfig {
+    pub policy: DevicePanicPolicy,
+    pub callback: Option<DeviceFailureCallback>,
+}
+
+impl fmt::Debug for DevicePanicConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DevicePanicConfig")
+            .field("policy", &self.policy)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
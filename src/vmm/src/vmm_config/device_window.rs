@@ -0,0 +1,139 @@
+use std::fmt;
+
+/// The size of the MMIO window, enough for a device per IRQ, whatever the architecture.
+pub const MMIO_WINDOW_SIZE: u64 = 0x10_0000;
+/// The size of the range, starting at `arch::MMIO_MEM_START`, the MMIO window can be moved
+/// within. The range is free of guest memory and of the devices at fixed addresses on every
+/// architecture.
+pub const MMIO_WINDOW_RANGE: u64 = 0x1000_0000;
+
+/// Where the MMIO devices of the microVM are placed, and which IRQs they use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceWindowConfig {
+    /// The address of the first MMIO device.
+    pub mmio_start: u64,
+    /// The first IRQ handed to the devices.
+    pub irq_base: u32,
+    /// The last IRQ handed to the devices.
+    pub irq_max: u32,
+}
+
+impl Default for DeviceWindowConfig {
+    fn default() -> Self {
+        DeviceWindowConfig {
+            mmio_start: arch::MMIO_MEM_START,
+            irq_base: arch::IRQ_BASE,
+            irq_max: arch::IRQ_MAX,
+        }
+    }
+}
+
+/// Errors associated with the device window configuration.
+#[derive(Debug, PartialEq)]
+pub enum DeviceWindowError {
+    /// The start of the MMIO window isn't page aligned.
+    MmioStartUnaligned(u64),
+    /// The MMIO window doesn't fit in the range reserved for it.
+    MmioStartOutOfRange(u64),
+    /// The IRQ range is empty, or exceeds the IRQs the interrupt controller provides.
+    InvalidIrqRange(u32, u32),
+}
+
+impl fmt::Display for DeviceWindowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DeviceWindowError::*;
+        match *self {
+            MmioStartUnaligned(start) => {
+                write!(f, "The MMIO window start {:#x} isn't page aligned", start)
+            }
+            MmioStartOutOfRange(start) => write!(
+                f,
+                "The MMIO window start {:#x} is outside of {:#x}-{:#x}",
+                start,
+                arch::MMIO_MEM_START,
+                arch::MMIO_MEM_START + MMIO_WINDOW_RANGE - MMIO_WINDOW_SIZE
+            ),
+            InvalidIrqRange(base, max) => write!(
+                f,
+                "The IRQ range {}-{} isn't a non-empty part of {}-{}",
+                base,
+                max,
+                arch::IRQ_BASE,
+                arch::IRQ_MAX
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceWindowError {}
+
+impl DeviceWindowConfig {
+    /// Checks the window lies within what the architecture reserves for the MMIO devices.
+    pub fn validate(&self) -> std::result::Result<(), DeviceWindowError> {
+        if self.mmio_start % arch::PAGE_SIZE as u64 != 0 {
+            return Err(DeviceWindowError::MmioStartUnaligned(self.mmio_start));
+        }
+        if self.mmio_start < arch::MMIO_MEM_START
+            || self.mmio_start > arch::MMIO_MEM_START + MMIO_WINDOW_RANGE - MMIO_WINDOW_SIZE
+        {
+            return Err(DeviceWindowError::MmioStartOutOfRange(self.mmio_start));
+        }
+        if self.irq_base < arch::IRQ_BASE
+            || self.irq_max > arch::IRQ_MAX
+            || self.irq_base > self.irq_max
+        {
+            return Err(DeviceWindowError::InvalidIrqRange(
+                self.irq_base,
+                self.irq_max,
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(DeviceWindowConfig::default().validate().is_ok());
+
+        let window = DeviceWindowConfig {
+            mmio_start: arch::MMIO_MEM_START + 0x80_0000,
+            irq_base: arch::IRQ_BASE + 1,
+            irq_max: arch::IRQ_BASE + 2,
+        };
+        assert!(window.validate().is_ok());
+
+        let unaligned = DeviceWindowConfig {
+            mmio_start: arch::MMIO_MEM_START + 0x10,
+            ..window
+        };
+        assert_eq!(
+            unaligned.validate().unwrap_err(),
+            DeviceWindowError::MmioStartUnaligned(arch::MMIO_MEM_START + 0x10)
+        );
+
+        let out_of_range = DeviceWindowConfig {
+            mmio_start: arch::MMIO_MEM_START + MMIO_WINDOW_RANGE,
+            ..window
+        };
+        assert_eq!(
+            out_of_range.validate().unwrap_err(),
+            DeviceWindowError::MmioStartOutOfRange(arch::MMIO_MEM_START + MMIO_WINDOW_RANGE)
+        );
+
+        let empty = DeviceWindowConfig {
+            irq_base: arch::IRQ_BASE + 2,
+            irq_max: arch::IRQ_BASE + 1,
+            ..window
+        };
+        assert!(empty.validate().is_err());
+        let too_high = DeviceWindowConfig {
+            irq_max: arch::IRQ_MAX + 1,
+            ..window
+        };
+        assert!(too_high.validate().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::Block;
+
+/// Errors associated with resizing the disks of the microVM.
+#[derive(Debug)]
+pub enum DiskResizeError {
+    /// No block device has this id, or the microVM isn't running yet.
+    UnknownDevice(String),
+    /// The disk is already larger than the requested size, which it can't shrink to.
+    Shrink(u64),
+    /// The file backing the disk couldn't be extended, or the guest notified.
+    Resize(io::Error),
+}
+
+impl fmt::Display for DiskResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DiskResizeError::*;
+        match self {
+            UnknownDevice(id) => write!(f, "No block device {} is attached", id),
+            Shrink(size) => write!(f, "The disk can't shrink from its {} bytes", size),
+            Resize(_) => write!(f, "Unable to resize the disk"),
+        }
+    }
+}
+
+impl std::error::Error for DiskResizeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiskResizeError::Resize(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The block devices of the microVM, registered as they're attached, so their disks can be grown
+/// while it runs.
+#[derive(Clone, Default)]
+pub struct BlockDevices(Arc<Mutex<HashMap<String, Arc<Mutex<Block>>>>>);
+
+impl BlockDevices {
+    pub fn register(&self, block: Arc<Mutex<Block>>) {
+        let id = block.lock().unwrap().id().to_string();
+        self.0.lock().unwrap().insert(id, block);
+    }
+
+    /// Grows the disk of the block device `id` to `size` bytes, and notifies the guest of its new
+    /// capacity. Returns the size exposed to the guest, rounded down to a whole sector.
+    pub fn resize(&self, id: &str, size: u64) -> Result<u64, DiskResizeError> {
+        let block = self
+            .0
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| DiskResizeError::UnknownDevice(id.to_string()))?;
+        let mut block = block.lock().unwrap();
+        if size < block.disk_size() {
+            return Err(DiskResizeError::Shrink(block.disk_size()));
+        }
+        block.resize(size).map_err(DiskResizeError::Resize)
+    }
+}
+
+impl fmt::Debug for BlockDevices {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlockDevices")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_block_devices() {
+        let disk = TempFile::new().unwrap().into_file();
+        disk.set_len(1 << 20).unwrap();
+        let devices = BlockDevices::default();
+        devices
+            .clone()
+            .register(Arc::new(Mutex::new(Block::new("data", disk).unwrap())));
+
+        assert!(matches!(
+            devices.resize("root", 2 << 20),
+            Err(DiskResizeError::UnknownDevice(_))
+        ));
+        assert!(matches!(
+            devices.resize("data", 1 << 19),
+            Err(DiskResizeError::Shrink(size)) if size == 1 << 20
+        ));
+        assert_eq!(devices.resize("data", (2 << 20) + 1).unwrap(), 2 << 20);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Default size of the buffer holding the early console output.
+pub const DEFAULT_EARLYCON_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Errors associated with the early console configuration.
+#[derive(Debug, PartialEq)]
+pub enum EarlyconConfigError {
+    /// The size of the buffer is zero.
+    InvalidBufferSize,
+}
+
+impl fmt::Display for EarlyconConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EarlyconConfigError::*;
+        match *self {
+            InvalidBufferSize => write!(f, "The early console buffer size must be non-zero"),
+        }
+    }
+}
+
+impl std::error::Error for EarlyconConfigError {}
+
+/// A fixed-size ring buffer capturing the output written by the guest kernel to the early
+/// console. Once full, the oldest bytes are discarded in favor of the new ones.
+#[derive(Clone)]
+pub struct EarlyconBuffer {
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl EarlyconBuffer {
+    pub fn new(capacity: usize) -> std::result::Result<Self, EarlyconConfigError> {
+        if capacity == 0 {
+            return Err(EarlyconConfigError::InvalidBufferSize);
+        }
+
+        Ok(EarlyconBuffer {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        })
+    }
+
+    /// Returns a copy of the output captured so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().iter().copied().collect()
+    }
+}
+
+impl io::Write for EarlyconBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let data = &buf[buf.len().saturating_sub(self.capacity)..];
+        let overflow = (buffer.len() + data.len()).saturating_sub(self.capacity);
+        buffer.drain(..overflow);
+        buffer.extend(data);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_earlycon_buffer() {
+        assert!(EarlyconBuffer::new(0).is_err());
+
+        let mut buffer = EarlyconBuffer::new(8).unwrap();
+        buffer.write_all(b"Linux").unwrap();
+        assert_eq!(buffer.contents(), b"Linux");
+
+        buffer.write_all(b" vers").unwrap();
+        assert_eq!(buffer.contents(), b"nux vers");
+
+        buffer.write_all(b"ion 5.10.0").unwrap();
+        assert_eq!(buffer.contents(), b"n 5.10.0");
+    }
+}
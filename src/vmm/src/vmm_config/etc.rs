@@ -0,0 +1,176 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use utils::tempdir::TempDir;
+use vmm_config::fs::{FsConfigError, FsDeviceConfig};
+
+/// The tag of the virtio-fs share holding the overrides. The init process of the guest mounts it,
+/// and bind-mounts each file over its counterpart in "/etc", leaving the root untouched.
+pub const ETC_FS_TAG: &str = "krun-etc";
+
+/// The maximum length of a hostname in Linux.
+const HOST_NAME_MAX: usize = 64;
+
+/// Errors associated with the identity of the guest and the overrides of "/etc".
+#[derive(Debug)]
+pub enum EtcError {
+    /// Unable to create the directory holding the overrides.
+    CreateStagingDir(utils::errno::Error),
+    /// Unable to create the share exposing the overrides.
+    FsDevice(FsConfigError),
+    /// The name isn't that of a file directly under "/etc".
+    InvalidFileName(String),
+    /// The hostname isn't a valid DNS name of up to 64 characters.
+    InvalidHostname(String),
+    /// The machine ID isn't 32 lowercase hexadecimal characters, or is all zeros.
+    InvalidMachineId(String),
+    /// Unable to write one of the overrides.
+    WriteFile(String, io::Error),
+}
+
+impl fmt::Display for EtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EtcError::*;
+        match self {
+            CreateStagingDir(e) => write!(f, "Unable to create the /etc staging directory: {}", e),
+            FsDevice(e) => write!(f, "Unable to share the /etc overrides: {}", e),
+            InvalidFileName(name) => write!(f, "Invalid /etc file name: {}", name),
+            InvalidHostname(hostname) => write!(f, "Invalid hostname: {}", hostname),
+            InvalidMachineId(id) => write!(f, "Invalid machine ID: {}", id),
+            WriteFile(name, e) => write!(f, "Unable to write /etc/{}: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for EtcError {}
+
+type Result<T> = std::result::Result<T, EtcError>;
+
+/// Checks `hostname` is made of dot-separated labels of letters, digits and hyphens, which don't
+/// start or end with a hyphen.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if hostname.len() > HOST_NAME_MAX || !hostname.split('.').all(valid_label) {
+        return Err(EtcError::InvalidHostname(hostname.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks `machine_id` has the format of "/etc/machine-id", described in machine-id(5).
+pub fn validate_machine_id(machine_id: &str) -> Result<()> {
+    let valid = machine_id.len() == 32
+        && machine_id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        && machine_id.chars().any(|c| c != '0');
+    if !valid {
+        return Err(EtcError::InvalidMachineId(machine_id.to_string()));
+    }
+    Ok(())
+}
+
+/// Files overriding those in "/etc" of the guest, written to a private host directory, which is
+/// removed when dropped.
+pub struct EtcOverrides {
+    dir: TempDir,
+}
+
+impl EtcOverrides {
+    pub fn new() -> Result<Self> {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("krun-etc-"))
+            .map_err(EtcError::CreateStagingDir)?;
+        Ok(EtcOverrides { dir })
+    }
+
+    /// Sets the contents of "/etc/`name`", replacing any previous override. Only files already
+    /// existing in the guest are overridden.
+    pub fn set(&self, name: &str, contents: &str) -> Result<()> {
+        let valid_name = !name.is_empty()
+            && name != "."
+            && name != ".."
+            && !name.contains(|c: char| c == '/' || c.is_control());
+        if !valid_name {
+            return Err(EtcError::InvalidFileName(name.to_string()));
+        }
+        fs::write(self.path().join(name), contents)
+            .map_err(|e| EtcError::WriteFile(name.to_string(), e))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Returns the configuration of the share exposing the overrides to the guest.
+    pub fn fs_config(&self) -> FsDeviceConfig {
+        FsDeviceConfig {
+            fs_id: ETC_FS_TAG.to_string(),
+            shared_dir: self.path().to_string_lossy().into_owned(),
+            mapped_volumes: None,
+        }
+    }
+}
+
+impl fmt::Debug for EtcOverrides {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EtcOverrides {{ dir: {:?} }}", self.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_identity() {
+        assert!(validate_hostname("builder-01").is_ok());
+        assert!(validate_hostname("builder-01.example.com").is_ok());
+        let too_long = "a".repeat(HOST_NAME_MAX + 1);
+        for hostname in &["", "-builder", "builder-", "a..b", "a_b", too_long.as_str()] {
+            assert!(matches!(
+                validate_hostname(hostname),
+                Err(EtcError::InvalidHostname(_))
+            ));
+        }
+
+        assert!(validate_machine_id("4c4c4544005a3410804cb4c04f4e3532").is_ok());
+        for id in &[
+            "",
+            "4c4c4544005a3410804cb4c04f4e353",
+            "4C4C4544005A3410804CB4C04F4E3532",
+            "4c4c4544-005a-3410-804c-b4c04f4e",
+            "00000000000000000000000000000000",
+        ] {
+            assert!(matches!(
+                validate_machine_id(id),
+                Err(EtcError::InvalidMachineId(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_etc_overrides() {
+        let overrides = EtcOverrides::new().unwrap();
+        overrides.set("hosts", "127.0.0.1 localhost\n").unwrap();
+        overrides.set("hosts", "::1 localhost\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(overrides.path().join("hosts")).unwrap(),
+            "::1 localhost\n"
+        );
+
+        for name in &["", ".", "..", "ssh/sshd_config", "../passwd"] {
+            assert!(matches!(
+                overrides.set(name, ""),
+                Err(EtcError::InvalidFileName(_))
+            ));
+        }
+    }
+}
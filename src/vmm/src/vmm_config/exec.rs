@@ -0,0 +1,628 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// The most supplementary groups the workload can be given, as they're passed to the guest on the
+/// kernel command line.
+pub const MAX_SUPPLEMENTARY_GROUPS: usize = 32;
+
+/// The most services the init process of the guest can run, as their events are reported to the
+/// VMM in a single byte.
+pub const MAX_SERVICES: usize = 32;
+
+/// Errors associated with the environment the workload is executed in.
+#[derive(Debug, PartialEq)]
+pub enum ExecEnvError {
+    /// The search path is empty, or contains characters that can't be passed to the guest.
+    InvalidPath(String),
+    /// The locale isn't a name like "C.UTF-8" or "en_US.UTF-8@euro".
+    InvalidLocale(String),
+    /// A user or group ID is -1, which the kernel reserves.
+    InvalidId(u32),
+    /// More than `MAX_SUPPLEMENTARY_GROUPS` supplementary groups are given.
+    TooManyGroups,
+    /// The name doesn't designate a Linux capability.
+    InvalidCapability(String),
+}
+
+impl fmt::Display for ExecEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ExecEnvError::*;
+        match self {
+            InvalidPath(path) => write!(f, "Invalid search path for the workload: {:?}", path),
+            InvalidLocale(locale) => write!(f, "Invalid locale for the workload: {:?}", locale),
+            InvalidId(id) => write!(f, "Invalid user or group ID for the workload: {}", id),
+            TooManyGroups => write!(
+                f,
+                "The workload can be given at most {} supplementary groups",
+                MAX_SUPPLEMENTARY_GROUPS
+            ),
+            InvalidCapability(name) => write!(f, "Invalid capability: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for ExecEnvError {}
+
+/// How the init process of the guest executes the workload, so embedders don't have to wrap
+/// their commands in a shell to get the environment they expect.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecEnvConfig {
+    /// Whether the workload is executed through a login shell, "/bin/sh -l", so the profiles of
+    /// the guest are applied to its environment.
+    pub login_shell: bool,
+    /// The search path of the workload, overriding the one of the environment and the profiles.
+    pub path: Option<String>,
+    /// The locale of the workload, set as `LANG`, with `LC_ALL` cleared so it takes effect.
+    pub locale: Option<String>,
+}
+
+impl ExecEnvConfig {
+    pub fn new(
+        login_shell: bool,
+        path: Option<&str>,
+        locale: Option<&str>,
+    ) -> Result<Self, ExecEnvError> {
+        if let Some(path) = path {
+            // The path is passed to the guest on the kernel command line, within double quotes.
+            if path.is_empty() || path.contains(|c: char| c == '"' || c.is_control()) {
+                return Err(ExecEnvError::InvalidPath(path.to_string()));
+            }
+        }
+        if let Some(locale) = locale {
+            if locale.is_empty()
+                || !locale
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.@".contains(c))
+            {
+                return Err(ExecEnvError::InvalidLocale(locale.to_string()));
+            }
+        }
+
+        Ok(ExecEnvConfig {
+            login_shell,
+            path: path.map(str::to_string),
+            locale: locale.map(str::to_string),
+        })
+    }
+
+    /// Returns the variables telling the init process of the guest how to execute the workload.
+    pub fn guest_env(&self) -> Vec<String> {
+        let mut env = Vec::new();
+        if self.login_shell {
+            env.push("KRUN_LOGIN_SHELL=1".to_string());
+        }
+        if let Some(path) = &self.path {
+            env.push(format!("KRUN_PATH=\"{}\"", path));
+        }
+        if let Some(locale) = &self.locale {
+            env.push(format!("KRUN_LANG={}", locale));
+        }
+        env
+    }
+}
+
+/// The user the init process of the guest executes the workload as, rather than root. Its home
+/// directory, from "/etc/passwd" of the guest, and its runtime directory, "/run/user/`uid`", are
+/// created if missing, and set in its environment along with its name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecUser {
+    pub uid: u32,
+    pub gid: u32,
+    /// The supplementary groups, replacing those of root.
+    pub groups: Vec<u32>,
+}
+
+impl ExecUser {
+    pub fn new(uid: u32, gid: u32, groups: Vec<u32>) -> Result<Self, ExecEnvError> {
+        if let Some(id) = [uid, gid]
+            .iter()
+            .chain(groups.iter())
+            .find(|id| **id == u32::MAX)
+        {
+            return Err(ExecEnvError::InvalidId(*id));
+        }
+        if groups.len() > MAX_SUPPLEMENTARY_GROUPS {
+            return Err(ExecEnvError::TooManyGroups);
+        }
+        Ok(ExecUser { uid, gid, groups })
+    }
+
+    /// Returns the variable telling the init process of the guest which user to switch to.
+    pub fn guest_env(&self) -> String {
+        let groups: Vec<String> = self.groups.iter().map(|g| g.to_string()).collect();
+        format!("KRUN_USER={}:{}:{}", self.uid, self.gid, groups.join(","))
+    }
+}
+
+/// The Linux capabilities, in the order of their numbers.
+const CAPABILITIES: &[&str] = &[
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "DAC_READ_SEARCH",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "SETGID",
+    "SETUID",
+    "SETPCAP",
+    "LINUX_IMMUTABLE",
+    "NET_BIND_SERVICE",
+    "NET_BROADCAST",
+    "NET_ADMIN",
+    "NET_RAW",
+    "IPC_LOCK",
+    "IPC_OWNER",
+    "SYS_MODULE",
+    "SYS_RAWIO",
+    "SYS_CHROOT",
+    "SYS_PTRACE",
+    "SYS_PACCT",
+    "SYS_ADMIN",
+    "SYS_BOOT",
+    "SYS_NICE",
+    "SYS_RESOURCE",
+    "SYS_TIME",
+    "SYS_TTY_CONFIG",
+    "MKNOD",
+    "LEASE",
+    "AUDIT_WRITE",
+    "AUDIT_CONTROL",
+    "SETFCAP",
+    "MAC_OVERRIDE",
+    "MAC_ADMIN",
+    "SYSLOG",
+    "WAKE_ALARM",
+    "BLOCK_SUSPEND",
+    "AUDIT_READ",
+    "PERFMON",
+    "BPF",
+    "CHECKPOINT_RESTORE",
+];
+
+/// The capabilities kept by the default profile, those OCI runtimes grant containers by default.
+const DEFAULT_CAPABILITIES: &[&str] = &[
+    "AUDIT_WRITE",
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "MKNOD",
+    "NET_BIND_SERVICE",
+    "NET_RAW",
+    "SETFCAP",
+    "SETGID",
+    "SETPCAP",
+    "SETUID",
+    "SYS_CHROOT",
+];
+
+/// Returns the set of the capabilities named in `names`, such as "CAP_NET_RAW" or "net_raw", as a
+/// mask of their numbers.
+pub fn capability_mask<S: AsRef<str>>(names: &[S]) -> Result<u64, ExecEnvError> {
+    names.iter().try_fold(0, |mask, name| {
+        let name = name.as_ref();
+        let upper = name.to_ascii_uppercase();
+        let bare = upper.strip_prefix("CAP_").unwrap_or(&upper);
+        match CAPABILITIES.iter().position(|cap| *cap == bare) {
+            Some(number) => Ok(mask | 1 << number),
+            None => Err(ExecEnvError::InvalidCapability(name.to_string())),
+        }
+    })
+}
+
+/// The system calls the init process of the guest denies to the workload, with `EPERM`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeccompProfile {
+    /// No system call is denied.
+    Unconfined,
+    /// Denies the system calls affecting the whole guest, such as loading modules, mounting,
+    /// rebooting, setting the clock or using BPF, like the default profile of OCI runtimes.
+    Default,
+    /// Also denies tracing other processes and entering or creating namespaces.
+    Strict,
+}
+
+impl Default for SeccompProfile {
+    fn default() -> Self {
+        SeccompProfile::Unconfined
+    }
+}
+
+/// A named set of privileges for the workload, matching the usual security of OCI containers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityProfile {
+    /// The workload keeps all the privileges of root.
+    Privileged,
+    /// The workload keeps the default capabilities of OCI containers, and the system calls of the
+    /// default seccomp profile are denied.
+    Default,
+    /// The workload keeps no capability, can't gain privileges through setuid executables, and
+    /// the system calls of the strict seccomp profile are denied.
+    Restricted,
+}
+
+/// The privileges the init process of the guest drops before executing the workload.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecSecurity {
+    /// The mask of the capabilities the workload keeps, or `None` to keep them all. The others are
+    /// also dropped from the bounding set, so they can't be regained through setuid executables.
+    pub capabilities: Option<u64>,
+    /// Whether the workload, and its children, can't gain privileges through execve().
+    pub no_new_privs: bool,
+    pub seccomp: SeccompProfile,
+}
+
+impl ExecSecurity {
+    pub fn from_profile(profile: SecurityProfile) -> Self {
+        match profile {
+            SecurityProfile::Privileged => ExecSecurity::default(),
+            SecurityProfile::Default => ExecSecurity {
+                // The default capabilities are all valid.
+                capabilities: Some(capability_mask(DEFAULT_CAPABILITIES).unwrap()),
+                no_new_privs: false,
+                seccomp: SeccompProfile::Default,
+            },
+            SecurityProfile::Restricted => ExecSecurity {
+                capabilities: Some(0),
+                no_new_privs: true,
+                seccomp: SeccompProfile::Strict,
+            },
+        }
+    }
+
+    /// Returns the variables telling the init process of the guest which privileges to drop.
+    pub fn guest_env(&self) -> Vec<String> {
+        let mut env = Vec::new();
+        if let Some(capabilities) = self.capabilities {
+            env.push(format!("KRUN_CAPS={:x}", capabilities));
+        }
+        if self.no_new_privs {
+            env.push("KRUN_NO_NEW_PRIVS=1".to_string());
+        }
+        match self.seccomp {
+            SeccompProfile::Unconfined => (),
+            SeccompProfile::Default => env.push("KRUN_SECCOMP=default".to_string()),
+            SeccompProfile::Strict => env.push("KRUN_SECCOMP=strict".to_string()),
+        }
+        env
+    }
+}
+
+/// Errors associated with the services run by the guest alongside the workload.
+#[derive(Debug, PartialEq)]
+pub enum ServiceError {
+    /// A service with the same name has already been added.
+    DuplicateName(String),
+    /// The command of the service is empty.
+    EmptyCommand(String),
+    /// An argument of the command contains characters that can't be passed to the guest: ',',
+    /// '"' or control characters.
+    InvalidArgument(String),
+    /// The name of the service is empty, or contains characters other than ASCII alphanumerics,
+    /// '-', '_', '.' and '@'.
+    InvalidName(String),
+    /// `MAX_SERVICES` have already been added.
+    TooManyServices,
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ServiceError::*;
+        match self {
+            DuplicateName(name) => write!(f, "A service named {} has already been added", name),
+            EmptyCommand(name) => write!(f, "The command of the service {} is empty", name),
+            InvalidArgument(arg) => write!(f, "Invalid argument of a service command: {:?}", arg),
+            InvalidName(name) => write!(f, "Invalid service name: {:?}", name),
+            TooManyServices => write!(f, "At most {} services can be added", MAX_SERVICES),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// What the init process of the guest does once a service exits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestartPolicy {
+    /// The service runs to completion before the next one is started, and isn't restarted.
+    OneShot,
+    /// The service is started in the background, and restarted whenever it exits.
+    Always,
+}
+
+impl RestartPolicy {
+    fn guest_name(self) -> &'static str {
+        match self {
+            RestartPolicy::OneShot => "oneshot",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+/// A process the init process of the guest runs alongside the workload, such as a sidecar, and
+/// supervises according to its restart policy. The services are started in the order they were
+/// added, before the workload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub args: Vec<String>,
+    pub restart: RestartPolicy,
+}
+
+impl ServiceConfig {
+    pub fn new(
+        name: &str,
+        args: Vec<String>,
+        restart: RestartPolicy,
+    ) -> Result<Self, ServiceError> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c))
+        {
+            return Err(ServiceError::InvalidName(name.to_string()));
+        }
+        if args.is_empty() || args[0].is_empty() {
+            return Err(ServiceError::EmptyCommand(name.to_string()));
+        }
+        // The command is passed to the guest as a quoted, comma-separated list.
+        if let Some(arg) = args
+            .iter()
+            .find(|arg| arg.contains(|c: char| c == ',' || c == '"' || c.is_control()))
+        {
+            return Err(ServiceError::InvalidArgument(arg.clone()));
+        }
+
+        Ok(ServiceConfig {
+            name: name.to_string(),
+            args,
+            restart,
+        })
+    }
+
+    /// Returns the variable telling the init process of the guest to run the service, the
+    /// `index`-th one.
+    pub fn guest_env(&self, index: usize) -> String {
+        format!(
+            "KRUN_SERVICE_{}=\"{},{}\"",
+            index,
+            self.restart.guest_name(),
+            self.args.join(",")
+        )
+    }
+}
+
+/// The state of a service, as reported by the init process of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceState {
+    /// The service has been started, or restarted.
+    Running,
+    /// The service exited with 0.
+    Exited,
+    /// The service exited with another status, was killed, or couldn't be executed.
+    Failed,
+}
+
+// Written by the init process of the guest, to the port or the address it signals the VMM through,
+// as `GUEST_SERVICE_BASE + index * GUEST_SERVICE_EVENTS + event`.
+const GUEST_SERVICE_BASE: u8 = 129;
+const GUEST_SERVICE_EVENTS: u8 = 3;
+
+/// Decodes a value written by the init process of the guest into the index of a service and its
+/// new state, if it is one.
+pub fn guest_service_event(value: u8) -> Option<(usize, ServiceState)> {
+    let offset = value.checked_sub(GUEST_SERVICE_BASE)?;
+    let index = usize::from(offset / GUEST_SERVICE_EVENTS);
+    if index >= MAX_SERVICES {
+        return None;
+    }
+    let state = match offset % GUEST_SERVICE_EVENTS {
+        0 => ServiceState::Running,
+        1 => ServiceState::Exited,
+        _ => ServiceState::Failed,
+    };
+    Some((index, state))
+}
+
+/// Callback invoked when the state of a service changes, from the thread of the vCPU the guest
+/// reported it through, so it should return promptly.
+pub type ServiceStateCallback = Arc<dyn Fn(&str, ServiceState) + Send + Sync>;
+
+/// Turns the events of the services reported by the guest into the changes of their states.
+#[derive(Clone)]
+pub struct ServiceMonitor {
+    names: Arc<Vec<String>>,
+    callback: Option<ServiceStateCallback>,
+}
+
+impl ServiceMonitor {
+    pub fn new(services: &[ServiceConfig], callback: Option<ServiceStateCallback>) -> Self {
+        ServiceMonitor {
+            names: Arc::new(services.iter().map(|s| s.name.clone()).collect()),
+            callback,
+        }
+    }
+
+    /// Handles `value`, written by the guest, returning whether it was the event of a service.
+    pub fn report(&self, value: u8) -> bool {
+        let (name, state) = match guest_service_event(value)
+            .and_then(|(index, state)| self.names.get(index).map(|name| (name, state)))
+        {
+            Some(event) => event,
+            None => return false,
+        };
+
+        match state {
+            ServiceState::Failed => warn!("The service {} of the guest failed", name),
+            state => info!("The service {} of the guest is {:?}", name, state),
+        }
+        if let Some(callback) = &self.callback {
+            callback(name, state);
+        }
+        true
+    }
+}
+
+impl fmt::Debug for ServiceMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServiceMonitor")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_env_config() {
+        assert!(ExecEnvConfig::default().guest_env().is_empty());
+
+        let config = ExecEnvConfig::new(
+            true,
+            Some("/opt/app/bin:/usr/bin:/bin"),
+            Some("en_US.UTF-8"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.guest_env(),
+            vec![
+                "KRUN_LOGIN_SHELL=1",
+                "KRUN_PATH=\"/opt/app/bin:/usr/bin:/bin\"",
+                "KRUN_LANG=en_US.UTF-8",
+            ]
+        );
+
+        for path in &["", "/bin\"", "/bin\n"] {
+            assert_eq!(
+                ExecEnvConfig::new(false, Some(path), None),
+                Err(ExecEnvError::InvalidPath(path.to_string()))
+            );
+        }
+        for locale in &["", "C UTF-8", "C;id"] {
+            assert_eq!(
+                ExecEnvConfig::new(false, None, Some(locale)),
+                Err(ExecEnvError::InvalidLocale(locale.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_exec_user() {
+        assert_eq!(
+            ExecUser::new(1000, 100, vec![]).unwrap().guest_env(),
+            "KRUN_USER=1000:100:"
+        );
+        assert_eq!(
+            ExecUser::new(0, 0, vec![10, 27]).unwrap().guest_env(),
+            "KRUN_USER=0:0:10,27"
+        );
+
+        assert_eq!(
+            ExecUser::new(1000, 1000, vec![5, u32::MAX]),
+            Err(ExecEnvError::InvalidId(u32::MAX))
+        );
+        assert_eq!(
+            ExecUser::new(1000, 1000, vec![5; MAX_SUPPLEMENTARY_GROUPS + 1]),
+            Err(ExecEnvError::TooManyGroups)
+        );
+    }
+
+    #[test]
+    fn test_exec_security() {
+        assert_eq!(capability_mask::<&str>(&[]), Ok(0));
+        assert_eq!(
+            capability_mask(&["CAP_CHOWN", "net_raw", "CHECKPOINT_RESTORE"]),
+            Ok(1 | 1 << 13 | 1 << 40)
+        );
+        assert_eq!(
+            capability_mask(&["CAP_NET_RAW", "CAP_TELEPORT"]),
+            Err(ExecEnvError::InvalidCapability("CAP_TELEPORT".to_string()))
+        );
+
+        assert!(ExecSecurity::from_profile(SecurityProfile::Privileged)
+            .guest_env()
+            .is_empty());
+        assert_eq!(
+            ExecSecurity::from_profile(SecurityProfile::Default).guest_env(),
+            vec!["KRUN_CAPS=a80425fb", "KRUN_SECCOMP=default"]
+        );
+        assert_eq!(
+            ExecSecurity::from_profile(SecurityProfile::Restricted).guest_env(),
+            vec!["KRUN_CAPS=0", "KRUN_NO_NEW_PRIVS=1", "KRUN_SECCOMP=strict"]
+        );
+    }
+
+    #[test]
+    fn test_service_config() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+
+        let service = ServiceConfig::new(
+            "log-shipper",
+            args(&["/bin/ship", "-q"]),
+            RestartPolicy::Always,
+        )
+        .unwrap();
+        assert_eq!(
+            service.guest_env(2),
+            "KRUN_SERVICE_2=\"always,/bin/ship,-q\""
+        );
+
+        for name in &["", "my service", "a/b"] {
+            assert_eq!(
+                ServiceConfig::new(name, args(&["/bin/true"]), RestartPolicy::OneShot),
+                Err(ServiceError::InvalidName(name.to_string()))
+            );
+        }
+        assert_eq!(
+            ServiceConfig::new("migrate", args(&[]), RestartPolicy::OneShot),
+            Err(ServiceError::EmptyCommand("migrate".to_string()))
+        );
+        assert_eq!(
+            ServiceConfig::new("migrate", args(&["/bin/m", "a,b"]), RestartPolicy::OneShot),
+            Err(ServiceError::InvalidArgument("a,b".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_service_monitor() {
+        use std::sync::Mutex;
+
+        assert_eq!(guest_service_event(128), None);
+        assert_eq!(guest_service_event(129), Some((0, ServiceState::Running)));
+        assert_eq!(guest_service_event(133), Some((1, ServiceState::Failed)));
+        assert_eq!(
+            guest_service_event(129 + 31 * 3 + 1),
+            Some((31, ServiceState::Exited))
+        );
+        assert_eq!(guest_service_event(129 + 32 * 3), None);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let callback_events = events.clone();
+        let services = vec![
+            ServiceConfig::new("a", vec!["/a".to_string()], RestartPolicy::OneShot).unwrap(),
+            ServiceConfig::new("b", vec!["/b".to_string()], RestartPolicy::Always).unwrap(),
+        ];
+        let monitor = ServiceMonitor::new(
+            &services,
+            Some(Arc::new(move |name: &str, state| {
+                callback_events
+                    .lock()
+                    .unwrap()
+                    .push((name.to_string(), state))
+            })),
+        );
+
+        assert!(monitor.report(130));
+        assert!(monitor.report(132));
+        // Neither an unknown service nor another signal are reported.
+        assert!(!monitor.report(135));
+        assert!(!monitor.report(124));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("a".to_string(), ServiceState::Exited),
+                ("b".to_string(), ServiceState::Running),
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use utils::eventfd::EventFd;
+
+/// Why the microVM exited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitReason {
+    /// The guest shut down, or reset the machine.
+    GuestShutdown,
+    /// The guest was stopped for exceeding its maximum runtime.
+    MaxRuntime,
+    /// A device failed, and the policy for its failures stopped the microVM.
+    DeviceFailure,
+    /// A vCPU failed.
+    VcpuError,
+}
+
+impl ExitReason {
+    pub fn as_str(self) -> &'static str {
+        use self::ExitReason::*;
+        match self {
+            GuestShutdown => "guest shutdown",
+            MaxRuntime => "max runtime exceeded",
+            DeviceFailure => "device failure",
+            VcpuError => "vcpu error",
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// How the microVM exited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExitStatus {
+    /// The exit code the process would have exited with.
+    pub code: i32,
+    pub reason: ExitReason,
+}
+
+struct ExitState {
+    evt: EventFd,
+    status: Mutex<Option<ExitStatus>>,
+}
+
+/// A pollable handle on the exit of the microVM. Its fd becomes readable once the microVM has
+/// exited, its vCPUs stopped and its teardown run, and the status is available from then on.
+///
+/// Once a microVM is given an exit handle, it no longer exits the process when it stops, the
+/// event loop returning instead, so the embedder outlives it.
+#[derive(Clone)]
+pub struct ExitHandle(Arc<ExitState>);
+
+impl ExitHandle {
+    pub fn new() -> io::Result<Self> {
+        Ok(ExitHandle(Arc::new(ExitState {
+            evt: EventFd::new(utils::eventfd::EFD_NONBLOCK)?,
+            status: Mutex::new(None),
+        })))
+    }
+
+    /// Returns how the microVM exited, or `None` while it hasn't.
+    pub fn status(&self) -> Option<ExitStatus> {
+        *self.0.status.lock().unwrap()
+    }
+
+    /// Records how the microVM exited, and makes the fd readable. Only the first exit counts.
+    pub fn notify(&self, status: ExitStatus) {
+        let mut current = self.0.status.lock().unwrap();
+        if current.is_some() {
+            return;
+        }
+        *current = Some(status);
+        if let Err(e) = self.0.evt.write(1) {
+            error!("Failed to signal the exit of the microVM: {:?}", e);
+        }
+    }
+}
+
+impl AsRawFd for ExitHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.evt.as_raw_fd()
+    }
+}
+
+impl fmt::Debug for ExitHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExitHandle")
+            .field("status", &self.status())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_readable(fd: RawFd) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn test_exit_handle() {
+        let handle = ExitHandle::new().unwrap();
+        assert_eq!(handle.status(), None);
+        assert!(!is_readable(handle.as_raw_fd()));
+
+        let status = ExitStatus {
+            code: 0,
+            reason: ExitReason::GuestShutdown,
+        };
+        handle.clone().notify(status);
+        assert_eq!(handle.status(), Some(status));
+        assert!(is_readable(handle.as_raw_fd()));
+
+        // Only the first exit counts.
+        handle.notify(ExitStatus {
+            code: 1,
+            reason: ExitReason::VcpuError,
+        });
+        assert_eq!(handle.status(), Some(status));
+    }
+}
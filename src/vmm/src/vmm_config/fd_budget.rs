@@ -0,0 +1 @@
+pub use devices::fd_budget::{FdBudget, FdEvent, FdEventCallback, FdEventKind, FdUsage};
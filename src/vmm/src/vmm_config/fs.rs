@@ -3,6 +3,7 @@ use std::fmt;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+use devices::fd_budget::FdAccount;
 use devices::virtio::{Fs, FsError};
 
 #[derive(Debug)]
@@ -15,7 +16,16 @@ impl fmt::Display for FsConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::FsConfigError::*;
         match *self {
-            CreateFsDevice(ref e) => write!(f, "Cannot create vsock device: {:?}", e),
+            CreateFsDevice(_) => write!(f, "Cannot create fs device"),
+        }
+    }
+}
+
+impl std::error::Error for FsConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::FsConfigError::*;
+        match *self {
+            CreateFsDevice(ref e) => Some(e),
         }
     }
 }
@@ -41,16 +51,20 @@ impl FsBuilder {
         }
     }
 
-    pub fn insert(&mut self, config: FsDeviceConfig) -> Result<()> {
-        let fs_dev = Arc::new(Mutex::new(Self::create_fs(config)?));
+    /// Creates an fs device from `config`, charging the file descriptors it holds to `fd_account`.
+    pub fn insert(&mut self, config: FsDeviceConfig, fd_account: FdAccount) -> Result<()> {
+        let fs_dev = Arc::new(Mutex::new(Self::create_fs(config, fd_account)?));
         self.list.push_back(fs_dev);
         Ok(())
     }
 
-    pub fn create_fs(config: FsDeviceConfig) -> Result<Fs> {
-        Ok(
-            devices::virtio::Fs::new(config.fs_id, config.shared_dir, config.mapped_volumes)
-                .map_err(FsConfigError::CreateFsDevice)?,
+    pub fn create_fs(config: FsDeviceConfig, fd_account: FdAccount) -> Result<Fs> {
+        Ok(devices::virtio::Fs::new(
+            config.fs_id,
+            config.shared_dir,
+            config.mapped_volumes,
+            Some(fd_account),
         )
+        .map_err(FsConfigError::CreateFsDevice)?)
     }
 }
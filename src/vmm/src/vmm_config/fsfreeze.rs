@@ -0,0 +1,267 @@
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use devices::virtio::CallbackInput;
+
+/// Request of the host to freeze the filesystems of the guest.
+pub const FSFREEZE_FREEZE: u8 = b'F';
+/// Request of the host to thaw the filesystems of the guest.
+pub const FSFREEZE_THAW: u8 = b'T';
+
+/// Errors associated with the freezes of the filesystems of the guest.
+#[derive(Debug)]
+pub enum FsFreezeError {
+    /// The time after which the guest thaws its filesystems on its own is zero.
+    InvalidThawTimeout,
+    /// The guest didn't reply in time, its agent being busy or not running.
+    Timeout,
+    /// The guest had thawed its filesystems on its own before being asked to, so a snapshot
+    /// taken in the meantime may not be consistent.
+    Expired,
+    /// The guest failed to freeze or to thaw its filesystems, with the errno it replied.
+    Guest(i32),
+    /// Failed to create the input of the console, or to signal it.
+    Input(io::Error),
+}
+
+impl fmt::Display for FsFreezeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FsFreezeError::*;
+        match self {
+            InvalidThawTimeout => write!(f, "The thaw timeout of the guest must not be zero"),
+            Timeout => write!(f, "The guest didn't reply in time"),
+            Expired => write!(
+                f,
+                "The guest thawed its filesystems on its own before being asked to"
+            ),
+            Guest(errno) => write!(
+                f,
+                "The guest failed to freeze or thaw its filesystems: {}",
+                io::Error::from_raw_os_error(*errno)
+            ),
+            Input(e) => write!(f, "Error with the input of the freeze channel: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FsFreezeError {}
+
+#[derive(Default)]
+struct Replies {
+    // The sequence number of the last request.
+    seq: u8,
+    // The last reply, made of the sequence number of its request and of its status.
+    reply: Option<(u8, u8)>,
+    // The first byte of a reply split across writes.
+    partial: Option<u8>,
+}
+
+/// A channel to the init of the guest, freezing and thawing its filesystems so the host can
+/// snapshot the disk images consistently, through a console of its own.
+///
+/// Each request is the command byte followed by a sequence number, and the guest replies with the
+/// sequence number followed by 0 or an errno. Freezing syncs every filesystem, so the writes
+/// cached in the guest reach the shares, but only those backed by a block device are frozen.
+///
+/// Clones share the channel, so the embedder can keep one while the microVM runs.
+#[derive(Clone)]
+pub struct FsFreeze {
+    thaw_timeout: Duration,
+    input: CallbackInput,
+    replies: Arc<(Mutex<Replies>, Condvar)>,
+    // Serializes the requests, each waiting for its reply.
+    request: Arc<Mutex<()>>,
+}
+
+impl FsFreeze {
+    /// Creates a channel whose guest thaws its filesystems on its own once `thaw_timeout` has
+    /// elapsed since it froze them, so an embedder failing to thaw them doesn't stall it forever.
+    pub fn new(thaw_timeout: Duration) -> std::result::Result<Self, FsFreezeError> {
+        if thaw_timeout.as_secs() == 0 {
+            return Err(FsFreezeError::InvalidThawTimeout);
+        }
+
+        Ok(FsFreeze {
+            thaw_timeout,
+            input: CallbackInput::new().map_err(FsFreezeError::Input)?,
+            replies: Arc::new((Mutex::new(Replies::default()), Condvar::new())),
+            request: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn thaw_timeout(&self) -> Duration {
+        self.thaw_timeout
+    }
+
+    /// Returns the input of the console, carrying the requests.
+    pub fn input(&self) -> CallbackInput {
+        self.input.clone()
+    }
+
+    /// Returns the output of the console, carrying the replies.
+    pub fn output(&self) -> FsFreezeOutput {
+        FsFreezeOutput {
+            replies: self.replies.clone(),
+        }
+    }
+
+    /// Freezes the filesystems of the guest, waiting up to `timeout` for it to reply.
+    pub fn freeze(&self, timeout: Duration) -> std::result::Result<(), FsFreezeError> {
+        self.request(FSFREEZE_FREEZE, timeout)
+    }
+
+    /// Thaws the filesystems of the guest, waiting up to `timeout` for it to reply. Fails with
+    /// `Expired` if the guest had already thawed them on its own.
+    pub fn thaw(&self, timeout: Duration) -> std::result::Result<(), FsFreezeError> {
+        self.request(FSFREEZE_THAW, timeout)
+    }
+
+    fn request(&self, command: u8, timeout: Duration) -> std::result::Result<(), FsFreezeError> {
+        let _request = self.request.lock().unwrap();
+        let (replies, replied) = &*self.replies;
+
+        // The replies to the requests given up on before are told apart by their sequence number.
+        let seq = {
+            let mut replies = replies.lock().unwrap();
+            replies.seq = replies.seq.wrapping_add(1);
+            replies.reply = None;
+            replies.seq
+        };
+        match self.input.push_all(&[command, seq]) {
+            Ok(true) => (),
+            // The guest hasn't read the requests given up on before.
+            Ok(false) => return Err(FsFreezeError::Timeout),
+            Err(e) => return Err(FsFreezeError::Input(e)),
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut replies = replies.lock().unwrap();
+        loop {
+            match replies.reply {
+                Some((reply_seq, status)) if reply_seq == seq => {
+                    return match i32::from(status) {
+                        0 => Ok(()),
+                        libc::ESTALE => Err(FsFreezeError::Expired),
+                        errno => Err(FsFreezeError::Guest(errno)),
+                    };
+                }
+                _ => (),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(FsFreezeError::Timeout);
+            }
+            replies = replied.wait_timeout(replies, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl fmt::Debug for FsFreeze {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsFreeze")
+            .field("thaw_timeout", &self.thaw_timeout)
+            .finish()
+    }
+}
+
+/// The output of the console of an `FsFreeze`, handing the replies of the guest to the request
+/// waiting for them.
+pub struct FsFreezeOutput {
+    replies: Arc<(Mutex<Replies>, Condvar)>,
+}
+
+impl io::Write for FsFreezeOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (replies, replied) = &*self.replies;
+        let mut replies = replies.lock().unwrap();
+        for &byte in buf {
+            match replies.partial.take() {
+                Some(seq) => {
+                    replies.reply = Some((seq, byte));
+                    replied.notify_all();
+                }
+                None => replies.partial = Some(byte),
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    // Replies to the next request read from the input of `fsfreeze` with `status`.
+    fn reply(fsfreeze: &FsFreeze, status: u8) -> thread::JoinHandle<u8> {
+        let mut input = fsfreeze.input();
+        let mut output = fsfreeze.output();
+        thread::spawn(move || {
+            let mut request = [0u8; 2];
+            while input.read(&mut request).unwrap() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Split across writes, like the console may do.
+            output.write_all(&request[1..]).unwrap();
+            output.write_all(&[status]).unwrap();
+            request[0]
+        })
+    }
+
+    #[test]
+    fn test_fsfreeze_config() {
+        assert!(matches!(
+            FsFreeze::new(Duration::from_millis(500)),
+            Err(FsFreezeError::InvalidThawTimeout)
+        ));
+    }
+
+    #[test]
+    fn test_fsfreeze() {
+        let fsfreeze = FsFreeze::new(Duration::from_secs(60)).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let agent = reply(&fsfreeze, 0);
+        fsfreeze.freeze(timeout).unwrap();
+        assert_eq!(agent.join().unwrap(), FSFREEZE_FREEZE);
+
+        let agent = reply(&fsfreeze, libc::ESTALE as u8);
+        assert!(matches!(
+            fsfreeze.thaw(timeout),
+            Err(FsFreezeError::Expired)
+        ));
+        assert_eq!(agent.join().unwrap(), FSFREEZE_THAW);
+
+        let agent = reply(&fsfreeze, libc::EIO as u8);
+        assert!(matches!(
+            fsfreeze.freeze(timeout),
+            Err(FsFreezeError::Guest(libc::EIO))
+        ));
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn test_fsfreeze_timeout() {
+        let fsfreeze = FsFreeze::new(Duration::from_secs(60)).unwrap();
+        assert!(matches!(
+            fsfreeze.freeze(Duration::from_millis(10)),
+            Err(FsFreezeError::Timeout)
+        ));
+
+        // The late reply to the request given up on doesn't answer the next one.
+        let mut request = [0u8; 4];
+        assert_eq!(fsfreeze.input().read(&mut request).unwrap(), 2);
+        fsfreeze.output().write_all(&[request[1], 0]).unwrap();
+        assert!(matches!(
+            fsfreeze.thaw(Duration::from_millis(10)),
+            Err(FsFreezeError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+use std::fmt;
+use std::fmt::Write as _;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use devices::virtio::CallbackInput;
+use vmm_config::boot_timeline::push_json_string;
+
+/// Request of the host for the network configuration of the guest.
+pub const GUEST_NETWORK_QUERY: u8 = b'Q';
+
+/// Errors associated with the queries of the network configuration of the guest.
+#[derive(Debug)]
+pub enum GuestNetworkError {
+    /// The guest didn't reply in time, its agent being busy or not running.
+    Timeout,
+    /// The guest replied with a line it isn't expected to.
+    Malformed(String),
+    /// Failed to create the input of the console, or to signal it.
+    Input(io::Error),
+}
+
+impl fmt::Display for GuestNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GuestNetworkError::*;
+        match self {
+            Timeout => write!(f, "The guest didn't reply in time"),
+            Malformed(line) => write!(
+                f,
+                "The guest replied with a malformed network configuration: {:?}",
+                line
+            ),
+            Input(e) => write!(f, "Error with the input of the network channel: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GuestNetworkError {}
+
+/// An address configured on an interface of the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestInterfaceAddress {
+    pub interface: String,
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+/// A route of the guest, through `gateway` if it's not directly reachable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestRoute {
+    pub destination: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    pub interface: String,
+}
+
+/// The network configuration of the guest, as reported by its init: the addresses of its
+/// interfaces but the loopback one, its routes and the nameservers it resolves names with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GuestNetworkInfo {
+    pub addresses: Vec<GuestInterfaceAddress>,
+    pub routes: Vec<GuestRoute>,
+    pub nameservers: Vec<IpAddr>,
+}
+
+impl GuestNetworkInfo {
+    /// Parses the report of the guest, one line per address, route or nameserver:
+    ///
+    /// ```text
+    /// addr <interface> <address>/<prefix length>
+    /// route <destination>/<prefix length> <gateway or -> <interface>
+    /// dns <address>
+    /// ```
+    ///
+    /// Lines of other kinds are ignored, so newer guests can report more.
+    pub fn parse(report: &str) -> std::result::Result<Self, GuestNetworkError> {
+        let mut info = GuestNetworkInfo::default();
+        for line in report.lines().filter(|line| !line.is_empty()) {
+            let malformed = || GuestNetworkError::Malformed(line.to_string());
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["addr", interface, network] => {
+                    let (address, prefix_len) = parse_network(network).ok_or_else(malformed)?;
+                    info.addresses.push(GuestInterfaceAddress {
+                        interface: interface.to_string(),
+                        address,
+                        prefix_len,
+                    });
+                }
+                ["route", network, gateway, interface] => {
+                    let (destination, prefix_len) = parse_network(network).ok_or_else(malformed)?;
+                    let gateway = match *gateway {
+                        "-" => None,
+                        gateway => Some(gateway.parse().map_err(|_| malformed())?),
+                    };
+                    info.routes.push(GuestRoute {
+                        destination,
+                        prefix_len,
+                        gateway,
+                        interface: interface.to_string(),
+                    });
+                }
+                ["dns", address] => info
+                    .nameservers
+                    .push(address.parse().map_err(|_| malformed())?),
+                ["addr", ..] | ["route", ..] | ["dns", ..] => return Err(malformed()),
+                _ => (),
+            }
+        }
+        Ok(info)
+    }
+
+    /// Serializes the configuration as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"addresses\":[");
+        for (index, address) in self.addresses.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"interface\":");
+            push_json_string(&mut out, &address.interface);
+            let _ = write!(
+                out,
+                ",\"address\":\"{}\",\"prefix_len\":{}}}",
+                address.address, address.prefix_len
+            );
+        }
+        out.push_str("],\"routes\":[");
+        for (index, route) in self.routes.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"destination\":\"{}\",\"prefix_len\":{},\"gateway\":",
+                route.destination, route.prefix_len
+            );
+            match route.gateway {
+                Some(gateway) => {
+                    let _ = write!(out, "\"{}\"", gateway);
+                }
+                None => out.push_str("null"),
+            }
+            out.push_str(",\"interface\":");
+            push_json_string(&mut out, &route.interface);
+            out.push('}');
+        }
+        out.push_str("],\"nameservers\":[");
+        for (index, nameserver) in self.nameservers.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\"", nameserver);
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+// Parses `<address>/<prefix length>`, the prefix length not exceeding the bits of the address.
+fn parse_network(network: &str) -> Option<(IpAddr, u8)> {
+    let slash = network.find('/')?;
+    let address: IpAddr = network[..slash].parse().ok()?;
+    let prefix_len: u8 = network[slash + 1..].parse().ok()?;
+    let max_len = if address.is_ipv4() { 32 } else { 128 };
+    if prefix_len > max_len {
+        return None;
+    }
+    Some((address, prefix_len))
+}
+
+// The part of a reply being read, along with what was read of it so far, as replies may be split
+// across writes.
+enum Partial {
+    Seq,
+    LenHigh(u8),
+    LenLow(u8, u8),
+    Report(u8, usize, Vec<u8>),
+}
+
+struct Replies {
+    // The sequence number of the last request.
+    seq: u8,
+    // The last reply, made of the sequence number of its request and of the report.
+    reply: Option<(u8, Vec<u8>)>,
+    partial: Partial,
+}
+
+/// A channel to the init of the guest, reporting its network configuration so the embedder can
+/// surface it, through a console of its own. It's mostly useful with the virtio-net backend, as
+/// the guest shares the network of the host otherwise.
+///
+/// Each request is the command byte followed by a sequence number, and the guest replies with the
+/// sequence number followed by the length of its report, on two bytes in big-endian order, and
+/// the report itself, as parsed by `GuestNetworkInfo::parse`. The configuration is read on each
+/// request, so it reflects the changes made by the workload.
+///
+/// Clones share the channel, so the embedder can keep one while the microVM runs.
+#[derive(Clone)]
+pub struct GuestNetwork {
+    input: CallbackInput,
+    replies: Arc<(Mutex<Replies>, Condvar)>,
+    // Serializes the requests, each waiting for its reply.
+    request: Arc<Mutex<()>>,
+}
+
+impl GuestNetwork {
+    pub fn new() -> std::result::Result<Self, GuestNetworkError> {
+        Ok(GuestNetwork {
+            input: CallbackInput::new().map_err(GuestNetworkError::Input)?,
+            replies: Arc::new((
+                Mutex::new(Replies {
+                    seq: 0,
+                    reply: None,
+                    partial: Partial::Seq,
+                }),
+                Condvar::new(),
+            )),
+            request: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Returns the input of the console, carrying the requests.
+    pub fn input(&self) -> CallbackInput {
+        self.input.clone()
+    }
+
+    /// Returns the output of the console, carrying the replies.
+    pub fn output(&self) -> GuestNetworkOutput {
+        GuestNetworkOutput {
+            replies: self.replies.clone(),
+        }
+    }
+
+    /// Queries the network configuration of the guest, waiting up to `timeout` for it to reply.
+    pub fn query(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<GuestNetworkInfo, GuestNetworkError> {
+        let _request = self.request.lock().unwrap();
+        let (replies, replied) = &*self.replies;
+
+        // The replies to the requests given up on before are told apart by their sequence number.
+        let seq = {
+            let mut replies = replies.lock().unwrap();
+            replies.seq = replies.seq.wrapping_add(1);
+            replies.reply = None;
+            replies.seq
+        };
+        match self.input.push_all(&[GUEST_NETWORK_QUERY, seq]) {
+            Ok(true) => (),
+            // The guest hasn't read the requests given up on before.
+            Ok(false) => return Err(GuestNetworkError::Timeout),
+            Err(e) => return Err(GuestNetworkError::Input(e)),
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut replies = replies.lock().unwrap();
+        loop {
+            match replies.reply.take() {
+                Some((reply_seq, report)) if reply_seq == seq => {
+                    return GuestNetworkInfo::parse(&String::from_utf8_lossy(&report));
+                }
+                _ => (),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(GuestNetworkError::Timeout);
+            }
+            replies = replied.wait_timeout(replies, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl fmt::Debug for GuestNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuestNetwork").finish()
+    }
+}
+
+/// The output of the console of a `GuestNetwork`, handing the replies of the guest to the request
+/// waiting for them.
+pub struct GuestNetworkOutput {
+    replies: Arc<(Mutex<Replies>, Condvar)>,
+}
+
+impl io::Write for GuestNetworkOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (replies, replied) = &*self.replies;
+        let mut replies = replies.lock().unwrap();
+        for &byte in buf {
+            let partial = match std::mem::replace(&mut replies.partial, Partial::Seq) {
+                Partial::Seq => Partial::LenHigh(byte),
+                Partial::LenHigh(seq) => Partial::LenLow(seq, byte),
+                Partial::LenLow(seq, high) => {
+                    let len = usize::from(high) << 8 | usize::from(byte);
+                    Partial::Report(seq, len, Vec::with_capacity(len))
+                }
+                Partial::Report(seq, len, mut report) => {
+                    report.push(byte);
+                    Partial::Report(seq, len, report)
+                }
+            };
+            // An empty report is complete as soon as its length is read.
+            replies.partial = match partial {
+                Partial::Report(seq, len, report) if report.len() == len => {
+                    replies.reply = Some((seq, report));
+                    replied.notify_all();
+                    Partial::Seq
+                }
+                partial => partial,
+            };
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    const REPORT: &str = "addr eth0 192.168.127.2/24\n\
+                          addr eth0 fe80::5054:ff:fe12:3456/64\n\
+                          route 0.0.0.0/0 192.168.127.1 eth0\n\
+                          route 192.168.127.0/24 - eth0\n\
+                          route ::/0 fe80::1 eth0\n\
+                          dns 192.168.127.1\n\
+                          mtu eth0 1500\n";
+
+    #[test]
+    fn test_guest_network_info() {
+        let info = GuestNetworkInfo::parse(REPORT).unwrap();
+        assert_eq!(info.addresses.len(), 2);
+        assert_eq!(
+            info.addresses[1],
+            GuestInterfaceAddress {
+                interface: "eth0".to_string(),
+                address: "fe80::5054:ff:fe12:3456".parse().unwrap(),
+                prefix_len: 64,
+            }
+        );
+        assert_eq!(info.routes.len(), 3);
+        assert_eq!(info.routes[1].gateway, None);
+        assert_eq!(
+            info.nameservers,
+            vec!["192.168.127.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            GuestNetworkInfo {
+                routes: info.routes[..2].to_vec(),
+                ..Default::default()
+            }
+            .to_json(),
+            "{\"addresses\":[],\"routes\":[\
+             {\"destination\":\"0.0.0.0\",\"prefix_len\":0,\"gateway\":\"192.168.127.1\",\
+             \"interface\":\"eth0\"},\
+             {\"destination\":\"192.168.127.0\",\"prefix_len\":24,\"gateway\":null,\
+             \"interface\":\"eth0\"}],\"nameservers\":[]}"
+        );
+
+        for line in &[
+            "addr eth0 192.168.127.2",
+            "addr eth0 192.168.127.2/33",
+            "route ::/0 gateway eth0",
+            "dns",
+        ] {
+            assert!(matches!(
+                GuestNetworkInfo::parse(line),
+                Err(GuestNetworkError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_guest_network() {
+        let network = GuestNetwork::new().unwrap();
+        let mut input = network.input();
+        let mut output = network.output();
+        let agent = thread::spawn(move || {
+            let mut request = [0u8; 2];
+            while input.read(&mut request).unwrap() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let len = REPORT.len() as u16;
+            // Split across writes, like the console may do.
+            output.write_all(&[request[1], (len >> 8) as u8]).unwrap();
+            output.write_all(&[len as u8]).unwrap();
+            output.write_all(&REPORT.as_bytes()[..10]).unwrap();
+            output.write_all(&REPORT.as_bytes()[10..]).unwrap();
+            request[0]
+        });
+        let info = network.query(Duration::from_secs(5)).unwrap();
+        assert_eq!(agent.join().unwrap(), GUEST_NETWORK_QUERY);
+        assert_eq!(info, GuestNetworkInfo::parse(REPORT).unwrap());
+    }
+
+    #[test]
+    fn test_guest_network_timeout() {
+        let network = GuestNetwork::new().unwrap();
+        assert!(matches!(
+            network.query(Duration::from_millis(10)),
+            Err(GuestNetworkError::Timeout)
+        ));
+
+        // The late reply to the request given up on doesn't answer the next one.
+        let mut request = [0u8; 4];
+        assert_eq!(network.input().read(&mut request).unwrap(), 2);
+        network.output().write_all(&[request[1], 0, 0]).unwrap();
+        assert!(matches!(
+            network.query(Duration::from_millis(10)),
+            Err(GuestNetworkError::Timeout)
+        ));
+    }
+}
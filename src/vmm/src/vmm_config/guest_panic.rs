@@ -0,0 +1,26 @@
+use std::fmt;
+use std::sync::Arc;
+
+pub use devices::virtio::{GuestPanic, GuestPanicKind};
+
+/// Callback invoked with every panic or oops of the guest kernel, from the thread of the event
+/// loop.
+pub type GuestPanicCallback = Arc<dyn Fn(&GuestPanic) + Send + Sync>;
+
+/// How the panics and oopses of the guest kernel are detected and reported.
+#[derive(Clone, Default)]
+pub struct GuestPanicConfig {
+    /// Detects them from the output of the console, for the guests with no other way of
+    /// reporting them.
+    pub scan_console: bool,
+    pub callback: Option<GuestPanicCallback>,
+}
+
+impl fmt::Debug for GuestPanicConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuestPanicConfig")
+            .field("scan_console", &self.scan_console)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Errors associated with the idle policy configuration.
+#[derive(Debug, PartialEq)]
+pub enum IdlePolicyConfigError {
+    /// The idle interval is zero.
+    InvalidIdleInterval,
+}
+
+impl fmt::Display for IdlePolicyConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::IdlePolicyConfigError::*;
+        match *self {
+            InvalidIdleInterval => write!(f, "The idle interval must be non-zero"),
+        }
+    }
+}
+
+impl std::error::Error for IdlePolicyConfigError {}
+
+/// Policy for freezing an idle microVM. Once the vCPUs have been halted, and the devices
+/// inactive, for `idle_interval`, the vCPUs are paused until there's activity on any device.
+/// If `reclaim_memory` is set, the memory of the frozen guest is also handed back to the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdlePolicyConfig {
+    pub idle_interval: Duration,
+    pub reclaim_memory: bool,
+}
+
+impl IdlePolicyConfig {
+    pub fn new(
+        idle_interval: Duration,
+        reclaim_memory: bool,
+    ) -> std::result::Result<Self, IdlePolicyConfigError> {
+        if idle_interval == Duration::from_secs(0) {
+            return Err(IdlePolicyConfigError::InvalidIdleInterval);
+        }
+
+        Ok(IdlePolicyConfig {
+            idle_interval,
+            reclaim_memory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_policy_config() {
+        assert_eq!(
+            IdlePolicyConfig::new(Duration::from_secs(0), false),
+            Err(IdlePolicyConfigError::InvalidIdleInterval)
+        );
+
+        let config = IdlePolicyConfig::new(Duration::from_secs(30), true).unwrap();
+        assert_eq!(config.idle_interval, Duration::from_secs(30));
+        assert!(config.reclaim_memory);
+    }
+}
@@ -1,12 +1,39 @@
 // Copyright 2020, Red Hat Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::slice;
+use std::sync::Arc;
 
-/// Data structure holding the attributes read from the `libkrunfw` kernel config.
-#[derive(Debug, Default)]
+use utils::sha256::{self, DIGEST_SIZE};
+use vm_memory::mmap::{MmapRegion, MmapRegionError};
+
+/// Where the kernel bundle is taken from. It's shared by the microVMs booting the same flavor,
+/// each loading its own copy of the kernel, if any.
+#[derive(Clone, Debug)]
+enum KernelBundleSource {
+    /// The kernel libkrunfw maps in the address space of the process for its whole lifetime.
+    Krunfw { host_addr: u64 },
+    /// A file holding the kernel at `offset`, read as the microVM is built.
+    File {
+        file: Arc<File>,
+        offset: u64,
+        sha256: Option<[u8; DIGEST_SIZE]>,
+    },
+    /// A copy of the kernel, taken when the bundle is set.
+    Buffer(Arc<Vec<u8>>),
+}
+
+/// Data structure holding the attributes read from the `libkrunfw` kernel config, or provided by
+/// the embedder. It's only built through its validating constructors, so it can't hold a host
+/// pointer the embedder didn't vouch for.
+#[derive(Clone, Debug)]
 pub struct KernelBundle {
-    pub host_addr: u64,
+    source: KernelBundleSource,
     pub guest_addr: u64,
     pub size: usize,
 }
@@ -14,21 +41,321 @@ pub struct KernelBundle {
 /// Structure used to specify the parameters for the `libkrunfw` kernel bundle.
 #[derive(Debug)]
 pub enum KernelBundleError {
+    /// The digest of the kernel doesn't match the expected one.
+    DigestMismatch,
+    /// The file holding the kernel ends before it.
+    FileTooShort,
     /// Guest address is not page-aligned.
     InvalidGuestAddress,
+    /// The name of a flavor is empty.
+    InvalidFlavorName,
     /// Host address is zero or not page-aligned.
     InvalidHostAddress,
     /// Kernel size is zero or not a multiple of the page size.
     InvalidSize,
+    /// The file holding the kernel can't be read.
+    Read(io::Error),
 }
 
 impl Display for KernelBundleError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::KernelBundleError::*;
         match *self {
+            DigestMismatch => write!(f, "The SHA-256 digest of the kernel doesn't match"),
+            FileTooShort => write!(f, "The kernel file is shorter than the kernel"),
             InvalidGuestAddress => write!(f, "Guest address is not page-aligned"),
+            InvalidFlavorName => write!(f, "The name of the kernel flavor is empty"),
             InvalidHostAddress => write!(f, "Host address is zero or not page-aligned"),
             InvalidSize => write!(f, "Kernel size is zero or not a multiple of the page size"),
+            Read(_) => write!(f, "Cannot read the kernel file"),
+        }
+    }
+}
+
+impl std::error::Error for KernelBundleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            KernelBundleError::Read(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn page_size() -> usize {
+    // Safe because this call just returns the page size and doesn't have any side effects.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn check_layout(guest_addr: u64, size: usize) -> std::result::Result<(), KernelBundleError> {
+    let page_size = page_size();
+    if (guest_addr as usize) & (page_size - 1) != 0 {
+        return Err(KernelBundleError::InvalidGuestAddress);
+    }
+    if size == 0 || size & (page_size - 1) != 0 {
+        return Err(KernelBundleError::InvalidSize);
+    }
+    Ok(())
+}
+
+fn check_digest(
+    data: &[u8],
+    sha256: Option<[u8; DIGEST_SIZE]>,
+) -> std::result::Result<(), KernelBundleError> {
+    match sha256 {
+        Some(expected) if sha256::digest(data) != expected => {
+            Err(KernelBundleError::DigestMismatch)
+        }
+        _ => Ok(()),
+    }
+}
+
+impl KernelBundle {
+    /// Returns the bundle of the kernel libkrunfw maps at `host_addr`.
+    ///
+    /// # Safety
+    ///
+    /// The `size` bytes at `host_addr` must stay mapped, and not be accessed by anything else, for
+    /// the lifetime of the process.
+    pub unsafe fn from_krunfw(
+        host_addr: u64,
+        guest_addr: u64,
+        size: usize,
+    ) -> std::result::Result<Self, KernelBundleError> {
+        if host_addr == 0 || (host_addr as usize) & (page_size() - 1) != 0 {
+            return Err(KernelBundleError::InvalidHostAddress);
+        }
+        check_layout(guest_addr, size)?;
+        Ok(KernelBundle {
+            source: KernelBundleSource::Krunfw { host_addr },
+            guest_addr,
+            size,
+        })
+    }
+
+    /// Returns the bundle of the kernel held by `file` at `offset`. It's read, and its digest
+    /// checked against `sha256` if any, as the microVM is built, so the file can't change behind
+    /// the check.
+    pub fn from_file(
+        file: File,
+        offset: u64,
+        guest_addr: u64,
+        size: usize,
+        sha256: Option<[u8; DIGEST_SIZE]>,
+    ) -> std::result::Result<Self, KernelBundleError> {
+        check_layout(guest_addr, size)?;
+        let len = file.metadata().map_err(KernelBundleError::Read)?.len();
+        if offset
+            .checked_add(size as u64)
+            .map_or(true, |end| end > len)
+        {
+            return Err(KernelBundleError::FileTooShort);
         }
+        Ok(KernelBundle {
+            source: KernelBundleSource::File {
+                file: Arc::new(file),
+                offset,
+                sha256,
+            },
+            guest_addr,
+            size,
+        })
+    }
+
+    /// Returns the bundle of a copy of the kernel in `data`, whose digest is checked against
+    /// `sha256` if any.
+    pub fn from_buffer(
+        data: &[u8],
+        guest_addr: u64,
+        sha256: Option<[u8; DIGEST_SIZE]>,
+    ) -> std::result::Result<Self, KernelBundleError> {
+        check_layout(guest_addr, data.len())?;
+        check_digest(data, sha256)?;
+        Ok(KernelBundle {
+            source: KernelBundleSource::Buffer(Arc::new(data.to_vec())),
+            guest_addr,
+            size: data.len(),
+        })
+    }
+
+    /// Returns the region holding the kernel, to be placed in the guest memory. The kernel of
+    /// libkrunfw is used in place, while the others are copied to a region of their own.
+    pub fn load(&self) -> std::result::Result<MmapRegion, KernelBundleLoadError> {
+        match self.source {
+            // Safe because `from_krunfw()` requires the kernel to stay mapped.
+            KernelBundleSource::Krunfw { host_addr } => {
+                unsafe { MmapRegion::build_raw(host_addr as *mut u8, self.size, 0, 0) }
+                    .map_err(KernelBundleLoadError::Mmap)
+            }
+            KernelBundleSource::File {
+                ref file,
+                offset,
+                sha256,
+            } => self.load_copy(|data| {
+                file.read_exact_at(data, offset)
+                    .map_err(KernelBundleError::Read)?;
+                check_digest(data, sha256)
+            }),
+            KernelBundleSource::Buffer(ref buf) => self.load_copy(|data| {
+                data.copy_from_slice(buf);
+                Ok(())
+            }),
+        }
+    }
+
+    fn load_copy<F>(&self, fill: F) -> std::result::Result<MmapRegion, KernelBundleLoadError>
+    where
+        F: FnOnce(&mut [u8]) -> std::result::Result<(), KernelBundleError>,
+    {
+        let region = MmapRegion::new(self.size).map_err(KernelBundleLoadError::Mmap)?;
+        // Safe because the region was just mapped, `size` bytes long, and nothing else holds it.
+        let data = unsafe { slice::from_raw_parts_mut(region.as_ptr(), self.size) };
+        fill(data).map_err(KernelBundleLoadError::Bundle)?;
+        Ok(region)
+    }
+}
+
+/// Errors associated with loading the kernel bundle as the microVM is built.
+#[derive(Debug)]
+pub enum KernelBundleLoadError {
+    /// The kernel can't be read, or doesn't match its digest.
+    Bundle(KernelBundleError),
+    /// The region holding the kernel can't be mapped.
+    Mmap(MmapRegionError),
+}
+
+/// Kernel bundles registered under the name of their flavor, such as a general-purpose kernel and
+/// a minimal one, so each microVM can pick the kernel it boots, and where it's placed.
+#[derive(Clone, Debug, Default)]
+pub struct KernelFlavors {
+    flavors: HashMap<String, KernelBundle>,
+}
+
+impl KernelFlavors {
+    /// Registers `kernel_bundle` as the flavor `name`, replacing the bundle registered under it, if
+    /// any. The microVMs that already picked the flavor keep the bundle they picked.
+    pub fn register(
+        &mut self,
+        name: &str,
+        kernel_bundle: KernelBundle,
+    ) -> std::result::Result<(), KernelBundleError> {
+        if name.is_empty() {
+            return Err(KernelBundleError::InvalidFlavorName);
+        }
+        self.flavors.insert(name.to_string(), kernel_bundle);
+        Ok(())
+    }
+
+    /// Returns the bundle registered as the flavor `name`, if any.
+    pub fn get(&self, name: &str) -> Option<KernelBundle> {
+        self.flavors.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_kernel_bundle() {
+        let page_size = page_size();
+        let kernel: Vec<u8> = (0..2 * page_size).map(|i| i as u8).collect();
+        let digest = sha256::digest(&kernel);
+
+        assert!(matches!(
+            KernelBundle::from_buffer(&kernel[1..], 0, None),
+            Err(KernelBundleError::InvalidSize)
+        ));
+        assert!(matches!(
+            KernelBundle::from_buffer(&kernel, 0x10, None),
+            Err(KernelBundleError::InvalidGuestAddress)
+        ));
+        assert!(matches!(
+            KernelBundle::from_buffer(&kernel, 0, Some([0; DIGEST_SIZE])),
+            Err(KernelBundleError::DigestMismatch)
+        ));
+        let bundle = KernelBundle::from_buffer(&kernel, 0, Some(digest)).unwrap();
+        let region = bundle.load().unwrap();
+        assert_eq!(region.size(), kernel.len());
+        assert_eq!(
+            unsafe { slice::from_raw_parts(region.as_ptr(), region.size()) },
+            &kernel[..]
+        );
+
+        let mut file = TempFile::new().unwrap().into_file();
+        file.write_all(&[0xff; 3]).unwrap();
+        file.write_all(&kernel).unwrap();
+        assert!(matches!(
+            KernelBundle::from_file(file.try_clone().unwrap(), 4, 0, kernel.len(), None),
+            Err(KernelBundleError::FileTooShort)
+        ));
+        let bundle =
+            KernelBundle::from_file(file.try_clone().unwrap(), 3, 0, kernel.len(), Some(digest))
+                .unwrap();
+        let region = bundle.load().unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(region.as_ptr(), region.size()) },
+            &kernel[..]
+        );
+        // The file is checked as it's read, not as the bundle is set.
+        file.write_all_at(&[0], 3).unwrap();
+        assert!(matches!(
+            bundle.load(),
+            Err(KernelBundleLoadError::Bundle(
+                KernelBundleError::DigestMismatch
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_kernel_flavors() {
+        let page_size = page_size();
+        let general = vec![1u8; 2 * page_size];
+        let minimal = vec![2u8; page_size];
+        let mut flavors = KernelFlavors::default();
+
+        assert!(matches!(
+            flavors.register(
+                "",
+                KernelBundle::from_buffer(&general, 0x1000, None).unwrap()
+            ),
+            Err(KernelBundleError::InvalidFlavorName)
+        ));
+        flavors
+            .register(
+                "general",
+                KernelBundle::from_buffer(&general, 0x1000, None).unwrap(),
+            )
+            .unwrap();
+        flavors
+            .register(
+                "minimal",
+                KernelBundle::from_buffer(&minimal, 0x20_0000, None).unwrap(),
+            )
+            .unwrap();
+        assert!(flavors.get("sev").is_none());
+
+        // Each flavor keeps its own guest address.
+        let bundle = flavors.get("general").unwrap();
+        assert_eq!((bundle.guest_addr, bundle.size), (0x1000, general.len()));
+        let bundle = flavors.get("minimal").unwrap();
+        assert_eq!((bundle.guest_addr, bundle.size), (0x20_0000, minimal.len()));
+        let region = bundle.load().unwrap();
+        assert_eq!(
+            unsafe { slice::from_raw_parts(region.as_ptr(), region.size()) },
+            &minimal[..]
+        );
+
+        // Registering a flavor again replaces it for the microVMs picking it from then on.
+        flavors
+            .register(
+                "minimal",
+                KernelBundle::from_buffer(&general, 0x20_0000, None).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(flavors.get("minimal").unwrap().size, general.len());
+        assert_eq!(bundle.size, minimal.len());
     }
 }
@@ -0,0 +1,168 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use utils::tempdir::TempDir;
+use vmm_config::fs::{FsConfigError, FsDeviceConfig};
+
+/// The tag of the virtio-fs share holding the staged modules. The init process of the guest
+/// mounts it, loads the modules listed in `MODULES_ORDER_FILE`, and unmounts it before executing
+/// the workload.
+pub const KERNEL_MODULES_FS_TAG: &str = "krun-modules";
+
+/// Lists the staged modules, one per line, in load order, each followed by its parameters.
+const MODULES_ORDER_FILE: &str = "modules.order";
+
+/// Errors associated with the kernel modules configuration.
+#[derive(Debug)]
+pub enum KernelModulesError {
+    /// Unable to create the directory holding the staged modules.
+    CreateStagingDir(utils::errno::Error),
+    /// Unable to create the share exposing the staged modules.
+    FsDevice(FsConfigError),
+    /// The module parameters span more than a line.
+    InvalidParams(String),
+    /// The module isn't a regular file.
+    InvalidModule(PathBuf),
+    /// Unable to copy the module to the staging directory.
+    StageModule(PathBuf, io::Error),
+}
+
+impl fmt::Display for KernelModulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KernelModulesError::*;
+        match self {
+            CreateStagingDir(e) => {
+                write!(f, "Unable to create the module staging directory: {}", e)
+            }
+            FsDevice(e) => write!(f, "Unable to share the kernel modules: {}", e),
+            InvalidParams(params) => write!(f, "Invalid module parameters: {:?}", params),
+            InvalidModule(path) => write!(f, "Invalid kernel module: {}", path.display()),
+            StageModule(path, e) => {
+                write!(f, "Unable to stage kernel module {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KernelModulesError {}
+
+type Result<T> = std::result::Result<T, KernelModulesError>;
+
+/// Extra kernel modules, staged in a private host directory to be loaded by the guest, in the
+/// order they were added. The directory is removed when dropped.
+pub struct KernelModules {
+    dir: TempDir,
+    order: String,
+    count: usize,
+}
+
+impl KernelModules {
+    pub fn new() -> Result<Self> {
+        let dir = TempDir::new_with_prefix(env::temp_dir().join("krun-modules-"))
+            .map_err(KernelModulesError::CreateStagingDir)?;
+        Ok(KernelModules {
+            dir,
+            order: String::new(),
+            count: 0,
+        })
+    }
+
+    /// Stages a copy of the module at `path`, to be loaded with `params`.
+    pub fn add(&mut self, path: &Path, params: &str) -> Result<()> {
+        if params.contains('\n') {
+            return Err(KernelModulesError::InvalidParams(params.to_string()));
+        }
+        let file_name = match path.file_name() {
+            Some(file_name) if path.is_file() => file_name.to_string_lossy(),
+            _ => return Err(KernelModulesError::InvalidModule(path.to_path_buf())),
+        };
+
+        // Prefixed with their position, so modules with the same name don't clash.
+        let staged_name = format!("{:03}-{}", self.count, file_name);
+        fs::copy(path, self.path().join(&staged_name))
+            .map_err(|e| KernelModulesError::StageModule(path.to_path_buf(), e))?;
+
+        let mut order = self.order.clone();
+        order.push_str(&format!("{} {}\n", staged_name, params));
+        fs::write(self.path().join(MODULES_ORDER_FILE), &order)
+            .map_err(|e| KernelModulesError::StageModule(path.to_path_buf(), e))?;
+
+        self.order = order;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Returns the configuration of the share exposing the staged modules to the guest.
+    pub fn fs_config(&self) -> FsDeviceConfig {
+        FsDeviceConfig {
+            fs_id: KERNEL_MODULES_FS_TAG.to_string(),
+            shared_dir: self.path().to_string_lossy().into_owned(),
+            mapped_volumes: None,
+        }
+    }
+}
+
+impl fmt::Debug for KernelModules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KernelModules {{ dir: {:?}, count: {} }}",
+            self.path(),
+            self.count
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_stage_modules() {
+        let module = TempFile::new().unwrap();
+        let mut modules = KernelModules::new().unwrap();
+        let module_name = module
+            .as_path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+
+        modules.add(module.as_path(), "").unwrap();
+        modules.add(module.as_path(), "debug=1 quiet").unwrap();
+        assert!(modules
+            .path()
+            .join(format!("000-{}", module_name))
+            .is_file());
+        assert!(modules
+            .path()
+            .join(format!("001-{}", module_name))
+            .is_file());
+        assert_eq!(
+            fs::read_to_string(modules.path().join(MODULES_ORDER_FILE)).unwrap(),
+            format!("000-{} \n001-{} debug=1 quiet\n", module_name, module_name)
+        );
+
+        assert!(matches!(
+            modules.add(module.as_path(), "a=1\nb=2"),
+            Err(KernelModulesError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            modules.add(Path::new("/nonexistent/module.ko"), ""),
+            Err(KernelModulesError::InvalidModule(_))
+        ));
+        let dir = modules.path().to_path_buf();
+        assert!(matches!(
+            modules.add(&dir, ""),
+            Err(KernelModulesError::InvalidModule(_))
+        ));
+    }
+}
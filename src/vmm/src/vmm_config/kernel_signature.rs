@@ -0,0 +1,140 @@
+// Copyright 2021 Red Hat, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+
+use utils::ed25519::PublicKey;
+pub use utils::ed25519::{PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+
+/// Errors associated with checking the signature of the kernel bundle.
+#[derive(Debug, PartialEq)]
+pub enum KernelSignatureError {
+    /// A trusted key isn't a valid Ed25519 public key.
+    InvalidKey,
+    /// Trusted keys were set, but not the signature of the kernel.
+    MissingSignature,
+    /// The signature of the kernel was set, but no key to check it against.
+    NoTrustedKeys,
+    /// The signature doesn't match the kernel under any of the trusted keys.
+    Untrusted,
+}
+
+impl Display for KernelSignatureError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::KernelSignatureError::*;
+        match *self {
+            InvalidKey => write!(f, "The trusted key isn't a valid Ed25519 public key"),
+            MissingSignature => write!(f, "The kernel must be signed, but no signature was set"),
+            NoTrustedKeys => write!(f, "No trusted key to check the signature of the kernel"),
+            Untrusted => write!(f, "The kernel isn't signed by any of the trusted keys"),
+        }
+    }
+}
+
+impl std::error::Error for KernelSignatureError {}
+
+/// The Ed25519 keys the kernel bundle must be signed with, and its detached signature. The
+/// signature is checked as the microVM is built, once the kernel is loaded and before it's placed
+/// in the guest memory. Nothing is checked until a key or the signature is set.
+#[derive(Clone, Debug, Default)]
+pub struct KernelSignatureConfig {
+    trusted_keys: Vec<PublicKey>,
+    signature: Option<[u8; SIGNATURE_SIZE]>,
+}
+
+impl KernelSignatureConfig {
+    /// Adds a key the kernel may be signed with.
+    pub fn add_trusted_key(
+        &mut self,
+        key: &[u8; PUBLIC_KEY_SIZE],
+    ) -> std::result::Result<(), KernelSignatureError> {
+        let key = PublicKey::from_bytes(key).ok_or(KernelSignatureError::InvalidKey)?;
+        if !self
+            .trusted_keys
+            .iter()
+            .any(|k| k.as_bytes() == key.as_bytes())
+        {
+            self.trusted_keys.push(key);
+        }
+        Ok(())
+    }
+
+    /// Sets the detached signature of the kernel.
+    pub fn set_signature(&mut self, signature: [u8; SIGNATURE_SIZE]) {
+        self.signature = Some(signature);
+    }
+
+    /// Returns whether the kernel has to be checked.
+    pub fn is_enforced(&self) -> bool {
+        !self.trusted_keys.is_empty() || self.signature.is_some()
+    }
+
+    /// Checks `kernel` is signed by one of the trusted keys.
+    pub fn verify(&self, kernel: &[u8]) -> std::result::Result<(), KernelSignatureError> {
+        if self.trusted_keys.is_empty() {
+            return Err(KernelSignatureError::NoTrustedKeys);
+        }
+        let signature = self
+            .signature
+            .as_ref()
+            .ok_or(KernelSignatureError::MissingSignature)?;
+        if self
+            .trusted_keys
+            .iter()
+            .any(|key| key.verify(kernel, signature))
+        {
+            Ok(())
+        } else {
+            Err(KernelSignatureError::Untrusted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The test vector 2 of RFC 8032.
+    const KEY: [u8; PUBLIC_KEY_SIZE] = [
+        0x3d, 0x40, 0x17, 0xc3, 0xe8, 0x43, 0x89, 0x5a, 0x92, 0xb7, 0x0a, 0xa7, 0x4d, 0x1b, 0x7e,
+        0xbc, 0x9c, 0x98, 0x2c, 0xcf, 0x2e, 0xc4, 0x96, 0x8c, 0xc0, 0xcd, 0x55, 0xf1, 0x2a, 0xf4,
+        0x66, 0x0c,
+    ];
+    const SIGNATURE: [u8; SIGNATURE_SIZE] = [
+        0x92, 0xa0, 0x09, 0xa9, 0xf0, 0xd4, 0xca, 0xb8, 0x72, 0x0e, 0x82, 0x0b, 0x5f, 0x64, 0x25,
+        0x40, 0xa2, 0xb2, 0x7b, 0x54, 0x16, 0x50, 0x3f, 0x8f, 0xb3, 0x76, 0x22, 0x23, 0xeb, 0xdb,
+        0x69, 0xda, 0x08, 0x5a, 0xc1, 0xe4, 0x3e, 0x15, 0x99, 0x6e, 0x45, 0x8f, 0x36, 0x13, 0xd0,
+        0xf1, 0x1d, 0x8c, 0x38, 0x7b, 0x2e, 0xae, 0xb4, 0x30, 0x2a, 0xee, 0xb0, 0x0d, 0x29, 0x16,
+        0x12, 0xbb, 0x0c, 0x00,
+    ];
+
+    #[test]
+    fn test_kernel_signature() {
+        let mut config = KernelSignatureConfig::default();
+        assert!(!config.is_enforced());
+
+        config.set_signature(SIGNATURE);
+        assert!(config.is_enforced());
+        assert_eq!(
+            config.verify(&[0x72]),
+            Err(KernelSignatureError::NoTrustedKeys)
+        );
+
+        let mut invalid_key = [0u8; PUBLIC_KEY_SIZE];
+        invalid_key[0] = 2;
+        assert_eq!(
+            config.add_trusted_key(&invalid_key),
+            Err(KernelSignatureError::InvalidKey)
+        );
+        config.add_trusted_key(&KEY).unwrap();
+        assert!(config.verify(&[0x72]).is_ok());
+        assert_eq!(config.verify(&[0x73]), Err(KernelSignatureError::Untrusted));
+
+        let mut config = KernelSignatureConfig::default();
+        config.add_trusted_key(&KEY).unwrap();
+        assert_eq!(
+            config.verify(&[0x72]),
+            Err(KernelSignatureError::MissingSignature)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+
+/// Errors associated with the KVM tuning configuration.
+#[derive(Debug, PartialEq)]
+pub enum KvmTuningConfigError {
+    /// The number of entries of the dirty ring isn't a power of two.
+    InvalidDirtyRingEntries(u32),
+}
+
+impl fmt::Display for KvmTuningConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KvmTuningConfigError::*;
+        match *self {
+            InvalidDirtyRingEntries(entries) => write!(
+                f,
+                "The dirty ring must have a power of two of entries, not {}",
+                entries
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KvmTuningConfigError {}
+
+/// How the regions of the guest memory are packed into the memory slots of KVM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemslotPacking {
+    /// The regions contiguous in both the guest and the host share a slot, so large guests
+    /// don't exhaust the slots of the host.
+    Coalesce,
+    /// Every region gets a slot of its own.
+    PerRegion,
+}
+
+impl Default for MemslotPacking {
+    fn default() -> Self {
+        MemslotPacking::Coalesce
+    }
+}
+
+/// The legacy timers offered to an x86_64 guest, on top of the timer of the local APIC and
+/// kvmclock, which the guest always has.
+///
+/// There's no HPET either way, as the guest only finds one through ACPI, which the microVM
+/// doesn't have.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerPolicy {
+    /// An in-kernel model of the PIT, with a stub of the PC speaker port.
+    Pit,
+    /// No legacy timer, sparing the guest from calibrating and then dropping the PIT, for
+    /// kernels that use the timer of the local APIC from the start.
+    None,
+}
+
+impl Default for TimerPolicy {
+    fn default() -> Self {
+        TimerPolicy::Pit
+    }
+}
+
+/// Tunings of KVM for the microVM, most lowering the tail latency of the guest, for
+/// real-time-ish workloads, at the expense of the host. Whatever isn't set keeps the default of
+/// the host.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KvmTuningConfig {
+    /// The entries of the dirty ring of each vCPU, used in place of the dirty bitmap, so tracking
+    /// the dirty pages doesn't walk the whole memory.
+    pub dirty_ring_entries: Option<u32>,
+    /// How long, in ns, a halted vCPU polls for a wakeup before yielding its thread, overriding
+    /// the halt_poll_ns parameter of the kvm module. Zero disables the polling.
+    pub halt_poll_ns: Option<u32>,
+    /// Whether to fault in and lock the memory of the guest in the RAM of the host, so it's never
+    /// faulted in lazily nor swapped out.
+    pub mlock: bool,
+    /// How the regions of the guest memory are packed into the memory slots of KVM.
+    pub memslot_packing: MemslotPacking,
+    /// The legacy timers offered to the guest, on x86_64.
+    pub timer_policy: TimerPolicy,
+}
+
+impl KvmTuningConfig {
+    pub fn validate(&self) -> std::result::Result<(), KvmTuningConfigError> {
+        match self.dirty_ring_entries {
+            Some(entries) if !entries.is_power_of_two() => {
+                Err(KvmTuningConfigError::InvalidDirtyRingEntries(entries))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(KvmTuningConfig::default().validate().is_ok());
+
+        let tuning = KvmTuningConfig {
+            dirty_ring_entries: Some(4096),
+            halt_poll_ns: Some(0),
+            mlock: true,
+            memslot_packing: MemslotPacking::PerRegion,
+            timer_policy: TimerPolicy::None,
+        };
+        assert!(tuning.validate().is_ok());
+
+        for entries in &[0, 1000] {
+            let tuning = KvmTuningConfig {
+                dirty_ring_entries: Some(*entries),
+                ..Default::default()
+            };
+            assert_eq!(
+                tuning.validate(),
+                Err(KvmTuningConfigError::InvalidDirtyRingEntries(*entries))
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::kvm_tuning::KvmTuningConfig;
+
+/// How long, in ns, a halted vCPU polls for a wakeup with the low-latency profile, unless the
+/// tunings of KVM set it otherwise.
+pub const LOW_LATENCY_HALT_POLL_NS: u32 = 200_000;
+
+/// The trade-off between the wakeup latency of the guest and the CPU time it consumes in the host
+/// while idle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LatencyProfile {
+    /// The vCPUs of an idle guest halt, yielding their threads.
+    Default,
+    /// The vCPUs of an idle guest keep polling for a wakeup, so they resume without waiting to be
+    /// scheduled back, at the cost of consuming host CPU time while idle, reported in the
+    /// `krun_vcpu_cpu_time_us` metric. On x86_64 the guest polls in its idle loop, with the
+    /// `idle=poll` option, so it doesn't halt at all, and elsewhere KVM polls for a while on its
+    /// behalf once halted.
+    LowLatency,
+}
+
+impl Default for LatencyProfile {
+    fn default() -> Self {
+        LatencyProfile::Default
+    }
+}
+
+impl LatencyProfile {
+    /// Returns `tuning`, completed with the tunings of KVM of the profile, if `tuning` doesn't
+    /// set them already. Unlike an explicit tuning, the halt polling of the profile is left out
+    /// on the hosts not supporting it, rather than failing the build of the microVM.
+    pub fn kvm_tuning(
+        self,
+        tuning: &KvmTuningConfig,
+        halt_poll_supported: bool,
+    ) -> KvmTuningConfig {
+        let mut tuning = *tuning;
+        if self == LatencyProfile::LowLatency && tuning.halt_poll_ns.is_none() {
+            if halt_poll_supported {
+                tuning.halt_poll_ns = Some(LOW_LATENCY_HALT_POLL_NS);
+            } else {
+                warn!("KVM doesn't support tuning the halt polling, leaving it to the host");
+            }
+        }
+        tuning
+    }
+
+    /// Returns the option of the guest kernel selecting its idle loop for the profile, as a key
+    /// and value, unless `cmdline` selects one already.
+    pub fn guest_idle_option(self, cmdline: &str) -> Option<(&'static str, &'static str)> {
+        if self != LatencyProfile::LowLatency || !cfg!(target_arch = "x86_64") {
+            return None;
+        }
+        if cmdline
+            .split_whitespace()
+            .any(|option| option.starts_with("idle="))
+        {
+            return None;
+        }
+        Some(("idle", "poll"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kvm_tuning() {
+        let tuning = KvmTuningConfig::default();
+        assert_eq!(LatencyProfile::Default.kvm_tuning(&tuning, true), tuning);
+        assert_eq!(
+            LatencyProfile::LowLatency
+                .kvm_tuning(&tuning, true)
+                .halt_poll_ns,
+            Some(LOW_LATENCY_HALT_POLL_NS)
+        );
+        assert_eq!(
+            LatencyProfile::LowLatency.kvm_tuning(&tuning, false),
+            tuning
+        );
+
+        // An explicit tuning wins.
+        let tuning = KvmTuningConfig {
+            halt_poll_ns: Some(0),
+            mlock: true,
+            ..Default::default()
+        };
+        assert_eq!(LatencyProfile::LowLatency.kvm_tuning(&tuning, true), tuning);
+    }
+
+    #[test]
+    fn test_guest_idle_option() {
+        assert_eq!(LatencyProfile::Default.guest_idle_option("quiet"), None);
+        assert_eq!(
+            LatencyProfile::LowLatency.guest_idle_option("quiet idle=halt"),
+            None
+        );
+        if cfg!(target_arch = "x86_64") {
+            assert_eq!(
+                LatencyProfile::LowLatency.guest_idle_option("quiet noidle=1"),
+                Some(("idle", "poll"))
+            );
+        } else {
+            assert_eq!(LatencyProfile::LowLatency.guest_idle_option("quiet"), None);
+        }
+    }
+}
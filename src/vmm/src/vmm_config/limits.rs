@@ -0,0 +1,217 @@
+use std::fmt;
+
+/// The resources of setrlimit(2), indexed by their value in the guest, which is always Linux.
+const RLIMIT_RESOURCES: &[&str] = &[
+    "CPU",
+    "FSIZE",
+    "DATA",
+    "STACK",
+    "CORE",
+    "RSS",
+    "NPROC",
+    "NOFILE",
+    "MEMLOCK",
+    "AS",
+    "LOCKS",
+    "SIGPENDING",
+    "MSGQUEUE",
+    "NICE",
+    "RTPRIO",
+    "RTTIME",
+];
+
+/// The value of RLIM_INFINITY in the guest.
+const RLIM_INFINITY: u64 = u64::MAX;
+
+/// The cgroup v2 controllers whose limits can be applied to the workload.
+const CGROUP_CONTROLLERS: &[&str] = &["cpu", "cpuset", "hugetlb", "io", "memory", "pids"];
+
+/// Errors associated with the limits of the workload.
+#[derive(Debug, PartialEq)]
+pub enum LimitsError {
+    /// The cgroup limit isn't in the "controller.file=value" form, or isn't of a supported
+    /// controller.
+    InvalidCgroupLimit(String),
+    /// The rlimit isn't in the "RESOURCE=RLIM_CUR:RLIM_MAX" form, or its soft limit is above its
+    /// hard limit.
+    InvalidRlimit(String),
+}
+
+impl fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LimitsError::*;
+        match self {
+            InvalidCgroupLimit(limit) => write!(f, "Invalid cgroup limit: {}", limit),
+            InvalidRlimit(rlimit) => write!(f, "Invalid rlimit: {}", rlimit),
+        }
+    }
+}
+
+impl std::error::Error for LimitsError {}
+
+fn parse_rlim(value: &str) -> Option<u64> {
+    match value {
+        "unlimited" | "infinity" => Some(RLIM_INFINITY),
+        _ => value.parse().ok(),
+    }
+}
+
+/// A resource limit of the workload, set by the init process of the guest before executing it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rlimit {
+    /// The resource, as numbered by Linux, like 7 for RLIMIT_NOFILE.
+    pub resource: u32,
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl Rlimit {
+    /// Parses an rlimit in the "RESOURCE=RLIM_CUR:RLIM_MAX" form. The resource is either its
+    /// number or its name, with or without the "RLIMIT_" prefix, like "NOFILE". The limits are
+    /// either numbers or "unlimited", and a single limit sets both.
+    pub fn parse(rlimit: &str) -> Result<Self, LimitsError> {
+        let invalid = || LimitsError::InvalidRlimit(rlimit.to_string());
+
+        let mut parts = rlimit.splitn(2, '=');
+        let (resource, limits) = match (parts.next(), parts.next()) {
+            (Some(resource), Some(limits)) => (resource.trim(), limits.trim()),
+            _ => return Err(invalid()),
+        };
+
+        let name = resource.to_ascii_uppercase();
+        let name = name.trim_start_matches("RLIMIT_");
+        let resource = match RLIMIT_RESOURCES.iter().position(|r| *r == name) {
+            Some(resource) => resource as u32,
+            None => match resource.parse::<u32>() {
+                Ok(resource) if (resource as usize) < RLIMIT_RESOURCES.len() => resource,
+                _ => return Err(invalid()),
+            },
+        };
+
+        let mut limits = limits.splitn(2, ':');
+        let cur = limits.next().and_then(parse_rlim).ok_or_else(invalid)?;
+        let max = match limits.next() {
+            Some(max) => parse_rlim(max).ok_or_else(invalid)?,
+            None => cur,
+        };
+        if cur > max {
+            return Err(invalid());
+        }
+
+        Ok(Rlimit { resource, cur, max })
+    }
+}
+
+impl fmt::Display for Rlimit {
+    /// Formats the rlimit as expected by the init process of the guest.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}:{}", self.resource, self.cur, self.max)
+    }
+}
+
+/// A limit of the cgroup the workload is moved to by the init process of the guest, written to
+/// one of the interface files of a cgroup v2 controller.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgroupLimit {
+    /// The interface file, like "memory.max".
+    pub file: String,
+    pub value: String,
+}
+
+impl CgroupLimit {
+    /// Parses a limit in the "controller.file=value" form, like "pids.max=512".
+    pub fn parse(limit: &str) -> Result<Self, LimitsError> {
+        let invalid = || LimitsError::InvalidCgroupLimit(limit.to_string());
+
+        let mut parts = limit.splitn(2, '=');
+        let (file, value) = match (parts.next(), parts.next()) {
+            (Some(file), Some(value)) => (file.trim(), value.trim()),
+            _ => return Err(invalid()),
+        };
+
+        let mut components = file.splitn(2, '.');
+        let valid_file = match (components.next(), components.next()) {
+            (Some(controller), Some(name)) => {
+                CGROUP_CONTROLLERS.contains(&controller)
+                    && !name.is_empty()
+                    && !name.starts_with('.')
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+            }
+            _ => false,
+        };
+        // The limits are passed to the guest as a quoted, comma-separated list.
+        let valid_value =
+            !value.is_empty() && !value.contains(|c: char| c == ',' || c == '"' || c.is_control());
+        if !valid_file || !valid_value {
+            return Err(invalid());
+        }
+
+        Ok(CgroupLimit {
+            file: file.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for CgroupLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.file, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rlimit() {
+        let nofile = Rlimit {
+            resource: 7,
+            cur: 1024,
+            max: 4096,
+        };
+        assert_eq!(Rlimit::parse("7=1024:4096").unwrap(), nofile);
+        assert_eq!(Rlimit::parse("NOFILE=1024:4096").unwrap(), nofile);
+        assert_eq!(Rlimit::parse("rlimit_nofile = 1024:4096").unwrap(), nofile);
+        assert_eq!(nofile.to_string(), "7=1024:4096");
+
+        let nproc = Rlimit::parse("RLIMIT_NPROC=unlimited").unwrap();
+        assert_eq!(nproc.resource, 6);
+        assert_eq!(nproc.cur, RLIM_INFINITY);
+        assert_eq!(nproc.max, RLIM_INFINITY);
+
+        for rlimit in &["NOFILE", "FOO=1:2", "16=1:2", "NOFILE=a:b", "NOFILE=2:1"] {
+            assert_eq!(
+                Rlimit::parse(rlimit),
+                Err(LimitsError::InvalidRlimit(rlimit.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_cgroup_limit() {
+        let limit = CgroupLimit::parse("memory.max=512M").unwrap();
+        assert_eq!(limit.file, "memory.max");
+        assert_eq!(limit.value, "512M");
+        assert!(CgroupLimit::parse("cpu.max=50000 100000").is_ok());
+        assert!(CgroupLimit::parse("memory.swap.max=0").is_ok());
+
+        for limit in &[
+            "memory.max",
+            "memory=1",
+            "foo.max=1",
+            "memory.=1",
+            "memory..max=1",
+            "memory.max=",
+            "memory./max=1",
+            "pids.max=1,2",
+        ] {
+            assert_eq!(
+                CgroupLimit::parse(limit),
+                Err(LimitsError::InvalidCgroupLimit(limit.to_string()))
+            );
+        }
+    }
+}
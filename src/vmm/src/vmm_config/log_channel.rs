@@ -0,0 +1,29 @@
+use std::fmt;
+use std::sync::Arc;
+
+pub use devices::virtio::{write_log_record, LOG_RECORD_MAX_SIZE};
+
+/// The device, as seen by the guest, its init process forwards the records sent to `/dev/log`
+/// through. It's the second console, attached right after the interactive one.
+pub const LOG_CHANNEL_GUEST_DEVICE: &str = "/dev/hvc1";
+
+/// Callback invoked with each record forwarded by the guest, from the thread of the event loop.
+pub type LogRecordCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// A console dedicated to the logs of the guest, keeping them apart from the interactive console.
+#[derive(Clone)]
+pub struct LogChannelConfig {
+    pub callback: LogRecordCallback,
+}
+
+impl LogChannelConfig {
+    pub fn new(callback: LogRecordCallback) -> Self {
+        LogChannelConfig { callback }
+    }
+}
+
+impl fmt::Debug for LogChannelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LogChannelConfig")
+    }
+}
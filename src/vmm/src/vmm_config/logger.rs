@@ -106,6 +106,8 @@ impl Display for LoggerConfigError {
     }
 }
 
+impl std::error::Error for LoggerConfigError {}
+
 /// Configures the logger as described in `logger_cfg`.
 pub fn init_logger(
     logger_cfg: LoggerConfig,
@@ -3,6 +3,8 @@
 
 use std::fmt;
 
+pub use arch::CpuTopology;
+
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
@@ -15,6 +17,8 @@ pub enum VmConfigError {
     InvalidVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
+    /// The CPU topology is invalid, or doesn't match the vcpu count or hyperthreading.
+    InvalidCpuTopology,
 }
 
 impl fmt::Display for VmConfigError {
@@ -27,10 +31,18 @@ impl fmt::Display for VmConfigError {
                  be 1 or an even number when hyperthreading is enabled.",
             ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
+            InvalidCpuTopology => write!(
+                f,
+                "The CPU topology is invalid! A core can only have 1 or 2 threads, there can \
+                 only be several sockets with a power of 2 of cores each, and the vCPU number \
+                 and hyperthreading must match it.",
+            ),
         }
     }
 }
 
+impl std::error::Error for VmConfigError {}
+
 /// Strongly typed structure that represents the configuration of the
 /// microvm.
 #[derive(Clone, Debug, PartialEq)]
@@ -41,6 +53,9 @@ pub struct VmConfig {
     pub mem_size_mib: Option<usize>,
     /// Enables or disabled hyperthreading.
     pub ht_enabled: Option<bool>,
+    /// How the vCPUs are laid out in sockets, cores and threads. All the vCPUs are in a single
+    /// socket if not set.
+    pub topology: Option<CpuTopology>,
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     pub cpu_template: Option<CpuFeaturesTemplate>,
 }
@@ -51,6 +66,7 @@ impl Default for VmConfig {
             vcpu_count: Some(1),
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
+            topology: None,
             cpu_template: None,
         }
     }
@@ -107,5 +123,10 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "The CPU topology is invalid! A core can only have 1 or 2 threads, \
+                            there can only be several sockets with a power of 2 of cores each, \
+                            and the vCPU number and hyperthreading must match it.";
+        assert_eq!(VmConfigError::InvalidCpuTopology.to_string(), expected_str);
     }
 }
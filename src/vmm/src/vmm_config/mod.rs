@@ -10,18 +10,102 @@ use libc::O_NONBLOCK;
 
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for the timeline of the boot of the microVM.
+pub mod boot_timeline;
+/// Wrapper for configuring the CA certificates trusted by the microVM.
+pub mod ca_certs;
+/// Wrapper for configuring the clipboard shared between the host and the guest.
+pub mod clipboard;
+/// Wrapper for configuring the cloud-init seed exposed to the microVM.
+pub mod cloud_init;
+/// Wrapper for configuring the console device attached to the microVM.
+pub mod console;
+/// Wrapper for configuring the embedder-provided devices attached to the microVM.
+pub mod custom_device;
+/// Wrapper for configuring what happens when a device of the microVM panics.
+pub mod device_panic;
+/// Wrapper for configuring the MMIO window and the IRQs of the devices of the microVM.
+pub mod device_window;
+/// Wrapper for resizing the disks of the block devices while the microVM runs.
+pub mod disk;
+/// Wrapper for configuring the capture of the guest early console output.
+pub mod earlycon;
+/// Wrapper for configuring the identity of the microVM and the overrides of its "/etc".
+pub mod etc;
+/// Wrapper for configuring how the guest executes the workload and the services alongside it.
+pub mod exec;
+/// Wrapper for the handle the exit of the microVM is signaled on.
+pub mod exit;
+/// Wrapper for configuring the file descriptors the devices can hold.
+pub mod fd_budget;
 /// Wrapper for configuring the Fs devices attached to the microVM.
 pub mod fs;
+/// Wrapper for freezing the filesystems of the guest while the host snapshots them.
+pub mod fsfreeze;
+/// Wrapper for querying the network configuration of the guest.
+pub mod guest_network;
+/// Wrapper for configuring how the panics of the guest kernel are detected and reported.
+pub mod guest_panic;
+/// Wrapper for configuring the freezing of the microVM while it's idle.
+pub mod idle;
 /// Wrapper over the microVM general information attached to the microVM.
 pub mod instance_info;
 /// Wrapper for configuring the kernel bundle to be loaded in the microVM.
 pub mod kernel_bundle;
+/// Wrapper for configuring the extra kernel modules loaded by the microVM.
+pub mod kernel_modules;
+/// Wrapper for configuring the keys the kernel bundle must be signed with.
+pub mod kernel_signature;
+/// Wrapper for configuring the tunings of KVM for the microVM.
+pub mod kvm_tuning;
+/// Wrapper for configuring the trade-off between the wakeup latency and the idle CPU time.
+pub mod latency_profile;
+/// Wrapper for configuring the resource limits and the cgroup of the workload.
+pub mod limits;
+/// Wrapper for configuring the channel the guest forwards its logs through.
+pub mod log_channel;
 /// Wrapper for configuring the logger.
 pub mod logger;
 /// Wrapper for configuring the memory and CPU of the microVM.
 pub mod machine_config;
+/// Generic, versioned options for configuring the microVM by key.
+pub mod options;
+/// Wrapper for configuring the readiness and liveness probes of the workload.
+pub mod probes;
+/// Wrapper for configuring the profiling of the vCPUs from the host.
+pub mod profiling;
+/// Wrapper for configuring the pool of guest memory the devices are restricted to.
+pub mod restricted_dma;
+/// Wrapper for configuring the wall-clock limit on the runtime of the microVM.
+pub mod runtime_limit;
+/// Wrapper for configuring the secrets handed to the guest.
+pub mod secrets;
+/// Wrapper for configuring the character devices of the host passed through to the guest.
+pub mod serial_passthrough;
+/// Wrapper for configuring where the guest mounts the shares.
+pub mod shares;
+/// Wrapper for the listening sockets handed over by the embedder or the service manager.
+pub mod socket_activation;
+/// Wrapper for configuring the swap of the microVM.
+pub mod swap;
+/// Wrapper for configuring the kernel tunables of the microVM.
+pub mod sysctl;
+/// Wrapper for configuring the sizes of the tmpfs of the guest.
+pub mod tmpfs;
+/// Wrapper for configuring the TPM of the microVM.
+pub mod tpm;
+/// Wrapper for configuring the regions whose accesses by the guest are handed to the embedder.
+pub mod user_device;
+/// Wrapper for the lifecycle of the microVM, queryable while it runs.
+pub mod vm_state;
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
+/// Wrapper for configuring the watermarks reported on by the queues of the devices.
+pub mod watermarks;
+/// Wrapper for configuring the threads running the blocking requests of the devices.
+pub mod worker_pool;
+/// Wrapper for configuring the notification of the workload being started by the guest.
+pub mod workload;
 
 type Result<T> = std::result::Result<T, std::io::Error>;
 
@@ -0,0 +1,538 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+use resources::VmResources;
+use vmm_config::idle::IdlePolicyConfig;
+use vmm_config::latency_profile::LatencyProfile;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::runtime_limit::{RuntimeLimitConfig, DEFAULT_GRACE_PERIOD};
+use vmm_config::vsock::{validate_conn_config, VsockConnConfig};
+
+/// Version of the option registry. It's bumped whenever options are added, and each option records
+/// the version it was introduced in, so callers can tell which keys to expect.
+pub const OPTIONS_VERSION: u32 = 9;
+
+/// The type of the value of an option.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptionType {
+    /// "true" or "false".
+    Bool,
+    /// A decimal unsigned 32-bit integer.
+    U32,
+}
+
+/// A parsed option value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionValue {
+    Bool(bool),
+    U32(u32),
+}
+
+/// Errors associated with setting an option.
+#[derive(Debug, PartialEq)]
+pub enum OptionError {
+    /// The value can't be parsed as the type of the option.
+    InvalidType(String, OptionType),
+    /// The value is of the right type, but it isn't accepted by the option.
+    InvalidValue(String, String),
+    /// The key doesn't name a known option.
+    UnknownOption(String),
+}
+
+impl fmt::Display for OptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OptionError::*;
+        match self {
+            InvalidType(key, kind) => write!(f, "Option {} expects a {:?} value", key, kind),
+            InvalidValue(key, reason) => write!(f, "Invalid value for option {}: {}", key, reason),
+            UnknownOption(key) => write!(f, "Unknown option: {}", key),
+        }
+    }
+}
+
+impl std::error::Error for OptionError {}
+
+type ApplyFn = fn(&mut VmResources, OptionValue) -> Result<(), String>;
+
+/// A context option, settable by key.
+pub struct OptionSpec {
+    pub key: &'static str,
+    pub kind: OptionType,
+    /// The registry version the option was introduced in.
+    pub since: u32,
+    pub description: &'static str,
+    apply: ApplyFn,
+}
+
+impl OptionSpec {
+    /// Parses `value` as the type of this option.
+    pub fn parse(&self, value: &str) -> Result<OptionValue, OptionError> {
+        let parsed = match self.kind {
+            OptionType::Bool => match value {
+                "true" => Some(OptionValue::Bool(true)),
+                "false" => Some(OptionValue::Bool(false)),
+                _ => None,
+            },
+            OptionType::U32 => value.parse().ok().map(OptionValue::U32),
+        };
+        parsed.ok_or_else(|| OptionError::InvalidType(self.key.to_string(), self.kind))
+    }
+}
+
+impl fmt::Debug for OptionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OptionSpec")
+            .field("key", &self.key)
+            .field("kind", &self.kind)
+            .field("since", &self.since)
+            .finish()
+    }
+}
+
+fn as_bool(value: OptionValue) -> bool {
+    match value {
+        OptionValue::Bool(value) => value,
+        OptionValue::U32(value) => value != 0,
+    }
+}
+
+fn as_u32(value: OptionValue) -> u32 {
+    match value {
+        OptionValue::Bool(value) => value as u32,
+        OptionValue::U32(value) => value,
+    }
+}
+
+fn set_vcpus(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let vcpu_count = u8::try_from(as_u32(value)).map_err(|e| e.to_string())?;
+    vmr.set_vm_config(&VmConfig {
+        vcpu_count: Some(vcpu_count),
+        mem_size_mib: None,
+        ht_enabled: None,
+        topology: None,
+        cpu_template: None,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn set_mem_mib(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.set_vm_config(&VmConfig {
+        vcpu_count: None,
+        mem_size_mib: Some(as_u32(value) as usize),
+        ht_enabled: None,
+        topology: None,
+        cpu_template: None,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn set_ht(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.set_vm_config(&VmConfig {
+        vcpu_count: None,
+        mem_size_mib: None,
+        ht_enabled: Some(as_bool(value)),
+        topology: None,
+        cpu_template: None,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn set_max_runtime(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let max_runtime = Duration::from_secs(as_u32(value).into());
+    if max_runtime == Duration::from_secs(0) {
+        vmr.runtime_limit = None;
+        return Ok(());
+    }
+
+    let grace_period = vmr
+        .runtime_limit
+        .map_or(DEFAULT_GRACE_PERIOD, |limit| limit.grace_period);
+    let runtime_limit =
+        RuntimeLimitConfig::new(max_runtime, grace_period).map_err(|e| e.to_string())?;
+    vmr.set_runtime_limit(runtime_limit);
+    Ok(())
+}
+
+fn set_grace_period(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let runtime_limit = vmr
+        .runtime_limit
+        .as_mut()
+        .ok_or("runtime.max_secs must be set first")?;
+    runtime_limit.grace_period = Duration::from_secs(as_u32(value).into());
+    Ok(())
+}
+
+fn set_idle_interval(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let idle_interval = Duration::from_millis(as_u32(value).into());
+    if idle_interval == Duration::from_secs(0) {
+        vmr.idle_policy = None;
+        return Ok(());
+    }
+
+    let reclaim_memory = vmr
+        .idle_policy
+        .map_or(false, |policy| policy.reclaim_memory);
+    let idle_policy =
+        IdlePolicyConfig::new(idle_interval, reclaim_memory).map_err(|e| e.to_string())?;
+    vmr.set_idle_policy(idle_policy);
+    Ok(())
+}
+
+fn set_reclaim_memory(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let idle_policy = vmr
+        .idle_policy
+        .as_mut()
+        .ok_or("idle.interval_ms must be set first")?;
+    idle_policy.reclaim_memory = as_bool(value);
+    Ok(())
+}
+
+fn set_crypto(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.crypto = as_bool(value);
+    Ok(())
+}
+
+fn set_vsock_shm(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.vsock_shm = as_bool(value);
+    Ok(())
+}
+
+fn set_vsock_buf_size(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let vsock_conn = VsockConnConfig::with_buf_size(as_u32(value));
+    validate_conn_config(&vsock_conn).map_err(|e| e.to_string())?;
+    vmr.vsock_conn = vsock_conn;
+    Ok(())
+}
+
+fn set_vsock_credit_threshold(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    let vsock_conn = VsockConnConfig {
+        credit_update_threshold: as_u32(value),
+        ..vmr.vsock_conn
+    };
+    validate_conn_config(&vsock_conn).map_err(|e| e.to_string())?;
+    vmr.vsock_conn = vsock_conn;
+    Ok(())
+}
+
+fn size_mib(value: OptionValue) -> Option<u32> {
+    match as_u32(value) {
+        0 => None,
+        size => Some(size),
+    }
+}
+
+fn set_tmp_size(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.tmpfs.tmp_size_mib = size_mib(value);
+    Ok(())
+}
+
+fn set_shm_size(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.tmpfs.shm_size_mib = size_mib(value);
+    Ok(())
+}
+
+fn set_i8042(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.no_i8042 = !as_bool(value);
+    Ok(())
+}
+
+fn set_balloon(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.no_balloon = !as_bool(value);
+    Ok(())
+}
+
+fn set_fs_negative_timeout(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.fs_lookup_cache.negative_timeout = Duration::from_millis(as_u32(value).into());
+    Ok(())
+}
+
+fn set_fs_batch_lookups(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.fs_lookup_cache.batch_lookups = as_bool(value);
+    Ok(())
+}
+
+fn set_low_latency(vmr: &mut VmResources, value: OptionValue) -> Result<(), String> {
+    vmr.latency_profile = if as_bool(value) {
+        LatencyProfile::LowLatency
+    } else {
+        LatencyProfile::Default
+    };
+    Ok(())
+}
+
+/// All the supported options, in the order they were introduced.
+pub static OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        key: "machine.vcpus",
+        kind: OptionType::U32,
+        since: 1,
+        description: "Number of vCPUs",
+        apply: set_vcpus,
+    },
+    OptionSpec {
+        key: "machine.mem_mib",
+        kind: OptionType::U32,
+        since: 1,
+        description: "Amount of RAM, in MiB",
+        apply: set_mem_mib,
+    },
+    OptionSpec {
+        key: "machine.ht",
+        kind: OptionType::Bool,
+        since: 1,
+        description: "Whether to enable hyperthreading",
+        apply: set_ht,
+    },
+    OptionSpec {
+        key: "runtime.max_secs",
+        kind: OptionType::U32,
+        since: 1,
+        description: "Wall-clock limit on the runtime, in seconds, or 0 for no limit",
+        apply: set_max_runtime,
+    },
+    OptionSpec {
+        key: "runtime.grace_secs",
+        kind: OptionType::U32,
+        since: 1,
+        description: "Time given to the guest to shut down once the runtime limit is exceeded",
+        apply: set_grace_period,
+    },
+    OptionSpec {
+        key: "idle.interval_ms",
+        kind: OptionType::U32,
+        since: 1,
+        description: "Idle time, in milliseconds, before freezing the microVM, or 0 to disable",
+        apply: set_idle_interval,
+    },
+    OptionSpec {
+        key: "idle.reclaim_memory",
+        kind: OptionType::Bool,
+        since: 1,
+        description: "Whether to reclaim the memory of the frozen microVM",
+        apply: set_reclaim_memory,
+    },
+    OptionSpec {
+        key: "devices.crypto",
+        kind: OptionType::Bool,
+        since: 2,
+        description: "Whether to offload the symmetric ciphers of the guest to the host",
+        apply: set_crypto,
+    },
+    OptionSpec {
+        key: "vsock.shm",
+        kind: OptionType::Bool,
+        since: 3,
+        description: "Whether to offer the guest a shared-memory data path for vsock",
+        apply: set_vsock_shm,
+    },
+    OptionSpec {
+        key: "vsock.buf_size",
+        kind: OptionType::U32,
+        since: 4,
+        description: "Buffer size of each vsock connection, in bytes",
+        apply: set_vsock_buf_size,
+    },
+    OptionSpec {
+        key: "vsock.credit_threshold",
+        kind: OptionType::U32,
+        since: 4,
+        description: "Bytes forwarded to the host before the guest gets a vsock credit update",
+        apply: set_vsock_credit_threshold,
+    },
+    OptionSpec {
+        key: "guest.tmp_mib",
+        kind: OptionType::U32,
+        since: 5,
+        description: "Size of a tmpfs mounted at /tmp, in MiB, or 0 to leave /tmp to the root",
+        apply: set_tmp_size,
+    },
+    OptionSpec {
+        key: "guest.shm_mib",
+        kind: OptionType::U32,
+        since: 5,
+        description: "Size of /dev/shm, in MiB, or 0 for the default of half of the RAM",
+        apply: set_shm_size,
+    },
+    OptionSpec {
+        key: "devices.i8042",
+        kind: OptionType::Bool,
+        since: 6,
+        description: "Whether to emulate the i8042 PS/2 controller on x86_64",
+        apply: set_i8042,
+    },
+    OptionSpec {
+        key: "devices.balloon",
+        kind: OptionType::Bool,
+        since: 7,
+        description: "Whether to attach the balloon device reclaiming the free memory of the guest",
+        apply: set_balloon,
+    },
+    OptionSpec {
+        key: "fs.negative_timeout_ms",
+        kind: OptionType::U32,
+        since: 8,
+        description: "How long the guest caches the names missing from the fs devices, in ms",
+        apply: set_fs_negative_timeout,
+    },
+    OptionSpec {
+        key: "fs.batch_lookups",
+        kind: OptionType::Bool,
+        since: 8,
+        description: "Whether the guest looks up all the entries of the directories it lists",
+        apply: set_fs_batch_lookups,
+    },
+    OptionSpec {
+        key: "machine.low_latency",
+        kind: OptionType::Bool,
+        since: 9,
+        description: "Whether idle vCPUs poll for a wakeup, trading host CPU time for latency",
+        apply: set_low_latency,
+    },
+];
+
+/// Looks up the option named `key`.
+pub fn find_option(key: &str) -> Option<&'static OptionSpec> {
+    OPTIONS.iter().find(|option| option.key == key)
+}
+
+/// Parses `value` and applies it to the option named `key` of `vmr`.
+pub fn set_option(vmr: &mut VmResources, key: &str, value: &str) -> Result<(), OptionError> {
+    let option = find_option(key).ok_or_else(|| OptionError::UnknownOption(key.to_string()))?;
+    let value = option.parse(value)?;
+    (option.apply)(vmr, value).map_err(|reason| OptionError::InvalidValue(key.to_string(), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::virtio::LookupCacheConfig;
+    use vmm_config::tmpfs::TmpfsConfig;
+
+    #[test]
+    fn test_option_registry() {
+        for (index, option) in OPTIONS.iter().enumerate() {
+            assert!(option.since >= 1 && option.since <= OPTIONS_VERSION);
+            assert!(OPTIONS[..index].iter().all(|other| other.key != option.key));
+        }
+        assert_eq!(find_option("machine.vcpus").unwrap().kind, OptionType::U32);
+        assert!(find_option("machine.cpus").is_none());
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut vmr = VmResources::default();
+
+        set_option(&mut vmr, "machine.vcpus", "4").unwrap();
+        set_option(&mut vmr, "machine.mem_mib", "1024").unwrap();
+        assert_eq!(vmr.vm_config().vcpu_count, Some(4));
+        assert_eq!(vmr.vm_config().mem_size_mib, Some(1024));
+
+        assert_eq!(
+            set_option(&mut vmr, "machine.cpus", "4"),
+            Err(OptionError::UnknownOption("machine.cpus".to_string()))
+        );
+        assert_eq!(
+            set_option(&mut vmr, "machine.vcpus", "four"),
+            Err(OptionError::InvalidType(
+                "machine.vcpus".to_string(),
+                OptionType::U32
+            ))
+        );
+        assert!(matches!(
+            set_option(&mut vmr, "machine.vcpus", "256"),
+            Err(OptionError::InvalidValue(..))
+        ));
+        assert!(matches!(
+            set_option(&mut vmr, "machine.vcpus", "0"),
+            Err(OptionError::InvalidValue(..))
+        ));
+        assert_eq!(vmr.vm_config().vcpu_count, Some(4));
+
+        assert!(matches!(
+            set_option(&mut vmr, "runtime.grace_secs", "5"),
+            Err(OptionError::InvalidValue(..))
+        ));
+        set_option(&mut vmr, "runtime.max_secs", "60").unwrap();
+        set_option(&mut vmr, "runtime.grace_secs", "5").unwrap();
+        assert_eq!(
+            vmr.runtime_limit,
+            Some(RuntimeLimitConfig {
+                max_runtime: Duration::from_secs(60),
+                grace_period: Duration::from_secs(5),
+            })
+        );
+        set_option(&mut vmr, "runtime.max_secs", "0").unwrap();
+        assert_eq!(vmr.runtime_limit, None);
+
+        assert_eq!(
+            set_option(&mut vmr, "idle.reclaim_memory", "yes"),
+            Err(OptionError::InvalidType(
+                "idle.reclaim_memory".to_string(),
+                OptionType::Bool
+            ))
+        );
+        set_option(&mut vmr, "idle.interval_ms", "500").unwrap();
+        set_option(&mut vmr, "idle.reclaim_memory", "true").unwrap();
+        assert_eq!(
+            vmr.idle_policy,
+            Some(IdlePolicyConfig {
+                idle_interval: Duration::from_millis(500),
+                reclaim_memory: true,
+            })
+        );
+
+        set_option(&mut vmr, "devices.crypto", "true").unwrap();
+        assert!(vmr.crypto);
+
+        set_option(&mut vmr, "vsock.shm", "true").unwrap();
+        assert!(vmr.vsock_shm);
+
+        assert!(matches!(
+            set_option(&mut vmr, "vsock.buf_size", "100000"),
+            Err(OptionError::InvalidValue(..))
+        ));
+        set_option(&mut vmr, "vsock.buf_size", "262144").unwrap();
+        assert_eq!(vmr.vsock_conn, VsockConnConfig::with_buf_size(256 * 1024));
+        assert!(matches!(
+            set_option(&mut vmr, "vsock.credit_threshold", "300000"),
+            Err(OptionError::InvalidValue(..))
+        ));
+        set_option(&mut vmr, "vsock.credit_threshold", "65536").unwrap();
+        assert_eq!(vmr.vsock_conn.credit_update_threshold, 64 * 1024);
+
+        set_option(&mut vmr, "guest.tmp_mib", "512").unwrap();
+        set_option(&mut vmr, "guest.shm_mib", "2048").unwrap();
+        assert_eq!(
+            vmr.tmpfs,
+            TmpfsConfig {
+                tmp_size_mib: Some(512),
+                shm_size_mib: Some(2048),
+            }
+        );
+        set_option(&mut vmr, "guest.tmp_mib", "0").unwrap();
+        assert_eq!(vmr.tmpfs.tmp_size_mib, None);
+
+        assert!(!vmr.no_i8042);
+        set_option(&mut vmr, "devices.i8042", "false").unwrap();
+        assert!(vmr.no_i8042);
+
+        assert!(!vmr.no_balloon);
+        set_option(&mut vmr, "devices.balloon", "false").unwrap();
+        assert!(vmr.no_balloon);
+
+        set_option(&mut vmr, "fs.negative_timeout_ms", "1500").unwrap();
+        set_option(&mut vmr, "fs.batch_lookups", "true").unwrap();
+        assert_eq!(
+            vmr.fs_lookup_cache,
+            LookupCacheConfig {
+                negative_timeout: Duration::from_millis(1500),
+                batch_lookups: true,
+            }
+        );
+
+        assert_eq!(vmr.latency_profile, LatencyProfile::Default);
+        set_option(&mut vmr, "machine.low_latency", "true").unwrap();
+        assert_eq!(vmr.latency_profile, LatencyProfile::LowLatency);
+    }
+}
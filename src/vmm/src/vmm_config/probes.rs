@@ -0,0 +1,346 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Errors associated with the configuration of the probes.
+#[derive(Debug, PartialEq)]
+pub enum ProbeConfigError {
+    /// The command run in the guest is empty.
+    EmptyCommand,
+    /// An argument of the command run in the guest contains characters that can't be passed to
+    /// the guest: ',', '"' or control characters.
+    InvalidArgument(String),
+    /// The period or the timeout is zero, or the timeout exceeds the period.
+    InvalidTiming,
+    /// A threshold is zero.
+    InvalidThreshold,
+}
+
+impl fmt::Display for ProbeConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ProbeConfigError::*;
+        match self {
+            EmptyCommand => write!(f, "The command of the probe is empty"),
+            InvalidArgument(arg) => write!(f, "Invalid argument of the probe command: {:?}", arg),
+            InvalidTiming => write!(
+                f,
+                "The period and the timeout of a probe must be non-zero, and the timeout must not \
+                 exceed the period"
+            ),
+            InvalidThreshold => write!(f, "The thresholds of a probe must be non-zero"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeConfigError {}
+
+/// What the state of a probe tells the orchestrator, as with the probes of Kubernetes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeKind {
+    /// Whether the workload is ready to serve.
+    Readiness,
+    /// Whether the workload is still working, rather than stuck.
+    Liveness,
+}
+
+impl ProbeKind {
+    /// The variable of the environment of the init process describing the command of the probe.
+    pub fn guest_env(self) -> &'static str {
+        match self {
+            ProbeKind::Readiness => "KRUN_READINESS_PROBE",
+            ProbeKind::Liveness => "KRUN_LIVENESS_PROBE",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// How a probe checks the workload.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeAction {
+    /// Runs a command in the guest, through the init process, succeeding if it exits with 0.
+    Exec(Vec<String>),
+    /// Connects to a host address forwarded to the guest, such as a port of the port map.
+    TcpConnect(SocketAddr),
+    /// Checks a host path exists, such as a file the workload creates in a share.
+    FileExists(PathBuf),
+}
+
+/// When a probe runs, and how many consecutive results change its state.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeTiming {
+    /// The time from the start of the vCPUs to the first run.
+    pub initial_delay: Duration,
+    pub period: Duration,
+    /// The time after which a run is a failure.
+    pub timeout: Duration,
+    /// The consecutive successes after which a failing probe succeeds.
+    pub success_threshold: u32,
+    /// The consecutive failures after which a succeeding probe fails.
+    pub failure_threshold: u32,
+}
+
+impl Default for ProbeTiming {
+    fn default() -> Self {
+        ProbeTiming {
+            initial_delay: Duration::from_secs(0),
+            period: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            success_threshold: 1,
+            failure_threshold: 3,
+        }
+    }
+}
+
+/// A probe of the workload, reporting the changes of its state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeConfig {
+    pub kind: ProbeKind,
+    pub action: ProbeAction,
+    pub timing: ProbeTiming,
+}
+
+impl ProbeConfig {
+    pub fn new(kind: ProbeKind, action: ProbeAction) -> Result<Self, ProbeConfigError> {
+        let config = ProbeConfig {
+            kind,
+            action,
+            timing: ProbeTiming::default(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ProbeConfigError> {
+        if let ProbeAction::Exec(args) = &self.action {
+            if args.is_empty() || args[0].is_empty() {
+                return Err(ProbeConfigError::EmptyCommand);
+            }
+            // The command is passed to the guest as a quoted, comma-separated list.
+            if let Some(arg) = args
+                .iter()
+                .find(|arg| arg.contains(|c: char| c == ',' || c == '"' || c.is_control()))
+            {
+                return Err(ProbeConfigError::InvalidArgument(arg.clone()));
+            }
+        }
+
+        let timing = &self.timing;
+        if timing.period == Duration::from_secs(0)
+            || timing.timeout == Duration::from_secs(0)
+            || timing.timeout > timing.period
+        {
+            return Err(ProbeConfigError::InvalidTiming);
+        }
+        if timing.success_threshold == 0 || timing.failure_threshold == 0 {
+            return Err(ProbeConfigError::InvalidThreshold);
+        }
+        Ok(())
+    }
+
+    /// Returns the description of the command the init process of the guest runs, if any: the
+    /// initial delay, the period and the timeout, in milliseconds, followed by the arguments.
+    pub fn guest_command(&self) -> Option<String> {
+        match &self.action {
+            ProbeAction::Exec(args) => Some(format!(
+                "{},{},{},{}",
+                self.timing.initial_delay.as_millis(),
+                self.timing.period.as_millis(),
+                self.timing.timeout.as_millis(),
+                args.join(",")
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a probe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeState {
+    /// The probe hasn't reached a threshold yet.
+    Unknown,
+    Success,
+    Failure,
+}
+
+/// Callback invoked when the state of a probe changes, from the thread running the probe.
+pub type ProbeCallback = Arc<dyn Fn(ProbeKind, ProbeState) + Send + Sync>;
+
+/// Turns the results of the runs of a probe into its state, according to its thresholds.
+pub struct ProbeTracker {
+    timing: ProbeTiming,
+    state: ProbeState,
+    // The consecutive results like the last one.
+    streak: u32,
+    last: Option<bool>,
+}
+
+impl ProbeTracker {
+    pub fn new(timing: ProbeTiming) -> Self {
+        ProbeTracker {
+            timing,
+            state: ProbeState::Unknown,
+            streak: 0,
+            last: None,
+        }
+    }
+
+    /// Records the result of a run, returning the new state of the probe if it changed.
+    pub fn record(&mut self, success: bool) -> Option<ProbeState> {
+        if self.last == Some(success) {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 1;
+            self.last = Some(success);
+        }
+
+        let state = match success {
+            true if self.streak >= self.timing.success_threshold => ProbeState::Success,
+            false if self.streak >= self.timing.failure_threshold => ProbeState::Failure,
+            _ => return None,
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+// Written by the init process of the guest, to the port or the address it signals the VMM through,
+// after each run of the command of a probe.
+const GUEST_READINESS_SUCCESS: u8 = 125;
+const GUEST_READINESS_FAILURE: u8 = 126;
+const GUEST_LIVENESS_SUCCESS: u8 = 127;
+const GUEST_LIVENESS_FAILURE: u8 = 128;
+
+/// Decodes a value written by the init process of the guest into the result of a run of the
+/// command of a probe, if it is one.
+pub fn guest_result(value: u8) -> Option<(ProbeKind, bool)> {
+    match value {
+        GUEST_READINESS_SUCCESS => Some((ProbeKind::Readiness, true)),
+        GUEST_READINESS_FAILURE => Some((ProbeKind::Readiness, false)),
+        GUEST_LIVENESS_SUCCESS => Some((ProbeKind::Liveness, true)),
+        GUEST_LIVENESS_FAILURE => Some((ProbeKind::Liveness, false)),
+        _ => None,
+    }
+}
+
+const REPORT_NONE: u8 = 0;
+const REPORT_SUCCESS: u8 = 1;
+const REPORT_FAILURE: u8 = 2;
+
+/// The last results of the commands run by the init process of the guest, reported by the vCPUs.
+#[derive(Clone, Default)]
+pub struct ProbeReports(Arc<[AtomicU8; 2]>);
+
+impl ProbeReports {
+    pub fn report(&self, kind: ProbeKind, success: bool) {
+        let report = if success {
+            REPORT_SUCCESS
+        } else {
+            REPORT_FAILURE
+        };
+        self.0[kind.index()].store(report, Ordering::SeqCst);
+    }
+
+    /// Takes the last result reported for `kind`, if any since the previous call.
+    pub fn take(&self, kind: ProbeKind) -> Option<bool> {
+        match self.0[kind.index()].swap(REPORT_NONE, Ordering::SeqCst) {
+            REPORT_SUCCESS => Some(true),
+            REPORT_FAILURE => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for ProbeReports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProbeReports")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_config() {
+        let exec = |args: &[&str]| ProbeAction::Exec(args.iter().map(|a| a.to_string()).collect());
+
+        let config = ProbeConfig::new(ProbeKind::Liveness, exec(&["/bin/check", "-q"])).unwrap();
+        assert_eq!(
+            config.guest_command().unwrap(),
+            "0,10000,1000,/bin/check,-q"
+        );
+        let config = ProbeConfig::new(
+            ProbeKind::Readiness,
+            ProbeAction::FileExists(PathBuf::from("/srv/ready")),
+        )
+        .unwrap();
+        assert!(config.guest_command().is_none());
+
+        assert_eq!(
+            ProbeConfig::new(ProbeKind::Liveness, exec(&[])),
+            Err(ProbeConfigError::EmptyCommand)
+        );
+        assert_eq!(
+            ProbeConfig::new(ProbeKind::Liveness, exec(&["check", "a,b"])),
+            Err(ProbeConfigError::InvalidArgument("a,b".to_string()))
+        );
+
+        let mut config = ProbeConfig::new(ProbeKind::Liveness, exec(&["check"])).unwrap();
+        config.timing.timeout = Duration::from_secs(20);
+        assert_eq!(config.validate(), Err(ProbeConfigError::InvalidTiming));
+        config.timing.timeout = Duration::from_secs(1);
+        config.timing.failure_threshold = 0;
+        assert_eq!(config.validate(), Err(ProbeConfigError::InvalidThreshold));
+    }
+
+    #[test]
+    fn test_probe_tracker() {
+        let mut tracker = ProbeTracker::new(ProbeTiming {
+            success_threshold: 2,
+            failure_threshold: 3,
+            ..Default::default()
+        });
+
+        assert_eq!(tracker.record(true), None);
+        assert_eq!(tracker.record(true), Some(ProbeState::Success));
+        assert_eq!(tracker.record(true), None);
+
+        // A success in between failures resets the streak.
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(true), None);
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(false), Some(ProbeState::Failure));
+        assert_eq!(tracker.record(false), None);
+    }
+
+    #[test]
+    fn test_guest_result() {
+        assert_eq!(guest_result(124), None);
+        assert_eq!(guest_result(125), Some((ProbeKind::Readiness, true)));
+        assert_eq!(guest_result(128), Some((ProbeKind::Liveness, false)));
+    }
+
+    #[test]
+    fn test_probe_reports() {
+        let reports = ProbeReports::default();
+        assert_eq!(reports.take(ProbeKind::Readiness), None);
+
+        reports.clone().report(ProbeKind::Readiness, false);
+        reports.report(ProbeKind::Readiness, true);
+        reports.report(ProbeKind::Liveness, false);
+        assert_eq!(reports.take(ProbeKind::Readiness), Some(true));
+        assert_eq!(reports.take(ProbeKind::Readiness), None);
+        assert_eq!(reports.take(ProbeKind::Liveness), Some(false));
+    }
+}
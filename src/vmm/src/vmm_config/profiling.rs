@@ -0,0 +1,197 @@
+use std::fmt;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The shortest period between two profiles, so sampling doesn't disturb the workload.
+pub const MIN_PROFILING_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Errors associated with the profiling configuration.
+#[derive(Debug, PartialEq)]
+pub enum ProfilingConfigError {
+    /// The interval is shorter than `MIN_PROFILING_INTERVAL`.
+    InvalidInterval,
+}
+
+impl fmt::Display for ProfilingConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ProfilingConfigError::*;
+        match *self {
+            InvalidInterval => write!(
+                f,
+                "The profiling interval must be at least {} ms",
+                MIN_PROFILING_INTERVAL.as_millis()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProfilingConfigError {}
+
+/// Profiling of the workload from the host. Every `interval`, the vCPUs are sampled into a
+/// report of what they did since the previous one. With `pmu`, the reports include the cycles and
+/// instructions spent in the guest, as counted by the PMU of the host, where available.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProfilingConfig {
+    pub interval: Duration,
+    pub pmu: bool,
+}
+
+impl ProfilingConfig {
+    pub fn new(interval: Duration, pmu: bool) -> Result<Self, ProfilingConfigError> {
+        if interval < MIN_PROFILING_INTERVAL {
+            return Err(ProfilingConfigError::InvalidInterval);
+        }
+        Ok(ProfilingConfig { interval, pmu })
+    }
+}
+
+/// What a vCPU did over the period of a report.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VcpuProfile {
+    /// The CPU time consumed by the thread of the vCPU, in the guest and in the VMM.
+    pub cpu_time: Duration,
+    /// The cycles spent in the guest, if the PMU can count them.
+    pub guest_cycles: Option<u64>,
+    /// The instructions retired in the guest, if the PMU can count them.
+    pub guest_instructions: Option<u64>,
+}
+
+/// The exits of all the vCPUs to the VMM, by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExitCounts {
+    pub io_in: u64,
+    pub io_out: u64,
+    pub mmio_read: u64,
+    pub mmio_write: u64,
+}
+
+impl ExitCounts {
+    /// The exits since `earlier`.
+    pub fn since(&self, earlier: &ExitCounts) -> ExitCounts {
+        ExitCounts {
+            io_in: self.io_in.saturating_sub(earlier.io_in),
+            io_out: self.io_out.saturating_sub(earlier.io_out),
+            mmio_read: self.mmio_read.saturating_sub(earlier.mmio_read),
+            mmio_write: self.mmio_write.saturating_sub(earlier.mmio_write),
+        }
+    }
+}
+
+/// A profile of the vCPUs over a period.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfileReport {
+    /// The number of the report, starting at 1.
+    pub sequence: u64,
+    /// The time covered by the report.
+    pub period: Duration,
+    pub vcpus: Vec<VcpuProfile>,
+    pub exits: ExitCounts,
+}
+
+impl ProfileReport {
+    /// Serializes the report as a single-line JSON object. The counters the PMU can't provide are
+    /// null.
+    pub fn to_json(&self) -> String {
+        let counter = |value: Option<u64>| value.map_or("null".to_string(), |v| v.to_string());
+
+        let mut out = format!(
+            "{{\"sequence\":{},\"period_us\":{},\"vcpus\":[",
+            self.sequence,
+            self.period.as_micros()
+        );
+        for (index, vcpu) in self.vcpus.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"cpu_time_us\":{},\"guest_cycles\":{},\"guest_instructions\":{}}}",
+                vcpu.cpu_time.as_micros(),
+                counter(vcpu.guest_cycles),
+                counter(vcpu.guest_instructions)
+            );
+        }
+        let _ = write!(
+            out,
+            "],\"exits\":{{\"io_in\":{},\"io_out\":{},\"mmio_read\":{},\"mmio_write\":{}}}}}",
+            self.exits.io_in, self.exits.io_out, self.exits.mmio_read, self.exits.mmio_write
+        );
+        out
+    }
+}
+
+/// The last profile of the microVM, published by the VMM and read by the embedder.
+#[derive(Clone, Default)]
+pub struct ProfileReports(Arc<Mutex<Option<ProfileReport>>>);
+
+impl ProfileReports {
+    pub fn publish(&self, report: ProfileReport) {
+        *self.0.lock().unwrap() = Some(report);
+    }
+
+    /// Returns the last report, if any has been published yet.
+    pub fn latest(&self) -> Option<ProfileReport> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl fmt::Debug for ProfileReports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProfileReports")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiling_config() {
+        assert_eq!(
+            ProfilingConfig::new(Duration::from_millis(10), true),
+            Err(ProfilingConfigError::InvalidInterval)
+        );
+        assert!(ProfilingConfig::new(MIN_PROFILING_INTERVAL, false).is_ok());
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let report = ProfileReport {
+            sequence: 3,
+            period: Duration::from_millis(1000),
+            vcpus: vec![
+                VcpuProfile {
+                    cpu_time: Duration::from_micros(250),
+                    guest_cycles: Some(1200),
+                    guest_instructions: Some(900),
+                },
+                VcpuProfile::default(),
+            ],
+            exits: ExitCounts {
+                io_in: 1,
+                io_out: 2,
+                mmio_read: 3,
+                mmio_write: 4,
+            }
+            .since(&ExitCounts {
+                io_in: 1,
+                io_out: 1,
+                mmio_read: 1,
+                mmio_write: 1,
+            }),
+        };
+        assert_eq!(
+            report.to_json(),
+            "{\"sequence\":3,\"period_us\":1000000,\"vcpus\":[\
+             {\"cpu_time_us\":250,\"guest_cycles\":1200,\"guest_instructions\":900},\
+             {\"cpu_time_us\":0,\"guest_cycles\":null,\"guest_instructions\":null}],\
+             \"exits\":{\"io_in\":0,\"io_out\":1,\"mmio_read\":2,\"mmio_write\":3}}"
+        );
+
+        let reports = ProfileReports::default();
+        assert!(reports.latest().is_none());
+        reports.clone().publish(report.clone());
+        assert_eq!(reports.latest(), Some(report));
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+/// The pool is reserved at a 2 MiB boundary, so it's sized in multiples of it.
+const POOL_SIZE_ALIGN_MIB: u32 = 2;
+/// The pool bounces every buffer of every device, so it's kept to a fraction of the guest memory
+/// anyway; past this, a misconfiguration is more likely than a need.
+const MAX_POOL_SIZE_MIB: u32 = 1024;
+
+/// Errors associated with the restricted DMA configuration.
+#[derive(Debug)]
+pub enum RestrictedDmaConfigError {
+    /// The size of the pool is zero, above the maximum, or not a multiple of 2 MiB.
+    InvalidPoolSize(u32),
+}
+
+impl fmt::Display for RestrictedDmaConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RestrictedDmaConfigError::*;
+        match self {
+            InvalidPoolSize(size) => write!(
+                f,
+                "Invalid restricted DMA pool size: {} MiB, expected a multiple of {} MiB up to {} MiB",
+                size, POOL_SIZE_ALIGN_MIB, MAX_POOL_SIZE_MIB
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RestrictedDmaConfigError {}
+
+/// Restricts the virtio devices to a pool of guest memory the guest bounces their buffers
+/// through, so the rest of its memory is never exposed to the VMM on their behalf. The queues
+/// reject the descriptors pointing out of the pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RestrictedDmaConfig {
+    pub pool_size_mib: u32,
+}
+
+impl RestrictedDmaConfig {
+    pub fn new(pool_size_mib: u32) -> Result<Self, RestrictedDmaConfigError> {
+        if pool_size_mib == 0
+            || pool_size_mib > MAX_POOL_SIZE_MIB
+            || pool_size_mib % POOL_SIZE_ALIGN_MIB != 0
+        {
+            return Err(RestrictedDmaConfigError::InvalidPoolSize(pool_size_mib));
+        }
+        Ok(RestrictedDmaConfig { pool_size_mib })
+    }
+
+    /// Returns the size of the pool in bytes.
+    pub fn pool_size(&self) -> u64 {
+        u64::from(self.pool_size_mib) << 20
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restricted_dma_config() {
+        assert_eq!(RestrictedDmaConfig::new(64).unwrap().pool_size(), 64 << 20);
+        for size in &[0, 3, 2048] {
+            assert!(matches!(
+                RestrictedDmaConfig::new(*size),
+                Err(RestrictedDmaConfigError::InvalidPoolSize(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Default time given to the guest to shut down once the maximum runtime has been exceeded.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Errors associated with the runtime limit configuration.
+#[derive(Debug, PartialEq)]
+pub enum RuntimeLimitConfigError {
+    /// The maximum runtime is zero.
+    InvalidMaxRuntime,
+}
+
+impl fmt::Display for RuntimeLimitConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RuntimeLimitConfigError::*;
+        match *self {
+            InvalidMaxRuntime => write!(f, "The maximum runtime must be non-zero"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeLimitConfigError {}
+
+/// Wall-clock limit on the runtime of the microVM. Once `max_runtime` has elapsed since the vCPUs
+/// were started, the guest is asked to shut down, and it's forcibly stopped if it's still running
+/// after `grace_period`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RuntimeLimitConfig {
+    pub max_runtime: Duration,
+    pub grace_period: Duration,
+}
+
+impl RuntimeLimitConfig {
+    pub fn new(
+        max_runtime: Duration,
+        grace_period: Duration,
+    ) -> std::result::Result<Self, RuntimeLimitConfigError> {
+        if max_runtime == Duration::from_secs(0) {
+            return Err(RuntimeLimitConfigError::InvalidMaxRuntime);
+        }
+
+        Ok(RuntimeLimitConfig {
+            max_runtime,
+            grace_period,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_limit_config() {
+        assert_eq!(
+            RuntimeLimitConfig::new(Duration::from_secs(0), DEFAULT_GRACE_PERIOD),
+            Err(RuntimeLimitConfigError::InvalidMaxRuntime)
+        );
+
+        let config =
+            RuntimeLimitConfig::new(Duration::from_secs(60), Duration::from_secs(0)).unwrap();
+        assert_eq!(config.max_runtime, Duration::from_secs(60));
+        assert_eq!(config.grace_period, Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,4 @@
+//! The secrets are kept by the mailbox handing them to the guest, so the VMM holds a single copy
+//! of them, which the mailbox wipes once read.
+
+pub use devices::legacy::{SecretsError, DEFAULT_SECRETS_TTL, MAX_SECRETS, MAX_SECRET_SIZE};
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Errors associated with the passthrough of the serial devices of the host.
+#[derive(Debug, PartialEq)]
+pub enum SerialPassthroughError {
+    /// The name is empty, or has characters other than ASCII letters, digits, '_' and '-'.
+    InvalidName(String),
+    /// A serial device was already passed through under the name.
+    DuplicateName(String),
+    /// The baud rate isn't one the host supports.
+    UnsupportedBaudRate(u32),
+}
+
+impl fmt::Display for SerialPassthroughError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SerialPassthroughError::*;
+        match self {
+            InvalidName(name) => write!(f, "Invalid serial device name: {:?}", name),
+            DuplicateName(name) => write!(f, "A serial device is already named {:?}", name),
+            UnsupportedBaudRate(rate) => write!(f, "Unsupported baud rate: {}", rate),
+        }
+    }
+}
+
+impl std::error::Error for SerialPassthroughError {}
+
+/// A character device of the host, such as a serial adapter, bound to a console of the guest.
+/// The bytes are passed through as they are, the device being set to raw mode if it's a terminal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerialPassthroughConfig {
+    /// The name the guest finds the console under.
+    pub name: String,
+    pub path: PathBuf,
+    /// The baud rate the device is set to, if it's a terminal, or `None` to leave it as it is.
+    pub baud_rate: Option<u32>,
+}
+
+impl SerialPassthroughConfig {
+    pub fn new(
+        name: &str,
+        path: PathBuf,
+        baud_rate: Option<u32>,
+    ) -> std::result::Result<Self, SerialPassthroughError> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(SerialPassthroughError::InvalidName(name.to_string()));
+        }
+        if let Some(rate) = baud_rate {
+            if speed(rate).is_none() {
+                return Err(SerialPassthroughError::UnsupportedBaudRate(rate));
+            }
+        }
+
+        Ok(SerialPassthroughConfig {
+            name: name.to_string(),
+            path,
+            baud_rate,
+        })
+    }
+
+    /// Returns the speed the device is set to, if any.
+    pub fn speed(&self) -> Option<libc::speed_t> {
+        self.baud_rate.and_then(speed)
+    }
+}
+
+// The speeds of Linux are constants of their own, standing for the standard baud rates only.
+#[cfg(target_os = "linux")]
+fn speed(baud_rate: u32) -> Option<libc::speed_t> {
+    let speed = match baud_rate {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        500000 => libc::B500000,
+        921600 => libc::B921600,
+        1000000 => libc::B1000000,
+        1500000 => libc::B1500000,
+        2000000 => libc::B2000000,
+        3000000 => libc::B3000000,
+        4000000 => libc::B4000000,
+        _ => return None,
+    };
+    Some(speed)
+}
+
+// Those of macOS are the baud rates themselves.
+#[cfg(target_os = "macos")]
+fn speed(baud_rate: u32) -> Option<libc::speed_t> {
+    if baud_rate == 0 {
+        return None;
+    }
+    Some(baud_rate as libc::speed_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serial_passthrough_config() {
+        let path = PathBuf::from("/dev/ttyUSB0");
+        for name in &["", "gps 0", "gps/0"] {
+            assert_eq!(
+                SerialPassthroughConfig::new(name, path.clone(), None),
+                Err(SerialPassthroughError::InvalidName(name.to_string()))
+            );
+        }
+        assert_eq!(
+            SerialPassthroughConfig::new("gps", path.clone(), Some(0)),
+            Err(SerialPassthroughError::UnsupportedBaudRate(0))
+        );
+
+        let config = SerialPassthroughConfig::new("gps_0", path.clone(), None).unwrap();
+        assert_eq!(config.speed(), None);
+        let config = SerialPassthroughConfig::new("gps-0", path, Some(115_200)).unwrap();
+        assert!(config.speed().is_some());
+    }
+}
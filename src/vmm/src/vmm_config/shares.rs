@@ -0,0 +1,229 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+pub use devices::virtio::{
+    DedupStore, EscapePolicy, ShareCache, ShareCacheConfig, ShareIoConfig, ShareQuota,
+};
+use vmm_config::fs::FsConfigError;
+
+/// The longest tag a virtio-fs device can have.
+const MAX_TAG_LEN: usize = 36;
+
+/// Errors associated with the configuration of the shares.
+#[derive(Debug)]
+pub enum ShareError {
+    /// Another share is already mounted at the guest path.
+    DuplicateGuestPath(PathBuf),
+    /// Another share already has the tag.
+    DuplicateTag(String),
+    /// Unable to create the share.
+    FsDevice(FsConfigError),
+    /// The capacity of the quota is zero.
+    InvalidQuota,
+    /// The guest path isn't absolute, is the root, or contains components or characters that
+    /// can't be passed to the guest.
+    InvalidGuestPath(PathBuf),
+    /// The tag is empty, too long, or contains characters other than ASCII alphanumerics, '-' and
+    /// '_'.
+    InvalidTag(String),
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ShareError::*;
+        match self {
+            DuplicateGuestPath(path) => {
+                write!(f, "A share is already mounted at {}", path.display())
+            }
+            DuplicateTag(tag) => write!(f, "A share is already tagged {}", tag),
+            FsDevice(_) => write!(f, "Unable to create the share"),
+            InvalidQuota => write!(f, "The capacity of a share quota must not be zero"),
+            InvalidGuestPath(path) => write!(f, "Invalid guest path: {}", path.display()),
+            InvalidTag(tag) => write!(f, "Invalid share tag: {:?}", tag),
+        }
+    }
+}
+
+impl std::error::Error for ShareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShareError::FsDevice(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The settings of an fs device, applied once it's attached to the microVM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShareSettings {
+    pub quota: Option<ShareQuota>,
+    pub io: ShareIoConfig,
+    pub escape_policy: EscapePolicy,
+    pub cache: Option<ShareCache>,
+    pub dedup: Option<DedupStore>,
+}
+
+/// How mount and unmount events propagate between the mount point of a share and its peers, as
+/// described in mount_namespaces(7).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MountPropagation {
+    Private,
+    Shared,
+    Slave,
+    Unbindable,
+}
+
+impl Default for MountPropagation {
+    fn default() -> Self {
+        MountPropagation::Private
+    }
+}
+
+impl MountPropagation {
+    fn as_str(self) -> &'static str {
+        match self {
+            MountPropagation::Private => "private",
+            MountPropagation::Shared => "shared",
+            MountPropagation::Slave => "slave",
+            MountPropagation::Unbindable => "unbindable",
+        }
+    }
+}
+
+/// Where, and how, the init process of the guest mounts a share before executing the workload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareMount {
+    /// The tag of the virtio-fs device exposing the share.
+    pub tag: String,
+    /// The absolute path the share is mounted at, created if missing. It may be within another
+    /// share, mounted first.
+    pub guest_path: PathBuf,
+    pub read_only: bool,
+    pub propagation: MountPropagation,
+    /// The shares are mounted by ascending order. Among those with the same order, the shallower
+    /// guest paths are mounted first, so a share is mounted before the ones nested in it, and
+    /// those as deep are mounted in the order they were added.
+    pub order: u32,
+}
+
+impl ShareMount {
+    /// Creates a read-write, private mount of the share tagged `tag` at `guest_path`.
+    pub fn new<P: AsRef<Path>>(tag: &str, guest_path: P) -> Result<Self, ShareError> {
+        let mount = ShareMount {
+            tag: tag.to_string(),
+            guest_path: guest_path.as_ref().to_path_buf(),
+            read_only: false,
+            propagation: MountPropagation::default(),
+            order: 0,
+        };
+        mount.validate()?;
+        Ok(mount)
+    }
+
+    /// Checks the tag and the guest path can be passed to the guest.
+    pub fn validate(&self) -> Result<(), ShareError> {
+        if self.tag.is_empty()
+            || self.tag.len() > MAX_TAG_LEN
+            || !self
+                .tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ShareError::InvalidTag(self.tag.clone()));
+        }
+
+        // The mounts are passed to the guest as a quoted, comma-separated list of colon-separated
+        // fields.
+        let valid_chars = self.guest_path.to_str().map_or(false, |path| {
+            !path.contains(|c: char| c == ',' || c == ':' || c == '"' || c.is_control())
+        });
+        let valid_components = self
+            .guest_path
+            .components()
+            .skip(1)
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !valid_chars
+            || !valid_components
+            || !self.guest_path.is_absolute()
+            || self.guest_path.parent().is_none()
+        {
+            return Err(ShareError::InvalidGuestPath(self.guest_path.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn depth(&self) -> usize {
+        self.guest_path.components().count()
+    }
+}
+
+impl fmt::Display for ShareMount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.tag,
+            self.guest_path.display(),
+            if self.read_only { "ro" } else { "rw" },
+            self.propagation.as_str()
+        )
+    }
+}
+
+/// Sorts `mounts`, in the order they were added, into the order the guest mounts them.
+pub fn sort_mounts(mounts: &mut [ShareMount]) {
+    // The sort is stable, so the order the shares were added in is kept otherwise.
+    mounts.sort_by_key(|mount| (mount.order, mount.depth()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mount = ShareMount::new("data", "/srv/data").unwrap();
+        assert_eq!(mount.to_string(), "data:/srv/data:rw:private");
+        let mount = ShareMount {
+            read_only: true,
+            propagation: MountPropagation::Shared,
+            ..mount
+        };
+        assert_eq!(mount.to_string(), "data:/srv/data:ro:shared");
+
+        for tag in &[
+            "",
+            "a/b",
+            "tag with spaces",
+            "x".repeat(MAX_TAG_LEN + 1).as_str(),
+        ] {
+            match ShareMount::new(tag, "/srv") {
+                Err(ShareError::InvalidTag(t)) => assert_eq!(t, *tag),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        for path in &["srv", "/", "/srv/../etc", "/a,b", "/a:b", "/a\"b"] {
+            match ShareMount::new("data", path) {
+                Err(ShareError::InvalidGuestPath(p)) => assert_eq!(p, Path::new(path)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sort_mounts() {
+        let mut mounts = vec![
+            ShareMount::new("cache", "/srv/data/cache").unwrap(),
+            ShareMount::new("data", "/srv/data").unwrap(),
+            ShareMount {
+                order: 1,
+                ..ShareMount::new("late", "/late").unwrap()
+            },
+            ShareMount::new("logs", "/logs").unwrap(),
+        ];
+        sort_mounts(&mut mounts);
+        let tags: Vec<&str> = mounts.iter().map(|m| m.tag.as_str()).collect();
+        assert_eq!(tags, vec!["logs", "data", "cache", "late"]);
+    }
+}
@@ -0,0 +1,252 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::process;
+
+use libc::{c_int, c_void, sockaddr_storage, socklen_t};
+
+/// The first file descriptor passed by the service manager, as in sd_listen_fds(3).
+pub const SD_LISTEN_FDS_START: RawFd = 3;
+/// The name, given through LISTEN_FDNAMES, of the socket the console is attached through.
+pub const CONSOLE_FD_NAME: &str = "console";
+
+/// Errors associated with the sockets handed over by the embedder or the service manager.
+#[derive(Debug)]
+pub enum SocketActivationError {
+    /// Failed to inspect the socket.
+    InspectSocket(RawFd, io::Error),
+    /// LISTEN_PID, LISTEN_FDS or LISTEN_FDNAMES is malformed.
+    InvalidEnv(&'static str),
+    /// The file descriptor isn't a listening socket.
+    NotListening(RawFd),
+    /// The file descriptor isn't a TCP or a Unix stream socket.
+    UnsupportedSocket(RawFd),
+}
+
+impl fmt::Display for SocketActivationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SocketActivationError::*;
+        match self {
+            InspectSocket(fd, _) => write!(f, "Cannot inspect the socket of fd {}", fd),
+            InvalidEnv(var) => write!(f, "Invalid {} in the environment", var),
+            NotListening(fd) => write!(f, "The fd {} isn't a listening socket", fd),
+            UnsupportedSocket(fd) => {
+                write!(f, "The fd {} isn't a TCP or a Unix stream socket", fd)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SocketActivationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SocketActivationError::InspectSocket(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, SocketActivationError>;
+
+/// A listening socket handed over by the embedder or the service manager.
+#[derive(Debug)]
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl ListenSocket {
+    /// Takes ownership of `fd`, closing it if it isn't a listening TCP or Unix stream socket.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor, not owned by anything else.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<Self> {
+        let socket = Self::check(fd).map(|family| match family {
+            libc::AF_UNIX => ListenSocket::Unix(UnixListener::from_raw_fd(fd)),
+            _ => ListenSocket::Tcp(TcpListener::from_raw_fd(fd)),
+        });
+        if socket.is_err() {
+            libc::close(fd);
+        }
+        socket
+    }
+
+    /// Returns the family of the socket `fd`, if it is a supported listening socket.
+    unsafe fn check(fd: RawFd) -> Result<c_int> {
+        if sockopt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+            return Err(SocketActivationError::UnsupportedSocket(fd));
+        }
+        if sockopt(fd, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(SocketActivationError::NotListening(fd));
+        }
+
+        let mut addr: sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<sockaddr_storage>() as socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            return Err(SocketActivationError::InspectSocket(
+                fd,
+                io::Error::last_os_error(),
+            ));
+        }
+        match addr.ss_family as c_int {
+            libc::AF_INET | libc::AF_INET6 | libc::AF_UNIX => Ok(addr.ss_family as c_int),
+            _ => Err(SocketActivationError::UnsupportedSocket(fd)),
+        }
+    }
+}
+
+unsafe fn sockopt(fd: RawFd, name: c_int) -> Result<c_int> {
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    if libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        name,
+        &mut value as *mut _ as *mut c_void,
+        &mut len,
+    ) < 0
+    {
+        return Err(SocketActivationError::InspectSocket(
+            fd,
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(value)
+}
+
+/// Returns the file descriptors passed by the service manager, as in sd_listen_fds(3), along with
+/// their names, if any. The variables describing them are removed from the environment, so they
+/// aren't passed on, and the file descriptors are set close-on-exec.
+pub fn sd_listen_fds() -> Result<Vec<(RawFd, Option<String>)>> {
+    let fds = parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_FDNAMES").ok().as_deref(),
+        process::id(),
+    )?;
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    for (fd, _) in fds.iter() {
+        // Safe because the file descriptor was handed over to this process, and the return value
+        // is checked.
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(SocketActivationError::InspectSocket(
+                *fd,
+                io::Error::last_os_error(),
+            ));
+        }
+    }
+    Ok(fds)
+}
+
+/// Parses the variables of the environment describing the file descriptors passed by the service
+/// manager. Those meant for another process, as told by LISTEN_PID, are ignored.
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, Option<String>)>> {
+    let listen_pid = match listen_pid {
+        Some(listen_pid) => listen_pid
+            .parse::<u32>()
+            .map_err(|_| SocketActivationError::InvalidEnv("LISTEN_PID"))?,
+        None => return Ok(Vec::new()),
+    };
+    if listen_pid != pid {
+        return Ok(Vec::new());
+    }
+
+    let count = match listen_fds {
+        Some(listen_fds) => listen_fds
+            .parse::<RawFd>()
+            .ok()
+            .filter(|count| *count >= 0 && *count <= RawFd::MAX - SD_LISTEN_FDS_START)
+            .ok_or(SocketActivationError::InvalidEnv("LISTEN_FDS"))?,
+        None => return Ok(Vec::new()),
+    };
+
+    let names: Vec<Option<String>> = match listen_fdnames {
+        Some(names) => {
+            let names: Vec<Option<String>> = names
+                .split(':')
+                .map(|name| Some(name.to_string()).filter(|name| !name.is_empty()))
+                .collect();
+            if names.len() != count as usize {
+                return Err(SocketActivationError::InvalidEnv("LISTEN_FDNAMES"));
+            }
+            names
+        }
+        None => vec![None; count as usize],
+    };
+
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .zip(names)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert!(parse_listen_fds(None, Some("2"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert!(parse_listen_fds(Some("41"), Some("2"), None, 42)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), None, 42).unwrap(),
+            vec![(3, None), (4, None)]
+        );
+        assert_eq!(
+            parse_listen_fds(Some("42"), Some("2"), Some("console:"), 42).unwrap(),
+            vec![(3, Some(CONSOLE_FD_NAME.to_string())), (4, None)]
+        );
+
+        for (pid, fds, names) in &[
+            ("x", "2", None),
+            ("42", "-1", None),
+            ("42", "two", None),
+            ("42", "2", Some("console")),
+        ] {
+            assert!(matches!(
+                parse_listen_fds(Some(*pid), Some(*fds), *names, 42),
+                Err(SocketActivationError::InvalidEnv(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_listen_socket() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        match unsafe { ListenSocket::from_raw_fd(tcp.into_raw_fd()) } {
+            Ok(ListenSocket::Tcp(tcp)) => assert_eq!(tcp.local_addr().unwrap().port(), port),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        assert!(matches!(
+            unsafe { ListenSocket::from_raw_fd(stream.into_raw_fd()) },
+            Err(SocketActivationError::NotListening(_))
+        ));
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(matches!(
+            unsafe { ListenSocket::from_raw_fd(file.into_raw_fd()) },
+            Err(SocketActivationError::InspectSocket(..))
+        ));
+    }
+}
@@ -0,0 +1,101 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use utils::tempfile::TempFile;
+
+/// The serial of the block device backing the swap, by which the init process of the guest finds
+/// it, formats it and enables it.
+pub const SWAP_DEVICE_ID: &str = "krun-swap";
+
+/// The smallest swap the kernel of the guest accepts is 10 pages, which is rounded up to a MiB.
+const MIN_SWAP_SIZE_MIB: u32 = 1;
+
+/// Errors associated with the swap configuration.
+#[derive(Debug)]
+pub enum SwapConfigError {
+    /// The directory of the file backing the swap doesn't exist.
+    InvalidDir(PathBuf),
+    /// The size of the swap is below a MiB.
+    InvalidSize(u32),
+}
+
+impl fmt::Display for SwapConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SwapConfigError::*;
+        match self {
+            InvalidDir(dir) => write!(f, "{} isn't a directory", dir.display()),
+            InvalidSize(size) => write!(f, "Invalid swap size: {} MiB", size),
+        }
+    }
+}
+
+impl std::error::Error for SwapConfigError {}
+
+/// A swap the guest enables before executing the workload, backed by a sparse file of the host,
+/// so memory-spiky workloads can survive without a huge RAM. The host only allocates the pages
+/// the guest swaps out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwapConfig {
+    pub size_mib: u32,
+    /// The directory the file backing the swap is created in, or the temporary directory of the
+    /// host if it's `None`.
+    pub dir: Option<PathBuf>,
+}
+
+impl SwapConfig {
+    pub fn new(size_mib: u32, dir: Option<PathBuf>) -> Result<Self, SwapConfigError> {
+        if size_mib < MIN_SWAP_SIZE_MIB {
+            return Err(SwapConfigError::InvalidSize(size_mib));
+        }
+        if let Some(dir) = &dir {
+            if !dir.is_dir() {
+                return Err(SwapConfigError::InvalidDir(dir.clone()));
+            }
+        }
+        Ok(SwapConfig { size_mib, dir })
+    }
+
+    /// Creates the file backing the swap. It's unlinked right away, so it's gone once the VMM
+    /// closes it, however it exits.
+    pub fn create_file(&self) -> io::Result<File> {
+        let dir = self.dir.clone().unwrap_or_else(env::temp_dir);
+        let file = TempFile::new_with_prefix(dir.join("krun-swap-"))
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?
+            .into_file();
+        // Extending the file leaves a hole, with no block allocated.
+        file.set_len(u64::from(self.size_mib) << 20)?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_swap_file() {
+        assert!(matches!(
+            SwapConfig::new(0, None),
+            Err(SwapConfigError::InvalidSize(0))
+        ));
+        assert!(matches!(
+            SwapConfig::new(64, Some(PathBuf::from("/nonexistent"))),
+            Err(SwapConfigError::InvalidDir(_))
+        ));
+
+        let dir = TempDir::new().unwrap();
+        let swap = SwapConfig::new(64, Some(dir.as_path().to_path_buf())).unwrap();
+        let file = swap.create_file().unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 64 << 20);
+        // The file is sparse, and unlinked.
+        assert!(metadata.blocks() * 512 < metadata.len());
+        assert_eq!(dir.as_path().read_dir().unwrap().count(), 0);
+    }
+}
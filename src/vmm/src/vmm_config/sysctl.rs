@@ -0,0 +1,131 @@
+use std::fmt;
+
+/// The top-level directories of "/proc/sys" a sysctl may belong to.
+const SYSCTL_ROOTS: &[&str] = &[
+    "abi", "crypto", "debug", "dev", "fs", "kernel", "net", "sunrpc", "user", "vm",
+];
+
+/// Errors associated with the sysctl configuration.
+#[derive(Debug, PartialEq)]
+pub enum SysctlError {
+    /// The sysctl isn't in the "name=value" form.
+    InvalidFormat(String),
+    /// The name doesn't designate a file under "/proc/sys".
+    InvalidName(String),
+    /// The value is empty, or contains characters that can't be passed to the guest.
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for SysctlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SysctlError::*;
+        match self {
+            InvalidFormat(sysctl) => write!(f, "Invalid sysctl, expected name=value: {}", sysctl),
+            InvalidName(name) => write!(f, "Invalid sysctl name: {}", name),
+            InvalidValue(name, value) => {
+                write!(f, "Invalid value for sysctl {}: {:?}", name, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SysctlError {}
+
+/// A kernel tunable applied by the init process of the guest before executing the workload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sysctl {
+    /// The name of the sysctl, as accepted by sysctl(8), such as "vm.overcommit_memory". The
+    /// components are separated by '.', and a '/' stands for a '.' within a component, so
+    /// "net.ipv4.conf.eth0/100.rp_filter" refers to the "eth0.100" interface.
+    pub name: String,
+    pub value: String,
+}
+
+impl Sysctl {
+    pub fn new(name: &str, value: &str) -> Result<Self, SysctlError> {
+        let valid_root = SYSCTL_ROOTS
+            .iter()
+            .any(|root| name.starts_with(root) && name[root.len()..].starts_with('.'));
+        // Neither separator may leave an empty component, which could escape "/proc/sys".
+        let valid_components = name.split(|c| c == '.' || c == '/').all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        });
+        if !valid_root || !valid_components {
+            return Err(SysctlError::InvalidName(name.to_string()));
+        }
+
+        // The sysctls are passed to the guest as a quoted, comma-separated list.
+        if value.is_empty() || value.contains(|c: char| c == ',' || c == '"' || c.is_control()) {
+            return Err(SysctlError::InvalidValue(
+                name.to_string(),
+                value.to_string(),
+            ));
+        }
+
+        Ok(Sysctl {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    /// Parses a sysctl in the "name=value" form.
+    pub fn parse(sysctl: &str) -> Result<Self, SysctlError> {
+        let mut parts = sysctl.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => Sysctl::new(name.trim(), value.trim()),
+            _ => Err(SysctlError::InvalidFormat(sysctl.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Sysctl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sysctl() {
+        let sysctl = Sysctl::parse("vm.overcommit_memory = 1").unwrap();
+        assert_eq!(sysctl.name, "vm.overcommit_memory");
+        assert_eq!(sysctl.value, "1");
+        assert_eq!(sysctl.to_string(), "vm.overcommit_memory=1");
+
+        assert!(Sysctl::parse("net.ipv4.ip_local_port_range=1024 65000").is_ok());
+        assert!(Sysctl::parse("net.ipv4.conf.eth0/100.rp_filter=2").is_ok());
+
+        assert_eq!(
+            Sysctl::parse("vm.swappiness"),
+            Err(SysctlError::InvalidFormat("vm.swappiness".to_string()))
+        );
+        for name in &[
+            "vm",
+            "foo.bar",
+            "vm..swappiness",
+            "vm.swappiness.",
+            "net.core/../x",
+            "kernel/pid_max",
+        ] {
+            assert_eq!(
+                Sysctl::new(name, "1"),
+                Err(SysctlError::InvalidName(name.to_string()))
+            );
+        }
+        for value in &["", "1,2", "\"1\"", "1\n"] {
+            assert_eq!(
+                Sysctl::new("vm.swappiness", value),
+                Err(SysctlError::InvalidValue(
+                    "vm.swappiness".to_string(),
+                    value.to_string()
+                ))
+            );
+        }
+    }
+}
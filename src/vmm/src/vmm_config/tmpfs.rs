@@ -0,0 +1,9 @@
+/// The sizes of the tmpfs mounted by the init process of the guest, for the workloads needing a
+/// large `/dev/shm` or `/tmp`, like browsers and ML runtimes, without modifying the root.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TmpfsConfig {
+    /// The size of `/tmp`, in MiB. Without it, `/tmp` is left to the root.
+    pub tmp_size_mib: Option<u32>,
+    /// The size of `/dev/shm`, in MiB. Without it, the kernel limits it to half of the RAM.
+    pub shm_size_mib: Option<u32>,
+}
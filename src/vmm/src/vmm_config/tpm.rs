@@ -0,0 +1,76 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+/// Errors associated with the TPM configuration.
+#[derive(Debug)]
+pub enum TpmConfigError {
+    /// The path isn't that of a socket.
+    NotASocket(PathBuf),
+    /// Unable to access the socket.
+    Socket(PathBuf, io::Error),
+}
+
+impl fmt::Display for TpmConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TpmConfigError::*;
+        match self {
+            NotASocket(path) => write!(f, "{} isn't a socket", path.display()),
+            Socket(path, e) => write!(f, "Unable to access {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for TpmConfigError {}
+
+/// The TPM of the microVM, emulated on the host by swtpm listening on a Unix socket. The socket
+/// is connected to when the microVM starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TpmConfig {
+    pub socket_path: PathBuf,
+}
+
+impl TpmConfig {
+    pub fn new(socket_path: &Path) -> Result<Self, TpmConfigError> {
+        let metadata = fs::metadata(socket_path)
+            .map_err(|e| TpmConfigError::Socket(socket_path.to_path_buf(), e))?;
+        if !metadata.file_type().is_socket() {
+            return Err(TpmConfigError::NotASocket(socket_path.to_path_buf()));
+        }
+
+        Ok(TpmConfig {
+            socket_path: socket_path.to_path_buf(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_tpm_config() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("swtpm.sock");
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+        assert_eq!(
+            TpmConfig::new(&socket_path).unwrap().socket_path,
+            socket_path
+        );
+
+        let file_path = dir.as_path().join("file");
+        fs::write(&file_path, "").unwrap();
+        assert!(matches!(
+            TpmConfig::new(&file_path),
+            Err(TpmConfigError::NotASocket(_))
+        ));
+        assert!(matches!(
+            TpmConfig::new(&dir.as_path().join("missing.sock")),
+            Err(TpmConfigError::Socket(_, _))
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+
+pub use devices::legacy::{UserAccess, UserDeviceCallback};
+use vmm_config::device_window::{DeviceWindowConfig, MMIO_WINDOW_RANGE, MMIO_WINDOW_SIZE};
+
+/// The size of the port I/O space.
+pub const PIO_SPACE_SIZE: u64 = 0x1_0000;
+
+/// Errors associated with the regions handed to the embedder.
+#[derive(Debug, PartialEq)]
+pub enum UserDeviceError {
+    /// The region is empty.
+    EmptyRegion,
+    /// The region, given by its base and its length, isn't within the range reserved for the
+    /// devices, or the port I/O space.
+    RegionOutOfRange(u64, u64),
+    /// Port I/O isn't supported on this architecture.
+    PioNotSupported,
+    /// The region overlaps another one, or a device of the microVM.
+    Overlap(u64),
+    /// The MMIO region overlaps the window the MMIO devices are placed in.
+    InDeviceWindow(u64),
+}
+
+impl fmt::Display for UserDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::UserDeviceError::*;
+        match self {
+            EmptyRegion => write!(f, "The region of the user device is empty"),
+            RegionOutOfRange(base, len) => write!(
+                f,
+                "The region {:#x}+{:#x} of the user device is out of range",
+                base, len
+            ),
+            PioNotSupported => write!(f, "Port I/O isn't supported on this architecture"),
+            Overlap(base) => write!(
+                f,
+                "The region of the user device at {:#x} overlaps another device",
+                base
+            ),
+            InDeviceWindow(base) => write!(
+                f,
+                "The region of the user device at {:#x} overlaps the MMIO device window",
+                base
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UserDeviceError {}
+
+/// The address space a user device is in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserRegionKind {
+    /// The guest physical address space, within the range reserved for the devices, which
+    /// starts at `arch::MMIO_MEM_START`.
+    Mmio,
+    /// The port I/O space, on x86_64 only.
+    Pio,
+}
+
+/// A region whose accesses by the guest are handed to the embedder, as a device of its own.
+#[derive(Clone)]
+pub struct UserDeviceConfig {
+    pub kind: UserRegionKind,
+    pub base: u64,
+    pub len: u64,
+    pub callback: UserDeviceCallback,
+}
+
+impl UserDeviceConfig {
+    pub fn new(
+        kind: UserRegionKind,
+        base: u64,
+        len: u64,
+        callback: UserDeviceCallback,
+    ) -> std::result::Result<Self, UserDeviceError> {
+        if len == 0 {
+            return Err(UserDeviceError::EmptyRegion);
+        }
+        let (start, end) = match kind {
+            UserRegionKind::Mmio => (
+                arch::MMIO_MEM_START,
+                arch::MMIO_MEM_START + MMIO_WINDOW_RANGE,
+            ),
+            UserRegionKind::Pio if cfg!(target_arch = "x86_64") => (0, PIO_SPACE_SIZE),
+            UserRegionKind::Pio => return Err(UserDeviceError::PioNotSupported),
+        };
+        if base < start
+            || base
+                .checked_add(len)
+                .map_or(true, |region_end| region_end > end)
+        {
+            return Err(UserDeviceError::RegionOutOfRange(base, len));
+        }
+
+        Ok(UserDeviceConfig {
+            kind,
+            base,
+            len,
+            callback,
+        })
+    }
+
+    /// Whether the region overlaps `other`, in the same address space.
+    pub fn overlaps(&self, other: &UserDeviceConfig) -> bool {
+        self.kind == other.kind
+            && self.base < other.base + other.len
+            && other.base < self.base + self.len
+    }
+
+    /// Checks the region is clear of the window the MMIO devices are placed in.
+    pub fn validate(
+        &self,
+        device_window: &DeviceWindowConfig,
+    ) -> std::result::Result<(), UserDeviceError> {
+        let window_start = device_window.mmio_start;
+        let window_end = window_start + MMIO_WINDOW_SIZE;
+        if self.kind == UserRegionKind::Mmio
+            && self.base < window_end
+            && window_start < self.base + self.len
+        {
+            return Err(UserDeviceError::InDeviceWindow(self.base));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UserDeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserDeviceConfig")
+            .field("kind", &self.kind)
+            .field("base", &self.base)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn region(
+        kind: UserRegionKind,
+        base: u64,
+        len: u64,
+    ) -> Result<UserDeviceConfig, UserDeviceError> {
+        UserDeviceConfig::new(kind, base, len, Arc::new(|_, _, _| ()))
+    }
+
+    #[test]
+    fn test_user_device_config() {
+        let base = arch::MMIO_MEM_START + MMIO_WINDOW_RANGE - 0x1000;
+        let mmio = region(UserRegionKind::Mmio, base, 0x1000).unwrap();
+        assert_eq!(
+            region(UserRegionKind::Mmio, base, 0).unwrap_err(),
+            UserDeviceError::EmptyRegion
+        );
+        assert_eq!(
+            region(UserRegionKind::Mmio, base, 0x2000).unwrap_err(),
+            UserDeviceError::RegionOutOfRange(base, 0x2000)
+        );
+        assert_eq!(
+            region(UserRegionKind::Mmio, u64::MAX, 2).unwrap_err(),
+            UserDeviceError::RegionOutOfRange(u64::MAX, 2)
+        );
+        assert!(mmio.overlaps(&region(UserRegionKind::Mmio, base + 0xfff, 1).unwrap()));
+        assert!(!mmio.overlaps(&region(UserRegionKind::Mmio, base - 0x1000, 0x1000).unwrap()));
+
+        let window = DeviceWindowConfig::default();
+        assert!(mmio.validate(&window).is_ok());
+        assert_eq!(
+            region(
+                UserRegionKind::Mmio,
+                window.mmio_start + MMIO_WINDOW_SIZE - 1,
+                1
+            )
+            .unwrap()
+            .validate(&window)
+            .unwrap_err(),
+            UserDeviceError::InDeviceWindow(window.mmio_start + MMIO_WINDOW_SIZE - 1)
+        );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let pio = region(UserRegionKind::Pio, 0x510, 2).unwrap();
+            assert!(!pio.overlaps(&region(UserRegionKind::Mmio, base, 0x1000).unwrap()));
+            assert!(pio.validate(&window).is_ok());
+            assert_eq!(
+                region(UserRegionKind::Pio, 0xffff, 2).unwrap_err(),
+                UserDeviceError::RegionOutOfRange(0xffff, 2)
+            );
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        assert_eq!(
+            region(UserRegionKind::Pio, 0x510, 2).unwrap_err(),
+            UserDeviceError::PioNotSupported
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use vmm_config::exit::ExitReason;
+
+/// Where the microVM is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmState {
+    /// The microVM is being configured, and hasn't started to boot.
+    Configured,
+    /// The microVM and its devices are being built.
+    Booting,
+    /// The vCPUs run, including while the idle monitor freezes them, as they're thawed on the
+    /// first sign of activity.
+    Running,
+    /// The vCPUs were paused on request, until they're resumed.
+    Paused,
+    /// The guest was asked to shut down, or the microVM to stop.
+    ShuttingDown,
+    /// The microVM stopped for the reason given, or failed to boot if there's none.
+    Stopped(Option<ExitReason>),
+}
+
+impl VmState {
+    pub fn as_str(self) -> &'static str {
+        use self::VmState::*;
+        match self {
+            Configured => "configured",
+            Booting => "booting",
+            Running => "running",
+            Paused => "paused",
+            ShuttingDown => "shutting-down",
+            Stopped(_) => "stopped",
+        }
+    }
+
+    /// Whether the microVM can move from this state to `to`. The states are only left forward,
+    /// except for pausing and resuming, and a microVM can stop from any state but `Configured`.
+    pub fn can_transition_to(self, to: VmState) -> bool {
+        use self::VmState::*;
+        matches!(
+            (self, to),
+            (Configured, Booting)
+                | (Booting, Running)
+                | (Running, Paused)
+                | (Paused, Running)
+                | (Running, ShuttingDown)
+                | (Paused, ShuttingDown)
+                | (Booting, Stopped(_))
+                | (Running, Stopped(_))
+                | (Paused, Stopped(_))
+                | (ShuttingDown, Stopped(_))
+        )
+    }
+}
+
+impl fmt::Display for VmState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmState::Stopped(Some(reason)) => write!(f, "stopped ({})", reason),
+            VmState::Stopped(None) => write!(f, "stopped (boot failure)"),
+            state => write!(f, "{}", state.as_str()),
+        }
+    }
+}
+
+/// Errors associated with the lifecycle of the microVM.
+#[derive(Debug, PartialEq)]
+pub enum VmStateError {
+    /// The microVM can't move from the first state to the second.
+    IllegalTransition(VmState, VmState),
+}
+
+impl fmt::Display for VmStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmStateError::IllegalTransition(from, to) => {
+                write!(f, "The microVM can't go from {} to {}", from, to)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmStateError {}
+
+/// The state of the microVM, shared by the VMM with the embedder and the monitor, which can query
+/// it while the VMM runs.
+#[derive(Clone)]
+pub struct VmStateHandle(Arc<Mutex<VmState>>);
+
+impl VmStateHandle {
+    pub fn get(&self) -> VmState {
+        *self.0.lock().unwrap()
+    }
+
+    /// Moves the microVM to `to`, failing if that's not a legal transition from its current state.
+    pub fn transition(&self, to: VmState) -> std::result::Result<(), VmStateError> {
+        let mut state = self.0.lock().unwrap();
+        if !state.can_transition_to(to) {
+            return Err(VmStateError::IllegalTransition(*state, to));
+        }
+        debug!("The microVM goes from {} to {}", *state, to);
+        *state = to;
+        Ok(())
+    }
+}
+
+impl Default for VmStateHandle {
+    fn default() -> Self {
+        VmStateHandle(Arc::new(Mutex::new(VmState::Configured)))
+    }
+}
+
+impl fmt::Debug for VmStateHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("VmStateHandle").field(&self.get()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_state() {
+        let state = VmStateHandle::default();
+        let shared = state.clone();
+        assert_eq!(shared.get(), VmState::Configured);
+        assert_eq!(
+            state.transition(VmState::Running).unwrap_err(),
+            VmStateError::IllegalTransition(VmState::Configured, VmState::Running)
+        );
+
+        for to in &[
+            VmState::Booting,
+            VmState::Running,
+            VmState::Paused,
+            VmState::Running,
+            VmState::ShuttingDown,
+        ] {
+            state.transition(*to).unwrap();
+            assert_eq!(shared.get(), *to);
+        }
+        assert!(state.transition(VmState::Paused).is_err());
+
+        let stopped = VmState::Stopped(Some(ExitReason::GuestShutdown));
+        state.transition(stopped).unwrap();
+        assert_eq!(shared.get(), stopped);
+        assert_eq!(stopped.to_string(), "stopped (guest shutdown)");
+        // Once stopped, the microVM stays so.
+        assert!(state.transition(VmState::Stopped(None)).is_err());
+        assert!(state.transition(VmState::Booting).is_err());
+
+        assert!(VmState::Booting.can_transition_to(VmState::Stopped(None)));
+        assert!(!VmState::Configured.can_transition_to(VmState::Stopped(None)));
+    }
+}
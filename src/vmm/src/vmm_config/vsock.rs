@@ -2,13 +2,37 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::collections::HashMap;
+use std::env;
 use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
+pub use devices::virtio::{ActivatedSockets, VsockConnConfig};
 use devices::virtio::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
 
 type MutexVsockUnix = Arc<Mutex<Vsock<VsockUnixBackend>>>;
 
+/// The smallest buffer of a vsock connection.
+pub const MIN_CONN_BUF_SIZE: u32 = 4 * 1024;
+/// The largest buffer of a vsock connection.
+pub const MAX_CONN_BUF_SIZE: u32 = 16 * 1024 * 1024;
+
+/// The CID of the guest, unless set otherwise.
+pub const DEFAULT_GUEST_CID: u32 = 3;
+/// The CID through which a kernel reaches itself, whatever its own CID.
+pub const VMADDR_CID_LOCAL: u32 = 1;
+/// The CID of the host, as seen by the guest.
+pub const VMADDR_CID_HOST: u32 = 2;
+/// The wildcard CID, asking for any CID not claimed by another microVM.
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// The highest CID handed out in place of `VMADDR_CID_ANY`.
+const MAX_ANY_GUEST_CID: u32 = 0xffff;
+
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum VsockConfigError {
@@ -16,16 +40,65 @@ pub enum VsockConfigError {
     CreateVsockBackend(VsockUnixBackendError),
     /// Failed to create the vsock device.
     CreateVsockDevice(VsockError),
+    /// The connection buffer size isn't a power of 2 within the supported range.
+    InvalidConnBufSize(u32),
+    /// The credit update threshold is 0, or exceeds the connection buffer size.
+    InvalidCreditUpdateThreshold(u32),
+    /// The CID is reserved, for the hypervisor, the host or as a wildcard.
+    InvalidGuestCid(u32),
+    /// Failed to claim the CID of the guest.
+    ClaimGuestCid(u32, io::Error),
+    /// The CID of the guest is claimed by another microVM.
+    GuestCidInUse(u32),
+    /// Every CID handed out in place of `VMADDR_CID_ANY` is claimed by another microVM.
+    NoFreeGuestCid,
 }
 
 impl fmt::Display for VsockConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::VsockConfigError::*;
         match *self {
-            CreateVsockBackend(ref e) => {
-                write!(f, "Cannot create backend for vsock device: {:?}", e)
-            }
-            CreateVsockDevice(ref e) => write!(f, "Cannot create vsock device: {:?}", e),
+            CreateVsockBackend(_) => write!(f, "Cannot create backend for vsock device"),
+            CreateVsockDevice(_) => write!(f, "Cannot create vsock device"),
+            InvalidConnBufSize(size) => write!(
+                f,
+                "Invalid vsock connection buffer size {}: must be a power of 2 between {} and {}",
+                size, MIN_CONN_BUF_SIZE, MAX_CONN_BUF_SIZE
+            ),
+            InvalidCreditUpdateThreshold(threshold) => write!(
+                f,
+                "Invalid vsock credit update threshold {}: must be between 1 and the connection \
+                 buffer size",
+                threshold
+            ),
+            InvalidGuestCid(cid) => write!(f, "Invalid vsock guest CID {}: reserved", cid),
+            ClaimGuestCid(cid, _) => write!(f, "Cannot claim the vsock guest CID {}", cid),
+            GuestCidInUse(cid) => write!(
+                f,
+                "The vsock guest CID {} is claimed by another microVM",
+                cid
+            ),
+            NoFreeGuestCid => write!(
+                f,
+                "Every vsock guest CID up to {} is claimed by another microVM",
+                MAX_ANY_GUEST_CID
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VsockConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use self::VsockConfigError::*;
+        match *self {
+            CreateVsockBackend(ref e) => Some(e),
+            CreateVsockDevice(ref e) => Some(e),
+            ClaimGuestCid(_, ref e) => Some(e),
+            InvalidConnBufSize(_)
+            | InvalidCreditUpdateThreshold(_)
+            | InvalidGuestCid(_)
+            | GuestCidInUse(_)
+            | NoFreeGuestCid => None,
         }
     }
 }
@@ -42,6 +115,137 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// An optional map of host to guest port mappings.
     pub host_port_map: Option<HashMap<u16, u16>>,
+    /// The flow control parameters of the connections.
+    pub conn_config: VsockConnConfig,
+}
+
+/// Checks the flow control parameters of the vsock connections.
+pub fn validate_conn_config(cfg: &VsockConnConfig) -> Result<()> {
+    if !cfg.buf_size.is_power_of_two()
+        || cfg.buf_size < MIN_CONN_BUF_SIZE
+        || cfg.buf_size > MAX_CONN_BUF_SIZE
+    {
+        return Err(VsockConfigError::InvalidConnBufSize(cfg.buf_size));
+    }
+    if cfg.credit_update_threshold == 0 || cfg.credit_update_threshold > cfg.buf_size {
+        return Err(VsockConfigError::InvalidCreditUpdateThreshold(
+            cfg.credit_update_threshold,
+        ));
+    }
+    Ok(())
+}
+
+/// The CID of the guest, along with the claim on it, if any.
+///
+/// Claims are advisory locks on files of a directory shared by the microVMs, the temporary
+/// directory of the host by default, so two microVMs claiming the same CID detect the collision.
+/// They're released when dropped, or when the process exits.
+#[derive(Debug)]
+pub struct GuestCid {
+    cid: u32,
+    claim: Option<File>,
+}
+
+impl Default for GuestCid {
+    fn default() -> Self {
+        GuestCid {
+            cid: DEFAULT_GUEST_CID,
+            claim: None,
+        }
+    }
+}
+
+impl GuestCid {
+    /// Sets the CID of the guest, without claiming it.
+    pub fn new(cid: u32) -> Result<Self> {
+        validate_guest_cid(cid)?;
+        Ok(GuestCid { cid, claim: None })
+    }
+
+    /// Claims the CID of the guest, or the lowest one not claimed by another microVM if it is
+    /// `VMADDR_CID_ANY`.
+    pub fn claim(cid: u32) -> Result<Self> {
+        Self::claim_in(&env::temp_dir(), cid)
+    }
+
+    /// Claims the CID of the guest among the microVMs sharing the directory `dir`.
+    ///
+    /// `VMADDR_CID_LOCAL` is never claimed: it only ever designates the guest to itself, so any
+    /// number of guests can have it, which is convenient for tests running the same code on the
+    /// host and in the guest.
+    pub fn claim_in(dir: &Path, cid: u32) -> Result<Self> {
+        if cid == VMADDR_CID_LOCAL {
+            return Ok(GuestCid { cid, claim: None });
+        }
+        if cid != VMADDR_CID_ANY {
+            validate_guest_cid(cid)?;
+            return match try_claim(dir, cid)? {
+                Some(claim) => Ok(GuestCid {
+                    cid,
+                    claim: Some(claim),
+                }),
+                None => Err(VsockConfigError::GuestCidInUse(cid)),
+            };
+        }
+
+        for cid in DEFAULT_GUEST_CID..=MAX_ANY_GUEST_CID {
+            if let Some(claim) = try_claim(dir, cid)? {
+                return Ok(GuestCid {
+                    cid,
+                    claim: Some(claim),
+                });
+            }
+        }
+        Err(VsockConfigError::NoFreeGuestCid)
+    }
+
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    /// Whether the CID is claimed, so no other microVM claims it while this one holds it.
+    pub fn is_claimed(&self) -> bool {
+        self.claim.is_some()
+    }
+}
+
+/// Checks the CID isn't reserved.
+pub fn validate_guest_cid(cid: u32) -> Result<()> {
+    // CID 0 is reserved for the hypervisor.
+    if cid == 0 || cid == VMADDR_CID_HOST || cid == VMADDR_CID_ANY {
+        return Err(VsockConfigError::InvalidGuestCid(cid));
+    }
+    Ok(())
+}
+
+fn claim_path(dir: &Path, cid: u32) -> PathBuf {
+    dir.join(format!("krun-vsock-cid-{}.lock", cid))
+}
+
+/// Locks the claim file of `cid`, returning `None` if another microVM holds it.
+fn try_claim(dir: &Path, cid: u32) -> Result<Option<File>> {
+    let path = claim_path(dir, cid);
+    // The claim files are left behind, as removing them would race with other microVMs locking
+    // them. Those created by other users may only be readable, which is enough to lock them.
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .mode(0o644)
+        .open(&path)
+        .or_else(|_| File::open(&path))
+        .map_err(|e| VsockConfigError::ClaimGuestCid(cid, e))?;
+
+    // Safe because the file descriptor is valid, and the return value is checked.
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(VsockConfigError::ClaimGuestCid(cid, err));
+    }
+    Ok(Some(file))
 }
 
 struct VsockWrapper {
@@ -76,8 +280,10 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_unixsock_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockUnixBackend>> {
-        let backend = VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.host_port_map)
-            .map_err(VsockConfigError::CreateVsockBackend)?;
+        validate_conn_config(&cfg.conn_config)?;
+        let backend =
+            VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.host_port_map, cfg.conn_config)
+                .map_err(VsockConfigError::CreateVsockBackend)?;
 
         Ok(Vsock::new(u64::from(cfg.guest_cid), backend)
             .map_err(VsockConfigError::CreateVsockDevice)?)
@@ -87,6 +293,7 @@ impl VsockBuilder {
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
 
     // Placeholder for the path where a socket file will be created.
@@ -118,6 +325,7 @@ pub(crate) mod tests {
             vsock_id: vsock_dev_id.to_string(),
             guest_cid: 3,
             host_port_map: None,
+            conn_config: VsockConnConfig::default(),
         }
     }
 
@@ -128,6 +336,28 @@ pub(crate) mod tests {
         VsockBuilder::create_unixsock_vsock(vsock_config).unwrap();
     }
 
+    #[test]
+    fn test_conn_config() {
+        assert!(validate_conn_config(&VsockConnConfig::default()).is_ok());
+        assert!(validate_conn_config(&VsockConnConfig::with_buf_size(MIN_CONN_BUF_SIZE)).is_ok());
+        for size in &[0, 2048, 48 * 1024, 2 * MAX_CONN_BUF_SIZE] {
+            assert!(matches!(
+                validate_conn_config(&VsockConnConfig::with_buf_size(*size)),
+                Err(VsockConfigError::InvalidConnBufSize(_))
+            ));
+        }
+        for threshold in &[0, 128 * 1024] {
+            let cfg = VsockConnConfig {
+                buf_size: 64 * 1024,
+                credit_update_threshold: *threshold,
+            };
+            assert!(matches!(
+                validate_conn_config(&cfg),
+                Err(VsockConfigError::InvalidCreditUpdateThreshold(_))
+            ));
+        }
+    }
+
     #[test]
     fn test_vsock_insert() {
         let mut store = VsockBuilder::new();
@@ -145,6 +375,44 @@ pub(crate) mod tests {
         assert_eq!(vsock.lock().unwrap().cid(), new_cid as u64);
     }
 
+    #[test]
+    fn test_guest_cid() {
+        assert_eq!(GuestCid::default().cid(), DEFAULT_GUEST_CID);
+        assert!(!GuestCid::new(42).unwrap().is_claimed());
+        for cid in &[0, VMADDR_CID_HOST, VMADDR_CID_ANY] {
+            assert!(matches!(
+                GuestCid::new(*cid),
+                Err(VsockConfigError::InvalidGuestCid(_))
+            ));
+        }
+
+        let dir = TempDir::new().unwrap();
+        let dir = dir.as_path();
+        let first = GuestCid::claim_in(dir, 42).unwrap();
+        assert!(first.is_claimed());
+        assert!(matches!(
+            GuestCid::claim_in(dir, 42),
+            Err(VsockConfigError::GuestCidInUse(42))
+        ));
+
+        // The wildcard skips the claimed CIDs.
+        let second = GuestCid::claim_in(dir, DEFAULT_GUEST_CID).unwrap();
+        let third = GuestCid::claim_in(dir, VMADDR_CID_ANY).unwrap();
+        assert_eq!(third.cid(), DEFAULT_GUEST_CID + 1);
+
+        // Dropping a claim releases the CID.
+        drop(second);
+        assert_eq!(
+            GuestCid::claim_in(dir, VMADDR_CID_ANY).unwrap().cid(),
+            DEFAULT_GUEST_CID
+        );
+
+        // Any number of guests can have the local CID.
+        let local = GuestCid::claim_in(dir, VMADDR_CID_LOCAL).unwrap();
+        assert!(!local.is_claimed());
+        assert!(GuestCid::claim_in(dir, VMADDR_CID_LOCAL).is_ok());
+    }
+
     #[test]
     fn test_error_messages() {
         use super::VsockConfigError::*;
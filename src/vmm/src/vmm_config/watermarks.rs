@@ -0,0 +1,75 @@
+use std::fmt;
+
+pub use devices::virtio::watermark::{
+    QueueWatermarks, WatermarkCallback, WatermarkEvent, WatermarkLevel,
+};
+
+/// The largest high watermark, the size of the queues of the console and vsock devices.
+pub const MAX_HIGH_WATERMARK: u32 = 256;
+
+/// Errors associated with the queue watermarks configuration.
+#[derive(Debug, PartialEq)]
+pub enum QueueWatermarksError {
+    /// The high watermark exceeds `MAX_HIGH_WATERMARK`.
+    HighTooLarge(u32),
+    /// The low watermark isn't lower than the high one.
+    LowNotBelowHigh(u32, u32),
+}
+
+impl fmt::Display for QueueWatermarksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::QueueWatermarksError::*;
+        match *self {
+            HighTooLarge(high) => write!(
+                f,
+                "The high watermark {} exceeds the queue size {}",
+                high, MAX_HIGH_WATERMARK
+            ),
+            LowNotBelowHigh(low, high) => write!(
+                f,
+                "The low watermark {} must be lower than the high watermark {}",
+                low, high
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueueWatermarksError {}
+
+/// Validates the watermarks and creates them, reporting to `callback`.
+pub fn queue_watermarks(
+    low: u32,
+    high: u32,
+    callback: WatermarkCallback,
+) -> std::result::Result<QueueWatermarks, QueueWatermarksError> {
+    if high > MAX_HIGH_WATERMARK {
+        return Err(QueueWatermarksError::HighTooLarge(high));
+    }
+    if low >= high {
+        return Err(QueueWatermarksError::LowNotBelowHigh(low, high));
+    }
+    Ok(QueueWatermarks::new(low as u16, high as u16, callback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_queue_watermarks() {
+        let callback: WatermarkCallback = Arc::new(|_: &WatermarkEvent| ());
+
+        let watermarks = queue_watermarks(16, 192, callback.clone()).unwrap();
+        assert_eq!((watermarks.low(), watermarks.high()), (16, 192));
+
+        assert_eq!(
+            queue_watermarks(0, MAX_HIGH_WATERMARK + 1, callback.clone()).unwrap_err(),
+            QueueWatermarksError::HighTooLarge(MAX_HIGH_WATERMARK + 1)
+        );
+        assert_eq!(
+            queue_watermarks(8, 8, callback).unwrap_err(),
+            QueueWatermarksError::LowNotBelowHigh(8, 8)
+        );
+    }
+}
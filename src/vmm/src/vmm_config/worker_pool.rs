@@ -0,0 +1,104 @@
+use std::fmt;
+
+pub use devices::virtio::worker_pool::{
+    WorkerGroup, WorkerPool, DEFAULT_DEVICE_LIMIT, DEFAULT_WORKER_THREADS,
+};
+
+/// The largest number of threads of the worker pool.
+pub const MAX_WORKER_THREADS: usize = 64;
+/// The largest number of requests a device can have in flight, the size of the queues of the
+/// devices running on the pool.
+pub const MAX_DEVICE_LIMIT: usize = 1024;
+
+/// The threads running the blocking requests of the devices, and the share each device gets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WorkerPoolConfig {
+    /// The number of threads of the pool. With none, the requests run on the event loop.
+    pub threads: usize,
+    /// The number of requests each device can have in flight on the pool.
+    pub device_limit: usize,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            threads: DEFAULT_WORKER_THREADS,
+            device_limit: DEFAULT_DEVICE_LIMIT,
+        }
+    }
+}
+
+/// Errors associated with the worker pool configuration.
+#[derive(Debug, PartialEq)]
+pub enum WorkerPoolError {
+    /// The number of threads exceeds `MAX_WORKER_THREADS`.
+    TooManyThreads(usize),
+    /// The per-device limit is zero, or exceeds `MAX_DEVICE_LIMIT`.
+    InvalidDeviceLimit(usize),
+}
+
+impl fmt::Display for WorkerPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WorkerPoolError::*;
+        match *self {
+            TooManyThreads(threads) => write!(
+                f,
+                "The worker pool can't have more than {} threads, got {}",
+                MAX_WORKER_THREADS, threads
+            ),
+            InvalidDeviceLimit(limit) => write!(
+                f,
+                "The per-device limit of the worker pool must be between 1 and {}, got {}",
+                MAX_DEVICE_LIMIT, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WorkerPoolError {}
+
+impl WorkerPoolConfig {
+    pub fn validate(&self) -> std::result::Result<(), WorkerPoolError> {
+        if self.threads > MAX_WORKER_THREADS {
+            return Err(WorkerPoolError::TooManyThreads(self.threads));
+        }
+        if self.device_limit == 0 || self.device_limit > MAX_DEVICE_LIMIT {
+            return Err(WorkerPoolError::InvalidDeviceLimit(self.device_limit));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(WorkerPoolConfig::default().validate().is_ok());
+        let inline = WorkerPoolConfig {
+            threads: 0,
+            device_limit: 1,
+        };
+        assert!(inline.validate().is_ok());
+
+        assert_eq!(
+            WorkerPoolConfig {
+                threads: MAX_WORKER_THREADS + 1,
+                ..inline
+            }
+            .validate()
+            .unwrap_err(),
+            WorkerPoolError::TooManyThreads(MAX_WORKER_THREADS + 1)
+        );
+        assert_eq!(
+            WorkerPoolConfig {
+                device_limit: 0,
+                ..inline
+            }
+            .validate()
+            .unwrap_err(),
+            WorkerPoolError::InvalidDeviceLimit(0)
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Callback invoked once the init process of the guest reports it's starting the workload, which
+/// tells when the workload is actually running, rather than when the vCPUs were started.
+///
+/// The callback runs on a vCPU thread, so it should return promptly.
+#[derive(Clone)]
+pub struct WorkloadStartedCallback {
+    callback: Arc<Mutex<Option<Box<dyn FnOnce() + Send>>>>,
+}
+
+impl WorkloadStartedCallback {
+    pub fn new(callback: Box<dyn FnOnce() + Send>) -> Self {
+        WorkloadStartedCallback {
+            callback: Arc::new(Mutex::new(Some(callback))),
+        }
+    }
+
+    /// Invokes the callback. Only the first report of the guest is delivered.
+    pub fn notify(&self) {
+        if let Some(callback) = self.callback.lock().unwrap().take() {
+            callback();
+        }
+    }
+}
+
+impl fmt::Debug for WorkloadStartedCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WorkloadStartedCallback")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_notify_once() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let callback_count = count.clone();
+        let callback = WorkloadStartedCallback::new(Box::new(move || {
+            callback_count.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        callback.clone().notify();
+        callback.notify();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}